@@ -0,0 +1,16 @@
+//! Generates `include/qvpn.h` from the functions `src/lib.rs` exports, so
+//! the header can't drift from them.
+
+use std::{env, path::Path};
+
+fn main() {
+  let dir = env::var("CARGO_MANIFEST_DIR").expect("cargo sets CARGO_MANIFEST_DIR");
+  let dir = Path::new(&dir);
+  println!("cargo:rerun-if-changed=src/lib.rs");
+  println!("cargo:rerun-if-changed=cbindgen.toml");
+  let config = cbindgen::Config::from_file(dir.join("cbindgen.toml"))
+    .unwrap_or_else(|err| panic!("reading cbindgen.toml: {}", err));
+  let bindings = cbindgen::generate_with_config(dir, config)
+    .unwrap_or_else(|err| panic!("generating include/qvpn.h: {}", err));
+  bindings.write_to_file(dir.join("include/qvpn.h"));
+}
@@ -0,0 +1,132 @@
+/* C API of the qvpn client. Generated by cbindgen from src/lib.rs; do not edit. */
+
+#ifndef QVPN_H
+#define QVPN_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Tunnels IP packets through a TUN interface named in the `[tunnel]`
+ * section.
+ */
+#define QVPN_MODE_TUNNEL 0
+
+/**
+ * Relays the SOCKS5, HTTP `CONNECT` or DNS clients of the address the
+ * `[client]` section gives, or else the UDP or TCP ports it forwards.
+ */
+#define QVPN_MODE_PROXY 1
+
+/**
+ * The engine is running, or reconnecting.
+ */
+#define QVPN_STATE_RUNNING 0
+
+/**
+ * The engine stopped without error.
+ */
+#define QVPN_STATE_STOPPED 1
+
+/**
+ * The engine stopped with an error, which [`qvpn_last_error`] returns
+ * after [`qvpn_stats`].
+ */
+#define QVPN_STATE_FAILED 2
+
+/**
+ * A running tunnel or proxy.
+ */
+typedef struct QvpnEngine QvpnEngine;
+
+/**
+ * Statistics of an engine, summed over its connections.
+ */
+typedef struct QvpnStats {
+  /**
+   * One of the `QVPN_STATE_` constants.
+   */
+  uint32_t state;
+  /**
+   * Connections open to the server.
+   */
+  uint32_t connections;
+  /**
+   * UDP payload bytes sent.
+   */
+  uint64_t bytes_sent;
+  /**
+   * UDP payload bytes received.
+   */
+  uint64_t bytes_received;
+  /**
+   * UDP datagrams sent.
+   */
+  uint64_t packets_sent;
+  /**
+   * UDP datagrams received.
+   */
+  uint64_t packets_received;
+  /**
+   * QUIC packets declared lost.
+   */
+  uint64_t lost_packets;
+  /**
+   * Highest smoothed round-trip time of the connections, in milliseconds.
+   */
+  uint32_t rtt_ms;
+} QvpnStats;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Starts a tunnel or proxy, as `mode` says, with the settings in
+ * `config`, the contents of a qvpn config file. Returns null if it can't
+ * be started.
+ *
+ * Logging is set up from the `[log]` section of the first config started.
+ *
+ * # Safety
+ *
+ * `config` must point to a nul-terminated string.
+ */
+struct QvpnEngine *qvpn_start(const char *config, uint32_t mode);
+
+/**
+ * Fills `stats` in with the statistics of `engine`. Returns 0, or -1 if
+ * either is null or reading them failed.
+ *
+ * # Safety
+ *
+ * `engine` must be null or returned by [`qvpn_start`] and not stopped, and
+ * `stats` must be null or point to a [`QvpnStats`].
+ */
+int32_t qvpn_stats(const struct QvpnEngine *engine, struct QvpnStats *stats);
+
+/**
+ * Stops `engine` and frees it. Returns 0, or -1 if stopping failed, in
+ * which case the engine is gone all the same. Does nothing if it is null.
+ *
+ * # Safety
+ *
+ * `engine` must be null or returned by [`qvpn_start`] and not stopped.
+ */
+int32_t qvpn_stop(struct QvpnEngine *engine);
+
+/**
+ * Why the last call that failed on this thread failed, or null if none
+ * did. The string stays valid until the next call on the thread that
+ * fails.
+ */
+const char *qvpn_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* QVPN_H */
@@ -0,0 +1,295 @@
+//! C ABI for embedding the qvpn client in other programs, such as mobile
+//! apps and desktop GUIs not written in Rust.
+//!
+//! A program starts a tunnel or proxy from the contents of a config file
+//! with [`qvpn_start`], reads its statistics with [`qvpn_stats`] and stops
+//! it with [`qvpn_stop`]. Each engine runs on a Tokio runtime of its own,
+//! so the functions may be called from any thread. A call that fails
+//! returns null or a negative number, and [`qvpn_last_error`] tells why.
+//! That includes a panic, which never unwinds into the caller.
+//!
+//! `include/qvpn.h` declares these functions for C. The build script
+//! generates it with cbindgen.
+
+use std::{
+  cell::RefCell,
+  ffi::{CStr, CString},
+  future::Future,
+  os::raw::c_char,
+  panic::{self, AssertUnwindSafe},
+  pin::Pin,
+  ptr,
+  sync::{Arc, Mutex, Once},
+  time::Duration,
+};
+
+use qvpn::{config::Config, Client, QvpnError, Result};
+use tokio::runtime::Runtime;
+
+/// Tunnels IP packets through a TUN interface named in the `[tunnel]`
+/// section.
+pub const QVPN_MODE_TUNNEL: u32 = 0;
+/// Relays the SOCKS5, HTTP `CONNECT` or DNS clients of the address the
+/// `[client]` section gives, or else the UDP or TCP ports it forwards.
+pub const QVPN_MODE_PROXY: u32 = 1;
+
+/// The engine is running, or reconnecting.
+pub const QVPN_STATE_RUNNING: u32 = 0;
+/// The engine stopped without error.
+pub const QVPN_STATE_STOPPED: u32 = 1;
+/// The engine stopped with an error, which [`qvpn_last_error`] returns
+/// after [`qvpn_stats`].
+pub const QVPN_STATE_FAILED: u32 = 2;
+
+/// How long stopping waits for the engine's tasks to finish.
+const STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A running tunnel or proxy.
+pub struct QvpnEngine {
+  runtime: Option<Runtime>,
+  client: Arc<Client>,
+  /// Set once the engine stopped, with why if it failed.
+  outcome: Arc<Mutex<Option<std::result::Result<(), String>>>>,
+}
+
+impl Drop for QvpnEngine {
+  fn drop(&mut self) {
+    // Dropping the tunnel restores the routes and resolvers it installed.
+    if let Some(runtime) = self.runtime.take() {
+      runtime.shutdown_timeout(STOP_TIMEOUT);
+    }
+  }
+}
+
+/// Statistics of an engine, summed over its connections.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QvpnStats {
+  /// One of the `QVPN_STATE_` constants.
+  pub state: u32,
+  /// Connections open to the server.
+  pub connections: u32,
+  /// UDP payload bytes sent.
+  pub bytes_sent: u64,
+  /// UDP payload bytes received.
+  pub bytes_received: u64,
+  /// UDP datagrams sent.
+  pub packets_sent: u64,
+  /// UDP datagrams received.
+  pub packets_received: u64,
+  /// QUIC packets declared lost.
+  pub lost_packets: u64,
+  /// Highest smoothed round-trip time of the connections, in milliseconds.
+  pub rtt_ms: u32,
+}
+
+thread_local! {
+  static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(err: impl ToString) {
+  let message = err.to_string().replace('\0', " ");
+  let message = CString::new(message).expect("nul bytes replaced");
+  LAST_ERROR.with(|x| *x.borrow_mut() = Some(message));
+}
+
+/// Runs `body`, or returns `failed` if it panics, with the panic as the
+/// last error, so no panic unwinds across the C ABI.
+fn guard<T>(failed: T, body: impl FnOnce() -> T) -> T {
+  panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|panic| {
+    let message = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+      (Some(message), _) => message,
+      (_, Some(message)) => message.as_str(),
+      _ => "unknown panic",
+    };
+    set_error(format!("internal error: {}", message));
+    failed
+  })
+}
+
+/// Starts a tunnel or proxy, as `mode` says, with the settings in
+/// `config`, the contents of a qvpn config file. Returns null if it can't
+/// be started.
+///
+/// Logging is set up from the `[log]` section of the first config started.
+///
+/// # Safety
+///
+/// `config` must point to a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn qvpn_start(config: *const c_char, mode: u32) -> *mut QvpnEngine {
+  guard(ptr::null_mut(), || qvpn_start_unguarded(config, mode))
+}
+
+unsafe fn qvpn_start_unguarded(config: *const c_char, mode: u32) -> *mut QvpnEngine {
+  if config.is_null() {
+    set_error("no config given");
+    return ptr::null_mut();
+  }
+  let config = match CStr::from_ptr(config).to_str() {
+    Ok(config) => config,
+    Err(err) => {
+      set_error(format!("config isn't UTF-8: {}", err));
+      return ptr::null_mut();
+    }
+  };
+  match start(config, mode) {
+    Ok(engine) => Box::into_raw(Box::new(engine)),
+    Err(err) => {
+      set_error(err);
+      ptr::null_mut()
+    }
+  }
+}
+
+fn start(config: &str, mode: u32) -> Result<QvpnEngine> {
+  static LOG: Once = Once::new();
+  let config = Config::parse(config)?;
+  LOG.call_once(|| {
+    if let Err(err) = config.init_log() {
+      eprintln!("qvpn: couldn't set up logging: {}", err);
+    }
+  });
+  let url = config
+    .client
+    .url
+    .clone()
+    .ok_or_else(|| QvpnError::InvalidInput("no url configured".into()))?;
+  let host = config.client.host.clone();
+  let runtime = Runtime::new()?;
+  let client = {
+    let _entered = runtime.enter();
+    Arc::new(config.client_builder()?.build()?)
+  };
+  let run: Pin<Box<dyn Future<Output = Result<()>> + Send>> = match mode {
+    QVPN_MODE_TUNNEL => {
+      let tun = config.tun_config().unwrap_or_default();
+      let client = client.clone();
+      Box::pin(async move { client.tunnel(&url, host.as_deref(), &tun).await })
+    }
+    QVPN_MODE_PROXY => {
+      let section = &config.client;
+      let client = client.clone();
+      if let Some(listen) = section.socks5 {
+        Box::pin(async move { client.socks5(&url, host.as_deref(), listen).await })
+      } else if let Some(listen) = section.dns_stub {
+        Box::pin(async move { client.dns_stub(&url, host.as_deref(), listen).await })
+      } else if let Some(listen) = section.http_proxy {
+        Box::pin(async move { client.http_proxy(&url, host.as_deref(), listen).await })
+      } else if !section.forward_udp.is_empty() {
+        let forwards = section.forward_udp.clone();
+        Box::pin(async move { client.forward_udp(&url, host.as_deref(), &forwards).await })
+      } else if !section.forward.is_empty() || !section.reverse_forward.is_empty() {
+        let (locals, reverses) = (section.forward.clone(), section.reverse_forward.clone());
+        Box::pin(async move {
+          client
+            .forward(&url, host.as_deref(), &locals, &reverses)
+            .await
+        })
+      } else {
+        return Err(QvpnError::InvalidInput(
+          "no socks5, http_proxy, dns_stub or forward configured".into(),
+        ));
+      }
+    }
+    _ => return Err(QvpnError::InvalidInput(format!("unknown mode {}", mode))),
+  };
+  let outcome = Arc::new(Mutex::new(None));
+  let finished = outcome.clone();
+  runtime.spawn(async move {
+    let result = run.await.map_err(|err| err.to_string());
+    *finished.lock().unwrap() = Some(result);
+  });
+  Ok(QvpnEngine {
+    runtime: Some(runtime),
+    client,
+    outcome,
+  })
+}
+
+/// Fills `stats` in with the statistics of `engine`. Returns 0, or -1 if
+/// either is null or reading them failed.
+///
+/// # Safety
+///
+/// `engine` must be null or returned by [`qvpn_start`] and not stopped, and
+/// `stats` must be null or point to a [`QvpnStats`].
+#[no_mangle]
+pub unsafe extern "C" fn qvpn_stats(engine: *const QvpnEngine, stats: *mut QvpnStats) -> i32 {
+  guard(-1, || qvpn_stats_unguarded(engine, stats))
+}
+
+unsafe fn qvpn_stats_unguarded(engine: *const QvpnEngine, stats: *mut QvpnStats) -> i32 {
+  let (engine, stats) = match (engine.as_ref(), stats.as_mut()) {
+    (Some(engine), Some(stats)) => (engine, stats),
+    _ => {
+      set_error("no engine or stats given");
+      return -1;
+    }
+  };
+  let connections = engine.client.stats();
+  *stats = QvpnStats {
+    state: match &*engine.outcome.lock().unwrap() {
+      None => QVPN_STATE_RUNNING,
+      Some(Ok(())) => QVPN_STATE_STOPPED,
+      Some(Err(err)) => {
+        set_error(err);
+        QVPN_STATE_FAILED
+      }
+    },
+    connections: connections.len() as u32,
+    bytes_sent: connections.iter().map(|x| x.bytes_sent).sum(),
+    bytes_received: connections.iter().map(|x| x.bytes_received).sum(),
+    packets_sent: connections.iter().map(|x| x.packets_sent).sum(),
+    packets_received: connections.iter().map(|x| x.packets_received).sum(),
+    lost_packets: connections.iter().map(|x| x.lost_packets).sum(),
+    rtt_ms: connections
+      .iter()
+      .map(|x| x.rtt.as_millis() as u32)
+      .max()
+      .unwrap_or_default(),
+  };
+  0
+}
+
+/// Stops `engine` and frees it. Returns 0, or -1 if stopping failed, in
+/// which case the engine is gone all the same. Does nothing if it is null.
+///
+/// # Safety
+///
+/// `engine` must be null or returned by [`qvpn_start`] and not stopped.
+#[no_mangle]
+pub unsafe extern "C" fn qvpn_stop(engine: *mut QvpnEngine) -> i32 {
+  guard(-1, || {
+    if !engine.is_null() {
+      drop(Box::from_raw(engine));
+    }
+    0
+  })
+}
+
+/// Why the last call that failed on this thread failed, or null if none
+/// did. The string stays valid until the next call on the thread that
+/// fails.
+#[no_mangle]
+pub extern "C" fn qvpn_last_error() -> *const c_char {
+  // Not guarded: a panic here would set the very error it reads.
+  LAST_ERROR
+    .try_with(|x| match &*x.borrow() {
+      Some(message) => message.as_ptr(),
+      None => ptr::null(),
+    })
+    .unwrap_or(ptr::null())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn panics_become_errors() {
+    assert_eq!(guard(-1, || panic!("boom")), -1);
+    let error = unsafe { CStr::from_ptr(qvpn_last_error()) };
+    assert_eq!(error.to_str().unwrap(), "internal error: boom");
+  }
+}
@@ -0,0 +1,181 @@
+//! Signed, time-limited access tokens for downloads.
+//!
+//! When the server has a [`SigningKey`], it serves a file or directory
+//! listing only to requests presenting a token, minted with the same key,
+//! whose [`Grant`] covers the path and hasn't expired. Clients present it
+//! in an `Authorization: Bearer` header or a `token` query parameter, so a
+//! link like `https://host:4433/pub/report.pdf?token=...` shares a file for
+//! as long as the token lasts without handing out certificates or
+//! passwords.
+//!
+//! A token is the grant as base64url encoded JSON, a `.`, and its
+//! HMAC-SHA256 under the key. It can't be revoked other than by changing
+//! the key.
+
+use std::{
+  fs,
+  path::Path,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::{http, QvpnError, Result};
+
+/// Shortest key accepted.
+pub const MIN_KEY_LEN: usize = 16;
+
+/// The paths a token grants access to, and until when.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grant {
+  /// Absolute paths below the server root, where `*` matches any run of
+  /// characters, `/` included.
+  pub paths: Vec<String>,
+  /// Seconds since the Unix epoch after which the token is refused.
+  #[serde(rename = "exp")]
+  pub expires: u64,
+}
+
+impl Grant {
+  /// Grants access to `paths` for `ttl` from now.
+  pub fn new(paths: Vec<String>, ttl: Duration) -> Self {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default();
+    Grant {
+      paths,
+      expires: (now + ttl).as_secs(),
+    }
+  }
+
+  /// When the grant expires.
+  pub fn expires_at(&self) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(self.expires)
+  }
+
+  /// Whether the grant covers `path`.
+  pub fn allows(&self, path: &str) -> bool {
+    self.paths.iter().any(|pattern| matches(pattern, path))
+  }
+}
+
+/// Whether `path` matches `pattern`, in which `*` matches any run of
+/// characters.
+fn matches(pattern: &str, path: &str) -> bool {
+  let mut parts = pattern.split('*');
+  let first = parts.next().unwrap_or_default();
+  let mut rest = match path.strip_prefix(first) {
+    Some(rest) => rest,
+    None => return false,
+  };
+  let parts: Vec<&str> = parts.collect();
+  let (last, middle) = match parts.split_last() {
+    Some(split) => split,
+    None => return rest.is_empty(),
+  };
+  for part in middle {
+    match rest.find(part) {
+      Some(at) => rest = &rest[at + part.len()..],
+      None => return false,
+    }
+  }
+  rest.ends_with(last)
+}
+
+/// The key tokens are signed with.
+#[derive(Clone)]
+pub struct SigningKey {
+  key: hmac::Key,
+}
+
+impl SigningKey {
+  /// Creates a key from `secret`, which must be at least [`MIN_KEY_LEN`]
+  /// bytes.
+  pub fn new(secret: &[u8]) -> Result<Self> {
+    if secret.len() < MIN_KEY_LEN {
+      return Err(QvpnError::InvalidInput(format!(
+        "token key is {} bytes, need at least {}",
+        secret.len(),
+        MIN_KEY_LEN
+      )));
+    }
+    Ok(SigningKey {
+      key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+    })
+  }
+
+  /// Reads the key from a file, ignoring a trailing newline.
+  pub fn load(path: &Path) -> Result<Self> {
+    let secret = fs::read(path)?;
+    let end = secret
+      .iter()
+      .rposition(|c| !matches!(c, b'\r' | b'\n'))
+      .map_or(0, |x| x + 1);
+    SigningKey::new(&secret[..end])
+  }
+
+  /// Mints a token for `grant`.
+  pub fn sign(&self, grant: &Grant) -> String {
+    let claims = serde_json::to_vec(grant).expect("grants serialize");
+    let claims = URL_SAFE_NO_PAD.encode(claims);
+    let tag = hmac::sign(&self.key, claims.as_bytes());
+    format!("{}.{}", claims, URL_SAFE_NO_PAD.encode(tag))
+  }
+
+  /// The grant of `token`, if this key signed it and it hasn't expired.
+  pub fn verify(&self, token: &str) -> Result<Grant> {
+    let malformed = || QvpnError::Unauthenticated("malformed access token".into());
+    let (claims, tag) = token.split_once('.').ok_or_else(malformed)?;
+    let tag = URL_SAFE_NO_PAD.decode(tag).map_err(|_| malformed())?;
+    hmac::verify(&self.key, claims.as_bytes(), &tag)
+      .map_err(|_| QvpnError::Unauthenticated("access token has a bad signature".into()))?;
+    let claims = URL_SAFE_NO_PAD.decode(claims).map_err(|_| malformed())?;
+    let grant: Grant = serde_json::from_slice(&claims).map_err(|_| malformed())?;
+    if grant.expires_at() <= SystemTime::now() {
+      return Err(QvpnError::Unauthenticated("access token expired".into()));
+    }
+    Ok(grant)
+  }
+
+  /// Checks that a request for `path`, with `query` and `headers`, presents
+  /// a token granting access to it.
+  pub fn authorize(
+    &self,
+    path: &str,
+    query: Option<&str>,
+    headers: &[(String, String)],
+  ) -> Result<()> {
+    let token = request_token(query, headers)
+      .ok_or_else(|| QvpnError::Unauthenticated("an access token is required".into()))?;
+    if !self.verify(&token)?.allows(path) {
+      return Err(QvpnError::Unauthenticated(format!(
+        "access token doesn't cover {}",
+        path
+      )));
+    }
+    Ok(())
+  }
+}
+
+impl std::fmt::Debug for SigningKey {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("SigningKey").finish_non_exhaustive()
+  }
+}
+
+/// The token of a request: the `Authorization: Bearer` header, or else the
+/// `token` query parameter.
+pub fn request_token(query: Option<&str>, headers: &[(String, String)]) -> Option<String> {
+  let bearer = http::find_header(headers, "Authorization").and_then(|x| {
+    let (scheme, token) = x.split_once(' ')?;
+    Some(token.trim()).filter(|_| scheme.eq_ignore_ascii_case("bearer"))
+  });
+  match bearer {
+    Some(token) => Some(token.to_string()),
+    None => url::form_urlencoded::parse(query?.as_bytes())
+      .find(|(name, _)| name == "token")
+      .map(|(_, value)| value.into_owned()),
+  }
+}
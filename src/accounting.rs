@@ -0,0 +1,181 @@
+//! Session accounting for the server.
+//!
+//! Every authenticated connection is a session. When it closes, the server
+//! logs a `session ended` event with who the client was, when it was
+//! connected, the tunnel address it leased and the bytes it transferred, and
+//! appends the same record as a JSON line to the accounting file if one is
+//! configured, for usage reports and abuse investigations.
+
+use std::{
+  collections::HashMap,
+  fs::{File, OpenOptions},
+  io::Write,
+  net::{IpAddr, SocketAddr},
+  path::Path,
+  sync::{Arc, Mutex},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{net, stats::Stats, Result};
+
+/// One connection of a client, from the handshake to its close.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+  /// Id of the connection, as in [`Stats`].
+  pub id: usize,
+  /// Address of the client.
+  pub remote: SocketAddr,
+  /// Fingerprint of the client certificate, if it presented one.
+  pub identity: Option<String>,
+  /// User the client logged in as, if it did.
+  pub user: Option<String>,
+  /// Tunnel address leased to the client, if any.
+  pub address: Option<IpAddr>,
+  /// When the handshake completed.
+  pub connected_at: SystemTime,
+  /// When the connection closed.
+  pub disconnected_at: SystemTime,
+  /// UDP payload bytes sent to the client.
+  pub bytes_sent: u64,
+  /// UDP payload bytes received from the client.
+  pub bytes_received: u64,
+}
+
+impl Session {
+  /// How long the client was connected.
+  pub fn duration(&self) -> Duration {
+    self
+      .disconnected_at
+      .duration_since(self.connected_at)
+      .unwrap_or_default()
+  }
+
+  /// The session as a JSON object, with times in seconds since the Unix
+  /// epoch.
+  pub fn to_json(&self) -> Value {
+    json!({
+      "id": self.id,
+      "remote": self.remote.to_string(),
+      "identity": self.identity,
+      "user": self.user,
+      "address": self.address.map(|x| x.to_string()),
+      "connected_at": unix_secs(self.connected_at),
+      "disconnected_at": unix_secs(self.disconnected_at),
+      "duration_secs": self.duration().as_secs_f64(),
+      "bytes_sent": self.bytes_sent,
+      "bytes_received": self.bytes_received,
+    })
+  }
+}
+
+/// Records the sessions of a server, by default only in the log. Cloning
+/// it shares the same records.
+#[derive(Debug, Clone, Default)]
+pub struct Accounting {
+  open: Arc<Mutex<HashMap<usize, Open>>>,
+  file: Option<Arc<Mutex<File>>>,
+}
+
+#[derive(Debug)]
+struct Open {
+  identity: Option<String>,
+  user: Option<String>,
+  address: Option<IpAddr>,
+  connected_at: SystemTime,
+}
+
+impl Accounting {
+  /// Records sessions in the log and also appends each as a JSON line to
+  /// the file at `path`, creating it if needed.
+  pub fn with_file(path: &Path) -> Result<Self> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(Accounting {
+      file: Some(Arc::new(Mutex::new(file))),
+      ..Self::default()
+    })
+  }
+
+  /// Starts the session of `connection`, whose client presented the
+  /// certificate with fingerprint `identity`. It is recorded once the
+  /// connection closes.
+  pub fn open(&self, connection: &quinn::Connection, identity: Option<String>) {
+    let open = Open {
+      identity,
+      user: None,
+      address: None,
+      connected_at: SystemTime::now(),
+    };
+    self.lock().insert(connection.stable_id(), open);
+    let (accounting, connection) = (self.clone(), connection.clone());
+    tokio::spawn(async move {
+      connection.closed().await;
+      accounting.close(&connection);
+    });
+  }
+
+  /// Notes that the client on `connection` logged in as `user`.
+  pub fn logged_in(&self, connection: &quinn::Connection, user: &str) {
+    if let Some(open) = self.lock().get_mut(&connection.stable_id()) {
+      open.user = Some(user.to_string());
+    }
+  }
+
+  /// Notes that `connection` leased the tunnel address `address`.
+  pub fn leased(&self, connection: &quinn::Connection, address: IpAddr) {
+    if let Some(open) = self.lock().get_mut(&connection.stable_id()) {
+      open.address = Some(address);
+    }
+  }
+
+  fn close(&self, connection: &quinn::Connection) {
+    let open = match self.lock().remove(&connection.stable_id()) {
+      Some(open) => open,
+      None => return,
+    };
+    let stats = Stats::of(connection, 0);
+    let session = Session {
+      id: stats.id,
+      remote: net::canonical(connection.remote_address()),
+      identity: open.identity,
+      user: open.user,
+      address: open.address,
+      connected_at: open.connected_at,
+      disconnected_at: SystemTime::now(),
+      bytes_sent: stats.bytes_sent,
+      bytes_received: stats.bytes_received,
+    };
+    self.record(&session);
+  }
+
+  fn record(&self, session: &Session) {
+    info!(
+      remote = %session.remote,
+      identity = session.identity.as_deref().unwrap_or("-"),
+      user = session.user.as_deref().unwrap_or("-"),
+      address = %session.address.map_or("-".to_string(), |x| x.to_string()),
+      duration = ?session.duration(),
+      bytes_sent = session.bytes_sent,
+      bytes_received = session.bytes_received,
+      "session ended"
+    );
+    if let Some(file) = &self.file {
+      let mut line = session.to_json().to_string();
+      line.push('\n');
+      let mut file = file.lock().expect("accounting file lock poisoned");
+      if let Err(err) = file.write_all(line.as_bytes()) {
+        warn!("couldn't write the accounting file: {}", err);
+      }
+    }
+  }
+
+  fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<usize, Open>> {
+    self.open.lock().expect("accounting lock poisoned")
+  }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+  time.duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs())
+}
@@ -0,0 +1,341 @@
+//! Automatic certificates from an ACME CA such as Let's Encrypt.
+//!
+//! The server answers HTTP-01 challenges on a plain TCP listener, usually
+//! port 80, while an order is pending. TLS-ALPN-01 isn't offered: the CA
+//! validates it over TCP, which the QUIC server doesn't listen on. Issued
+//! certificates are cached on disk, renewed [`RENEW_BEFORE`] expiry, and
+//! swapped into the running server through its [`CertResolver`].
+
+use std::{
+  collections::HashMap,
+  fs,
+  io::{self, Write},
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use instant_acme::{
+  Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+  NewAccount, NewOrder, OrderStatus,
+};
+use tokio::{
+  io::AsyncWriteExt,
+  net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+  http,
+  tls::{self, CertResolver},
+  QvpnError, Result,
+};
+
+/// How long before expiry a certificate is renewed.
+pub const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Delay before retrying a failed issuance.
+const RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Longest wait between expiry checks, so a clock change or a replaced
+/// cache file is noticed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Times the order state is polled before giving up on validation.
+const POLL_ATTEMPTS: u32 = 10;
+
+/// Where and how certificates are obtained.
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+  /// Domains the certificate covers; the first names the cache entry.
+  pub domains: Vec<String>,
+  /// Contact URIs for the account, e.g. `mailto:admin@example.com`.
+  pub contact: Vec<String>,
+  /// ACME directory URL.
+  pub directory: String,
+  /// Directory the account and certificates are kept in.
+  pub cache: PathBuf,
+  /// Address to answer HTTP-01 challenges on.
+  pub http_listen: SocketAddr,
+}
+
+impl AcmeConfig {
+  /// Let's Encrypt settings for `domains`, cached under the user's data
+  /// directory.
+  pub fn new(domains: Vec<String>) -> Result<Self> {
+    let dirs = directories_next::ProjectDirs::from("org", "quinn", "quinn-examples")
+      .ok_or_else(|| QvpnError::InvalidInput("no valid home directory found".into()))?;
+    Ok(AcmeConfig {
+      domains,
+      contact: vec![],
+      directory: LetsEncrypt::Production.url().into(),
+      cache: dirs.data_dir().join("acme"),
+      http_listen: SocketAddr::from(([0, 0, 0, 0], 80)),
+    })
+  }
+
+  fn cert_path(&self) -> PathBuf {
+    self.cache.join(format!("{}.crt", self.domains[0]))
+  }
+
+  fn key_path(&self) -> PathBuf {
+    self.cache.join(format!("{}.key", self.domains[0]))
+  }
+
+  fn account_path(&self) -> PathBuf {
+    self.cache.join("account.json")
+  }
+}
+
+/// A certificate and its key, in PEM.
+struct Issued {
+  chain: String,
+  key: String,
+}
+
+impl Issued {
+  fn load(config: &AcmeConfig) -> io::Result<Self> {
+    Ok(Issued {
+      chain: fs::read_to_string(config.cert_path())?,
+      key: fs::read_to_string(config.key_path())?,
+    })
+  }
+
+  fn save(&self, config: &AcmeConfig) -> io::Result<()> {
+    fs::create_dir_all(&config.cache)?;
+    write_private(&config.key_path(), self.key.as_bytes())?;
+    fs::write(config.cert_path(), &self.chain)
+  }
+
+  /// Time left until the leaf certificate expires, zero if it has.
+  fn remaining(&self) -> Result<Duration> {
+    let chain = tls::parse_chain(self.chain.as_bytes(), false)?;
+    let (_, leaf) = x509_parser::parse_x509_certificate(&chain[0])
+      .map_err(|err| QvpnError::InvalidInput(format!("invalid certificate: {}", err)))?;
+    Ok(leaf.validity().time_to_expiration().unwrap_or_default())
+  }
+
+  fn install(&self, resolver: &CertResolver) -> Result<()> {
+    let chain = tls::parse_chain(self.chain.as_bytes(), false)?;
+    let key = tls::parse_key(self.key.as_bytes(), false)?;
+    resolver.set(chain, &key)
+  }
+}
+
+/// Installs the cached certificate into `resolver` if there is one.
+/// Returns whether one was installed.
+pub fn install_cached(config: &AcmeConfig, resolver: &CertResolver) -> Result<bool> {
+  match Issued::load(config) {
+    Ok(issued) => {
+      issued.install(resolver)?;
+      Ok(true)
+    }
+    Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+    Err(err) => Err(err.into()),
+  }
+}
+
+/// Keeps `resolver` serving a valid certificate for `config.domains`,
+/// issuing one when the cache has none and renewing it before it expires.
+pub async fn run(config: AcmeConfig, resolver: Arc<CertResolver>) {
+  loop {
+    let remaining = Issued::load(&config)
+      .map_err(QvpnError::from)
+      .and_then(|issued| issued.remaining());
+    let delay = match remaining {
+      Ok(remaining) if remaining > RENEW_BEFORE => (remaining - RENEW_BEFORE).min(CHECK_INTERVAL),
+      _ => match issue(&config).await {
+        Ok(issued) => match issued.save(&config) {
+          Ok(()) => match issued.install(&resolver) {
+            Ok(()) => {
+              info!(domains = ?config.domains, "certificate issued");
+              continue;
+            }
+            Err(err) => {
+              warn!("couldn't install the issued certificate: {}", err);
+              RETRY_DELAY
+            }
+          },
+          Err(err) => {
+            warn!("couldn't save the issued certificate: {}", err);
+            RETRY_DELAY
+          }
+        },
+        Err(err) => {
+          warn!(domains = ?config.domains, "certificate issuance failed: {}", err);
+          RETRY_DELAY
+        }
+      },
+    };
+    debug!(?delay, "next certificate check");
+    tokio::time::sleep(delay).await;
+  }
+}
+
+/// Orders a certificate, answering the CA's HTTP-01 challenges.
+async fn issue(config: &AcmeConfig) -> Result<Issued> {
+  let account = account(config).await?;
+  let identifiers: Vec<_> = config
+    .domains
+    .iter()
+    .map(|domain| Identifier::Dns(domain.clone()))
+    .collect();
+  let (mut order, state) = account
+    .new_order(&NewOrder {
+      identifiers: &identifiers,
+    })
+    .await
+    .map_err(acme_error)?;
+  info!(domains = ?config.domains, "ordering certificate");
+
+  let tokens = Arc::new(Mutex::new(HashMap::new()));
+  let listener = TcpListener::bind(config.http_listen).await?;
+  let responder = tokio::spawn(serve_challenges(listener, tokens.clone()));
+  let result = async {
+    let authorizations = order
+      .authorizations(&state.authorizations)
+      .await
+      .map_err(acme_error)?;
+    for authorization in &authorizations {
+      if let AuthorizationStatus::Valid = authorization.status {
+        continue;
+      }
+      let challenge = authorization
+        .challenges
+        .iter()
+        .find(|x| x.r#type == ChallengeType::Http01)
+        .ok_or_else(|| QvpnError::Remote("CA offered no http-01 challenge".into()))?;
+      let key_authorization = order.key_authorization(challenge);
+      tokens.lock().expect("challenge lock poisoned").insert(
+        challenge.token.clone(),
+        key_authorization.as_str().to_string(),
+      );
+      order
+        .set_challenge_ready(&challenge.url)
+        .await
+        .map_err(acme_error)?;
+    }
+
+    let mut delay = Duration::from_millis(250);
+    let mut state = order.state().await.map_err(acme_error)?;
+    for _ in 0..POLL_ATTEMPTS {
+      if matches!(state.status, OrderStatus::Ready | OrderStatus::Invalid) {
+        break;
+      }
+      tokio::time::sleep(delay).await;
+      delay *= 2;
+      state = order.state().await.map_err(acme_error)?;
+    }
+    if state.status != OrderStatus::Ready {
+      return Err(QvpnError::Remote(format!(
+        "order is {:?}: {:?}",
+        state.status, state.error
+      )));
+    }
+
+    let key = rcgen::KeyPair::generate()?;
+    let request = rcgen::CertificateParams::new(config.domains.clone())?.serialize_request(&key)?;
+    let chain = order
+      .finalize(request.der(), &state.finalize)
+      .await
+      .map_err(acme_error)?;
+    Ok(Issued {
+      chain,
+      key: key.serialize_pem(),
+    })
+  }
+  .await;
+  responder.abort();
+  result
+}
+
+/// Loads the cached account, or registers a new one and caches it.
+async fn account(config: &AcmeConfig) -> Result<Account> {
+  let path = config.account_path();
+  match fs::read(&path) {
+    Ok(json) => {
+      let credentials: AccountCredentials = serde_json::from_slice(&json)
+        .map_err(|err| QvpnError::InvalidInput(format!("{}: {}", path.display(), err)))?;
+      return Account::from_credentials(credentials).map_err(acme_error);
+    }
+    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+    Err(err) => return Err(err.into()),
+  }
+  let contact: Vec<_> = config.contact.iter().map(String::as_str).collect();
+  let account = Account::create(
+    &NewAccount {
+      contact: &contact,
+      terms_of_service_agreed: true,
+      only_return_existing: false,
+    },
+    &config.directory,
+  )
+  .await
+  .map_err(acme_error)?;
+  let json = serde_json::to_vec(&account.credentials()).expect("credentials serialize");
+  fs::create_dir_all(&config.cache)?;
+  write_private(&path, &json)?;
+  info!(directory = %config.directory, "registered ACME account");
+  Ok(account)
+}
+
+/// Answers `GET /.well-known/acme-challenge/<token>` with the key
+/// authorization of `token`.
+async fn serve_challenges(
+  listener: TcpListener,
+  tokens: Arc<Mutex<HashMap<String, String>>>,
+) -> Result<()> {
+  loop {
+    let (tcp, peer) = listener.accept().await?;
+    let tokens = tokens.clone();
+    tokio::spawn(async move {
+      if let Err(err) = answer_challenge(tcp, &tokens).await {
+        debug!(%peer, "challenge request failed: {}", err);
+      }
+    });
+  }
+}
+
+async fn answer_challenge(
+  mut tcp: TcpStream,
+  tokens: &Mutex<HashMap<String, String>>,
+) -> Result<()> {
+  let (head, _) = http::read_until(&mut tcp, b"\r\n\r\n").await?;
+  let line = head.split(|&c| c == b'\r').next().unwrap_or_default();
+  let body = std::str::from_utf8(line)
+    .ok()
+    .and_then(|line| line.strip_prefix("GET /.well-known/acme-challenge/"))
+    .and_then(|rest| rest.split(' ').next())
+    .and_then(|token| {
+      let tokens = tokens.lock().expect("challenge lock poisoned");
+      tokens.get(token).cloned()
+    });
+  let response = match body {
+    Some(body) => {
+      debug!("answering challenge");
+      format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+      )
+    }
+    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+  };
+  tcp.write_all(response.as_bytes()).await?;
+  tcp.shutdown().await?;
+  Ok(())
+}
+
+fn acme_error(err: instant_acme::Error) -> QvpnError {
+  QvpnError::Remote(format!("ACME: {}", err))
+}
+
+/// Writes a file readable only by the owner.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+  let mut options = fs::OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  options.open(path)?.write_all(contents)
+}
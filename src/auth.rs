@@ -0,0 +1,355 @@
+//! User authentication for tunnel and proxy clients.
+//!
+//! When the server has an [`Authenticator`], clients must log in with a
+//! user name and password on the control stream ([`Message::Authenticate`])
+//! before it leases them a tunnel address or relays their `CONNECT`
+//! requests, or with an ID token if it also takes [`oidc`](crate::oidc)
+//! logins. A failed login closes the connection. The backends are:
+//!
+//! - [`StaticUsers`]: a fixed list, e.g. from the `[auth]` config section.
+//! - [`Htpasswd`]: an htpasswd file, re-read on every login. Entries hashed
+//!   with `{SHA}` or stored in plain text are supported; bcrypt, MD5 and
+//!   crypt entries, and so plain text passwords of 13 characters, are
+//!   skipped with a warning.
+//! - [`Command`]: an external program, given the user name and password on
+//!   standard input one per line, which accepts by exiting with status 0.
+//!   It can check a directory or call a webhook.
+//!
+//! User names with control characters, which could smuggle a different
+//! name past a backend, are refused before any backend sees them.
+//!
+//! [`Message::Authenticate`]: crate::control::Message::Authenticate
+
+use std::{
+  collections::HashMap,
+  fmt, fs,
+  path::{Path, PathBuf},
+  process::Stdio,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::future::BoxFuture;
+use ring::{
+  digest, hmac,
+  rand::{SecureRandom, SystemRandom},
+};
+use tokio::{io::AsyncWriteExt, process};
+use tracing::warn;
+
+use crate::{
+  control::{Control, Message},
+  QvpnError, Result,
+};
+
+/// How long an external command may take to decide.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks user names and passwords.
+pub trait Authenticator: Send + Sync + 'static {
+  /// Whether `password` is the password of `user`. Errors mean the backend
+  /// couldn't decide, and are logged on the server only.
+  fn authenticate<'a>(&'a self, user: &'a str, password: &'a str) -> BoxFuture<'a, Result<bool>>;
+}
+
+impl fmt::Debug for dyn Authenticator {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("Authenticator")
+  }
+}
+
+/// Whether `user` is a name a client may log in as: not empty and without
+/// control characters such as the newline ending a [`Command`]'s user line.
+pub fn valid_user(user: &str) -> bool {
+  !user.is_empty() && !user.chars().any(char::is_control)
+}
+
+/// Compares secrets in constant time, through an HMAC under a random key.
+struct Comparer {
+  key: hmac::Key,
+}
+
+impl Comparer {
+  fn new() -> Result<Self> {
+    let mut secret = [0; 32];
+    SystemRandom::new()
+      .fill(&mut secret)
+      .map_err(|_| QvpnError::InvalidInput("no randomness for the password key".into()))?;
+    Ok(Comparer {
+      key: hmac::Key::new(hmac::HMAC_SHA256, &secret),
+    })
+  }
+
+  fn equal(&self, a: &[u8], b: &[u8]) -> bool {
+    hmac::verify(&self.key, a, hmac::sign(&self.key, b).as_ref()).is_ok()
+  }
+}
+
+/// A fixed list of users and their passwords.
+pub struct StaticUsers {
+  users: HashMap<String, String>,
+  comparer: Comparer,
+}
+
+impl StaticUsers {
+  /// Accepts the users in `users`, which maps names to passwords.
+  pub fn new(users: HashMap<String, String>) -> Result<Self> {
+    if users.values().any(String::is_empty) {
+      return Err(QvpnError::InvalidInput(
+        "auth users must have a password".into(),
+      ));
+    }
+    Ok(StaticUsers {
+      users,
+      comparer: Comparer::new()?,
+    })
+  }
+}
+
+impl Authenticator for StaticUsers {
+  fn authenticate<'a>(&'a self, user: &'a str, password: &'a str) -> BoxFuture<'a, Result<bool>> {
+    let ok = self
+      .users
+      .get(user)
+      .is_some_and(|x| self.comparer.equal(x.as_bytes(), password.as_bytes()));
+    Box::pin(async move { Ok(ok) })
+  }
+}
+
+/// Users in an htpasswd file.
+pub struct Htpasswd {
+  path: PathBuf,
+  comparer: Comparer,
+  /// Lines already warned about, so each is reported once.
+  skipped: Mutex<Vec<usize>>,
+}
+
+impl Htpasswd {
+  /// Checks users against the file at `path`, which must be readable.
+  pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+    let path = path.into();
+    std::fs::metadata(&path)?;
+    Ok(Htpasswd {
+      path,
+      comparer: Comparer::new()?,
+      skipped: Mutex::default(),
+    })
+  }
+
+  fn check(&self, file: &str, user: &str, password: &str) -> bool {
+    for (number, line) in file.lines().enumerate() {
+      let line = line.trim();
+      let (name, hash) = match line.split_once(':') {
+        Some(entry) if !line.starts_with('#') => entry,
+        _ => continue,
+      };
+      if name != user {
+        continue;
+      }
+      if let Some(hash) = hash.strip_prefix("{SHA}") {
+        let digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+        return self
+          .comparer
+          .equal(hash.as_bytes(), STANDARD.encode(digest).as_bytes());
+      }
+      if hash.starts_with('$') || hash.len() == 13 {
+        let mut skipped = self.skipped.lock().expect("htpasswd lock poisoned");
+        if !skipped.contains(&number) {
+          skipped.push(number);
+          warn!(
+            file = %self.path.display(),
+            line = number + 1,
+            "skipping a password hash other than {{SHA}} or plain text"
+          );
+        }
+        return false;
+      }
+      return self.comparer.equal(hash.as_bytes(), password.as_bytes());
+    }
+    false
+  }
+}
+
+impl Authenticator for Htpasswd {
+  fn authenticate<'a>(&'a self, user: &'a str, password: &'a str) -> BoxFuture<'a, Result<bool>> {
+    Box::pin(async move {
+      let file = tokio::fs::read_to_string(&self.path).await?;
+      Ok(self.check(&file, user, password))
+    })
+  }
+}
+
+/// An external program that decides.
+pub struct Command {
+  program: PathBuf,
+  args: Vec<String>,
+}
+
+impl Command {
+  /// Runs `program` with `args` for every login.
+  pub fn new(program: impl Into<PathBuf>, args: Vec<String>) -> Self {
+    Command {
+      program: program.into(),
+      args,
+    }
+  }
+
+  async fn run(&self, user: &str, password: &str) -> Result<bool> {
+    if !valid_user(user) || password.contains(['\n', '\r']) {
+      return Ok(false);
+    }
+    let mut child = process::Command::new(&self.program)
+      .args(&self.args)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::null())
+      .kill_on_drop(true)
+      .spawn()
+      .map_err(|err| QvpnError::InvalidInput(format!("{}: {}", self.program.display(), err)))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    stdin
+      .write_all(format!("{}\n{}\n", user, password).as_bytes())
+      .await?;
+    drop(stdin);
+    let status = tokio::time::timeout(COMMAND_TIMEOUT, child.wait())
+      .await
+      .map_err(|_| QvpnError::Timeout("authentication command"))??;
+    Ok(status.success())
+  }
+}
+
+impl Authenticator for Command {
+  fn authenticate<'a>(&'a self, user: &'a str, password: &'a str) -> BoxFuture<'a, Result<bool>> {
+    Box::pin(self.run(user, password))
+  }
+}
+
+/// User name and password a client logs in with.
+#[derive(Clone)]
+pub struct Credentials {
+  user: String,
+  password: String,
+}
+
+impl Credentials {
+  /// Logs in as `user` with `password`.
+  pub fn new(user: impl Into<String>, password: impl Into<String>) -> Self {
+    Credentials {
+      user: user.into(),
+      password: password.into(),
+    }
+  }
+
+  /// Logs in as `user` with the password in the file at `path`, ignoring a
+  /// trailing newline.
+  pub fn load(user: impl Into<String>, path: &Path) -> Result<Self> {
+    let password = fs::read_to_string(path)?;
+    Ok(Credentials::new(
+      user,
+      password.trim_end_matches(['\r', '\n']),
+    ))
+  }
+
+  /// The user name.
+  pub fn user(&self) -> &str {
+    &self.user
+  }
+
+  /// Logs in on `control` and waits for the server to accept. A rejected
+  /// login is returned as [`QvpnError::Unauthenticated`].
+  pub async fn log_in(&self, control: &mut Control) -> Result<()> {
+    let login = Message::Authenticate {
+      user: self.user.clone(),
+      password: self.password.clone(),
+    };
+    log_in(control, &login).await
+  }
+}
+
+/// Logs in on `control` with the OpenID Connect ID `token`, like
+/// [`Credentials::log_in`]. See [`oidc`](crate::oidc).
+pub async fn present_token(control: &mut Control, token: &str) -> Result<()> {
+  let login = Message::Token {
+    token: token.to_string(),
+  };
+  log_in(control, &login).await
+}
+
+async fn log_in(control: &mut Control, login: &Message) -> Result<()> {
+  control.send(login).await?;
+  loop {
+    match control.recv().await? {
+      Some(Message::Authenticated) => return Ok(()),
+      Some(Message::AuthFailed { reason }) => return Err(QvpnError::Unauthenticated(reason)),
+      Some(Message::Disconnect { reason }) => return Err(QvpnError::Remote(reason)),
+      Some(_) => {}
+      None => {
+        return Err(QvpnError::Protocol(
+          "control stream closed during login".into(),
+        ))
+      }
+    }
+  }
+}
+
+impl fmt::Debug for Credentials {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Credentials")
+      .field("user", &self.user)
+      .finish_non_exhaustive()
+  }
+}
+
+/// Users logged in on each connection. Cloning it shares the same set.
+#[derive(Debug, Clone, Default)]
+pub struct Logins {
+  users: Arc<Mutex<HashMap<usize, String>>>,
+}
+
+impl Logins {
+  /// Records that `user` logged in on `connection`.
+  pub fn insert(&self, connection: &quinn::Connection, user: &str) {
+    self
+      .users
+      .lock()
+      .expect("logins lock poisoned")
+      .insert(connection.stable_id(), user.to_string());
+  }
+
+  /// The user logged in on `connection`, if any.
+  pub fn user(&self, connection: &quinn::Connection) -> Option<String> {
+    let users = self.users.lock().expect("logins lock poisoned");
+    users.get(&connection.stable_id()).cloned()
+  }
+
+  /// Forgets the login on `connection`.
+  pub fn remove(&self, connection: &quinn::Connection) {
+    self
+      .users
+      .lock()
+      .expect("logins lock poisoned")
+      .remove(&connection.stable_id());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn valid_user_refuses_control_characters() {
+    assert!(valid_user("alice"));
+    assert!(valid_user("alice@example.com"));
+    assert!(valid_user("Zoë Smith"));
+    for user in [
+      "",
+      "alice\nhunter2",
+      "alice\r",
+      "alice\0",
+      "\u{1b}[31malice",
+      "alice\u{85}",
+    ] {
+      assert!(!valid_user(user), "{:?}", user);
+    }
+  }
+}
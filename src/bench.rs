@@ -0,0 +1,317 @@
+//! Transport benchmarks between a client and a server.
+//!
+//! `qvpn bench` measures what a connection carries with no disk in the way.
+//! Over streams, each of several `BENCH stream <ms>` requests is answered
+//! with synthetic data for that many milliseconds. Over datagrams, a
+//! `BENCH datagram <ms>` request makes the server send full
+//! [`Kind::Bench`] datagrams for that long, numbered by their id, and then
+//! answer with its [`Counters`], so the client can tell how many were lost.
+//! `BENCH stats` returns the counters alone; taken before and after a run,
+//! they give the loss of the packets the server sent.
+//!
+//! Servers answer these requests only with benchmarks enabled, and for at
+//! most [`MAX_DURATION`] each.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::{
+  datagram::{self, Frame, Kind, Transport},
+  http::ResponseHead,
+  listing, QvpnError, Result,
+};
+
+/// Start of the request line of every benchmark request.
+pub const REQUEST_PREFIX: &[u8] = b"BENCH ";
+
+/// Longest a server sends synthetic data for one request.
+pub const MAX_DURATION: Duration = Duration::from_secs(300);
+
+/// How often the client samples the round-trip time.
+const RTT_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What a benchmark request asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+  /// Synthetic data on the request's stream for this long.
+  Stream(Duration),
+  /// Synthetic data in datagrams for this long, then the server's
+  /// [`Counters`].
+  Datagram(Duration),
+  /// The server's [`Counters`].
+  Stats,
+}
+
+impl Request {
+  /// Formats the request, ending with an empty line.
+  pub fn encode(&self) -> String {
+    match self {
+      Request::Stream(duration) => format!("BENCH stream {}\r\n\r\n", duration.as_millis()),
+      Request::Datagram(duration) => format!("BENCH datagram {}\r\n\r\n", duration.as_millis()),
+      Request::Stats => "BENCH stats\r\n\r\n".into(),
+    }
+  }
+
+  /// Parses a request line, capping durations at [`MAX_DURATION`].
+  pub fn parse(line: &[u8]) -> Result<Request> {
+    let line = std::str::from_utf8(line)
+      .map_err(|_| QvpnError::BadRequest("benchmark request isn't UTF-8".into()))?;
+    let mut parts = line.split_ascii_whitespace().skip(1);
+    let (kind, millis) = (parts.next(), parts.next());
+    let duration = || {
+      millis
+        .and_then(|x| x.parse().ok())
+        .map(|x| Duration::from_millis(x).min(MAX_DURATION))
+        .ok_or_else(|| QvpnError::BadRequest(format!("malformed benchmark request: {}", line)))
+    };
+    match kind {
+      Some("stream") => Ok(Request::Stream(duration()?)),
+      Some("datagram") => Ok(Request::Datagram(duration()?)),
+      Some("stats") => Ok(Request::Stats),
+      _ => Err(QvpnError::BadRequest(format!(
+        "unknown benchmark request: {}",
+        line.trim_end()
+      ))),
+    }
+  }
+}
+
+/// What the server sent on a connection so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counters {
+  /// QUIC packets sent.
+  pub packets_sent: u64,
+  /// QUIC packets declared lost.
+  pub packets_lost: u64,
+  /// Times the congestion window was reduced.
+  pub congestion_events: u64,
+  /// Benchmark datagrams sent, for a `BENCH datagram` request.
+  pub datagrams_sent: u64,
+}
+
+impl Counters {
+  /// Reads the counters of `connection`, which sent `datagrams_sent`
+  /// benchmark datagrams.
+  pub fn of(connection: &quinn::Connection, datagrams_sent: u64) -> Self {
+    let stats = connection.stats();
+    Counters {
+      packets_sent: stats.path.sent_packets,
+      packets_lost: stats.path.lost_packets,
+      congestion_events: stats.path.congestion_events,
+      datagrams_sent,
+    }
+  }
+
+  /// What changed since `earlier`.
+  pub fn since(&self, earlier: &Counters) -> Counters {
+    Counters {
+      packets_sent: self.packets_sent.saturating_sub(earlier.packets_sent),
+      packets_lost: self.packets_lost.saturating_sub(earlier.packets_lost),
+      congestion_events: self
+        .congestion_events
+        .saturating_sub(earlier.congestion_events),
+      datagrams_sent: self.datagrams_sent.saturating_sub(earlier.datagrams_sent),
+    }
+  }
+}
+
+/// How to run a benchmark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+  /// How long the server sends data for.
+  pub duration: Duration,
+  /// Concurrent requests over the stream transport. The datagram transport
+  /// always uses one.
+  pub streams: usize,
+  /// Whether data comes over streams or in datagrams.
+  pub transport: Transport,
+}
+
+impl Default for Options {
+  fn default() -> Self {
+    Options {
+      duration: Duration::from_secs(10),
+      streams: 1,
+      transport: Transport::Stream,
+    }
+  }
+}
+
+/// Results of a benchmark.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+  /// `stream` or `datagram`.
+  pub transport: String,
+  /// Concurrent requests.
+  pub streams: usize,
+  /// Time from the first request until the last data arrived.
+  pub duration_secs: f64,
+  /// Bytes of synthetic data received.
+  pub bytes: u64,
+  /// Synthetic data received per second, in bits.
+  pub goodput_bps: f64,
+  /// Round-trip times sampled during the run.
+  pub rtt: Rtt,
+  /// Packets and datagrams lost on their way from the server.
+  pub loss: Loss,
+}
+
+/// Distribution of round-trip time samples, in milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Rtt {
+  pub samples: usize,
+  pub min_ms: f64,
+  pub mean_ms: f64,
+  pub p50_ms: f64,
+  pub p90_ms: f64,
+  pub p99_ms: f64,
+  pub max_ms: f64,
+}
+
+impl Rtt {
+  /// Summarizes `samples`.
+  pub fn from_samples(mut samples: Vec<Duration>) -> Self {
+    if samples.is_empty() {
+      return Rtt::default();
+    }
+    samples.sort();
+    let ms = |x: Duration| x.as_secs_f64() * 1000.0;
+    // Nearest-rank percentiles.
+    let percentile = |p: f64| {
+      let rank = (p * samples.len() as f64).ceil() as usize;
+      ms(samples[rank.clamp(1, samples.len()) - 1])
+    };
+    let total: Duration = samples.iter().sum();
+    Rtt {
+      samples: samples.len(),
+      min_ms: ms(samples[0]),
+      mean_ms: ms(total) / samples.len() as f64,
+      p50_ms: percentile(0.5),
+      p90_ms: percentile(0.9),
+      p99_ms: percentile(0.99),
+      max_ms: ms(samples[samples.len() - 1]),
+    }
+  }
+}
+
+/// Loss on the way from the server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Loss {
+  /// QUIC packets the server sent during the run.
+  pub packets_sent: u64,
+  /// Of those, declared lost.
+  pub packets_lost: u64,
+  /// Fraction of the packets lost.
+  pub packet_loss: f64,
+  /// Times the server reduced its congestion window.
+  pub congestion_events: u64,
+  /// Datagrams the server sent, with the datagram transport.
+  pub datagrams_sent: Option<u64>,
+  /// Of those, received.
+  pub datagrams_received: Option<u64>,
+  /// Fraction of the datagrams lost.
+  pub datagram_loss: Option<f64>,
+}
+
+impl Loss {
+  /// Loss from the server's `counters` for the run and, with the datagram
+  /// transport, the number of datagrams `received`.
+  pub fn new(counters: &Counters, received: Option<u64>) -> Self {
+    Loss {
+      packets_sent: counters.packets_sent,
+      packets_lost: counters.packets_lost,
+      packet_loss: fraction(counters.packets_lost, counters.packets_sent),
+      congestion_events: counters.congestion_events,
+      datagrams_sent: received.map(|_| counters.datagrams_sent),
+      datagrams_received: received,
+      datagram_loss: received.map(|received| {
+        fraction(
+          counters.datagrams_sent.saturating_sub(received),
+          counters.datagrams_sent,
+        )
+      }),
+    }
+  }
+}
+
+fn fraction(part: u64, whole: u64) -> f64 {
+  if whole == 0 {
+    return 0.0;
+  }
+  (part as f64 / whole as f64).min(1.0)
+}
+
+/// Samples the round-trip time of `connection` for `duration`.
+pub async fn sample_rtt(connection: &quinn::Connection, duration: Duration) -> Vec<Duration> {
+  let end = Instant::now() + duration;
+  let mut interval = tokio::time::interval(RTT_INTERVAL);
+  let mut samples = vec![];
+  while Instant::now() < end {
+    interval.tick().await;
+    samples.push(connection.rtt());
+  }
+  samples
+}
+
+/// Answers `request` on `send`, sending synthetic data in parts of
+/// `chunk_size` bytes. Returns the number of bytes of synthetic data sent.
+pub async fn serve(
+  request: Request,
+  connection: &quinn::Connection,
+  send: &mut quinn::SendStream,
+  chunk_size: usize,
+) -> Result<u64> {
+  let (duration, datagrams) = match request {
+    Request::Stream(duration) => (duration, false),
+    Request::Datagram(duration) => (duration, true),
+    Request::Stats => {
+      write_counters(send, &Counters::of(connection, 0)).await?;
+      return Ok(0);
+    }
+  };
+  let end = Instant::now() + duration;
+  let mut sent = 0;
+  if datagrams {
+    let max = datagram::max_payload(connection)
+      .ok_or_else(|| QvpnError::Unsupported("the client doesn't accept datagrams".into()))?;
+    let data = Bytes::from(vec![0; max]);
+    let mut id = 0;
+    while Instant::now() < end {
+      // The path MTU may change during the run.
+      let len = datagram::max_payload(connection).unwrap_or(0).min(max);
+      let frame = Frame {
+        kind: Kind::Bench,
+        id,
+        payload: data.slice(..len),
+      };
+      connection.send_datagram_wait(frame.encode()).await?;
+      id = id.wrapping_add(1);
+      sent += len as u64;
+    }
+    // Queued datagrams are only counted by quinn once they go out.
+    let queued = u64::from(id);
+    write_counters(send, &Counters::of(connection, queued)).await?;
+  } else {
+    let head = ResponseHead::new(200, "OK").header("Content-Type", "application/octet-stream");
+    send.write_all(head.encode().as_bytes()).await?;
+    let data = Bytes::from(vec![0; chunk_size]);
+    while Instant::now() < end {
+      send.write_chunk(data.clone()).await?;
+      sent += data.len() as u64;
+    }
+  }
+  Ok(sent)
+}
+
+async fn write_counters(send: &mut quinn::SendStream, counters: &Counters) -> Result<()> {
+  let body = serde_json::to_string(counters).expect("counters serialize");
+  let head = ResponseHead::new(200, "OK")
+    .header("Content-Type", listing::Format::Json.mime())
+    .header("Content-Length", body.len());
+  send.write_all(head.encode().as_bytes()).await?;
+  send.write_all(body.as_bytes()).await?;
+  Ok(())
+}
@@ -0,0 +1,340 @@
+//! `qvpn trust`, `token`, `systemd-unit`, `completions`, `man`, and, on Unix, `ctl`
+//! or, on Windows, `service`.
+
+use std::{env, fs, io, path::PathBuf, time::Duration};
+
+use clap::{error::ErrorKind, Args, Command, Subcommand};
+use clap_complete::Shell;
+#[cfg(unix)]
+use serde_json::{json, Value};
+
+#[cfg(unix)]
+use qvpn::manage;
+use qvpn::{
+  access::{Grant, SigningKey},
+  config::{parse_duration, Config, TrustSection},
+  known_hosts::KnownHosts,
+  service,
+};
+
+use crate::exit;
+
+/// Commands that can run as a service.
+const SERVICE_COMMANDS: [&str; 3] = ["serve", "tunnel", "proxy"];
+
+#[derive(Args, Debug)]
+pub struct TrustOpt {
+  /// File recording the keys of trusted hosts [default: in the data
+  /// directory]
+  #[arg(long = "known-hosts", env = "QVPN_KNOWN_HOSTS")]
+  known_hosts: Option<PathBuf>,
+  #[command(subcommand)]
+  command: TrustCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum TrustCommand {
+  /// List trusted hosts and their key fingerprints
+  List,
+  /// Forget a host, so the key it presents next is trusted again
+  Forget {
+    /// Server name, or peer address in the form 127.0.0.1:1234
+    host: String,
+  },
+}
+
+#[derive(Args, Debug)]
+pub struct TokenOpt {
+  /// File with the key the server signs tokens with [default: the
+  /// configured token_key]
+  #[arg(long = "token-key", env = "QVPN_TOKEN_KEY")]
+  token_key: Option<PathBuf>,
+  #[command(subcommand)]
+  command: TokenCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum TokenCommand {
+  /// Print a token granting downloads of some paths for a while
+  Create {
+    /// How long the token is valid, e.g. `1h`
+    #[arg(long = "ttl", value_parser = parse_duration)]
+    ttl: Duration,
+    /// Path the token grants, where `*` matches anything, e.g. `/pub/*`.
+    /// May be repeated
+    #[arg(long = "paths", value_delimiter = ',', required = true)]
+    paths: Vec<String>,
+  },
+}
+
+#[cfg(unix)]
+#[derive(Args, Debug)]
+pub struct CtlOpt {
+  /// Management socket of the server [default: the configured
+  /// admin_socket]
+  #[arg(long = "socket", env = "QVPN_ADMIN_SOCKET")]
+  socket: Option<PathBuf>,
+  #[command(subcommand)]
+  command: CtlCommand,
+}
+
+#[cfg(unix)]
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+  /// List connected clients
+  Clients,
+  /// Disconnect a client
+  Kick {
+    /// Id of the client, as listed by `clients`
+    id: usize,
+    /// Reason the client is told
+    #[arg(long = "reason")]
+    reason: Option<String>,
+  },
+  /// Print the transfer statistics of every connection
+  Stats,
+  /// Re-read the tunnel routes and resolvers from the server's config file
+  /// and push them to clients
+  Reload,
+  /// Reload the certificate files without waiting for the next check
+  RotateCerts,
+}
+
+#[derive(Args, Debug)]
+pub struct SystemdUnitOpt {
+  /// Command the unit runs
+  #[arg(default_value = "serve", value_parser = SERVICE_COMMANDS)]
+  command: String,
+  /// Restart the service if it stops responding for this long, e.g. `1m`
+  /// [default: 30s]
+  #[arg(long = "watchdog", env = "QVPN_WATCHDOG", value_parser = parse_duration)]
+  watchdog: Option<Duration>,
+}
+
+#[derive(Args, Debug)]
+pub struct CompletionsOpt {
+  /// Shell to complete commands for
+  #[arg(value_enum)]
+  shell: Shell,
+}
+
+pub fn trust(options: TrustOpt, file: Config) -> ! {
+  let config = Config {
+    trust: TrustSection {
+      known_hosts: options.known_hosts,
+    },
+    ..Default::default()
+  }
+  .merge(file);
+  let path = config.known_hosts_path().unwrap_or_else(|err| exit(err));
+  let known_hosts = KnownHosts::new(path);
+  match options.command {
+    TrustCommand::List => {
+      for (host, fingerprint) in known_hosts.entries().unwrap_or_else(|err| exit(err)) {
+        println!("{} {}", host, fingerprint);
+      }
+    }
+    TrustCommand::Forget { host } => match known_hosts.forget(&host) {
+      Ok(true) => println!("forgot {}", host),
+      Ok(false) => exit(format!(
+        "{} is not in {}",
+        host,
+        known_hosts.path().display()
+      )),
+      Err(err) => exit(err),
+    },
+  }
+  std::process::exit(0);
+}
+
+pub fn token(options: TokenOpt, file: Config) -> ! {
+  let path = options
+    .token_key
+    .or(file.server.token_key)
+    .unwrap_or_else(|| exit("no token key, pass --token-key or set server.token_key"));
+  let key = SigningKey::load(&path).unwrap_or_else(|err| exit(err));
+  match options.command {
+    TokenCommand::Create { ttl, paths } => {
+      if let Some(path) = paths.iter().find(|x| !x.starts_with('/')) {
+        exit(format!("{} is not an absolute path", path));
+      }
+      println!("{}", key.sign(&Grant::new(paths, ttl)));
+    }
+  }
+  std::process::exit(0);
+}
+
+#[cfg(unix)]
+pub fn ctl(options: CtlOpt, file: Config) -> ! {
+  let path = options
+    .socket
+    .or(file.server.admin_socket)
+    .unwrap_or_else(|| exit("no management socket, pass --socket or set server.admin_socket"));
+  let (method, params) = match options.command {
+    CtlCommand::Clients => ("clients", Value::Null),
+    CtlCommand::Kick { id, reason } => ("kick", json!({ "id": id, "reason": reason })),
+    CtlCommand::Stats => ("stats", Value::Null),
+    CtlCommand::Reload => ("reload", Value::Null),
+    CtlCommand::RotateCerts => ("rotate_certs", Value::Null),
+  };
+  let runtime = tokio::runtime::Runtime::new().unwrap_or_else(|err| exit(err));
+  let result = runtime
+    .block_on(manage::call(&path, method, params))
+    .unwrap_or_else(|err| exit(err));
+  println!(
+    "{}",
+    serde_json::to_string_pretty(&result).expect("JSON values serialize")
+  );
+  std::process::exit(0);
+}
+
+pub fn systemd_unit(options: SystemdUnitOpt, config: Option<PathBuf>) -> ! {
+  let config = canonical_config(config, "systemd-unit");
+  let exec = env::current_exe().unwrap_or_else(|err| exit(err));
+  print!(
+    "{}",
+    service::systemd_unit(
+      &format!("qvpn {}", options.command),
+      &exec,
+      &["--config", &config.to_string_lossy(), &options.command],
+      options.watchdog.unwrap_or(service::DEFAULT_WATCHDOG),
+    )
+  );
+  std::process::exit(0);
+}
+
+/// Prints the completion script of `command` for the chosen shell.
+pub fn completions(options: CompletionsOpt, mut command: Command) -> ! {
+  clap_complete::generate(options.shell, &mut command, "qvpn", &mut io::stdout());
+  std::process::exit(0);
+}
+
+/// Prints a man page with the help of every subcommand of the command
+/// `build` returns.
+pub fn man(build: fn() -> Command) -> ! {
+  let command = build();
+  let about = command.get_about().map(ToString::to_string);
+  let mut page = format!(
+    ".TH QVPN 1 \"\" \"qvpn {}\"\n.SH NAME\nqvpn \\- {}\n",
+    command.get_version().unwrap_or_default(),
+    roff(&about.unwrap_or_default())
+  );
+  page.push_str(".SH DESCRIPTION\n");
+  page.push_str(&preformatted(&help(build, &[])));
+  page.push_str(".SH COMMANDS\n");
+  document_commands(build, &command, &[], &mut page);
+  print!("{}", page);
+  std::process::exit(0);
+}
+
+/// Adds the help of the subcommands of `command`, at `path`, to `page`.
+fn document_commands(
+  build: fn() -> Command,
+  command: &Command,
+  path: &[String],
+  page: &mut String,
+) {
+  for sub in command.get_subcommands() {
+    let mut path = path.to_vec();
+    path.push(sub.get_name().to_string());
+    page.push_str(&format!(".SS qvpn {}\n", path.join(" ")));
+    page.push_str(&preformatted(&help(build, &path)));
+    document_commands(build, sub, &path, page);
+  }
+}
+
+/// Help of the command at `path`, as `--help` prints it.
+fn help(build: fn() -> Command, path: &[String]) -> String {
+  let args = std::iter::once("qvpn")
+    .chain(path.iter().map(String::as_str))
+    .chain(Some("--help"));
+  match build().term_width(80).try_get_matches_from(args) {
+    Err(err) if err.kind() == ErrorKind::DisplayHelp => err.render().to_string(),
+    _ => unreachable!("--help displays help"),
+  }
+}
+
+/// `text` as an unfilled roff block.
+fn preformatted(text: &str) -> String {
+  let mut block = String::from(".nf\n");
+  for line in text.trim_end().lines() {
+    block.push_str(&roff(line));
+    block.push('\n');
+  }
+  block.push_str(".fi\n");
+  block
+}
+
+/// Escapes `line` for roff.
+fn roff(line: &str) -> String {
+  let line = line.replace('\\', "\\e");
+  if line.starts_with('.') || line.starts_with('\'') {
+    format!("\\&{}", line)
+  } else {
+    line
+  }
+}
+
+/// The absolute path of the `--config` file `command` needs.
+fn canonical_config(config: Option<PathBuf>, command: &str) -> PathBuf {
+  let config = config.unwrap_or_else(|| exit(format!("{} needs --config", command)));
+  fs::canonicalize(&config).unwrap_or_else(|err| exit(format!("{}: {}", config.display(), err)))
+}
+
+#[cfg(windows)]
+#[derive(Subcommand, Debug)]
+pub enum ServiceOpt {
+  /// Register the service, which starts at boot and restarts on failure
+  Install {
+    /// Command the service runs
+    #[arg(default_value = "serve", value_parser = ["serve", "tunnel"])]
+    command: String,
+  },
+  /// Stop and remove the service
+  Uninstall,
+  /// Run as the service; the service manager starts this
+  Run {
+    /// Command the service runs
+    #[arg(default_value = "serve", value_parser = ["serve", "tunnel"])]
+    command: String,
+  },
+}
+
+#[cfg(windows)]
+pub fn windows_service(options: ServiceOpt, path: Option<PathBuf>, config: Config) -> ! {
+  use service::windows;
+
+  match options {
+    ServiceOpt::Install { command } => {
+      let path = canonical_config(path, "service install");
+      let args = vec![
+        "--config".into(),
+        path.into_os_string(),
+        "service".into(),
+        "run".into(),
+        command.clone().into(),
+      ];
+      let exe = env::current_exe().unwrap_or_else(|err| exit(err));
+      windows::install(exe, args, &format!("qvpn {}", command)).unwrap_or_else(|err| exit(err));
+      println!("installed the {} service", windows::SERVICE_NAME);
+    }
+    ServiceOpt::Uninstall => {
+      windows::uninstall().unwrap_or_else(|err| exit(err));
+      println!("removed the {} service", windows::SERVICE_NAME);
+    }
+    ServiceOpt::Run { command } => {
+      windows::init_log(config.log.level.as_deref()).unwrap_or_else(|err| exit(err));
+      let result = windows::run(move |shutdown| {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(async move {
+          match command.as_str() {
+            "tunnel" => crate::client::tunnel(config, shutdown.wait()).await,
+            _ => crate::serve::serve(config, path, shutdown.wait()).await,
+          }
+        })
+      });
+      result.unwrap_or_else(|err| exit(err));
+    }
+  }
+  std::process::exit(0);
+}
@@ -0,0 +1,595 @@
+//! `qvpn get`, `put`, `tunnel`, `proxy` and `bench`.
+
+use std::{
+  fs,
+  future::Future,
+  net::{IpAddr, SocketAddr},
+  path::{Path, PathBuf},
+  time::{Duration, Instant},
+};
+
+use clap::Args;
+use indicatif::{ProgressBar, ProgressStyle};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info};
+use url::Url;
+
+use qvpn::{
+  bench,
+  client::Body,
+  config::{parse_duration, Config, TunnelSection},
+  datagram::Transport,
+  forward, integrity,
+  lease::Ipv4Net,
+  listing::Format,
+  site::Firewall,
+  udp::Forward,
+  QvpnError,
+};
+
+use crate::opts::{ClientOpts, ServiceOpts};
+
+#[derive(Args, Debug)]
+pub struct GetOpt {
+  /// URL to fetch [default: the configured url]
+  url: Option<Url>,
+  #[command(flatten)]
+  client: ClientOpts,
+  /// How to carry the request: `stream` or `datagram` [default: stream]
+  #[arg(long = "transport", env = "QVPN_TRANSPORT")]
+  transport: Option<Transport>,
+  /// Directory listing format: `html` or `json` [default: html]
+  #[arg(long = "format", env = "QVPN_FORMAT")]
+  format: Option<Format>,
+  /// Access token to present, for servers that require one. A `token`
+  /// query parameter in the URL does the same
+  #[arg(long = "token", env = "QVPN_TOKEN")]
+  token: Option<String>,
+  /// Ask for the file compressed, which servers run with `--compress` do
+  /// unless it's a ranged request or its type already is compressed
+  #[arg(long = "compress", env = "QVPN_COMPRESS")]
+  compress: bool,
+  /// Ask the server for the file's SHA-256 and fail unless the download
+  /// matches it
+  #[arg(long = "verify", env = "QVPN_VERIFY", conflicts_with = "transport")]
+  verify: bool,
+  /// Keep downloads in this directory and transfer them again only if they
+  /// changed on the server
+  #[arg(long = "cache", env = "QVPN_CACHE")]
+  cache: Option<PathBuf>,
+  /// Write the response body to this file instead of stdout
+  #[arg(short = 'o', long = "output", env = "QVPN_OUTPUT")]
+  output: Option<PathBuf>,
+  /// Continue a partial download in `--output` by requesting only the
+  /// missing bytes
+  #[arg(long = "resume", env = "QVPN_RESUME", requires = "output")]
+  resume: bool,
+  /// Download `--output` over this many concurrent range requests
+  #[arg(
+    long = "streams",
+    env = "QVPN_STREAMS",
+    default_value = "1",
+    conflicts_with = "resume"
+  )]
+  streams: usize,
+}
+
+/// Where and how `get` saves the response.
+pub struct Download {
+  output: Option<PathBuf>,
+  resume: bool,
+  streams: usize,
+}
+
+impl GetOpt {
+  pub fn into_config(self) -> (Config, Download) {
+    let mut config = self.client.into_config();
+    config.client.url = self.url;
+    config.client.format = self.format;
+    config.client.access_token = self.token;
+    config.client.compress = Some(self.compress).filter(|x| *x);
+    config.client.verify = Some(self.verify).filter(|x| *x);
+    config.client.cache = self.cache;
+    config.transport.mode = self.transport;
+    let download = Download {
+      output: self.output,
+      resume: self.resume,
+      streams: self.streams,
+    };
+    (config, download)
+  }
+}
+
+#[derive(Args, Debug)]
+pub struct PutOpt {
+  /// File to upload, or directory whose missing and changed files to upload
+  path: PathBuf,
+  /// URL to upload to, a directory when syncing [default: the configured
+  /// url]
+  url: Option<Url>,
+  #[command(flatten)]
+  client: ClientOpts,
+  /// Upload this many files of a directory at once
+  #[arg(long = "streams", env = "QVPN_STREAMS", default_value = "1")]
+  streams: usize,
+}
+
+/// What `put` uploads.
+pub struct Upload {
+  path: PathBuf,
+  streams: usize,
+}
+
+impl PutOpt {
+  pub fn into_config(self) -> (Config, Upload) {
+    let mut config = self.client.into_config();
+    config.client.url = self.url;
+    let upload = Upload {
+      path: self.path,
+      streams: self.streams,
+    };
+    (config, upload)
+  }
+}
+
+#[derive(Args, Debug)]
+pub struct TunnelOpt {
+  /// URL of the server [default: the configured url]
+  url: Option<Url>,
+  #[command(flatten)]
+  client: ClientOpts,
+  /// Name of the TUN interface [default: qvpn0]
+  #[arg(long = "tun", env = "QVPN_TUN")]
+  tun: Option<String>,
+  /// Use the already open TUN interface with this descriptor, configured by
+  /// the program that passed it, instead of creating one. Unix only
+  #[arg(long = "tun-fd", env = "QVPN_TUN_FD", conflicts_with = "tun_fd_socket")]
+  tun_fd: Option<i32>,
+  /// Send each lease, as a line of JSON, to the program listening on this
+  /// Unix socket and use the TUN descriptor it passes back, e.g. from an
+  /// Android `VpnService`. Unix only
+  #[arg(long = "tun-fd-socket", env = "QVPN_TUN_FD_SOCKET")]
+  tun_fd_socket: Option<PathBuf>,
+  /// Route all IPv4 traffic through the tunnel, not just the networks the
+  /// server pushes
+  #[arg(long = "full-tunnel", env = "QVPN_FULL_TUNNEL")]
+  full_tunnel: bool,
+  /// Server to fail over to when the current one stops responding, in order
+  /// of preference. May be repeated
+  #[arg(long = "failover", env = "QVPN_FAILOVER", value_delimiter = ',')]
+  failover: Vec<Url>,
+  /// Consider the server unresponsive when it leaves a keepalive unanswered
+  /// this long, e.g. `5s` [default: 10s]
+  #[arg(long = "health-timeout", env = "QVPN_HEALTH_TIMEOUT", value_parser = parse_duration)]
+  health_timeout: Option<Duration>,
+  /// Experimental: also carry the tunnel over a connection from this local
+  /// address, e.g. the one on a second network. May be repeated
+  #[arg(long = "bond", env = "QVPN_BOND", value_delimiter = ',')]
+  bond: Vec<IpAddr>,
+  /// Be the gateway of this network behind the client, e.g.
+  /// `192.168.1.0/24`, if the server allows it. May be repeated
+  #[arg(long = "advertise", env = "QVPN_ADVERTISE", value_delimiter = ',')]
+  advertise: Vec<Ipv4Net>,
+  /// Masquerade traffic leaving the tunnel for other interfaces with
+  /// `iptables` or `nftables`, so hosts behind `--advertise` need no route
+  /// back. Linux only
+  #[arg(long = "masquerade", env = "QVPN_MASQUERADE")]
+  masquerade: Option<Firewall>,
+  /// Block everything the host sends other than through the tunnel and to
+  /// the servers, even while it reconnects, with `iptables` or `nftables`
+  /// [default: nftables]. Linux only
+  #[arg(
+    long = "kill-switch",
+    env = "QVPN_KILL_SWITCH",
+    num_args = 0..=1,
+    default_missing_value = "nftables"
+  )]
+  kill_switch: Option<Firewall>,
+  /// Shell command to run each time the tunnel comes up, with `QVPN_*`
+  /// environment variables describing the lease
+  #[arg(long = "up", env = "QVPN_UP")]
+  up: Option<String>,
+  /// Shell command to run each time the tunnel goes down
+  #[arg(long = "down", env = "QVPN_DOWN")]
+  down: Option<String>,
+  #[command(flatten)]
+  service: ServiceOpts,
+}
+
+impl TunnelOpt {
+  pub fn into_config(self) -> Config {
+    let mut config = self.client.into_config();
+    config.client.url = self.url;
+    config.tunnel = TunnelSection {
+      name: self.tun,
+      fd: self.tun_fd,
+      fd_socket: self.tun_fd_socket,
+      full_tunnel: Some(self.full_tunnel).filter(|x| *x),
+      failover: self.failover,
+      health_timeout_ms: self.health_timeout.map(|x| x.as_millis() as u64),
+      bond: self.bond,
+      advertise: self.advertise,
+      masquerade: self.masquerade,
+      kill_switch: self.kill_switch,
+      up: self.up,
+      down: self.down,
+      ..Default::default()
+    };
+    config.service = self.service.into_section();
+    config
+  }
+}
+
+#[derive(Args, Debug)]
+pub struct ProxyOpt {
+  /// URL of the server [default: the configured url]
+  url: Option<Url>,
+  #[command(flatten)]
+  client: ClientOpts,
+  /// Accept SOCKS5 connections on this address and tunnel them to the
+  /// server
+  #[arg(long = "socks5", env = "QVPN_SOCKS5", conflicts_with_all = ["http_proxy", "dns_stub"])]
+  socks5: Option<SocketAddr>,
+  /// Accept HTTP CONNECT requests on this address and tunnel them to the
+  /// server
+  #[arg(
+    long = "http-proxy",
+    env = "QVPN_HTTP_PROXY",
+    conflicts_with = "dns_stub"
+  )]
+  http_proxy: Option<SocketAddr>,
+  /// Answer DNS queries on this address, e.g. `127.0.0.1:53`, by relaying
+  /// them to the DNS over QUIC server
+  #[arg(long = "dns-stub", env = "QVPN_DNS_STUB")]
+  dns_stub: Option<SocketAddr>,
+  /// Forward UDP datagrams received on a local port to a target reachable
+  /// from the server, e.g. `127.0.0.1:5000=game.example:5000`, alone or
+  /// alongside the proxy. May be repeated
+  #[arg(long = "forward-udp", env = "QVPN_FORWARD_UDP", value_delimiter = ',')]
+  forward_udp: Vec<Forward>,
+  /// Forward TCP connections accepted on a local port to a target the
+  /// server connects to, as `[addr:]port=host:port`, e.g.
+  /// `8080=intranet:80`, like `ssh -L`. May be repeated
+  #[arg(long = "forward", env = "QVPN_FORWARD", value_delimiter = ',')]
+  forward: Vec<forward::Local>,
+  /// Have the server listen on a port its rules allow and forward the
+  /// connections to a local service, as `port=[host:]port`, e.g.
+  /// `8080=3000`, like `ssh -R`. May be repeated
+  #[arg(
+    long = "reverse-forward",
+    env = "QVPN_REVERSE_FORWARD",
+    value_delimiter = ','
+  )]
+  reverse_forward: Vec<forward::Reverse>,
+  #[command(flatten)]
+  service: ServiceOpts,
+}
+
+impl ProxyOpt {
+  pub fn into_config(self) -> Config {
+    let mut config = self.client.into_config();
+    config.client.url = self.url;
+    config.client.socks5 = self.socks5;
+    config.client.http_proxy = self.http_proxy;
+    config.client.dns_stub = self.dns_stub;
+    config.client.forward_udp = self.forward_udp;
+    config.client.forward = self.forward;
+    config.client.reverse_forward = self.reverse_forward;
+    config.service = self.service.into_section();
+    config
+  }
+}
+
+#[derive(Args, Debug)]
+pub struct ExposeOpt {
+  /// URL of the server [default: the configured url]
+  url: Option<Url>,
+  #[command(flatten)]
+  client: ClientOpts,
+  /// Name to share the service under, e.g. `demo` for
+  /// `demo.<server domain>`
+  #[arg(long = "name", env = "QVPN_EXPOSE_NAME")]
+  name: Option<String>,
+  /// `host:port` of the local service, e.g. `127.0.0.1:3000`
+  #[arg(long = "target", env = "QVPN_EXPOSE_TARGET")]
+  target: Option<String>,
+  #[command(flatten)]
+  service: ServiceOpts,
+}
+
+impl ExposeOpt {
+  pub fn into_config(self) -> Config {
+    let mut config = self.client.into_config();
+    config.client.url = self.url;
+    config.client.expose = self.name;
+    config.client.expose_target = self.target;
+    config.service = self.service.into_section();
+    config
+  }
+}
+
+#[derive(Args, Debug)]
+pub struct BenchOpt {
+  /// URL of a server run with `--bench` [default: the configured url]
+  url: Option<Url>,
+  #[command(flatten)]
+  client: ClientOpts,
+  /// How to carry the data: `stream` or `datagram` [default: stream]
+  #[arg(long = "transport", env = "QVPN_TRANSPORT")]
+  transport: Option<Transport>,
+  /// How long the server sends data for, e.g. `30s`
+  #[arg(long = "duration", env = "QVPN_DURATION", default_value = "10s", value_parser = parse_duration)]
+  duration: Duration,
+  /// Receive data over this many concurrent streams; datagrams always
+  /// come from one request
+  #[arg(long = "streams", env = "QVPN_STREAMS", default_value = "1")]
+  streams: usize,
+}
+
+impl BenchOpt {
+  pub fn into_config(self) -> (Config, bench::Options) {
+    let mut config = self.client.into_config();
+    config.client.url = self.url;
+    config.transport.mode = self.transport;
+    let options = bench::Options {
+      duration: self.duration,
+      streams: self.streams,
+      ..Default::default()
+    };
+    (config, options)
+  }
+}
+
+/// Fetches the configured url to stdout or a file.
+pub async fn get(config: Config, download: Download) -> qvpn::Result<()> {
+  let Download {
+    output,
+    resume,
+    streams,
+  } = download;
+  let url = url(&config)?;
+  let host = config.client.host.as_deref();
+  let client = config.client_builder()?.build()?;
+
+  if let (Some(path), true) = (&output, streams > 1) {
+    let progress = progress_bar(Some(0), 0);
+    let start = Instant::now();
+    let received = client
+      .download_parallel(&url, host, path, streams, |len, total| {
+        progress.set_length(total);
+        progress.inc(len);
+      })
+      .await?;
+    progress.finish();
+    let elapsed = start.elapsed();
+    info!(
+      bytes = received,
+      streams,
+      ?elapsed,
+      "MiB/s" = received as f32 / (elapsed.as_secs_f32() * 1024.0 * 1024.0),
+      "download complete"
+    );
+    client.wait_idle().await;
+    return Ok(());
+  }
+
+  let offset = match &output {
+    Some(path) if resume => fs::metadata(path).map(|meta| meta.len()).unwrap_or(0),
+    _ => 0,
+  };
+  let (head, mut body) = client.stream(&url, host, offset).await?;
+  info!(status = %head, "response");
+  for (name, value) in &head.headers {
+    debug!("{}: {}", name, value);
+  }
+  let append = match head.status {
+    200 => false,
+    206 => true,
+    416 if offset > 0 && head.get("Content-Range") == Some(&format!("bytes */{}", offset)) => {
+      info!("already complete");
+      return Ok(());
+    }
+    _ => return Err(QvpnError::Remote(head.to_string())),
+  };
+  let received = match &output {
+    Some(path) => save(&mut body, path, append, offset).await?,
+    None => copy_to_stdout(&mut body).await?,
+  };
+  // Whole bodies are checked as they arrive, resumed ones once complete.
+  if let (Some(path), true, Some(digest)) = (&output, append, integrity::expected(&head)) {
+    if config.client.verify == Some(true) {
+      integrity::verify_file(path, &digest).await?;
+      info!("verified");
+    }
+  }
+  let elapsed = body.elapsed();
+  info!(
+    bytes = received,
+    ?elapsed,
+    "MiB/s" = received as f32 / (elapsed.as_secs_f32() * 1024.0 * 1024.0),
+    "response received"
+  );
+
+  // Give the server a fair chance to receive the close packet
+  client.wait_idle().await;
+  Ok(())
+}
+
+/// Uploads a file to the configured url, or syncs a directory to it.
+pub async fn put(config: Config, upload: Upload) -> qvpn::Result<()> {
+  let url = url(&config)?;
+  let host = config.client.host.as_deref();
+  let client = config.client_builder()?.build()?;
+  if upload.path.is_dir() {
+    let start = Instant::now();
+    let summary = client
+      .sync(&url, host, &upload.path, upload.streams)
+      .await?;
+    info!(
+      uploaded = summary.uploaded,
+      files = summary.scanned,
+      bytes = summary.bytes,
+      elapsed = ?start.elapsed(),
+      "synced"
+    );
+  } else {
+    let head = client.put(&url, host, &upload.path).await?;
+    info!(status = %head, "uploaded");
+  }
+  client.wait_idle().await;
+  Ok(())
+}
+
+/// Tunnels IP packets to the configured url until `shutdown` completes.
+pub async fn tunnel(config: Config, shutdown: impl Future<Output = ()>) -> qvpn::Result<()> {
+  let url = url(&config)?;
+  let tun = config.tun_config().unwrap_or_default();
+  let client = config.client_builder()?.build()?;
+  // Stopping the tunnel restores the routes and resolvers it installed.
+  tokio::select! {
+    res = client.tunnel(&url, config.client.host.as_deref(), &tun) => res,
+    () = shutdown => {
+      info!("shutting down");
+      Ok(())
+    }
+  }
+}
+
+/// Relays local clients to the configured url through the configured proxy.
+pub async fn proxy(config: Config) -> qvpn::Result<()> {
+  let url = url(&config)?;
+  let host = config.client.host.as_deref();
+  let client = config.client_builder()?.build()?;
+  let section = &config.client;
+  let forwards = &section.forward_udp;
+  let (locals, reverses) = (&section.forward, &section.reverse_forward);
+  let forwarding = !forwards.is_empty() || !locals.is_empty() || !reverses.is_empty();
+  let proxy = async {
+    if let Some(listen) = section.socks5 {
+      client.socks5(&url, host, listen).await
+    } else if let Some(listen) = section.dns_stub {
+      client.dns_stub(&url, host, listen).await
+    } else if let Some(listen) = section.http_proxy {
+      client.http_proxy(&url, host, listen).await
+    } else if forwarding {
+      std::future::pending().await
+    } else {
+      Err(QvpnError::InvalidInput(
+        "no --socks5, --http-proxy, --dns-stub or forward configured".into(),
+      ))
+    }
+  };
+  let udp = async {
+    if forwards.is_empty() {
+      return std::future::pending().await;
+    }
+    client.forward_udp(&url, host, forwards).await
+  };
+  let tcp = async {
+    if locals.is_empty() && reverses.is_empty() {
+      return std::future::pending().await;
+    }
+    client.forward(&url, host, locals, reverses).await
+  };
+  tokio::select! {
+    res = proxy => res,
+    res = udp => res,
+    res = tcp => res,
+  }
+}
+
+/// Shares the configured local service through the configured url.
+pub async fn expose(config: Config) -> qvpn::Result<()> {
+  let url = url(&config)?;
+  let host = config.client.host.as_deref();
+  let (name, target) = match (&config.client.expose, &config.client.expose_target) {
+    (Some(name), Some(target)) => (name, target),
+    _ => {
+      return Err(QvpnError::InvalidInput(
+        "no --name and --target configured".into(),
+      ))
+    }
+  };
+  let client = config.client_builder()?.build()?;
+  client.expose(&url, host, name, target).await
+}
+
+/// Benchmarks the connection to the configured url and prints the report
+/// as JSON.
+pub async fn bench(config: Config, options: bench::Options) -> qvpn::Result<()> {
+  let url = url(&config)?;
+  let client = config.client_builder()?.build()?;
+  let options = bench::Options {
+    transport: config.transport.mode.unwrap_or_default(),
+    ..options
+  };
+  let report = client
+    .bench(&url, config.client.host.as_deref(), &options)
+    .await?;
+  info!(
+    "Mbit/s" = report.goodput_bps / 1e6,
+    rtt_ms = report.rtt.p50_ms,
+    loss = report.loss.packet_loss,
+    "benchmark complete"
+  );
+  println!(
+    "{}",
+    serde_json::to_string_pretty(&report).expect("reports serialize")
+  );
+  client.wait_idle().await;
+  Ok(())
+}
+
+fn url(config: &Config) -> qvpn::Result<Url> {
+  config
+    .client
+    .url
+    .clone()
+    .ok_or_else(|| QvpnError::InvalidInput("no url given".into()))
+}
+
+/// Streams `body` into the file at `path`, showing a progress bar. Returns
+/// the number of bytes received.
+async fn save(body: &mut Body, path: &Path, append: bool, offset: u64) -> qvpn::Result<u64> {
+  let mut file = tokio::fs::OpenOptions::new()
+    .create(true)
+    .write(true)
+    .append(append)
+    .truncate(!append)
+    .open(path)
+    .await?;
+  let start = if append { offset } else { 0 };
+  let progress = progress_bar(body.content_length().map(|len| start + len), start);
+  while let Some(chunk) = body.chunk().await? {
+    file.write_all(&chunk).await?;
+    progress.inc(chunk.len() as u64);
+  }
+  file.flush().await?;
+  progress.finish();
+  Ok(body.received())
+}
+
+/// Progress bar for a download of `len` bytes, or a spinner if the length
+/// is unknown, starting at `position`.
+fn progress_bar(len: Option<u64>, position: u64) -> ProgressBar {
+  let progress = match len {
+    Some(len) => ProgressBar::new(len).with_style(
+      ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} {bytes_per_sec} eta {eta}")
+        .expect("valid template"),
+    ),
+    None => ProgressBar::new_spinner().with_style(
+      ProgressStyle::with_template("{spinner} {bytes} {bytes_per_sec}").expect("valid template"),
+    ),
+  };
+  progress.set_position(position);
+  progress
+}
+
+async fn copy_to_stdout(body: &mut Body) -> qvpn::Result<u64> {
+  let mut stdout = tokio::io::stdout();
+  while let Some(chunk) = body.chunk().await? {
+    stdout.write_all(&chunk).await?;
+  }
+  stdout.flush().await?;
+  Ok(body.received())
+}
@@ -0,0 +1,204 @@
+//! The qvpn command line: the server, its clients and the peer-to-peer node.
+//!
+//! Every command reads the `--config` file and overrides it with its flags.
+
+use std::{future::Future, path::PathBuf};
+
+use clap::{CommandFactory, Parser, Subcommand};
+
+use qvpn::{
+  config::{Config, LogSection},
+  service::{self, PidFile},
+};
+
+mod admin;
+mod client;
+mod opts;
+mod peer;
+mod serve;
+
+/// QUIC file server, VPN and peer-to-peer node
+#[derive(Parser, Debug)]
+#[command(name = "qvpn", version)]
+struct Opt {
+  /// TOML config file; flags override its values
+  #[arg(
+    long = "config",
+    env = "QVPN_CONFIG",
+    global = true,
+    help_heading = "Global options"
+  )]
+  config: Option<PathBuf>,
+  #[command(flatten)]
+  log: opts::LogOpts,
+  #[command(subcommand)]
+  command: Command,
+}
+
+// Parsed once, so the size of the largest variant doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Serve files, and optionally a tunnel, proxy or DNS over QUIC
+  Serve(serve::ServeOpt),
+  /// Download a file or directory listing
+  Get(client::GetOpt),
+  /// Upload a file, or the missing and changed files of a directory
+  Put(client::PutOpt),
+  /// Tunnel IP packets through a TUN interface
+  Tunnel(client::TunnelOpt),
+  /// Relay local SOCKS5, HTTP CONNECT or DNS clients through the server
+  Proxy(client::ProxyOpt),
+  /// Share a local service through the server under a name
+  Expose(client::ExposeOpt),
+  /// Measure goodput, round-trip times and loss to a server run with
+  /// `--bench`, printing a JSON report
+  Bench(client::BenchOpt),
+  /// Run an interactive peer-to-peer messaging node
+  Peer(peer::PeerOpt),
+  /// Manage hosts trusted on first use
+  Trust(admin::TrustOpt),
+  /// Mint signed, time-limited tokens for downloading files from a server
+  /// with a `--token-key`
+  Token(admin::TokenOpt),
+  /// List, kick and inspect the clients of a running server, or reload its
+  /// settings, through its admin socket
+  #[cfg(unix)]
+  Ctl(admin::CtlOpt),
+  /// Print a systemd unit running a command with the `--config` file
+  SystemdUnit(admin::SystemdUnitOpt),
+  /// Print a completion script for a shell
+  Completions(admin::CompletionsOpt),
+  /// Print a man page
+  Man,
+  /// Run the server, or a tunnel, as a Windows service with the `--config`
+  /// file
+  #[cfg(windows)]
+  #[command(subcommand)]
+  Service(admin::ServiceOpt),
+}
+
+fn main() {
+  let options = Opt::parse();
+  let file = match &options.config {
+    Some(path) => Config::load(path).unwrap_or_else(|err| exit(err)),
+    None => Config::default(),
+  };
+  let log = options.log.into_section();
+  match options.command {
+    Command::Serve(opt) => {
+      let config = configure(opt.into_config(), log, file);
+      let path = options.config;
+      start_service(config, |config| {
+        serve::serve(config, path, async {
+          service::shutdown_signal().await;
+          service::stopping();
+        })
+      })
+    }
+    Command::Get(opt) => {
+      let (flags, download) = opt.into_config();
+      start(configure(flags, log, file), |config| {
+        client::get(config, download)
+      })
+    }
+    Command::Put(opt) => {
+      let (flags, upload) = opt.into_config();
+      start(configure(flags, log, file), |config| {
+        client::put(config, upload)
+      })
+    }
+    Command::Tunnel(opt) => {
+      let config = configure(opt.into_config(), log, file);
+      start_service(config, |config| {
+        client::tunnel(config, async {
+          service::shutdown_signal().await;
+          service::stopping();
+        })
+      })
+    }
+    Command::Proxy(opt) => start_service(configure(opt.into_config(), log, file), client::proxy),
+    Command::Expose(opt) => start_service(configure(opt.into_config(), log, file), client::expose),
+    Command::Bench(opt) => {
+      let (flags, options) = opt.into_config();
+      start(configure(flags, log, file), |config| {
+        client::bench(config, options)
+      })
+    }
+    Command::Peer(opt) => {
+      let (flags, punch) = opt.into_config();
+      start(configure(flags, log, file), |config| {
+        peer::run(config, punch)
+      })
+    }
+    Command::Trust(opt) => admin::trust(opt, file),
+    Command::Token(opt) => admin::token(opt, file),
+    #[cfg(unix)]
+    Command::Ctl(opt) => admin::ctl(opt, file),
+    Command::SystemdUnit(opt) => admin::systemd_unit(opt, options.config),
+    Command::Completions(opt) => admin::completions(opt, Opt::command()),
+    Command::Man => admin::man(Opt::command),
+    #[cfg(windows)]
+    Command::Service(opt) => admin::windows_service(
+      opt,
+      options.config,
+      Config {
+        log,
+        ..Default::default()
+      }
+      .merge(file),
+    ),
+  }
+}
+
+/// Command line settings `flags` and `log` over the config `file`.
+fn configure(flags: Config, log: LogSection, file: Config) -> Config {
+  Config { log, ..flags }.merge(file)
+}
+
+/// Starts logging, then runs `main` on a new runtime and exits with its
+/// result.
+fn start<F, Fut>(config: Config, main: F) -> !
+where
+  F: FnOnce(Config) -> Fut,
+  Fut: Future<Output = qvpn::Result<()>>,
+{
+  config.init_log().unwrap_or_else(|err| exit(err));
+  let runtime = tokio::runtime::Runtime::new().unwrap_or_else(|err| exit(err));
+  finish(runtime.block_on(main(config)))
+}
+
+/// As [`start`], but first detaches from the terminal and writes the pid
+/// file, as the `[service]` settings ask.
+fn start_service<F, Fut>(config: Config, main: F) -> !
+where
+  F: FnOnce(Config) -> Fut,
+  Fut: Future<Output = qvpn::Result<()>>,
+{
+  config.init_log().unwrap_or_else(|err| exit(err));
+  // Forking is only safe before the runtime starts its threads.
+  if config.service.daemon.unwrap_or(false) {
+    service::daemonize().unwrap_or_else(|err| exit(err));
+  }
+  let pid_file = config
+    .service
+    .pid_file
+    .as_ref()
+    .map(|path| PidFile::create(path).unwrap_or_else(|err| exit(err)));
+  let runtime = tokio::runtime::Runtime::new().unwrap_or_else(|err| exit(err));
+  let result = runtime.block_on(main(config));
+  drop(pid_file);
+  finish(result)
+}
+
+fn finish(result: qvpn::Result<()>) -> ! {
+  if let Err(err) = result {
+    exit(err);
+  }
+  std::process::exit(0);
+}
+
+fn exit(err: impl std::fmt::Display) -> ! {
+  eprintln!("{}", err);
+  std::process::exit(1);
+}
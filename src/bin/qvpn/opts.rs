@@ -0,0 +1,227 @@
+//! Flags shared by several commands.
+
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::Args;
+use url::Url;
+
+use qvpn::{
+  config::{
+    parse_duration, ClientSection, Config, LogSection, ReconnectSection, ServiceSection,
+    TransportSection, TrustSection,
+  },
+  congestion::Congestion,
+  log::LogFormat,
+};
+
+// Log settings, accepted before or after the command.
+#[derive(Args, Debug)]
+#[command(next_help_heading = "Global options")]
+pub struct LogOpts {
+  /// Log filter, e.g. `debug` or `info,qvpn::server=trace` [default: info]
+  #[arg(long = "log-level", env = "QVPN_LOG_LEVEL", global = true)]
+  log_level: Option<String>,
+  /// Log output: `text` or `json` [default: text]
+  #[arg(long = "log-format", env = "QVPN_LOG_FORMAT", global = true)]
+  log_format: Option<LogFormat>,
+  /// Log the transfer statistics of every connection this often, e.g. `5s`
+  #[arg(long = "stats-interval", env = "QVPN_STATS_INTERVAL", global = true, value_parser = parse_duration)]
+  stats_interval: Option<Duration>,
+}
+
+impl LogOpts {
+  pub fn into_section(self) -> LogSection {
+    LogSection {
+      level: self.log_level,
+      format: self.log_format,
+      stats_interval_ms: self.stats_interval.map(|x| x.as_millis() as u64),
+    }
+  }
+}
+
+// Connection tuning, for the server and clients.
+#[derive(Args, Debug)]
+pub struct TransportOpts {
+  /// Close connections after this long without activity, e.g. `30s`
+  #[arg(long = "idle-timeout", env = "QVPN_IDLE_TIMEOUT", value_parser = parse_duration)]
+  idle_timeout: Option<Duration>,
+  /// Send keep-alives after this long without traffic, e.g. `10s`
+  #[arg(long = "keep-alive-interval", env = "QVPN_KEEP_ALIVE_INTERVAL", value_parser = parse_duration)]
+  keep_alive_interval: Option<Duration>,
+  /// Congestion controller: `newreno`, `cubic` or `bbr` [default: newreno]
+  #[arg(long = "congestion", env = "QVPN_CONGESTION")]
+  congestion: Option<Congestion>,
+  /// Write a qlog trace of every connection into this directory
+  #[arg(long = "qlog", env = "QVPN_QLOG")]
+  qlog: Option<PathBuf>,
+  /// Bytes the other side may send on a stream before it is read; raise it
+  /// on paths with a large bandwidth-delay product [default: 1250000]
+  #[arg(long = "stream-receive-window", env = "QVPN_STREAM_RECEIVE_WINDOW")]
+  stream_receive_window: Option<u64>,
+  /// Bytes the other side may send on a connection before it is read, and
+  /// this side may keep unacknowledged [default: unlimited, and 10000000
+  /// unacknowledged]
+  #[arg(long = "conn-receive-window", env = "QVPN_CONN_RECEIVE_WINDOW")]
+  conn_receive_window: Option<u64>,
+  /// Requests the other side may have open at once on a connection
+  /// [default: 100]
+  #[arg(
+    long = "max-concurrent-bidi-streams",
+    env = "QVPN_MAX_CONCURRENT_BIDI_STREAMS"
+  )]
+  max_concurrent_bidi_streams: Option<u64>,
+  /// Round-trip time to assume until one is measured, e.g. `150ms` for a
+  /// long path [default: 333ms]
+  #[arg(long = "initial-rtt", env = "QVPN_INITIAL_RTT", value_parser = parse_duration)]
+  initial_rtt: Option<Duration>,
+}
+
+impl TransportOpts {
+  pub fn into_section(self) -> TransportSection {
+    TransportSection {
+      idle_timeout_ms: self.idle_timeout.map(|x| x.as_millis() as u64),
+      keep_alive_interval_ms: self.keep_alive_interval.map(|x| x.as_millis() as u64),
+      qlog: self.qlog,
+      congestion: self.congestion,
+      stream_receive_window: self.stream_receive_window,
+      conn_receive_window: self.conn_receive_window,
+      max_concurrent_bidi_streams: self.max_concurrent_bidi_streams,
+      initial_rtt_ms: self.initial_rtt.map(|x| x.as_millis() as u64),
+      ..Default::default()
+    }
+  }
+}
+
+// Running in the background, for long-lived commands.
+#[derive(Args, Debug)]
+pub struct ServiceOpts {
+  /// Detach from the terminal and run in the background; logs still go to
+  /// stderr
+  #[arg(long = "daemon", env = "QVPN_DAEMON")]
+  daemon: bool,
+  /// Write the process id to this file
+  #[arg(long = "pid-file", env = "QVPN_PID_FILE")]
+  pid_file: Option<PathBuf>,
+}
+
+impl ServiceOpts {
+  pub fn into_section(self) -> ServiceSection {
+    ServiceSection {
+      daemon: Some(self.daemon).filter(|x| *x),
+      pid_file: self.pid_file,
+    }
+  }
+}
+
+// How clients find, trust and talk to the server.
+#[derive(Args, Debug)]
+pub struct ClientOpts {
+  /// TLS server name, if different from the URL host
+  #[arg(long = "host", env = "QVPN_HOST")]
+  host: Option<String>,
+  /// Trust only the CA certificates in this PEM or DER file
+  #[arg(long = "ca", env = "QVPN_CA", conflicts_with = "insecure")]
+  ca: Option<PathBuf>,
+  /// Accept any server certificate without verification
+  #[arg(long = "insecure", env = "QVPN_INSECURE")]
+  insecure: bool,
+  /// Accept only a server whose certificate's public key has this SHA-256,
+  /// in hex, whoever signed it. May be repeated
+  #[arg(long = "pin", env = "QVPN_PIN", value_delimiter = ',', conflicts_with_all = ["ca", "insecure"])]
+  pin: Vec<String>,
+  /// Trust the key a server presents the first time and refuse any other
+  /// afterwards
+  #[arg(long = "tofu", env = "QVPN_TOFU", conflicts_with_all = ["ca", "insecure", "pin"])]
+  tofu: bool,
+  /// File recording the keys of servers trusted on first use [default: in
+  /// the data directory]
+  #[arg(long = "known-hosts", env = "QVPN_KNOWN_HOSTS")]
+  known_hosts: Option<PathBuf>,
+  /// Prove knowledge of the pre-shared key in this file to the server
+  #[arg(long = "psk", env = "QVPN_PSK")]
+  psk: Option<PathBuf>,
+  /// Log in as this user on servers that require it
+  #[arg(long = "username", env = "QVPN_USERNAME", requires = "password_file")]
+  username: Option<String>,
+  /// File with the password of `--username`
+  #[arg(
+    long = "password-file",
+    env = "QVPN_PASSWORD_FILE",
+    requires = "username"
+  )]
+  password_file: Option<PathBuf>,
+  /// Log in at this OpenID Connect provider with the device flow, on
+  /// servers that require it
+  #[arg(
+    long = "oidc-issuer",
+    env = "QVPN_OIDC_ISSUER",
+    requires = "oidc_client_id",
+    conflicts_with = "username"
+  )]
+  oidc_issuer: Option<Url>,
+  /// OAuth client id registered at `--oidc-issuer` for qvpn
+  #[arg(
+    long = "oidc-client-id",
+    env = "QVPN_OIDC_CLIENT_ID",
+    requires = "oidc_issuer"
+  )]
+  oidc_client_id: Option<String>,
+  /// Send requests as 0-RTT early data when resuming a session. Session
+  /// tickets are kept in memory only, so this speeds up reconnects and
+  /// repeated requests within one run, not the first request of a new one
+  #[arg(long = "enable-0rtt", env = "QVPN_ENABLE_0RTT")]
+  enable_0rtt: bool,
+  /// Reconnect up to this many times, with jittered exponential backoff,
+  /// after losing the connection [default: 0]
+  #[arg(long = "reconnect", env = "QVPN_RECONNECT")]
+  reconnect: Option<u32>,
+  /// Local address to send from, e.g. `0.0.0.0:0` to use IPv4 only
+  /// [default: [::]:0]
+  #[arg(long = "bind", env = "QVPN_BIND")]
+  bind: Option<SocketAddr>,
+  /// When the server has several addresses, try the next one if a
+  /// connection attempt takes longer than this, e.g. `100ms` [default:
+  /// 250ms]
+  #[arg(long = "attempt-delay", env = "QVPN_ATTEMPT_DELAY", value_parser = parse_duration)]
+  attempt_delay: Option<Duration>,
+  /// Let tunnels and proxies time out when the network changes instead of
+  /// migrating to the new address
+  #[arg(long = "no-migration", env = "QVPN_NO_MIGRATION")]
+  no_migration: bool,
+  #[command(flatten)]
+  transport: TransportOpts,
+}
+
+impl ClientOpts {
+  /// Settings for these flags, to which each command adds its own.
+  pub fn into_config(self) -> Config {
+    Config {
+      client: ClientSection {
+        host: self.host,
+        ca: self.ca,
+        insecure: Some(self.insecure).filter(|x| *x),
+        psk: self.psk,
+        user: self.username,
+        password_file: self.password_file,
+        oidc_issuer: self.oidc_issuer,
+        oidc_client_id: self.oidc_client_id,
+        pins: self.pin,
+        tofu: Some(self.tofu).filter(|x| *x),
+        zero_rtt: Some(self.enable_0rtt).filter(|x| *x),
+        bind: self.bind,
+        attempt_delay_ms: self.attempt_delay.map(|x| x.as_millis() as u64),
+        migration: Some(!self.no_migration).filter(|x| !*x),
+        ..Default::default()
+      },
+      transport: self.transport.into_section(),
+      reconnect: ReconnectSection {
+        max_attempts: self.reconnect,
+        ..Default::default()
+      },
+      trust: TrustSection {
+        known_hosts: self.known_hosts,
+      },
+      ..Default::default()
+    }
+  }
+}
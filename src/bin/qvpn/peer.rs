@@ -0,0 +1,483 @@
+//! `qvpn peer`.
+
+use std::{
+  net::{IpAddr, SocketAddr},
+  path::PathBuf,
+  sync::Arc,
+  time::Duration,
+};
+
+use clap::Args;
+use tracing::{debug, error, info, warn};
+
+use qvpn::{
+  config::{
+    parse_duration, Config, PeerSection, ReconnectSection, TransportSection, TrustSection,
+    TunnelSection,
+  },
+  identity,
+  node::{NodeEvent, QvpnNode},
+  peer::PeerHandle,
+  protocol::Message,
+  repl::{self, Command},
+  send_queue::Overflow,
+  transfer::{Offer, Transfers},
+  QvpnError,
+};
+
+#[derive(Args, Debug)]
+pub struct PeerOpt {
+  /// Peers to connect to, in the form 127.0.0.1:1234
+  peers: Vec<SocketAddr>,
+  /// Advertise this peer with mDNS and connect to peers found on the local
+  /// network
+  #[arg(long = "mdns", env = "QVPN_MDNS")]
+  mdns: bool,
+  /// Local IP address to listen on [default: 127.0.0.1]
+  #[arg(long = "local-ip", env = "QVPN_LOCAL_IP")]
+  local_ip: Option<IpAddr>,
+  /// Local address to listen on, e.g. `[::]:5000` [default: a random port on
+  /// --local-ip]
+  #[arg(long = "bind", env = "QVPN_BIND", conflicts_with = "local_ip")]
+  bind: Option<SocketAddr>,
+  /// File to remember peers in, so they are reconnected to after a restart
+  #[arg(long = "peer-store", env = "QVPN_PEER_STORE")]
+  peer_store: Option<PathBuf>,
+  /// Send our peer list to every peer that connects
+  #[arg(long = "gossip", env = "QVPN_GOSSIP")]
+  gossip: bool,
+  /// Connect to peers learned from other peers' lists
+  #[arg(long = "auto-connect", env = "QVPN_AUTO_CONNECT", requires = "gossip")]
+  auto_connect: bool,
+  /// Most new peers to connect to per peer list received, with
+  /// --auto-connect [default: 3]
+  #[arg(
+    long = "gossip-fanout",
+    env = "QVPN_GOSSIP_FANOUT",
+    requires = "gossip"
+  )]
+  gossip_fanout: Option<usize>,
+  /// Ed25519 key identifying this node, created if missing [default: in the
+  /// data directory]
+  #[arg(long = "identity", env = "QVPN_IDENTITY")]
+  identity: Option<PathBuf>,
+  /// File recording the identity each peer address proved first; peers
+  /// proving another are disconnected [default: in the data directory]
+  #[arg(long = "known-hosts", env = "QVPN_KNOWN_HOSTS")]
+  known_hosts: Option<PathBuf>,
+  /// Directory to save files received from peers in [default: .]
+  #[arg(long = "download-dir", env = "QVPN_DOWNLOAD_DIR")]
+  download_dir: Option<PathBuf>,
+  /// Register peers and introduce them to each other for hole punching
+  #[arg(long = "rendezvous-server", env = "QVPN_RENDEZVOUS_SERVER")]
+  rendezvous_server: bool,
+  /// Rendezvous server to register with
+  #[arg(long = "rendezvous", env = "QVPN_RENDEZVOUS", requires = "name")]
+  rendezvous: Option<SocketAddr>,
+  /// Name to register with the rendezvous server
+  #[arg(long = "name", env = "QVPN_NAME", requires = "rendezvous")]
+  name: Option<String>,
+  /// Forward messages between connected peers that can't reach each other
+  #[arg(long = "relay", env = "QVPN_RELAY")]
+  relay: bool,
+  /// Bytes per second to relay at most [default: unlimited]
+  #[arg(long = "relay-rate", env = "QVPN_RELAY_RATE", requires = "relay")]
+  relay_rate: Option<u64>,
+  /// Relaying peer to use when hole punching fails. May be repeated
+  #[arg(long = "relay-via", env = "QVPN_RELAY_VIA", value_delimiter = ',')]
+  relay_via: Vec<SocketAddr>,
+  /// Punch a hole to the peer registered under this name. May be repeated
+  #[arg(
+    long = "punch",
+    env = "QVPN_PUNCH",
+    value_delimiter = ',',
+    requires = "rendezvous"
+  )]
+  punch: Vec<String>,
+  /// Forget peers disconnected or banned for this long, e.g. `10m`
+  /// [default: 10m]
+  #[arg(long = "reap-after", env = "QVPN_REAP_AFTER", value_parser = parse_duration)]
+  reap_after: Option<Duration>,
+  /// Messages queued per peer [default: 256]
+  #[arg(long = "send-queue", env = "QVPN_SEND_QUEUE")]
+  send_queue: Option<usize>,
+  /// What to do with messages for a peer whose queue is full: block,
+  /// drop-oldest or drop-newest [default: block]
+  #[arg(long = "send-overflow", env = "QVPN_SEND_OVERFLOW")]
+  send_overflow: Option<Overflow>,
+  /// Topic to subscribe to on startup. May be repeated
+  #[arg(long = "topic", env = "QVPN_TOPIC", value_delimiter = ',')]
+  topics: Vec<String>,
+  /// Keep messages for offline peers in this file and deliver them when the
+  /// peers are back
+  #[arg(long = "outbox", env = "QVPN_OUTBOX")]
+  outbox: Option<PathBuf>,
+  /// Bytes of messages the outbox keeps [default: 16777216]
+  #[arg(
+    long = "outbox-max-bytes",
+    env = "QVPN_OUTBOX_MAX_BYTES",
+    requires = "outbox"
+  )]
+  outbox_max_bytes: Option<u64>,
+  /// Keep messages in the outbox for this long, e.g. `12h` [default: 24h]
+  #[arg(
+    long = "outbox-ttl",
+    env = "QVPN_OUTBOX_TTL",
+    value_parser = parse_duration,
+    requires = "outbox"
+  )]
+  outbox_ttl: Option<Duration>,
+  /// Send messages bigger than this many bytes in parts [default: 262144]
+  #[arg(long = "chunk-size", env = "QVPN_CHUNK_SIZE")]
+  chunk_size: Option<usize>,
+  /// Largest message in bytes accepted in parts [default: 16777220]
+  #[arg(long = "max-message-size", env = "QVPN_MAX_MESSAGE_SIZE")]
+  max_message_size: Option<usize>,
+  /// Ping every peer after this long, e.g. `20s` [default: 20s]
+  #[arg(long = "keep-alive-interval", env = "QVPN_KEEP_ALIVE_INTERVAL", value_parser = parse_duration)]
+  keep_alive_interval: Option<Duration>,
+  /// Reconnect up to this many times, with jittered exponential backoff,
+  /// after losing the connection [default: 0]
+  #[arg(long = "reconnect", env = "QVPN_RECONNECT")]
+  reconnect: Option<u32>,
+}
+
+impl PeerOpt {
+  /// Settings for these flags, and the names to punch holes to.
+  pub fn into_config(self) -> (Config, Vec<String>) {
+    let config = Config {
+      peer: Some(PeerSection {
+        peers: self.peers,
+        local_ip: self.local_ip,
+        bind: self.bind,
+        mdns: Some(self.mdns).filter(|x| *x),
+        peer_store: self.peer_store,
+        gossip: Some(self.gossip).filter(|x| *x),
+        auto_connect: Some(self.auto_connect).filter(|x| *x),
+        gossip_fanout: self.gossip_fanout,
+        download_dir: self.download_dir,
+        identity: self.identity,
+        reap_after_secs: self.reap_after.map(|x| x.as_secs()),
+        send_queue: self.send_queue,
+        send_overflow: self.send_overflow,
+        topics: self.topics,
+        outbox: self.outbox,
+        outbox_max_bytes: self.outbox_max_bytes,
+        outbox_ttl_secs: self.outbox_ttl.map(|x| x.as_secs()),
+        chunk_size: self.chunk_size,
+        max_message_size: self.max_message_size,
+        rendezvous: self.rendezvous,
+        name: self.name,
+        rendezvous_server: Some(self.rendezvous_server).filter(|x| *x),
+        relay: Some(self.relay).filter(|x| *x),
+        relay_rate: self.relay_rate,
+        relay_via: self.relay_via,
+      }),
+      transport: TransportSection {
+        keep_alive_interval_ms: self.keep_alive_interval.map(|x| x.as_millis() as u64),
+        ..Default::default()
+      },
+      reconnect: ReconnectSection {
+        max_attempts: self.reconnect,
+        ..Default::default()
+      },
+      trust: TrustSection {
+        known_hosts: self.known_hosts,
+      },
+      ..Default::default()
+    };
+    (config, self.punch)
+  }
+}
+
+/// Runs the node, reading commands from the terminal.
+pub async fn run(config: Config, punch: Vec<String>) -> qvpn::Result<()> {
+  let peer = config.peer.clone().unwrap_or_default();
+  let server_mode = peer.peers.is_empty();
+  let download_dir = peer.download_dir.unwrap_or_else(|| PathBuf::from("."));
+  // The config file may be shared with `qvpn tunnel`, whose tunnel this
+  // isn't.
+  let config = Config {
+    tunnel: TunnelSection::default(),
+    ..config
+  };
+  let (handle, mut events) = QvpnNode::spawn(config).await?;
+  let (node, socket, local) = match (handle.peer(), handle.socket_addr(), handle.local_addr()) {
+    (Some(node), Some(socket), Some(local)) => (node.clone(), socket, local),
+    _ => return Err(QvpnError::InvalidInput("no [peer] section".into())),
+  };
+  info!(?socket, ?local, server_mode, "listening");
+  if let Some(identity) = node.identity() {
+    info!(fingerprint = %identity.fingerprint(), "identity");
+  }
+  let transfers = Transfers::new(download_dir);
+  let (prompt, commands) = (node.clone(), transfers.clone());
+  tokio::spawn(async move {
+    let mut lines = repl::lines("> ");
+    while let Some(line) = lines.recv().await {
+      match line.parse() {
+        Ok(command) => {
+          if let Err(err) = execute(&prompt, &commands, command).await {
+            println!("{}", err);
+          }
+        }
+        Err(err) => println!("{}", err),
+      }
+    }
+  });
+  for name in &punch {
+    if let Err(err) = node.punch(name).await {
+      error!(%name, "punch request failed: {}", err);
+    }
+  }
+  info!(peers = node.peer_count().await, "connected");
+  while let Some(event) = events.next().await {
+    match event {
+      NodeEvent::PeerConnected(peer) => println!("{} connected", display_name(&node, peer)),
+      NodeEvent::PeerDisconnected(peer) => {
+        println!("{} disconnected", display_name(&node, peer))
+      }
+      NodeEvent::PeerBanned(peer) => {
+        println!("{} banned: wrong identity", display_name(&node, peer))
+      }
+      NodeEvent::Message {
+        peer,
+        origin,
+        message,
+      } => {
+        let origin = origin.as_deref();
+        if let Err(err) = dispatch(&node, &transfers, peer, origin, message).await {
+          warn!(%peer, "handling message failed: {}", err);
+        }
+      }
+      NodeEvent::Error(err) => warn!("{}", err),
+      event => debug!(?event, "node event"),
+    }
+  }
+  std::process::exit(1)
+}
+
+/// Runs a command typed at the prompt.
+async fn execute(
+  node: &PeerHandle,
+  transfers: &Arc<Transfers>,
+  command: Command,
+) -> qvpn::Result<()> {
+  match command {
+    Command::Say(text) => {
+      let direct = false;
+      let sent = node.broadcast(&Message::Chat { text, direct }).await;
+      for (peer, err) in &sent.failed {
+        println!("not delivered to {}: {}", display_name(node, *peer), err);
+      }
+    }
+    Command::Msg(peer, text) => {
+      let peer = resolve(node, &peer)?;
+      let (direct, stored) = (true, node.stored(&peer));
+      node.send(&Message::Chat { text, direct }, &peer).await?;
+      if node.stored(&peer) > stored {
+        println!(
+          "{} is offline, message kept until it is back",
+          display_name(node, peer)
+        );
+      }
+    }
+    Command::Connect(peer) => {
+      node.connect(peer).await?;
+      println!("connected to {}", display_name(node, peer));
+    }
+    Command::Peers => {
+      for peer in node.peers().await {
+        println!("{}", display_name(node, peer));
+      }
+      for (peer, relay) in node.relayed_peers().await {
+        println!("{} via {}", display_name(node, peer), relay);
+      }
+      if let Some(store) = node.peer_store() {
+        let connected = node.peers().await;
+        for record in store.peers() {
+          if !connected.contains(&record.address) {
+            println!(
+              "{} (offline, last seen {})",
+              display_name(node, record.address),
+              record.last_seen
+            );
+          }
+        }
+      }
+    }
+    Command::Nick(peer, name) => {
+      let peer = resolve(node, &peer)?;
+      let store = node
+        .peer_store()
+        .ok_or_else(|| qvpn::QvpnError::InvalidInput("nicknames need --peer-store".into()))?;
+      store.set_nickname(peer, name);
+    }
+    Command::Send(peer, path) => {
+      let peer = resolve(node, &peer)?;
+      let offer = transfers.offer(node, peer, path.as_ref()).await?;
+      println!(
+        "offered {} ({} bytes) to {}",
+        offer.name,
+        offer.size,
+        display_name(node, peer)
+      );
+    }
+    Command::Sub(topic) => node.subscribe(&topic).await?,
+    Command::Unsub(topic) => {
+      if !node.unsubscribe(&topic).await {
+        println!("not subscribed to {}", topic);
+      }
+    }
+    Command::Pub(topic, text) => {
+      let sent = node.publish(&topic, text.into_bytes()).await;
+      if sent.delivered.is_empty() && sent.failed.is_empty() {
+        println!("no subscribers to {}", topic);
+      }
+      for (peer, err) in &sent.failed {
+        println!("not delivered to {}: {}", display_name(node, *peer), err);
+      }
+    }
+    Command::Topics => {
+      for topic in node.subscriptions() {
+        println!(
+          "{} ({} peers subscribed)",
+          topic,
+          node.subscribers(&topic).len()
+        );
+      }
+    }
+    Command::Accept(id) => {
+      let (peer, offer) = transfers.accept(node, &id).await?;
+      println!("receiving {} from {}", offer.name, display_name(node, peer));
+    }
+    Command::Reject(id) => {
+      let (peer, offer) = transfers.reject(node, &id).await?;
+      println!("rejected {} from {}", offer.name, display_name(node, peer));
+    }
+    Command::Stats => {
+      println!(
+        "{} peers, {} relayed, {} pending offers",
+        node.peers().await.len(),
+        node.relayed_peers().await.len(),
+        transfers.pending().await.len()
+      );
+      for ((from, to), stats) in node.relay_stats().unwrap_or_default() {
+        println!(
+          "relayed {} -> {}: {} messages, {} bytes, {} dropped",
+          from, to, stats.messages, stats.bytes, stats.dropped
+        );
+      }
+    }
+    Command::Help => println!("{}", repl::HELP),
+    Command::Quit => std::process::exit(0),
+  }
+  Ok(())
+}
+
+/// Parses a peer address, or looks up a nickname in the peer store.
+fn resolve(node: &PeerHandle, peer: &str) -> qvpn::Result<SocketAddr> {
+  if let Ok(addr) = peer.parse() {
+    return Ok(addr);
+  }
+  node
+    .peer_store()
+    .and_then(|store| {
+      store
+        .peers()
+        .into_iter()
+        .find(|x| x.nickname.as_deref() == Some(peer))
+    })
+    .map(|x| x.address)
+    .ok_or_else(|| qvpn::QvpnError::InvalidInput(format!("unknown peer `{}`", peer)))
+}
+
+/// Acts on one message from `peer`, signed by `origin` if that is set.
+async fn dispatch(
+  node: &PeerHandle,
+  transfers: &Arc<Transfers>,
+  peer: SocketAddr,
+  origin: Option<&[u8]>,
+  message: Message,
+) -> qvpn::Result<()> {
+  match message {
+    Message::Chat { text, direct } => {
+      let to = if direct { " (to you)" } else { "" };
+      println!("<-- {}{} : {}", author(node, peer, origin), to, text)
+    }
+    Message::FileOffer { name, size, hash } => {
+      let (id, offer) = (hash.get(..8).unwrap_or(&hash).to_string(), name.clone());
+      transfers.offered(peer, Offer { name, size, hash }).await?;
+      println!(
+        "<-- {} offers {} ({} bytes): /accept {} or /reject {}",
+        author(node, peer, origin),
+        offer,
+        size,
+        id,
+        id
+      );
+    }
+    Message::FileAccept { hash, offset } => {
+      let (node, transfers) = (node.clone(), transfers.clone());
+      tokio::spawn(async move {
+        if let Err(err) = transfers.send(&node, peer, &hash, offset).await {
+          warn!(%peer, "sending file failed: {}", err);
+        }
+      });
+    }
+    Message::FileReject { hash } => info!(%peer, %hash, "file rejected"),
+    Message::FileChunk { hash, offset, data } => {
+      if let Some(path) = transfers.receive(peer, &hash, offset, &data).await? {
+        println!("saved {}", path.display());
+      }
+    }
+    Message::Publish { topic, payload, .. } => println!(
+      "<-- {} [{}] : {}",
+      author(node, peer, origin),
+      topic,
+      String::from_utf8_lossy(&payload)
+    ),
+    message => debug!(%peer, ?message, "ignoring message"),
+  }
+  Ok(())
+}
+
+/// How `peer` is shown: its nickname if it has one, with the fingerprint of
+/// its proven identity or failing that its address.
+fn display_name(node: &PeerHandle, peer: SocketAddr) -> String {
+  let nickname = node
+    .peer_store()
+    .and_then(|store| store.get(&peer))
+    .and_then(|record| record.nickname);
+  let id = match node.identity_of(&peer) {
+    Some(key) => identity::fingerprint(&key),
+    None => peer.to_string(),
+  };
+  match nickname {
+    Some(nickname) => format!("{} ({})", nickname, id),
+    None => id,
+  }
+}
+
+/// Who wrote a message `peer` delivered: whoever signed it, if that isn't
+/// `peer` itself.
+fn author(node: &PeerHandle, peer: SocketAddr, origin: Option<&[u8]>) -> String {
+  let key = match origin {
+    Some(key) if node.identity_of(&peer).as_deref() != Some(key) => key,
+    _ => return display_name(node, peer),
+  };
+  let encoded = identity::encode_key(key);
+  let nickname = node.peer_store().and_then(|store| {
+    store
+      .peers()
+      .into_iter()
+      .find(|x| x.public_key.as_ref() == Some(&encoded))
+      .and_then(|x| x.nickname)
+  });
+  let fingerprint = identity::fingerprint(key);
+  let name = match nickname {
+    Some(nickname) => format!("{} ({})", nickname, fingerprint),
+    None => fingerprint,
+  };
+  format!("{} via {}", name, display_name(node, peer))
+}
@@ -0,0 +1,414 @@
+//! `qvpn serve`.
+
+use std::{
+  future::Future,
+  net::{IpAddr, SocketAddr},
+  path::PathBuf,
+};
+
+use clap::Args;
+use tracing::info;
+use url::Url;
+
+use qvpn::{
+  config::{
+    AcmeSection, AuthSection, Config, EgressSection, ForwardSection, MountSection,
+    ReverseForwardSection, ServerSection, SiteSection, TunnelSection,
+  },
+  lease::Ipv4Net,
+  server::Mode,
+  service,
+  site::Firewall,
+};
+
+use crate::opts::{ServiceOpts, TransportOpts};
+
+#[derive(Args, Debug)]
+pub struct ServeOpt {
+  /// directory to serve files from
+  root: Option<PathBuf>,
+  /// Also serve a directory under a path prefix, as `PREFIX=DIR`, e.g.
+  /// `/pub=/srv/public`. May be repeated
+  #[arg(long = "mount", env = "QVPN_MOUNT", value_delimiter = ',')]
+  mount: Vec<MountSection>,
+  /// Like `--mount`, but refusing uploads. May be repeated
+  #[arg(
+    long = "readonly-mount",
+    env = "QVPN_READONLY_MOUNT",
+    value_delimiter = ','
+  )]
+  readonly_mount: Vec<MountSection>,
+  /// Answer requests for a directory containing a file with this name, e.g.
+  /// `index.html`, with the file instead of a listing
+  #[arg(long = "index", env = "QVPN_INDEX")]
+  index: Option<String>,
+  /// Follow symlinks wherever they lead; by default only those to targets
+  /// inside the same root or mount are
+  #[arg(long = "follow-symlinks", env = "QVPN_FOLLOW_SYMLINKS")]
+  follow_symlinks: bool,
+  /// Compress file responses with gzip or deflate for clients that accept
+  /// it, except types that already are compressed
+  #[arg(long = "compress", env = "QVPN_COMPRESS")]
+  compress: bool,
+  /// Keep up to this many bytes of popular files in memory instead of
+  /// reading them from disk for every request [default: 0, disabled]
+  #[arg(long = "cache-size", env = "QVPN_CACHE_SIZE")]
+  cache_size: Option<u64>,
+  /// file to log TLS keys to for debugging
+  #[arg(long = "keylog", env = "QVPN_KEYLOG")]
+  keylog: bool,
+  /// TLS private key in PEM format
+  #[arg(short = 'k', long = "key", env = "QVPN_KEY", requires = "cert")]
+  key: Option<PathBuf>,
+  /// TLS certificate in PEM format
+  #[arg(short = 'c', long = "cert", env = "QVPN_CERT", requires = "key")]
+  cert: Option<PathBuf>,
+  /// Obtain and renew the certificate from Let's Encrypt or another ACME CA
+  #[arg(long = "acme", env = "QVPN_ACME", conflicts_with_all = ["key", "cert"])]
+  acme: bool,
+  /// Domain to request a certificate for. May be repeated
+  #[arg(
+    long = "domain",
+    env = "QVPN_DOMAIN",
+    value_delimiter = ',',
+    requires = "acme"
+  )]
+  domain: Vec<String>,
+  /// Contact URI for the ACME account, e.g. mailto:admin@example.com. May be
+  /// repeated
+  #[arg(
+    long = "acme-contact",
+    env = "QVPN_ACME_CONTACT",
+    value_delimiter = ',',
+    requires = "acme"
+  )]
+  acme_contact: Vec<String>,
+  /// ACME directory URL [default: Let's Encrypt]
+  #[arg(
+    long = "acme-directory",
+    env = "QVPN_ACME_DIRECTORY",
+    requires = "acme"
+  )]
+  acme_directory: Option<Url>,
+  /// Directory to keep the ACME account and certificates in
+  #[arg(long = "acme-cache", env = "QVPN_ACME_CACHE", requires = "acme")]
+  acme_cache: Option<PathBuf>,
+  /// Address to answer HTTP-01 challenges on [default: 0.0.0.0:80]
+  #[arg(long = "acme-http", env = "QVPN_ACME_HTTP", requires = "acme")]
+  acme_http: Option<SocketAddr>,
+  /// Require client certificates signed by a CA in this PEM or DER file
+  #[arg(long = "client-ca", env = "QVPN_CLIENT_CA")]
+  client_ca: Option<PathBuf>,
+  /// Require clients to prove knowledge of the pre-shared key in this file
+  #[arg(long = "psk", env = "QVPN_PSK")]
+  psk: Option<PathBuf>,
+  /// Require tunnel and proxy clients to log in as a user in this htpasswd
+  /// file, with `{SHA}` or plain text passwords
+  #[arg(
+    long = "htpasswd",
+    env = "QVPN_HTPASSWD",
+    conflicts_with = "auth_command"
+  )]
+  htpasswd: Option<PathBuf>,
+  /// Require tunnel and proxy clients to log in as a user this program
+  /// accepts. It reads the user name and password from standard input, one
+  /// per line, and accepts by exiting with status 0
+  #[arg(long = "auth-command", env = "QVPN_AUTH_COMMAND")]
+  auth_command: Option<String>,
+  /// Let tunnel and proxy clients log in with ID tokens from this OpenID
+  /// Connect provider
+  #[arg(
+    long = "oidc-issuer",
+    env = "QVPN_OIDC_ISSUER",
+    requires = "oidc_audience"
+  )]
+  oidc_issuer: Option<Url>,
+  /// Accept ID tokens issued for this audience, the OAuth client id of the
+  /// qvpn clients
+  #[arg(
+    long = "oidc-audience",
+    env = "QVPN_OIDC_AUDIENCE",
+    requires = "oidc_issuer"
+  )]
+  oidc_audience: Option<String>,
+  /// Log ID token holders in as the user this claim names instead of their
+  /// `sub`, e.g. `preferred_username`
+  #[arg(long = "oidc-claim", env = "QVPN_OIDC_CLAIM", requires = "oidc_issuer")]
+  oidc_claim: Option<String>,
+  /// Refuse new connections while this many are open
+  #[arg(long = "max-connections", env = "QVPN_MAX_CONNECTIONS")]
+  max_connections: Option<u32>,
+  /// Streams a client may have open at once on one connection
+  #[arg(long = "max-streams-per-conn", env = "QVPN_MAX_STREAMS_PER_CONN")]
+  max_streams_per_conn: Option<u64>,
+  /// Refuse connections from a source IP beyond this many a minute
+  #[arg(long = "max-connection-rate", env = "QVPN_MAX_CONNECTION_RATE")]
+  max_connection_rate: Option<u32>,
+  /// Enable stateless retries
+  #[arg(long = "stateless-retry", env = "QVPN_STATELESS_RETRY")]
+  stateless_retry: bool,
+  /// Answer `GET` requests sent as 0-RTT early data by resuming clients
+  #[arg(long = "enable-0rtt", env = "QVPN_ENABLE_0RTT")]
+  enable_0rtt: bool,
+  /// `file`, `connect-proxy` to also relay `CONNECT host:port` requests to
+  /// TCP targets, or `doq` to answer DNS over QUIC queries [default: file]
+  #[arg(long = "mode", env = "QVPN_MODE")]
+  mode: Option<Mode>,
+  /// Let clients forward TCP connections to this `host:port`, like
+  /// `ssh -L`, outside `connect-proxy` mode too. May be repeated
+  #[arg(
+    long = "allow-forward",
+    env = "QVPN_ALLOW_FORWARD",
+    value_delimiter = ','
+  )]
+  allow_forward: Vec<ForwardSection>,
+  /// Let clients have the server listen on this address and forward the
+  /// connections to them, like `ssh -R`. May be repeated
+  #[arg(
+    long = "allow-reverse-forward",
+    env = "QVPN_ALLOW_REVERSE_FORWARD",
+    value_delimiter = ','
+  )]
+  allow_reverse_forward: Vec<ReverseForwardSection>,
+  /// Accept HTTP connections on this address for the services clients
+  /// `qvpn expose`, routed by the first label of their `Host` header, e.g.
+  /// `[::]:8000`
+  #[arg(long = "expose", env = "QVPN_EXPOSE")]
+  expose: Option<SocketAddr>,
+  /// Accept `PUT` requests that write files below the root
+  #[arg(long = "allow-upload", env = "QVPN_ALLOW_UPLOAD")]
+  allow_upload: bool,
+  /// Answer `qvpn bench` with synthetic data generated in memory
+  #[arg(long = "bench", env = "QVPN_BENCH")]
+  bench: bool,
+  /// Speak only the pre-HTTP/3 request protocol, under the `h3-29` ALPN
+  /// protocol, for older clients; HTTP/3 clients can't connect
+  #[arg(long = "legacy-proto", env = "QVPN_LEGACY_PROTO")]
+  legacy_proto: bool,
+  /// Resolver to forward queries to in `--mode doq`, e.g. `127.0.0.1:53`
+  #[arg(long = "upstream", env = "QVPN_UPSTREAM")]
+  upstream: Option<SocketAddr>,
+  /// Switch to this unprivileged user, by name or id, once the endpoint is
+  /// bound and `--tun` is open. A tunnel that routes sites, installs
+  /// firewall rules or runs hooks keeps `CAP_NET_ADMIN` on Linux
+  #[arg(long = "user", env = "QVPN_USER")]
+  user: Option<String>,
+  /// Switch to this group instead of the `--user`'s primary group
+  #[arg(long = "group", env = "QVPN_GROUP", requires = "user")]
+  group: Option<String>,
+  /// Answer `qvpn ctl` on a Unix socket at this path
+  #[arg(long = "admin-socket", env = "QVPN_ADMIN_SOCKET")]
+  admin_socket: Option<PathBuf>,
+  /// Bytes read from a file at a time when streaming it [default: 65536]
+  #[arg(long = "chunk-size", env = "QVPN_CHUNK_SIZE")]
+  chunk_size: Option<usize>,
+  /// Address to listen on [default: [::]:4433, IPv6 and IPv4]
+  #[arg(long = "listen", env = "QVPN_LISTEN")]
+  listen: Option<SocketAddr>,
+  /// Serve Prometheus metrics over HTTP/1.1 on this address, e.g.
+  /// `127.0.0.1:9100`
+  #[arg(long = "metrics", env = "QVPN_METRICS")]
+  metrics: Option<SocketAddr>,
+  /// Serve a web dashboard of connections, leases and recent events over
+  /// HTTP/1.1 on this loopback address, e.g. `127.0.0.1:8080`
+  #[arg(
+    long = "dashboard",
+    env = "QVPN_DASHBOARD",
+    requires = "dashboard_password"
+  )]
+  dashboard: Option<SocketAddr>,
+  /// File with the password the dashboard asks for, with any user name
+  #[arg(long = "dashboard-password", env = "QVPN_DASHBOARD_PASSWORD")]
+  dashboard_password: Option<PathBuf>,
+  /// Append a JSON line with the client, tunnel address, duration and bytes
+  /// transferred of every session to this file
+  #[arg(long = "accounting", env = "QVPN_ACCOUNTING")]
+  accounting: Option<PathBuf>,
+  /// Serve files only to requests presenting an access token signed with
+  /// the key in this file, as minted by `qvpn token create`
+  #[arg(long = "token-key", env = "QVPN_TOKEN_KEY")]
+  token_key: Option<PathBuf>,
+  /// Forward client packets to a TUN interface with this name
+  #[arg(long = "tun", env = "QVPN_TUN")]
+  tun: Option<String>,
+  /// Subnet to lease client addresses from; the server takes the first host
+  /// [default: 10.8.0.0/24]
+  #[arg(long = "subnet", env = "QVPN_SUBNET")]
+  subnet: Option<Ipv4Net>,
+  /// Have clients route this network through the tunnel. May be repeated
+  #[arg(long = "route", env = "QVPN_ROUTE", value_delimiter = ',')]
+  route: Vec<Ipv4Net>,
+  /// Have clients resolve names through this name server while tunneled.
+  /// May be repeated
+  #[arg(long = "dns", env = "QVPN_DNS", value_delimiter = ',')]
+  dns: Vec<IpAddr>,
+  /// Search domain pushed to clients with `--dns`. May be repeated
+  #[arg(long = "dns-search", env = "QVPN_DNS_SEARCH", value_delimiter = ',')]
+  dns_search: Vec<String>,
+  /// MTU of the TUN interface, and the most clients are leased; each
+  /// client's follows what its path fits as path MTU discovery probes it
+  /// [default: 1150]
+  #[arg(long = "mtu", env = "QVPN_MTU")]
+  mtu: Option<u16>,
+  /// Clamp the MSS of TCP connections through the tunnel to what fits in
+  /// each client's path
+  #[arg(long = "clamp-mss", env = "QVPN_CLAMP_MSS")]
+  clamp_mss: bool,
+  /// Make `--tun` a TAP interface carrying Ethernet frames, so broadcasts
+  /// and discovery protocols reach clients. Linux only
+  #[arg(long = "tap", env = "QVPN_TAP")]
+  tap: bool,
+  /// Add the `--tap` interface to this bridge, joining clients to its LAN
+  /// segment, e.g. `br0`
+  #[arg(long = "bridge", env = "QVPN_BRIDGE", requires = "tap")]
+  bridge: Option<String>,
+  /// Let tunnel clients be the gateways of this network or networks inside
+  /// it, routed to them and pushed to the other clients, e.g.
+  /// `192.168.0.0/16`. May be repeated
+  #[arg(long = "allow-site", env = "QVPN_ALLOW_SITE", value_delimiter = ',')]
+  allow_site: Vec<SiteSection>,
+  /// Allow or deny packets tunnel clients send to a network, as
+  /// `ACTION:ADDR/PREFIX[:PROTOCOL[:PORTS]]`, e.g. `allow:10.0.0.0/8:tcp:22`
+  /// or `deny:0.0.0.0/0`. The first matching rule decides; packets none
+  /// match are allowed. May be repeated
+  #[arg(long = "egress", env = "QVPN_EGRESS", value_delimiter = ',')]
+  egress: Vec<EgressSection>,
+  /// Masquerade traffic leaving the tunnel for other interfaces with
+  /// `iptables` or `nftables`. Linux only
+  #[arg(long = "masquerade", env = "QVPN_MASQUERADE")]
+  masquerade: Option<Firewall>,
+  /// Leave the firewall and IPv4 forwarding alone instead of installing
+  /// nftables rules that masquerade the subnet and forward its traffic for
+  /// full-tunnel clients
+  #[arg(
+    long = "no-firewall",
+    env = "QVPN_NO_FIREWALL",
+    conflicts_with = "masquerade"
+  )]
+  no_firewall: bool,
+  /// Shell command to run once the tunnel is up, with `QVPN_*` environment
+  /// variables describing it
+  #[arg(long = "up", env = "QVPN_UP")]
+  up: Option<String>,
+  /// Shell command to run once the tunnel is down
+  #[arg(long = "down", env = "QVPN_DOWN")]
+  down: Option<String>,
+  /// Shell command to run when a client is leased a tunnel address, with
+  /// `QVPN_ADDRESS`, `QVPN_USER` and more describing it
+  #[arg(long = "client-connect", env = "QVPN_CLIENT_CONNECT")]
+  client_connect: Option<String>,
+  /// Shell command to run when a client with a tunnel address disconnects
+  #[arg(long = "client-disconnect", env = "QVPN_CLIENT_DISCONNECT")]
+  client_disconnect: Option<String>,
+  #[command(flatten)]
+  transport: TransportOpts,
+  #[command(flatten)]
+  service: ServiceOpts,
+}
+
+impl ServeOpt {
+  pub fn into_config(self) -> Config {
+    Config {
+      server: ServerSection {
+        listen: self.listen,
+        root: self.root,
+        mounts: self
+          .mount
+          .into_iter()
+          .chain(self.readonly_mount.into_iter().map(|x| MountSection {
+            readonly: Some(true),
+            ..x
+          }))
+          .collect(),
+        index: self.index,
+        follow_symlinks: Some(self.follow_symlinks).filter(|x| *x),
+        compress: Some(self.compress).filter(|x| *x),
+        cache_size: self.cache_size,
+        key: self.key,
+        cert: self.cert,
+        client_ca: self.client_ca,
+        keylog: Some(self.keylog).filter(|x| *x),
+        stateless_retry: Some(self.stateless_retry).filter(|x| *x),
+        zero_rtt: Some(self.enable_0rtt).filter(|x| *x),
+        mode: self.mode,
+        upload: Some(self.allow_upload).filter(|x| *x),
+        bench: Some(self.bench).filter(|x| *x),
+        chunk_size: self.chunk_size,
+        metrics: self.metrics,
+        psk: self.psk,
+        max_connections: self.max_connections,
+        max_streams_per_conn: self.max_streams_per_conn,
+        max_connection_rate: self.max_connection_rate,
+        legacy_proto: Some(self.legacy_proto).filter(|x| *x),
+        upstream: self.upstream,
+        user: self.user,
+        group: self.group,
+        admin_socket: self.admin_socket,
+        dashboard: self.dashboard,
+        dashboard_password: self.dashboard_password,
+        accounting: self.accounting,
+        token_key: self.token_key,
+        forwards: self.allow_forward,
+        reverse_forwards: self.allow_reverse_forward,
+        expose: self.expose,
+        sites: self.allow_site,
+        egress: self.egress,
+      },
+      tunnel: TunnelSection {
+        name: self.tun,
+        subnet: self.subnet,
+        mtu: self.mtu,
+        tap: Some(self.tap).filter(|x| *x),
+        bridge: self.bridge,
+        masquerade: self.masquerade,
+        firewall: Some(!self.no_firewall).filter(|x| !*x),
+        clamp_mss: Some(self.clamp_mss).filter(|x| *x),
+        routes: self.route,
+        dns: self.dns,
+        search_domains: self.dns_search,
+        up: self.up,
+        down: self.down,
+        client_connect: self.client_connect,
+        client_disconnect: self.client_disconnect,
+        ..Default::default()
+      },
+      transport: self.transport.into_section(),
+      auth: AuthSection {
+        htpasswd: self.htpasswd,
+        command: self.auth_command.into_iter().collect(),
+        oidc_issuer: self.oidc_issuer,
+        oidc_audience: self.oidc_audience,
+        oidc_claim: self.oidc_claim,
+        ..Default::default()
+      },
+      acme: AcmeSection {
+        enabled: Some(self.acme).filter(|x| *x),
+        domains: self.domain,
+        contact: self.acme_contact,
+        directory: self.acme_directory,
+        cache: self.acme_cache,
+        http_listen: self.acme_http,
+      },
+      service: self.service.into_section(),
+      ..Default::default()
+    }
+  }
+}
+
+/// Serves until `shutdown` completes. A management `reload` re-reads
+/// `config_file`.
+pub async fn serve(
+  config: Config,
+  config_file: Option<PathBuf>,
+  shutdown: impl Future<Output = ()>,
+) -> qvpn::Result<()> {
+  let mut builder = config.server_builder()?;
+  if let Some(path) = config_file {
+    builder = builder.config_file(path);
+  }
+  let server = builder.build()?;
+  info!(listen = %server.local_addr()?, "listening");
+  service::ready();
+  server.run_until(shutdown).await;
+  Ok(())
+}
@@ -0,0 +1,290 @@
+//! Bonded uplinks: one tunnel carried over several connections at once.
+//!
+//! This is experimental. A client with more than one network, say Wi-Fi and
+//! LTE, leases a tunnel address on one connection, then opens one more per
+//! extra local address and joins them to the lease with a
+//! [`Message::BondToken`]. It numbers its tunnel packets and spreads them
+//! over the paths with a [`Scheduler`]; the server puts them back in order
+//! with a [`Reorder`] buffer and answers over the fastest path.
+//!
+//! [`Message::BondToken`]: crate::control::Message::BondToken
+
+use std::{
+  collections::VecDeque,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::{stream::FuturesUnordered, StreamExt};
+use tracing::{debug, warn};
+
+use crate::{
+  datagram::{Frame, Kind},
+  fragment::Reassembler,
+  net,
+  tun::{self, Tun},
+  Result,
+};
+
+/// Packets a [`Reorder`] buffer holds while waiting for a missing one.
+const REORDER_WINDOW: usize = 256;
+
+/// How long a [`Reorder`] buffer waits for a missing packet before giving
+/// it up as lost.
+const REORDER_TIMEOUT: Duration = Duration::from_millis(30);
+
+/// How often a [`Scheduler`] re-reads the statistics of its paths.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Weight of the latest sample in a path's smoothed loss rate.
+const LOSS_GAIN: f64 = 0.25;
+
+/// Puts numbered packets arriving over several paths back in order.
+#[derive(Debug, Default)]
+pub struct Reorder {
+  /// Number of the packet to deliver next, once the first one arrived.
+  next: Option<u32>,
+  /// Packets `next`, `next + 1`, ... received so far.
+  slots: VecDeque<Option<Bytes>>,
+  /// Since when the buffer has been waiting for `next`.
+  waiting: Option<Instant>,
+}
+
+impl Reorder {
+  /// Takes packet number `seq`, received at `now`, and returns the packets
+  /// that can be delivered in order. A packet missing for longer than
+  /// [`REORDER_TIMEOUT`] is skipped; one arriving after it was skipped is
+  /// delivered at once.
+  pub fn push(&mut self, seq: u32, packet: Bytes, now: Instant) -> Vec<Bytes> {
+    let next = *self.next.get_or_insert(seq);
+    let offset = seq.wrapping_sub(next) as i32;
+    if offset < 0 {
+      return vec![packet];
+    }
+    let mut ready = vec![];
+    let mut offset = offset as usize;
+    if offset >= REORDER_WINDOW {
+      // Far ahead of the window: whatever was missing isn't coming.
+      ready.extend(self.slots.drain(..).flatten());
+      self.next = Some(seq);
+      offset = 0;
+    }
+    if self.slots.len() <= offset {
+      self.slots.resize(offset + 1, None);
+    }
+    self.slots[offset].get_or_insert(packet);
+    if self
+      .waiting
+      .is_some_and(|since| now - since >= REORDER_TIMEOUT)
+    {
+      while let Some(None) = self.slots.front() {
+        self.slots.pop_front();
+        self.advance();
+      }
+    }
+    let mut advanced = false;
+    while let Some(Some(_)) = self.slots.front() {
+      ready.extend(self.slots.pop_front().flatten());
+      self.advance();
+      advanced = true;
+    }
+    if self.slots.is_empty() {
+      self.waiting = None;
+    } else if advanced || self.waiting.is_none() {
+      self.waiting = Some(now);
+    }
+    ready
+  }
+
+  fn advance(&mut self) {
+    self.next = self.next.map(|next| next.wrapping_add(1));
+  }
+}
+
+/// Spreads packets over the paths of a bonded uplink in proportion to each
+/// path's estimated throughput: its congestion window over its round-trip
+/// time, discounted by its recent loss rate.
+#[derive(Debug)]
+pub struct Scheduler {
+  paths: Vec<PathState>,
+  refreshed: Instant,
+}
+
+#[derive(Debug)]
+struct PathState {
+  connection: quinn::Connection,
+  /// Estimated bytes per second.
+  weight: f64,
+  /// Bytes sent over the path, divided by its weight.
+  virtual_time: f64,
+  sent_packets: u64,
+  lost_packets: u64,
+  /// Smoothed fraction of packets lost.
+  loss: f64,
+}
+
+impl Scheduler {
+  /// Schedules over `paths`, which must not be empty.
+  pub fn new(paths: Vec<quinn::Connection>) -> Self {
+    let mut scheduler = Scheduler {
+      paths: paths
+        .into_iter()
+        .map(|connection| PathState {
+          connection,
+          weight: 1.0,
+          virtual_time: 0.0,
+          sent_packets: 0,
+          lost_packets: 0,
+          loss: 0.0,
+        })
+        .collect(),
+      refreshed: Instant::now(),
+    };
+    scheduler.refresh();
+    scheduler
+  }
+
+  /// Number of paths left.
+  pub fn len(&self) -> usize {
+    self.paths.len()
+  }
+
+  /// Whether every path was removed.
+  pub fn is_empty(&self) -> bool {
+    self.paths.is_empty()
+  }
+
+  /// Picks the path to send a `len` byte packet on, returning its index.
+  pub fn pick(&mut self, len: usize) -> usize {
+    if self.refreshed.elapsed() >= REFRESH_INTERVAL {
+      self.refresh();
+    }
+    let (index, path) = self
+      .paths
+      .iter_mut()
+      .enumerate()
+      .min_by(|(_, a), (_, b)| a.virtual_time.total_cmp(&b.virtual_time))
+      .expect("scheduler has paths");
+    path.virtual_time += len as f64 / path.weight;
+    index
+  }
+
+  /// Connection of path `index`.
+  pub fn connection(&self, index: usize) -> &quinn::Connection {
+    &self.paths[index].connection
+  }
+
+  /// Stops using path `index`.
+  pub fn remove(&mut self, index: usize) {
+    self.paths.remove(index);
+  }
+
+  /// Re-reads the round-trip time, congestion window and losses of every
+  /// path.
+  fn refresh(&mut self) {
+    for path in &mut self.paths {
+      let stats = path.connection.stats().path;
+      let sent = stats.sent_packets.saturating_sub(path.sent_packets);
+      let lost = stats.lost_packets.saturating_sub(path.lost_packets);
+      if sent > 0 {
+        let sample = (lost as f64 / sent as f64).min(1.0);
+        path.loss += LOSS_GAIN * (sample - path.loss);
+      }
+      path.sent_packets = stats.sent_packets;
+      path.lost_packets = stats.lost_packets;
+      let rtt = stats.rtt.as_secs_f64().max(0.001);
+      path.weight = (stats.cwnd as f64 / rtt * (1.0 - path.loss)).max(1.0);
+      debug!(
+        remote = %net::canonical(path.connection.remote_address()),
+        rtt = ?stats.rtt,
+        cwnd = stats.cwnd,
+        loss = path.loss,
+        "bonded path"
+      );
+    }
+    // Paths that were idle start level with the others, not with credit.
+    let start = self
+      .paths
+      .iter()
+      .map(|path| path.virtual_time)
+      .fold(f64::INFINITY, f64::min);
+    for path in &mut self.paths {
+      path.virtual_time -= start;
+    }
+    self.refreshed = Instant::now();
+  }
+}
+
+/// Forwards packets between `tun` and the paths of a bonded uplink until
+/// the last path fails. A path that fails while others remain is dropped.
+pub async fn pump(tun: Arc<Tun>, paths: Vec<quinn::Connection>) -> Result<()> {
+  let outbound = async {
+    let mut scheduler = Scheduler::new(paths.clone());
+    let mut buf = vec![0; tun.max_len()];
+    let mut seq = 0u32;
+    loop {
+      let len = tun.recv(&mut buf).await?;
+      seq = seq.wrapping_add(1);
+      let frame = Frame::sequenced(seq, buf[..len].to_vec());
+      loop {
+        let index = scheduler.pick(len);
+        let connection = scheduler.connection(index);
+        match tun::send_frame(connection, frame.clone()) {
+          Ok(()) => break,
+          Err(err) if scheduler.len() > 1 => {
+            warn!(
+              remote = %net::canonical(connection.remote_address()),
+              "bonded path failed: {}",
+              err
+            );
+            scheduler.remove(index);
+          }
+          Err(err) => return Err(err),
+        }
+      }
+    }
+  };
+  let inbound = async {
+    let mut readers: FuturesUnordered<_> = paths
+      .iter()
+      .map(|connection| forward(&tun, connection))
+      .collect();
+    while let Some(res) = readers.next().await {
+      match res {
+        Err(err) if readers.is_empty() => return Err(err),
+        Err(err) => warn!("bonded path failed: {}", err),
+        Ok(()) => {}
+      }
+    }
+    Ok(())
+  };
+  tokio::select! {
+    res = outbound => res,
+    res = inbound => res,
+  }
+}
+
+/// Writes the tunnel packets received on `connection` to `tun`.
+async fn forward(tun: &Tun, connection: &quinn::Connection) -> Result<()> {
+  let mut reassembler = Reassembler::default();
+  loop {
+    match Frame::decode(connection.read_datagram().await?) {
+      Some(Frame {
+        kind: Kind::Packet,
+        payload,
+        ..
+      }) => tun.send(&payload).await?,
+      Some(Frame {
+        kind: Kind::Fragment,
+        payload,
+        ..
+      }) => {
+        if let Some(packet) = reassembler.push(payload) {
+          tun.send(&packet).await?;
+        }
+      }
+      _ => debug!("ignoring non-packet datagram"),
+    }
+  }
+}
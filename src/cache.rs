@@ -0,0 +1,185 @@
+//! Client-side cache of downloaded files.
+//!
+//! A client with a cache directory keeps the body of each whole-file
+//! download whose response has an `ETag` or `Last-Modified` header, with
+//! those validators, under a name derived from the URL. The next request
+//! for the URL carries `If-None-Match` and `If-Modified-Since`, and if the
+//! server answers `304 Not Modified` the body is read from the cache
+//! instead of being transferred again.
+//!
+//! Each entry is a `<key>.json` file with the validators and response
+//! headers next to a `<key>.body` file. Bodies are written to
+//! `<key>.part` first, so an interrupted download never replaces a good
+//! entry.
+
+use std::path::{Path, PathBuf};
+
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use crate::{http::ResponseHead, Result};
+
+/// Response headers kept with a cached body.
+const KEPT_HEADERS: &[&str] = &[
+  "Content-Type",
+  "ETag",
+  "Last-Modified",
+  crate::integrity::HEADER,
+];
+
+/// A directory of cached responses.
+#[derive(Debug, Clone)]
+pub struct Cache {
+  dir: PathBuf,
+}
+
+/// What the cache knows about a URL.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+  /// The URL, without its query.
+  pub url: String,
+  /// Response headers, for the head of responses served from the cache.
+  pub headers: Vec<(String, String)>,
+  /// Length of the body in bytes.
+  pub len: u64,
+}
+
+impl Entry {
+  /// Header lines asking the server to answer `304 Not Modified` unless
+  /// the file changed, each ending in `\r\n`.
+  pub fn conditions(&self) -> String {
+    let mut lines = String::new();
+    let find = |name| crate::http::find_header(&self.headers, name);
+    if let Some(etag) = find("ETag") {
+      lines.push_str(&format!("If-None-Match: {}\r\n", etag));
+    }
+    if let Some(modified) = find("Last-Modified") {
+      lines.push_str(&format!("If-Modified-Since: {}\r\n", modified));
+    }
+    lines
+  }
+
+  /// The head of a response served from the cache.
+  pub fn head(&self) -> ResponseHead {
+    let head = self
+      .headers
+      .iter()
+      .fold(ResponseHead::new(200, "OK"), |head, (name, value)| {
+        head.header(name, value)
+      });
+    head.header("Content-Length", self.len)
+  }
+}
+
+impl Cache {
+  /// Keeps cached responses in `dir`, which is created when needed.
+  pub fn new(dir: impl Into<PathBuf>) -> Self {
+    Cache { dir: dir.into() }
+  }
+
+  /// The entry for `url`, if its body is cached.
+  pub async fn lookup(&self, url: &Url) -> Option<Entry> {
+    let key = key(url);
+    let meta = tokio::fs::read(self.path(&key, "json")).await.ok()?;
+    let entry: Entry = serde_json::from_slice(&meta).ok()?;
+    let body = tokio::fs::metadata(self.path(&key, "body")).await.ok()?;
+    Some(entry).filter(|x| x.url == strip_query(url) && x.len == body.len())
+  }
+
+  /// Opens the cached body of `url`.
+  pub async fn open(&self, url: &Url) -> Result<tokio::fs::File> {
+    Ok(tokio::fs::File::open(self.path(&key(url), "body")).await?)
+  }
+
+  /// Starts caching the body of the response to a request for `url`, if
+  /// `head` has validators to check it with later.
+  pub async fn writer(&self, url: &Url, head: &ResponseHead) -> Result<Option<Writer>> {
+    if head.get("ETag").is_none() && head.get("Last-Modified").is_none() {
+      return Ok(None);
+    }
+    tokio::fs::create_dir_all(&self.dir).await?;
+    let key = key(url);
+    let part = self.path(&key, "part");
+    let file = tokio::fs::File::create(&part).await?;
+    let headers = head
+      .headers
+      .iter()
+      .filter(|(name, _)| KEPT_HEADERS.iter().any(|x| name.eq_ignore_ascii_case(x)))
+      .cloned()
+      .collect();
+    Ok(Some(Writer {
+      file,
+      part,
+      body: self.path(&key, "body"),
+      meta: self.path(&key, "json"),
+      entry: Entry {
+        url: strip_query(url),
+        headers,
+        len: 0,
+      },
+    }))
+  }
+
+  fn path(&self, key: &str, extension: &str) -> PathBuf {
+    self.dir.join(format!("{}.{}", key, extension))
+  }
+}
+
+/// Writes a body into the cache as it arrives.
+#[derive(Debug)]
+pub struct Writer {
+  file: tokio::fs::File,
+  part: PathBuf,
+  body: PathBuf,
+  meta: PathBuf,
+  entry: Entry,
+}
+
+impl Writer {
+  /// Appends the next part of the body.
+  pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+    self.file.write_all(data).await?;
+    self.entry.len += data.len() as u64;
+    Ok(())
+  }
+
+  /// Replaces the entry with the complete body.
+  pub async fn finish(mut self) -> Result<()> {
+    self.file.flush().await?;
+    drop(self.file);
+    // Without its metadata, the old body is never served while replaced.
+    match tokio::fs::remove_file(&self.meta).await {
+      Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+      _ => {}
+    }
+    tokio::fs::rename(&self.part, &self.body).await?;
+    let meta = serde_json::to_vec_pretty(&self.entry).expect("entries serialize");
+    write_atomic(&self.meta, &meta).await
+  }
+}
+
+/// Names cache entries after the SHA-256 of the URL without its query,
+/// which may hold an access token.
+fn key(url: &Url) -> String {
+  digest::digest(&digest::SHA256, strip_query(url).as_bytes())
+    .as_ref()
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect()
+}
+
+fn strip_query(url: &Url) -> String {
+  let mut url = url.clone();
+  url.set_query(None);
+  url.set_fragment(None);
+  url.to_string()
+}
+
+async fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+  let tmp = path.with_extension("tmp");
+  tokio::fs::write(&tmp, contents).await?;
+  tokio::fs::rename(&tmp, path).await?;
+  Ok(())
+}
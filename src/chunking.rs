@@ -0,0 +1,180 @@
+//! Splitting large messages between peers.
+//!
+//! Peers read every message into memory whole, so a message bigger than
+//! the chunk size is sent as [`Message::Chunk`]s instead: parts of at most
+//! the chunk size, each carrying the message's random id, its index and the
+//! number of parts. The receiver puts the parts together in whatever order
+//! they arrive and handles the result like any other message. Messages are
+//! refused past a maximum size, and parts of a message not completed in
+//! [`ASSEMBLY_TIMEOUT`] are dropped.
+
+use std::{
+  collections::{BTreeMap, HashMap},
+  net::SocketAddr,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use tracing::debug;
+
+use crate::{
+  protocol::{Message, MAX_FRAME},
+  topics, QvpnError, Result,
+};
+
+/// Largest part sent by default.
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Largest message put together from parts by default: a frame of the
+/// largest size decoded, with its length.
+pub const DEFAULT_MAX_ASSEMBLED: usize = MAX_FRAME + 4;
+
+/// How long the parts of a message may take to arrive.
+pub const ASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Most parts a message may be split into.
+pub const MAX_PARTS: u32 = 4096;
+
+/// Messages from one peer put together at once.
+const MAX_PARTIAL: usize = 8;
+
+/// Sizes of parts and of the messages put together from them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkLimits {
+  /// Largest part sent; bigger messages are split.
+  pub chunk_size: usize,
+  /// Largest message accepted in parts.
+  pub max_assembled: usize,
+}
+
+impl Default for ChunkLimits {
+  fn default() -> Self {
+    ChunkLimits {
+      chunk_size: DEFAULT_CHUNK_SIZE,
+      max_assembled: DEFAULT_MAX_ASSEMBLED,
+    }
+  }
+}
+
+/// Splits `msg` into encoded [`Message::Chunk`]s of at most `chunk_size`
+/// bytes of it each.
+pub fn split(msg: &Bytes, chunk_size: usize) -> Vec<Bytes> {
+  let id = topics::new_id();
+  let parts: Vec<_> = msg.chunks(chunk_size.max(1)).collect();
+  let count = parts.len() as u32;
+  parts
+    .into_iter()
+    .enumerate()
+    .map(|(index, data)| {
+      Message::Chunk {
+        id,
+        index: index as u32,
+        count,
+        data: data.to_vec(),
+      }
+      .encode()
+    })
+    .collect()
+}
+
+/// Messages being put together from their parts.
+#[derive(Debug)]
+pub struct Assembler {
+  max_assembled: usize,
+  partial: Mutex<HashMap<(SocketAddr, u64), Partial>>,
+}
+
+#[derive(Debug)]
+struct Partial {
+  count: u32,
+  /// Parts received, by index.
+  parts: BTreeMap<u32, Vec<u8>>,
+  size: usize,
+  started: Instant,
+}
+
+impl Assembler {
+  /// Accepts messages of up to `max_assembled` bytes.
+  pub fn new(max_assembled: usize) -> Self {
+    Assembler {
+      max_assembled,
+      partial: Mutex::default(),
+    }
+  }
+
+  /// Adds part `index` of `count` of the message `id` from `peer`,
+  /// returning the message once every part arrived. Fails, dropping the
+  /// parts received, if the message grows too big or its parts disagree.
+  pub fn add(
+    &self,
+    peer: SocketAddr,
+    id: u64,
+    index: u32,
+    count: u32,
+    data: Vec<u8>,
+  ) -> Result<Option<Bytes>> {
+    if index >= count || count > MAX_PARTS {
+      return Err(QvpnError::Protocol(format!(
+        "bad message part {} of {}",
+        index, count
+      )));
+    }
+    let mut partial = self.partial.lock().expect("assembler lock poisoned");
+    partial.retain(|(peer, id), x| {
+      let stale = x.started.elapsed() >= ASSEMBLY_TIMEOUT;
+      if stale {
+        debug!(%peer, id, "dropping incomplete message");
+      }
+      !stale
+    });
+    let key = (peer, id);
+    if !partial.contains_key(&key)
+      && partial.keys().filter(|(x, _)| *x == peer).count() >= MAX_PARTIAL
+    {
+      return Err(QvpnError::Protocol(format!(
+        "more than {} messages in parts at once",
+        MAX_PARTIAL
+      )));
+    }
+    let message = partial.entry(key).or_insert_with(|| Partial {
+      count,
+      parts: BTreeMap::new(),
+      size: 0,
+      started: Instant::now(),
+    });
+    if message.count != count {
+      partial.remove(&key);
+      return Err(QvpnError::Protocol(
+        "message parts disagree on their count".into(),
+      ));
+    }
+    if message.parts.contains_key(&index) {
+      return Ok(None);
+    }
+    message.size += data.len();
+    if message.size > self.max_assembled {
+      partial.remove(&key);
+      return Err(QvpnError::Protocol(format!(
+        "message in parts exceeds the {} byte limit",
+        self.max_assembled
+      )));
+    }
+    message.parts.insert(index, data);
+    if message.parts.len() < count as usize {
+      return Ok(None);
+    }
+    let message = partial.remove(&key).expect("present above");
+    let mut assembled = BytesMut::with_capacity(message.size);
+    for part in message.parts.values() {
+      assembled.extend_from_slice(part);
+    }
+    Ok(Some(assembled.freeze()))
+  }
+
+  /// Drops the parts received from a disconnected peer.
+  pub fn forget(&self, peer: &SocketAddr) {
+    let mut partial = self.partial.lock().expect("assembler lock poisoned");
+    partial.retain(|(x, _), _| x != peer);
+  }
+}
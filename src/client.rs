@@ -0,0 +1,2439 @@
+//! HTTP/0.9 style file client over QUIC.
+
+use std::{
+  collections::HashMap,
+  convert::{TryFrom, TryInto},
+  io::SeekFrom,
+  iter,
+  net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs},
+  path::{Path, PathBuf},
+  str,
+  sync::{
+    atomic::{AtomicU32, AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use futures::{stream::FuturesUnordered, Future, FutureExt, StreamExt, TryStreamExt};
+use percent_encoding::utf8_percent_encode;
+use quinn::crypto::rustls::QuicClientConfig;
+use tokio::{
+  io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+  net::{TcpListener, TcpStream, UdpSocket},
+  sync::broadcast,
+};
+use tracing::{debug, info, warn};
+use url::{Host, Url};
+
+use crate::{
+  auth::{self, Credentials},
+  bench::{self, Counters, Loss, Report, Rtt},
+  bond,
+  cache::{self, Cache, Entry},
+  compress::{self, Decoder},
+  congestion::{self, Congestion},
+  control::{self, Control},
+  datagram::{Frame, Kind, Transport},
+  dns::{DnsConfig, DnsOverride},
+  doq, expose, forward,
+  hook::{self, Hooks},
+  http::{self, ResponseHead},
+  integrity::{self, Verifier},
+  kill_switch::KillSwitch,
+  lease::{Ipv4Net, Lease},
+  listing::{self, Format},
+  net,
+  oidc::{DeviceCode, Login},
+  proxy,
+  psk::Psk,
+  qlog,
+  reconnect::ReconnectPolicy,
+  rpc::{self, Caller},
+  service,
+  site::{Firewall, Masquerade},
+  socks,
+  stats::{self, Stats, Tracker},
+  sync,
+  tls::Trust,
+  tun::{self, BypassRoute, Tun, TunConfig},
+  tuning::Tuning,
+  udp, version, QvpnError, Result, ALPN_QUIC_HTTP,
+};
+
+/// Builder for a [`Client`].
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+  bind: SocketAddr,
+  transport: Transport,
+  idle_timeout: Option<Duration>,
+  keep_alive_interval: Option<Duration>,
+  trust: Trust,
+  format: Option<Format>,
+  access_token: Option<String>,
+  compression: bool,
+  verify: bool,
+  cache: Option<PathBuf>,
+  qlog: Option<PathBuf>,
+  reconnect: ReconnectPolicy,
+  zero_rtt: bool,
+  congestion: Congestion,
+  tuning: Tuning,
+  psk: Option<Psk>,
+  credentials: Option<Credentials>,
+  oidc: Option<Login>,
+  full_tunnel: bool,
+  attempt_delay: Duration,
+  failover: Vec<Url>,
+  health_timeout: Duration,
+  migration: bool,
+  bond: Vec<IpAddr>,
+  advertise: Vec<Ipv4Net>,
+  hooks: Hooks,
+  stats_interval: Option<Duration>,
+}
+
+impl Default for ClientBuilder {
+  fn default() -> Self {
+    Self {
+      bind: net::dual_stack(0),
+      transport: Transport::default(),
+      idle_timeout: None,
+      keep_alive_interval: None,
+      trust: Trust::default(),
+      format: None,
+      access_token: None,
+      compression: false,
+      verify: false,
+      cache: None,
+      qlog: None,
+      reconnect: ReconnectPolicy::default(),
+      zero_rtt: false,
+      congestion: Congestion::default(),
+      tuning: Tuning::default(),
+      psk: None,
+      credentials: None,
+      oidc: None,
+      full_tunnel: false,
+      attempt_delay: DEFAULT_ATTEMPT_DELAY,
+      failover: vec![],
+      health_timeout: DEFAULT_HEALTH_TIMEOUT,
+      migration: true,
+      bond: vec![],
+      advertise: vec![],
+      hooks: Hooks::default(),
+      stats_interval: None,
+    }
+  }
+}
+
+/// How long the server gets to answer a control keepalive before it is
+/// considered unresponsive.
+pub const DEFAULT_HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a connection attempt gets before the next address is tried
+/// alongside it, as recommended by RFC 8305.
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long to wait for the reply to a datagram request before giving up.
+const DATAGRAM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long, beyond two round trips, to wait for benchmark datagrams after
+/// the server is done sending them.
+const DATAGRAM_GRACE: Duration = Duration::from_millis(100);
+
+impl ClientBuilder {
+  /// Local address to bind the client endpoint to. The default `[::]:0`
+  /// reaches servers over both IPv6 and IPv4.
+  pub fn bind(mut self, addr: SocketAddr) -> Self {
+    self.bind = addr;
+    self
+  }
+
+  /// How requests are carried to the server.
+  pub fn transport(mut self, transport: Transport) -> Self {
+    self.transport = transport;
+    self
+  }
+
+  /// Close connections after this long without activity.
+  pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+    self.idle_timeout = Some(timeout);
+    self
+  }
+
+  /// Send keep-alives after this long without traffic.
+  pub fn keep_alive_interval(mut self, interval: Duration) -> Self {
+    self.keep_alive_interval = Some(interval);
+    self
+  }
+
+  /// Write a qlog trace of every connection into this directory.
+  pub fn qlog(mut self, dir: impl Into<PathBuf>) -> Self {
+    self.qlog = Some(dir.into());
+    self
+  }
+
+  /// Reconnect and replay the request when the connection to the server is
+  /// lost. Tunnels and proxies reconnect and carry on.
+  pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+    self.reconnect = policy;
+    self
+  }
+
+  /// Congestion controller for connections to the server.
+  pub fn congestion(mut self, congestion: Congestion) -> Self {
+    self.congestion = congestion;
+    self
+  }
+
+  /// Replace quinn's flow-control windows and initial RTT.
+  pub fn tuning(mut self, tuning: Tuning) -> Self {
+    self.tuning = tuning;
+    self
+  }
+
+  /// Send GET requests as 0-RTT early data when resuming a session with a
+  /// server that allows it. Session tickets are kept in memory for as long
+  /// as the client, so only its later connections to a server resume: rustls
+  /// offers no way to store them on disk and load them in another run.
+  pub fn zero_rtt(mut self, enabled: bool) -> Self {
+    self.zero_rtt = enabled;
+    self
+  }
+
+  /// Prove knowledge of this pre-shared key to the server after each
+  /// handshake. Requests then wait for the handshake instead of going out as
+  /// 0-RTT data.
+  pub fn psk(mut self, psk: Psk) -> Self {
+    self.psk = Some(psk);
+    self
+  }
+
+  /// Log in with these credentials before tunneling or proxying, as
+  /// servers with an [`auth`](crate::auth) backend require.
+  pub fn credentials(mut self, credentials: Credentials) -> Self {
+    self.credentials = Some(credentials);
+    self
+  }
+
+  /// Log in with an ID token from an OpenID Connect device login before
+  /// tunneling or proxying. The user is asked to approve it through an
+  /// [`Event::LoginRequired`]. See [`oidc`](crate::oidc).
+  pub fn oidc(mut self, login: Login) -> Self {
+    self.oidc = Some(login);
+    self
+  }
+
+  /// How the server's certificate is verified.
+  pub fn trust(mut self, trust: Trust) -> Self {
+    self.trust = trust;
+    self
+  }
+
+  /// Ask for directory listings in this format.
+  pub fn format(mut self, format: Format) -> Self {
+    self.format = Some(format);
+    self
+  }
+
+  /// Present this access token with every request, for servers that
+  /// require one. A `token` query parameter in the URL does the same. See
+  /// [`access`](crate::access).
+  pub fn access_token(mut self, token: impl Into<String>) -> Self {
+    self.access_token = Some(token.into());
+    self
+  }
+
+  /// Ask for whole files to be sent compressed, which servers with
+  /// compression enabled do unless their type already is. Bodies are
+  /// decompressed as they arrive. See [`compress`](crate::compress).
+  pub fn compression(mut self, enabled: bool) -> Self {
+    self.compression = enabled;
+    self
+  }
+
+  /// Ask the server for the SHA-256 of each file downloaded and fail the
+  /// download unless it matches, or if the server doesn't send one. See
+  /// [`integrity`](crate::integrity).
+  pub fn verify(mut self, enabled: bool) -> Self {
+    self.verify = enabled;
+    self
+  }
+
+  /// Keep downloaded files in this directory and fetch them again only if
+  /// the server says they changed. See [`cache`](crate::cache).
+  pub fn cache(mut self, dir: impl Into<PathBuf>) -> Self {
+    self.cache = Some(dir.into());
+    self
+  }
+
+  /// Route all IPv4 traffic through a [`Client::tunnel`], not just the
+  /// networks the server pushes. The route to the server itself is pinned
+  /// outside the tunnel.
+  pub fn full_tunnel(mut self, enabled: bool) -> Self {
+    self.full_tunnel = enabled;
+    self
+  }
+
+  /// When the server name resolves to several addresses, wait this long for
+  /// each connection attempt before racing one to the next address.
+  pub fn attempt_delay(mut self, delay: Duration) -> Self {
+    self.attempt_delay = delay;
+    self
+  }
+
+  /// Servers a [`Client::tunnel`] fails over to, in order of preference,
+  /// when the one it uses stops responding or can't be reached.
+  pub fn failover(mut self, servers: Vec<Url>) -> Self {
+    self.failover = servers;
+    self
+  }
+
+  /// Treat the server as unresponsive when it leaves a control keepalive
+  /// unanswered for this long.
+  pub fn health_timeout(mut self, timeout: Duration) -> Self {
+    self.health_timeout = timeout;
+    self
+  }
+
+  /// Move tunnels and proxies to a new socket when the host changes
+  /// networks, so their connections migrate instead of timing out.
+  /// Enabled by default.
+  pub fn migration(mut self, enabled: bool) -> Self {
+    self.migration = enabled;
+    self
+  }
+
+  /// Experimental: also carry a [`Client::tunnel`] over one connection
+  /// from each of these local addresses, say of a second network, spreading
+  /// packets across all of them. See [`bond`].
+  pub fn bond(mut self, locals: Vec<IpAddr>) -> Self {
+    self.bond = locals;
+    self
+  }
+
+  /// Be the gateway of these networks for a [`Client::tunnel`]: the server
+  /// routes them to this client and pushes them to the others, if its
+  /// rules allow. See [`site`](crate::site).
+  pub fn advertise(mut self, networks: Vec<Ipv4Net>) -> Self {
+    self.advertise = networks;
+    self
+  }
+
+  /// Run the `up` and `down` commands of `hooks` as a [`Client::tunnel`]
+  /// comes up and goes down. See [`hook`](crate::hook).
+  pub fn hooks(mut self, hooks: Hooks) -> Self {
+    self.hooks = hooks;
+    self
+  }
+
+  /// Log the [`Stats`] of every connection this often.
+  pub fn stats_interval(mut self, interval: Duration) -> Self {
+    self.stats_interval = Some(interval);
+    self
+  }
+
+  /// Binds the client endpoint.
+  pub fn build(self) -> Result<Client> {
+    let mut transport_config = quinn::TransportConfig::default();
+    if let Some(idle_timeout) = self.idle_timeout {
+      transport_config.max_idle_timeout(Some(idle_timeout.try_into()?));
+    }
+    transport_config.keep_alive_interval(self.keep_alive_interval);
+    self.congestion.configure(&mut transport_config);
+    self.tuning.configure(&mut transport_config)?;
+    let transport_config = Arc::new(transport_config);
+    let mut crypto = self.trust.client_config()?;
+    crypto.enable_early_data = self.zero_rtt;
+    let mut doq_crypto = crypto.clone();
+    doq_crypto.alpn_protocols = vec![doq::ALPN.to_vec()];
+    crypto.alpn_protocols = ALPN_QUIC_HTTP.iter().map(|x| x.to_vec()).collect();
+    let client_config = |crypto| -> Result<quinn::ClientConfig> {
+      let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+      config.transport_config(transport_config.clone());
+      Ok(config)
+    };
+    let doq_config = client_config(doq_crypto)?;
+
+    let mut endpoint = quinn::Endpoint::new(
+      quinn::EndpointConfig::default(),
+      None,
+      net::bind_udp(self.bind)?,
+      Arc::new(quinn::TokioRuntime),
+    )?;
+    let client_config = client_config(crypto)?;
+    endpoint.set_default_client_config(client_config.clone());
+    let stats = Tracker::default();
+    if let Some(interval) = self.stats_interval {
+      tokio::spawn(stats::report(&stats, interval));
+    }
+    Ok(Client {
+      endpoint,
+      bind: self.bind,
+      transport: self.transport,
+      format: self.format,
+      access_token: self.access_token,
+      compression: self.compression,
+      verify: self.verify,
+      cache: self.cache.map(Cache::new),
+      next_id: AtomicU32::new(1),
+      qlog: self.qlog,
+      traces: Mutex::default(),
+      reconnect: self.reconnect,
+      zero_rtt: self.zero_rtt,
+      congestion: self.congestion,
+      psk: self.psk,
+      credentials: self.credentials,
+      oidc: self.oidc,
+      full_tunnel: self.full_tunnel,
+      attempt_delay: self.attempt_delay,
+      failover: self.failover,
+      health_timeout: self.health_timeout,
+      migration: self.migration,
+      bond: self.bond,
+      advertise: self.advertise,
+      hooks: self.hooks,
+      stats,
+      events: broadcast::channel(EVENT_CAPACITY).0,
+      client_config,
+      doq_config,
+      pinned: Mutex::default(),
+    })
+  }
+}
+
+/// How often tunnels and proxies check whether the host changed networks.
+const PATH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Events a [`Client::events`] receiver lags behind by before missing some.
+const EVENT_CAPACITY: usize = 16;
+
+/// Something that happened to the connections of a [`Client`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+  /// The host changed networks and the connection to `remote` migrated
+  /// from local address `from` to `to`, which the server answered on.
+  PathChanged {
+    remote: SocketAddr,
+    from: IpAddr,
+    to: IpAddr,
+  },
+  /// An OpenID Connect login waits for the user to enter the code at the
+  /// identity provider.
+  LoginRequired(DeviceCode),
+  /// A tunnel through `remote` came up on `interface`, with the leased
+  /// tunnel address `address`.
+  TunnelUp {
+    interface: String,
+    address: Ipv4Addr,
+    remote: SocketAddr,
+  },
+}
+
+/// Response to a [`Client::get`] request.
+#[derive(Debug)]
+pub struct Response {
+  /// Status line and headers. Datagram responses carry no headers, so a
+  /// plain `200 OK` head is filled in for them.
+  pub head: ResponseHead,
+  /// Response body.
+  pub body: Vec<u8>,
+  /// Time from sending the request to receiving the full response.
+  pub duration: Duration,
+}
+
+/// Body of a response returned by [`Client::stream`].
+pub struct Body {
+  /// Closed once the body is complete, unless shared with other requests.
+  connection: Option<(quinn::Connection, Congestion)>,
+  buffered: Vec<u8>,
+  recv: Option<quinn::RecvStream>,
+  received: u64,
+  expected: Option<u64>,
+  /// Undoes the `Content-Encoding` of the body, if it has one.
+  decoder: Option<Decoder>,
+  /// Checks a whole body against the digest the server announced.
+  verifier: Option<Verifier>,
+  /// The cached body, read instead of the stream when the server answered
+  /// `304 Not Modified`.
+  cached: Option<tokio::fs::File>,
+  /// Copies the body into the cache.
+  writer: Option<cache::Writer>,
+  started: Instant,
+}
+
+/// Largest chunk returned by [`Body::chunk`].
+const BODY_CHUNK: usize = 64 * 1024;
+
+impl Body {
+  /// Length announced by the server, if any.
+  pub fn content_length(&self) -> Option<u64> {
+    self.expected
+  }
+
+  /// Bytes received so far, as sent before any decompression, or read from
+  /// the cache.
+  pub fn received(&self) -> u64 {
+    self.received
+  }
+
+  /// Time since the request was sent.
+  pub fn elapsed(&self) -> Duration {
+    self.started.elapsed()
+  }
+
+  /// Returns the next part of the body, decompressed if the server
+  /// compressed it, or `None` once it is complete.
+  ///
+  /// Fails if the stream ends before the announced length was received,
+  /// or if a whole body doesn't match the digest the server announced.
+  pub async fn chunk(&mut self) -> Result<Option<Vec<u8>>> {
+    let chunk = self.decoded_chunk().await?;
+    match &chunk {
+      Some(data) => {
+        if let Some(verifier) = &mut self.verifier {
+          verifier.update(data);
+        }
+        if let Some(writer) = &mut self.writer {
+          writer.write(data).await?;
+        }
+      }
+      None => {
+        if let Some(verifier) = self.verifier.take() {
+          verifier.finish()?;
+        }
+        if let Some(writer) = self.writer.take() {
+          writer.finish().await?;
+        }
+      }
+    }
+    Ok(chunk)
+  }
+
+  /// Returns the next part of the body with its encoding undone.
+  async fn decoded_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+    loop {
+      let chunk = self.read_chunk().await?;
+      let decoder = match &mut self.decoder {
+        Some(decoder) => decoder,
+        None => return Ok(chunk),
+      };
+      match chunk {
+        Some(chunk) => {
+          let data = decoder.update(&chunk)?;
+          if !data.is_empty() {
+            return Ok(Some(data));
+          }
+        }
+        None => {
+          if let Some(decoder) = self.decoder.take() {
+            decoder.finish()?;
+          }
+          return Ok(None);
+        }
+      }
+    }
+  }
+
+  /// Returns the next part of the body as sent.
+  async fn read_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+    let chunk = if !self.buffered.is_empty() {
+      Some(std::mem::take(&mut self.buffered))
+    } else if let Some(file) = &mut self.cached {
+      let mut buf = vec![0; BODY_CHUNK];
+      match file.read(&mut buf).await? {
+        0 => None,
+        len => {
+          buf.truncate(len);
+          Some(buf)
+        }
+      }
+    } else if let Some(recv) = &mut self.recv {
+      let mut buf = vec![0; BODY_CHUNK];
+      match recv
+        .read(&mut buf)
+        .await
+        .map_err(quinn::ReadToEndError::Read)?
+      {
+        Some(len) => {
+          buf.truncate(len);
+          Some(buf)
+        }
+        None => None,
+      }
+    } else {
+      None
+    };
+    match chunk {
+      Some(chunk) => {
+        self.received += chunk.len() as u64;
+        Ok(Some(chunk))
+      }
+      None => {
+        self.recv = None;
+        self.cached = None;
+        if let Some((connection, congestion)) = self.connection.take() {
+          close(&connection, congestion);
+        }
+        match self.expected {
+          Some(len) if len != self.received => Err(QvpnError::Protocol(format!(
+            "expected {} bytes but received {}",
+            len, self.received
+          ))),
+          _ => Ok(None),
+        }
+      }
+    }
+  }
+}
+
+impl Response {
+  /// Throughput of the response body in MiB/s.
+  pub fn throughput(&self) -> f32 {
+    self.body.len() as f32 / (duration_secs(&self.duration) * 1024.0 * 1024.0)
+  }
+}
+
+/// A bound QUIC client endpoint.
+pub struct Client {
+  endpoint: quinn::Endpoint,
+  bind: SocketAddr,
+  transport: Transport,
+  format: Option<Format>,
+  access_token: Option<String>,
+  compression: bool,
+  verify: bool,
+  cache: Option<Cache>,
+  next_id: AtomicU32,
+  qlog: Option<PathBuf>,
+  traces: Mutex<Vec<qlog::Trace>>,
+  reconnect: ReconnectPolicy,
+  zero_rtt: bool,
+  congestion: Congestion,
+  psk: Option<Psk>,
+  credentials: Option<Credentials>,
+  oidc: Option<Login>,
+  full_tunnel: bool,
+  attempt_delay: Duration,
+  failover: Vec<Url>,
+  health_timeout: Duration,
+  migration: bool,
+  bond: Vec<IpAddr>,
+  advertise: Vec<Ipv4Net>,
+  hooks: Hooks,
+  stats: Tracker,
+  events: broadcast::Sender<Event>,
+  /// Connection settings of the endpoint, for the bonded paths' endpoints.
+  client_config: quinn::ClientConfig,
+  /// Connection settings with the DoQ ALPN protocol.
+  doq_config: quinn::ClientConfig,
+  /// Addresses server names resolved to when a kill switch went up, used
+  /// instead of resolving them again while it blocks name servers.
+  pinned: Mutex<HashMap<(String, u16), Vec<SocketAddr>>>,
+}
+
+impl Client {
+  /// Returns a builder with default settings.
+  pub fn builder() -> ClientBuilder {
+    ClientBuilder::default()
+  }
+
+  /// Transfer statistics of every open connection.
+  pub fn stats(&self) -> Vec<Stats> {
+    self.stats.snapshot()
+  }
+
+  /// Subscribes to the [`Event`]s of tunnels and proxies from now on.
+  pub fn events(&self) -> broadcast::Receiver<Event> {
+    self.events.subscribe()
+  }
+
+  /// Connects to the server named by `url`, using `host` as the TLS server
+  /// name if given.
+  pub async fn connect(&self, url: &Url, host: Option<&str>) -> Result<quinn::Connection> {
+    let (connection, _) = self.dial(url, host, false).await?;
+    Ok(connection)
+  }
+
+  /// Connects like [`Client::connect`]. With `early` set and a resumable
+  /// session, returns before the handshake completes so requests go out as
+  /// 0-RTT data, along with a future telling whether the server accepted
+  /// them.
+  async fn dial(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+    early: bool,
+  ) -> Result<(quinn::Connection, Option<quinn::ZeroRttAccepted>)> {
+    let (remotes, host) = remote(url, host, 443, self.endpoint.local_addr()?, &self.pinned)?;
+    info!(%host, remote = %remotes[0], "connecting");
+    let connecting = self.endpoint.connect(remotes[0], host)?;
+    let (connection, accepted) = if early && self.psk.is_none() {
+      match connecting.into_0rtt() {
+        Ok((connection, accepted)) => {
+          debug!("resuming session with 0-RTT");
+          (connection, Some(accepted))
+        }
+        Err(connecting) => (
+          self
+            .race(connecting, &remotes, host, None, ALPN_QUIC_HTTP)
+            .await?,
+          None,
+        ),
+      }
+    } else {
+      (
+        self
+          .race(connecting, &remotes, host, None, ALPN_QUIC_HTTP)
+          .await?,
+        None,
+      )
+    };
+    if let Some(dir) = &self.qlog {
+      match qlog::Trace::start(dir, &connection, qlog::Vantage::Client).await {
+        Ok(trace) => {
+          let mut traces = self.traces.lock().expect("trace lock poisoned");
+          traces.retain(|trace| !trace.is_finished());
+          traces.push(trace);
+        }
+        Err(err) => warn!("couldn't start qlog trace: {}", err),
+      }
+    }
+    self.stats.track(&connection);
+    if let Some(psk) = &self.psk {
+      psk.authenticate(&connection).await?;
+      debug!("pre-shared key proven");
+    }
+    Ok((connection, accepted))
+  }
+
+  /// Connects like [`Client::connect`] and negotiates the qvpn protocol
+  /// version, returning it along with the control stream tunnels and
+  /// proxies need. Logs in first if the client has credentials.
+  async fn connect_qvpn(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+  ) -> Result<(quinn::Connection, u32, Control)> {
+    let connection = self.connect(url, host).await?;
+    let (version, mut control) = version::negotiate(&connection).await?;
+    debug!(version, "negotiated protocol version");
+    if (self.credentials.is_some() || self.oidc.is_some()) && version < version::AUTH {
+      return Err(QvpnError::Incompatible(
+        "the server predates logins; upgrade it or drop the credentials".into(),
+      ));
+    }
+    if let Some(credentials) = &self.credentials {
+      credentials.log_in(&mut control).await?;
+      debug!(user = credentials.user(), "logged in");
+    } else if let Some(login) = &self.oidc {
+      let token = login
+        .id_token(|code| {
+          info!(
+            url = %code.verification_uri,
+            code = %code.user_code,
+            "approve the login in a browser to continue"
+          );
+          let _ = self.events.send(Event::LoginRequired(code.clone()));
+        })
+        .await?;
+      auth::present_token(&mut control, &token).await?;
+      debug!("logged in with an ID token");
+    }
+    Ok((connection, version, control))
+  }
+
+  /// Requests `url` from the server, using `host` as the TLS server name if
+  /// given. Error statuses are returned as [`QvpnError::Remote`].
+  pub async fn get(&self, url: &Url, host: Option<&str>) -> Result<Response> {
+    let response = self.fetch(url, host, 0).await?;
+    if !response.head.is_success() {
+      return Err(QvpnError::Remote(response.head.to_string()));
+    }
+    Ok(response)
+  }
+
+  /// Requests `url` from byte `offset` onwards, returning the response
+  /// whatever its status.
+  ///
+  /// A non-zero offset sends a `Range` header, which the server answers
+  /// with `206 Partial Content`, `416 Range Not Satisfiable` if the offset
+  /// is past the end, or `200 OK` and the whole file if it ignores ranges.
+  pub async fn fetch(&self, url: &Url, host: Option<&str>, offset: u64) -> Result<Response> {
+    self
+      .reconnect
+      .retry("request", || async {
+        let (head, mut body) = self.open(url, host, offset).await?;
+        let mut data = Vec::new();
+        while let Some(chunk) = body.chunk().await? {
+          data.extend_from_slice(&chunk);
+        }
+        Ok(Response {
+          head,
+          body: data,
+          duration: body.elapsed(),
+        })
+      })
+      .await
+  }
+
+  /// Like [`Client::fetch`], but returns as soon as the response head has
+  /// arrived so the body can be consumed chunk by chunk.
+  pub async fn stream(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+    offset: u64,
+  ) -> Result<(ResponseHead, Body)> {
+    self
+      .reconnect
+      .retry("request", || self.open(url, host, offset))
+      .await
+  }
+
+  /// Connects and sends a request, returning once the response head has
+  /// arrived.
+  async fn open(&self, url: &Url, host: Option<&str>, offset: u64) -> Result<(ResponseHead, Body)> {
+    if offset > 0 && self.transport == Transport::Datagram {
+      return Err(QvpnError::InvalidInput(
+        "ranges require the stream transport".into(),
+      ));
+    }
+    if self.verify && self.transport == Transport::Datagram {
+      return Err(QvpnError::InvalidInput(
+        "verifying downloads requires the stream transport".into(),
+      ));
+    }
+    let start = Instant::now();
+    let range = Some((offset, None)).filter(|_| offset > 0);
+    let cache = self
+      .cache
+      .as_ref()
+      .filter(|_| offset == 0 && self.transport == Transport::Stream);
+    let cached = match cache {
+      Some(cache) => cache.lookup(url).await,
+      None => None,
+    };
+    let request = self.get_request(url, range, cached.as_ref());
+    let early = self.zero_rtt && self.transport == Transport::Stream;
+    let (connection, accepted) = self.dial(url, host, early).await?;
+
+    debug!(elapsed = ?start.elapsed(), "connected");
+    let line = request.lines().next().unwrap_or_default();
+    info!(request = %line, "sending request");
+
+    let response_start = Instant::now();
+    match self.transport {
+      Transport::Stream => {
+        let mut result = self.send_request(&connection, &request).await;
+        if result.is_err() {
+          if let Some(accepted) = accepted {
+            if !accepted.await {
+              debug!("server rejected 0-RTT, resending request");
+              result = self.send_request(&connection, &request).await;
+            }
+          }
+        }
+        let (mut head, mut body) = result?;
+        debug!(elapsed = ?(response_start - start), "response started");
+        match (cache, cached, head.status) {
+          (Some(cache), Some(entry), 304) => {
+            info!("not modified, reading the cache");
+            body.recv = None;
+            body.buffered.clear();
+            body.cached = Some(cache.open(url).await?);
+            body.expected = Some(entry.len);
+            head = entry.head();
+            if self.verify {
+              body.verifier = integrity::expected(&head).map(Verifier::new);
+            }
+          }
+          (Some(cache), _, 200) => {
+            body.writer = cache.writer(url, &head).await.unwrap_or_else(|err| {
+              warn!("not caching {}: {}", url, err);
+              None
+            });
+          }
+          _ => {}
+        }
+        body.connection = Some((connection, self.congestion));
+        Ok((head, body))
+      }
+      Transport::Datagram => {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let frame = Frame {
+          kind: Kind::Request,
+          id,
+          payload: request.into(),
+        };
+        connection.send_datagram(frame.encode())?;
+        debug!(elapsed = ?(response_start - start), "request sent");
+        let data = tokio::time::timeout(DATAGRAM_TIMEOUT, datagram_response(&connection, id))
+          .await
+          .map_err(|_| QvpnError::Timeout("waiting for datagram response"))??;
+        let head = ResponseHead::new(200, "OK").header("Content-Length", data.len());
+        let body = Body {
+          connection: Some((connection, self.congestion)),
+          expected: Some(data.len() as u64),
+          buffered: data,
+          recv: None,
+          received: 0,
+          decoder: None,
+          verifier: None,
+          cached: None,
+          writer: None,
+          started: response_start,
+        };
+        Ok((head, body))
+      }
+    }
+  }
+
+  /// Uploads the file at `path` to `url` with a `PUT` request, returning
+  /// the response head. Error statuses are returned as
+  /// [`QvpnError::Remote`].
+  pub async fn put(&self, url: &Url, host: Option<&str>, path: &Path) -> Result<ResponseHead> {
+    self
+      .reconnect
+      .retry("upload", || async {
+        let connection = self.connect(url, host).await?;
+        let result = self.upload(&connection, url.path(), path).await;
+        close(&connection, self.congestion);
+        result.map(|(head, _)| head)
+      })
+      .await
+  }
+
+  /// Makes the directory `url` on the server match the local directory
+  /// `dir`, uploading missing and changed files over up to `streams`
+  /// concurrent streams. Files that only exist on the server are kept.
+  pub async fn sync(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+    dir: &Path,
+    streams: usize,
+  ) -> Result<sync::Summary> {
+    self
+      .reconnect
+      .retry("sync", || self.sync_once(url, host, dir, streams))
+      .await
+  }
+
+  async fn sync_once(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+    dir: &Path,
+    streams: usize,
+  ) -> Result<sync::Summary> {
+    let manifest = sync::scan(dir).await?;
+    let base = url.path().trim_end_matches('/').to_string();
+    let connection = self.connect(url, host).await?;
+    let result = async {
+      let json = serde_json::to_string(&manifest).expect("manifest serializes");
+      let request = sync::sync_request(&format!("{}/", base), json.len()) + &json;
+      let (head, mut body) = self.send_request(&connection, &request).await?;
+      if !head.is_success() {
+        return Err(QvpnError::Remote(head.to_string()));
+      }
+      let mut data = Vec::new();
+      while let Some(chunk) = body.chunk().await? {
+        data.extend_from_slice(&chunk);
+      }
+      let missing: Vec<String> = serde_json::from_slice(&data)
+        .map_err(|err| QvpnError::Protocol(format!("malformed sync response: {}", err)))?;
+      info!(
+        needed = missing.len(),
+        files = manifest.len(),
+        "compared sync manifest"
+      );
+      let uploads = futures::stream::iter(missing.iter().map(|relative| {
+        let target = format!("{}/{}", base, utf8_percent_encode(relative, listing::PATH));
+        let local = dir.join(relative);
+        let connection = &connection;
+        async move {
+          let (_, len) = self.upload(connection, &target, &local).await?;
+          info!(path = %relative, bytes = len, "uploaded");
+          Ok::<_, QvpnError>(len)
+        }
+      }))
+      .buffer_unordered(streams.max(1));
+      let bytes = uploads.try_collect::<Vec<_>>().await?.into_iter().sum();
+      Ok(sync::Summary {
+        scanned: manifest.len(),
+        uploaded: missing.len(),
+        bytes,
+      })
+    }
+    .await;
+    close(&connection, self.congestion);
+    result
+  }
+
+  /// Connects to the server named by `url`, logging in if configured, and
+  /// opens a stream to make [`rpc`] calls on. The connection lasts as long
+  /// as the caller or a clone of it.
+  pub async fn rpc(&self, url: &Url, host: Option<&str>) -> Result<Caller> {
+    let (connection, version, control) = self.connect_qvpn(url, host).await?;
+    if version < version::RPC {
+      return Err(QvpnError::Incompatible(
+        "the server predates rpc calls; upgrade it".into(),
+      ));
+    }
+    let (mut send, recv) = connection.open_bi().await?;
+    self.stats.stream_opened(&connection);
+    send.write_all(rpc::REQUEST_LINE).await?;
+    Ok(Caller::start(send, recv, Some(control)))
+  }
+
+  /// Measures goodput, round-trip times and loss between the client and
+  /// the server at `url`, which must have benchmarks enabled. Logs in
+  /// first if the client has credentials. See [`bench`].
+  pub async fn bench(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+    options: &bench::Options,
+  ) -> Result<Report> {
+    // The login lasts as long as the control stream.
+    let (connection, _, _control) = self.connect_qvpn(url, host).await?;
+    let result = self.bench_on(&connection, options).await;
+    close(&connection, self.congestion);
+    result
+  }
+
+  async fn bench_on(
+    &self,
+    connection: &quinn::Connection,
+    options: &bench::Options,
+  ) -> Result<Report> {
+    let before = self
+      .bench_counters(connection, bench::Request::Stats)
+      .await?;
+    let start = Instant::now();
+    let run = async {
+      match options.transport {
+        Transport::Stream => {
+          let requests =
+            (0..options.streams.max(1)).map(|_| self.bench_stream(connection, options.duration));
+          let bytes = futures::future::try_join_all(requests)
+            .await?
+            .into_iter()
+            .sum();
+          let elapsed = start.elapsed();
+          let after = self
+            .bench_counters(connection, bench::Request::Stats)
+            .await?;
+          Ok::<_, QvpnError>((bytes, elapsed, after, None))
+        }
+        Transport::Datagram => {
+          let (bytes, received, elapsed, after) =
+            self.bench_datagrams(connection, options.duration).await?;
+          Ok((bytes, elapsed, after, Some(received)))
+        }
+      }
+    };
+    let (samples, result) = tokio::join!(bench::sample_rtt(connection, options.duration), run);
+    let (bytes, elapsed, after, received) = result?;
+    let duration_secs = elapsed.as_secs_f64();
+    Ok(Report {
+      transport: options.transport.to_string(),
+      streams: match options.transport {
+        Transport::Stream => options.streams.max(1),
+        Transport::Datagram => 1,
+      },
+      duration_secs,
+      bytes,
+      goodput_bps: bytes as f64 * 8.0 / duration_secs,
+      rtt: Rtt::from_samples(samples),
+      loss: Loss::new(&after.since(&before), received),
+    })
+  }
+
+  /// Asks the server for its [`Counters`], after sending benchmark
+  /// datagrams if `request` says so.
+  async fn bench_counters(
+    &self,
+    connection: &quinn::Connection,
+    request: bench::Request,
+  ) -> Result<Counters> {
+    let (head, mut body) = self.send_request(connection, &request.encode()).await?;
+    if !head.is_success() {
+      return Err(QvpnError::Remote(head.to_string()));
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = body.chunk().await? {
+      data.extend_from_slice(&chunk);
+    }
+    serde_json::from_slice(&data)
+      .map_err(|err| QvpnError::Protocol(format!("malformed benchmark counters: {}", err)))
+  }
+
+  /// Receives synthetic data on a stream for `duration`, returning the
+  /// number of bytes.
+  async fn bench_stream(&self, connection: &quinn::Connection, duration: Duration) -> Result<u64> {
+    let request = bench::Request::Stream(duration).encode();
+    let (head, mut body) = self.send_request(connection, &request).await?;
+    if !head.is_success() {
+      return Err(QvpnError::Remote(head.to_string()));
+    }
+    let mut bytes = body.buffered.len() as u64;
+    let mut recv = body.recv.take().expect("streamed body");
+    // Chunks are counted without copying them, so the client's own copies
+    // don't limit the measurement.
+    while let Some(chunk) = recv
+      .read_chunk(usize::MAX, false)
+      .await
+      .map_err(quinn::ReadToEndError::Read)?
+    {
+      bytes += chunk.bytes.len() as u64;
+    }
+    Ok(bytes)
+  }
+
+  /// Receives benchmark datagrams for `duration`, returning the bytes and
+  /// datagrams received, the time until the server was done, and its
+  /// counters.
+  async fn bench_datagrams(
+    &self,
+    connection: &quinn::Connection,
+    duration: Duration,
+  ) -> Result<(u64, u64, Duration, Counters)> {
+    let start = Instant::now();
+    let (mut bytes, mut received) = (0, 0);
+    let mut count = |datagram: bytes::Bytes| {
+      if let Some(Frame {
+        kind: Kind::Bench,
+        payload,
+        ..
+      }) = Frame::decode(datagram)
+      {
+        bytes += payload.len() as u64;
+        received += 1;
+      }
+    };
+    let request = self.bench_counters(connection, bench::Request::Datagram(duration));
+    tokio::pin!(request);
+    let counters = loop {
+      tokio::select! {
+        counters = &mut request => break counters?,
+        datagram = connection.read_datagram() => count(datagram?),
+      }
+    };
+    let elapsed = start.elapsed();
+    // Datagrams sent just before the counters may still be on their way.
+    let grace = tokio::time::sleep(connection.rtt() * 2 + DATAGRAM_GRACE);
+    tokio::pin!(grace);
+    loop {
+      tokio::select! {
+        () = &mut grace => break,
+        datagram = connection.read_datagram() => count(datagram?),
+      }
+    }
+    Ok((bytes, received, elapsed, counters))
+  }
+
+  /// Downloads `url` into the file at `path` using `streams` concurrent
+  /// range requests over a single connection, returning the file length.
+  ///
+  /// `progress` is called with the size of each received chunk and the
+  /// total length. Falls back to a single stream when the server does not
+  /// answer range requests.
+  pub async fn download_parallel<F>(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+    path: &Path,
+    streams: usize,
+    progress: F,
+  ) -> Result<u64>
+  where
+    F: Fn(u64, u64),
+  {
+    self
+      .reconnect
+      .retry("download", || {
+        self.download_parallel_once(url, host, path, streams, &progress)
+      })
+      .await
+  }
+
+  async fn download_parallel_once<F>(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+    path: &Path,
+    streams: usize,
+    progress: &F,
+  ) -> Result<u64>
+  where
+    F: Fn(u64, u64),
+  {
+    if self.transport != Transport::Stream {
+      return Err(QvpnError::InvalidInput(
+        "parallel downloads require the stream transport".into(),
+      ));
+    }
+    let connection = self.connect(url, host).await?;
+    // A one byte range tells us the length and whether ranges work at all.
+    let (head, mut probe) = self
+      .send_request(
+        &connection,
+        &self.get_request(url, Some((0, Some(0))), None),
+      )
+      .await?;
+    let total = match head.status {
+      206 => head
+        .get("Content-Range")
+        .and_then(http::content_range_total)
+        .ok_or_else(|| QvpnError::Protocol("missing Content-Range".into()))?,
+      416 => 0,
+      200 => {
+        let total = probe.content_length().unwrap_or(0);
+        let mut file = tokio::fs::File::create(path).await?;
+        while let Some(chunk) = probe.chunk().await? {
+          file.write_all(&chunk).await?;
+          progress(chunk.len() as u64, total);
+        }
+        file.flush().await?;
+        close(&connection, self.congestion);
+        return Ok(probe.received());
+      }
+      _ => return Err(QvpnError::Remote(head.to_string())),
+    };
+    drop(probe);
+    tokio::fs::File::create(path).await?.set_len(total).await?;
+
+    let streams = streams.max(1) as u64;
+    let part = total.div_ceil(streams).max(1);
+    let parts = (0..streams)
+      .map(|i| i * part)
+      .take_while(|&start| start < total)
+      .map(|start| {
+        let end = (start + part).min(total) - 1;
+        let request = self.get_request(url, Some((start, Some(end))), None);
+        let connection = &connection;
+        async move {
+          let (head, mut body) = self.send_request(connection, &request).await?;
+          let expected = format!("bytes {}-{}/{}", start, end, total);
+          if head.status != 206 || head.get("Content-Range") != Some(expected.as_str()) {
+            return Err(QvpnError::Protocol(format!(
+              "expected range {} but got {}",
+              expected, head
+            )));
+          }
+          let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+          file.seek(SeekFrom::Start(start)).await?;
+          while let Some(chunk) = body.chunk().await? {
+            file.write_all(&chunk).await?;
+            progress(chunk.len() as u64, total);
+          }
+          file.flush().await?;
+          Ok::<_, QvpnError>(())
+        }
+      });
+    futures::future::try_join_all(parts).await?;
+    close(&connection, self.congestion);
+    if self.verify && total > 0 {
+      integrity::verify_file(path, &expected_digest(&head)?).await?;
+    }
+    Ok(total)
+  }
+
+  /// Formats a `GET` request for `url`, optionally limited to an inclusive
+  /// byte range whose end may be open, and conditional on the file having
+  /// changed since it was `cached`.
+  fn get_request(
+    &self,
+    url: &Url,
+    range: Option<(u64, Option<u64>)>,
+    cached: Option<&Entry>,
+  ) -> String {
+    let mut request = format!("GET {} HTTP/3\r\n", url.path());
+    match range {
+      Some((start, Some(end))) => request.push_str(&format!("Range: bytes={}-{}\r\n", start, end)),
+      Some((start, None)) => request.push_str(&format!("Range: bytes={}-\r\n", start)),
+      None => {}
+    }
+    if let Some(format) = self.format {
+      request.push_str(&format!("Accept: {}\r\n", format.mime()));
+    }
+    if let Some(entry) = cached {
+      request.push_str(&entry.conditions());
+    }
+    if self.verify {
+      request.push_str(&format!(
+        "{}: {}\r\n",
+        integrity::WANT_HEADER,
+        integrity::WANT_SHA256
+      ));
+    }
+    if self.compression && range.is_none() {
+      request.push_str(&format!(
+        "Accept-Encoding: {}\r\n",
+        compress::accept_encoding()
+      ));
+    }
+    let token = url
+      .query_pairs()
+      .find(|(name, _)| name == "token")
+      .map(|(_, token)| token.into_owned())
+      .or_else(|| self.access_token.clone());
+    if let Some(token) = token {
+      request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    request
+  }
+
+  /// Leases a tunnel address from the server named by `url`, opens a TUN
+  /// interface with it and forwards packets until the connection fails.
+  ///
+  /// With [`ClientBuilder::failover`] servers, a server that can't be
+  /// reached or stops responding is replaced by the next one in the list,
+  /// and the tunnel is brought up again through it. Only once every server
+  /// failed in turn does that count as a reconnect attempt.
+  ///
+  /// With a [`TunConfig::kill_switch`], the host sends nothing but through
+  /// the interface and to the servers until the tunnel is torn down, even
+  /// while it reconnects. See [`kill_switch`](crate::kill_switch).
+  ///
+  /// The address, netmask and MTU of `config` are replaced by the lease.
+  pub async fn tunnel(&self, url: &Url, host: Option<&str>, config: &TunConfig) -> Result<()> {
+    let servers: Vec<&Url> = iter::once(url).chain(&self.failover).collect();
+    let _kill_switch = config
+      .kill_switch
+      .map(|firewall| self.kill_switch(firewall, &servers, config))
+      .transpose()?;
+    let active = AtomicUsize::new(0);
+    self
+      .reconnect
+      .sustain(
+        "tunnel",
+        || async {
+          let (index, session) = self
+            .lease_any(&servers, host, active.load(Ordering::Relaxed))
+            .await?;
+          active.store(index, Ordering::Relaxed);
+          Ok(session)
+        },
+        |mut session| async {
+          loop {
+            let (connection, control, lease, bond) = session;
+            let path = connection.clone();
+            let tunnel = self.run_tunnel(connection, control, lease, bond, config);
+            let err = match self.migrating(&path, tunnel).await {
+              Ok(()) => return Ok(()),
+              Err(err) if err.is_transient() && servers.len() > 1 => err,
+              Err(err) => return Err(err),
+            };
+            let failed = active.load(Ordering::Relaxed);
+            warn!(server = %servers[failed], "tunnel lost, failing over: {}", err);
+            active.store((failed + 1) % servers.len(), Ordering::Relaxed);
+            let (index, next) = self
+              .lease_any(&servers, host, active.load(Ordering::Relaxed))
+              .await?;
+            info!(server = %servers[index], "failed over");
+            active.store(index, Ordering::Relaxed);
+            session = next;
+          }
+        },
+      )
+      .await
+  }
+
+  /// Blocks what the host sends other than through the interface of
+  /// `config` and to `servers`, pinning their names to the addresses they
+  /// resolve to now.
+  fn kill_switch(
+    &self,
+    firewall: Firewall,
+    servers: &[&Url],
+    config: &TunConfig,
+  ) -> Result<KillSwitch> {
+    if config.fd.is_some() || config.fd_socket.is_some() {
+      return Err(QvpnError::InvalidInput(
+        "a provided interface's kill switch is up to the program that opened it".into(),
+      ));
+    }
+    let local = self.endpoint.local_addr()?;
+    let mut addrs = vec![];
+    for server in servers {
+      let (remotes, _) = remote(server, None, 443, local, &self.pinned)?;
+      if let Some(Host::Domain(domain)) = server.host() {
+        let port = server.port().unwrap_or(443);
+        let mut pinned = self.pinned.lock().expect("pinned lock poisoned");
+        pinned.insert((domain.to_string(), port), remotes.clone());
+      }
+      addrs.extend(remotes);
+    }
+    let switch = KillSwitch::new(firewall, &config.name, addrs)?;
+    info!(interface = %config.name, "kill switch up");
+    Ok(switch)
+  }
+
+  /// Brings up a TUN interface with the address from `lease` and forwards
+  /// packets over the connection, spread across the `bond` paths as well if
+  /// there are any, until it fails or the server disconnects.
+  async fn run_tunnel(
+    &self,
+    connection: quinn::Connection,
+    mut control: Control,
+    lease: Lease,
+    bond: Vec<BondPath>,
+    config: &TunConfig,
+  ) -> Result<()> {
+    let remote = net::canonical(connection.remote_address());
+    info!(
+      address = %lease.address,
+      netmask = %lease.netmask,
+      %remote,
+      "leased tunnel address"
+    );
+    let config = TunConfig {
+      address: lease.address,
+      netmask: lease.netmask,
+      mtu: lease.mtu,
+      tap: lease.tap,
+      ..config.clone()
+    };
+    let tun = Arc::new(self.open_tun(&config, &lease).await?);
+    let _masquerade = config
+      .masquerade
+      .map(|firewall| Masquerade::new(firewall, tun.name()))
+      .transpose()?;
+    let _bypass = match remote.ip() {
+      // The program that opened the interface keeps its own traffic off it.
+      IpAddr::V4(server) if self.full_tunnel && !tun.is_provided() => {
+        Some(BypassRoute::new(server)?)
+      }
+      _ => None,
+    };
+    let mut routing = Routing::new(&tun, &lease, self.full_tunnel, &self.advertise);
+    routing.apply(lease.routes.clone())?;
+    routing.set_dns(DnsConfig {
+      servers: lease.dns.clone(),
+      search: lease.search.clone(),
+    })?;
+    info!(
+      interface = tun.name(),
+      %remote,
+      "tunnel up"
+    );
+    let _ = self.events.send(Event::TunnelUp {
+      interface: tun.name().to_string(),
+      address: lease.address,
+      remote,
+    });
+    let _down = self.hooks.up(vec![
+      ("QVPN_INTERFACE", tun.name().to_string()),
+      ("QVPN_ADDRESS", lease.address.to_string()),
+      ("QVPN_NETMASK", lease.netmask.to_string()),
+      ("QVPN_GATEWAY", lease.gateway.to_string()),
+      ("QVPN_MTU", lease.mtu.to_string()),
+      ("QVPN_ROUTES", hook::list(&lease.routes)),
+      ("QVPN_DNS", hook::list(&lease.dns)),
+      ("QVPN_SEARCH", hook::list(&lease.search)),
+      ("QVPN_REMOTE", remote.to_string()),
+    ]);
+    service::ready();
+    let forward = async {
+      if bond.is_empty() {
+        return tun::pump(tun.clone(), connection).await;
+      }
+      let paths = iter::once(connection)
+        .chain(bond.iter().map(|path| path.connection.clone()))
+        .collect();
+      bond::pump(tun.clone(), paths).await
+    };
+    tokio::select! {
+      res = forward => res,
+      res = run_control(&mut control, Some(&mut routing), self.health_timeout) => res,
+    }
+  }
+
+  /// Opens the TUN interface for `lease`, or asks the program on the
+  /// configured descriptor socket to.
+  async fn open_tun(&self, config: &TunConfig, lease: &Lease) -> Result<Tun> {
+    #[cfg(unix)]
+    if let Some(path) = &config.fd_socket {
+      let handoff = tun::fd::Handoff {
+        address: lease.address,
+        netmask: lease.netmask,
+        mtu: lease.mtu,
+        routes: lease
+          .routes
+          .iter()
+          .filter(|x| !self.advertise.contains(x))
+          .map(ToString::to_string)
+          .collect(),
+        dns: lease.dns.clone(),
+        search: lease.search.clone(),
+        full_tunnel: self.full_tunnel,
+        tap: lease.tap,
+      };
+      let fd = tun::fd::request(path, &handoff).await?;
+      info!(socket = %path.display(), "received TUN descriptor");
+      return Tun::from_fd(fd, config);
+    }
+    #[cfg(not(unix))]
+    if let Some(path) = &config.fd_socket {
+      let _ = lease;
+      return Err(QvpnError::Unsupported(format!(
+        "cannot receive a TUN descriptor from {} on this platform",
+        path.display()
+      )));
+    }
+    Tun::open(config)
+  }
+
+  /// Leases a tunnel address from the first of `servers` that grants one,
+  /// trying them in order from index `start` and wrapping around. Returns
+  /// the index of that server along with the lease and the bonded paths
+  /// joined to it.
+  async fn lease_any(
+    &self,
+    servers: &[&Url],
+    host: Option<&str>,
+    start: usize,
+  ) -> Result<(usize, (quinn::Connection, Control, Lease, Vec<BondPath>))> {
+    let mut last_err = None;
+    for index in (start..servers.len()).chain(0..start) {
+      let server = servers[index];
+      let session = async {
+        let (connection, version, mut control) = self.connect_qvpn(server, host).await?;
+        let mut lease = request_lease(&mut control).await?;
+        self.advertise_networks(&mut control, version).await?;
+        let bond = self
+          .join_bond(server, host, &connection, &mut control, version, &mut lease)
+          .await?;
+        Ok::<_, QvpnError>((connection, control, lease, bond))
+      };
+      match session.await {
+        Ok(session) => return Ok((index, session)),
+        Err(err) if err.is_transient() && servers.len() > 1 => {
+          warn!(%server, "server unavailable: {}", err);
+          last_err = Some(err);
+        }
+        Err(err) => return Err(err),
+      }
+    }
+    Err(last_err.expect("no servers to try"))
+  }
+
+  /// Tells the server, which leased an address on `control`, the networks
+  /// this client is the gateway of, if any.
+  async fn advertise_networks(&self, control: &mut Control, version: u32) -> Result<()> {
+    if self.advertise.is_empty() {
+      return Ok(());
+    }
+    if version < version::SITES {
+      return Err(QvpnError::Incompatible(
+        "server doesn't support site gateways; upgrade it".into(),
+      ));
+    }
+    #[cfg(target_os = "linux")]
+    if !crate::site::forwarding_enabled() {
+      warn!(
+        "IPv4 forwarding is off; set net.ipv4.ip_forward = 1 to route for the advertised networks"
+      );
+    }
+    control
+      .send(&control::Message::Advertise {
+        networks: self.advertise.clone(),
+      })
+      .await
+  }
+
+  /// Opens one connection from each bond address to the server of
+  /// `connection`, found at `url`, and joins them to the tunnel address it
+  /// leased. Paths that can't be opened are left out; if the server
+  /// predates bonding, all of them are. Routes and resolvers the server
+  /// pushes meanwhile go into `lease`.
+  async fn join_bond(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+    connection: &quinn::Connection,
+    control: &mut Control,
+    version: u32,
+    lease: &mut Lease,
+  ) -> Result<Vec<BondPath>> {
+    if self.bond.is_empty() {
+      return Ok(vec![]);
+    }
+    if version < version::BONDING {
+      warn!(
+        version,
+        "server doesn't support bonding, using a single path"
+      );
+      return Ok(vec![]);
+    }
+    control.send(&control::Message::BondRequest).await?;
+    let token = loop {
+      match control.recv().await? {
+        Some(control::Message::BondToken { token }) => break token,
+        Some(control::Message::Routes { routes }) => lease.routes = routes,
+        Some(control::Message::Dns { servers, search }) => {
+          lease.dns = servers;
+          lease.search = search;
+        }
+        Some(control::Message::Disconnect { reason }) => return Err(disconnected(reason)),
+        Some(message) => debug!(?message, "ignoring control message"),
+        None => {
+          return Err(QvpnError::Protocol(
+            "control stream ended before the bond token".into(),
+          ))
+        }
+      }
+    };
+    let remote = net::canonical(connection.remote_address());
+    let host = server_name(url, host)?;
+    let mut paths = vec![];
+    for &local in &self.bond {
+      match self.join_path(local, remote, host, token).await {
+        Ok(path) => {
+          info!(%local, %remote, "bonded path up");
+          paths.push(path);
+        }
+        Err(err) => warn!(%local, "couldn't open bonded path: {}", err),
+      }
+    }
+    Ok(paths)
+  }
+
+  /// Connects from `local` to `remote` on an endpoint of its own and joins
+  /// the connection to the tunnel with bond `token`.
+  async fn join_path(
+    &self,
+    local: IpAddr,
+    remote: SocketAddr,
+    host: &str,
+    token: u64,
+  ) -> Result<BondPath> {
+    let local = SocketAddr::new(local, 0);
+    if !net::reachable(local, remote) {
+      return Err(QvpnError::InvalidInput(format!(
+        "{} can't reach {}",
+        local, remote
+      )));
+    }
+    let endpoint = quinn::Endpoint::new(
+      quinn::EndpointConfig::default(),
+      None,
+      net::bind_udp(local)?,
+      Arc::new(quinn::TokioRuntime),
+    )?;
+    let connection = endpoint
+      .connect_with(self.client_config.clone(), remote, host)?
+      .await
+      .map_err(|err| version::connection_error(err, ALPN_QUIC_HTTP))?;
+    self.stats.track(&connection);
+    if let Some(psk) = &self.psk {
+      psk.authenticate(&connection).await?;
+    }
+    let (_, mut control) = version::negotiate(&connection).await?;
+    control.send(&control::Message::Join { token }).await?;
+    loop {
+      match control.recv().await? {
+        Some(control::Message::Joined { .. }) => {
+          return Ok(BondPath {
+            connection,
+            _control: control,
+            _endpoint: endpoint,
+          })
+        }
+        Some(control::Message::Disconnect { reason }) => return Err(disconnected(reason)),
+        Some(message) => debug!(?message, "ignoring control message"),
+        None => {
+          return Err(QvpnError::Protocol(
+            "control stream ended before joining".into(),
+          ))
+        }
+      }
+    }
+  }
+
+  /// Connects to the server named by `url` and forwards SOCKS5 connections
+  /// accepted on `listen` through it until the connection fails.
+  ///
+  /// The server must run in [`Mode::ConnectProxy`].
+  ///
+  /// [`Mode::ConnectProxy`]: crate::server::Mode::ConnectProxy
+  pub async fn socks5(&self, url: &Url, host: Option<&str>, listen: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    info!(listen = %listener.local_addr()?, "socks5 proxy up");
+    service::ready();
+    self
+      .reconnect
+      .sustain(
+        "proxy connection",
+        || self.connect_proxy(url, host),
+        |(connection, control)| {
+          let path = connection.clone();
+          let proxy = serve_proxy(
+            &listener,
+            connection,
+            control,
+            socks::handle,
+            &self.stats,
+            self.health_timeout,
+          );
+          async move { self.migrating(&path, proxy).await }
+        },
+      )
+      .await
+  }
+
+  /// Connects to the server named by `url` and forwards HTTP `CONNECT`
+  /// requests accepted on `listen` through it until the connection fails.
+  ///
+  /// The server must run in [`Mode::ConnectProxy`].
+  ///
+  /// [`Mode::ConnectProxy`]: crate::server::Mode::ConnectProxy
+  pub async fn http_proxy(&self, url: &Url, host: Option<&str>, listen: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(listen).await?;
+    info!(listen = %listener.local_addr()?, "http proxy up");
+    service::ready();
+    self
+      .reconnect
+      .sustain(
+        "proxy connection",
+        || self.connect_proxy(url, host),
+        |(connection, control)| {
+          let path = connection.clone();
+          let proxy = serve_proxy(
+            &listener,
+            connection,
+            control,
+            proxy::handle_http,
+            &self.stats,
+            self.health_timeout,
+          );
+          async move { self.migrating(&path, proxy).await }
+        },
+      )
+      .await
+  }
+
+  /// Connects to the server named by `url` and relays the UDP datagrams
+  /// received on the local addresses of `forwards` to their targets, and
+  /// the replies back, until the connection fails. See [`udp`].
+  ///
+  /// The server must run in [`Mode::ConnectProxy`].
+  ///
+  /// [`Mode::ConnectProxy`]: crate::server::Mode::ConnectProxy
+  pub async fn forward_udp(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+    forwards: &[udp::Forward],
+  ) -> Result<()> {
+    let mut ports = vec![];
+    for forward in forwards {
+      let socket = UdpSocket::bind(forward.local).await?;
+      info!(listen = %socket.local_addr()?, remote = %forward.remote, "udp forward up");
+      ports.push((Arc::new(socket), forward.clone()));
+    }
+    service::ready();
+    self
+      .reconnect
+      .sustain(
+        "proxy connection",
+        || async {
+          let (connection, version, control) = self.connect_qvpn(url, host).await?;
+          if version < version::UDP {
+            return Err(QvpnError::Incompatible(
+              "the server predates udp forwarding; upgrade it".into(),
+            ));
+          }
+          info!(remote = %net::canonical(connection.remote_address()), "connected");
+          Ok((connection, control))
+        },
+        |(connection, mut control)| {
+          let path = connection.clone();
+          let ports = &ports;
+          async move {
+            let forward = async {
+              tokio::select! {
+                res = udp::forward(ports, connection) => res,
+                res = run_control(&mut control, None, self.health_timeout) => res,
+              }
+            };
+            self.migrating(&path, forward).await
+          }
+        },
+      )
+      .await
+  }
+
+  /// Connects to the server named by `url` and forwards TCP ports through
+  /// it until the connection fails: the `locals` on this side to targets
+  /// the server connects to, like `ssh -L`, and the `reverses` on the
+  /// server to services this side connects to, like `ssh -R`. See
+  /// [`forward`].
+  ///
+  /// The server must run in [`Mode::ConnectProxy`] or have rules allowing
+  /// the forwards.
+  ///
+  /// [`Mode::ConnectProxy`]: crate::server::Mode::ConnectProxy
+  pub async fn forward(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+    locals: &[forward::Local],
+    reverses: &[forward::Reverse],
+  ) -> Result<()> {
+    let mut listeners = vec![];
+    for local in locals {
+      let listener = TcpListener::bind(local.listen).await?;
+      info!(listen = %listener.local_addr()?, target = %local.target, "forward up");
+      listeners.push((listener, local.clone()));
+    }
+    service::ready();
+    self
+      .reconnect
+      .sustain(
+        "forward connection",
+        || async {
+          let (connection, version, control) = self.connect_qvpn(url, host).await?;
+          if !reverses.is_empty() && version < version::REVERSE_FORWARD {
+            return Err(QvpnError::Incompatible(
+              "the server predates reverse forwarding; upgrade it".into(),
+            ));
+          }
+          info!(remote = %net::canonical(connection.remote_address()), "connected");
+          Ok((connection, control))
+        },
+        |(connection, mut control)| {
+          let path = connection.clone();
+          let listeners = &listeners;
+          async move {
+            let forward = async {
+              tokio::select! {
+                res = forward::serve_local(listeners, &connection) => res,
+                res = forward::serve_reverse(&connection, reverses) => res,
+                res = run_control(&mut control, None, self.health_timeout) => res,
+              }
+            };
+            self.migrating(&path, forward).await
+          }
+        },
+      )
+      .await
+  }
+
+  /// Connects to the server named by `url`, registers `name` with it and
+  /// connects the visitors the server receives for that name to `target`,
+  /// until the connection fails. See [`expose`].
+  ///
+  /// The server must accept exposed services.
+  pub async fn expose(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+    name: &str,
+    target: &str,
+  ) -> Result<()> {
+    if !expose::valid_name(name) {
+      return Err(QvpnError::InvalidInput(format!(
+        "`{}` is not a valid name; use lowercase letters, digits and dashes",
+        name
+      )));
+    }
+    service::ready();
+    // Lets a reconnect take the name over before the server notices the old
+    // connection is gone.
+    let token = Mutex::new(None);
+    self
+      .reconnect
+      .sustain(
+        "expose connection",
+        || async {
+          let (connection, version, control) = self.connect_qvpn(url, host).await?;
+          if version < version::EXPOSE {
+            return Err(QvpnError::Incompatible(
+              "the server predates exposed services; upgrade it".into(),
+            ));
+          }
+          info!(remote = %net::canonical(connection.remote_address()), "connected");
+          Ok((connection, control))
+        },
+        |(connection, mut control)| {
+          let path = connection.clone();
+          let token = &token;
+          async move {
+            let expose = async {
+              tokio::select! {
+                res = expose::share(&connection, name, target, token) => res,
+                res = run_control(&mut control, None, self.health_timeout) => res,
+              }
+            };
+            self.migrating(&path, expose).await
+          }
+        },
+      )
+      .await
+  }
+
+  async fn connect_proxy(
+    &self,
+    url: &Url,
+    host: Option<&str>,
+  ) -> Result<(quinn::Connection, Control)> {
+    let (connection, _, control) = self.connect_qvpn(url, host).await?;
+    info!(remote = %net::canonical(connection.remote_address()), "connected");
+    Ok((connection, control))
+  }
+
+  /// Answers DNS queries received on `listen` by relaying them to the DNS
+  /// over QUIC server named by `url`, until the connection fails. The port
+  /// defaults to [`doq::DEFAULT_PORT`].
+  ///
+  /// The server must run in [`Mode::Doq`].
+  ///
+  /// [`Mode::Doq`]: crate::server::Mode::Doq
+  pub async fn dns_stub(&self, url: &Url, host: Option<&str>, listen: SocketAddr) -> Result<()> {
+    let socket = Arc::new(UdpSocket::bind(listen).await?);
+    info!(listen = %socket.local_addr()?, "dns stub resolver up");
+    service::ready();
+    self
+      .reconnect
+      .sustain(
+        "DoQ connection",
+        || self.connect_doq(url, host),
+        |connection| {
+          let socket = socket.clone();
+          async move { doq::stub(socket, &connection).await }
+        },
+      )
+      .await
+  }
+
+  async fn connect_doq(&self, url: &Url, host: Option<&str>) -> Result<quinn::Connection> {
+    let (remotes, host) = remote(
+      url,
+      host,
+      doq::DEFAULT_PORT,
+      self.endpoint.local_addr()?,
+      &self.pinned,
+    )?;
+    info!(%host, remote = %remotes[0], "connecting");
+    let connecting = self
+      .endpoint
+      .connect_with(self.doq_config.clone(), remotes[0], host)?;
+    let connection = self
+      .race(
+        connecting,
+        &remotes,
+        host,
+        Some(&self.doq_config),
+        &[doq::ALPN],
+      )
+      .await?;
+    info!(remote = %net::canonical(connection.remote_address()), "connected");
+    self.stats.track(&connection);
+    Ok(connection)
+  }
+
+  /// Completes `first`, the attempt to `remotes[0]`, or if it takes longer
+  /// than the attempt delay or fails, races it against attempts to the
+  /// other addresses in turn as RFC 8305 describes. Returns the first
+  /// connection established, or the last error if none is.
+  async fn race(
+    &self,
+    first: quinn::Connecting,
+    remotes: &[SocketAddr],
+    host: &str,
+    config: Option<&quinn::ClientConfig>,
+    offered: &[&[u8]],
+  ) -> Result<quinn::Connection> {
+    let attempt = |remote: SocketAddr, connecting: quinn::Connecting| {
+      connecting.map(move |result| (remote, result))
+    };
+    let mut attempts = FuturesUnordered::new();
+    attempts.push(attempt(remotes[0], first));
+    let mut pending = remotes[1..].iter().copied();
+    let mut last_err = None;
+    loop {
+      let start_next = tokio::select! {
+        Some((remote, result)) = attempts.next() => match result {
+          Ok(connection) => {
+            if remotes.len() > 1 {
+              info!(%remote, "won connection race");
+            }
+            return Ok(connection);
+          }
+          Err(err) => {
+            debug!(%remote, "connection attempt failed: {}", err);
+            last_err = Some(version::connection_error(err, offered));
+            true
+          }
+        },
+        _ = tokio::time::sleep(self.attempt_delay), if pending.len() > 0 => true,
+      };
+      if start_next {
+        for remote in pending.by_ref() {
+          let connecting = match config {
+            Some(config) => self.endpoint.connect_with(config.clone(), remote, host),
+            None => self.endpoint.connect(remote, host),
+          };
+          match connecting {
+            Ok(connecting) => {
+              debug!(%remote, "racing another address");
+              attempts.push(attempt(remote, connecting));
+              break;
+            }
+            Err(err) => debug!(%remote, "couldn't try address: {}", err),
+          }
+        }
+      }
+      if attempts.is_empty() {
+        return Err(last_err.expect("every attempt failed"));
+      }
+    }
+  }
+
+  /// Runs `session` on `connection`, moving the endpoint to a new socket
+  /// whenever the host changes networks while it lasts, unless migration
+  /// is disabled.
+  async fn migrating(
+    &self,
+    connection: &quinn::Connection,
+    session: impl Future<Output = Result<()>>,
+  ) -> Result<()> {
+    if !self.migration {
+      return session.await;
+    }
+    tokio::select! {
+      res = session => res,
+      res = self.follow_path(connection) => res,
+    }
+  }
+
+  /// Watches the local address the server is reached from. When it
+  /// changes, rebinds the endpoint, which makes the connection probe the
+  /// new path, and reports a [`Event::PathChanged`] once the server answers
+  /// on it. Only returns if rebinding fails.
+  async fn follow_path(&self, connection: &quinn::Connection) -> Result<()> {
+    let remote = net::canonical(connection.remote_address());
+    let mut current = net::source_ip(remote).ok();
+    let mut interval = tokio::time::interval(PATH_CHECK_INTERVAL);
+    loop {
+      interval.tick().await;
+      let source = match net::source_ip(remote) {
+        Ok(source) => source,
+        // Offline for now; the next network shows up as a change.
+        Err(err) => {
+          debug!(%remote, "no route to the server: {}", err);
+          continue;
+        }
+      };
+      let from = match current.replace(source) {
+        Some(from) if from != source => from,
+        _ => continue,
+      };
+      info!(%from, to = %source, "network changed, migrating");
+      let received = connection.stats().udp_rx.datagrams;
+      self.endpoint.rebind(net::bind_udp(self.bind)?)?;
+      let answered = tokio::time::timeout(self.health_timeout, async {
+        while connection.stats().udp_rx.datagrams == received {
+          tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+      });
+      match answered.await {
+        Ok(()) => {
+          info!(%from, to = %source, "migrated");
+          let _ = self.events.send(Event::PathChanged {
+            remote,
+            from,
+            to: source,
+          });
+        }
+        Err(_) => warn!(to = %source, "server hasn't answered on the new path yet"),
+      }
+    }
+  }
+
+  /// Uploads the file at `path` to the request path `target` on a new stream
+  /// of `connection`, returning the response head and the bytes sent.
+  async fn upload(
+    &self,
+    connection: &quinn::Connection,
+    target: &str,
+    path: &Path,
+  ) -> Result<(ResponseHead, u64)> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    let request = format!("PUT {} HTTP/3\r\nContent-Length: {}\r\n\r\n", target, len);
+    info!(request = %request.trim_end(), "sending upload");
+    let (mut tx, mut rx) = connection.open_bi().await?;
+    self.stats.stream_opened(connection);
+    tx.write_all(request.as_bytes()).await?;
+    tokio::io::copy(&mut file, &mut tx).await?;
+    tx.finish()?;
+    let (head, _) = http::read_until(&mut rx, b"\r\n\r\n").await?;
+    let head = ResponseHead::decode(&head)?;
+    if !head.is_success() {
+      return Err(QvpnError::Remote(head.to_string()));
+    }
+    Ok((head, len))
+  }
+
+  /// Sends `request` on a new stream of `connection` and waits for the
+  /// response head. The returned body leaves the connection open.
+  async fn send_request(
+    &self,
+    connection: &quinn::Connection,
+    request: &str,
+  ) -> Result<(ResponseHead, Body)> {
+    let started = Instant::now();
+    let (mut tx, mut rx) = connection.open_bi().await?;
+    self.stats.stream_opened(connection);
+    tx.write_all(request.as_bytes()).await?;
+    tx.finish()?;
+    let (head, buffered) = http::read_until(&mut rx, b"\r\n\r\n").await?;
+    let head = ResponseHead::decode(&head)?;
+    let decoder = match head.get("Content-Encoding") {
+      Some(encoding) if !encoding.eq_ignore_ascii_case("identity") => {
+        Some(Decoder::new(encoding.parse().map_err(QvpnError::Protocol)?))
+      }
+      _ => None,
+    };
+    let verifier = match (self.verify, head.status) {
+      (true, 200) => Some(Verifier::new(expected_digest(&head)?)),
+      (true, 206) => {
+        expected_digest(&head)?;
+        None
+      }
+      _ => None,
+    };
+    let body = Body {
+      connection: None,
+      buffered,
+      recv: Some(rx),
+      received: 0,
+      expected: head.content_length(),
+      decoder,
+      verifier,
+      cached: None,
+      writer: None,
+      started,
+    };
+    Ok((head, body))
+  }
+
+  /// Waits for open connections to be cleanly shut down.
+  pub async fn wait_idle(&self) {
+    self.endpoint.wait_idle().await;
+  }
+}
+
+/// Address and TLS server name of the server named by `url`, on `port`
+/// unless the URL names one. Keeps the addresses a socket bound to `local`
+/// can reach, alternating between IPv6 and IPv4 starting with the family
+/// resolved first, in the order to try them. Names `pinned` to addresses
+/// aren't resolved again.
+fn remote<'a>(
+  url: &'a Url,
+  host: Option<&'a str>,
+  port: u16,
+  local: SocketAddr,
+  pinned: &Mutex<HashMap<(String, u16), Vec<SocketAddr>>>,
+) -> Result<(Vec<SocketAddr>, &'a str)> {
+  let port = url.port().unwrap_or(port);
+  let addrs: Vec<SocketAddr> = match url.host() {
+    Some(Host::Domain(domain)) => {
+      let pinned = pinned.lock().expect("pinned lock poisoned");
+      match pinned.get(&(domain.to_string(), port)) {
+        Some(addrs) => addrs.clone(),
+        None => (domain, port).to_socket_addrs()?.collect(),
+      }
+    }
+    Some(Host::Ipv4(ip)) => vec![(ip, port).into()],
+    Some(Host::Ipv6(ip)) => vec![(ip, port).into()],
+    None => return Err(QvpnError::InvalidInput(format!("url {} has no host", url))),
+  };
+  if addrs.is_empty() {
+    return Err(QvpnError::InvalidInput(format!(
+      "couldn't resolve {} to an address",
+      url
+    )));
+  }
+  let remotes = net::interleave(
+    addrs
+      .into_iter()
+      .filter(|addr| net::reachable(local, *addr))
+      .collect(),
+  );
+  if remotes.is_empty() {
+    return Err(QvpnError::InvalidInput(format!(
+      "no address of {} is reachable from {}",
+      url, local
+    )));
+  }
+  Ok((remotes, server_name(url, host)?))
+}
+
+/// TLS server name of the server named by `url`: `host` if given.
+fn server_name<'a>(url: &'a Url, host: Option<&'a str>) -> Result<&'a str> {
+  // IPv6 literals keep their brackets in the URL but not as a server name.
+  host
+    .or_else(|| url.host_str())
+    .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+    .ok_or_else(|| QvpnError::InvalidInput("no hostname specified".into()))
+}
+
+/// An extra connection of a bonded uplink, from a local address of its own.
+struct BondPath {
+  connection: quinn::Connection,
+  /// Held open so the server keeps the path joined.
+  _control: Control,
+  _endpoint: quinn::Endpoint,
+}
+
+/// Logs the congestion state of `connection` and closes it.
+fn close(connection: &quinn::Connection, congestion: Congestion) {
+  congestion::report(connection, congestion);
+  connection.close(0u32.into(), b"done");
+}
+
+/// Forwards connections accepted on `listener` through the connection
+/// while keeping its control stream alive, counting their streams in
+/// `stats`.
+async fn serve_proxy<F, Fut>(
+  listener: &TcpListener,
+  connection: quinn::Connection,
+  mut control: Control,
+  handle: F,
+  stats: &Tracker,
+  health_timeout: Duration,
+) -> Result<()>
+where
+  F: Fn(TcpStream, quinn::Connection) -> Fut,
+  Fut: Future<Output = Result<()>> + Send + 'static,
+{
+  let handle = |tcp, connection: quinn::Connection| {
+    stats.stream_opened(&connection);
+    handle(tcp, connection)
+  };
+  tokio::select! {
+    res = proxy::serve(listener, connection, handle) => res,
+    res = run_control(&mut control, None, health_timeout) => res,
+  }
+}
+
+/// Asks for a tunnel address on the control stream.
+async fn request_lease(control: &mut Control) -> Result<Lease> {
+  control.send(&control::Message::LeaseRequest).await?;
+  let (mut mtu, mut routes, mut tap) = (None, vec![], false);
+  let mut dns = DnsConfig::default();
+  loop {
+    match control.recv().await? {
+      Some(control::Message::Mtu { mtu: x }) => mtu = Some(x),
+      Some(control::Message::Ethernet) => tap = true,
+      Some(control::Message::Routes { routes: x }) => routes = x,
+      Some(control::Message::Dns { servers, search }) => dns = DnsConfig { servers, search },
+      Some(control::Message::Assign {
+        address,
+        netmask,
+        gateway,
+      }) => {
+        return Ok(Lease {
+          address,
+          netmask,
+          gateway,
+          mtu: mtu.ok_or_else(|| QvpnError::Protocol("lease without an MTU".into()))?,
+          routes,
+          dns: dns.servers,
+          search: dns.search,
+          tap,
+        })
+      }
+      Some(control::Message::Disconnect { reason }) => return Err(disconnected(reason)),
+      Some(message) => debug!(?message, "ignoring control message"),
+      None => {
+        return Err(QvpnError::Protocol(
+          "control stream ended before the lease".into(),
+        ))
+      }
+    }
+  }
+}
+
+/// Sends keepalives on the control stream and acts on what the server
+/// sends, until it disconnects the client or leaves a keepalive unanswered
+/// for `health_timeout`. Routes and resolvers pushed to a tunnel are
+/// installed through `routing`.
+async fn run_control(
+  control: &mut Control,
+  mut routing: Option<&mut Routing<'_>>,
+  health_timeout: Duration,
+) -> Result<()> {
+  let mut keepalive = tokio::time::interval(control::KEEPALIVE_INTERVAL);
+  // When the oldest keepalive still unanswered was sent.
+  let mut unanswered: Option<Instant> = None;
+  loop {
+    let deadline = unanswered.unwrap_or_else(Instant::now) + health_timeout;
+    let message = tokio::select! {
+      _ = keepalive.tick() => {
+        control.send(&control::Message::Keepalive).await?;
+        unanswered.get_or_insert_with(Instant::now);
+        continue;
+      }
+      _ = tokio::time::sleep_until(deadline.into()), if unanswered.is_some() => {
+        return Err(QvpnError::Timeout("server stopped answering keepalives"));
+      }
+      message = control.recv() => message?,
+    };
+    match message {
+      Some(control::Message::Keepalive) => {
+        debug!("control keepalive");
+        unanswered = None;
+      }
+      Some(control::Message::Routes { routes }) => match &mut routing {
+        Some(routing) => routing.apply(routes)?,
+        None => debug!("ignoring routes without a tunnel"),
+      },
+      Some(control::Message::Dns { servers, search }) => match &mut routing {
+        Some(routing) => routing.set_dns(DnsConfig { servers, search })?,
+        None => debug!("ignoring resolvers without a tunnel"),
+      },
+      Some(control::Message::Mtu { mtu }) => match &mut routing {
+        Some(routing) => routing.set_mtu(mtu),
+        None => debug!("ignoring MTU without a tunnel"),
+      },
+      Some(control::Message::Rekey) => info!("server certificate changed"),
+      Some(control::Message::Disconnect { reason }) => return Err(disconnected(reason)),
+      Some(message) => debug!(?message, "ignoring control message"),
+      None => {
+        return Err(QvpnError::Protocol(
+          "server ended the control stream".into(),
+        ))
+      }
+    }
+  }
+}
+
+/// Routes and resolvers installed for a tunnel interface.
+struct Routing<'a> {
+  tun: &'a Tun,
+  /// The tunnel subnet, which the interface address already routes.
+  subnet: Option<Ipv4Net>,
+  full_tunnel: bool,
+  /// Networks this client is the gateway of, pushed back to it.
+  advertised: Vec<Ipv4Net>,
+  installed: Vec<Ipv4Net>,
+  dns: Option<DnsOverride>,
+}
+
+impl<'a> Routing<'a> {
+  fn new(tun: &'a Tun, lease: &Lease, full_tunnel: bool, advertised: &[Ipv4Net]) -> Self {
+    Routing {
+      tun,
+      subnet: Ipv4Net::new(lease.address, u32::from(lease.netmask).count_ones() as u8),
+      full_tunnel,
+      advertised: advertised.to_vec(),
+      installed: vec![],
+      dns: None,
+    }
+  }
+
+  /// Resolves names through `config` instead of the resolvers installed
+  /// before, or restores the system's own if it is empty.
+  fn set_dns(&mut self, config: DnsConfig) -> Result<()> {
+    if self.tun.is_provided() {
+      info!(
+        ?config,
+        "resolvers left to the program that opened the interface"
+      );
+      return Ok(());
+    }
+    // The previous override is restored before the new one saves the state.
+    self.dns = None;
+    if !config.is_empty() {
+      self.dns = Some(DnsOverride::install(self.tun.name(), &config)?);
+    }
+    Ok(())
+  }
+
+  /// Lowers the interface MTU to `mtu`, or raises it back up to at most
+  /// the leased one, as the path's MTU changes.
+  fn set_mtu(&mut self, mtu: u16) {
+    if self.tun.is_provided() {
+      info!(mtu, "MTU left to the program that opened the interface");
+      return;
+    }
+    match self.tun.set_mtu(mtu) {
+      Ok(mtu) => info!(mtu, "tunnel MTU changed with the path"),
+      Err(err) => warn!(mtu, "couldn't change the tunnel MTU: {}", err),
+    }
+  }
+
+  /// Routes `pushed`, plus everything for a full tunnel, through the
+  /// interface, removing routes pushed before that are gone. The networks
+  /// this client advertised stay on its LAN.
+  fn apply(&mut self, pushed: Vec<Ipv4Net>) -> Result<()> {
+    if self.tun.is_provided() {
+      info!(routes = ?pushed, "routes left to the program that opened the interface");
+      return Ok(());
+    }
+    let full = if self.full_tunnel {
+      &tun::FULL_TUNNEL[..]
+    } else {
+      &[]
+    };
+    let mut wanted: Vec<_> = pushed.into_iter().chain(full.iter().copied()).collect();
+    wanted.retain(|route| Some(*route) != self.subnet && !self.advertised.contains(route));
+    wanted.dedup();
+    for route in self.installed.iter().filter(|x| !wanted.contains(x)) {
+      match tun::delete_route(self.tun.name(), *route) {
+        Ok(()) => info!(%route, "route withdrawn"),
+        Err(err) => warn!(%route, "couldn't remove route: {}", err),
+      }
+    }
+    for route in wanted.iter().filter(|x| !self.installed.contains(x)) {
+      tun::add_route(self.tun.name(), *route)?;
+      info!(%route, "routing through the tunnel");
+    }
+    self.installed = wanted;
+    Ok(())
+  }
+}
+
+/// The digest a response to a request asking for one must announce.
+fn expected_digest(head: &ResponseHead) -> Result<Vec<u8>> {
+  integrity::expected(head).ok_or_else(|| {
+    QvpnError::Integrity(format!(
+      "the server sent no SHA-256 {} header",
+      integrity::HEADER
+    ))
+  })
+}
+
+fn disconnected(reason: String) -> QvpnError {
+  QvpnError::Remote(format!("disconnected by the server: {}", reason))
+}
+
+async fn datagram_response(connection: &quinn::Connection, id: u32) -> Result<Vec<u8>> {
+  loop {
+    match Frame::decode(connection.read_datagram().await?) {
+      Some(frame) if frame.id == id => match frame.kind {
+        Kind::Response => return Ok(frame.payload.to_vec()),
+        Kind::Error => {
+          return Err(QvpnError::Remote(
+            String::from_utf8_lossy(&frame.payload).into_owned(),
+          ))
+        }
+        _ => {}
+      },
+      _ => {}
+    }
+  }
+}
+
+fn duration_secs(x: &Duration) -> f32 {
+  x.as_secs() as f32 + x.subsec_nanos() as f32 * 1e-9
+}
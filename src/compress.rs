@@ -0,0 +1,431 @@
+//! On-the-fly compression of file responses.
+//!
+//! A client asking for compressed responses sends `Accept-Encoding: gzip,
+//! deflate`. A server with compression enabled answers whole-file `GET`s
+//! whose type isn't already compressed with a `Content-Encoding` header and
+//! the body compressed as it's read, so without a `Content-Length`; the
+//! body ends with the stream. Range requests, small files and datagram
+//! responses are always sent as they are.
+//!
+//! The client undoes the encoding as the body arrives, so callers of
+//! [`Body::chunk`](crate::client::Body::chunk) see the original bytes.
+
+use std::{fmt, str::FromStr};
+
+use miniz_oxide::{
+  deflate::core::{create_comp_flags_from_zip_params, CompressorOxide},
+  inflate::stream::InflateState,
+  DataFormat, MZError, MZFlush, MZStatus,
+};
+
+use crate::{QvpnError, Result};
+
+/// Files smaller than this are sent uncompressed.
+pub const MIN_SIZE: u64 = 1024;
+
+/// Compression level, from 1 (fastest) to 9 (smallest).
+const LEVEL: i32 = 6;
+
+/// Window size of the DEFLATE streams, in bits.
+const WINDOW_BITS: i32 = 15;
+
+/// Room added to the output buffer whenever it runs full.
+const OUTPUT_CHUNK: usize = 32 * 1024;
+
+/// Media types not worth compressing again, as exact types or as prefixes
+/// ending in `/`, `-` or `.`.
+const PRECOMPRESSED: &[&str] = &[
+  "image/",
+  "audio/",
+  "video/",
+  "font/woff",
+  "font/woff2",
+  "application/gzip",
+  "application/x-gzip",
+  "application/zip",
+  "application/x-bzip2",
+  "application/x-xz",
+  "application/zstd",
+  "application/x-7z-compressed",
+  "application/vnd.rar",
+  "application/x-rar-compressed",
+  "application/java-archive",
+  "application/epub+zip",
+  "application/pdf",
+  "application/vnd.openxmlformats-",
+  "application/vnd.oasis.opendocument.",
+];
+
+/// A content coding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+  /// DEFLATE in a gzip member.
+  Gzip,
+  /// DEFLATE in a zlib stream.
+  Deflate,
+}
+
+impl Encoding {
+  /// All codings, most preferred first.
+  pub const ALL: [Encoding; 2] = [Encoding::Gzip, Encoding::Deflate];
+
+  /// Name in `Accept-Encoding` and `Content-Encoding` headers.
+  pub fn name(self) -> &'static str {
+    match self {
+      Encoding::Gzip => "gzip",
+      Encoding::Deflate => "deflate",
+    }
+  }
+
+  /// The coding to answer an `Accept-Encoding` header with: the supported
+  /// one with the highest quality, preferring gzip on ties.
+  pub fn negotiate(accept: &str) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for item in accept.split(',') {
+      let mut params = item.split(';');
+      let name = params.next().unwrap_or_default().trim();
+      let quality = params
+        .filter_map(|x| x.trim().strip_prefix("q="))
+        .find_map(|x| x.trim().parse::<f32>().ok())
+        .unwrap_or(1.0);
+      let candidates: &[Encoding] = match name {
+        "*" => &Encoding::ALL,
+        name => match name.parse() {
+          Ok(Encoding::Gzip) => &[Encoding::Gzip],
+          Ok(Encoding::Deflate) => &[Encoding::Deflate],
+          Err(_) => &[],
+        },
+      };
+      for &encoding in candidates {
+        if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+          best = Some((encoding, quality));
+        }
+      }
+    }
+    best.map(|(encoding, _)| encoding)
+  }
+}
+
+impl FromStr for Encoding {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s.trim().to_ascii_lowercase().as_str() {
+      "gzip" | "x-gzip" => Ok(Encoding::Gzip),
+      "deflate" => Ok(Encoding::Deflate),
+      _ => Err(format!("unsupported content encoding `{}`", s)),
+    }
+  }
+}
+
+impl fmt::Display for Encoding {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+/// The `Accept-Encoding` header value listing every supported coding.
+pub fn accept_encoding() -> String {
+  Encoding::ALL
+    .iter()
+    .map(|x| x.name())
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+/// Whether a response of media type `mime` is worth compressing.
+pub fn compressible(mime: &str) -> bool {
+  let mime = mime.split(';').next().unwrap_or_default().trim();
+  !PRECOMPRESSED.iter().any(|x| {
+    if x.ends_with('/') || x.ends_with('-') || x.ends_with('.') {
+      mime.starts_with(x)
+    } else {
+      mime == *x
+    }
+  })
+}
+
+/// Compresses a body as it's read.
+pub struct Encoder {
+  encoding: Encoding,
+  compressor: Box<CompressorOxide>,
+  header: bool,
+  crc: u32,
+  len: u32,
+}
+
+impl Encoder {
+  /// Starts a body in `encoding`.
+  pub fn new(encoding: Encoding) -> Self {
+    let window_bits = match encoding {
+      Encoding::Gzip => -WINDOW_BITS,
+      Encoding::Deflate => WINDOW_BITS,
+    };
+    let flags = create_comp_flags_from_zip_params(LEVEL, window_bits, 0);
+    Encoder {
+      encoding,
+      compressor: Box::new(CompressorOxide::new(flags)),
+      header: false,
+      crc: !0,
+      len: 0,
+    }
+  }
+
+  /// Compresses the next part of the body, returning the output that is
+  /// ready, which may be nothing.
+  pub fn update(&mut self, input: &[u8]) -> Vec<u8> {
+    let mut out = self.start();
+    if self.encoding == Encoding::Gzip {
+      self.crc = crc32(self.crc, input);
+      self.len = self.len.wrapping_add(input.len() as u32);
+    }
+    self.deflate(input, &mut out, MZFlush::None);
+    out
+  }
+
+  /// Flushes the rest of the body, including any trailer.
+  pub fn finish(mut self) -> Vec<u8> {
+    let mut out = self.start();
+    self.deflate(&[], &mut out, MZFlush::Finish);
+    if self.encoding == Encoding::Gzip {
+      out.extend_from_slice(&(!self.crc).to_le_bytes());
+      out.extend_from_slice(&self.len.to_le_bytes());
+    }
+    out
+  }
+
+  /// The gzip header, the first time it's called.
+  fn start(&mut self) -> Vec<u8> {
+    if self.header || self.encoding != Encoding::Gzip {
+      return vec![];
+    }
+    self.header = true;
+    // No flags or time, unknown OS.
+    vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255]
+  }
+
+  fn deflate(&mut self, mut input: &[u8], out: &mut Vec<u8>, flush: MZFlush) {
+    loop {
+      let start = out.len();
+      out.resize(start + OUTPUT_CHUNK, 0);
+      let result = miniz_oxide::deflate::stream::deflate(
+        &mut self.compressor,
+        input,
+        &mut out[start..],
+        flush,
+      );
+      out.truncate(start + result.bytes_written);
+      input = &input[result.bytes_consumed..];
+      let full = result.bytes_written == OUTPUT_CHUNK;
+      match result.status {
+        Ok(MZStatus::StreamEnd) => break,
+        Ok(_) if full || !input.is_empty() || flush == MZFlush::Finish => {}
+        _ => break,
+      }
+    }
+  }
+}
+
+impl fmt::Debug for Encoder {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Encoder")
+      .field("encoding", &self.encoding)
+      .finish_non_exhaustive()
+  }
+}
+
+/// Undoes the encoding of a body as it arrives.
+pub struct Decoder {
+  encoding: Encoding,
+  state: Box<InflateState>,
+  /// Input not consumed yet: an incomplete gzip header, or the trailer.
+  pending: Vec<u8>,
+  header: bool,
+  done: bool,
+  crc: u32,
+  len: u32,
+}
+
+impl Decoder {
+  /// Starts a body in `encoding`.
+  pub fn new(encoding: Encoding) -> Self {
+    let format = match encoding {
+      Encoding::Gzip => DataFormat::Raw,
+      Encoding::Deflate => DataFormat::Zlib,
+    };
+    Decoder {
+      encoding,
+      state: InflateState::new_boxed(format),
+      pending: vec![],
+      header: encoding != Encoding::Gzip,
+      done: false,
+      crc: !0,
+      len: 0,
+    }
+  }
+
+  /// Decompresses the next part of the body, returning the output that is
+  /// ready, which may be nothing.
+  pub fn update(&mut self, input: &[u8]) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    if self.done {
+      self.pending.extend_from_slice(input);
+      return Ok(out);
+    }
+    let owned;
+    let mut input = if self.pending.is_empty() {
+      input
+    } else {
+      self.pending.extend_from_slice(input);
+      owned = std::mem::take(&mut self.pending);
+      &owned[..]
+    };
+    if !self.header {
+      match gzip_header_len(input)? {
+        Some(len) => {
+          input = &input[len..];
+          self.header = true;
+        }
+        None => {
+          self.pending = input.to_vec();
+          return Ok(out);
+        }
+      }
+    }
+    loop {
+      let start = out.len();
+      out.resize(start + OUTPUT_CHUNK, 0);
+      let result = miniz_oxide::inflate::stream::inflate(
+        &mut self.state,
+        input,
+        &mut out[start..],
+        MZFlush::None,
+      );
+      out.truncate(start + result.bytes_written);
+      input = &input[result.bytes_consumed..];
+      let full = result.bytes_written == OUTPUT_CHUNK;
+      match result.status {
+        Ok(MZStatus::StreamEnd) => {
+          self.done = true;
+          self.pending = input.to_vec();
+          break;
+        }
+        Ok(_) if full || !input.is_empty() => {}
+        Ok(_) | Err(MZError::Buf) => break,
+        Err(err) => {
+          return Err(QvpnError::Protocol(format!(
+            "corrupt {} body: {:?}",
+            self.encoding, err
+          )))
+        }
+      }
+    }
+    if self.encoding == Encoding::Gzip {
+      self.crc = crc32(self.crc, &out);
+      self.len = self.len.wrapping_add(out.len() as u32);
+    }
+    Ok(out)
+  }
+
+  /// Checks that the body was complete.
+  pub fn finish(self) -> Result<()> {
+    let truncated = || QvpnError::Protocol(format!("truncated {} body", self.encoding));
+    if !self.done {
+      return Err(truncated());
+    }
+    if self.encoding == Encoding::Gzip {
+      if self.pending.len() < 8 {
+        return Err(truncated());
+      }
+      let crc = u32::from_le_bytes([
+        self.pending[0],
+        self.pending[1],
+        self.pending[2],
+        self.pending[3],
+      ]);
+      let len = u32::from_le_bytes([
+        self.pending[4],
+        self.pending[5],
+        self.pending[6],
+        self.pending[7],
+      ]);
+      if crc != !self.crc || len != self.len {
+        return Err(QvpnError::Protocol("gzip body fails its checksum".into()));
+      }
+    }
+    Ok(())
+  }
+}
+
+impl fmt::Debug for Decoder {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Decoder")
+      .field("encoding", &self.encoding)
+      .finish_non_exhaustive()
+  }
+}
+
+/// Length of the gzip member header at the start of `buf`, or `None` if
+/// more input is needed to tell.
+fn gzip_header_len(buf: &[u8]) -> Result<Option<usize>> {
+  const FHCRC: u8 = 2;
+  const FEXTRA: u8 = 4;
+  const FNAME: u8 = 8;
+  const FCOMMENT: u8 = 16;
+  if buf.len() < 10 {
+    return Ok(None);
+  }
+  if buf[..3] != [0x1f, 0x8b, 8] {
+    return Err(QvpnError::Protocol("not a gzip body".into()));
+  }
+  let flags = buf[3];
+  let mut len = 10;
+  if flags & FEXTRA != 0 {
+    match buf.get(len..len + 2) {
+      Some(x) => len += 2 + u16::from_le_bytes([x[0], x[1]]) as usize,
+      None => return Ok(None),
+    }
+  }
+  for flag in [FNAME, FCOMMENT] {
+    if flags & flag != 0 {
+      match buf.get(len..).and_then(|x| x.iter().position(|c| *c == 0)) {
+        Some(end) => len += end + 1,
+        None => return Ok(None),
+      }
+    }
+  }
+  if flags & FHCRC != 0 {
+    len += 2;
+  }
+  Ok(Some(len).filter(|x| *x <= buf.len()))
+}
+
+/// Continues the CRC-32 `crc`, kept inverted, over `data`.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+  data.iter().fold(crc, |crc, byte| {
+    (crc >> 8) ^ CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize]
+  })
+}
+
+/// CRC-32 of each byte value, for the reflected polynomial `0xedb88320`.
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+  let mut table = [0; 256];
+  let mut i = 0;
+  while i < 256 {
+    let mut crc = i as u32;
+    let mut bit = 0;
+    while bit < 8 {
+      crc = if crc & 1 != 0 {
+        (crc >> 1) ^ 0xedb8_8320
+      } else {
+        crc >> 1
+      };
+      bit += 1;
+    }
+    table[i] = crc;
+    i += 1;
+  }
+  table
+}
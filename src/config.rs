@@ -0,0 +1,1677 @@
+//! TOML configuration files.
+//!
+//! Every setting is optional. Each `qvpn` command builds a [`Config`] from
+//! its command line flags and [`Config::merge`] it over the file, so flags win
+//! over file values and file values win over the built-in defaults.
+//!
+//! ```toml
+//! [server]
+//! listen = "[::]:4433"
+//! root = "/srv/qvpn"
+//! dashboard = "127.0.0.1:8080"
+//! dashboard_password = "/etc/qvpn/dashboard.pass"
+//! accounting = "/var/log/qvpn/sessions.jsonl"
+//! token_key = "/etc/qvpn/token.key"
+//! expose = "[::]:8000"
+//! index = "index.html"
+//! compress = true
+//!
+//! [[server.mounts]]
+//! prefix = "/pub"
+//! path = "/srv/public"
+//!
+//! [[server.mounts]]
+//! prefix = "/logs"
+//! path = "/var/log"
+//! readonly = true
+//! clients = ["sha256:9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"]
+//!
+//! [[server.forwards]]
+//! target = "intranet:80"
+//!
+//! [[server.reverse_forwards]]
+//! listen = "0.0.0.0:8080"
+//! users = ["alice"]
+//!
+//! [[server.sites]]
+//! network = "192.168.0.0/16"
+//! users = ["branch-office"]
+//!
+//! [[server.egress]]
+//! action = "allow"
+//! network = "192.168.10.0/24"
+//! protocol = "tcp"
+//! ports = "443"
+//!
+//! [[server.egress]]
+//! action = "deny"
+//! network = "0.0.0.0/0"
+//! users = ["contractor"]
+//!
+//! [transport]
+//! mode = "stream"
+//! idle_timeout_ms = 30000
+//! keep_alive_interval_ms = 10000
+//! congestion = "cubic"
+//! conn_receive_window = 67108864
+//!
+//! [tunnel]
+//! name = "qvpn0"
+//! subnet = "10.8.0.0/24"
+//! routes = ["192.168.10.0/24"]
+//! dns = ["10.8.0.1"]
+//! search_domains = ["corp.example.com"]
+//! client_connect = "/etc/qvpn/client-connect.sh"
+//!
+//! [peer]
+//! peers = ["192.0.2.1:5000"]
+//! rendezvous = "198.51.100.7:5000"
+//! name = "laptop"
+//! peer_store = "peers.json"
+//! outbox = "outbox.json"
+//! gossip = true
+//! auto_connect = true
+//! send_overflow = "drop-oldest"
+//!
+//! [reconnect]
+//! max_attempts = 5
+//!
+//! [acme]
+//! enabled = true
+//! domains = ["vpn.example.com"]
+//! contact = ["mailto:admin@example.com"]
+//!
+//! [auth]
+//! htpasswd = "/etc/qvpn/htpasswd"
+//! oidc_issuer = "https://login.example.com/realms/corp"
+//! oidc_audience = "qvpn"
+//!
+//! [hardening]
+//! always_retry = true
+//! validation_cache_secs = 600
+//!
+//! [log]
+//! level = "info,qvpn::server=debug"
+//! format = "json"
+//! stats_interval_ms = 5000
+//!
+//! [service]
+//! daemon = true
+//! pid_file = "/run/qvpn.pid"
+//! ```
+
+use std::{
+  collections::HashMap,
+  fs,
+  net::{IpAddr, SocketAddr},
+  path::{Path, PathBuf},
+  str::FromStr,
+  sync::Arc,
+  time::Duration,
+};
+
+use serde::Deserialize;
+use tracing::warn;
+use url::Url;
+
+use crate::{
+  access::SigningKey,
+  acme::AcmeConfig,
+  auth::{Authenticator, Command, Credentials, Htpasswd, StaticUsers},
+  chunking::ChunkLimits,
+  congestion::Congestion,
+  dashboard::Password,
+  datagram::Transport,
+  dns::DnsConfig,
+  egress::{self, Action, Ports, Protocol},
+  forward,
+  gossip::Gossip,
+  hook::Hooks,
+  identity::{self, Identity},
+  known_hosts::{self, KnownHosts},
+  lease::Ipv4Net,
+  listing::Format,
+  log::LogFormat,
+  mount::Mount,
+  oidc::{Login, Verifier},
+  outbox::OutboxLimits,
+  psk::Psk,
+  reconnect::ReconnectPolicy,
+  relay::RelayLimits,
+  send_queue::{Overflow, QueueLimits},
+  server::Mode,
+  site::{self, Firewall},
+  tls::{self, Trust},
+  tun::{TunConfig, DEFAULT_MTU},
+  tuning::Tuning,
+  udp::Forward,
+  ClientBuilder, PeerBuilder, QvpnError, Result, ServerBuilder,
+};
+
+/// Contents of a configuration file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub server: ServerSection,
+  pub client: ClientSection,
+  pub peer: Option<PeerSection>,
+  pub transport: TransportSection,
+  pub tunnel: TunnelSection,
+  pub reconnect: ReconnectSection,
+  pub hardening: HardeningSection,
+  pub auth: AuthSection,
+  pub acme: AcmeSection,
+  pub trust: TrustSection,
+  pub log: LogSection,
+  pub service: ServiceSection,
+}
+
+/// `[server]` section.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+  /// Address to listen on.
+  pub listen: Option<SocketAddr>,
+  /// Directory to serve files from, at `/` unless a mount replaces it.
+  pub root: Option<PathBuf>,
+  /// More directories to serve, under their own path prefixes.
+  pub mounts: Vec<MountSection>,
+  /// File served for directories containing it, instead of a listing.
+  pub index: Option<String>,
+  /// Follow symlinks out of the root and mounts [default: false].
+  pub follow_symlinks: Option<bool>,
+  /// Compress file responses for clients that accept it [default: false].
+  pub compress: Option<bool>,
+  /// Bytes of popular files to keep in memory [default: 0, disabled].
+  pub cache_size: Option<u64>,
+  /// TLS private key in PEM or DER format.
+  pub key: Option<PathBuf>,
+  /// TLS certificate chain in PEM or DER format.
+  pub cert: Option<PathBuf>,
+  /// CA certificates that client certificates must chain to. Clients without
+  /// a valid certificate are rejected when this is set.
+  pub client_ca: Option<PathBuf>,
+  /// Log TLS keys to `SSLKEYLOGFILE`.
+  pub keylog: Option<bool>,
+  /// Enable stateless retries.
+  pub stateless_retry: Option<bool>,
+  /// Accept 0-RTT `GET` requests from clients resuming a session.
+  pub zero_rtt: Option<bool>,
+  /// `file`, `connect-proxy` to also relay `CONNECT` requests to TCP
+  /// targets, or `doq` to answer DNS over QUIC queries.
+  pub mode: Option<Mode>,
+  /// Accept `PUT` uploads below the root.
+  pub upload: Option<bool>,
+  /// Answer `qvpn bench` requests with synthetic data [default: false].
+  pub bench: Option<bool>,
+  /// Bytes read from a file at a time when streaming it.
+  pub chunk_size: Option<usize>,
+  /// Address to serve Prometheus metrics on.
+  pub metrics: Option<SocketAddr>,
+  /// Require clients to prove the pre-shared key in this file.
+  pub psk: Option<PathBuf>,
+  /// Connections open at once before new ones are refused.
+  pub max_connections: Option<u32>,
+  /// Streams a client may have open at once on one connection.
+  pub max_streams_per_conn: Option<u64>,
+  /// New connections each source IP may open a minute.
+  pub max_connection_rate: Option<u32>,
+  /// Speak only the qvpn protocol, under the `h3-29` ALPN protocol, for
+  /// clients that predate HTTP/3 support.
+  pub legacy_proto: Option<bool>,
+  /// Resolver `doq` mode forwards queries to.
+  pub upstream: Option<SocketAddr>,
+  /// Unprivileged user to switch to once the endpoint and TUN interface
+  /// are set up.
+  pub user: Option<String>,
+  /// Group to switch to with `user`, instead of the user's primary group.
+  pub group: Option<String>,
+  /// Unix socket to answer the management API on, for `qvpn ctl`.
+  pub admin_socket: Option<PathBuf>,
+  /// Loopback address to serve the web dashboard on.
+  pub dashboard: Option<SocketAddr>,
+  /// File with the password the dashboard asks for.
+  pub dashboard_password: Option<PathBuf>,
+  /// File to append a JSON line to for every client session.
+  pub accounting: Option<PathBuf>,
+  /// File with the key access tokens are signed with. Files are then only
+  /// served to requests presenting a token.
+  pub token_key: Option<PathBuf>,
+  /// Targets clients may forward TCP connections to, besides any in
+  /// `connect-proxy` mode.
+  pub forwards: Vec<ForwardSection>,
+  /// Addresses clients may have the server listen on and forward the
+  /// connections to them.
+  pub reverse_forwards: Vec<ReverseForwardSection>,
+  /// Address to accept HTTP connections on for the services clients
+  /// expose, routed by the first label of their `Host` header.
+  pub expose: Option<SocketAddr>,
+  /// Networks tunnel clients may be the gateways of.
+  pub sites: Vec<SiteSection>,
+  /// Where tunnel clients may send packets, first matching rule first.
+  pub egress: Vec<EgressSection>,
+}
+
+/// `[[server.mounts]]` entry: a directory served under a request path
+/// prefix. See [`mount`](crate::mount).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountSection {
+  /// Request path prefix, e.g. `/pub`.
+  pub prefix: String,
+  /// Directory served under the prefix.
+  pub path: PathBuf,
+  /// Refuse uploads below the prefix [default: false].
+  #[serde(default)]
+  pub readonly: Option<bool>,
+  /// SHA-256 fingerprints of the client certificates allowed; anyone if
+  /// empty.
+  #[serde(default)]
+  pub clients: Vec<String>,
+}
+
+impl FromStr for MountSection {
+  type Err = String;
+
+  /// Parses `PREFIX=DIR`.
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s.split_once('=') {
+      Some((prefix, path)) if !path.is_empty() => Ok(MountSection {
+        prefix: prefix.to_string(),
+        path: PathBuf::from(path),
+        readonly: None,
+        clients: vec![],
+      }),
+      _ => Err(format!("invalid mount `{}`, expected PREFIX=DIR", s)),
+    }
+  }
+}
+
+/// `[[server.forwards]]` entry: a target clients may forward connections
+/// to, like `ssh -L`. See [`forward`](crate::forward).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardSection {
+  /// `host:port`, exactly as clients ask for it.
+  pub target: String,
+  /// Users allowed, who must log in; any client that may use the proxy if
+  /// empty.
+  #[serde(default)]
+  pub users: Vec<String>,
+}
+
+impl FromStr for ForwardSection {
+  type Err = String;
+
+  /// Parses `HOST:PORT`.
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s.rsplit_once(':') {
+      Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(ForwardSection {
+        target: s.to_string(),
+        users: vec![],
+      }),
+      _ => Err(format!(
+        "invalid forward target `{}`, expected HOST:PORT",
+        s
+      )),
+    }
+  }
+}
+
+/// `[[server.reverse_forwards]]` entry: an address clients may have the
+/// server listen on, like `ssh -R`. See [`forward`](crate::forward).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReverseForwardSection {
+  /// Address to listen on; clients ask for it by its port.
+  pub listen: SocketAddr,
+  /// Users allowed, who must log in; any client that may use the proxy if
+  /// empty.
+  #[serde(default)]
+  pub users: Vec<String>,
+}
+
+impl FromStr for ReverseForwardSection {
+  type Err = String;
+
+  /// Parses `ADDR:PORT`.
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s.parse() {
+      Ok(listen) => Ok(ReverseForwardSection {
+        listen,
+        users: vec![],
+      }),
+      Err(_) => Err(format!(
+        "invalid reverse forward `{}`, expected ADDR:PORT",
+        s
+      )),
+    }
+  }
+}
+
+/// `[[server.sites]]` entry: networks tunnel clients may advertise as
+/// behind them. See [`site`](crate::site).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SiteSection {
+  /// Network clients may advertise, or any network inside it.
+  pub network: Ipv4Net,
+  /// Users allowed, who must log in; any client that may use the tunnel if
+  /// empty.
+  #[serde(default)]
+  pub users: Vec<String>,
+}
+
+impl FromStr for SiteSection {
+  type Err = String;
+
+  /// Parses `ADDR/PREFIX`.
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s.parse() {
+      Ok(network) => Ok(SiteSection {
+        network,
+        users: vec![],
+      }),
+      Err(_) => Err(format!(
+        "invalid site network `{}`, expected ADDR/PREFIX",
+        s
+      )),
+    }
+  }
+}
+
+/// `[[server.egress]]` entry: a rule allowing or denying the packets of
+/// tunnel clients to a network. See [`egress`](crate::egress).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EgressSection {
+  /// `allow` or `deny`.
+  pub action: Action,
+  /// Network the packets are sent to.
+  pub network: Ipv4Net,
+  /// `tcp`, `udp` or `icmp`; any if unset.
+  pub protocol: Option<Protocol>,
+  /// TCP or UDP destination port, or `START-END` range; any if unset.
+  pub ports: Option<Ports>,
+  /// Users the rule applies to, who must log in; every client if empty.
+  #[serde(default)]
+  pub users: Vec<String>,
+}
+
+impl FromStr for EgressSection {
+  type Err = String;
+
+  /// Parses `ACTION:ADDR/PREFIX[:PROTOCOL[:PORTS]]`, where `PROTOCOL` may be
+  /// `any`.
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let invalid = || {
+      format!(
+        "invalid egress rule `{}`, expected ACTION:ADDR/PREFIX[:PROTOCOL[:PORTS]]",
+        s
+      )
+    };
+    let mut parts = s.split(':');
+    let action = parts.next().unwrap_or_default().parse()?;
+    let network = parts
+      .next()
+      .and_then(|x| x.parse().ok())
+      .ok_or_else(invalid)?;
+    let protocol = match parts.next() {
+      None | Some("any") => None,
+      Some(protocol) => Some(protocol.parse()?),
+    };
+    let ports = parts.next().map(str::parse).transpose()?;
+    if parts.next().is_some() {
+      return Err(invalid());
+    }
+    if ports.is_some() && protocol == Some(Protocol::Icmp) {
+      return Err(format!("invalid egress rule `{}`, icmp has no ports", s));
+    }
+    Ok(EgressSection {
+      action,
+      network,
+      protocol,
+      ports,
+      users: vec![],
+    })
+  }
+}
+
+/// `[client]` section.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientSection {
+  /// Server URL.
+  pub url: Option<Url>,
+  /// TLS server name, if different from the URL host.
+  pub host: Option<String>,
+  /// Local address to bind to.
+  pub bind: Option<SocketAddr>,
+  /// Milliseconds to wait for a connection attempt before also trying the
+  /// server's next address [default: 250].
+  pub attempt_delay_ms: Option<u64>,
+  /// Migrate tunnels and proxies to the new local address when the network
+  /// changes [default: true].
+  pub migration: Option<bool>,
+  /// Accept SOCKS5 connections on this address and tunnel them to the
+  /// server instead of fetching `url`.
+  pub socks5: Option<SocketAddr>,
+  /// Accept HTTP `CONNECT` requests on this address and tunnel them to the
+  /// server instead of fetching `url`.
+  pub http_proxy: Option<SocketAddr>,
+  /// Answer DNS queries on this address by relaying them to a DoQ server
+  /// instead of fetching `url`.
+  pub dns_stub: Option<SocketAddr>,
+  /// UDP ports to forward to targets reachable from the server, as
+  /// `local:port=remote:port`, alongside or instead of the proxies above.
+  pub forward_udp: Vec<Forward>,
+  /// TCP ports to forward to targets the server connects to, as
+  /// `[addr:]port=host:port`, like `ssh -L`.
+  pub forward: Vec<forward::Local>,
+  /// Server ports to forward to services this side connects to, as
+  /// `port=[host:]port`, like `ssh -R`.
+  pub reverse_forward: Vec<forward::Reverse>,
+  /// Name to expose `expose_target` under through the server.
+  pub expose: Option<String>,
+  /// `host:port` of the local service to expose.
+  pub expose_target: Option<String>,
+  /// Directory listing format: `html` or `json`.
+  pub format: Option<Format>,
+  /// Access token to present with requests.
+  pub access_token: Option<String>,
+  /// Ask for compressed file responses [default: false].
+  pub compress: Option<bool>,
+  /// Check downloads against the SHA-256 the server sends [default: false].
+  pub verify: Option<bool>,
+  /// Directory to cache downloads in, so unchanged files aren't
+  /// transferred again.
+  pub cache: Option<PathBuf>,
+  /// Trust only the CA certificates in this PEM or DER file instead of the
+  /// system trust store.
+  pub ca: Option<PathBuf>,
+  /// Skip server certificate verification.
+  pub insecure: Option<bool>,
+  /// Accept only a server whose public key has one of these SHA-256 hashes,
+  /// in hex, instead of checking who signed its certificate.
+  pub pins: Vec<String>,
+  /// Trust whatever key a server presents the first time and only that key
+  /// afterwards.
+  pub tofu: Option<bool>,
+  /// Keep session tickets in memory and send `GET` requests as 0-RTT early
+  /// data when resuming a session within the same run.
+  pub zero_rtt: Option<bool>,
+  /// Ignored: session tickets are only kept in memory, since rustls can't
+  /// store them anywhere else. Accepted so older config files still load.
+  pub session_cache: Option<PathBuf>,
+  /// Prove the pre-shared key in this file to the server.
+  pub psk: Option<PathBuf>,
+  /// User to log in as on servers that require it.
+  pub user: Option<String>,
+  /// File with the password of `user`.
+  pub password_file: Option<PathBuf>,
+  /// OpenID Connect provider to log in at with the device flow, on servers
+  /// that require it.
+  pub oidc_issuer: Option<Url>,
+  /// OAuth client id registered at `oidc_issuer` for qvpn.
+  pub oidc_client_id: Option<String>,
+  /// Scopes to ask for besides `openid`.
+  pub oidc_scopes: Vec<String>,
+}
+
+/// `[peer]` section.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerSection {
+  /// Local IP address to listen on.
+  pub local_ip: Option<IpAddr>,
+  /// Local address to listen on, overriding `local_ip`.
+  pub bind: Option<SocketAddr>,
+  /// Peers to connect to on startup.
+  pub peers: Vec<SocketAddr>,
+  /// Rendezvous server to register with.
+  pub rendezvous: Option<SocketAddr>,
+  /// Name to register with the rendezvous server.
+  pub name: Option<String>,
+  /// Act as a rendezvous server for other peers.
+  pub rendezvous_server: Option<bool>,
+  /// Forward messages between connected peers that can't reach each other.
+  pub relay: Option<bool>,
+  /// Bytes per second a relay forwards; unlimited if unset.
+  pub relay_rate: Option<u64>,
+  /// Relaying peers to fall back to when hole punching fails.
+  pub relay_via: Vec<SocketAddr>,
+  /// Advertise on and discover peers from the local network with mDNS.
+  pub mdns: Option<bool>,
+  /// File to remember peers in across restarts.
+  pub peer_store: Option<PathBuf>,
+  /// Send the peer list to every peer that connects.
+  pub gossip: Option<bool>,
+  /// Connect to peers learned from gossip.
+  pub auto_connect: Option<bool>,
+  /// Most gossiped peers connected to per peer list received.
+  pub gossip_fanout: Option<usize>,
+  /// Directory to save files received from peers in.
+  pub download_dir: Option<PathBuf>,
+  /// Ed25519 key identifying the peer, created if missing.
+  pub identity: Option<PathBuf>,
+  /// Forget peers disconnected or banned for this many seconds [default:
+  /// 600].
+  pub reap_after_secs: Option<u64>,
+  /// Messages queued per peer [default: 256].
+  pub send_queue: Option<usize>,
+  /// What to do with messages for a peer whose queue is full: `block`,
+  /// `drop-oldest` or `drop-newest` [default: block].
+  pub send_overflow: Option<Overflow>,
+  /// Topics to subscribe to on startup.
+  pub topics: Vec<String>,
+  /// File to keep messages for offline peers in until they are back.
+  pub outbox: Option<PathBuf>,
+  /// Bytes of messages the outbox keeps [default: 16777216].
+  pub outbox_max_bytes: Option<u64>,
+  /// Seconds the outbox keeps a message [default: 86400].
+  pub outbox_ttl_secs: Option<u64>,
+  /// Messages bigger than this many bytes are sent in parts [default:
+  /// 262144].
+  pub chunk_size: Option<usize>,
+  /// Largest message in bytes accepted in parts [default: 16777220].
+  pub max_message_size: Option<usize>,
+}
+
+/// `[trust]` section, shared by the client and peer.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrustSection {
+  /// File recording the keys of hosts trusted on first use.
+  pub known_hosts: Option<PathBuf>,
+}
+
+/// `[transport]` section, shared by all modes.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportSection {
+  /// How the client carries requests: `stream` or `datagram`.
+  pub mode: Option<Transport>,
+  /// Close connections after this long without activity.
+  pub idle_timeout_ms: Option<u64>,
+  /// Send keep-alives after this long without traffic, so NAT bindings
+  /// and the idle timeout don't expire on quiet connections.
+  pub keep_alive_interval_ms: Option<u64>,
+  /// Directory to write a qlog trace of every connection to.
+  pub qlog: Option<PathBuf>,
+  /// Congestion controller: `newreno`, `cubic` or `bbr`.
+  pub congestion: Option<Congestion>,
+  /// Bytes the peer may send on a stream before it is read [default:
+  /// 1250000].
+  pub stream_receive_window: Option<u64>,
+  /// Bytes the peer may send on a connection before it is read, and the
+  /// local side may keep unacknowledged [default: unlimited, and 10000000
+  /// unacknowledged].
+  pub conn_receive_window: Option<u64>,
+  /// Requests the peer may have open at once on a connection [default:
+  /// 100]. The server's `max_streams_per_conn` wins over it.
+  pub max_concurrent_bidi_streams: Option<u64>,
+  /// Round-trip time assumed before the first one is measured [default:
+  /// 333].
+  pub initial_rtt_ms: Option<u64>,
+}
+
+/// `[tunnel]` section. Tunnelling is enabled when `name`, `fd` or
+/// `fd_socket` is set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TunnelSection {
+  /// TUN interface name.
+  pub name: Option<String>,
+  /// Descriptor of a TUN interface another program opened and configured,
+  /// used instead of creating one. Unix only.
+  pub fd: Option<i32>,
+  /// Unix socket of a program, like an Android `VpnService`, that opens
+  /// and configures the interface for the lease sent to it and passes its
+  /// descriptor back.
+  pub fd_socket: Option<PathBuf>,
+  /// Subnet the server leases client addresses from.
+  pub subnet: Option<Ipv4Net>,
+  /// Interface MTU on the server; clients use the MTU from their lease,
+  /// lowered to what their path fits.
+  pub mtu: Option<u16>,
+  /// Carry Ethernet frames through a TAP interface on the server instead
+  /// of IP packets; clients follow the server. Linux only.
+  pub tap: Option<bool>,
+  /// Bridge the server adds its TAP interface to, joining clients to that
+  /// LAN segment.
+  pub bridge: Option<String>,
+  /// Networks behind the server that clients route through the tunnel.
+  pub routes: Vec<Ipv4Net>,
+  /// Networks behind the client that it is the gateway of, if the
+  /// server's `sites` allow.
+  pub advertise: Vec<Ipv4Net>,
+  /// Masquerade traffic leaving the tunnel for other interfaces, with
+  /// `iptables` or `nftables`. Linux only.
+  pub masquerade: Option<Firewall>,
+  /// Block what the client sends other than through the tunnel and to its
+  /// servers, with `iptables` or `nftables`, until the tunnel is torn down.
+  /// Linux only.
+  pub kill_switch: Option<Firewall>,
+  /// Have the server install nftables rules masquerading the subnet and
+  /// forwarding its traffic, unless `masquerade` picks the firewall, so
+  /// full-tunnel clients reach past it [default: true].
+  pub firewall: Option<bool>,
+  /// Have the server clamp the MSS of TCP connections through the tunnel to
+  /// what fits in each client's path [default: false].
+  pub clamp_mss: Option<bool>,
+  /// Route all of the client's IPv4 traffic through the tunnel.
+  pub full_tunnel: Option<bool>,
+  /// Name servers clients resolve every name through while tunneled.
+  pub dns: Vec<IpAddr>,
+  /// Search domains pushed to clients along with `dns`.
+  pub search_domains: Vec<String>,
+  /// Servers the client fails over to, in order of preference, when the
+  /// one at `client.url` stops responding.
+  pub failover: Vec<Url>,
+  /// Milliseconds the server gets to answer a control keepalive before the
+  /// client considers it unresponsive [default: 10000].
+  pub health_timeout_ms: Option<u64>,
+  /// Experimental: local addresses, one per extra network, the client
+  /// carries the tunnel over alongside its main connection.
+  pub bond: Vec<IpAddr>,
+  /// Shell command run once the tunnel is up. See [`hook`](crate::hook).
+  pub up: Option<String>,
+  /// Shell command run once the tunnel is down.
+  pub down: Option<String>,
+  /// Shell command the server runs when a client is leased an address.
+  pub client_connect: Option<String>,
+  /// Shell command the server runs when a client with a lease disconnects.
+  pub client_disconnect: Option<String>,
+}
+
+/// `[reconnect]` section, used by the client and peer.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReconnectSection {
+  /// Attempts after the connection is lost; zero disables reconnecting.
+  pub max_attempts: Option<u32>,
+  /// Delay before the first attempt, doubled for each further one.
+  pub initial_backoff_ms: Option<u64>,
+  /// Upper bound on the delay between attempts.
+  pub max_backoff_ms: Option<u64>,
+}
+
+/// `[hardening]` section, used by the server.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HardeningSection {
+  /// Send a stateless retry to every client without a valid token, so no
+  /// state is kept for spoofed sources.
+  pub always_retry: Option<bool>,
+  /// How long a retry token stays valid.
+  pub retry_token_lifetime_ms: Option<u64>,
+  /// Let clients keep their connection when their address changes.
+  pub migration: Option<bool>,
+  /// Log bytes sent per byte received by each handshake.
+  pub log_amplification: Option<bool>,
+  /// Remember addresses that completed a handshake for this long and exempt
+  /// them from `max_connection_rate`.
+  pub validation_cache_secs: Option<u64>,
+}
+
+/// `[auth]` section, used by the server. Tunnel and proxy clients must log
+/// in when one backend is set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+  /// User names and their passwords.
+  pub users: HashMap<String, String>,
+  /// htpasswd file to check users against.
+  pub htpasswd: Option<PathBuf>,
+  /// Program and arguments that get the user name and password on standard
+  /// input and accept by exiting with status 0.
+  pub command: Vec<String>,
+  /// OpenID Connect provider whose ID tokens clients may also log in with.
+  pub oidc_issuer: Option<Url>,
+  /// Audience the ID tokens must be issued for: the clients' OAuth client
+  /// id.
+  pub oidc_audience: Option<String>,
+  /// Claim of the ID tokens naming the user, `sub` by default.
+  pub oidc_claim: Option<String>,
+}
+
+/// `[acme]` section, used by the server. Certificates are obtained
+/// automatically when `enabled` is set.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcmeSection {
+  /// Obtain the server certificate from an ACME CA.
+  pub enabled: Option<bool>,
+  /// Domains the certificate covers.
+  pub domains: Vec<String>,
+  /// Contact URIs for the account, e.g. `mailto:admin@example.com`.
+  pub contact: Vec<String>,
+  /// ACME directory URL [default: Let's Encrypt].
+  pub directory: Option<Url>,
+  /// Directory the account and certificates are kept in.
+  pub cache: Option<PathBuf>,
+  /// Address to answer HTTP-01 challenges on [default: 0.0.0.0:80].
+  pub http_listen: Option<SocketAddr>,
+}
+
+/// `[log]` section, shared by all binaries.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+  /// Filter such as `debug` or `info,qvpn::server=trace`.
+  pub level: Option<String>,
+  /// `text` or `json`.
+  pub format: Option<LogFormat>,
+  /// Milliseconds between logging the transfer statistics of every
+  /// connection. Unset, they aren't logged.
+  pub stats_interval_ms: Option<u64>,
+}
+
+/// `[service]` section, shared by the server and client.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceSection {
+  /// Detach from the terminal.
+  pub daemon: Option<bool>,
+  /// File to write the process id to.
+  pub pid_file: Option<PathBuf>,
+}
+
+impl Config {
+  /// Reads and parses a configuration file.
+  pub fn load(path: &Path) -> Result<Config> {
+    let contents = fs::read_to_string(path)?;
+    toml::from_str(&contents)
+      .map_err(|err| QvpnError::InvalidInput(format!("{}: {}", path.display(), err)))
+  }
+
+  /// Parses the contents of a configuration file.
+  pub fn parse(contents: &str) -> Result<Config> {
+    toml::from_str(contents).map_err(|err| QvpnError::InvalidInput(err.to_string()))
+  }
+
+  /// Combines two configs, preferring values from `self`.
+  pub fn merge(self, fallback: Config) -> Config {
+    Config {
+      server: ServerSection {
+        listen: self.server.listen.or(fallback.server.listen),
+        root: self.server.root.or(fallback.server.root),
+        mounts: if self.server.mounts.is_empty() {
+          fallback.server.mounts
+        } else {
+          self.server.mounts
+        },
+        index: self.server.index.or(fallback.server.index),
+        follow_symlinks: self
+          .server
+          .follow_symlinks
+          .or(fallback.server.follow_symlinks),
+        compress: self.server.compress.or(fallback.server.compress),
+        cache_size: self.server.cache_size.or(fallback.server.cache_size),
+        key: self.server.key.or(fallback.server.key),
+        cert: self.server.cert.or(fallback.server.cert),
+        client_ca: self.server.client_ca.or(fallback.server.client_ca),
+        keylog: self.server.keylog.or(fallback.server.keylog),
+        stateless_retry: self
+          .server
+          .stateless_retry
+          .or(fallback.server.stateless_retry),
+        zero_rtt: self.server.zero_rtt.or(fallback.server.zero_rtt),
+        mode: self.server.mode.or(fallback.server.mode),
+        upload: self.server.upload.or(fallback.server.upload),
+        bench: self.server.bench.or(fallback.server.bench),
+        chunk_size: self.server.chunk_size.or(fallback.server.chunk_size),
+        metrics: self.server.metrics.or(fallback.server.metrics),
+        psk: self.server.psk.or(fallback.server.psk),
+        max_connections: self
+          .server
+          .max_connections
+          .or(fallback.server.max_connections),
+        max_streams_per_conn: self
+          .server
+          .max_streams_per_conn
+          .or(fallback.server.max_streams_per_conn),
+        max_connection_rate: self
+          .server
+          .max_connection_rate
+          .or(fallback.server.max_connection_rate),
+        legacy_proto: self.server.legacy_proto.or(fallback.server.legacy_proto),
+        upstream: self.server.upstream.or(fallback.server.upstream),
+        user: self.server.user.or(fallback.server.user),
+        group: self.server.group.or(fallback.server.group),
+        admin_socket: self.server.admin_socket.or(fallback.server.admin_socket),
+        dashboard: self.server.dashboard.or(fallback.server.dashboard),
+        dashboard_password: self
+          .server
+          .dashboard_password
+          .or(fallback.server.dashboard_password),
+        accounting: self.server.accounting.or(fallback.server.accounting),
+        token_key: self.server.token_key.or(fallback.server.token_key),
+        forwards: if self.server.forwards.is_empty() {
+          fallback.server.forwards
+        } else {
+          self.server.forwards
+        },
+        reverse_forwards: if self.server.reverse_forwards.is_empty() {
+          fallback.server.reverse_forwards
+        } else {
+          self.server.reverse_forwards
+        },
+        expose: self.server.expose.or(fallback.server.expose),
+        sites: if self.server.sites.is_empty() {
+          fallback.server.sites
+        } else {
+          self.server.sites
+        },
+        egress: if self.server.egress.is_empty() {
+          fallback.server.egress
+        } else {
+          self.server.egress
+        },
+      },
+      client: ClientSection {
+        url: self.client.url.or(fallback.client.url),
+        host: self.client.host.or(fallback.client.host),
+        bind: self.client.bind.or(fallback.client.bind),
+        attempt_delay_ms: self
+          .client
+          .attempt_delay_ms
+          .or(fallback.client.attempt_delay_ms),
+        migration: self.client.migration.or(fallback.client.migration),
+        socks5: self.client.socks5.or(fallback.client.socks5),
+        http_proxy: self.client.http_proxy.or(fallback.client.http_proxy),
+        dns_stub: self.client.dns_stub.or(fallback.client.dns_stub),
+        forward_udp: if self.client.forward_udp.is_empty() {
+          fallback.client.forward_udp
+        } else {
+          self.client.forward_udp
+        },
+        forward: if self.client.forward.is_empty() {
+          fallback.client.forward
+        } else {
+          self.client.forward
+        },
+        reverse_forward: if self.client.reverse_forward.is_empty() {
+          fallback.client.reverse_forward
+        } else {
+          self.client.reverse_forward
+        },
+        expose: self.client.expose.or(fallback.client.expose),
+        expose_target: self.client.expose_target.or(fallback.client.expose_target),
+        format: self.client.format.or(fallback.client.format),
+        access_token: self.client.access_token.or(fallback.client.access_token),
+        compress: self.client.compress.or(fallback.client.compress),
+        verify: self.client.verify.or(fallback.client.verify),
+        cache: self.client.cache.or(fallback.client.cache),
+        ca: self.client.ca.or(fallback.client.ca),
+        zero_rtt: self.client.zero_rtt.or(fallback.client.zero_rtt),
+        session_cache: self.client.session_cache.or(fallback.client.session_cache),
+        insecure: self.client.insecure.or(fallback.client.insecure),
+        psk: self.client.psk.or(fallback.client.psk),
+        user: self.client.user.or(fallback.client.user),
+        password_file: self.client.password_file.or(fallback.client.password_file),
+        oidc_issuer: self.client.oidc_issuer.or(fallback.client.oidc_issuer),
+        oidc_client_id: self
+          .client
+          .oidc_client_id
+          .or(fallback.client.oidc_client_id),
+        oidc_scopes: if self.client.oidc_scopes.is_empty() {
+          fallback.client.oidc_scopes
+        } else {
+          self.client.oidc_scopes
+        },
+        tofu: self.client.tofu.or(fallback.client.tofu),
+        pins: if self.client.pins.is_empty() {
+          fallback.client.pins
+        } else {
+          self.client.pins
+        },
+      },
+      peer: match (self.peer, fallback.peer) {
+        (Some(peer), Some(fallback_peer)) => Some(PeerSection {
+          local_ip: peer.local_ip.or(fallback_peer.local_ip),
+          bind: peer.bind.or(fallback_peer.bind),
+          peers: if peer.peers.is_empty() {
+            fallback_peer.peers
+          } else {
+            peer.peers
+          },
+          rendezvous: peer.rendezvous.or(fallback_peer.rendezvous),
+          name: peer.name.or(fallback_peer.name),
+          rendezvous_server: peer.rendezvous_server.or(fallback_peer.rendezvous_server),
+          relay: peer.relay.or(fallback_peer.relay),
+          relay_rate: peer.relay_rate.or(fallback_peer.relay_rate),
+          relay_via: if peer.relay_via.is_empty() {
+            fallback_peer.relay_via
+          } else {
+            peer.relay_via
+          },
+          mdns: peer.mdns.or(fallback_peer.mdns),
+          peer_store: peer.peer_store.or(fallback_peer.peer_store),
+          gossip: peer.gossip.or(fallback_peer.gossip),
+          auto_connect: peer.auto_connect.or(fallback_peer.auto_connect),
+          gossip_fanout: peer.gossip_fanout.or(fallback_peer.gossip_fanout),
+          download_dir: peer.download_dir.or(fallback_peer.download_dir),
+          identity: peer.identity.or(fallback_peer.identity),
+          reap_after_secs: peer.reap_after_secs.or(fallback_peer.reap_after_secs),
+          send_queue: peer.send_queue.or(fallback_peer.send_queue),
+          send_overflow: peer.send_overflow.or(fallback_peer.send_overflow),
+          topics: if peer.topics.is_empty() {
+            fallback_peer.topics
+          } else {
+            peer.topics
+          },
+          outbox: peer.outbox.or(fallback_peer.outbox),
+          outbox_max_bytes: peer.outbox_max_bytes.or(fallback_peer.outbox_max_bytes),
+          outbox_ttl_secs: peer.outbox_ttl_secs.or(fallback_peer.outbox_ttl_secs),
+          chunk_size: peer.chunk_size.or(fallback_peer.chunk_size),
+          max_message_size: peer.max_message_size.or(fallback_peer.max_message_size),
+        }),
+        (peer, fallback_peer) => peer.or(fallback_peer),
+      },
+      transport: TransportSection {
+        mode: self.transport.mode.or(fallback.transport.mode),
+        idle_timeout_ms: self
+          .transport
+          .idle_timeout_ms
+          .or(fallback.transport.idle_timeout_ms),
+        keep_alive_interval_ms: self
+          .transport
+          .keep_alive_interval_ms
+          .or(fallback.transport.keep_alive_interval_ms),
+        qlog: self.transport.qlog.or(fallback.transport.qlog),
+        congestion: self.transport.congestion.or(fallback.transport.congestion),
+        stream_receive_window: self
+          .transport
+          .stream_receive_window
+          .or(fallback.transport.stream_receive_window),
+        conn_receive_window: self
+          .transport
+          .conn_receive_window
+          .or(fallback.transport.conn_receive_window),
+        max_concurrent_bidi_streams: self
+          .transport
+          .max_concurrent_bidi_streams
+          .or(fallback.transport.max_concurrent_bidi_streams),
+        initial_rtt_ms: self
+          .transport
+          .initial_rtt_ms
+          .or(fallback.transport.initial_rtt_ms),
+      },
+      tunnel: TunnelSection {
+        name: self.tunnel.name.or(fallback.tunnel.name),
+        fd: self.tunnel.fd.or(fallback.tunnel.fd),
+        fd_socket: self.tunnel.fd_socket.or(fallback.tunnel.fd_socket),
+        subnet: self.tunnel.subnet.or(fallback.tunnel.subnet),
+        mtu: self.tunnel.mtu.or(fallback.tunnel.mtu),
+        tap: self.tunnel.tap.or(fallback.tunnel.tap),
+        bridge: self.tunnel.bridge.or(fallback.tunnel.bridge),
+        routes: if self.tunnel.routes.is_empty() {
+          fallback.tunnel.routes
+        } else {
+          self.tunnel.routes
+        },
+        advertise: if self.tunnel.advertise.is_empty() {
+          fallback.tunnel.advertise
+        } else {
+          self.tunnel.advertise
+        },
+        masquerade: self.tunnel.masquerade.or(fallback.tunnel.masquerade),
+        kill_switch: self.tunnel.kill_switch.or(fallback.tunnel.kill_switch),
+        firewall: self.tunnel.firewall.or(fallback.tunnel.firewall),
+        clamp_mss: self.tunnel.clamp_mss.or(fallback.tunnel.clamp_mss),
+        full_tunnel: self.tunnel.full_tunnel.or(fallback.tunnel.full_tunnel),
+        dns: if self.tunnel.dns.is_empty() {
+          fallback.tunnel.dns
+        } else {
+          self.tunnel.dns
+        },
+        search_domains: if self.tunnel.search_domains.is_empty() {
+          fallback.tunnel.search_domains
+        } else {
+          self.tunnel.search_domains
+        },
+        failover: if self.tunnel.failover.is_empty() {
+          fallback.tunnel.failover
+        } else {
+          self.tunnel.failover
+        },
+        health_timeout_ms: self
+          .tunnel
+          .health_timeout_ms
+          .or(fallback.tunnel.health_timeout_ms),
+        bond: if self.tunnel.bond.is_empty() {
+          fallback.tunnel.bond
+        } else {
+          self.tunnel.bond
+        },
+        up: self.tunnel.up.or(fallback.tunnel.up),
+        down: self.tunnel.down.or(fallback.tunnel.down),
+        client_connect: self
+          .tunnel
+          .client_connect
+          .or(fallback.tunnel.client_connect),
+        client_disconnect: self
+          .tunnel
+          .client_disconnect
+          .or(fallback.tunnel.client_disconnect),
+      },
+      reconnect: ReconnectSection {
+        max_attempts: self
+          .reconnect
+          .max_attempts
+          .or(fallback.reconnect.max_attempts),
+        initial_backoff_ms: self
+          .reconnect
+          .initial_backoff_ms
+          .or(fallback.reconnect.initial_backoff_ms),
+        max_backoff_ms: self
+          .reconnect
+          .max_backoff_ms
+          .or(fallback.reconnect.max_backoff_ms),
+      },
+      hardening: HardeningSection {
+        always_retry: self
+          .hardening
+          .always_retry
+          .or(fallback.hardening.always_retry),
+        retry_token_lifetime_ms: self
+          .hardening
+          .retry_token_lifetime_ms
+          .or(fallback.hardening.retry_token_lifetime_ms),
+        migration: self.hardening.migration.or(fallback.hardening.migration),
+        log_amplification: self
+          .hardening
+          .log_amplification
+          .or(fallback.hardening.log_amplification),
+        validation_cache_secs: self
+          .hardening
+          .validation_cache_secs
+          .or(fallback.hardening.validation_cache_secs),
+      },
+      auth: AuthSection {
+        users: if self.auth.users.is_empty() {
+          fallback.auth.users
+        } else {
+          self.auth.users
+        },
+        htpasswd: self.auth.htpasswd.or(fallback.auth.htpasswd),
+        command: if self.auth.command.is_empty() {
+          fallback.auth.command
+        } else {
+          self.auth.command
+        },
+        oidc_issuer: self.auth.oidc_issuer.or(fallback.auth.oidc_issuer),
+        oidc_audience: self.auth.oidc_audience.or(fallback.auth.oidc_audience),
+        oidc_claim: self.auth.oidc_claim.or(fallback.auth.oidc_claim),
+      },
+      acme: AcmeSection {
+        enabled: self.acme.enabled.or(fallback.acme.enabled),
+        domains: if self.acme.domains.is_empty() {
+          fallback.acme.domains
+        } else {
+          self.acme.domains
+        },
+        contact: if self.acme.contact.is_empty() {
+          fallback.acme.contact
+        } else {
+          self.acme.contact
+        },
+        directory: self.acme.directory.or(fallback.acme.directory),
+        cache: self.acme.cache.or(fallback.acme.cache),
+        http_listen: self.acme.http_listen.or(fallback.acme.http_listen),
+      },
+      trust: TrustSection {
+        known_hosts: self.trust.known_hosts.or(fallback.trust.known_hosts),
+      },
+      log: LogSection {
+        level: self.log.level.or(fallback.log.level),
+        format: self.log.format.or(fallback.log.format),
+        stats_interval_ms: self
+          .log
+          .stats_interval_ms
+          .or(fallback.log.stats_interval_ms),
+      },
+      service: ServiceSection {
+        daemon: self.service.daemon.or(fallback.service.daemon),
+        pid_file: self.service.pid_file.or(fallback.service.pid_file),
+      },
+    }
+  }
+
+  /// Installs the global log subscriber for these settings.
+  pub fn init_log(&self) -> Result<()> {
+    crate::log::init(
+      self.log.level.as_deref(),
+      self.log.format.unwrap_or_default(),
+    )
+  }
+
+  /// Commands run as the tunnel comes up and goes down and, on a server, as
+  /// clients connect and disconnect.
+  pub fn hooks(&self) -> Hooks {
+    Hooks {
+      up: self.tunnel.up.clone(),
+      down: self.tunnel.down.clone(),
+      client_connect: self.tunnel.client_connect.clone(),
+      client_disconnect: self.tunnel.client_disconnect.clone(),
+    }
+  }
+
+  /// TUN interface settings, if tunnelling is enabled.
+  pub fn tun_config(&self) -> Option<TunConfig> {
+    let tunnel = &self.tunnel;
+    if tunnel.name.is_none() && tunnel.fd.is_none() && tunnel.fd_socket.is_none() {
+      return None;
+    }
+    let defaults = TunConfig::default();
+    Some(TunConfig {
+      name: tunnel.name.clone().unwrap_or(defaults.name),
+      mtu: tunnel.mtu.unwrap_or(DEFAULT_MTU),
+      fd: tunnel.fd,
+      fd_socket: tunnel.fd_socket.clone(),
+      tap: tunnel.tap.unwrap_or(false),
+      bridge: tunnel.bridge.clone(),
+      masquerade: tunnel.masquerade,
+      kill_switch: tunnel.kill_switch,
+      ..defaults
+    })
+  }
+
+  /// Server builder for these settings.
+  pub fn server_builder(&self) -> Result<ServerBuilder> {
+    let server = &self.server;
+    let hardening = &self.hardening;
+    // A DoQ server serves no files.
+    let builder = match (&server.root, server.mode) {
+      (Some(root), _) => ServerBuilder::new(root),
+      (None, Some(Mode::Doq)) => ServerBuilder::new("."),
+      (None, _) if !server.mounts.is_empty() => ServerBuilder::without_root(),
+      (None, _) => {
+        return Err(QvpnError::InvalidInput(
+          "no root directory or mounts configured".into(),
+        ))
+      }
+    };
+    let mut builder = builder
+      .keylog(server.keylog.unwrap_or(false))
+      .stateless_retry(
+        server
+          .stateless_retry
+          .or(hardening.always_retry)
+          .unwrap_or(false),
+      )
+      .migration(hardening.migration.unwrap_or(true))
+      .log_amplification(hardening.log_amplification.unwrap_or(false))
+      .zero_rtt(server.zero_rtt.unwrap_or(false))
+      .mode(server.mode.unwrap_or_default())
+      .uploads(server.upload.unwrap_or(false))
+      .bench(server.bench.unwrap_or(false))
+      .follow_symlinks(server.follow_symlinks.unwrap_or(false))
+      .compression(server.compress.unwrap_or(false))
+      .cache_size(server.cache_size.unwrap_or(0))
+      .legacy_proto(server.legacy_proto.unwrap_or(false));
+    for mount in &server.mounts {
+      let mount = Mount::new(&mount.prefix, &mount.path)
+        .readonly(mount.readonly.unwrap_or(false))
+        .clients(mount.clients.clone());
+      builder = builder.mount(mount);
+    }
+    for rule in &server.forwards {
+      builder = builder.forward(forward::Target::new(&rule.target).users(rule.users.clone()));
+    }
+    for rule in &server.reverse_forwards {
+      builder =
+        builder.reverse_forward(forward::Listen::new(rule.listen).users(rule.users.clone()));
+    }
+    for rule in &server.sites {
+      builder = builder.site(site::Rule::new(rule.network).users(rule.users.clone()));
+    }
+    for rule in &server.egress {
+      builder = builder.egress(
+        egress::Rule::new(rule.action, rule.network)
+          .protocol(rule.protocol)
+          .ports(rule.ports)
+          .users(rule.users.clone()),
+      );
+    }
+    if let Some(index) = &server.index {
+      builder = builder.index(index);
+    }
+    if let Some(listen) = server.listen {
+      builder = builder.listen(listen);
+    }
+    if let Some(metrics) = server.metrics {
+      builder = builder.metrics(metrics);
+    }
+    if let Some(expose) = server.expose {
+      builder = builder.expose(expose);
+    }
+    if let Some(path) = &server.admin_socket {
+      builder = builder.admin_socket(path);
+    }
+    if let Some(authenticator) = self.authenticator()? {
+      builder = builder.authenticator(authenticator);
+    }
+    match (&self.auth.oidc_issuer, &self.auth.oidc_audience) {
+      (Some(issuer), Some(audience)) => {
+        let mut verifier = Verifier::new(issuer.clone(), audience);
+        if let Some(claim) = &self.auth.oidc_claim {
+          verifier = verifier.claim(claim);
+        }
+        builder = builder.oidc(verifier);
+      }
+      (None, None) if self.auth.oidc_claim.is_some() => {
+        return Err(QvpnError::InvalidInput(
+          "oidc_claim needs oidc_issuer and oidc_audience".into(),
+        ))
+      }
+      (None, None) => {}
+      _ => {
+        return Err(QvpnError::InvalidInput(
+          "oidc_issuer and oidc_audience must be configured together".into(),
+        ))
+      }
+    }
+    if let Some(path) = &server.accounting {
+      builder = builder.accounting(path);
+    }
+    if let Some(path) = &server.token_key {
+      builder = builder.access_tokens(SigningKey::load(path)?);
+    }
+    match (server.dashboard, &server.dashboard_password) {
+      (Some(addr), Some(path)) => builder = builder.dashboard(addr, Password::load(path)?),
+      (Some(_), None) => {
+        return Err(QvpnError::InvalidInput(
+          "the dashboard needs a dashboard_password file".into(),
+        ))
+      }
+      (None, _) => {}
+    }
+    if let Some(upstream) = server.upstream {
+      builder = builder.upstream(upstream);
+    }
+    if let Some(user) = &server.user {
+      builder = builder.user(user);
+    }
+    if let Some(group) = &server.group {
+      builder = builder.group(group);
+    }
+    if let Some(acme) = self.acme_config()? {
+      if server.key.is_some() || server.cert.is_some() {
+        return Err(QvpnError::InvalidInput(
+          "acme and key/cert are mutually exclusive".into(),
+        ));
+      }
+      builder = builder.acme(acme);
+    }
+    match (&server.key, &server.cert) {
+      (Some(key), Some(cert)) => builder = builder.certificate(key, cert),
+      (None, None) => {}
+      _ => {
+        return Err(QvpnError::InvalidInput(
+          "key and cert must be configured together".into(),
+        ))
+      }
+    }
+    if let Some(chunk_size) = server.chunk_size {
+      builder = builder.chunk_size(chunk_size);
+    }
+    if let Some(client_ca) = &server.client_ca {
+      builder = builder.client_ca(client_ca);
+    }
+    if let Some(path) = &server.psk {
+      builder = builder.psk(Psk::load(path)?);
+    }
+    if let Some(max) = server.max_connections {
+      builder = builder.max_connections(max);
+    }
+    if let Some(max) = server.max_streams_per_conn {
+      builder = builder.max_streams_per_connection(max);
+    }
+    if let Some(per_minute) = server.max_connection_rate {
+      builder = builder.connection_rate(per_minute);
+    }
+    if let Some(ms) = hardening.retry_token_lifetime_ms {
+      builder = builder.retry_token_lifetime(Duration::from_millis(ms));
+    }
+    if let Some(secs) = hardening.validation_cache_secs {
+      builder = builder.validation_cache(Duration::from_secs(secs));
+    }
+    if let Some(idle_timeout) = self.idle_timeout() {
+      builder = builder.idle_timeout(idle_timeout);
+    }
+    if let Some(interval) = self.keep_alive_interval() {
+      builder = builder.keep_alive_interval(interval);
+    }
+    if let Some(qlog) = &self.transport.qlog {
+      builder = builder.qlog(qlog);
+    }
+    if let Some(congestion) = self.transport.congestion {
+      builder = builder.congestion(congestion);
+    }
+    builder = builder.tuning(self.tuning());
+    if let Some(interval) = self.stats_interval() {
+      builder = builder.stats_interval(interval);
+    }
+    if let Some(tun) = self.tun_config() {
+      builder = builder.tunnel(tun);
+    }
+    if let Some(subnet) = self.tunnel.subnet {
+      builder = builder.subnet(subnet);
+    }
+    builder = builder
+      .firewall(self.tunnel.firewall.unwrap_or(true))
+      .clamp_mss(self.tunnel.clamp_mss.unwrap_or(false))
+      .hooks(self.hooks());
+    Ok(builder.routes(self.tunnel.routes.clone()).dns(DnsConfig {
+      servers: self.tunnel.dns.clone(),
+      search: self.tunnel.search_domains.clone(),
+    }))
+  }
+
+  /// Client builder for these settings.
+  pub fn client_builder(&self) -> Result<ClientBuilder> {
+    let client = &self.client;
+    let mut trusts = vec![];
+    if let Some(ca) = &client.ca {
+      trusts.push(Trust::Ca(ca.clone()));
+    }
+    if client.insecure.unwrap_or(false) {
+      trusts.push(Trust::Insecure);
+    }
+    if !client.pins.is_empty() {
+      let pins = client.pins.iter().map(|pin| tls::parse_pin(pin));
+      trusts.push(Trust::Pinned(pins.collect::<Result<_>>()?));
+    }
+    if client.tofu.unwrap_or(false) {
+      trusts.push(Trust::Tofu(self.known_hosts_path()?));
+    }
+    if trusts.len() > 1 {
+      return Err(QvpnError::InvalidInput(
+        "ca, insecure, pins and tofu are mutually exclusive".into(),
+      ));
+    }
+    let trust = trusts.pop().unwrap_or_default();
+    let mut builder = ClientBuilder::default()
+      .transport(self.transport.mode.unwrap_or_default())
+      .trust(trust)
+      .zero_rtt(self.client.zero_rtt.unwrap_or(false))
+      .full_tunnel(self.tunnel.full_tunnel.unwrap_or(false))
+      .failover(self.tunnel.failover.clone())
+      .bond(self.tunnel.bond.clone())
+      .advertise(self.tunnel.advertise.clone())
+      .hooks(self.hooks())
+      .migration(self.client.migration.unwrap_or(true))
+      .compression(self.client.compress.unwrap_or(false))
+      .verify(self.client.verify.unwrap_or(false));
+    if self.client.session_cache.is_some() {
+      warn!("ignoring session_cache, session tickets are only kept in memory");
+    }
+    if let Some(path) = &self.client.psk {
+      builder = builder.psk(Psk::load(path)?);
+    }
+    match (&self.client.user, &self.client.password_file) {
+      (Some(user), Some(path)) => builder = builder.credentials(Credentials::load(user, path)?),
+      (None, None) => {}
+      _ => {
+        return Err(QvpnError::InvalidInput(
+          "user and password_file must be configured together".into(),
+        ))
+      }
+    }
+    match (&self.client.oidc_issuer, &self.client.oidc_client_id) {
+      (Some(_), Some(_)) if self.client.user.is_some() => {
+        return Err(QvpnError::InvalidInput(
+          "user and oidc_issuer are mutually exclusive".into(),
+        ))
+      }
+      (Some(issuer), Some(client_id)) => {
+        let login = Login::new(issuer.clone(), client_id).scopes(self.client.oidc_scopes.clone());
+        builder = builder.oidc(login);
+      }
+      (None, None) => {}
+      _ => {
+        return Err(QvpnError::InvalidInput(
+          "oidc_issuer and oidc_client_id must be configured together".into(),
+        ))
+      }
+    }
+    if let Some(format) = self.client.format {
+      builder = builder.format(format);
+    }
+    if let Some(token) = &self.client.access_token {
+      builder = builder.access_token(token);
+    }
+    if let Some(dir) = &self.client.cache {
+      builder = builder.cache(dir);
+    }
+    if let Some(bind) = self.client.bind {
+      builder = builder.bind(bind);
+    }
+    if let Some(ms) = self.client.attempt_delay_ms {
+      builder = builder.attempt_delay(Duration::from_millis(ms));
+    }
+    if let Some(ms) = self.tunnel.health_timeout_ms {
+      builder = builder.health_timeout(Duration::from_millis(ms));
+    }
+    if let Some(idle_timeout) = self.idle_timeout() {
+      builder = builder.idle_timeout(idle_timeout);
+    }
+    if let Some(interval) = self.keep_alive_interval() {
+      builder = builder.keep_alive_interval(interval);
+    }
+    if let Some(qlog) = &self.transport.qlog {
+      builder = builder.qlog(qlog);
+    }
+    if let Some(congestion) = self.transport.congestion {
+      builder = builder.congestion(congestion);
+    }
+    builder = builder.tuning(self.tuning());
+    if let Some(interval) = self.stats_interval() {
+      builder = builder.stats_interval(interval);
+    }
+    Ok(builder.reconnect(self.reconnect_policy()))
+  }
+
+  /// Peer builder for these settings.
+  pub fn peer_builder(&self) -> Result<PeerBuilder> {
+    let peer = self.peer.clone().unwrap_or_default();
+    let mut builder = PeerBuilder::default()
+      .rendezvous_server(peer.rendezvous_server.unwrap_or(false))
+      .mdns(peer.mdns.unwrap_or(false));
+    if let Some(local_ip) = peer.local_ip {
+      builder = builder.local_ip(local_ip);
+    }
+    if self.log.stats_interval_ms.is_some() {
+      warn!("ignoring stats_interval_ms, peer connections don't report statistics");
+    }
+    builder = builder.tuning(self.tuning());
+    if let Some(bind) = peer.bind {
+      builder = builder.bind(bind);
+    }
+    if let Some(idle_timeout_ms) = self.transport.idle_timeout_ms {
+      builder = builder.idle_timeout(Duration::from_millis(idle_timeout_ms));
+    }
+    if let Some(interval) = self.keep_alive_interval() {
+      builder = builder.keep_alive_interval(interval);
+    }
+    for peer in &peer.peers {
+      builder = builder.connect_to(*peer);
+    }
+    if peer.relay.unwrap_or(false) {
+      builder = builder.relay(RelayLimits {
+        rate: peer.relay_rate.unwrap_or(0),
+      });
+    }
+    for relay in &peer.relay_via {
+      builder = builder.relay_via(*relay);
+    }
+    if let Some(path) = &peer.peer_store {
+      builder = builder.peer_store(path);
+    }
+    if let Some(secs) = peer.reap_after_secs {
+      builder = builder.reap_after(Duration::from_secs(secs));
+    }
+    let default = QueueLimits::default();
+    builder = builder.send_queue(QueueLimits {
+      capacity: peer.send_queue.unwrap_or(default.capacity),
+      overflow: peer.send_overflow.unwrap_or(default.overflow),
+    });
+    for topic in &peer.topics {
+      builder = builder.subscribe(topic);
+    }
+    let default = ChunkLimits::default();
+    builder = builder.chunking(ChunkLimits {
+      chunk_size: peer.chunk_size.unwrap_or(default.chunk_size),
+      max_assembled: peer.max_message_size.unwrap_or(default.max_assembled),
+    });
+    if let Some(path) = &peer.outbox {
+      let default = OutboxLimits::default();
+      builder = builder.outbox(
+        path,
+        OutboxLimits {
+          max_bytes: peer.outbox_max_bytes.unwrap_or(default.max_bytes),
+          ttl: peer
+            .outbox_ttl_secs
+            .map_or(default.ttl, Duration::from_secs),
+        },
+      );
+    }
+    let identity = match &peer.identity {
+      Some(path) => path.clone(),
+      None => identity::default_path()?,
+    };
+    builder = builder
+      .identity(Identity::load_or_generate(&identity)?)
+      .known_hosts(KnownHosts::new(self.known_hosts_path()?));
+    if peer.gossip.unwrap_or(false) {
+      let default = Gossip::default();
+      builder = builder.gossip(Gossip {
+        auto_connect: peer.auto_connect.unwrap_or(default.auto_connect),
+        fanout: peer.gossip_fanout.unwrap_or(default.fanout),
+      });
+    }
+    match (peer.rendezvous, &peer.name) {
+      (Some(server), Some(name)) => builder = builder.rendezvous(server, name),
+      (None, None) => {}
+      _ => {
+        return Err(QvpnError::InvalidInput(
+          "rendezvous and name must be configured together".into(),
+        ))
+      }
+    }
+    Ok(builder.reconnect(self.reconnect_policy()))
+  }
+
+  /// Reconnection policy for these settings.
+  pub fn reconnect_policy(&self) -> ReconnectPolicy {
+    let default = ReconnectPolicy::default();
+    ReconnectPolicy {
+      max_attempts: self.reconnect.max_attempts.unwrap_or(default.max_attempts),
+      initial_backoff: self
+        .reconnect
+        .initial_backoff_ms
+        .map_or(default.initial_backoff, Duration::from_millis),
+      max_backoff: self
+        .reconnect
+        .max_backoff_ms
+        .map_or(default.max_backoff, Duration::from_millis),
+    }
+  }
+
+  /// File recording the keys of hosts trusted on first use.
+  pub fn known_hosts_path(&self) -> Result<PathBuf> {
+    match &self.trust.known_hosts {
+      Some(path) => Ok(path.clone()),
+      None => known_hosts::default_path(),
+    }
+  }
+
+  /// ACME settings, if automatic certificates are enabled.
+  pub fn acme_config(&self) -> Result<Option<AcmeConfig>> {
+    let acme = &self.acme;
+    if !acme.enabled.unwrap_or(false) {
+      return Ok(None);
+    }
+    let mut config = AcmeConfig::new(acme.domains.clone())?;
+    config.contact = acme.contact.clone();
+    if let Some(directory) = &acme.directory {
+      config.directory = directory.to_string();
+    }
+    if let Some(cache) = &acme.cache {
+      config.cache = cache.clone();
+    }
+    if let Some(addr) = acme.http_listen {
+      config.http_listen = addr;
+    }
+    Ok(Some(config))
+  }
+
+  /// The login backend of the `[auth]` section, if one is set.
+  pub fn authenticator(&self) -> Result<Option<Arc<dyn Authenticator>>> {
+    let auth = &self.auth;
+    let mut backends: Vec<Arc<dyn Authenticator>> = vec![];
+    if !auth.users.is_empty() {
+      backends.push(Arc::new(StaticUsers::new(auth.users.clone())?));
+    }
+    if let Some(path) = &auth.htpasswd {
+      backends.push(Arc::new(Htpasswd::new(path)?));
+    }
+    if let Some((program, args)) = auth.command.split_first() {
+      backends.push(Arc::new(Command::new(program, args.to_vec())));
+    }
+    if backends.len() > 1 {
+      return Err(QvpnError::InvalidInput(
+        "auth users, htpasswd and command are mutually exclusive".into(),
+      ));
+    }
+    Ok(backends.pop())
+  }
+
+  fn stats_interval(&self) -> Option<Duration> {
+    self.log.stats_interval_ms.map(Duration::from_millis)
+  }
+
+  fn tuning(&self) -> Tuning {
+    let transport = &self.transport;
+    Tuning {
+      stream_receive_window: transport.stream_receive_window,
+      receive_window: transport.conn_receive_window,
+      max_bidi_streams: transport.max_concurrent_bidi_streams,
+      initial_rtt: transport.initial_rtt_ms.map(Duration::from_millis),
+    }
+  }
+
+  fn idle_timeout(&self) -> Option<Duration> {
+    self.transport.idle_timeout_ms.map(Duration::from_millis)
+  }
+
+  fn keep_alive_interval(&self) -> Option<Duration> {
+    self
+      .transport
+      .keep_alive_interval_ms
+      .map(Duration::from_millis)
+  }
+}
+
+/// Parses a duration such as `30s`, `500ms`, `5m` or `1h`. A bare number
+/// is taken as seconds.
+pub fn parse_duration(s: &str) -> std::result::Result<Duration, String> {
+  let s = s.trim();
+  let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+  let (value, unit) = s.split_at(split);
+  let value: u64 = value
+    .parse()
+    .map_err(|_| format!("invalid duration `{}`", s))?;
+  match unit.trim() {
+    "ms" => Ok(Duration::from_millis(value)),
+    "" | "s" => Ok(Duration::from_secs(value)),
+    "m" => Ok(Duration::from_secs(value * 60)),
+    "h" => Ok(Duration::from_secs(value * 3600)),
+    unit => Err(format!(
+      "unknown unit `{}` in duration `{}`, expected ms, s, m or h",
+      unit, s
+    )),
+  }
+}
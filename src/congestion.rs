@@ -0,0 +1,105 @@
+//! Congestion controller selection.
+
+use std::{fmt, str::FromStr, sync::Arc};
+
+use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+use serde::{de, Deserialize, Deserializer};
+use tracing::info;
+
+/// Congestion controller used for a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Congestion {
+  /// Halves the window on loss and grows it by one datagram per round trip.
+  #[default]
+  NewReno,
+  /// Grows the window along a cubic curve around the size it had at the last
+  /// loss, which recovers faster on long fat paths.
+  Cubic,
+  /// Paces sending at the measured bottleneck bandwidth and round trip time
+  /// instead of backing off on loss. quinn's implementation is experimental.
+  Bbr,
+}
+
+impl Congestion {
+  /// Installs this controller in a transport configuration.
+  pub fn configure(self, transport: &mut quinn::TransportConfig) {
+    match self {
+      Congestion::NewReno => {
+        transport.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+      }
+      Congestion::Cubic => {
+        transport.congestion_controller_factory(Arc::new(CubicConfig::default()))
+      }
+      Congestion::Bbr => transport.congestion_controller_factory(Arc::new(BbrConfig::default())),
+    };
+  }
+}
+
+impl FromStr for Congestion {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "newreno" | "new-reno" => Ok(Congestion::NewReno),
+      "cubic" => Ok(Congestion::Cubic),
+      "bbr" => Ok(Congestion::Bbr),
+      _ => Err(format!(
+        "unknown congestion controller `{}`, expected newreno, cubic or bbr",
+        s
+      )),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Congestion {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Congestion {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Congestion::NewReno => f.write_str("newreno"),
+      Congestion::Cubic => f.write_str("cubic"),
+      Congestion::Bbr => f.write_str("bbr"),
+    }
+  }
+}
+
+/// Logs the controller and the final window and RTT of a connection that is
+/// about to close.
+pub fn report(connection: &quinn::Connection, congestion: Congestion) {
+  let stats = connection.stats();
+  info!(
+    %congestion,
+    cwnd = stats.path.cwnd,
+    rtt = ?stats.path.rtt,
+    congestion_events = stats.path.congestion_events,
+    "connection stats"
+  );
+}
+
+/// Calls [`report`] when dropped.
+pub struct Report {
+  connection: quinn::Connection,
+  congestion: Congestion,
+}
+
+impl Report {
+  /// Reports on `connection` once the guard goes out of scope.
+  pub fn new(connection: quinn::Connection, congestion: Congestion) -> Self {
+    Report {
+      connection,
+      congestion,
+    }
+  }
+}
+
+impl Drop for Report {
+  fn drop(&mut self) {
+    report(&self.connection, self.congestion);
+  }
+}
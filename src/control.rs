@@ -0,0 +1,217 @@
+//! The control channel between qvpn clients and the server.
+//!
+//! The stream a tunnel or proxy client negotiates its version on (see
+//! [`version`]) stays open as the control stream. It is the first stream
+//! the client opens, so stream 0 unless a pre-shared key was proven first.
+//! Both sides then exchange [`Message`]s on it, bincode encoded and framed
+//! with their length as a 32-bit big-endian integer, for as long as the
+//! connection lasts.
+//!
+//! [`version`]: crate::version
+
+use std::{
+  collections::HashMap,
+  io,
+  net::{IpAddr, Ipv4Addr},
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use crate::{lease::Ipv4Net, QvpnError, Result};
+
+/// Largest encoded message accepted.
+pub const MAX_MESSAGE: usize = 64 * 1024;
+
+/// How often clients send a [`Message::Keepalive`].
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A message on the control stream.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+  /// Asks the server for a tunnel address. Answered with [`Message::Mtu`],
+  /// [`Message::Routes`], [`Message::Dns`] and, for a TAP tunnel,
+  /// [`Message::Ethernet`], then [`Message::Assign`].
+  LeaseRequest,
+  /// Tunnel address assigned to the client.
+  Assign {
+    address: Ipv4Addr,
+    netmask: Ipv4Addr,
+    gateway: Ipv4Addr,
+  },
+  /// Networks the client routes through the tunnel, replacing any pushed
+  /// before.
+  Routes { routes: Vec<Ipv4Net> },
+  /// Name servers and search domains the client resolves names with while
+  /// tunneled, replacing any pushed before.
+  Dns {
+    servers: Vec<IpAddr>,
+    search: Vec<String>,
+  },
+  /// MTU of the tunnel interface. Sent again after [`Message::Assign`]
+  /// whenever path MTU discovery changes what fits in a datagram, never
+  /// above the first.
+  Mtu { mtu: u16 },
+  /// Sent by clients every [`KEEPALIVE_INTERVAL`] and echoed by the server.
+  Keepalive,
+  /// The server's certificate changed; connections made from now on see
+  /// the new one.
+  Rekey,
+  /// The server is about to close the connection.
+  Disconnect { reason: String },
+  /// Asks for a token other connections can join the client's tunnel
+  /// address with, after [`Message::Assign`]. Answered with
+  /// [`Message::BondToken`]. Needs protocol version 2.
+  BondRequest,
+  /// Secret that lets another connection carry the tunnel packets of the
+  /// address leased on this one.
+  BondToken { token: u64 },
+  /// Sent instead of a [`Message::LeaseRequest`] to add this connection as
+  /// another path of a bonded uplink. Answered with [`Message::Joined`].
+  Join { token: u64 },
+  /// The connection now carries packets for `address` too.
+  Joined { address: Ipv4Addr },
+  /// Logs in as `user`, before a [`Message::LeaseRequest`] or any
+  /// `CONNECT` request. Answered with [`Message::Authenticated`], or
+  /// [`Message::AuthFailed`] before the server closes the connection.
+  /// Needs protocol version 3.
+  Authenticate { user: String, password: String },
+  /// Logs in with an OpenID Connect ID token, like
+  /// [`Message::Authenticate`]. Needs protocol version 3.
+  Token { token: String },
+  /// The login succeeded.
+  Authenticated,
+  /// The login failed.
+  AuthFailed { reason: String },
+  /// The tunnel carries Ethernet frames, so the client opens a TAP
+  /// interface for its lease. Sent before [`Message::Assign`]. Needs
+  /// protocol version 8.
+  Ethernet,
+  /// The client is the gateway of these networks, after
+  /// [`Message::Assign`]. The server closes the connection if it may not
+  /// advertise them. Needs protocol version 9.
+  Advertise { networks: Vec<Ipv4Net> },
+}
+
+impl Message {
+  fn encode(&self) -> BytesMut {
+    let body = bincode::serialize(self).expect("message serializes");
+    let mut buf = BytesMut::with_capacity(4 + body.len());
+    buf.put_u32(body.len() as u32);
+    buf.put_slice(&body);
+    buf
+  }
+}
+
+/// One side of a control stream.
+#[derive(Debug)]
+pub struct Control {
+  send: quinn::SendStream,
+  recv: quinn::RecvStream,
+  buf: BytesMut,
+}
+
+impl Control {
+  /// Takes over a stream, of which `received` was already read.
+  pub fn new(send: quinn::SendStream, recv: quinn::RecvStream, received: Vec<u8>) -> Self {
+    Control {
+      send,
+      recv,
+      buf: BytesMut::from(&received[..]),
+    }
+  }
+
+  /// Sends `message`.
+  pub async fn send(&mut self, message: &Message) -> Result<()> {
+    self.send.write_all(&message.encode()).await?;
+    Ok(())
+  }
+
+  /// Receives the next message, or `None` once the peer finished the
+  /// stream. Cancelling it loses nothing, so it can be raced against
+  /// [`Control::send`] in a `select!`.
+  pub async fn recv(&mut self) -> Result<Option<Message>> {
+    loop {
+      if self.buf.len() >= 4 {
+        let len = (&self.buf[..4]).get_u32() as usize;
+        if len > MAX_MESSAGE {
+          return Err(QvpnError::Protocol(format!(
+            "{} byte control message exceeds the {} byte limit",
+            len, MAX_MESSAGE
+          )));
+        }
+        if self.buf.len() >= 4 + len {
+          self.buf.advance(4);
+          let body = self.buf.split_to(len);
+          return bincode::deserialize(&body)
+            .map(Some)
+            .map_err(|err| QvpnError::Protocol(format!("bad control message: {}", err)));
+        }
+      }
+      let mut chunk = [0; 4096];
+      match self.recv.read(&mut chunk).await.map_err(io::Error::from)? {
+        Some(len) => self.buf.extend_from_slice(&chunk[..len]),
+        None if self.buf.is_empty() => return Ok(None),
+        None => return Err(QvpnError::Protocol("control stream cut short".into())),
+      }
+    }
+  }
+
+  /// Finishes the sending side and waits until the peer received
+  /// everything sent.
+  pub async fn finish(&mut self) -> Result<()> {
+    self.send.finish()?;
+    self.send.stopped().await.map_err(io::Error::from)?;
+    Ok(())
+  }
+}
+
+/// Queues for messages to the control stream of every connection, so the
+/// server can notify all clients at once.
+#[derive(Debug, Clone, Default)]
+pub struct Channels {
+  senders: Arc<Mutex<HashMap<usize, mpsc::UnboundedSender<Message>>>>,
+}
+
+impl Channels {
+  /// Registers the control stream of `connection`, returning the messages
+  /// queued for it.
+  pub fn register(&self, connection: &quinn::Connection) -> mpsc::UnboundedReceiver<Message> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    self
+      .senders
+      .lock()
+      .expect("control channels lock poisoned")
+      .insert(connection.stable_id(), tx);
+    rx
+  }
+
+  /// Forgets the control stream of `connection`.
+  pub fn unregister(&self, connection: &quinn::Connection) {
+    self
+      .senders
+      .lock()
+      .expect("control channels lock poisoned")
+      .remove(&connection.stable_id());
+  }
+
+  /// Queues `message` for the control stream of the connection with id
+  /// `id`, returning whether it has one.
+  pub fn send(&self, id: usize, message: Message) -> bool {
+    let senders = self.senders.lock().expect("control channels lock poisoned");
+    senders.get(&id).is_some_and(|tx| tx.send(message).is_ok())
+  }
+
+  /// Queues `message` for every registered control stream, returning how
+  /// many there are.
+  pub fn broadcast(&self, message: &Message) -> usize {
+    let senders = self.senders.lock().expect("control channels lock poisoned");
+    senders
+      .values()
+      .filter(|tx| tx.send(message.clone()).is_ok())
+      .count()
+  }
+}
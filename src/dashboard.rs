@@ -0,0 +1,150 @@
+//! Web dashboard for the server.
+//!
+//! [`serve`] answers plain HTTP/1.1 like the [`metrics`](crate::metrics)
+//! endpoint: `GET /` returns a page showing the active connections with
+//! bandwidth graphs, the lease table and recent log events, which it polls
+//! from `GET /status.json`. Both require HTTP basic authentication with the
+//! dashboard password; the user name is ignored.
+//!
+//! Since the password crosses the network in the clear, the server only
+//! binds the dashboard to loopback addresses. Reach it from elsewhere
+//! through an SSH tunnel or a reverse proxy terminating TLS.
+
+use std::{fs, future::Future, path::Path, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::{
+  hmac,
+  rand::{SecureRandom, SystemRandom},
+};
+use serde_json::Value;
+use tokio::{
+  io::AsyncWriteExt,
+  net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+use crate::{http, QvpnError, Result};
+
+/// The dashboard page.
+const PAGE: &str = include_str!("dashboard.html");
+
+/// Checks dashboard passwords without keeping the password itself or
+/// leaking how much of a guess matched.
+#[derive(Debug, Clone)]
+pub struct Password {
+  key: hmac::Key,
+  tag: hmac::Tag,
+}
+
+impl Password {
+  /// Accepts `password`, which must not be empty.
+  pub fn new(password: &[u8]) -> Result<Self> {
+    if password.is_empty() {
+      return Err(QvpnError::InvalidInput(
+        "the dashboard password is empty".into(),
+      ));
+    }
+    let mut secret = [0; 32];
+    SystemRandom::new()
+      .fill(&mut secret)
+      .map_err(|_| QvpnError::InvalidInput("no randomness for the dashboard key".into()))?;
+    let key = hmac::Key::new(hmac::HMAC_SHA256, &secret);
+    let tag = hmac::sign(&key, password);
+    Ok(Password { key, tag })
+  }
+
+  /// Reads the password from a file, ignoring a trailing newline.
+  pub fn load(path: &Path) -> Result<Self> {
+    let password = fs::read(path)?;
+    let end = password
+      .iter()
+      .rposition(|c| !matches!(c, b'\r' | b'\n'))
+      .map_or(0, |x| x + 1);
+    Password::new(&password[..end])
+  }
+
+  /// Whether `password` is the dashboard password.
+  pub fn matches(&self, password: &[u8]) -> bool {
+    hmac::verify(&self.key, password, self.tag.as_ref()).is_ok()
+  }
+}
+
+/// Answers dashboard requests on `listener` until it fails, getting the
+/// server's state from `status`.
+pub async fn serve<F, Fut>(listener: TcpListener, password: Password, status: F) -> Result<()>
+where
+  F: Fn() -> Fut + Send + Sync + 'static,
+  Fut: Future<Output = Value> + Send,
+{
+  info!(listen = %listener.local_addr()?, "dashboard up");
+  let (password, status) = (Arc::new(password), Arc::new(status));
+  loop {
+    let (tcp, peer) = listener.accept().await?;
+    let (password, status) = (password.clone(), status.clone());
+    tokio::spawn(async move {
+      if let Err(err) = answer(tcp, &password, &*status).await {
+        debug!(%peer, "dashboard request failed: {}", err);
+      }
+    });
+  }
+}
+
+async fn answer<F, Fut>(mut tcp: TcpStream, password: &Password, status: &F) -> Result<()>
+where
+  F: Fn() -> Fut,
+  Fut: Future<Output = Value>,
+{
+  let (head, _) = http::read_until(&mut tcp, b"\r\n\r\n").await?;
+  let head = String::from_utf8_lossy(&head);
+  let response = if !authorized(&head, password) {
+    if head
+      .lines()
+      .any(|line| line.to_ascii_lowercase().starts_with("authorization:"))
+    {
+      warn!(peer = %tcp.peer_addr()?, "wrong dashboard password");
+    }
+    "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"qvpn\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+  } else if head.starts_with("GET / ") {
+    respond("text/html; charset=utf-8", PAGE)
+  } else if head.starts_with("GET /status.json ") {
+    respond("application/json", &status().await.to_string())
+  } else {
+    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+  };
+  tcp.write_all(response.as_bytes()).await?;
+  tcp.shutdown().await?;
+  Ok(())
+}
+
+/// Whether the request with `head` carries basic credentials with the
+/// dashboard password.
+fn authorized(head: &str, password: &Password) -> bool {
+  let credentials = head.lines().find_map(|line| {
+    let (name, value) = line.split_once(':')?;
+    if !name.eq_ignore_ascii_case("authorization") {
+      return None;
+    }
+    let (scheme, credentials) = value.trim().split_once(' ')?;
+    scheme
+      .eq_ignore_ascii_case("basic")
+      .then(|| credentials.trim())
+  });
+  let decoded = match credentials.and_then(|x| STANDARD.decode(x).ok()) {
+    Some(decoded) => decoded,
+    None => return false,
+  };
+  match decoded.iter().position(|&c| c == b':') {
+    Some(colon) => password.matches(&decoded[colon + 1..]),
+    None => false,
+  }
+}
+
+fn respond(content_type: &str, body: &str) -> String {
+  format!(
+    "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+    content_type,
+    body.len(),
+    body
+  )
+}
@@ -0,0 +1,164 @@
+//! Framing for application data carried in QUIC datagrams.
+//!
+//! Every datagram starts with a 5 byte header: a one byte [`Kind`] followed by
+//! a big-endian `u32` id that pairs responses with their requests. Tunnel
+//! packets use id 0, or a sequence number when sent over a bonded uplink (see
+//! [`bond`]).
+//!
+//! [`bond`]: crate::bond
+
+use std::{fmt, str::FromStr};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{de, Deserialize, Deserializer};
+
+/// Length of the frame header in bytes.
+pub const HEADER_LEN: usize = 5;
+
+/// Type of a datagram frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+  /// An IP packet for the tunnel, or an Ethernet frame if it is a TAP
+  /// tunnel.
+  Packet,
+  /// A file request, in the same format as on a stream.
+  Request,
+  /// The response to a request.
+  Response,
+  /// A request failed; the payload is a human readable reason.
+  Error,
+  /// Synthetic data of a benchmark, numbered by the id. See
+  /// [`bench`](crate::bench).
+  Bench,
+  /// A UDP payload of the flow numbered by the id. See
+  /// [`udp`](crate::udp).
+  Udp,
+  /// Part of a tunnel packet too large for one datagram, with the id the
+  /// packet would have had. See [`fragment`](crate::fragment).
+  Fragment,
+}
+
+impl Kind {
+  fn to_u8(self) -> u8 {
+    match self {
+      Kind::Packet => 0,
+      Kind::Request => 1,
+      Kind::Response => 2,
+      Kind::Error => 3,
+      Kind::Bench => 4,
+      Kind::Udp => 5,
+      Kind::Fragment => 6,
+    }
+  }
+
+  fn from_u8(x: u8) -> Option<Kind> {
+    match x {
+      0 => Some(Kind::Packet),
+      1 => Some(Kind::Request),
+      2 => Some(Kind::Response),
+      3 => Some(Kind::Error),
+      4 => Some(Kind::Bench),
+      5 => Some(Kind::Udp),
+      6 => Some(Kind::Fragment),
+      _ => None,
+    }
+  }
+}
+
+/// A single datagram.
+#[derive(Debug, Clone)]
+pub struct Frame {
+  pub kind: Kind,
+  pub id: u32,
+  pub payload: Bytes,
+}
+
+impl Frame {
+  /// Frame carrying a tunnel packet.
+  pub fn packet(payload: impl Into<Bytes>) -> Self {
+    Frame {
+      kind: Kind::Packet,
+      id: 0,
+      payload: payload.into(),
+    }
+  }
+
+  /// Frame carrying tunnel packet number `seq` of a bonded uplink.
+  pub fn sequenced(seq: u32, payload: impl Into<Bytes>) -> Self {
+    Frame {
+      kind: Kind::Packet,
+      id: seq,
+      payload: payload.into(),
+    }
+  }
+
+  /// Serializes the frame.
+  pub fn encode(&self) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + self.payload.len());
+    buf.put_u8(self.kind.to_u8());
+    buf.put_u32(self.id);
+    buf.put_slice(&self.payload);
+    buf.freeze()
+  }
+
+  /// Parses a received datagram, returning `None` if it is malformed.
+  pub fn decode(mut datagram: Bytes) -> Option<Frame> {
+    if datagram.len() < HEADER_LEN {
+      return None;
+    }
+    let kind = Kind::from_u8(datagram[0])?;
+    let id = u32::from_be_bytes([datagram[1], datagram[2], datagram[3], datagram[4]]);
+    let payload = datagram.split_off(HEADER_LEN);
+    Some(Frame { kind, id, payload })
+  }
+}
+
+/// Largest payload that fits in a single frame on `connection`.
+pub fn max_payload(connection: &quinn::Connection) -> Option<usize> {
+  connection
+    .max_datagram_size()
+    .map(|max| max.saturating_sub(HEADER_LEN))
+}
+
+/// How requests are carried to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+  /// One bidirectional stream per request; reliable and unbounded in size.
+  #[default]
+  Stream,
+  /// One datagram per request and response; unreliable and limited to a
+  /// single datagram, but free of head-of-line blocking.
+  Datagram,
+}
+
+impl FromStr for Transport {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "stream" => Ok(Transport::Stream),
+      "datagram" => Ok(Transport::Datagram),
+      _ => Err(format!(
+        "unknown transport `{}`, expected stream or datagram",
+        s
+      )),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Transport {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Transport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Transport::Stream => f.write_str("stream"),
+      Transport::Datagram => f.write_str("datagram"),
+    }
+  }
+}
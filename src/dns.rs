@@ -0,0 +1,377 @@
+//! Resolvers pushed to tunnel clients.
+//!
+//! While tunneled the client resolves every name through the servers the
+//! qvpn server pushed, so queries don't leak to the resolvers of the
+//! underlying network. With systemd-resolved the tunnel link gets the `~.`
+//! routing domain and becomes the default route for queries; otherwise
+//! `resolvconf` is asked for an exclusive entry, and failing that
+//! `/etc/resolv.conf` is rewritten. On Windows the servers are set on the
+//! tunnel adapter with `netsh` and the adapter gets the lowest interface
+//! metric, so its resolvers are asked first. On macOS every network service
+//! is pointed at them with `networksetup`. The previous configuration is
+//! restored when the [`DnsOverride`] is dropped.
+
+use std::{fmt, net::IpAddr};
+
+#[cfg(target_os = "linux")]
+use std::{fs, path::Path};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::{io, io::Write, process::Command};
+
+use tracing::{info, warn};
+
+#[cfg(windows)]
+use crate::tun::windows::netsh;
+#[cfg(not(windows))]
+use crate::QvpnError;
+use crate::Result;
+
+/// Resolver file rewritten when no resolver manager is installed.
+#[cfg(target_os = "linux")]
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Name servers and search domains for the tunnel.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DnsConfig {
+  /// Name server addresses, in order of preference.
+  pub servers: Vec<IpAddr>,
+  /// Domains appended to unqualified names.
+  pub search: Vec<String>,
+}
+
+impl DnsConfig {
+  /// Whether there is nothing to install.
+  pub fn is_empty(&self) -> bool {
+    self.servers.is_empty() && self.search.is_empty()
+  }
+
+  /// The configuration as `resolv.conf` lines.
+  pub fn resolv_conf(&self) -> String {
+    let mut out = String::from("# generated by qvpn\n");
+    for server in &self.servers {
+      out.push_str(&format!("nameserver {}\n", server));
+    }
+    if !self.search.is_empty() {
+      out.push_str(&format!("search {}\n", self.search.join(" ")));
+    }
+    out
+  }
+}
+
+/// How the resolver configuration was changed.
+enum Backend {
+  /// Through `resolvectl`, for the interface.
+  #[cfg(target_os = "linux")]
+  Resolved,
+  /// Through `resolvconf`, as the interface's entry.
+  #[cfg(target_os = "linux")]
+  Resolvconf,
+  /// By rewriting `/etc/resolv.conf`, which held this before.
+  #[cfg(target_os = "linux")]
+  File(Vec<u8>),
+  /// Through `networksetup`, on every network service, which had these
+  /// servers and search domains before.
+  #[cfg(target_os = "macos")]
+  Networksetup(Vec<(String, Vec<String>, Vec<String>)>),
+  /// Through `netsh`, on the adapter.
+  #[cfg(windows)]
+  Netsh,
+}
+
+impl fmt::Debug for Backend {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match *self {
+      #[cfg(target_os = "linux")]
+      Backend::Resolved => "systemd-resolved",
+      #[cfg(target_os = "linux")]
+      Backend::Resolvconf => "resolvconf",
+      #[cfg(target_os = "linux")]
+      Backend::File(_) => "/etc/resolv.conf",
+      #[cfg(target_os = "macos")]
+      Backend::Networksetup(_) => "networksetup",
+      #[cfg(windows)]
+      Backend::Netsh => "netsh",
+    })
+  }
+}
+
+/// Resolver configuration installed for a tunnel interface, restored when
+/// dropped.
+#[derive(Debug)]
+pub struct DnsOverride {
+  dev: String,
+  backend: Backend,
+}
+
+impl DnsOverride {
+  /// Resolves names through `config` for as long as the override lives.
+  #[cfg(target_os = "linux")]
+  pub fn install(dev: &str, config: &DnsConfig) -> Result<Self> {
+    let backend = if resolved_running() {
+      let mut dns = vec!["dns", dev];
+      let servers: Vec<_> = config.servers.iter().map(IpAddr::to_string).collect();
+      dns.extend(servers.iter().map(String::as_str));
+      run("resolvectl", &dns, None)?;
+      let mut domain = vec!["domain", dev, "~."];
+      domain.extend(config.search.iter().map(String::as_str));
+      run("resolvectl", &domain, None)?;
+      run("resolvectl", &["default-route", dev, "yes"], None)?;
+      Backend::Resolved
+    } else if which("resolvconf") {
+      let entry = format!("{}.qvpn", dev);
+      run(
+        "resolvconf",
+        &["-x", "-a", &entry],
+        Some(config.resolv_conf().as_bytes()),
+      )?;
+      Backend::Resolvconf
+    } else {
+      let previous = fs::read(RESOLV_CONF)?;
+      write_resolv_conf(config.resolv_conf().as_bytes())?;
+      Backend::File(previous)
+    };
+    info!(
+      interface = dev,
+      servers = ?config.servers,
+      search = ?config.search,
+      ?backend,
+      "resolvers installed"
+    );
+    Ok(DnsOverride {
+      dev: dev.to_string(),
+      backend,
+    })
+  }
+
+  /// Resolves names through `config` for as long as the override lives.
+  #[cfg(target_os = "macos")]
+  pub fn install(dev: &str, config: &DnsConfig) -> Result<Self> {
+    let servers: Vec<_> = config.servers.iter().map(IpAddr::to_string).collect();
+    let mut saved = vec![];
+    for service in network_services()? {
+      let previous = (
+        networksetup_list(&["-getdnsservers", &service])?,
+        networksetup_list(&["-getsearchdomains", &service])?,
+      );
+      networksetup_set("-setdnsservers", &service, &servers)?;
+      if !config.search.is_empty() {
+        networksetup_set("-setsearchdomains", &service, &config.search)?;
+      }
+      saved.push((service, previous.0, previous.1));
+    }
+    let backend = Backend::Networksetup(saved);
+    info!(
+      interface = dev,
+      servers = ?config.servers,
+      search = ?config.search,
+      ?backend,
+      "resolvers installed"
+    );
+    Ok(DnsOverride {
+      dev: dev.to_string(),
+      backend,
+    })
+  }
+
+  /// Resolves names through `config` for as long as the override lives.
+  #[cfg(windows)]
+  pub fn install(dev: &str, config: &DnsConfig) -> Result<Self> {
+    let name = format!("name={}", dev);
+    for family in ["ipv4", "ipv6"] {
+      let servers = config
+        .servers
+        .iter()
+        .filter(|server| server.is_ipv4() == (family == "ipv4"));
+      for (index, server) in servers.enumerate() {
+        let address = format!("address={}", server);
+        let position = format!("index={}", index + 1);
+        let mut args = vec!["interface", family];
+        if index == 0 {
+          args.extend(["set", "dnsservers", &name, "source=static", &address]);
+          args.push("register=none");
+        } else {
+          args.extend(["add", "dnsservers", &name, &address, &position]);
+        }
+        args.push("validate=no");
+        netsh(&args)?;
+      }
+    }
+    netsh(&["interface", "ipv4", "set", "interface", dev, "metric=1"])?;
+    if let Some(suffix) = config.search.first() {
+      // Windows takes one suffix per adapter; the rest are global settings
+      // that would outlive the tunnel.
+      if config.search.len() > 1 {
+        warn!(search = ?&config.search[1..], "only the first search domain is used");
+      }
+      let script = format!(
+        "Set-DnsClient -InterfaceAlias '{}' -ConnectionSpecificSuffix '{}'",
+        dev.replace('\'', "''"),
+        suffix.replace('\'', "''")
+      );
+      let status = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+        .status()?;
+      if !status.success() {
+        warn!(%suffix, "couldn't set the search domain: {}", status);
+      }
+    }
+    let backend = Backend::Netsh;
+    info!(
+      interface = dev,
+      servers = ?config.servers,
+      search = ?config.search,
+      ?backend,
+      "resolvers installed"
+    );
+    Ok(DnsOverride {
+      dev: dev.to_string(),
+      backend,
+    })
+  }
+
+  /// Resolves names through `config` for as long as the override lives.
+  #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+  pub fn install(dev: &str, config: &DnsConfig) -> Result<Self> {
+    let _ = (dev, config);
+    Err(QvpnError::Unsupported(
+      "cannot configure resolvers on this platform".into(),
+    ))
+  }
+
+  #[cfg(target_os = "linux")]
+  fn restore(&self) -> Result<()> {
+    match &self.backend {
+      Backend::Resolved => run("resolvectl", &["revert", &self.dev], None),
+      Backend::Resolvconf => run("resolvconf", &["-d", &format!("{}.qvpn", self.dev)], None),
+      Backend::File(previous) => write_resolv_conf(previous),
+    }
+  }
+
+  #[cfg(target_os = "macos")]
+  fn restore(&self) -> Result<()> {
+    let Backend::Networksetup(saved) = &self.backend;
+    for (service, servers, search) in saved {
+      networksetup_set("-setdnsservers", service, servers)?;
+      networksetup_set("-setsearchdomains", service, search)?;
+    }
+    Ok(())
+  }
+
+  #[cfg(windows)]
+  fn restore(&self) -> Result<()> {
+    match self.backend {
+      Backend::Netsh => {
+        let name = format!("name={}", self.dev);
+        for family in ["ipv4", "ipv6"] {
+          netsh(&["interface", family, "delete", "dnsservers", &name, "all"])?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+  fn restore(&self) -> Result<()> {
+    Ok(())
+  }
+}
+
+impl Drop for DnsOverride {
+  fn drop(&mut self) {
+    match self.restore() {
+      Ok(()) => info!(interface = %self.dev, "resolvers restored"),
+      Err(err) => warn!(interface = %self.dev, "couldn't restore resolvers: {}", err),
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn resolved_running() -> bool {
+  Path::new("/run/systemd/resolve/io.systemd.Resolve").exists() && which("resolvectl")
+}
+
+#[cfg(target_os = "linux")]
+fn which(program: &str) -> bool {
+  std::env::var_os("PATH")
+    .map(|path| std::env::split_paths(&path).any(|dir| dir.join(program).is_file()))
+    .unwrap_or(false)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn run(program: &str, args: &[&str], stdin: Option<&[u8]>) -> Result<()> {
+  let mut command = Command::new(program);
+  command.args(args);
+  if stdin.is_some() {
+    command.stdin(std::process::Stdio::piped());
+  }
+  let mut child = command.spawn()?;
+  if let (Some(input), Some(mut pipe)) = (stdin, child.stdin.take()) {
+    pipe.write_all(input)?;
+  }
+  let status = child.wait()?;
+  if !status.success() {
+    return Err(QvpnError::Io(io::Error::other(format!(
+      "{} {} failed: {}",
+      program,
+      args.join(" "),
+      status
+    ))));
+  }
+  Ok(())
+}
+
+/// Names of the enabled network services.
+#[cfg(target_os = "macos")]
+fn network_services() -> Result<Vec<String>> {
+  let output = Command::new("networksetup")
+    .arg("-listallnetworkservices")
+    .output()?;
+  let services = String::from_utf8_lossy(&output.stdout);
+  // The first line explains that disabled services are starred.
+  Ok(
+    services
+      .lines()
+      .skip(1)
+      .filter(|line| !line.is_empty() && !line.starts_with('*'))
+      .map(str::to_string)
+      .collect(),
+  )
+}
+
+/// Runs a `networksetup` query that prints one value per line, or a
+/// sentence when there are none.
+#[cfg(target_os = "macos")]
+fn networksetup_list(args: &[&str]) -> Result<Vec<String>> {
+  let output = Command::new("networksetup").args(args).output()?;
+  if !output.status.success() {
+    return Err(QvpnError::Io(io::Error::other(format!(
+      "networksetup {} failed: {}",
+      args.join(" "),
+      output.status
+    ))));
+  }
+  let values = String::from_utf8_lossy(&output.stdout);
+  if values.contains(' ') {
+    return Ok(vec![]);
+  }
+  Ok(values.split_whitespace().map(str::to_string).collect())
+}
+
+/// Sets a list setting of `service`, clearing it if `values` is empty.
+#[cfg(target_os = "macos")]
+fn networksetup_set(flag: &str, service: &str, values: &[String]) -> Result<()> {
+  let mut args = vec![flag, service];
+  if values.is_empty() {
+    args.push("Empty");
+  }
+  args.extend(values.iter().map(String::as_str));
+  run("networksetup", &args, None)
+}
+
+/// Replaces the contents of `/etc/resolv.conf` in place, following it if
+/// it is a symlink.
+#[cfg(target_os = "linux")]
+fn write_resolv_conf(contents: &[u8]) -> Result<()> {
+  fs::write(RESOLV_CONF, contents)?;
+  Ok(())
+}
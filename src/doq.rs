@@ -0,0 +1,200 @@
+//! DNS over QUIC (RFC 9250).
+//!
+//! In [`Mode::Doq`] the server answers each query, sent on its own stream
+//! with a two byte length prefix, by forwarding it to an upstream resolver
+//! over UDP, or TCP if the UDP answer was truncated. The client runs a stub
+//! resolver that relays local UDP queries over a DoQ connection.
+//!
+//! [`Mode::Doq`]: crate::server::Mode::Doq
+
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::{TcpStream, UdpSocket},
+};
+use tracing::{debug, info, Instrument};
+
+use crate::{QvpnError, Result};
+
+/// ALPN protocol of DNS over QUIC.
+pub const ALPN: &[u8] = b"doq";
+
+/// Port DoQ servers listen on.
+pub const DEFAULT_PORT: u16 = 853;
+
+/// Error code for connections closed after a malformed query.
+pub const PROTOCOL_ERROR: u32 = 0x2;
+
+/// How long a query may take, upstream or over the connection.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest DNS message.
+const MAX_MESSAGE: usize = 65535;
+
+/// Length of a DNS message header.
+const HEADER_LEN: usize = 12;
+
+/// The TC bit, in the third header byte.
+const TRUNCATED: u8 = 0x02;
+
+/// Answers the queries `connection` sends by forwarding them to
+/// `upstream`, until the connection closes.
+pub async fn serve(connection: quinn::Connection, upstream: SocketAddr) -> Result<()> {
+  loop {
+    let (send, recv) = match connection.accept_bi().await {
+      Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+        info!("connection closed");
+        return Ok(());
+      }
+      Err(err) => return Err(err.into()),
+      Ok(stream) => stream,
+    };
+    let connection = connection.clone();
+    tokio::spawn(
+      async move {
+        match answer(send, recv, upstream).await {
+          Ok(()) => {}
+          Err(QvpnError::Protocol(err)) => {
+            debug!("malformed query: {}", err);
+            connection.close(PROTOCOL_ERROR.into(), b"malformed query");
+          }
+          Err(err) => debug!("query failed: {}", err),
+        }
+      }
+      .in_current_span(),
+    );
+  }
+}
+
+async fn answer(
+  mut send: quinn::SendStream,
+  recv: quinn::RecvStream,
+  upstream: SocketAddr,
+) -> Result<()> {
+  let query = read_message(recv).await?;
+  if query[..2] != [0, 0] {
+    return Err(QvpnError::Protocol(
+      "query has a non-zero message ID".into(),
+    ));
+  }
+  let mut response = tokio::time::timeout(QUERY_TIMEOUT, forward(&query, upstream))
+    .await
+    .map_err(|_| QvpnError::Remote(format!("{} didn't answer in time", upstream)))??;
+  response[..2].copy_from_slice(&[0, 0]);
+  send.write_all(&framed(&response)).await?;
+  send.finish()?;
+  Ok(())
+}
+
+/// Sends `query` to `upstream` under a random message ID and returns the
+/// answer, which carries that ID.
+async fn forward(query: &[u8], upstream: SocketAddr) -> Result<Vec<u8>> {
+  let mut id = [0; 2];
+  SystemRandom::new()
+    .fill(&mut id)
+    .map_err(|_| QvpnError::Io(io::Error::other("no randomness for a message ID")))?;
+  let mut query = query.to_vec();
+  query[..2].copy_from_slice(&id);
+
+  let local: SocketAddr = match upstream {
+    SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+    SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+  };
+  let socket = UdpSocket::bind(local).await?;
+  socket.connect(upstream).await?;
+  socket.send(&query).await?;
+  let mut buf = vec![0; MAX_MESSAGE];
+  let response = loop {
+    let len = socket.recv(&mut buf).await?;
+    // Answers to anything else are stale or spoofed.
+    if len >= HEADER_LEN && buf[..2] == id {
+      break &buf[..len];
+    }
+  };
+  if response[2] & TRUNCATED == 0 {
+    return Ok(response.to_vec());
+  }
+  debug!(%upstream, "answer truncated, retrying over tcp");
+  let mut tcp = TcpStream::connect(upstream).await?;
+  tcp.write_all(&framed(&query)).await?;
+  let len = tcp.read_u16().await? as usize;
+  let mut response = vec![0; len];
+  tcp.read_exact(&mut response).await?;
+  Ok(response)
+}
+
+/// Relays the queries received on `socket` over `connection`, until it
+/// fails.
+pub async fn stub(socket: Arc<UdpSocket>, connection: &quinn::Connection) -> Result<()> {
+  let mut buf = vec![0; MAX_MESSAGE];
+  loop {
+    let (len, client) = socket.recv_from(&mut buf).await?;
+    if len < HEADER_LEN {
+      debug!(%client, "ignoring short query");
+      continue;
+    }
+    let mut query = buf[..len].to_vec();
+    let id = [query[0], query[1]];
+    query[..2].copy_from_slice(&[0, 0]);
+    let (send, recv) = connection.open_bi().await?;
+    let socket = socket.clone();
+    tokio::spawn(
+      async move {
+        let exchange = async {
+          let mut response = exchange(send, recv, &query).await?;
+          response[..2].copy_from_slice(&id);
+          socket.send_to(&response, client).await?;
+          Ok::<_, QvpnError>(())
+        };
+        match tokio::time::timeout(QUERY_TIMEOUT, exchange).await {
+          Ok(Ok(())) => {}
+          Ok(Err(err)) => debug!(%client, "query failed: {}", err),
+          Err(_) => debug!(%client, "query timed out"),
+        }
+      }
+      .in_current_span(),
+    );
+  }
+}
+
+async fn exchange(
+  mut send: quinn::SendStream,
+  recv: quinn::RecvStream,
+  query: &[u8],
+) -> Result<Vec<u8>> {
+  send.write_all(&framed(query)).await?;
+  send.finish()?;
+  read_message(recv).await
+}
+
+/// Reads a length-prefixed DNS message, which must end the stream.
+async fn read_message(mut recv: quinn::RecvStream) -> Result<Vec<u8>> {
+  let data = recv
+    .read_to_end(2 + MAX_MESSAGE)
+    .await
+    .map_err(|err| match err {
+      quinn::ReadToEndError::TooLong => QvpnError::Protocol("message too long".into()),
+      quinn::ReadToEndError::Read(err) => QvpnError::Io(err.into()),
+    })?;
+  if data.len() < 2 {
+    return Err(QvpnError::Protocol("stream ended before the length".into()));
+  }
+  let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+  if data.len() != 2 + len || len < HEADER_LEN {
+    return Err(QvpnError::Protocol(format!(
+      "{} byte message in a {} byte stream",
+      len,
+      data.len()
+    )));
+  }
+  Ok(data[2..].to_vec())
+}
+
+fn framed(message: &[u8]) -> Vec<u8> {
+  let mut buf = Vec::with_capacity(2 + message.len());
+  buf.extend_from_slice(&(message.len() as u16).to_be_bytes());
+  buf.extend_from_slice(message);
+  buf
+}
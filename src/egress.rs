@@ -0,0 +1,373 @@
+//! Egress policy for tunnel clients.
+//!
+//! A server can restrict where the packets of each client may go once they
+//! come out of the tunnel. [`Rule`]s match the destination network,
+//! protocol and ports of IPv4 packets, and the first rule that matches a
+//! packet from a client it applies to allows or drops it. Packets no rule
+//! matches are allowed, so a policy letting clients reach only some
+//! networks ends with a rule denying `0.0.0.0/0`. Other than IPv4, clients
+//! any rule applies to may send only ARP, on a TAP tunnel.
+//!
+//! Each rule counts the packets it matched for the server's
+//! [`metrics`](crate::metrics).
+
+use std::{
+  fmt,
+  net::Ipv4Addr,
+  str::FromStr,
+  sync::atomic::{AtomicU64, Ordering},
+};
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::lease::Ipv4Net;
+
+/// What a rule does with the packets it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+  Allow,
+  Deny,
+}
+
+impl FromStr for Action {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "allow" => Ok(Action::Allow),
+      "deny" => Ok(Action::Deny),
+      _ => Err(format!("unknown action `{}`, expected allow or deny", s)),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Action {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Action {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Action::Allow => "allow",
+      Action::Deny => "deny",
+    })
+  }
+}
+
+/// IP protocol a rule matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+  Tcp,
+  Udp,
+  Icmp,
+}
+
+impl Protocol {
+  fn number(self) -> u8 {
+    match self {
+      Protocol::Tcp => 6,
+      Protocol::Udp => 17,
+      Protocol::Icmp => 1,
+    }
+  }
+}
+
+impl FromStr for Protocol {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "tcp" => Ok(Protocol::Tcp),
+      "udp" => Ok(Protocol::Udp),
+      "icmp" => Ok(Protocol::Icmp),
+      _ => Err(format!(
+        "unknown protocol `{}`, expected tcp, udp or icmp",
+        s
+      )),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Protocol {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Protocol {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Protocol::Tcp => "tcp",
+      Protocol::Udp => "udp",
+      Protocol::Icmp => "icmp",
+    })
+  }
+}
+
+/// Destination ports a rule matches, from `start` to `end` inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ports {
+  pub start: u16,
+  pub end: u16,
+}
+
+impl FromStr for Ports {
+  type Err = String;
+
+  /// Parses `PORT` or `START-END`.
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    match (start.parse(), end.parse()) {
+      (Ok(start), Ok(end)) if start <= end => Ok(Ports { start, end }),
+      _ => Err(format!("invalid ports `{}`, expected PORT or START-END", s)),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Ports {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Ports {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.start == self.end {
+      true => write!(f, "{}", self.start),
+      false => write!(f, "{}-{}", self.start, self.end),
+    }
+  }
+}
+
+/// Allows or denies packets to a network.
+#[derive(Debug, Clone)]
+pub struct Rule {
+  action: Action,
+  network: Ipv4Net,
+  protocol: Option<Protocol>,
+  ports: Option<Ports>,
+  users: Vec<String>,
+}
+
+impl Rule {
+  /// Does `action` with packets to `network` from every client.
+  pub fn new(action: Action, network: Ipv4Net) -> Self {
+    Rule {
+      action,
+      network,
+      protocol: None,
+      ports: None,
+      users: vec![],
+    }
+  }
+
+  /// Matches only packets of `protocol`; any if `None`.
+  pub fn protocol(mut self, protocol: Option<Protocol>) -> Self {
+    self.protocol = protocol;
+    self
+  }
+
+  /// Matches only TCP and UDP packets to `ports`; any if `None`.
+  pub fn ports(mut self, ports: Option<Ports>) -> Self {
+    self.ports = ports;
+    self
+  }
+
+  /// Applies only to these logged in users; to every client if empty.
+  pub fn users(mut self, users: Vec<String>) -> Self {
+    self.users = users;
+    self
+  }
+
+  fn applies(&self, user: Option<&str>) -> bool {
+    let users = &self.users;
+    users.is_empty() || user.is_some_and(|user| users.iter().any(|x| x == user))
+  }
+
+  fn matches(&self, packet: &Ipv4) -> bool {
+    self.network.contains(packet.destination)
+      && self
+        .protocol
+        .is_none_or(|protocol| protocol.number() == packet.protocol)
+      && self.ports.is_none_or(|ports| match packet.port {
+        Some(port) => (ports.start..=ports.end).contains(&port),
+        // The rest of a fragmented packet goes where its first fragment
+        // went, so it is up to that one. A header cut too short to have a
+        // port might be going anywhere, so deny rules match it.
+        None => {
+          matches!(packet.protocol, 6 | 17) && (packet.fragment || self.action == Action::Deny)
+        }
+      })
+  }
+}
+
+impl fmt::Display for Rule {
+  /// Formats `ACTION:NETWORK[:PROTOCOL[:PORTS]]`, with `any` for a rule with
+  /// ports and no protocol.
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.action, self.network)?;
+    match (self.protocol, self.ports) {
+      (Some(protocol), Some(ports)) => write!(f, ":{}:{}", protocol, ports),
+      (Some(protocol), None) => write!(f, ":{}", protocol),
+      (None, Some(ports)) => write!(f, ":any:{}", ports),
+      (None, None) => Ok(()),
+    }
+  }
+}
+
+/// The headers of an IPv4 packet rules look at.
+struct Ipv4 {
+  destination: Ipv4Addr,
+  protocol: u8,
+  /// Destination port of a TCP or UDP packet, unless it is a fragment
+  /// without the header or the header is cut short.
+  port: Option<u16>,
+  /// Whether it is a fragment other than the first.
+  fragment: bool,
+}
+
+impl Ipv4 {
+  fn parse(packet: &[u8]) -> Option<Self> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+      return None;
+    }
+    let header_len = usize::from(packet[0] & 0xf) * 4;
+    if header_len < 20 {
+      return None;
+    }
+    let protocol = packet[9];
+    let fragment = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff != 0;
+    let port = match protocol {
+      6 | 17 if !fragment => packet
+        .get(header_len + 2..header_len + 4)
+        .map(|x| u16::from_be_bytes([x[0], x[1]])),
+      _ => None,
+    };
+    Some(Ipv4 {
+      destination: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+      protocol,
+      port,
+      fragment,
+    })
+  }
+}
+
+/// The egress rules of a server, in order, with the packets each matched.
+#[derive(Debug, Default)]
+pub struct Policy {
+  rules: Vec<Rule>,
+  hits: Vec<AtomicU64>,
+}
+
+impl Policy {
+  /// Checks packets against `rules`, first match first.
+  pub fn new(rules: Vec<Rule>) -> Self {
+    let hits = rules.iter().map(|_| AtomicU64::new(0)).collect();
+    Policy { rules, hits }
+  }
+
+  /// Whether there are no rules, and every packet is allowed.
+  pub fn is_empty(&self) -> bool {
+    self.rules.is_empty()
+  }
+
+  /// Whether any rule applies to `user`, if logged in.
+  pub fn applies(&self, user: Option<&str>) -> bool {
+    self.rules.iter().any(|rule| rule.applies(user))
+  }
+
+  /// Whether `user`, if logged in, may send the IP `packet` out of the
+  /// tunnel, counting the hit on the rule that decided.
+  pub fn allows(&self, packet: &[u8], user: Option<&str>) -> bool {
+    let packet = match Ipv4::parse(packet) {
+      Some(packet) => packet,
+      None => return !self.applies(user),
+    };
+    let hit = self
+      .rules
+      .iter()
+      .zip(&self.hits)
+      .find(|(rule, _)| rule.applies(user) && rule.matches(&packet));
+    match hit {
+      Some((rule, hits)) => {
+        hits.fetch_add(1, Ordering::Relaxed);
+        rule.action == Action::Allow
+      }
+      None => true,
+    }
+  }
+
+  /// Each rule with the packets it matched so far.
+  pub fn hits(&self) -> Vec<(&Rule, u64)> {
+    self
+      .rules
+      .iter()
+      .zip(&self.hits)
+      .map(|(rule, hits)| (rule, hits.load(Ordering::Relaxed)))
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A TCP packet to `destination:port`, cut after `len` bytes.
+  fn tcp(destination: [u8; 4], port: u16, len: usize) -> Vec<u8> {
+    let mut packet = vec![0x45, 0, 0, 40, 0, 0, 0, 0, 64, 6, 0, 0, 10, 0, 0, 2];
+    packet.extend_from_slice(&destination);
+    packet.extend_from_slice(&[0, 80]);
+    packet.extend_from_slice(&port.to_be_bytes());
+    packet.resize(40, 0);
+    packet.truncate(len);
+    packet
+  }
+
+  fn policy(rules: &[&str]) -> Policy {
+    let rules = rules
+      .iter()
+      .map(|rule| {
+        let mut parts = rule.split(':');
+        let action = parts.next().unwrap().parse().unwrap();
+        let network = parts.next().unwrap().parse().unwrap();
+        Rule::new(action, network)
+          .protocol(parts.next().map(|x| x.parse().unwrap()))
+          .ports(parts.next().map(|x| x.parse().unwrap()))
+      })
+      .collect();
+    Policy::new(rules)
+  }
+
+  #[test]
+  fn port_rules_match_ports() {
+    let policy = policy(&["deny:10.1.0.0/16:tcp:22", "allow:10.1.0.0/16:tcp:443"]);
+    assert!(!policy.allows(&tcp([10, 1, 0, 1], 22, 40), None));
+    assert!(policy.allows(&tcp([10, 1, 0, 1], 443, 40), None));
+    assert!(policy.allows(&tcp([10, 2, 0, 1], 22, 40), None));
+  }
+
+  #[test]
+  fn deny_rules_match_cut_short_headers() {
+    let policy = policy(&["deny:10.1.0.0/16:tcp:22"]);
+    assert!(!policy.allows(&tcp([10, 1, 0, 1], 22, 21), None));
+
+    let policy = self::policy(&["allow:10.1.0.0/16:tcp:443", "deny:0.0.0.0/0"]);
+    assert!(!policy.allows(&tcp([10, 1, 0, 1], 443, 21), None));
+  }
+
+  #[test]
+  fn headers_shorter_than_20_bytes_are_dropped() {
+    let policy = policy(&["deny:10.1.0.0/16:tcp:22"]);
+    let mut packet = tcp([10, 1, 0, 1], 22, 40);
+    packet[0] = 0x41;
+    assert!(!policy.allows(&packet, None));
+  }
+}
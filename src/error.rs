@@ -0,0 +1,120 @@
+//! Error type shared by the whole crate.
+
+use std::{io, net::SocketAddr, path::PathBuf};
+
+use thiserror::Error;
+
+/// Errors returned by qvpn.
+#[derive(Debug, Error)]
+pub enum QvpnError {
+  /// A local I/O operation failed.
+  #[error("io error: {0}")]
+  Io(#[from] io::Error),
+  /// A transport setting was out of range.
+  #[error("invalid transport config: {0}")]
+  TransportConfig(#[from] quinn::VarIntBoundsExceeded),
+  /// A connection could not be started.
+  #[error("failed to connect: {0}")]
+  Connect(#[from] quinn::ConnectError),
+  /// An established connection failed.
+  #[error("connection lost: {0}")]
+  Connection(#[from] quinn::ConnectionError),
+  /// Writing to a stream failed.
+  #[error("failed to write to stream: {0}")]
+  Write(#[from] quinn::WriteError),
+  /// A stream was used after it was finished or reset.
+  #[error("stream closed: {0}")]
+  ClosedStream(#[from] quinn::ClosedStream),
+  /// Reading a stream to its end failed.
+  #[error("failed to read from stream: {0}")]
+  Read(#[from] quinn::ReadToEndError),
+  /// A datagram could not be sent.
+  #[error("failed to send datagram: {0}")]
+  SendDatagram(#[from] quinn::SendDatagramError),
+  /// An HTTP/3 connection failed.
+  #[error("http/3 connection error: {0}")]
+  H3Connection(#[from] h3::error::ConnectionError),
+  /// An HTTP/3 request stream failed.
+  #[error("http/3 stream error: {0}")]
+  H3Stream(#[from] h3::error::StreamError),
+  /// A self-signed certificate could not be generated.
+  #[error("failed to generate certificate: {0}")]
+  CertificateGeneration(#[from] rcgen::Error),
+  /// The TLS configuration was rejected.
+  #[error("tls error: {0}")]
+  Tls(#[from] rustls::Error),
+  /// The TLS configuration offers no cipher suite QUIC can start with.
+  #[error("tls error: {0}")]
+  CipherSuite(#[from] quinn::crypto::rustls::NoInitialCipherSuite),
+  /// A request from the remote side was malformed.
+  #[error("bad request: {0}")]
+  BadRequest(String),
+  /// The server reported an error.
+  #[error("server error: {0}")]
+  Remote(String),
+  /// The server could not connect to a proxy target.
+  #[error("failed to connect to {0}: {1}")]
+  Dial(String, #[source] io::Error),
+  /// A requested file does not exist.
+  #[error("not found: {}", .0.display())]
+  NotFound(PathBuf),
+  /// A requested byte range lies outside a file of the given length.
+  #[error("range not satisfiable for {0} byte file")]
+  RangeNotSatisfiable(u64),
+  /// The remote side violated the protocol.
+  #[error("protocol error: {0}")]
+  Protocol(String),
+  /// A local setting or argument was invalid.
+  #[error("invalid input: {0}")]
+  InvalidInput(String),
+  /// The operation is not supported on this platform or configuration.
+  #[error("unsupported: {0}")]
+  Unsupported(String),
+  /// The remote side did not prove its identity.
+  #[error("unauthenticated: {0}")]
+  Unauthenticated(String),
+  /// A name or resource is already taken.
+  #[error("conflict: {0}")]
+  Conflict(String),
+  /// Client and server share no protocol or protocol version.
+  #[error("incompatible peer: {0}")]
+  Incompatible(String),
+  /// A download didn't match the digest the server announced.
+  #[error("integrity check failed: {0}")]
+  Integrity(String),
+  /// The operation did not complete in time.
+  #[error("timed out: {0}")]
+  Timeout(&'static str),
+  /// A peer's send queue or outbox was full and refused the message.
+  #[error("message queue for {0} is full")]
+  QueueFull(SocketAddr),
+  /// The peer was dropped before the message could be queued.
+  #[error("peer {0} disconnected")]
+  Disconnected(SocketAddr),
+}
+
+impl QvpnError {
+  /// Whether the error was caused by losing the connection, so that
+  /// reconnecting and trying again may succeed.
+  pub fn is_transient(&self) -> bool {
+    fn lost(err: &quinn::ConnectionError) -> bool {
+      matches!(
+        err,
+        quinn::ConnectionError::Reset | quinn::ConnectionError::TimedOut
+      )
+    }
+    match self {
+      QvpnError::Connection(err) => lost(err),
+      QvpnError::Write(quinn::WriteError::ConnectionLost(err)) => lost(err),
+      QvpnError::Read(quinn::ReadToEndError::Read(quinn::ReadError::ConnectionLost(err))) => {
+        lost(err)
+      }
+      QvpnError::Io(err) => matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::TimedOut
+      ),
+      QvpnError::Timeout(_) => true,
+      _ => false,
+    }
+  }
+}
@@ -0,0 +1,256 @@
+//! Sharing local services through the server, like ngrok.
+//!
+//! A client registers a name with `EXPOSE <name>\r\n` on a stream of its
+//! own. The server answers `200 OK` with a token in an [`TOKEN_HEADER`]
+//! header and keeps the name for the client until the stream ends. Sending
+//! the token after the name, as `EXPOSE <name> <token>\r\n`, takes the name
+//! over, so a client that reconnects gets it back before the server notices
+//! the old connection is gone.
+//!
+//! The server accepts HTTP connections on its expose address and routes each
+//! by the first label of its `Host` header, so `demo.share.example.com`
+//! reaches the client that registered `demo`. It carries the connection
+//! back as a reverse [`forward`] does, and the client connects it to its
+//! local service.
+//!
+//! [`forward`]: crate::forward
+
+use std::{
+  collections::HashMap,
+  fmt::Write,
+  net::SocketAddr,
+  str,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::{
+  io::AsyncWriteExt,
+  net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+  forward,
+  http::{self, ResponseHead},
+  QvpnError, Result,
+};
+
+/// Start of a request line registering a name.
+pub const REQUEST_PREFIX: &[u8] = b"EXPOSE ";
+
+/// Header of the `200 OK` response that carries the token for taking the
+/// name over.
+pub const TOKEN_HEADER: &str = "Qvpn-Expose-Token";
+
+/// How long a visitor gets to send its request head.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether `name` can be registered: 1 to 63 lowercase letters, digits
+/// and dashes, like a DNS label.
+pub fn valid_name(name: &str) -> bool {
+  (1..=63).contains(&name.len())
+    && name
+      .bytes()
+      .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'-')
+}
+
+struct Exposed {
+  connection: quinn::Connection,
+  token: String,
+}
+
+/// The names clients registered with a server. Cloning it shares the same
+/// names.
+#[derive(Clone, Default)]
+pub struct Registry {
+  names: Arc<Mutex<HashMap<String, Exposed>>>,
+}
+
+impl Registry {
+  /// The registered names.
+  pub fn names(&self) -> Vec<String> {
+    self.names.lock().unwrap().keys().cloned().collect()
+  }
+
+  fn connection(&self, name: &str) -> Option<quinn::Connection> {
+    let names = self.names.lock().unwrap();
+    names.get(name).map(|x| x.connection.clone())
+  }
+
+  /// Registers `name` for `connection`, taking it over if `token` is the
+  /// one it was registered with, and returns the token for it.
+  fn register(
+    &self,
+    name: &str,
+    token: Option<&str>,
+    connection: &quinn::Connection,
+  ) -> Result<String> {
+    let mut names = self.names.lock().unwrap();
+    if let Some(exposed) = names.get(name) {
+      let taken_over = token == Some(exposed.token.as_str());
+      if !taken_over && exposed.connection.close_reason().is_none() {
+        return Err(QvpnError::Conflict(format!("{} is taken", name)));
+      }
+    }
+    let token = match token {
+      Some(token) => token.to_string(),
+      None => new_token()?,
+    };
+    names.insert(
+      name.to_string(),
+      Exposed {
+        connection: connection.clone(),
+        token: token.clone(),
+      },
+    );
+    Ok(token)
+  }
+
+  /// Forgets `name` unless another connection took it over.
+  fn remove(&self, name: &str, connection: &quinn::Connection) {
+    let mut names = self.names.lock().unwrap();
+    if names
+      .get(name)
+      .is_some_and(|x| x.connection.stable_id() == connection.stable_id())
+    {
+      names.remove(name);
+    }
+  }
+}
+
+fn new_token() -> Result<String> {
+  let mut bytes = [0; 16];
+  SystemRandom::new()
+    .fill(&mut bytes)
+    .map_err(|_| QvpnError::InvalidInput("no randomness for the expose token".into()))?;
+  let mut token = String::new();
+  for byte in bytes {
+    write!(token, "{:02x}", byte).expect("writing to a string");
+  }
+  Ok(token)
+}
+
+/// Formats a request registering `name`, taking it over with `token`.
+pub fn request(name: &str, token: Option<&str>) -> String {
+  match token {
+    Some(token) => format!("EXPOSE {} {}\r\n", name, token),
+    None => format!("EXPOSE {}\r\n", name),
+  }
+}
+
+/// Serves the `EXPOSE` request `line` of the client of `connection`:
+/// registers the name and keeps it until the client ends the stream.
+pub async fn serve_register(
+  registry: &Registry,
+  line: &[u8],
+  connection: &quinn::Connection,
+  send: &mut quinn::SendStream,
+  mut recv: quinn::RecvStream,
+) -> Result<()> {
+  let mut words = line
+    .strip_prefix(REQUEST_PREFIX)
+    .and_then(|x| str::from_utf8(x).ok())
+    .unwrap_or_default()
+    .split_whitespace();
+  let name = words.next().unwrap_or_default();
+  if !valid_name(name) {
+    return Err(QvpnError::BadRequest(format!(
+      "`{}` is not a valid name",
+      name
+    )));
+  }
+  let token = registry.register(name, words.next(), connection)?;
+  let head = ResponseHead::new(200, "OK").header(TOKEN_HEADER, token);
+  let result = send.write_all(head.encode().as_bytes()).await;
+  if result.is_ok() {
+    info!(name, "service exposed");
+    // The client sends nothing more on the stream; it ending, or the
+    // connection closing, ends the registration.
+    if let Err(err) = recv.read_to_end(0).await {
+      debug!(name, "expose stream failed: {}", err);
+    }
+    info!(name, "service no longer exposed");
+  }
+  registry.remove(name, connection);
+  result.map_err(Into::into)
+}
+
+/// Registers `name` with the server of `connection`, taking it over with
+/// the token in `token` if there is one, and connects the visitors the
+/// server carries back to `target` until the connection fails. `token` is
+/// left holding the token the server handed out, for the next connection.
+pub async fn share(
+  connection: &quinn::Connection,
+  name: &str,
+  target: &str,
+  token: &Mutex<Option<String>>,
+) -> Result<()> {
+  let (mut send, mut recv) = connection.open_bi().await?;
+  let line = request(name, token.lock().unwrap().as_deref());
+  send.write_all(line.as_bytes()).await?;
+  let (head, _) = http::read_until(&mut recv, b"\r\n\r\n").await?;
+  let head = ResponseHead::decode(&head)?;
+  if !head.is_success() {
+    return Err(QvpnError::Remote(format!(
+      "exposing {} refused: {}",
+      name, head
+    )));
+  }
+  *token.lock().unwrap() = head.get(TOKEN_HEADER).map(str::to_string);
+  info!(name, target, "service exposed");
+  // The stream is kept open for as long as the name should last.
+  let _stream = (send, recv);
+  forward::accept_forwarded(connection, &[(name.to_string(), target.to_string())]).await
+}
+
+/// Accepts HTTP connections on `listener` and carries each to the client
+/// that registered the name its `Host` header asks for.
+pub async fn serve(listener: TcpListener, registry: Registry) -> Result<()> {
+  loop {
+    let (tcp, peer) = listener.accept().await?;
+    let registry = registry.clone();
+    tokio::spawn(async move {
+      if let Err(err) = route(tcp, peer, &registry).await {
+        warn!(%peer, "exposed connection failed: {}", err);
+      }
+    });
+  }
+}
+
+async fn route(mut tcp: TcpStream, peer: SocketAddr, registry: &Registry) -> Result<()> {
+  let (mut head, rest) =
+    tokio::time::timeout(HEAD_TIMEOUT, http::read_until(&mut tcp, b"\r\n\r\n"))
+      .await
+      .map_err(|_| QvpnError::Timeout("reading the request head"))??;
+  let name = host(&head)
+    .and_then(|host| host.split(['.', ':']).next())
+    .map(str::to_ascii_lowercase);
+  let connection = name.as_deref().and_then(|name| registry.connection(name));
+  let (name, connection) = match (name, connection) {
+    (Some(name), Some(connection)) => (name, connection),
+    (name, _) => {
+      let body = format!("no service named {}\n", name.as_deref().unwrap_or("?"));
+      let response = format!(
+        "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+      );
+      tcp.write_all(response.as_bytes()).await?;
+      return Ok(());
+    }
+  };
+  debug!(%peer, name, "exposed connection");
+  head.extend(rest);
+  forward::forward_back(tcp, peer, &name, &head, &connection).await
+}
+
+/// The `Host` header of a request head.
+fn host(head: &[u8]) -> Option<&str> {
+  let head = str::from_utf8(head).ok()?;
+  head.split("\r\n").skip(1).find_map(|line| {
+    let (name, value) = line.split_once(':')?;
+    name.eq_ignore_ascii_case("host").then(|| value.trim())
+  })
+}
@@ -0,0 +1,115 @@
+//! In-memory cache of popular files on the server.
+//!
+//! With a cache size set, the server keeps the contents of the files it
+//! serves in memory, dropping the least recently used ones once they don't
+//! fit, so a file requested over and over isn't read from disk for every
+//! stream. Entries are keyed by path and checked against the file's size
+//! and modification time, so changed files are read again. Files bigger
+//! than an eighth of the cache are always read from disk.
+
+use std::{
+  collections::{BTreeMap, HashMap},
+  path::{Path, PathBuf},
+  sync::Mutex,
+  time::SystemTime,
+};
+
+use bytes::Bytes;
+
+/// The contents of files, up to a total size.
+#[derive(Debug)]
+pub struct FileCache {
+  capacity: u64,
+  inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+  entries: HashMap<PathBuf, Entry>,
+  /// Paths by the tick they were last used at, oldest first.
+  recent: BTreeMap<u64, PathBuf>,
+  size: u64,
+  tick: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+  modified: Option<SystemTime>,
+  data: Bytes,
+  used: u64,
+}
+
+impl FileCache {
+  /// Keeps up to `capacity` bytes of file contents.
+  pub fn new(capacity: u64) -> Self {
+    FileCache {
+      capacity,
+      inner: Mutex::default(),
+    }
+  }
+
+  /// Whether a file of `len` bytes is small enough to cache.
+  pub fn admits(&self, len: u64) -> bool {
+    len <= self.capacity / 8
+  }
+
+  /// Bytes of file contents held.
+  pub fn size(&self) -> u64 {
+    self.inner.lock().unwrap().size
+  }
+
+  /// The contents of the file at `path`, if cached for the version of
+  /// `len` bytes modified at `modified`.
+  pub fn get(&self, path: &Path, len: u64, modified: Option<SystemTime>) -> Option<Bytes> {
+    let mut inner = self.inner.lock().unwrap();
+    let inner = &mut *inner;
+    let entry = inner.entries.get_mut(path)?;
+    if entry.data.len() as u64 != len || entry.modified != modified {
+      return None;
+    }
+    inner.tick += 1;
+    inner.recent.remove(&entry.used);
+    entry.used = inner.tick;
+    inner.recent.insert(entry.used, path.to_path_buf());
+    Some(entry.data.clone())
+  }
+
+  /// Caches `data` as the contents of the file at `path` modified at
+  /// `modified`, dropping the least recently used files to make room.
+  pub fn insert(&self, path: &Path, modified: Option<SystemTime>, data: Bytes) {
+    let len = data.len() as u64;
+    if !self.admits(len) {
+      return;
+    }
+    let mut inner = self.inner.lock().unwrap();
+    inner.remove(path);
+    while inner.size + len > self.capacity {
+      let oldest = match inner.recent.values().next() {
+        Some(path) => path.clone(),
+        None => break,
+      };
+      inner.remove(&oldest);
+    }
+    inner.tick += 1;
+    let used = inner.tick;
+    inner.recent.insert(used, path.to_path_buf());
+    inner.size += len;
+    inner.entries.insert(
+      path.to_path_buf(),
+      Entry {
+        modified,
+        data,
+        used,
+      },
+    );
+  }
+}
+
+impl Inner {
+  fn remove(&mut self, path: &Path) {
+    if let Some(entry) = self.entries.remove(path) {
+      self.recent.remove(&entry.used);
+      self.size -= entry.data.len() as u64;
+    }
+  }
+}
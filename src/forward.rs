@@ -0,0 +1,387 @@
+//! TCP port forwarding, like `ssh -L` and `ssh -R`.
+//!
+//! A [`Local`] forward listens on the client and carries each connection it
+//! accepts to its target on a `CONNECT` stream, as the [`proxy`] does. The
+//! server allows it in connect-proxy mode, or when a [`Target`] rule names
+//! the target.
+//!
+//! A [`Reverse`] forward asks the server for a listener with
+//! `LISTEN <port>\r\n` on a stream of its own. If a [`Listen`] rule allows
+//! the port, the server binds the address the rule gives, answers `200 OK`
+//! and keeps listening until the stream ends. It carries each connection it
+//! accepts back on a stream it opens, starting `FORWARDED <port>
+//! <peer>\r\n`, and the client connects it to the local service.
+//!
+//! Rules can be limited to some users, who must then log in.
+//!
+//! [`proxy`]: crate::proxy
+
+use std::{
+  fmt,
+  net::{Ipv4Addr, SocketAddr},
+  str::{self, FromStr},
+};
+
+use serde::{de, Deserialize, Deserializer};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::{
+  http::{self, ResponseHead},
+  proxy, QvpnError, Result,
+};
+
+/// Start of a request line asking for a reverse forward.
+pub const LISTEN_PREFIX: &[u8] = b"LISTEN ";
+
+/// Start of the line of a stream the server opens for a forwarded
+/// connection.
+const FORWARDED_PREFIX: &str = "FORWARDED ";
+
+/// A port on the client forwarded to a target the server connects to,
+/// parsed from `[addr:]port=host:port`. The address defaults to
+/// `127.0.0.1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Local {
+  /// Address connections are accepted on.
+  pub listen: SocketAddr,
+  /// `host:port` the server connects them to.
+  pub target: String,
+}
+
+impl FromStr for Local {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let (listen, target) = s
+      .split_once('=')
+      .ok_or_else(|| format!("expected [addr:]port=host:port, got `{}`", s))?;
+    Ok(Local {
+      listen: match listen.parse::<u16>() {
+        Ok(port) => (Ipv4Addr::LOCALHOST, port).into(),
+        Err(_) => listen
+          .parse()
+          .map_err(|err| format!("bad listen address `{}`: {}", listen, err))?,
+      },
+      target: host_port(target)?,
+    })
+  }
+}
+
+/// A port on the server forwarded to a service the client connects to,
+/// parsed from `port=[host:]port`. The host defaults to `127.0.0.1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reverse {
+  /// Server port, which a [`Listen`] rule must allow.
+  pub port: u16,
+  /// `host:port` of the service the client connects to.
+  pub target: String,
+}
+
+impl FromStr for Reverse {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let (port, target) = s
+      .split_once('=')
+      .ok_or_else(|| format!("expected port=[host:]port, got `{}`", s))?;
+    Ok(Reverse {
+      port: port
+        .parse()
+        .map_err(|err| format!("bad server port `{}`: {}", port, err))?,
+      target: match target.parse::<u16>() {
+        Ok(port) => format!("127.0.0.1:{}", port),
+        Err(_) => host_port(target)?,
+      },
+    })
+  }
+}
+
+/// Checks that `s` looks like `host:port`.
+fn host_port(s: &str) -> std::result::Result<String, String> {
+  match s.rsplit_once(':') {
+    Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(s.to_string()),
+    _ => Err(format!("expected host:port, got `{}`", s)),
+  }
+}
+
+impl<'de> Deserialize<'de> for Local {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl<'de> Deserialize<'de> for Reverse {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Local {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}={}", self.listen, self.target)
+  }
+}
+
+impl fmt::Display for Reverse {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}={}", self.port, self.target)
+  }
+}
+
+/// A server rule letting clients forward connections to `host:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+  target: String,
+  users: Vec<String>,
+}
+
+impl Target {
+  /// Allows forwarding to `target`, given exactly as clients ask for it.
+  pub fn new(target: impl Into<String>) -> Self {
+    Target {
+      target: target.into(),
+      users: vec![],
+    }
+  }
+
+  /// Allow only these logged in users. Any client that may use the proxy
+  /// may forward when this is empty.
+  pub fn users(mut self, users: Vec<String>) -> Self {
+    self.users = users;
+    self
+  }
+}
+
+/// A server rule letting clients have the server listen on `listen` and
+/// forward the connections to them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listen {
+  listen: SocketAddr,
+  users: Vec<String>,
+}
+
+impl Listen {
+  /// Allows listening on `listen`, which clients ask for by its port.
+  pub fn new(listen: SocketAddr) -> Self {
+    Listen {
+      listen,
+      users: vec![],
+    }
+  }
+
+  /// Allow only these logged in users. Any client that may use the proxy
+  /// may forward when this is empty.
+  pub fn users(mut self, users: Vec<String>) -> Self {
+    self.users = users;
+    self
+  }
+}
+
+fn allowed(users: &[String], user: Option<&str>) -> bool {
+  users.is_empty() || user.is_some_and(|user| users.iter().any(|x| x == user))
+}
+
+/// The forwarding rules of a server.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+  pub(crate) targets: Vec<Target>,
+  pub(crate) listens: Vec<Listen>,
+}
+
+impl Acl {
+  /// Whether `user`, if logged in, may forward connections to `target`.
+  pub fn allows(&self, target: &str, user: Option<&str>) -> bool {
+    self
+      .targets
+      .iter()
+      .any(|rule| rule.target == target && allowed(&rule.users, user))
+  }
+
+  /// The address `user`, if logged in, may have the server listen on for
+  /// `port`.
+  pub fn listen_addr(&self, port: u16, user: Option<&str>) -> Option<SocketAddr> {
+    self
+      .listens
+      .iter()
+      .find(|rule| rule.listen.port() == port && allowed(&rule.users, user))
+      .map(|rule| rule.listen)
+  }
+
+  /// Whether there are no rules.
+  pub fn is_empty(&self) -> bool {
+    self.targets.is_empty() && self.listens.is_empty()
+  }
+}
+
+/// Extracts the port from a `LISTEN` request line.
+fn listen_port(line: &[u8]) -> Result<u16> {
+  line
+    .strip_prefix(LISTEN_PREFIX)
+    .and_then(|x| str::from_utf8(x).ok())
+    .and_then(|x| x.trim_end().parse().ok())
+    .ok_or_else(|| QvpnError::BadRequest("malformed LISTEN request".into()))
+}
+
+/// Serves the `LISTEN` request `line` of `user`, if logged in, on the
+/// server: listens on the address its rule gives and forwards connections
+/// to the client of `connection`, until the client ends the stream.
+pub async fn serve_listen(
+  acl: &Acl,
+  user: Option<&str>,
+  line: &[u8],
+  connection: &quinn::Connection,
+  send: &mut quinn::SendStream,
+  mut recv: quinn::RecvStream,
+) -> Result<()> {
+  let port = listen_port(line)?;
+  let addr = acl.listen_addr(port, user).ok_or_else(|| {
+    QvpnError::Unauthenticated(format!("no reverse forward of port {} allowed", port))
+  })?;
+  let listener = TcpListener::bind(addr)
+    .await
+    .map_err(|err| QvpnError::Dial(addr.to_string(), err))?;
+  send
+    .write_all(ResponseHead::new(200, "OK").encode().as_bytes())
+    .await?;
+  info!(listen = %addr, "reverse forward up");
+  loop {
+    tokio::select! {
+      accepted = listener.accept() => {
+        let (tcp, peer) = accepted?;
+        let connection = connection.clone();
+        tokio::spawn(async move {
+          let key = port.to_string();
+          if let Err(err) = forward_back(tcp, peer, &key, &[], &connection).await {
+            warn!(%peer, port, "reverse forwarded connection failed: {}", err);
+          }
+        });
+      }
+      // The client sends nothing more on the stream; it ending, or the
+      // connection closing, ends the forward.
+      res = recv.read_to_end(0) => {
+        if let Err(err) = res {
+          debug!(port, "reverse forward stream failed: {}", err);
+        }
+        break;
+      }
+    }
+  }
+  info!(listen = %addr, "reverse forward down");
+  Ok(())
+}
+
+/// Carries `tcp`, accepted from `peer` for the forward `key`, to the
+/// client, after the bytes in `read` already read from it.
+pub(crate) async fn forward_back(
+  tcp: TcpStream,
+  peer: SocketAddr,
+  key: &str,
+  read: &[u8],
+  connection: &quinn::Connection,
+) -> Result<()> {
+  let (mut send, recv) = connection.open_bi().await?;
+  let line = format!("{}{} {}\r\n", FORWARDED_PREFIX, key, peer);
+  send.write_all(line.as_bytes()).await?;
+  send.write_all(read).await?;
+  debug!(%peer, key, "forwarding connection to the client");
+  proxy::splice(tcp, &[], send, recv).await?;
+  Ok(())
+}
+
+/// Accepts connections on the `listeners` of local forwards and carries
+/// each to its target through `connection`, until either fails.
+pub async fn serve_local(
+  listeners: &[(TcpListener, Local)],
+  connection: &quinn::Connection,
+) -> Result<()> {
+  if listeners.is_empty() {
+    return std::future::pending().await;
+  }
+  let accept = listeners.iter().map(|(listener, forward)| async move {
+    loop {
+      let (tcp, peer) = listener.accept().await?;
+      let connection = connection.clone();
+      let target = forward.target.clone();
+      tokio::spawn(async move {
+        let result = async {
+          let (send, recv, rest) = proxy::open(&connection, &target).await?;
+          proxy::splice(tcp, &rest, send, recv).await
+        };
+        if let Err(err) = result.await {
+          warn!(%peer, %target, "forwarded connection failed: {}", err);
+        }
+      });
+    }
+  });
+  futures::future::try_join_all(accept)
+    .await
+    .map(|_: Vec<()>| ())
+}
+
+/// Asks the server of `connection` to listen for the `reverses` forwards,
+/// and connects the connections it forwards back to their local services
+/// until the connection fails.
+pub async fn serve_reverse(connection: &quinn::Connection, reverses: &[Reverse]) -> Result<()> {
+  // The streams are kept open for as long as the forwards should last.
+  let mut streams = vec![];
+  for reverse in reverses {
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let line = format!("LISTEN {}\r\n", reverse.port);
+    send.write_all(line.as_bytes()).await?;
+    let (head, _) = http::read_until(&mut recv, b"\r\n\r\n").await?;
+    let head = ResponseHead::decode(&head)?;
+    if !head.is_success() {
+      return Err(QvpnError::Remote(format!(
+        "reverse forward of port {} refused: {}",
+        reverse.port, head
+      )));
+    }
+    info!(port = reverse.port, target = %reverse.target, "reverse forward up");
+    streams.push((send, recv));
+  }
+  let targets: Vec<_> = reverses
+    .iter()
+    .map(|x| (x.port.to_string(), x.target.clone()))
+    .collect();
+  accept_forwarded(connection, &targets).await
+}
+
+/// Connects the connections the server of `connection` forwards back to
+/// the target paired with their forward's key in `targets`, until the
+/// connection fails.
+pub(crate) async fn accept_forwarded(
+  connection: &quinn::Connection,
+  targets: &[(String, String)],
+) -> Result<()> {
+  loop {
+    let (send, mut recv) = connection.accept_bi().await?;
+    let targets = targets.to_vec();
+    tokio::spawn(async move {
+      let result = async {
+        let (line, rest) = http::read_until(&mut recv, b"\r\n").await?;
+        let (key, peer) = str::from_utf8(&line)
+          .ok()
+          .and_then(|x| x.trim_end().strip_prefix(FORWARDED_PREFIX))
+          .and_then(|x| x.split_once(' '))
+          .ok_or_else(|| QvpnError::Protocol("malformed forwarded connection".into()))?;
+        let (_, target) = targets
+          .iter()
+          .find(|(x, _)| x == key)
+          .ok_or_else(|| QvpnError::Protocol(format!("connection for unknown forward {}", key)))?;
+        debug!(%peer, key, %target, "forwarded connection");
+        let tcp = TcpStream::connect(target)
+          .await
+          .map_err(|err| QvpnError::Dial(target.clone(), err))?;
+        proxy::splice(tcp, &rest, send, recv).await
+      };
+      if let Err(err) = result.await {
+        warn!("forwarded connection failed: {}", err);
+      }
+    });
+  }
+}
@@ -0,0 +1,166 @@
+//! Fragmentation of tunnel packets too large for a single datagram.
+//!
+//! The interface MTU can exceed what fits in a datagram on a path: the
+//! server's interface serves clients on many paths, and path MTU discovery
+//! may lower a path's MTU at any time. Rather than being dropped, such a
+//! packet is [`split`] into [`Kind::Fragment`] frames with the id the packet
+//! would have had, each starting with a [`HEADER_LEN`] byte header: a
+//! big-endian `u32` fragment id shared by the packet's fragments, the
+//! fragment's index and the number of fragments. The receiving side's
+//! [`Reassembler`] puts the packet back together. Fragments of a packet not
+//! complete within [`REASSEMBLY_TIMEOUT`] are dropped, as the whole packet
+//! would have been.
+//!
+//! Peers that predate fragments ignore them, as they would a packet they
+//! can't parse.
+//!
+//! [`Kind::Fragment`]: crate::datagram::Kind::Fragment
+
+use std::{
+  collections::HashMap,
+  convert::TryFrom,
+  sync::atomic::{AtomicU32, AtomicU64, Ordering},
+  time::{Duration, Instant},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::datagram::{Frame, Kind};
+
+/// Length of the header of a fragment's payload.
+pub const HEADER_LEN: usize = 6;
+
+/// How long the fragments of a packet are kept waiting for the rest.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Packets a [`Reassembler`] waits on at once; the oldest is dropped to
+/// make room for another.
+pub const MAX_PENDING: usize = 64;
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+static FRAGMENTED: AtomicU64 = AtomicU64::new(0);
+static FRAGMENTS: AtomicU64 = AtomicU64::new(0);
+static REASSEMBLED: AtomicU64 = AtomicU64::new(0);
+static EXPIRED: AtomicU64 = AtomicU64::new(0);
+
+/// How often packets were fragmented and put back together since start.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+  /// Packets sent in fragments.
+  pub fragmented: u64,
+  /// Fragments those packets were sent in.
+  pub fragments: u64,
+  /// Packets received in fragments and put back together.
+  pub reassembled: u64,
+  /// Packets received in fragments that were dropped incomplete.
+  pub expired: u64,
+}
+
+/// The counters of this process.
+pub fn counters() -> Counters {
+  Counters {
+    fragmented: FRAGMENTED.load(Ordering::Relaxed),
+    fragments: FRAGMENTS.load(Ordering::Relaxed),
+    reassembled: REASSEMBLED.load(Ordering::Relaxed),
+    expired: EXPIRED.load(Ordering::Relaxed),
+  }
+}
+
+/// Splits `frame`, a tunnel packet, into fragments whose payloads are at
+/// most `max` bytes, or returns `None` if that would take more than 255.
+pub fn split(frame: &Frame, max: usize) -> Option<Vec<Frame>> {
+  let chunk = max.checked_sub(HEADER_LEN).filter(|x| *x > 0)?;
+  let chunks = frame.payload.chunks(chunk);
+  let count = u8::try_from(chunks.len()).ok()?;
+  let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+  let fragments: Vec<_> = chunks
+    .enumerate()
+    .map(|(index, data)| {
+      let mut payload = BytesMut::with_capacity(HEADER_LEN + data.len());
+      payload.put_u32(id);
+      payload.put_u8(index as u8);
+      payload.put_u8(count);
+      payload.put_slice(data);
+      Frame {
+        kind: Kind::Fragment,
+        id: frame.id,
+        payload: payload.freeze(),
+      }
+    })
+    .collect();
+  FRAGMENTED.fetch_add(1, Ordering::Relaxed);
+  FRAGMENTS.fetch_add(fragments.len() as u64, Ordering::Relaxed);
+  Some(fragments)
+}
+
+/// Puts fragmented packets received on one connection back together.
+#[derive(Debug, Default)]
+pub struct Reassembler {
+  pending: HashMap<u32, Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+  parts: Vec<Option<Bytes>>,
+  missing: usize,
+  since: Instant,
+}
+
+impl Reassembler {
+  /// Adds the payload of a fragment, returning its packet once every
+  /// fragment of it is in. Malformed fragments are ignored.
+  pub fn push(&mut self, mut payload: Bytes) -> Option<Bytes> {
+    if payload.len() < HEADER_LEN {
+      return None;
+    }
+    let header = payload.split_to(HEADER_LEN);
+    let id = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let (index, count) = (usize::from(header[4]), usize::from(header[5]));
+    if index >= count {
+      return None;
+    }
+    let now = Instant::now();
+    self.expire(now);
+    if !self.pending.contains_key(&id) && self.pending.len() >= MAX_PENDING {
+      let oldest = self
+        .pending
+        .iter()
+        .min_by_key(|(_, pending)| pending.since)
+        .map(|(id, _)| *id);
+      if let Some(oldest) = oldest {
+        self.pending.remove(&oldest);
+        EXPIRED.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+    let pending = self.pending.entry(id).or_insert_with(|| Pending {
+      parts: vec![None; count],
+      missing: count,
+      since: now,
+    });
+    if pending.parts.len() != count {
+      return None;
+    }
+    if pending.parts[index].is_none() {
+      pending.parts[index] = Some(payload);
+      pending.missing -= 1;
+    }
+    if pending.missing > 0 {
+      return None;
+    }
+    let pending = self.pending.remove(&id)?;
+    let mut packet = BytesMut::new();
+    for part in pending.parts.into_iter().flatten() {
+      packet.put(part);
+    }
+    REASSEMBLED.fetch_add(1, Ordering::Relaxed);
+    Some(packet.freeze())
+  }
+
+  fn expire(&mut self, now: Instant) {
+    let before = self.pending.len();
+    self
+      .pending
+      .retain(|_, pending| now.duration_since(pending.since) < REASSEMBLY_TIMEOUT);
+    EXPIRED.fetch_add((before - self.pending.len()) as u64, Ordering::Relaxed);
+  }
+}
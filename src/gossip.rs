@@ -0,0 +1,52 @@
+//! Peer list exchange between peers.
+//!
+//! With gossip enabled a peer sends its peer list to every peer that
+//! connects, and may connect to some of the peers it hears about in turn,
+//! so a mesh assembles itself from a single bootstrap address.
+
+use std::{collections::HashSet, net::SocketAddr};
+
+/// How peer lists are exchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gossip {
+  /// Connect to peers learned from other peers' lists.
+  pub auto_connect: bool,
+  /// Most new peers connected to for each list received.
+  pub fanout: usize,
+}
+
+impl Default for Gossip {
+  fn default() -> Self {
+    Gossip {
+      auto_connect: false,
+      fanout: 3,
+    }
+  }
+}
+
+impl Gossip {
+  /// Picks up to `fanout` peers from `candidates` worth connecting to: not
+  /// ourselves, not already `known`, and not tried before. The picked peers
+  /// are added to `tried`.
+  pub fn select(
+    &self,
+    candidates: &[SocketAddr],
+    ourselves: SocketAddr,
+    known: &[SocketAddr],
+    tried: &mut HashSet<SocketAddr>,
+  ) -> Vec<SocketAddr> {
+    if !self.auto_connect {
+      return vec![];
+    }
+    let mut selected = vec![];
+    for peer in candidates {
+      if selected.len() == self.fanout {
+        break;
+      }
+      if *peer != ourselves && !known.contains(peer) && tried.insert(*peer) {
+        selected.push(*peer);
+      }
+    }
+    selected
+  }
+}
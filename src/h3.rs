@@ -0,0 +1,135 @@
+//! The server side of HTTP/3, enough for `curl --http3` and browsers to
+//! fetch files, on the [`h3`](::h3) crate.
+//!
+//! Requests are read one per stream as clients open them. Request bodies
+//! are left unread, and server push and trailers aren't supported.
+
+use bytes::Bytes;
+
+use crate::{
+  http::{self, ResponseHead},
+  QvpnError, Result,
+};
+
+/// ALPN protocol of HTTP/3.
+pub const ALPN: &[&[u8]] = &[b"h3"];
+
+/// A connection speaking HTTP/3.
+pub struct Connection(::h3::server::Connection<h3_quinn::Connection, Bytes>);
+
+impl Connection {
+  /// Speaks HTTP/3 on `connection`, sending the server's settings.
+  pub async fn accept(connection: quinn::Connection) -> Result<Self> {
+    let connection = ::h3::server::builder()
+      .max_field_section_size(http::MAX_HEAD as u64)
+      .build(h3_quinn::Connection::new(connection))
+      .await?;
+    Ok(Connection(connection))
+  }
+
+  /// Waits for the client to open the next request stream, or `None` once
+  /// it closed the connection.
+  pub async fn next(&mut self) -> Result<Option<Incoming>> {
+    match self.0.accept().await {
+      Ok(incoming) => Ok(incoming.map(Incoming)),
+      Err(err) if err.is_h3_no_error() => Ok(None),
+      Err(err) => Err(err.into()),
+    }
+  }
+}
+
+/// A request stream whose request hasn't been read yet.
+pub struct Incoming(::h3::server::RequestResolver<h3_quinn::Connection, Bytes>);
+
+impl Incoming {
+  /// Reads the request, returning it with the stream to answer it on.
+  pub async fn request(self) -> Result<(Request, Stream)> {
+    let (req, stream) = self.0.resolve_request().await?;
+    Ok((Request::from(req), Stream(stream)))
+  }
+}
+
+/// A request read from a request stream.
+#[derive(Debug, Clone)]
+pub struct Request {
+  /// Value of `:method`.
+  pub method: String,
+  /// Value of `:path`, including any query.
+  pub path: String,
+  /// Regular header fields, with lowercase names.
+  pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+  /// The percent-decoded path without the query.
+  pub fn decoded_path(&self) -> Result<String> {
+    let path = self.path.split('?').next().unwrap_or_default();
+    http::decode_path(path.as_bytes())
+  }
+
+  /// The query of the path, if any.
+  pub fn query(&self) -> Option<&str> {
+    self.path.split_once('?').map(|(_, query)| query)
+  }
+}
+
+impl From<::http::Request<()>> for Request {
+  fn from(req: ::http::Request<()>) -> Self {
+    Request {
+      method: req.method().to_string(),
+      path: req
+        .uri()
+        .path_and_query()
+        .map_or("/", |x| x.as_str())
+        .to_string(),
+      headers: req
+        .headers()
+        .iter()
+        .map(|(name, value)| {
+          let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+          (name.to_string(), value)
+        })
+        .collect(),
+    }
+  }
+}
+
+/// The stream a request came on, to answer it.
+pub struct Stream(::h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>);
+
+impl Stream {
+  /// Sends `head` as a HEADERS frame. The reason phrase isn't sent.
+  pub async fn send_head(&mut self, head: &ResponseHead) -> Result<()> {
+    let mut response = ::http::Response::builder().status(head.status);
+    for (name, value) in &head.headers {
+      response = response.header(name.as_str(), value.as_str());
+    }
+    let response = response
+      .body(())
+      .map_err(|err| QvpnError::InvalidInput(format!("invalid response head: {}", err)))?;
+    self.0.send_response(response).await?;
+    Ok(())
+  }
+
+  /// Sends `data` as a DATA frame.
+  pub async fn send_data(&mut self, data: Bytes) -> Result<()> {
+    self.0.send_data(data).await?;
+    Ok(())
+  }
+
+  /// Ends the response.
+  pub async fn finish(&mut self) -> Result<()> {
+    self.0.finish().await?;
+    Ok(())
+  }
+
+  /// Resets the stream after an internal error.
+  pub fn reset(&mut self) {
+    self.0.stop_stream(::h3::error::Code::H3_INTERNAL_ERROR);
+  }
+
+  /// The stream's id, for logging.
+  pub fn id(&self) -> impl std::fmt::Display {
+    self.0.id()
+  }
+}
@@ -0,0 +1,170 @@
+//! Lifecycle hooks.
+//!
+//! Like OpenVPN's `--up` and `--client-connect`, operators can have a
+//! command run when a tunnel comes up or goes down and, on a server, when a
+//! client connects to or disconnects from the tunnel, to update DNS
+//! records, adjust a firewall or send a notification. Each command runs
+//! through the shell, `sh -c` or `cmd /C` on Windows, in the background,
+//! with `QVPN_EVENT` set to the [`Event`] and more `QVPN_*` environment
+//! variables describing it:
+//!
+//! - `QVPN_INTERFACE`: name of the tunnel interface.
+//! - `QVPN_ADDRESS`: tunnel address of the client, or of the server for the
+//!   server's own `up` and `down`.
+//! - `QVPN_NETMASK`, `QVPN_GATEWAY`, `QVPN_MTU`, `QVPN_DNS` and
+//!   `QVPN_SEARCH`: the rest of the lease, on a client.
+//! - `QVPN_SUBNET`: the subnet clients are leased addresses from, on a
+//!   server.
+//! - `QVPN_ROUTES`: networks routed through the tunnel, separated by
+//!   spaces.
+//! - `QVPN_REMOTE`: address of the server, or of the client on a server.
+//! - `QVPN_USER` and `QVPN_IDENTITY`: user the client logged in as and
+//!   fingerprint of its certificate, if any, on a server.
+//! - `QVPN_BYTES_SENT` and `QVPN_BYTES_RECEIVED`: UDP payload bytes sent to
+//!   and received from the client, for `client-disconnect`.
+//!
+//! A command that fails is logged and otherwise ignored. A server that
+//! dropped root runs them as its unprivileged user, with `CAP_NET_ADMIN` on
+//! Linux; see [`privilege`](crate::privilege).
+
+use std::{
+  fmt,
+  process::{Command, Stdio},
+};
+
+use tracing::{debug, warn};
+
+/// When a hook runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+  /// The tunnel interface came up.
+  Up,
+  /// The tunnel interface went down.
+  Down,
+  /// A client was leased a tunnel address.
+  ClientConnect,
+  /// A client that was leased a tunnel address disconnected.
+  ClientDisconnect,
+}
+
+impl fmt::Display for Event {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Event::Up => "up",
+      Event::Down => "down",
+      Event::ClientConnect => "client-connect",
+      Event::ClientDisconnect => "client-disconnect",
+    })
+  }
+}
+
+/// Environment variables describing an event.
+pub type Env = Vec<(&'static str, String)>;
+
+/// Shell commands run on each [`Event`], if any.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+  /// Run once the tunnel is up.
+  pub up: Option<String>,
+  /// Run once the tunnel is down.
+  pub down: Option<String>,
+  /// Run on a server when a client is leased an address.
+  pub client_connect: Option<String>,
+  /// Run on a server when a client with a lease disconnects.
+  pub client_disconnect: Option<String>,
+}
+
+impl Hooks {
+  /// Whether no event runs a command.
+  pub fn is_empty(&self) -> bool {
+    self.up.is_none()
+      && self.down.is_none()
+      && self.client_connect.is_none()
+      && self.client_disconnect.is_none()
+  }
+
+  /// Runs the command for `event`, if any, with `env`.
+  pub fn run(&self, event: Event, env: &[(&'static str, String)]) {
+    let command = match event {
+      Event::Up => &self.up,
+      Event::Down => &self.down,
+      Event::ClientConnect => &self.client_connect,
+      Event::ClientDisconnect => &self.client_disconnect,
+    };
+    if let Some(command) = command {
+      spawn(command, event, env);
+    }
+  }
+
+  /// Runs the `up` command with `env`, and the `down` one with the same
+  /// once the returned guard is dropped.
+  pub fn up(&self, env: Env) -> Down {
+    self.run(Event::Up, &env);
+    Down {
+      command: self.down.clone(),
+      env,
+    }
+  }
+}
+
+/// Runs the `down` hook when dropped.
+#[derive(Debug)]
+pub struct Down {
+  command: Option<String>,
+  env: Env,
+}
+
+impl Drop for Down {
+  fn drop(&mut self) {
+    if let Some(command) = &self.command {
+      spawn(command, Event::Down, &self.env);
+    }
+  }
+}
+
+/// Starts `command` through the shell and logs how it exits, without
+/// waiting for it.
+fn spawn(command: &str, event: Event, env: &[(&'static str, String)]) {
+  #[cfg(not(windows))]
+  let mut shell = {
+    let mut shell = Command::new("sh");
+    shell.arg("-c");
+    shell
+  };
+  #[cfg(windows)]
+  let mut shell = {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C");
+    shell
+  };
+  let child = shell
+    .arg(command)
+    .env("QVPN_EVENT", event.to_string())
+    .envs(env.iter().map(|(name, value)| (name, value)))
+    .stdin(Stdio::null())
+    .spawn();
+  let mut child = match child {
+    Ok(child) => child,
+    Err(err) => {
+      warn!(%event, "couldn't run hook: {}", err);
+      return;
+    }
+  };
+  debug!(%event, command, "running hook");
+  // A thread of its own reaps the command, even from a drop outside the
+  // runtime.
+  std::thread::spawn(move || match child.wait() {
+    Ok(status) if status.success() => debug!(%event, "hook done"),
+    Ok(status) => warn!(%event, "hook failed: {}", status),
+    Err(err) => warn!(%event, "couldn't wait for hook: {}", err),
+  });
+}
+
+/// `items` separated by spaces, as hooks get lists.
+pub fn list<T: ToString>(items: &[T]) -> String {
+  items
+    .iter()
+    .map(ToString::to_string)
+    .collect::<Vec<_>>()
+    .join(" ")
+}
@@ -0,0 +1,243 @@
+//! Minimal HTTP/0.9 style response heads.
+//!
+//! File responses start with a status line and `Name: value` headers ended
+//! by a blank line, followed by the body.
+
+use std::{
+  fmt, str,
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{QvpnError, Result};
+
+/// Longest request line or response head accepted.
+pub const MAX_HEAD: usize = 64 * 1024;
+
+/// Status line and headers of a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseHead {
+  /// Status code, e.g. `200`.
+  pub status: u16,
+  /// Reason phrase, e.g. `OK`.
+  pub reason: String,
+  /// Headers in the order they were added or received.
+  pub headers: Vec<(String, String)>,
+}
+
+impl ResponseHead {
+  /// Creates a head without headers.
+  pub fn new(status: u16, reason: impl Into<String>) -> Self {
+    ResponseHead {
+      status,
+      reason: reason.into(),
+      headers: vec![],
+    }
+  }
+
+  /// Appends a header.
+  pub fn header(mut self, name: impl Into<String>, value: impl fmt::Display) -> Self {
+    self.headers.push((name.into(), value.to_string()));
+    self
+  }
+
+  /// Value of the first header called `name`, ignoring case.
+  pub fn get(&self, name: &str) -> Option<&str> {
+    find_header(&self.headers, name)
+  }
+
+  /// The `Content-Length` header, if present and valid.
+  pub fn content_length(&self) -> Option<u64> {
+    self.get("Content-Length")?.parse().ok()
+  }
+
+  /// Whether the status is in the 2xx range.
+  pub fn is_success(&self) -> bool {
+    (200..300).contains(&self.status)
+  }
+
+  /// Serializes the head, including the terminating blank line.
+  pub fn encode(&self) -> String {
+    let mut out = format!("HTTP/3 {} {}\r\n", self.status, self.reason);
+    for (name, value) in &self.headers {
+      out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str("\r\n");
+    out
+  }
+
+  /// Parses a head produced by [`ResponseHead::encode`].
+  pub fn decode(head: &[u8]) -> Result<Self> {
+    let head =
+      str::from_utf8(head).map_err(|_| QvpnError::Protocol("response head is not utf-8".into()))?;
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    let malformed = || QvpnError::Protocol(format!("malformed status line `{}`", status_line));
+    let mut parts = status_line.splitn(3, ' ');
+    match parts.next() {
+      Some(version) if version.starts_with("HTTP/") => {}
+      _ => return Err(malformed()),
+    }
+    let status = parts
+      .next()
+      .and_then(|x| x.parse().ok())
+      .ok_or_else(malformed)?;
+    let reason = parts.next().unwrap_or_default().to_string();
+    let headers = parse_headers(lines).map_err(QvpnError::Protocol)?;
+    Ok(ResponseHead {
+      status,
+      reason,
+      headers,
+    })
+  }
+}
+
+impl fmt::Display for ResponseHead {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} {}", self.status, self.reason)
+  }
+}
+
+/// Parses `Name: value` lines up to the first empty line. Returns an error
+/// message naming the first malformed line.
+pub fn parse_headers<'a>(
+  lines: impl Iterator<Item = &'a str>,
+) -> std::result::Result<Vec<(String, String)>, String> {
+  let mut headers = vec![];
+  for line in lines.take_while(|line| !line.is_empty()) {
+    let colon = line
+      .find(':')
+      .ok_or_else(|| format!("malformed header `{}`", line))?;
+    headers.push((
+      line[..colon].trim().to_string(),
+      line[colon + 1..].trim().to_string(),
+    ));
+  }
+  Ok(headers)
+}
+
+/// Value of the first header called `name`, ignoring case.
+pub fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+  headers
+    .iter()
+    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+    .map(|(_, value)| value.as_str())
+}
+
+/// Percent-decodes a request path, which must decode to UTF-8 without NUL
+/// characters.
+pub fn decode_path(path: &[u8]) -> Result<String> {
+  let path = percent_encoding::percent_decode(path)
+    .decode_utf8()
+    .map_err(|_| QvpnError::BadRequest("path is not valid utf-8".into()))?;
+  if path.contains('\0') {
+    return Err(QvpnError::BadRequest(
+      "path contains a NUL character".into(),
+    ));
+  }
+  Ok(path.into_owned())
+}
+
+/// Resolves a `Range: bytes=...` header against a body of `len` bytes.
+///
+/// Returns the first and last byte offsets, inclusive. `None` means the
+/// header should be ignored and the whole body sent, which is the case for
+/// other units and multiple ranges. `Some(None)` means the range lies
+/// outside the body.
+pub fn parse_range(value: &str, len: u64) -> Option<Option<(u64, u64)>> {
+  let spec = value.trim().strip_prefix("bytes=")?;
+  if spec.contains(',') {
+    return None;
+  }
+  let dash = spec.find('-')?;
+  let (start, end) = (spec[..dash].trim(), spec[dash + 1..].trim());
+  let range = if start.is_empty() {
+    // Suffix range: the last `end` bytes.
+    let suffix: u64 = end.parse().ok()?;
+    if suffix == 0 || len == 0 {
+      None
+    } else {
+      Some((len.saturating_sub(suffix), len - 1))
+    }
+  } else {
+    let start: u64 = start.parse().ok()?;
+    let end = if end.is_empty() {
+      len.saturating_sub(1)
+    } else {
+      end.parse::<u64>().ok()?.min(len.saturating_sub(1))
+    };
+    Some((start, end)).filter(|&(start, end)| start < len && start <= end)
+  };
+  Some(range)
+}
+
+/// `ETag` of a file version, from its size and modification time.
+pub fn etag(len: u64, modified: Option<SystemTime>) -> String {
+  let nanos = modified
+    .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+    .map_or(0, |x| x.as_nanos());
+  format!("\"{:x}-{:x}\"", len, nanos)
+}
+
+/// Whether a request with `headers` for the file version with `etag` and
+/// modification time `modified` is answered with `304 Not Modified`.
+/// `If-None-Match` takes precedence over `If-Modified-Since`.
+pub fn not_modified(
+  headers: &[(String, String)],
+  etag: &str,
+  modified: Option<SystemTime>,
+) -> bool {
+  if let Some(tags) = find_header(headers, "If-None-Match") {
+    return tags
+      .split(',')
+      .map(|x| x.trim().trim_start_matches("W/"))
+      .any(|x| x == "*" || x == etag);
+  }
+  let since =
+    find_header(headers, "If-Modified-Since").and_then(|x| httpdate::parse_http_date(x).ok());
+  let secs = |x: SystemTime| x.duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs());
+  match (since, modified) {
+    // HTTP dates have a resolution of seconds.
+    (Some(since), Some(modified)) => secs(modified) <= secs(since),
+    _ => false,
+  }
+}
+
+/// Total length from a `Content-Range: bytes a-b/total` header.
+pub fn content_range_total(value: &str) -> Option<u64> {
+  value.rsplit('/').next()?.trim().parse().ok()
+}
+
+/// Reads until `delimiter` has been received.
+///
+/// Returns everything up to and including the delimiter, and any bytes
+/// received after it. If the stream ends first, everything read so far is
+/// returned as the first part.
+pub async fn read_until<R: AsyncRead + Unpin>(
+  reader: &mut R,
+  delimiter: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>)> {
+  let mut head = Vec::new();
+  let mut chunk = [0; 4096];
+  let mut searched = 0;
+  loop {
+    if let Some(end) = head[searched..]
+      .windows(delimiter.len())
+      .position(|x| x == delimiter)
+    {
+      let rest = head.split_off(searched + end + delimiter.len());
+      return Ok((head, rest));
+    }
+    searched = head.len().saturating_sub(delimiter.len() - 1);
+    if head.len() > MAX_HEAD {
+      return Err(QvpnError::BadRequest(
+        "request or response head too long".into(),
+      ));
+    }
+    match reader.read(&mut chunk).await? {
+      0 => return Ok((head, Vec::new())),
+      len => head.extend_from_slice(&chunk[..len]),
+    }
+  }
+}
@@ -0,0 +1,134 @@
+//! Ed25519 identities of peers.
+//!
+//! Every node keeps a key pair in a file, so it is recognised across
+//! restarts and address changes. Peers prove they hold the key behind their
+//! public key by signing a challenge when they connect, and sign the
+//! messages they originate, so a message can be attributed to its author
+//! even when it arrives through a relay.
+
+use std::{
+  fs,
+  io::{self, Write},
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use ring::{
+  digest,
+  rand::{SecureRandom, SystemRandom},
+  signature::{self, Ed25519KeyPair, KeyPair},
+};
+use tracing::info;
+
+use crate::{QvpnError, Result};
+
+/// Domain separator signed with handshake challenges, so a handshake
+/// signature can't be passed off as a message signature.
+const HELLO_CONTEXT: &[u8] = b"qvpn-hello\0";
+
+/// Bytes of a handshake challenge.
+pub const CHALLENGE_LEN: usize = 32;
+
+/// File the key is stored in when no other path is configured.
+pub fn default_path() -> Result<PathBuf> {
+  let dirs = directories_next::ProjectDirs::from("org", "quinn", "quinn-examples")
+    .ok_or_else(|| QvpnError::InvalidInput("no valid home directory found".into()))?;
+  Ok(dirs.data_dir().join("identity.pk8"))
+}
+
+/// The key pair of this node.
+#[derive(Debug)]
+pub struct Identity {
+  key: Ed25519KeyPair,
+}
+
+impl Identity {
+  /// Loads the PKCS#8 key at `path`, generating and saving a new one if the
+  /// file doesn't exist.
+  pub fn load_or_generate(path: &Path) -> Result<Arc<Self>> {
+    let pkcs8 = match fs::read(path) {
+      Ok(pkcs8) => pkcs8,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+          .map_err(|_| QvpnError::InvalidInput("couldn't generate a key".into()))?;
+        save(path, pkcs8.as_ref())?;
+        info!(path = %path.display(), "generated a new identity");
+        pkcs8.as_ref().to_vec()
+      }
+      Err(err) => return Err(err.into()),
+    };
+    let key = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|err| {
+      QvpnError::InvalidInput(format!("invalid key in {}: {}", path.display(), err))
+    })?;
+    Ok(Arc::new(Identity { key }))
+  }
+
+  /// The public key peers know this node by.
+  pub fn public_key(&self) -> &[u8] {
+    self.key.public_key().as_ref()
+  }
+
+  /// Fingerprint of this node's public key.
+  pub fn fingerprint(&self) -> String {
+    fingerprint(self.public_key())
+  }
+
+  /// Signs a message this node originates.
+  pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+    self.key.sign(msg).as_ref().to_vec()
+  }
+
+  /// Answers a handshake challenge.
+  pub fn prove(&self, challenge: &[u8]) -> Vec<u8> {
+    self.sign(&[HELLO_CONTEXT, challenge].concat())
+  }
+}
+
+/// A fresh handshake challenge.
+pub fn challenge() -> Vec<u8> {
+  let mut challenge = vec![0; CHALLENGE_LEN];
+  SystemRandom::new()
+    .fill(&mut challenge)
+    .expect("system randomness available");
+  challenge
+}
+
+/// Whether `signature` by `public_key` covers `msg`.
+pub fn verify(public_key: &[u8], msg: &[u8], signature: &[u8]) -> bool {
+  signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+    .verify(msg, signature)
+    .is_ok()
+}
+
+/// Whether `proof` answers `challenge` for `public_key`.
+pub fn verify_proof(public_key: &[u8], challenge: &[u8], proof: &[u8]) -> bool {
+  verify(public_key, &[HELLO_CONTEXT, challenge].concat(), proof)
+}
+
+/// Hex encoding of a public key, as kept in the peer store.
+pub fn encode_key(public_key: &[u8]) -> String {
+  public_key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Short, human comparable form of a public key: the first 16 bytes of its
+/// SHA-256 as colon separated hex pairs.
+pub fn fingerprint(public_key: &[u8]) -> String {
+  let hash = digest::digest(&digest::SHA256, public_key);
+  let hex: Vec<_> = hash.as_ref()[..16]
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect();
+  hex.join(":")
+}
+
+/// Writes the key readable only by the owner.
+fn save(path: &Path, pkcs8: &[u8]) -> io::Result<()> {
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  let mut options = fs::OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+  options.open(path)?.write_all(pkcs8)
+}
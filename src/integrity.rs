@@ -0,0 +1,158 @@
+//! End-to-end integrity checks of downloads.
+//!
+//! A client that wants to verify a file sends `Want-Repr-Digest:
+//! sha-256=10`, and the server answers with the SHA-256 of the whole file
+//! in a `Repr-Digest: sha-256=:<base64>:` header, as in RFC 9530. The
+//! digest covers the file as stored, so it is the same for range requests
+//! and compressed responses, and a resumed or parallel download can be
+//! checked once the file is complete.
+//!
+//! Servers cache digests by path, size and modification time, so a file is
+//! only hashed again once it changes.
+
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  sync::Mutex,
+  time::SystemTime,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ring::digest;
+use tokio::io::AsyncReadExt;
+
+use crate::{http::ResponseHead, QvpnError, Result};
+
+/// Header asking for a digest.
+pub const WANT_HEADER: &str = "Want-Repr-Digest";
+
+/// Header carrying the digest.
+pub const HEADER: &str = "Repr-Digest";
+
+/// Value of [`WANT_HEADER`] asking for SHA-256.
+pub const WANT_SHA256: &str = "sha-256=10";
+
+/// Most digests a [`DigestCache`] keeps.
+const CACHE_CAPACITY: usize = 1024;
+
+/// Formats the [`HEADER`] value for a SHA-256 `digest`.
+pub fn header_value(digest: &[u8]) -> String {
+  format!("sha-256=:{}:", STANDARD.encode(digest))
+}
+
+/// The SHA-256 digest announced by `head`, if any.
+pub fn expected(head: &ResponseHead) -> Option<Vec<u8>> {
+  head.get(HEADER)?.split(',').find_map(|x| {
+    let (algorithm, value) = x.trim().split_once('=')?;
+    if !algorithm.eq_ignore_ascii_case("sha-256") {
+      return None;
+    }
+    let value = value.strip_prefix(':')?.strip_suffix(':')?;
+    STANDARD.decode(value).ok()
+  })
+}
+
+/// SHA-256 of a file.
+pub async fn hash_file(path: &Path) -> Result<Vec<u8>> {
+  let mut file = tokio::fs::File::open(path).await?;
+  let mut context = digest::Context::new(&digest::SHA256);
+  let mut chunk = vec![0; 64 * 1024];
+  loop {
+    match file.read(&mut chunk).await? {
+      0 => break,
+      len => context.update(&chunk[..len]),
+    }
+  }
+  Ok(context.finish().as_ref().to_vec())
+}
+
+/// Checks that the file at `path` has the SHA-256 `expected`.
+pub async fn verify_file(path: &Path, expected: &[u8]) -> Result<()> {
+  check(expected, &hash_file(path).await?)
+}
+
+/// Hashes a body as it arrives and compares it with the digest the server
+/// announced.
+pub struct Verifier {
+  context: digest::Context,
+  expected: Vec<u8>,
+}
+
+impl Verifier {
+  /// Starts checking a body against the SHA-256 `expected`.
+  pub fn new(expected: Vec<u8>) -> Self {
+    Verifier {
+      context: digest::Context::new(&digest::SHA256),
+      expected,
+    }
+  }
+
+  /// Hashes the next part of the body.
+  pub fn update(&mut self, data: &[u8]) {
+    self.context.update(data);
+  }
+
+  /// Fails unless the body had the expected digest.
+  pub fn finish(self) -> Result<()> {
+    check(&self.expected, self.context.finish().as_ref())
+  }
+}
+
+impl std::fmt::Debug for Verifier {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Verifier")
+      .field("expected", &header_value(&self.expected))
+      .finish_non_exhaustive()
+  }
+}
+
+fn check(expected: &[u8], actual: &[u8]) -> Result<()> {
+  if expected != actual {
+    return Err(QvpnError::Integrity(format!(
+      "expected {} but received {}",
+      header_value(expected),
+      header_value(actual)
+    )));
+  }
+  Ok(())
+}
+
+/// Digests of served files.
+#[derive(Debug, Default)]
+pub struct DigestCache {
+  entries: Mutex<HashMap<PathBuf, Cached>>,
+}
+
+/// A digest and the version of the file it is for.
+#[derive(Debug)]
+struct Cached {
+  len: u64,
+  modified: SystemTime,
+  digest: Vec<u8>,
+}
+
+impl DigestCache {
+  /// SHA-256 of the file at `path`, hashing it only if it changed since the
+  /// last time.
+  pub async fn get(&self, path: &Path) -> Result<Vec<u8>> {
+    let meta = tokio::fs::metadata(path).await?;
+    let modified = meta.modified()?;
+    if let Some(cached) = self.entries.lock().unwrap().get(path) {
+      if cached.len == meta.len() && cached.modified == modified {
+        return Ok(cached.digest.clone());
+      }
+    }
+    let digest = hash_file(path).await?;
+    let mut entries = self.entries.lock().unwrap();
+    if entries.len() >= CACHE_CAPACITY {
+      entries.clear();
+    }
+    let cached = Cached {
+      len: meta.len(),
+      modified,
+      digest: digest.clone(),
+    };
+    entries.insert(path.to_path_buf(), cached);
+    Ok(digest)
+  }
+}
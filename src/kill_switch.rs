@@ -0,0 +1,153 @@
+//! Client kill switch.
+//!
+//! While a tunnel is meant to be up, a [`KillSwitch`] keeps the host from
+//! sending anything but to the loopback interface, through the tunnel
+//! interface, and to the tunnel's servers. Should the connection drop,
+//! traffic that the tunnel's routes would have carried is blocked instead of
+//! leaking out of the host's other interfaces, until the tunnel is back.
+//! The rules go once the switch is dropped, when the tunnel is torn down.
+//!
+//! Server names are resolved once, when the switch goes up, since name
+//! servers outside the tunnel are blocked along with everything else.
+
+use std::net::SocketAddr;
+
+use crate::{site::Firewall, Result};
+
+/// Firewall rules dropping what the host sends anywhere but through a
+/// tunnel interface or to its servers, removed when dropped.
+#[derive(Debug)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct KillSwitch {
+  firewall: Firewall,
+  dev: String,
+  servers: Vec<SocketAddr>,
+}
+
+impl KillSwitch {
+  /// Blocks what the host sends other than through the interface named
+  /// `dev` and to the UDP addresses of `servers`.
+  #[cfg(target_os = "linux")]
+  pub fn new(firewall: Firewall, dev: &str, servers: Vec<SocketAddr>) -> Result<Self> {
+    let switch = KillSwitch {
+      firewall,
+      dev: dev.to_string(),
+      servers,
+    };
+    // Rules left behind by a run that didn't get to clean up are replaced.
+    switch.remove();
+    // Dropping the switch on an error takes back the rules added so far.
+    for args in switch.rules(true) {
+      crate::site::run(&args)?;
+    }
+    Ok(switch)
+  }
+
+  /// Blocks what the host sends other than through the interface named
+  /// `dev` and to the UDP addresses of `servers`.
+  #[cfg(not(target_os = "linux"))]
+  pub fn new(firewall: Firewall, dev: &str, servers: Vec<SocketAddr>) -> Result<Self> {
+    let _ = servers;
+    Err(crate::QvpnError::Unsupported(format!(
+      "cannot install a kill switch for {} with {} on this platform",
+      dev, firewall
+    )))
+  }
+
+  /// Commands adding, or else deleting, the rules.
+  #[cfg(target_os = "linux")]
+  fn rules(&self, add: bool) -> Vec<Vec<String>> {
+    let dev = self.dev.as_str();
+    let line = |x: &str| x.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+    match (self.firewall, add) {
+      (Firewall::Iptables, true) => {
+        let mut rules = vec![];
+        for (iptables, v6) in [("iptables", false), ("ip6tables", true)] {
+          rules.push(line(&format!("{} -N {}", iptables, CHAIN)));
+          rules.push(line(&format!("{} -A {} -o lo -j ACCEPT", iptables, CHAIN)));
+          rules.push(line(&format!(
+            "{} -A {} -o {} -j ACCEPT",
+            iptables, CHAIN, dev
+          )));
+          for server in self.servers.iter().filter(|x| x.is_ipv6() == v6) {
+            rules.push(line(&format!(
+              "{} -A {} -d {} -p udp --dport {} -j ACCEPT",
+              iptables,
+              CHAIN,
+              server.ip(),
+              server.port()
+            )));
+          }
+          rules.push(line(&format!("{} -A {} -j DROP", iptables, CHAIN)));
+          // Ahead of any rules accepting what the host sends.
+          rules.push(line(&format!("{} -I OUTPUT -j {}", iptables, CHAIN)));
+        }
+        rules
+      }
+      (Firewall::Iptables, false) => ["iptables", "ip6tables"]
+        .iter()
+        .flat_map(|iptables| {
+          [
+            line(&format!("{} -D OUTPUT -j {}", iptables, CHAIN)),
+            line(&format!("{} -F {}", iptables, CHAIN)),
+            line(&format!("{} -X {}", iptables, CHAIN)),
+          ]
+        })
+        .collect(),
+      (Firewall::Nftables, true) => {
+        let mut rules = vec![
+          line(&format!("nft add table inet {}", TABLE)),
+          line(&format!(
+            "nft add chain inet {} output {{ type filter hook output priority 0 ; policy drop ; }}",
+            TABLE
+          )),
+          line(&format!(
+            "nft add rule inet {} output oifname lo accept",
+            TABLE
+          )),
+          line(&format!(
+            "nft add rule inet {} output oifname {} accept",
+            TABLE, dev
+          )),
+        ];
+        for server in &self.servers {
+          let family = if server.is_ipv6() { "ip6" } else { "ip" };
+          rules.push(line(&format!(
+            "nft add rule inet {} output {} daddr {} udp dport {} accept",
+            TABLE,
+            family,
+            server.ip(),
+            server.port()
+          )));
+        }
+        rules
+      }
+      (Firewall::Nftables, false) => vec![line(&format!("nft delete table inet {}", TABLE))],
+    }
+  }
+
+  #[cfg(target_os = "linux")]
+  fn remove(&self) {
+    for args in self.rules(false) {
+      let _ = std::process::Command::new(&args[0])
+        .args(&args[1..])
+        .stderr(std::process::Stdio::null())
+        .status();
+    }
+  }
+}
+
+impl Drop for KillSwitch {
+  fn drop(&mut self) {
+    #[cfg(target_os = "linux")]
+    self.remove();
+  }
+}
+
+/// iptables chain holding the rules.
+#[cfg(target_os = "linux")]
+const CHAIN: &str = "QVPN_KILL_SWITCH";
+
+/// nftables table holding the rules.
+#[cfg(target_os = "linux")]
+const TABLE: &str = "qvpn_kill_switch";
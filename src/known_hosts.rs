@@ -0,0 +1,117 @@
+//! Trust on first use for servers and peers without a CA.
+//!
+//! The first time a host is seen, the fingerprint of its key is recorded in
+//! a `known_hosts` file of `<host> <fingerprint>` lines. From then on a host
+//! presenting a different key is refused until its entry is forgotten with
+//! `qvpn trust forget <host>`.
+
+use std::{
+  fs, io,
+  path::{Path, PathBuf},
+};
+
+use tracing::{error, warn};
+
+use crate::{QvpnError, Result};
+
+/// File the fingerprints are kept in when no other path is configured.
+pub fn default_path() -> Result<PathBuf> {
+  let dirs = directories_next::ProjectDirs::from("org", "quinn", "quinn-examples")
+    .ok_or_else(|| QvpnError::InvalidInput("no valid home directory found".into()))?;
+  Ok(dirs.data_dir().join("known_hosts"))
+}
+
+/// A `known_hosts` file.
+#[derive(Debug, Clone)]
+pub struct KnownHosts {
+  path: PathBuf,
+}
+
+impl KnownHosts {
+  /// Uses the file at `path`, which is created on the first new host.
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    KnownHosts { path: path.into() }
+  }
+
+  /// Path of the file.
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Recorded hosts and their fingerprints, in file order.
+  pub fn entries(&self) -> Result<Vec<(String, String)>> {
+    let contents = match fs::read_to_string(&self.path) {
+      Ok(contents) => contents,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+      Err(err) => return Err(err.into()),
+    };
+    Ok(
+      contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+          let mut fields = line.split_whitespace();
+          Some((fields.next()?.to_string(), fields.next()?.to_string()))
+        })
+        .collect(),
+    )
+  }
+
+  /// Checks `fingerprint` against the one recorded for `host`, recording it
+  /// if the host is new. A different fingerprint is an error.
+  pub fn check(&self, host: &str, fingerprint: &str) -> Result<()> {
+    let entries = self.entries()?;
+    match entries.iter().find(|(known, _)| known == host) {
+      Some((_, known)) if known == fingerprint => Ok(()),
+      Some((_, known)) => {
+        error!(
+          %host,
+          expected = %known,
+          presented = %fingerprint,
+          "HOST KEY CHANGED: someone may be intercepting the connection; run \
+           `qvpn trust forget {}` if the change is expected",
+          host
+        );
+        Err(QvpnError::Unauthenticated(format!(
+          "{} presented {} but {} is recorded in {}",
+          host,
+          fingerprint,
+          known,
+          self.path.display()
+        )))
+      }
+      None => {
+        warn!(%host, %fingerprint, "trusting new host on first use");
+        let mut entries = entries;
+        entries.push((host.to_string(), fingerprint.to_string()));
+        self.save(&entries)
+      }
+    }
+  }
+
+  /// Forgets `host`, returning whether it was recorded.
+  pub fn forget(&self, host: &str) -> Result<bool> {
+    let mut entries = self.entries()?;
+    let len = entries.len();
+    entries.retain(|(known, _)| known != host);
+    if entries.len() == len {
+      return Ok(false);
+    }
+    self.save(&entries)?;
+    Ok(true)
+  }
+
+  fn save(&self, entries: &[(String, String)]) -> Result<()> {
+    if let Some(dir) = self.path.parent() {
+      fs::create_dir_all(dir)?;
+    }
+    let contents: String = entries
+      .iter()
+      .map(|(host, fingerprint)| format!("{} {}\n", host, fingerprint))
+      .collect();
+    let partial = self.path.with_extension("part");
+    fs::write(&partial, contents)?;
+    fs::rename(&partial, &self.path)?;
+    Ok(())
+  }
+}
@@ -0,0 +1,297 @@
+//! Virtual IP address assignment for tunnel clients.
+//!
+//! The server owns a subnet; its own interface takes the first host address
+//! and every tunnel connection leases one of the remaining addresses for as
+//! long as it stays connected.
+
+use std::{
+  collections::HashMap,
+  fmt,
+  net::{IpAddr, Ipv4Addr},
+  str::FromStr,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{QvpnError, Result};
+
+/// An IPv4 network in CIDR notation, e.g. `10.8.0.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv4Net {
+  addr: Ipv4Addr,
+  prefix: u8,
+}
+
+impl Ipv4Net {
+  /// Creates a network, masking off any host bits in `addr`.
+  pub fn new(addr: Ipv4Addr, prefix: u8) -> Option<Self> {
+    if prefix > 32 {
+      return None;
+    }
+    let addr = Ipv4Addr::from(u32::from(addr) & mask(prefix));
+    Some(Ipv4Net { addr, prefix })
+  }
+
+  /// The lower (`0`) or upper (`1`) half of the IPv4 address space.
+  pub const fn half(upper: u8) -> Self {
+    Ipv4Net {
+      addr: Ipv4Addr::new(upper << 7, 0, 0, 0),
+      prefix: 1,
+    }
+  }
+
+  /// Network address.
+  pub fn addr(&self) -> Ipv4Addr {
+    self.addr
+  }
+
+  /// Prefix length.
+  pub fn prefix(&self) -> u8 {
+    self.prefix
+  }
+
+  /// Netmask, e.g. `255.255.255.0` for a /24.
+  pub fn netmask(&self) -> Ipv4Addr {
+    Ipv4Addr::from(mask(self.prefix))
+  }
+
+  /// Whether `ip` is inside this network.
+  pub fn contains(&self, ip: Ipv4Addr) -> bool {
+    u32::from(ip) & mask(self.prefix) == u32::from(self.addr)
+  }
+
+  /// Whether this network and `other` share any address.
+  pub fn overlaps(&self, other: Ipv4Net) -> bool {
+    self.contains(other.addr) || other.contains(self.addr)
+  }
+
+  /// Usable host addresses, excluding the network and broadcast addresses.
+  pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+    let start = u32::from(self.addr);
+    let size = 1u64 << (32 - self.prefix as u32);
+    let (first, last) = if size <= 2 {
+      (start as u64, start as u64 + size - 1)
+    } else {
+      (start as u64 + 1, start as u64 + size - 2)
+    };
+    (first..=last).map(|x| Ipv4Addr::from(x as u32))
+  }
+}
+
+fn mask(prefix: u8) -> u32 {
+  if prefix == 0 {
+    0
+  } else {
+    u32::MAX << (32 - prefix as u32)
+  }
+}
+
+impl FromStr for Ipv4Net {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let (addr, prefix) = match s.find('/') {
+      Some(i) => (&s[..i], &s[i + 1..]),
+      None => (s, "32"),
+    };
+    let addr: Ipv4Addr = addr
+      .parse()
+      .map_err(|_| format!("invalid address in `{}`", s))?;
+    let prefix: u8 = prefix
+      .parse()
+      .map_err(|_| format!("invalid prefix in `{}`", s))?;
+    Ipv4Net::new(addr, prefix).ok_or_else(|| format!("prefix out of range in `{}`", s))
+  }
+}
+
+impl<'de> Deserialize<'de> for Ipv4Net {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl Serialize for Ipv4Net {
+  fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl fmt::Display for Ipv4Net {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.addr, self.prefix)
+  }
+}
+
+/// Tunnel settings handed to a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+  /// Address assigned to the client's interface.
+  pub address: Ipv4Addr,
+  /// Netmask of the tunnel subnet.
+  pub netmask: Ipv4Addr,
+  /// The server's address inside the tunnel.
+  pub gateway: Ipv4Addr,
+  /// Interface MTU.
+  pub mtu: u16,
+  /// Networks the client should route through the tunnel.
+  pub routes: Vec<Ipv4Net>,
+  /// Name servers the client should resolve names through.
+  pub dns: Vec<IpAddr>,
+  /// Search domains for unqualified names.
+  pub search: Vec<String>,
+  /// The tunnel carries Ethernet frames, through a TAP interface.
+  pub tap: bool,
+}
+
+impl Lease {
+  /// Serializes the lease as `key value` lines.
+  pub fn encode(&self) -> String {
+    let mut out = format!(
+      "address {}\nnetmask {}\ngateway {}\nmtu {}\n",
+      self.address, self.netmask, self.gateway, self.mtu
+    );
+    for route in &self.routes {
+      out.push_str(&format!("route {}\n", route));
+    }
+    for server in &self.dns {
+      out.push_str(&format!("dns {}\n", server));
+    }
+    for domain in &self.search {
+      out.push_str(&format!("search {}\n", domain));
+    }
+    if self.tap {
+      out.push_str("tap true\n");
+    }
+    out
+  }
+
+  /// Parses a lease produced by [`Lease::encode`].
+  pub fn decode(s: &str) -> Result<Lease> {
+    let (mut address, mut netmask, mut gateway, mut mtu) = (None, None, None, None);
+    let (mut routes, mut dns, mut search) = (vec![], vec![], vec![]);
+    let mut tap = false;
+    for line in s.lines() {
+      let mut parts = line.splitn(2, ' ');
+      let key = parts.next().unwrap_or_default();
+      let malformed = || QvpnError::Protocol(format!("malformed lease line `{}`", line));
+      let value = parts.next().ok_or_else(malformed)?;
+      match key {
+        "address" => address = Some(value.parse().map_err(|_| malformed())?),
+        "netmask" => netmask = Some(value.parse().map_err(|_| malformed())?),
+        "gateway" => gateway = Some(value.parse().map_err(|_| malformed())?),
+        "mtu" => mtu = Some(value.parse().map_err(|_| malformed())?),
+        "route" => routes.push(value.parse().map_err(|_| malformed())?),
+        "dns" => dns.push(value.parse().map_err(|_| malformed())?),
+        "search" => search.push(value.to_string()),
+        "tap" => tap = value.parse().map_err(|_| malformed())?,
+        _ => {}
+      }
+    }
+    let missing = |key| QvpnError::Protocol(format!("lease is missing {}", key));
+    Ok(Lease {
+      address: address.ok_or_else(|| missing("address"))?,
+      netmask: netmask.ok_or_else(|| missing("netmask"))?,
+      gateway: gateway.ok_or_else(|| missing("gateway"))?,
+      mtu: mtu.ok_or_else(|| missing("mtu"))?,
+      routes,
+      dns,
+      search,
+      tap,
+    })
+  }
+}
+
+/// Tracks which addresses of a subnet are leased to which connection.
+#[derive(Debug)]
+pub struct LeasePool {
+  subnet: Ipv4Net,
+  gateway: Ipv4Addr,
+  leases: HashMap<Ipv4Addr, usize>,
+}
+
+impl LeasePool {
+  /// Creates a pool over `subnet`, reserving its first host for the server.
+  pub fn new(subnet: Ipv4Net) -> Result<Self> {
+    let gateway = subnet
+      .hosts()
+      .next()
+      .ok_or_else(|| QvpnError::InvalidInput(format!("subnet {} has no host addresses", subnet)))?;
+    Ok(LeasePool {
+      subnet,
+      gateway,
+      leases: HashMap::new(),
+    })
+  }
+
+  /// The pool's subnet.
+  pub fn subnet(&self) -> Ipv4Net {
+    self.subnet
+  }
+
+  /// The server's address inside the tunnel.
+  pub fn gateway(&self) -> Ipv4Addr {
+    self.gateway
+  }
+
+  /// Leases a free address to connection `id`, unless it holds one
+  /// already, which is returned instead. `None` if the pool is exhausted.
+  pub fn allocate(&mut self, id: usize) -> Option<Ipv4Addr> {
+    if let Some(address) = self.leased(id) {
+      return Some(address);
+    }
+    let gateway = self.gateway;
+    let leases = &self.leases;
+    let address = self
+      .subnet
+      .hosts()
+      .find(|ip| *ip != gateway && !leases.contains_key(ip))?;
+    self.leases.insert(address, id);
+    Some(address)
+  }
+
+  /// The address leased to connection `id`, if any.
+  pub fn leased(&self, id: usize) -> Option<Ipv4Addr> {
+    self
+      .leases
+      .iter()
+      .find(|(_, owner)| **owner == id)
+      .map(|(address, _)| *address)
+  }
+
+  /// Returns every address leased to connection `id` to the pool.
+  pub fn release(&mut self, id: usize) {
+    self.leases.retain(|_, owner| *owner != id);
+  }
+
+  /// Number of addresses currently leased.
+  pub fn len(&self) -> usize {
+    self.leases.len()
+  }
+
+  /// Whether no addresses are leased.
+  pub fn is_empty(&self) -> bool {
+    self.leases.is_empty()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn connections_hold_one_lease() {
+    let mut pool = LeasePool::new("10.8.0.0/30".parse().unwrap()).unwrap();
+    let address = pool.allocate(1).unwrap();
+    assert_eq!(address, Ipv4Addr::new(10, 8, 0, 2));
+    assert_eq!(pool.allocate(1), Some(address));
+    assert_eq!(pool.len(), 1);
+    assert_eq!(pool.leased(1), Some(address));
+    // The /30 has no other host for a second connection.
+    assert_eq!(pool.allocate(2), None);
+    pool.release(1);
+    assert_eq!(pool.leased(1), None);
+    assert_eq!(pool.allocate(2), Some(address));
+  }
+}
@@ -0,0 +1,96 @@
+//! qvpn: file transfer, tunnelling and peer messaging over QUIC.
+//!
+//! The binaries in this crate are thin wrappers around the [`Server`],
+//! [`Client`] and [`Peer`] types exposed here.
+
+pub mod access;
+pub mod accounting;
+pub mod acme;
+pub mod auth;
+pub mod bench;
+pub mod bond;
+pub mod cache;
+pub mod chunking;
+pub mod client;
+pub mod compress;
+pub mod config;
+pub mod congestion;
+pub mod control;
+pub mod dashboard;
+pub mod datagram;
+pub mod dns;
+pub mod doq;
+pub mod egress;
+pub mod error;
+pub mod expose;
+pub mod file_cache;
+pub mod forward;
+pub mod fragment;
+pub mod gossip;
+pub mod h3;
+pub mod hook;
+pub mod http;
+pub mod identity;
+pub mod integrity;
+pub mod kill_switch;
+pub mod known_hosts;
+pub mod lease;
+pub mod limit;
+pub mod listing;
+pub mod log;
+#[cfg(unix)]
+pub mod manage;
+pub mod mdns;
+pub mod metrics;
+pub mod mount;
+pub mod net;
+pub mod node;
+pub mod oidc;
+pub mod outbox;
+pub mod peer;
+pub mod peer_endpoint;
+pub mod peer_manager;
+pub mod peer_store;
+pub mod privilege;
+pub mod protocol;
+pub mod proxy;
+pub mod psk;
+pub mod qlog;
+pub mod reconnect;
+pub mod relay;
+pub mod rendezvous;
+pub mod repl;
+pub mod rpc;
+pub mod send_queue;
+pub mod server;
+pub mod service;
+pub mod site;
+pub mod socks;
+pub mod stats;
+pub mod sync;
+pub mod tls;
+pub mod topics;
+pub mod transfer;
+pub mod tun;
+pub mod tuning;
+pub mod udp;
+pub mod version;
+
+pub use client::{Client, ClientBuilder};
+pub use error::QvpnError;
+pub use node::{EventStream, Handle, NodeEvent, QvpnNode};
+pub use peer::{Peer, PeerBuilder};
+pub use server::{ClientIdentity, Server, ServerBuilder};
+
+/// ALPN protocol of the qvpn request protocol.
+pub const ALPN_QVPN: &[u8] = b"qvpn/1";
+
+/// ALPN protocol the qvpn request protocol was spoken under before servers
+/// spoke HTTP/3, and still is with `--legacy-proto`.
+pub const ALPN_LEGACY: &[u8] = b"h3-29";
+
+/// ALPN protocols offered by the quinn client, most preferred first.
+pub const ALPN_QUIC_HTTP: &[&[u8]] = &[ALPN_QVPN, ALPN_LEGACY];
+
+/// Result alias used throughout the library.
+pub type Result<T> = std::result::Result<T, QvpnError>;
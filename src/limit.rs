@@ -0,0 +1,176 @@
+//! Per-address rate limiting of new connections.
+//!
+//! Each source IP gets a token bucket holding up to `per_minute` tokens that
+//! refills at `per_minute` tokens a minute. A new connection takes a token,
+//! and connections arriving at an empty bucket are refused. Addresses that
+//! recently completed a handshake can be remembered in [`ValidatedAddrs`]
+//! and let through without a token.
+//!
+//! The limiter tracks a bounded number of addresses, forgetting the one it
+//! saw first to make room for another, and forgets full buckets at most
+//! once a second.
+
+use std::{
+  collections::{HashMap, VecDeque},
+  net::IpAddr,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+/// Addresses tracked before stale entries are forgotten.
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// Most addresses a [`RateLimiter`] tracks at once.
+const MAX_TRACKED: usize = 65536;
+
+/// Least time between two prunes of a [`RateLimiter`].
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits how often each source IP may open a connection.
+#[derive(Debug)]
+pub struct RateLimiter {
+  per_minute: u32,
+  buckets: Mutex<Buckets>,
+}
+
+#[derive(Debug)]
+struct Buckets {
+  buckets: HashMap<IpAddr, Bucket>,
+  /// Tracked addresses in the order they were first seen.
+  order: VecDeque<IpAddr>,
+  pruned: Instant,
+}
+
+#[derive(Debug)]
+struct Bucket {
+  tokens: f64,
+  refilled: Instant,
+}
+
+impl RateLimiter {
+  /// Allows each address `per_minute` new connections a minute, in bursts
+  /// of up to as many.
+  pub fn new(per_minute: u32) -> Self {
+    RateLimiter {
+      per_minute: per_minute.max(1),
+      buckets: Mutex::new(Buckets {
+        buckets: HashMap::new(),
+        order: VecDeque::new(),
+        pruned: Instant::now(),
+      }),
+    }
+  }
+
+  /// Decides whether a new connection from `ip` may proceed now.
+  pub fn admit(&self, ip: IpAddr) -> bool {
+    let mut state = self.buckets.lock().expect("rate limiter lock poisoned");
+    let Buckets {
+      buckets,
+      order,
+      pruned,
+    } = &mut *state;
+    let now = Instant::now();
+    let burst = self.per_minute as f64;
+    let rate = burst / 60.0;
+    if buckets.len() >= PRUNE_THRESHOLD && now.duration_since(*pruned) >= PRUNE_INTERVAL {
+      buckets.retain(|_, bucket| {
+        bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * rate < burst
+      });
+      order.retain(|ip| buckets.contains_key(ip));
+      *pruned = now;
+    }
+    if !buckets.contains_key(&ip) {
+      while buckets.len() >= MAX_TRACKED {
+        match order.pop_front() {
+          Some(oldest) => buckets.remove(&oldest),
+          None => break,
+        };
+      }
+      order.push_back(ip);
+    }
+    let bucket = buckets.entry(ip).or_insert(Bucket {
+      tokens: burst,
+      refilled: now,
+    });
+    let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+    bucket.refilled = now;
+    if bucket.tokens >= 1.0 {
+      bucket.tokens -= 1.0;
+      true
+    } else {
+      false
+    }
+  }
+}
+
+/// Source IPs that recently completed a handshake, and so proved they can
+/// receive at the address they send from.
+#[derive(Debug)]
+pub struct ValidatedAddrs {
+  ttl: Duration,
+  addrs: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl ValidatedAddrs {
+  /// Remembers each address for `ttl` after its last handshake.
+  pub fn new(ttl: Duration) -> Self {
+    ValidatedAddrs {
+      ttl,
+      addrs: Mutex::default(),
+    }
+  }
+
+  /// Records a completed handshake from `ip`.
+  pub fn insert(&self, ip: IpAddr) {
+    let mut addrs = self.addrs.lock().expect("validation cache lock poisoned");
+    let now = Instant::now();
+    if addrs.len() >= PRUNE_THRESHOLD {
+      let ttl = self.ttl;
+      addrs.retain(|_, validated| now.duration_since(*validated) < ttl);
+    }
+    addrs.insert(ip, now);
+  }
+
+  /// Whether `ip` completed a handshake within the last `ttl`.
+  pub fn contains(&self, ip: IpAddr) -> bool {
+    let addrs = self.addrs.lock().expect("validation cache lock poisoned");
+    addrs
+      .get(&ip)
+      .is_some_and(|validated| validated.elapsed() < self.ttl)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::Ipv4Addr;
+
+  use super::*;
+
+  fn ip(n: usize) -> IpAddr {
+    Ipv4Addr::from(n as u32).into()
+  }
+
+  #[test]
+  fn limits_each_address() {
+    let limiter = RateLimiter::new(2);
+    assert!(limiter.admit(ip(1)));
+    assert!(limiter.admit(ip(1)));
+    assert!(!limiter.admit(ip(1)));
+    assert!(limiter.admit(ip(2)));
+  }
+
+  #[test]
+  fn forgets_the_first_address_seen_past_the_cap() {
+    let limiter = RateLimiter::new(1);
+    for n in 0..=MAX_TRACKED {
+      assert!(limiter.admit(ip(n)));
+    }
+    let state = limiter.buckets.lock().unwrap();
+    assert_eq!(state.buckets.len(), MAX_TRACKED);
+    assert!(!state.buckets.contains_key(&ip(0)));
+    drop(state);
+    // Still limited, as an address seen since.
+    assert!(!limiter.admit(ip(MAX_TRACKED)));
+  }
+}
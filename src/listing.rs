@@ -0,0 +1,192 @@
+//! Directory listings returned for requests that resolve to a directory.
+
+use std::{
+  fmt,
+  path::Path,
+  str::FromStr,
+  time::{Duration, UNIX_EPOCH},
+};
+
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::Result;
+
+/// Characters escaped in listing links, besides controls.
+pub(crate) const PATH: &AsciiSet = &CONTROLS
+  .add(b' ')
+  .add(b'"')
+  .add(b'#')
+  .add(b'%')
+  .add(b'<')
+  .add(b'>')
+  .add(b'?')
+  .add(b'`')
+  .add(b'{')
+  .add(b'}');
+
+/// Representation of a directory listing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+  /// An HTML page with links, for browsers.
+  #[default]
+  Html,
+  /// A JSON array of entries, for scripts.
+  Json,
+}
+
+impl Format {
+  /// MIME type of the listing.
+  pub fn mime(&self) -> &'static str {
+    match self {
+      Format::Html => "text/html; charset=utf-8",
+      Format::Json => "application/json",
+    }
+  }
+
+  /// Picks the format named by an `Accept` header, defaulting to HTML.
+  pub fn from_accept(accept: Option<&str>) -> Format {
+    match accept {
+      Some(accept) if accept.contains("application/json") => Format::Json,
+      _ => Format::Html,
+    }
+  }
+}
+
+impl FromStr for Format {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "html" => Ok(Format::Html),
+      "json" => Ok(Format::Json),
+      _ => Err(format!("unknown format `{}`, expected html or json", s)),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Format {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Format {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Format::Html => f.write_str("html"),
+      Format::Json => f.write_str("json"),
+    }
+  }
+}
+
+/// One entry of a directory listing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Entry {
+  /// File name.
+  pub name: String,
+  /// Whether the entry is a directory.
+  pub dir: bool,
+  /// Size in bytes; zero for directories.
+  pub size: u64,
+  /// Modification time in seconds since the Unix epoch, if known.
+  pub modified: Option<u64>,
+}
+
+/// Reads the entries of `dir`, directories first, each group sorted by name.
+/// Entries whose names are not valid UTF-8 are skipped.
+pub async fn read_dir(dir: &Path) -> Result<Vec<Entry>> {
+  let mut entries = vec![];
+  let mut reader = tokio::fs::read_dir(dir).await?;
+  while let Some(entry) = reader.next_entry().await? {
+    let name = match entry.file_name().into_string() {
+      Ok(name) => name,
+      Err(_) => continue,
+    };
+    let meta = entry.metadata().await?;
+    entries.push(Entry {
+      name,
+      dir: meta.is_dir(),
+      size: if meta.is_dir() { 0 } else { meta.len() },
+      modified: meta
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs()),
+    });
+  }
+  entries.sort_by(|a, b| b.dir.cmp(&a.dir).then_with(|| a.name.cmp(&b.name)));
+  Ok(entries)
+}
+
+/// Renders `entries` of the directory at request path `path`.
+pub fn render(format: Format, path: &str, entries: &[Entry]) -> String {
+  match format {
+    Format::Html => html(path, entries),
+    Format::Json => serde_json::to_string(entries).expect("entries serialize"),
+  }
+}
+
+fn html(path: &str, entries: &[Entry]) -> String {
+  let base = if path.ends_with('/') {
+    path.to_string()
+  } else {
+    format!("{}/", path)
+  };
+  let title = format!("Index of {}", escape(&base));
+  let mut out = format!(
+    "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n<body>\n<h1>{0}</h1>\n<table>\n",
+    title
+  );
+  if let Some((parent, _)) = base.trim_end_matches('/').rsplit_once('/') {
+    out.push_str(&format!(
+      "<tr><td><a href=\"{}/\">../</a></td><td></td><td></td></tr>\n",
+      escape(&utf8_percent_encode(parent, PATH).to_string())
+    ));
+  }
+  for entry in entries {
+    let suffix = if entry.dir { "/" } else { "" };
+    let href = format!(
+      "{}{}{}",
+      utf8_percent_encode(&base, PATH),
+      utf8_percent_encode(&entry.name, PATH),
+      suffix
+    );
+    let modified = entry
+      .modified
+      .map(|x| httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(x)))
+      .unwrap_or_default();
+    let size = if entry.dir {
+      String::new()
+    } else {
+      entry.size.to_string()
+    };
+    out.push_str(&format!(
+      "<tr><td><a href=\"{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+      escape(&href),
+      escape(&entry.name),
+      suffix,
+      size,
+      modified
+    ));
+  }
+  out.push_str("</table>\n</body>\n</html>\n");
+  out
+}
+
+fn escape(s: &str) -> String {
+  let mut out = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '&' => out.push_str("&amp;"),
+      '<' => out.push_str("&lt;"),
+      '>' => out.push_str("&gt;"),
+      '"' => out.push_str("&quot;"),
+      '\'' => out.push_str("&#39;"),
+      c => out.push(c),
+    }
+  }
+  out
+}
@@ -0,0 +1,169 @@
+//! Log output shared by the binaries.
+//!
+//! Events are written to stderr, so a downloaded body on stdout stays clean.
+//! Connections and streams are logged within spans, which lets operators
+//! filter and correlate events, e.g. with `--log-level qvpn::server=debug`.
+
+use std::{collections::VecDeque, fmt, io, str::FromStr, sync::Mutex};
+
+use serde::{de, Deserialize, Deserializer};
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+use crate::{QvpnError, Result};
+
+/// Filter used when neither a level nor `RUST_LOG` is given.
+pub const DEFAULT_LEVEL: &str = "info";
+
+/// Events kept for [`recent`].
+pub const RECENT_EVENTS: usize = 100;
+
+/// The last [`RECENT_EVENTS`] events logged, oldest first.
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// How log events are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+  /// Human readable lines.
+  #[default]
+  Text,
+  /// One JSON object per event, for log collectors.
+  Json,
+}
+
+impl FromStr for LogFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "text" => Ok(LogFormat::Text),
+      "json" => Ok(LogFormat::Json),
+      _ => Err(format!("unknown log format `{}`, expected text or json", s)),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for LogFormat {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for LogFormat {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LogFormat::Text => f.write_str("text"),
+      LogFormat::Json => f.write_str("json"),
+    }
+  }
+}
+
+/// Installs the global subscriber.
+///
+/// `level` is a filter such as `debug` or `info,qvpn::server=trace`. Without
+/// it, `RUST_LOG` is used if set, and [`DEFAULT_LEVEL`] otherwise.
+pub fn init(level: Option<&str>, format: LogFormat) -> Result<()> {
+  init_with(level, format, std::io::stderr, true)
+}
+
+/// Installs the global subscriber, writing events through `writer`, with
+/// colours if `ansi`.
+pub(crate) fn init_with<W>(
+  level: Option<&str>,
+  format: LogFormat,
+  writer: W,
+  ansi: bool,
+) -> Result<()>
+where
+  W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+  let filter = match level {
+    Some(level) => EnvFilter::try_new(level),
+    None => EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(DEFAULT_LEVEL)),
+  }
+  .map_err(|err| QvpnError::InvalidInput(format!("log level: {}", err)))?;
+  let builder = tracing_subscriber::fmt()
+    .with_env_filter(filter)
+    .with_ansi(ansi)
+    .with_writer(Tee(writer));
+  let result = match format {
+    LogFormat::Text => builder.try_init(),
+    LogFormat::Json => builder.json().try_init(),
+  };
+  result.map_err(|err| QvpnError::InvalidInput(format!("log: {}", err)))
+}
+
+/// The last [`RECENT_EVENTS`] events logged, oldest first, as written but
+/// without colours.
+pub fn recent() -> Vec<String> {
+  let recent = RECENT.lock().expect("recent events lock poisoned");
+  recent.iter().cloned().collect()
+}
+
+/// Writes events through the inner writer and remembers them for
+/// [`recent`].
+struct Tee<W>(W);
+
+impl<'a, W: MakeWriter<'a>> MakeWriter<'a> for Tee<W> {
+  type Writer = TeeWriter<W::Writer>;
+
+  fn make_writer(&'a self) -> Self::Writer {
+    TeeWriter {
+      inner: self.0.make_writer(),
+      event: vec![],
+    }
+  }
+}
+
+/// Writer for one event, remembered once it is dropped.
+struct TeeWriter<W: io::Write> {
+  inner: W,
+  event: Vec<u8>,
+}
+
+impl<W: io::Write> io::Write for TeeWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let len = self.inner.write(buf)?;
+    self.event.extend_from_slice(&buf[..len]);
+    Ok(len)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+impl<W: io::Write> Drop for TeeWriter<W> {
+  fn drop(&mut self) {
+    let event = strip_ansi(&String::from_utf8_lossy(&self.event));
+    let event = event.trim_end();
+    if event.is_empty() {
+      return;
+    }
+    let mut recent = RECENT.lock().expect("recent events lock poisoned");
+    if recent.len() == RECENT_EVENTS {
+      recent.pop_front();
+    }
+    recent.push_back(event.to_string());
+  }
+}
+
+/// `text` without the escape sequences that colour it.
+fn strip_ansi(text: &str) -> String {
+  let mut out = String::with_capacity(text.len());
+  let mut chars = text.chars();
+  while let Some(c) = chars.next() {
+    if c == '\x1b' {
+      // Skip to the final byte of the sequence, e.g. the `m` of `\x1b[2m`.
+      for c in chars.by_ref() {
+        if c.is_ascii_alphabetic() {
+          break;
+        }
+      }
+    } else {
+      out.push(c);
+    }
+  }
+  out
+}
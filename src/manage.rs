@@ -0,0 +1,229 @@
+//! Management API of a running server, on a local Unix socket.
+//!
+//! Requests and responses are JSON-RPC 2.0 objects, one per line. The
+//! methods are:
+//!
+//! - `clients`: the connected clients, as `[{"id", "remote"}]`.
+//! - `kick` with `{"id", "reason"}`: disconnects a client.
+//! - `stats`: the transfer [`Stats`] of every connection.
+//! - `reload`: re-reads the tunnel routes and resolvers from the config file
+//!   and pushes them to connected clients.
+//! - `rotate_certs`: reloads the certificate files now rather than at the
+//!   next poll.
+//!
+//! `qvpn ctl` is the client side.
+
+use std::{fs, io::ErrorKind, net::SocketAddr, path::Path, sync::Arc};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+  io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+  net::{UnixListener, UnixStream},
+};
+use tracing::{debug, info};
+
+use crate::{stats::Stats, QvpnError, Result};
+
+/// JSON-RPC error code for a method that doesn't exist.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// JSON-RPC error code for missing or malformed parameters.
+const INVALID_PARAMS: i64 = -32602;
+
+/// JSON-RPC error code for a request that isn't valid JSON-RPC.
+const INVALID_REQUEST: i64 = -32600;
+
+/// JSON-RPC error code for a method that failed.
+const SERVER_ERROR: i64 = -32000;
+
+/// A client connected to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+  /// Id to [`Handler::kick`] the client by.
+  pub id: usize,
+  /// Address of the client.
+  pub remote: SocketAddr,
+}
+
+/// What the management API can do to a running server.
+pub trait Handler: Send + Sync + 'static {
+  /// The connected clients.
+  fn clients(&self) -> Vec<ClientInfo>;
+
+  /// Disconnects client `id`, telling it `reason`. Returns whether it was
+  /// connected.
+  fn kick(&self, id: usize, reason: &str) -> bool;
+
+  /// Transfer statistics of every connection.
+  fn stats(&self) -> Vec<Stats>;
+
+  /// Re-reads the settings that can change while the server runs,
+  /// returning what they are now.
+  fn reload(&self) -> Result<Value>;
+
+  /// Reloads the certificate from its files.
+  fn rotate_certs(&self) -> Result<()>;
+}
+
+#[derive(Deserialize)]
+struct Request {
+  jsonrpc: String,
+  #[serde(default)]
+  id: Value,
+  method: String,
+  #[serde(default)]
+  params: Value,
+}
+
+#[derive(Deserialize)]
+struct KickParams {
+  id: usize,
+  reason: Option<String>,
+}
+
+/// Reason a kicked client is told when the request gives none.
+const DEFAULT_KICK_REASON: &str = "disconnected by the administrator";
+
+/// Binds the management socket at `path`, replacing a stale socket left
+/// by a previous run. Only the owner may connect.
+pub fn bind(path: &Path) -> Result<std::os::unix::net::UnixListener> {
+  match fs::remove_file(path) {
+    Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+    _ => {}
+  }
+  // The socket gets its permissions from the umask as it is created, so
+  // narrowing them afterwards would leave a moment in which anyone could
+  // connect. Files other threads create meanwhile only end up stricter.
+  let umask = unsafe { libc::umask(0o177) };
+  let listener = std::os::unix::net::UnixListener::bind(path);
+  unsafe { libc::umask(umask) };
+  let listener = listener?;
+  listener.set_nonblocking(true)?;
+  Ok(listener)
+}
+
+/// Answers management requests on `listener` until it fails.
+pub async fn serve(listener: UnixListener, handler: Arc<dyn Handler>) -> Result<()> {
+  loop {
+    let (stream, _) = listener.accept().await?;
+    let handler = handler.clone();
+    tokio::spawn(async move {
+      if let Err(err) = answer(stream, &*handler).await {
+        debug!("management client failed: {}", err);
+      }
+    });
+  }
+}
+
+/// Answers the requests on `stream`, one per line, until it is closed.
+async fn answer(stream: UnixStream, handler: &dyn Handler) -> Result<()> {
+  let (read, mut write) = stream.into_split();
+  let mut lines = BufReader::new(read).lines();
+  while let Some(line) = lines.next_line().await? {
+    if line.trim().is_empty() {
+      continue;
+    }
+    let response = match serde_json::from_str::<Request>(&line) {
+      Ok(request) if request.jsonrpc == "2.0" => {
+        let id = request.id.clone();
+        match dispatch(handler, request) {
+          Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+          Err((code, message)) => error_response(id, code, &message),
+        }
+      }
+      Ok(request) => error_response(request.id, INVALID_REQUEST, "expected jsonrpc 2.0"),
+      Err(err) => error_response(Value::Null, INVALID_REQUEST, &err.to_string()),
+    };
+    let mut response = response.to_string();
+    response.push('\n');
+    write.write_all(response.as_bytes()).await?;
+  }
+  Ok(())
+}
+
+fn dispatch(handler: &dyn Handler, request: Request) -> std::result::Result<Value, (i64, String)> {
+  let failed = |err: QvpnError| (SERVER_ERROR, err.to_string());
+  match request.method.as_str() {
+    "clients" => Ok(Value::Array(
+      handler
+        .clients()
+        .into_iter()
+        .map(|client| json!({"id": client.id, "remote": client.remote.to_string()}))
+        .collect(),
+    )),
+    "kick" => {
+      let params: KickParams =
+        serde_json::from_value(request.params).map_err(|err| (INVALID_PARAMS, err.to_string()))?;
+      let reason = params.reason.as_deref().unwrap_or(DEFAULT_KICK_REASON);
+      if !handler.kick(params.id, reason) {
+        return Err((SERVER_ERROR, format!("no client with id {}", params.id)));
+      }
+      info!(id = params.id, %reason, "kicked client");
+      Ok(Value::Bool(true))
+    }
+    "stats" => Ok(Value::Array(
+      handler.stats().iter().map(Stats::to_json).collect(),
+    )),
+    "reload" => {
+      let settings = handler.reload().map_err(failed)?;
+      info!("reloaded settings");
+      Ok(settings)
+    }
+    "rotate_certs" => {
+      handler.rotate_certs().map_err(failed)?;
+      info!("rotated certificate");
+      Ok(Value::Bool(true))
+    }
+    method => Err((METHOD_NOT_FOUND, format!("unknown method {}", method))),
+  }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+  json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+/// Calls `method` with `params` on the management socket at `path` and
+/// returns its result. An error the server answers with is returned as
+/// [`QvpnError::Remote`].
+pub async fn call(path: &Path, method: &str, params: Value) -> Result<Value> {
+  let stream = UnixStream::connect(path)
+    .await
+    .map_err(|err| QvpnError::Dial(path.display().to_string(), err))?;
+  let (read, mut write) = stream.into_split();
+  let mut request =
+    json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params}).to_string();
+  request.push('\n');
+  write.write_all(request.as_bytes()).await?;
+  let line = BufReader::new(read)
+    .lines()
+    .next_line()
+    .await?
+    .ok_or_else(|| QvpnError::Protocol("management socket closed without answering".into()))?;
+  let mut response: Value = serde_json::from_str(&line)
+    .map_err(|err| QvpnError::Protocol(format!("malformed management response: {}", err)))?;
+  if let Some(error) = response.get("error") {
+    let message = error["message"].as_str().unwrap_or("unknown error");
+    return Err(QvpnError::Remote(message.to_string()));
+  }
+  response
+    .get_mut("result")
+    .map(Value::take)
+    .ok_or_else(|| QvpnError::Protocol("management response without a result".into()))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::os::unix::fs::PermissionsExt;
+
+  use super::*;
+
+  #[test]
+  fn bind_creates_socket_only_the_owner_may_use() {
+    let path = std::env::temp_dir().join(format!("qvpn-manage-{}.sock", std::process::id()));
+    let _listener = bind(&path).unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(mode & 0o777, 0o600);
+  }
+}
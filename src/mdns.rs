@@ -0,0 +1,304 @@
+//! Peer discovery on the local network with mDNS and DNS-SD.
+//!
+//! Every peer advertises itself as an instance of [`SERVICE`], answering
+//! queries with the usual PTR, SRV, TXT and A records, and queries for the
+//! service every [`QUERY_INTERVAL`]. Instances found in the answers are
+//! reported so the peer can connect to them. Only the parts of the DNS wire
+//! format that DNS-SD needs are implemented here.
+
+use std::{
+  net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+  time::Duration,
+};
+
+use ring::rand::{self, SecureRandom};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::{net::UdpSocket, sync::mpsc};
+use tracing::{debug, info};
+
+use crate::Result;
+
+/// DNS-SD service type peers advertise.
+pub const SERVICE: &str = "_qvpn._udp.local";
+
+/// How often the network is asked for peers.
+pub const QUERY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// mDNS multicast group and port.
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+
+/// Seconds other hosts may cache our records.
+const TTL: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Tells caches to replace rather than add to records of a unique name.
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// Advertises the peer listening on `addr` and sends the addresses of peers
+/// found on the network to `discovered` until it is closed.
+pub async fn run(addr: SocketAddr, discovered: mpsc::UnboundedSender<SocketAddr>) -> Result<()> {
+  let socket = bind()?;
+  let mut suffix = [0; 4];
+  let _ = rand::SystemRandom::new().fill(&mut suffix);
+  let instance = format!(
+    "qvpn-{}-{:08x}.{}",
+    addr.port(),
+    u32::from_le_bytes(suffix),
+    SERVICE
+  );
+  let announcement = response(&instance, addr);
+  let target = SocketAddr::from((GROUP, PORT));
+  info!(%instance, "advertising over mDNS");
+  socket.send_to(&announcement, target).await?;
+
+  let mut queries = tokio::time::interval(QUERY_INTERVAL);
+  let mut buf = vec![0; 9000];
+  loop {
+    tokio::select! {
+      _ = queries.tick() => {
+        socket.send_to(&query(), target).await?;
+      }
+      received = socket.recv_from(&mut buf) => {
+        let (len, from) = received?;
+        let packet = &buf[..len];
+        if is_query_for_service(packet) {
+          debug!(%from, "answering mDNS query");
+          socket.send_to(&announcement, target).await?;
+        }
+        for found in instances(packet, from) {
+          if found.0 != instance {
+            debug!(instance = %found.0, addr = %found.1, "found peer over mDNS");
+            if discovered.send(found.1).is_err() {
+              return Ok(());
+            }
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Binds the mDNS port, sharing it with any responder already running.
+fn bind() -> Result<UdpSocket> {
+  let socket = Socket::new(Domain::ipv4(), Type::dgram(), Some(Protocol::udp()))?;
+  socket.set_reuse_address(true)?;
+  #[cfg(unix)]
+  socket.set_reuse_port(true)?;
+  socket.bind(&SockAddr::from(SocketAddrV4::new(
+    Ipv4Addr::UNSPECIFIED,
+    PORT,
+  )))?;
+  socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+  socket.set_multicast_loop_v4(true)?;
+  socket.set_nonblocking(true)?;
+  Ok(UdpSocket::from_std(socket.into_udp_socket())?)
+}
+
+/// A query for instances of the service.
+fn query() -> Vec<u8> {
+  let mut packet = header(0, 1, 0, 0);
+  write_name(&mut packet, SERVICE);
+  packet.extend(&TYPE_PTR.to_be_bytes());
+  packet.extend(&CLASS_IN.to_be_bytes());
+  packet
+}
+
+/// The records describing `instance`, listening on `addr`.
+fn response(instance: &str, addr: SocketAddr) -> Vec<u8> {
+  let host = format!("{}.local", instance.split('.').next().unwrap_or_default());
+  let mut packet = header(0x8400, 0, 1, 3);
+
+  let mut ptr = vec![];
+  write_name(&mut ptr, instance);
+  record(&mut packet, SERVICE, TYPE_PTR, CLASS_IN, &ptr);
+
+  let mut srv = vec![0, 0, 0, 0];
+  srv.extend(&addr.port().to_be_bytes());
+  write_name(&mut srv, &host);
+  record(
+    &mut packet,
+    instance,
+    TYPE_SRV,
+    CLASS_IN | CACHE_FLUSH,
+    &srv,
+  );
+
+  // DNS-SD requires a TXT record; an empty one is a single empty string.
+  record(
+    &mut packet,
+    instance,
+    TYPE_TXT,
+    CLASS_IN | CACHE_FLUSH,
+    &[0],
+  );
+
+  // Receivers use the packet's source address when this is unspecified.
+  let ip = match addr {
+    SocketAddr::V4(addr) => *addr.ip(),
+    SocketAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+  };
+  record(
+    &mut packet,
+    &host,
+    TYPE_A,
+    CLASS_IN | CACHE_FLUSH,
+    &ip.octets(),
+  );
+  packet
+}
+
+fn header(flags: u16, questions: u16, answers: u16, additional: u16) -> Vec<u8> {
+  let mut packet = vec![0, 0];
+  for x in [flags, questions, answers, 0, additional] {
+    packet.extend(&x.to_be_bytes());
+  }
+  packet
+}
+
+fn record(packet: &mut Vec<u8>, name: &str, kind: u16, class: u16, data: &[u8]) {
+  write_name(packet, name);
+  packet.extend(&kind.to_be_bytes());
+  packet.extend(&class.to_be_bytes());
+  packet.extend(&TTL.to_be_bytes());
+  packet.extend(&(data.len() as u16).to_be_bytes());
+  packet.extend(data);
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &str) {
+  for label in name.split('.').filter(|x| !x.is_empty()) {
+    buf.push(label.len().min(63) as u8);
+    buf.extend(&label.as_bytes()[..label.len().min(63)]);
+  }
+  buf.push(0);
+}
+
+/// Reads a possibly compressed name at `pos`, returning it and the position
+/// after it.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+  let mut labels = vec![];
+  let mut end = None;
+  // Bounds the pointers followed so a looping packet can't hang us.
+  for _ in 0..32 {
+    let len = *packet.get(pos)? as usize;
+    match len {
+      0 => {
+        return Some((labels.join("."), end.unwrap_or(pos + 1)));
+      }
+      x if x & 0xc0 == 0xc0 => {
+        let pointer = u16::from_be_bytes([*packet.get(pos)?, *packet.get(pos + 1)?]) & 0x3fff;
+        end.get_or_insert(pos + 2);
+        pos = pointer as usize;
+      }
+      _ => {
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += 1 + len;
+      }
+    }
+  }
+  None
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+  Some(u16::from_be_bytes([
+    *packet.get(pos)?,
+    *packet.get(pos + 1)?,
+  ]))
+}
+
+/// Whether `packet` is a query asking for instances of the service.
+fn is_query_for_service(packet: &[u8]) -> bool {
+  let questions = match (read_u16(packet, 2), read_u16(packet, 4)) {
+    (Some(flags), Some(questions)) if flags & 0x8000 == 0 => questions,
+    _ => return false,
+  };
+  let mut pos = 12;
+  for _ in 0..questions {
+    let (name, next) = match read_name(packet, pos) {
+      Some(x) => x,
+      None => return false,
+    };
+    let kind = read_u16(packet, next).unwrap_or_default();
+    if name.eq_ignore_ascii_case(SERVICE) && (kind == TYPE_PTR || kind == TYPE_ANY) {
+      return true;
+    }
+    pos = next + 4;
+  }
+  false
+}
+
+/// Instances of the service and their addresses described by the records of
+/// a response from `from`.
+fn instances(packet: &[u8], from: SocketAddr) -> Vec<(String, SocketAddr)> {
+  let counts = (4..12)
+    .step_by(2)
+    .map(|pos| read_u16(packet, pos).unwrap_or_default() as usize)
+    .collect::<Vec<_>>();
+  match read_u16(packet, 2) {
+    Some(flags) if flags & 0x8000 != 0 => {}
+    _ => return vec![],
+  }
+  let mut pos = 12;
+  for _ in 0..counts[0] {
+    match read_name(packet, pos) {
+      Some((_, next)) => pos = next + 4,
+      None => return vec![],
+    }
+  }
+  let (mut services, mut hosts) = (vec![], vec![]);
+  for _ in 0..counts[1] + counts[2] + counts[3] {
+    let (name, next) = match read_name(packet, pos) {
+      Some(x) => x,
+      None => break,
+    };
+    let (kind, len) = match (read_u16(packet, next), read_u16(packet, next + 8)) {
+      (Some(kind), Some(len)) => (kind, len as usize),
+      _ => break,
+    };
+    let data = next + 10;
+    if data + len > packet.len() {
+      break;
+    }
+    match kind {
+      TYPE_SRV if name.to_ascii_lowercase().ends_with(SERVICE) => {
+        if let (Some(port), Some((host, _))) =
+          (read_u16(packet, data + 4), read_name(packet, data + 6))
+        {
+          services.push((name, port, host));
+        }
+      }
+      TYPE_A if len == 4 => {
+        let ip = Ipv4Addr::new(
+          packet[data],
+          packet[data + 1],
+          packet[data + 2],
+          packet[data + 3],
+        );
+        hosts.push((name, ip));
+      }
+      _ => {}
+    }
+    pos = data + len;
+  }
+  services
+    .into_iter()
+    .map(|(name, port, host)| {
+      let ip = hosts
+        .iter()
+        .find(|(x, _)| x.eq_ignore_ascii_case(&host))
+        .map(|(_, ip)| *ip)
+        .filter(|ip| !ip.is_unspecified())
+        .unwrap_or(match from {
+          SocketAddr::V4(from) => *from.ip(),
+          SocketAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        });
+      (name, SocketAddr::from((ip, port)))
+    })
+    .collect()
+}
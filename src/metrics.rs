@@ -0,0 +1,350 @@
+//! Prometheus metrics for the server.
+//!
+//! [`serve`] answers `GET /metrics` over plain HTTP/1.1 with the counters in
+//! the text exposition format, for scraping by Prometheus or compatible
+//! agents.
+
+use std::{
+  collections::HashMap,
+  fmt::Write,
+  sync::{
+    atomic::{AtomicI64, AtomicU64, Ordering},
+    Arc, Mutex, OnceLock,
+  },
+};
+
+use tokio::{
+  io::AsyncWriteExt,
+  net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info};
+
+use crate::{
+  egress::Policy,
+  fragment, http,
+  site::{Health, Sites},
+  Result,
+};
+
+/// Distinct request paths tracked before further paths are counted as
+/// `other`, which bounds memory use when clients request random paths.
+pub const MAX_PATHS: usize = 1000;
+
+/// Counters updated by the server.
+#[derive(Debug, Default)]
+pub struct Metrics {
+  connections: AtomicU64,
+  handshake_failures: AtomicU64,
+  refused: AtomicU64,
+  open_streams: AtomicI64,
+  bytes_sent: AtomicU64,
+  bytes_received: AtomicU64,
+  file_cache_hits: AtomicU64,
+  file_cache_misses: AtomicU64,
+  file_cache_bytes: AtomicI64,
+  requests: Mutex<HashMap<(String, String), u64>>,
+  /// Site gateways whose health is rendered too, once the tunnel is up.
+  sites: OnceLock<Arc<Sites>>,
+  /// Egress rules whose hits are rendered too.
+  egress: OnceLock<Arc<Policy>>,
+}
+
+impl Metrics {
+  /// Counts an established connection.
+  pub fn connection_accepted(&self) {
+    self.connections.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Counts a connection that failed during the handshake.
+  pub fn handshake_failed(&self) {
+    self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Counts a connection refused for exceeding a rate limit.
+  pub fn connection_refused(&self) {
+    self.refused.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Counts a stream as open until the returned guard is dropped.
+  pub fn open_stream(self: &Arc<Self>) -> OpenStream {
+    self.open_streams.fetch_add(1, Ordering::Relaxed);
+    OpenStream(self.clone())
+  }
+
+  /// Counts bytes sent to clients.
+  pub fn sent(&self, bytes: u64) {
+    self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  /// Counts bytes received from clients.
+  pub fn received(&self, bytes: u64) {
+    self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  /// Counts a file served from the in-memory cache.
+  pub fn file_cache_hit(&self) {
+    self.file_cache_hits.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Counts a file small enough to cache that had to be read from disk,
+  /// and records the size of the cache after adding it.
+  pub fn file_cache_miss(&self, cache_size: u64) {
+    self.file_cache_misses.fetch_add(1, Ordering::Relaxed);
+    self
+      .file_cache_bytes
+      .store(cache_size as i64, Ordering::Relaxed);
+  }
+
+  /// Counts a request for `path` with `method`.
+  pub fn request(&self, method: &str, path: &str) {
+    let mut requests = self.requests.lock().expect("metrics lock poisoned");
+    let key = (method.to_string(), path.to_string());
+    let key = if requests.len() >= MAX_PATHS && !requests.contains_key(&key) {
+      (method.to_string(), "other".to_string())
+    } else {
+      key
+    };
+    *requests.entry(key).or_default() += 1;
+  }
+
+  /// Renders the health of each of `sites` along with the counters.
+  pub fn watch_sites(&self, sites: Arc<Sites>) {
+    let _ = self.sites.set(sites);
+  }
+
+  /// Renders the packets each rule of `policy` matched along with the
+  /// counters.
+  pub fn watch_egress(&self, policy: Arc<Policy>) {
+    let _ = self.egress.set(policy);
+  }
+
+  /// Renders all metrics in the Prometheus text format.
+  pub fn render(&self) -> String {
+    let mut out = String::new();
+    let fragments = fragment::counters();
+    let counters = [
+      (
+        "qvpn_connections_total",
+        "Connections accepted.",
+        "counter",
+        self.connections.load(Ordering::Relaxed) as i64,
+      ),
+      (
+        "qvpn_handshake_failures_total",
+        "Connections that failed during the handshake.",
+        "counter",
+        self.handshake_failures.load(Ordering::Relaxed) as i64,
+      ),
+      (
+        "qvpn_refused_connections_total",
+        "Connections refused for exceeding the per-address rate limit.",
+        "counter",
+        self.refused.load(Ordering::Relaxed) as i64,
+      ),
+      (
+        "qvpn_open_streams",
+        "Streams currently being handled.",
+        "gauge",
+        self.open_streams.load(Ordering::Relaxed),
+      ),
+      (
+        "qvpn_sent_bytes_total",
+        "Body and proxied bytes sent to clients.",
+        "counter",
+        self.bytes_sent.load(Ordering::Relaxed) as i64,
+      ),
+      (
+        "qvpn_received_bytes_total",
+        "Upload and proxied bytes received from clients.",
+        "counter",
+        self.bytes_received.load(Ordering::Relaxed) as i64,
+      ),
+      (
+        "qvpn_file_cache_hits_total",
+        "Files served from the in-memory cache.",
+        "counter",
+        self.file_cache_hits.load(Ordering::Relaxed) as i64,
+      ),
+      (
+        "qvpn_file_cache_misses_total",
+        "Files small enough to cache that were read from disk.",
+        "counter",
+        self.file_cache_misses.load(Ordering::Relaxed) as i64,
+      ),
+      (
+        "qvpn_file_cache_bytes",
+        "Bytes of file contents held in the in-memory cache.",
+        "gauge",
+        self.file_cache_bytes.load(Ordering::Relaxed),
+      ),
+      (
+        "qvpn_tunnel_fragmented_packets_total",
+        "Tunnel packets too large for a datagram that were sent in fragments.",
+        "counter",
+        fragments.fragmented as i64,
+      ),
+      (
+        "qvpn_tunnel_fragments_sent_total",
+        "Fragments those tunnel packets were sent in.",
+        "counter",
+        fragments.fragments as i64,
+      ),
+      (
+        "qvpn_tunnel_reassembled_packets_total",
+        "Tunnel packets received in fragments and reassembled.",
+        "counter",
+        fragments.reassembled as i64,
+      ),
+      (
+        "qvpn_tunnel_fragments_expired_total",
+        "Tunnel packets received in fragments that were dropped incomplete.",
+        "counter",
+        fragments.expired as i64,
+      ),
+    ];
+    for (name, help, kind, value) in counters {
+      let _ = writeln!(out, "# HELP {} {}", name, help);
+      let _ = writeln!(out, "# TYPE {} {}", name, kind);
+      let _ = writeln!(out, "{} {}", name, value);
+    }
+    let _ = writeln!(
+      out,
+      "# HELP qvpn_requests_total Requests by method and path."
+    );
+    let _ = writeln!(out, "# TYPE qvpn_requests_total counter");
+    let requests = self.requests.lock().expect("metrics lock poisoned");
+    let mut requests: Vec<_> = requests.iter().collect();
+    requests.sort();
+    for ((method, path), count) in requests {
+      let _ = writeln!(
+        out,
+        "qvpn_requests_total{{method=\"{}\",path=\"{}\"}} {}",
+        escape(method),
+        escape(path),
+        count
+      );
+    }
+    if let Some(sites) = self.sites.get() {
+      render_sites(&mut out, sites);
+    }
+    if let Some(policy) = self.egress.get().filter(|x| !x.is_empty()) {
+      let _ = writeln!(
+        out,
+        "# HELP qvpn_egress_rule_hits_total Tunnel packets an egress rule matched."
+      );
+      let _ = writeln!(out, "# TYPE qvpn_egress_rule_hits_total counter");
+      for (index, (rule, hits)) in policy.hits().into_iter().enumerate() {
+        let _ = writeln!(
+          out,
+          "qvpn_egress_rule_hits_total{{index=\"{}\",rule=\"{}\"}} {}",
+          index, rule, hits
+        );
+      }
+    }
+    out
+  }
+}
+
+/// Name, help text, type and value of a metric of each site.
+type SiteMetric = (
+  &'static str,
+  &'static str,
+  &'static str,
+  fn(&Health) -> String,
+);
+
+/// Renders the health of every site gateway, labelled by its tunnel address
+/// and the networks behind it.
+fn render_sites(out: &mut String, sites: &Sites) {
+  let health = sites.health();
+  let gauges: [SiteMetric; 4] = [
+    (
+      "qvpn_site_rtt_seconds",
+      "Round-trip time to a site gateway.",
+      "gauge",
+      |x| x.rtt.as_secs_f64().to_string(),
+    ),
+    (
+      "qvpn_site_sent_packets_total",
+      "Packets sent to a site gateway.",
+      "counter",
+      |x| x.sent_packets.to_string(),
+    ),
+    (
+      "qvpn_site_lost_packets_total",
+      "Packets sent to a site gateway that were lost.",
+      "counter",
+      |x| x.lost_packets.to_string(),
+    ),
+    (
+      "qvpn_site_up_seconds",
+      "How long a site gateway has been advertising its networks.",
+      "gauge",
+      |x| x.up.as_secs().to_string(),
+    ),
+  ];
+  for (name, help, kind, value) in gauges {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for site in &health {
+      let networks: Vec<_> = site.networks.iter().map(ToString::to_string).collect();
+      let _ = writeln!(
+        out,
+        "{}{{site=\"{}\",networks=\"{}\"}} {}",
+        name,
+        site.address,
+        networks.join(","),
+        value(site)
+      );
+    }
+  }
+}
+
+/// Guard returned by [`Metrics::open_stream`].
+#[derive(Debug)]
+pub struct OpenStream(Arc<Metrics>);
+
+impl Drop for OpenStream {
+  fn drop(&mut self) {
+    self.0.open_streams.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+/// Answers scrapes on `listener` until it fails.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> Result<()> {
+  info!(listen = %listener.local_addr()?, "metrics endpoint up");
+  loop {
+    let (tcp, peer) = listener.accept().await?;
+    let metrics = metrics.clone();
+    tokio::spawn(async move {
+      if let Err(err) = scrape(tcp, &metrics).await {
+        debug!(%peer, "metrics request failed: {}", err);
+      }
+    });
+  }
+}
+
+async fn scrape(mut tcp: TcpStream, metrics: &Metrics) -> Result<()> {
+  let (head, _) = http::read_until(&mut tcp, b"\r\n\r\n").await?;
+  let response = if head.starts_with(b"GET /metrics ") {
+    let body = metrics.render();
+    format!(
+      "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+      body.len(),
+      body
+    )
+  } else {
+    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+  };
+  tcp.write_all(response.as_bytes()).await?;
+  tcp.shutdown().await?;
+  Ok(())
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
@@ -0,0 +1,307 @@
+//! The file server's virtual tree.
+//!
+//! Each [`Mount`] serves a directory under a request path prefix, e.g.
+//! `/srv/public` under `/pub`, so `/pub/a.txt` is `/srv/public/a.txt`. A
+//! request is served by the mount with the longest prefix that contains
+//! it. Paths that only lead to mounts, like `/` with mounts at `/pub` and
+//! `/logs` and none at `/`, list the mounts below them.
+//!
+//! A mount can be read-only, refusing uploads, and can be limited to
+//! clients presenting one of a list of certificates.
+//!
+//! Request paths can't climb out of a mount with `..`, percent-encoded or
+//! not, but symlinks inside its directory can point anywhere. Unless the
+//! server follows symlinks, [`Mount::contains`] is checked before a path is
+//! served or written, so only links to targets inside the directory work.
+
+use std::path::{self, Path, PathBuf};
+
+use crate::{QvpnError, Result};
+
+/// A directory served under a request path prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+  prefix: String,
+  dir: PathBuf,
+  /// `dir` with symlinks resolved, once checked by [`Mounts::new`].
+  canonical: PathBuf,
+  readonly: bool,
+  clients: Vec<String>,
+}
+
+impl Mount {
+  /// Serves `dir` under the absolute request path `prefix`.
+  pub fn new(prefix: impl Into<String>, dir: impl Into<PathBuf>) -> Self {
+    Mount {
+      prefix: prefix.into(),
+      dir: dir.into(),
+      canonical: PathBuf::new(),
+      readonly: false,
+      clients: vec![],
+    }
+  }
+
+  /// Refuse uploads below the prefix.
+  pub fn readonly(mut self, readonly: bool) -> Self {
+    self.readonly = readonly;
+    self
+  }
+
+  /// Serve only clients presenting a certificate with one of these SHA-256
+  /// fingerprints, in hex with or without a `sha256:` prefix. Anyone may
+  /// use the mount when this is empty.
+  pub fn clients(mut self, fingerprints: Vec<String>) -> Self {
+    self.clients = fingerprints
+      .into_iter()
+      .map(|x| {
+        x.trim_start_matches("sha256:")
+          .replace(':', "")
+          .to_ascii_lowercase()
+      })
+      .collect();
+    self
+  }
+
+  /// The request path prefix.
+  pub fn prefix(&self) -> &str {
+    &self.prefix
+  }
+
+  /// The directory served.
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  /// Whether uploads are refused.
+  pub fn is_readonly(&self) -> bool {
+    self.readonly
+  }
+
+  /// Whether the client with the certificate `fingerprint`, if it presented
+  /// one, may use the mount.
+  pub fn allows(&self, fingerprint: Option<&str>) -> bool {
+    self.clients.is_empty() || fingerprint.is_some_and(|x| self.clients.iter().any(|y| x == y))
+  }
+
+  /// Whether `path` is inside the directory once symlinks are resolved. A
+  /// path that doesn't exist yet is judged by its nearest existing
+  /// ancestor. One that exists but can't be resolved, like a dangling
+  /// symlink, which a write would follow anywhere, is not inside.
+  pub async fn contains(&self, path: &Path) -> bool {
+    let mut path = path;
+    loop {
+      match tokio::fs::canonicalize(path).await {
+        Ok(real_path) => return real_path.starts_with(&self.canonical),
+        Err(_) if tokio::fs::symlink_metadata(path).await.is_ok() => return false,
+        Err(_) => match path.parent() {
+          Some(parent) => path = parent,
+          None => return false,
+        },
+      }
+    }
+  }
+
+  /// The rest of `path` below the prefix, if the mount contains it.
+  fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+    if self.prefix == "/" {
+      return Some(path);
+    }
+    let rest = path.strip_prefix(&self.prefix)?;
+    Some(rest).filter(|x| x.is_empty() || x.starts_with('/'))
+  }
+}
+
+/// Where a request path leads.
+#[derive(Debug)]
+pub enum Resolved<'a> {
+  /// A path in the directory of `mount`.
+  Path {
+    /// The mount containing the path.
+    mount: &'a Mount,
+    /// The path on disk.
+    real_path: PathBuf,
+  },
+  /// An ancestor of mounts, which aren't in any mount themselves. Holds the
+  /// names of the next component of each, sorted.
+  Mounts(Vec<String>),
+}
+
+/// The mounts of a server.
+#[derive(Debug)]
+pub struct Mounts {
+  /// Longest prefix first.
+  mounts: Vec<Mount>,
+}
+
+impl Mounts {
+  /// Checks that the prefixes are absolute, distinct paths without `.` or
+  /// `..` and that the directories exist. A trailing `/` is ignored.
+  pub fn new(mut mounts: Vec<Mount>) -> Result<Self> {
+    for mount in &mut mounts {
+      if mount.prefix.len() > 1 {
+        mount.prefix = mount.prefix.trim_end_matches('/').to_string();
+      }
+      let valid = mount.prefix.starts_with('/')
+        && (mount.prefix == "/"
+          || mount.prefix[1..]
+            .split('/')
+            .all(|x| !matches!(x, "" | "." | "..")));
+      if !valid {
+        return Err(QvpnError::InvalidInput(format!(
+          "mount prefix `{}` must be an absolute path",
+          mount.prefix
+        )));
+      }
+      if !mount.dir.is_dir() {
+        return Err(QvpnError::NotFound(mount.dir.clone()));
+      }
+      mount.canonical = mount.dir.canonicalize()?;
+    }
+    mounts.sort_by(|a, b| (b.prefix.len(), &a.prefix).cmp(&(a.prefix.len(), &b.prefix)));
+    if let Some(twice) = mounts.windows(2).find(|x| x[0].prefix == x[1].prefix) {
+      return Err(QvpnError::InvalidInput(format!(
+        "{} is mounted twice",
+        twice[0].prefix
+      )));
+    }
+    Ok(Mounts { mounts })
+  }
+
+  /// The mounts, longest prefix first.
+  pub fn iter(&self) -> impl Iterator<Item = &Mount> {
+    self.mounts.iter()
+  }
+
+  /// Maps the absolute request `path`.
+  pub fn resolve(&self, path: &str) -> Result<Resolved<'_>> {
+    if !path.starts_with('/') {
+      return Err(QvpnError::BadRequest("path must be absolute".into()));
+    }
+    for mount in &self.mounts {
+      if let Some(rest) = mount.strip(path) {
+        return Ok(Resolved::Path {
+          mount,
+          real_path: below(&mount.dir, rest)?,
+        });
+      }
+    }
+    let names = self.children(path);
+    if names.is_empty() {
+      return Err(QvpnError::NotFound(PathBuf::from(path)));
+    }
+    Ok(Resolved::Mounts(names))
+  }
+
+  /// The names of the next component of the mounts strictly below the
+  /// request `path`, sorted, so listings of `path` can include them.
+  pub fn children(&self, path: &str) -> Vec<String> {
+    let base = path.trim_end_matches('/');
+    let mut names: Vec<String> = self
+      .mounts
+      .iter()
+      .filter_map(|x| x.prefix.strip_prefix(base)?.strip_prefix('/'))
+      .filter(|rest| !rest.is_empty())
+      .map(|rest| rest.split('/').next().unwrap_or_default().to_string())
+      .collect();
+    names.sort();
+    names.dedup();
+    names
+  }
+}
+
+/// Maps the request path `rest`, relative to a mount, below `dir`.
+fn below(dir: &Path, rest: &str) -> Result<PathBuf> {
+  let mut real_path = dir.to_path_buf();
+  for c in Path::new(rest).components() {
+    match c {
+      path::Component::RootDir => {}
+      path::Component::Normal(x) => real_path.push(x),
+      x => {
+        return Err(QvpnError::BadRequest(format!(
+          "illegal component in path: {:?}",
+          x
+        )))
+      }
+    }
+  }
+  Ok(real_path)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::*;
+  use crate::http;
+
+  /// A fresh directory holding `root`, served at `/`, and `outside` next to
+  /// it.
+  fn scratch(name: &str) -> (PathBuf, Mounts) {
+    let base = std::env::temp_dir().join(format!("qvpn-mount-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(base.join("root/sub")).unwrap();
+    fs::create_dir_all(base.join("outside")).unwrap();
+    fs::write(base.join("root/sub/a.txt"), "a").unwrap();
+    fs::write(base.join("outside/secret.txt"), "secret").unwrap();
+    let mounts = Mounts::new(vec![Mount::new("/", base.join("root"))]).unwrap();
+    (base, mounts)
+  }
+
+  fn real_path<'a>(mounts: &'a Mounts, path: &str) -> Result<(&'a Mount, PathBuf)> {
+    match mounts.resolve(path)? {
+      Resolved::Path { mount, real_path } => Ok((mount, real_path)),
+      Resolved::Mounts(_) => panic!("{} leads to mounts", path),
+    }
+  }
+
+  #[test]
+  fn resolve_refuses_parent_components() {
+    let (base, mounts) = scratch("dotdot");
+    for path in [
+      "/../outside/secret.txt",
+      "/sub/../../outside/secret.txt",
+      "/sub/..",
+    ] {
+      assert!(real_path(&mounts, path).is_err(), "{}", path);
+    }
+    fs::remove_dir_all(base).unwrap();
+  }
+
+  #[test]
+  fn resolve_refuses_percent_encoded_parent_components() {
+    let (base, mounts) = scratch("encoded");
+    for path in [
+      "/%2e%2e/outside/secret.txt",
+      "/sub/%2E%2E/%2e%2e/outside/secret.txt",
+      "/sub/..%2f..%2foutside/secret.txt",
+    ] {
+      let decoded = http::decode_path(path.as_bytes()).unwrap();
+      assert!(real_path(&mounts, &decoded).is_err(), "{}", path);
+    }
+    fs::remove_dir_all(base).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn contains_refuses_symlinks_out_of_the_mount() {
+    let (base, mounts) = scratch("symlink");
+    let root = base.join("root");
+    std::os::unix::fs::symlink(base.join("outside"), root.join("out")).unwrap();
+    std::os::unix::fs::symlink(base.join("outside/new.txt"), root.join("dangling")).unwrap();
+    std::os::unix::fs::symlink(root.join("sub/a.txt"), root.join("inside")).unwrap();
+    for (path, inside) in [
+      ("/sub/a.txt", true),
+      ("/sub/new.txt", true),
+      ("/new/deeper.txt", true),
+      ("/inside", true),
+      ("/out/secret.txt", false),
+      ("/out/new.txt", false),
+      ("/dangling", false),
+      ("/dangling/deeper.txt", false),
+    ] {
+      let (mount, real_path) = real_path(&mounts, path).unwrap();
+      assert_eq!(mount.contains(&real_path).await, inside, "{}", path);
+    }
+    fs::remove_dir_all(base).unwrap();
+  }
+}
@@ -0,0 +1,90 @@
+//! Socket helpers shared by the client and server.
+
+use std::{
+  io,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tracing::debug;
+
+/// Any address on both IPv6 and IPv4, on `port`.
+pub fn dual_stack(port: u16) -> SocketAddr {
+  SocketAddr::from((Ipv6Addr::UNSPECIFIED, port))
+}
+
+/// Binds a UDP socket to `addr`.
+///
+/// The unspecified IPv6 address `[::]` accepts IPv4 as well, falling back to
+/// `0.0.0.0` on hosts without IPv6.
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+  match addr {
+    SocketAddr::V6(v6) if v6.ip().is_unspecified() => {
+      let socket =
+        Socket::new(Domain::ipv6(), Type::dgram(), Some(Protocol::udp())).and_then(|socket| {
+          socket.set_only_v6(false)?;
+          socket.bind(&SockAddr::from(addr))?;
+          Ok(socket)
+        });
+      match socket {
+        Ok(socket) => Ok(socket.into_udp_socket()),
+        Err(err) => {
+          debug!(%addr, "no IPv6, binding IPv4 only: {}", err);
+          UdpSocket::bind((Ipv4Addr::UNSPECIFIED, addr.port()))
+        }
+      }
+    }
+    _ => UdpSocket::bind(addr),
+  }
+}
+
+/// `addr` with an IPv4-mapped IPv6 address, as a dual-stack socket reports
+/// IPv4 peers, turned back into the IPv4 address.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+  match addr {
+    SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+      Some(ip) => SocketAddr::from((ip, v6.port())),
+      None => addr,
+    },
+    SocketAddr::V4(_) => addr,
+  }
+}
+
+/// Whether a socket bound to `local` can send to `remote`.
+pub fn reachable(local: SocketAddr, remote: SocketAddr) -> bool {
+  match local {
+    SocketAddr::V4(_) => remote.is_ipv4(),
+    SocketAddr::V6(v6) => v6.ip().is_unspecified() || remote.is_ipv6(),
+  }
+}
+
+/// Reorders `addrs` to alternate between address families, starting with
+/// the family of the first, so a broken family only delays every other
+/// connection attempt (RFC 8305, section 4).
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+  let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+  let (preferred, other): (Vec<_>, Vec<_>) = addrs
+    .into_iter()
+    .partition(|addr| addr.is_ipv6() == first_v6);
+  let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+  let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+  loop {
+    match (preferred.next(), other.next()) {
+      (None, None) => return interleaved,
+      (a, b) => interleaved.extend(a.into_iter().chain(b)),
+    }
+  }
+}
+
+/// Local IP address the host currently sends to `remote` from, found by
+/// connecting a UDP socket, which sends nothing.
+pub fn source_ip(remote: SocketAddr) -> io::Result<IpAddr> {
+  let remote = canonical(remote);
+  let any = match remote {
+    SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+    SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+  };
+  let socket = UdpSocket::bind(any)?;
+  socket.connect(remote)?;
+  Ok(socket.local_addr()?.ip())
+}
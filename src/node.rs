@@ -0,0 +1,342 @@
+//! Running qvpn inside another program.
+//!
+//! [`QvpnNode::spawn`] starts the peer-to-peer node of a [`Config`]'s
+//! `[peer]` section on tasks of the current runtime, along with a tunnel to
+//! the configured url if the config names a TUN interface. The program drives the node
+//! through the returned [`Handle`] and follows it on the [`EventStream`]:
+//! peers coming and going, the messages they send, the tunnel coming up and
+//! background errors. Pings and peer lists are taken care of by the node.
+
+use std::{
+  net::{Ipv4Addr, SocketAddr},
+  pin::Pin,
+  sync::{Arc, Mutex},
+  task::{Context, Poll},
+};
+
+use futures::Stream;
+use tokio::{
+  sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+  },
+  task::AbortHandle,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+  client::Event,
+  config::Config,
+  oidc::DeviceCode,
+  peer::{Peer, PeerHandle},
+  peer_manager::{PeerEvent, PeerState},
+  protocol::Message,
+  QvpnError, Result,
+};
+
+/// Events buffered for a program that doesn't keep up; the node waits for
+/// it past that.
+pub const EVENT_CAPACITY: usize = 1024;
+
+/// Something that happened to a running node.
+#[derive(Debug)]
+pub enum NodeEvent {
+  /// A peer connected.
+  PeerConnected(SocketAddr),
+  /// A peer disconnected.
+  PeerDisconnected(SocketAddr),
+  /// A peer proved another identity than the one it is known by, and was
+  /// disconnected.
+  PeerBanned(SocketAddr),
+  /// A message from `peer`, signed by the public key `origin` if that is
+  /// set.
+  Message {
+    peer: SocketAddr,
+    origin: Option<Vec<u8>>,
+    message: Message,
+  },
+  /// The tunnel through `remote` came up on `interface`, with the leased
+  /// tunnel address `address`. Sent again after every reconnect.
+  TunnelUp {
+    interface: String,
+    address: Ipv4Addr,
+    remote: SocketAddr,
+  },
+  /// The tunnel stopped for good, after any reconnect attempts.
+  TunnelDown,
+  /// The tunnel's OpenID Connect login waits for the user to enter the code
+  /// at the identity provider.
+  LoginRequired(DeviceCode),
+  /// Something failed in the background; the node keeps running.
+  Error(QvpnError),
+}
+
+/// Starts nodes.
+#[derive(Debug)]
+pub struct QvpnNode;
+
+impl QvpnNode {
+  /// Starts the node `config` describes: a peer if `config` has a `[peer]`
+  /// section, and a tunnel if it has a url and a `[tunnel]` interface name.
+  /// The event stream ends once the node stops. Peers connected to while
+  /// starting are not reported; ask [`PeerHandle::peers`] for them.
+  pub async fn spawn(config: Config) -> Result<(Handle, EventStream)> {
+    let tunnel = match (config.tun_config(), &config.client.url) {
+      (Some(tun), Some(url)) => Some((config.client_builder()?.build()?, url.clone(), tun)),
+      _ => None,
+    };
+    let peer = match config.peer {
+      Some(_) => Some(config.peer_builder()?.build().await?),
+      None if tunnel.is_some() => None,
+      None => {
+        return Err(QvpnError::InvalidInput(
+          "nothing to run: no [peer] section and no tunnel".into(),
+        ))
+      }
+    };
+    let (events, receiver) = mpsc::channel(EVENT_CAPACITY);
+    let handle = Handle {
+      peer: peer.as_ref().map(Peer::handle),
+      socket_addr: peer.as_ref().map(Peer::socket_addr),
+      local_addr: peer.as_ref().map(Peer::local_addr),
+      tasks: Arc::default(),
+    };
+    if let Some(node) = &handle.peer {
+      handle.start(watch_peers(node.events(), events.clone()));
+    }
+    if let Some((client, url, tun)) = tunnel {
+      let host = config.client.host.clone();
+      let mut client_events = client.events();
+      let forwarded = events.clone();
+      handle.start(async move {
+        loop {
+          let event = match client_events.recv().await {
+            Ok(Event::TunnelUp {
+              interface,
+              address,
+              remote,
+            }) => NodeEvent::TunnelUp {
+              interface,
+              address,
+              remote,
+            },
+            Ok(Event::LoginRequired(code)) => NodeEvent::LoginRequired(code),
+            Ok(event) => {
+              debug!(?event, "client event");
+              continue;
+            }
+            Err(RecvError::Lagged(missed)) => {
+              debug!(missed, "missed client events");
+              continue;
+            }
+            Err(RecvError::Closed) => break,
+          };
+          let _ = forwarded.send(event).await;
+        }
+      });
+      let events = events.clone();
+      handle.start(async move {
+        let result = client.tunnel(&url, host.as_deref(), &tun).await;
+        let _ = events.send(NodeEvent::TunnelDown).await;
+        if let Err(err) = result {
+          let _ = events.send(NodeEvent::Error(err)).await;
+        }
+      });
+    }
+    if let Some(peer) = peer {
+      let tasks = handle.tasks.clone();
+      let node = peer.handle();
+      handle.start(async move {
+        receive(peer, events).await;
+        // Nothing more to report once the node stops listening.
+        abort(&tasks);
+      });
+      if node.peer_count().await > 0 {
+        let sent = node.broadcast(&Message::PeerListRequest).await;
+        for (peer, err) in &sent.failed {
+          warn!(%peer, "asking for peers failed: {}", err);
+        }
+      }
+    }
+    Ok((handle, EventStream { receiver }))
+  }
+}
+
+/// Controls a running node. Clones control the same node.
+#[derive(Clone)]
+pub struct Handle {
+  peer: Option<PeerHandle>,
+  socket_addr: Option<SocketAddr>,
+  local_addr: Option<SocketAddr>,
+  tasks: Arc<Mutex<Vec<AbortHandle>>>,
+}
+
+impl Handle {
+  /// The peer-to-peer node, to send messages and manage peers with, unless
+  /// the config had no `[peer]` section.
+  pub fn peer(&self) -> Option<&PeerHandle> {
+    self.peer.as_ref()
+  }
+
+  /// Externally reachable address of the peer, if there is one.
+  pub fn socket_addr(&self) -> Option<SocketAddr> {
+    self.socket_addr
+  }
+
+  /// Local address the peer is bound to, if there is one.
+  pub fn local_addr(&self) -> Option<SocketAddr> {
+    self.local_addr
+  }
+
+  /// Stops the node and its tunnel, ending the event stream.
+  pub fn shutdown(&self) {
+    abort(&self.tasks);
+  }
+
+  fn start(&self, task: impl std::future::Future<Output = ()> + Send + 'static) {
+    let task = tokio::spawn(task);
+    self.tasks.lock().unwrap().push(task.abort_handle());
+  }
+}
+
+fn abort(tasks: &Mutex<Vec<AbortHandle>>) {
+  for task in tasks.lock().unwrap().drain(..) {
+    task.abort();
+  }
+}
+
+/// The events of a running node, ending once it stops.
+#[derive(Debug)]
+pub struct EventStream {
+  receiver: mpsc::Receiver<NodeEvent>,
+}
+
+impl EventStream {
+  /// Waits for the next event, `None` once the node stopped.
+  pub async fn next(&mut self) -> Option<NodeEvent> {
+    self.receiver.recv().await
+  }
+}
+
+impl Stream for EventStream {
+  type Item = NodeEvent;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<NodeEvent>> {
+    self.receiver.poll_recv(cx)
+  }
+}
+
+/// Reports peers connecting, disconnecting and being banned.
+async fn watch_peers(mut changes: broadcast::Receiver<PeerEvent>, events: mpsc::Sender<NodeEvent>) {
+  loop {
+    let event = match changes.recv().await {
+      Ok(PeerEvent::Changed { peer, state }) => match state {
+        PeerState::Connecting => continue,
+        PeerState::Connected => NodeEvent::PeerConnected(peer),
+        PeerState::Disconnected => NodeEvent::PeerDisconnected(peer),
+        PeerState::Banned => NodeEvent::PeerBanned(peer),
+      },
+      Ok(PeerEvent::Reaped(peer)) => {
+        debug!(%peer, "forgot peer");
+        continue;
+      }
+      Err(RecvError::Lagged(missed)) => {
+        debug!(missed, "missed peer events");
+        continue;
+      }
+      Err(RecvError::Closed) => break,
+    };
+    let _ = events.send(event).await;
+  }
+}
+
+/// Reports the messages peers send, answering pings and peer lists, until
+/// the node stops listening.
+async fn receive(mut peer: Peer, events: mpsc::Sender<NodeEvent>) {
+  let node = peer.handle();
+  while let Some((from, bytes)) = peer.next_message().await {
+    let messages = match Message::decode(&bytes) {
+      Ok(messages) => messages,
+      Err(err) => {
+        warn!(peer = %from, "dropping message: {}", err);
+        continue;
+      }
+    };
+    for message in messages {
+      let (origin, messages) = match message.open() {
+        Ok(opened) => opened,
+        Err(err) => {
+          warn!(peer = %from, "dropping message: {}", err);
+          continue;
+        }
+      };
+      for message in messages {
+        let event = match answer(&node, from, message).await {
+          Ok(Some(message)) => NodeEvent::Message {
+            peer: from,
+            origin: origin.clone(),
+            message,
+          },
+          Ok(None) => continue,
+          Err(err) => NodeEvent::Error(err),
+        };
+        let _ = events.send(event).await;
+      }
+    }
+  }
+  info!("node stopped listening");
+}
+
+/// Acts on the messages the node takes care of itself, returning the
+/// others.
+async fn answer(node: &PeerHandle, peer: SocketAddr, message: Message) -> Result<Option<Message>> {
+  debug!(%peer, ?message, "message");
+  match message {
+    Message::Ping => node.send(&Message::Pong, &peer).await?,
+    Message::Pong => info!(%peer, "pong"),
+    Message::PeerListRequest => {
+      let peers = node.peers().await;
+      node.send(&Message::PeerList { peers }, &peer).await?
+    }
+    Message::PeerList { peers } => {
+      info!(%peer, ?peers, "peer list");
+      node.learn_peers(peer, &peers).await
+    }
+    Message::Hello { .. }
+    | Message::Proof { .. }
+    | Message::Signed { .. }
+    | Message::Subscribe { .. }
+    | Message::Stored { .. }
+    | Message::Ack { .. }
+    | Message::Chunk { .. } => debug!(%peer, ?message, "ignoring out of place message"),
+    message => return Ok(Some(message)),
+  }
+  Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn spawns_peer_only_with_peer_section() {
+    match QvpnNode::spawn(Config::parse("").unwrap()).await {
+      Err(QvpnError::InvalidInput(_)) => {}
+      Err(err) => panic!("{}", err),
+      Ok(_) => panic!("spawned a node with nothing to run"),
+    }
+
+    let dir = std::env::temp_dir().join(format!("qvpn-node-{}", std::process::id()));
+    let config = Config::parse(&format!(
+      "[peer]\nlocal_ip = \"127.0.0.1\"\nidentity = {:?}\n[trust]\nknown_hosts = {:?}\n",
+      dir.join("identity.pk8"),
+      dir.join("known_hosts"),
+    ))
+    .unwrap();
+    let (handle, _events) = QvpnNode::spawn(config).await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(handle.peer().is_some());
+    assert!(handle.local_addr().unwrap().ip().is_loopback());
+    handle.shutdown();
+  }
+}
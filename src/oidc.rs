@@ -0,0 +1,659 @@
+//! OpenID Connect logins.
+//!
+//! Clients with a [`Login`] run the OAuth 2.0 device authorization flow
+//! against the identity provider: they report a [`DeviceCode`] for the user
+//! to enter in a browser, poll until the user approved, and present the ID
+//! token on the control stream ([`Message::Token`]). The server checks it
+//! with a [`Verifier`] against the provider's published keys (JWKS), the
+//! issuer, the audience and the expiry.
+//!
+//! The user a token logs in as is its `sub` claim by default, which the
+//! provider never reassigns, rather than a name or email that users may be
+//! able to change to someone else's. Servers that key users on another
+//! claim pick it with [`Verifier::claim`].
+//!
+//! Both sides find the provider's endpoints through OpenID Connect
+//! discovery. The provider must be reached over HTTPS, or HTTP on a loopback
+//! address for testing. Tokens are kept in memory only, so every run of a
+//! client logs in afresh; reconnects reuse the token, refreshed if needed.
+//!
+//! [`Message::Token`]: crate::control::Message::Token
+
+use std::{
+  convert::TryFrom,
+  io::{self, Read, Write},
+  net::{TcpStream, ToSocketAddrs},
+  sync::Arc,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+use tokio::sync::{Mutex, OnceCell};
+use tracing::debug;
+use url::{form_urlencoded, Url};
+
+use crate::{http::ResponseHead, tls, QvpnError, Result};
+
+/// How long a request to the identity provider may take.
+pub const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest response accepted from the identity provider.
+pub const MAX_RESPONSE: usize = 1024 * 1024;
+
+/// Clock skew tolerated when checking token times.
+pub const LEEWAY: Duration = Duration::from_secs(60);
+
+/// Claim naming the user a token logs in as, unless configured otherwise.
+pub const DEFAULT_CLAIM: &str = "sub";
+
+/// Shortest time between two fetches of the provider's keys, so tokens
+/// with unknown key ids can't make the server hammer the provider.
+pub const JWKS_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Endpoints of an identity provider.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Provider {
+  /// Issuer that tokens name in their `iss` claim.
+  pub issuer: String,
+  /// Where the provider's signing keys are published.
+  pub jwks_uri: Url,
+  /// Where device codes are requested.
+  pub device_authorization_endpoint: Option<Url>,
+  /// Where tokens are requested.
+  pub token_endpoint: Url,
+}
+
+impl Provider {
+  /// Reads the discovery document of `issuer`.
+  pub async fn discover(issuer: &Url) -> Result<Self> {
+    let mut url = issuer.clone();
+    let path = format!(
+      "{}/.well-known/openid-configuration",
+      url.path().trim_end_matches('/')
+    );
+    url.set_path(&path);
+    get_json(&url).await
+  }
+}
+
+/// Code the user enters at the provider to approve a device login.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceCode {
+  /// Page where the user enters the code.
+  pub verification_uri: String,
+  /// Page that has the code filled in already, if the provider offers one.
+  pub verification_uri_complete: Option<String>,
+  /// Code to enter.
+  pub user_code: String,
+}
+
+#[derive(Deserialize)]
+struct DeviceAuthorization {
+  device_code: String,
+  user_code: String,
+  verification_uri: String,
+  verification_uri_complete: Option<String>,
+  expires_in: u64,
+  #[serde(default = "default_interval")]
+  interval: u64,
+}
+
+fn default_interval() -> u64 {
+  5
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+  id_token: Option<String>,
+  refresh_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenError {
+  error: String,
+  error_description: Option<String>,
+}
+
+/// An ID token and what it takes to renew it.
+#[derive(Clone)]
+struct Token {
+  id_token: String,
+  refresh_token: Option<String>,
+  expires: SystemTime,
+}
+
+/// Device flow logins of a client. Cloning it shares the same token.
+#[derive(Clone)]
+pub struct Login {
+  issuer: Url,
+  client_id: String,
+  scopes: Vec<String>,
+  provider: Arc<OnceCell<Provider>>,
+  token: Arc<Mutex<Option<Token>>>,
+}
+
+impl std::fmt::Debug for Login {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Login")
+      .field("issuer", &self.issuer.as_str())
+      .field("client_id", &self.client_id)
+      .finish_non_exhaustive()
+  }
+}
+
+impl Login {
+  /// Logs in at the provider `issuer` as the OAuth client `client_id`,
+  /// asking for the `openid` scope.
+  pub fn new(issuer: Url, client_id: impl Into<String>) -> Self {
+    Login {
+      issuer,
+      client_id: client_id.into(),
+      scopes: vec!["openid".into()],
+      provider: Arc::default(),
+      token: Arc::default(),
+    }
+  }
+
+  /// Also asks for these scopes, e.g. `profile` or `email` for the claims
+  /// the server names users by.
+  pub fn scopes(mut self, scopes: Vec<String>) -> Self {
+    self.scopes.extend(scopes);
+    self
+  }
+
+  /// A valid ID token: the one from an earlier login, renewed with its
+  /// refresh token once it expires, or a new one from a device login, for
+  /// which the user is shown the code with `prompt`.
+  pub async fn id_token(&self, prompt: impl FnOnce(&DeviceCode)) -> Result<String> {
+    let provider = self
+      .provider
+      .get_or_try_init(|| Provider::discover(&self.issuer))
+      .await?;
+    let mut token = self.token.lock().await;
+    if let Some(current) = token.as_ref() {
+      if current.expires > SystemTime::now() + LEEWAY {
+        return Ok(current.id_token.clone());
+      }
+      if let Some(refresh) = &current.refresh_token {
+        match self.refresh(provider, refresh).await {
+          Ok(renewed) => {
+            debug!("refreshed the ID token");
+            let id_token = renewed.id_token.clone();
+            *token = Some(renewed);
+            return Ok(id_token);
+          }
+          Err(err) => debug!("couldn't refresh the ID token: {}", err),
+        }
+      }
+    }
+    let fresh = self.device_login(provider, prompt).await?;
+    let id_token = fresh.id_token.clone();
+    *token = Some(fresh);
+    Ok(id_token)
+  }
+
+  async fn device_login(
+    &self,
+    provider: &Provider,
+    prompt: impl FnOnce(&DeviceCode),
+  ) -> Result<Token> {
+    let endpoint = provider
+      .device_authorization_endpoint
+      .as_ref()
+      .ok_or_else(|| {
+        QvpnError::Unsupported("the identity provider doesn't offer device logins".into())
+      })?;
+    let scope = self.scopes.join(" ");
+    let authorization: DeviceAuthorization = post_form(
+      endpoint,
+      &[("client_id", &self.client_id), ("scope", &scope)],
+    )
+    .await
+    .and_then(|(status, body)| json_body(status, &body))?;
+    prompt(&DeviceCode {
+      verification_uri: authorization.verification_uri,
+      verification_uri_complete: authorization.verification_uri_complete,
+      user_code: authorization.user_code,
+    });
+    let deadline = Instant::now() + Duration::from_secs(authorization.expires_in);
+    let mut interval = Duration::from_secs(authorization.interval);
+    loop {
+      tokio::time::sleep(interval).await;
+      if Instant::now() > deadline {
+        return Err(QvpnError::Unauthenticated(
+          "the device code expired before the login was approved".into(),
+        ));
+      }
+      let (status, body) = post_form(
+        &provider.token_endpoint,
+        &[
+          ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+          ("device_code", &authorization.device_code),
+          ("client_id", &self.client_id),
+        ],
+      )
+      .await?;
+      if (200..300).contains(&status) {
+        return token(&body);
+      }
+      let error: TokenError = json_body(200, &body)?;
+      match error.error.as_str() {
+        "authorization_pending" => {}
+        "slow_down" => interval += Duration::from_secs(5),
+        _ => return Err(token_error(error)),
+      }
+    }
+  }
+
+  async fn refresh(&self, provider: &Provider, refresh_token: &str) -> Result<Token> {
+    let (status, body) = post_form(
+      &provider.token_endpoint,
+      &[
+        ("grant_type", "refresh_token"),
+        ("refresh_token", refresh_token),
+        ("client_id", &self.client_id),
+      ],
+    )
+    .await?;
+    if !(200..300).contains(&status) {
+      return Err(token_error(json_body(200, &body)?));
+    }
+    let mut renewed = token(&body)?;
+    // Providers may keep the refresh token the same and not repeat it.
+    renewed.refresh_token = renewed.refresh_token.or_else(|| Some(refresh_token.into()));
+    Ok(renewed)
+  }
+}
+
+fn token(body: &[u8]) -> Result<Token> {
+  let response: TokenResponse = json_body(200, body)?;
+  let id_token = response
+    .id_token
+    .ok_or_else(|| QvpnError::Protocol("the token response has no ID token".into()))?;
+  let claims = decode(&id_token)?.claims;
+  let expires = claims["exp"]
+    .as_u64()
+    .map(|exp| UNIX_EPOCH + Duration::from_secs(exp))
+    .ok_or_else(|| QvpnError::Protocol("the ID token has no expiry".into()))?;
+  Ok(Token {
+    id_token,
+    refresh_token: response.refresh_token,
+    expires,
+  })
+}
+
+fn token_error(error: TokenError) -> QvpnError {
+  QvpnError::Unauthenticated(match error.error_description {
+    Some(description) => format!("{}: {}", error.error, description),
+    None => error.error,
+  })
+}
+
+/// Checks ID tokens issued by one provider for one audience.
+pub struct Verifier {
+  issuer: Url,
+  audience: String,
+  claim: String,
+  provider: OnceCell<Provider>,
+  keys: Mutex<Keys>,
+}
+
+impl std::fmt::Debug for Verifier {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Verifier")
+      .field("issuer", &self.issuer.as_str())
+      .field("audience", &self.audience)
+      .field("claim", &self.claim)
+      .finish_non_exhaustive()
+  }
+}
+
+#[derive(Default)]
+struct Keys {
+  keys: Vec<Jwk>,
+  fetched: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Jwk {
+  kty: String,
+  kid: Option<String>,
+  n: Option<String>,
+  e: Option<String>,
+  crv: Option<String>,
+  x: Option<String>,
+  y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+  keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+  alg: String,
+  kid: Option<String>,
+}
+
+/// A JWT split into its parts.
+struct Jwt<'a> {
+  header: Header,
+  claims: Value,
+  signed: &'a str,
+  signature: Vec<u8>,
+}
+
+impl Verifier {
+  /// Accepts ID tokens that `issuer` signed for `audience`, the OAuth
+  /// client id of the qvpn clients. The provider is only contacted once
+  /// the first token arrives.
+  pub fn new(issuer: Url, audience: impl Into<String>) -> Self {
+    Verifier {
+      issuer,
+      audience: audience.into(),
+      claim: DEFAULT_CLAIM.into(),
+      provider: OnceCell::new(),
+      keys: Mutex::default(),
+    }
+  }
+
+  /// Takes the user a token logs in as from the string claim `claim`, e.g.
+  /// `preferred_username` or `email`, instead of [`DEFAULT_CLAIM`]. Only
+  /// pick a claim the provider doesn't let users set to someone else's.
+  pub fn claim(mut self, claim: impl Into<String>) -> Self {
+    self.claim = claim.into();
+    self
+  }
+
+  /// Checks `token`, returning the user it names in the configured claim,
+  /// `sub` by default.
+  pub async fn verify(&self, token: &str) -> Result<String> {
+    let provider = self
+      .provider
+      .get_or_try_init(|| Provider::discover(&self.issuer))
+      .await?;
+    let jwt = decode(token)?;
+    let key = self.key(provider, jwt.header.kid.as_deref()).await?;
+    verify_signature(&jwt, &key)?;
+    let claims = &jwt.claims;
+    if claims["iss"].as_str() != Some(provider.issuer.as_str()) {
+      return Err(invalid("issued by another provider"));
+    }
+    let audience = &claims["aud"];
+    let for_us = match audience {
+      Value::String(aud) => *aud == self.audience,
+      Value::Array(auds) => auds.iter().any(|x| x.as_str() == Some(&self.audience)),
+      _ => false,
+    };
+    if !for_us {
+      return Err(invalid("issued for another audience"));
+    }
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default();
+    match claims["exp"].as_u64() {
+      Some(exp) if now < Duration::from_secs(exp) + LEEWAY => {}
+      _ => return Err(invalid("expired")),
+    }
+    if let Some(nbf) = claims["nbf"].as_u64() {
+      if now + LEEWAY < Duration::from_secs(nbf) {
+        return Err(invalid("not valid yet"));
+      }
+    }
+    claims[self.claim.as_str()]
+      .as_str()
+      .map(str::to_string)
+      .ok_or_else(|| invalid(&format!("has no {} claim", self.claim)))
+  }
+
+  /// The key with id `kid`, or the only key if the token names none.
+  /// Fetches the keys again if none matches, at most every
+  /// [`JWKS_REFETCH_INTERVAL`].
+  async fn key(&self, provider: &Provider, kid: Option<&str>) -> Result<Jwk> {
+    let mut keys = self.keys.lock().await;
+    let find = |keys: &[Jwk]| match kid {
+      Some(kid) => keys
+        .iter()
+        .find(|key| key.kid.as_deref() == Some(kid))
+        .cloned(),
+      None if keys.len() == 1 => keys.first().cloned(),
+      None => None,
+    };
+    if let Some(key) = find(&keys.keys) {
+      return Ok(key);
+    }
+    if keys
+      .fetched
+      .is_some_and(|at| at.elapsed() < JWKS_REFETCH_INTERVAL)
+    {
+      return Err(invalid("signed with an unknown key"));
+    }
+    let jwks: Jwks = get_json(&provider.jwks_uri).await?;
+    debug!(
+      keys = jwks.keys.len(),
+      "fetched the identity provider's keys"
+    );
+    keys.keys = jwks.keys;
+    keys.fetched = Some(Instant::now());
+    find(&keys.keys).ok_or_else(|| invalid("signed with an unknown key"))
+  }
+}
+
+fn invalid(reason: &str) -> QvpnError {
+  QvpnError::Unauthenticated(format!("token {}", reason))
+}
+
+/// Splits `token` and decodes its header and claims, without checking
+/// anything.
+fn decode(token: &str) -> Result<Jwt<'_>> {
+  let malformed = || QvpnError::Unauthenticated("malformed token".into());
+  let (signed, signature) = token.rsplit_once('.').ok_or_else(malformed)?;
+  let (header, claims) = signed.split_once('.').ok_or_else(malformed)?;
+  let part = |part: &str| URL_SAFE_NO_PAD.decode(part).map_err(|_| malformed());
+  Ok(Jwt {
+    header: serde_json::from_slice(&part(header)?).map_err(|_| malformed())?,
+    claims: serde_json::from_slice(&part(claims)?).map_err(|_| malformed())?,
+    signed,
+    signature: part(signature)?,
+  })
+}
+
+fn verify_signature(jwt: &Jwt<'_>, key: &Jwk) -> Result<()> {
+  let field = |value: &Option<String>| {
+    value
+      .as_deref()
+      .and_then(|x| URL_SAFE_NO_PAD.decode(x).ok())
+      .ok_or_else(|| invalid("signed with a malformed key"))
+  };
+  let message = jwt.signed.as_bytes();
+  let verified = match (jwt.header.alg.as_str(), key.kty.as_str()) {
+    (alg @ ("RS256" | "RS384" | "RS512"), "RSA") => {
+      let params = match alg {
+        "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+        "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+        _ => &signature::RSA_PKCS1_2048_8192_SHA512,
+      };
+      let (n, e) = (field(&key.n)?, field(&key.e)?);
+      RsaPublicKeyComponents { n: &n, e: &e }.verify(params, message, &jwt.signature)
+    }
+    (alg @ ("ES256" | "ES384"), "EC") => {
+      let (params, crv) = match alg {
+        "ES256" => (&signature::ECDSA_P256_SHA256_FIXED, "P-256"),
+        _ => (&signature::ECDSA_P384_SHA384_FIXED, "P-384"),
+      };
+      if key.crv.as_deref() != Some(crv) {
+        return Err(invalid("signed with a key of another curve"));
+      }
+      let mut point = vec![4];
+      point.extend(field(&key.x)?);
+      point.extend(field(&key.y)?);
+      UnparsedPublicKey::new(params, point).verify(message, &jwt.signature)
+    }
+    (alg, _) => {
+      return Err(QvpnError::Unauthenticated(format!(
+        "token signed with unsupported algorithm {}",
+        alg
+      )))
+    }
+  };
+  verified.map_err(|_| invalid("signature is invalid"))
+}
+
+/// Fetches `url` and parses its JSON body.
+async fn get_json<T: DeserializeOwned>(url: &Url) -> Result<T> {
+  let (status, body) = request(url, None).await?;
+  json_body(status, &body)
+}
+
+/// Posts `form` to `url`, returning the status and body.
+async fn post_form(url: &Url, form: &[(&str, &str)]) -> Result<(u16, Vec<u8>)> {
+  let body = form_urlencoded::Serializer::new(String::new())
+    .extend_pairs(form)
+    .finish();
+  request(url, Some(body)).await
+}
+
+fn json_body<T: DeserializeOwned>(status: u16, body: &[u8]) -> Result<T> {
+  if !(200..300).contains(&status) {
+    return Err(QvpnError::Remote(format!(
+      "identity provider answered {}: {}",
+      status,
+      String::from_utf8_lossy(body)
+    )));
+  }
+  serde_json::from_slice(body)
+    .map_err(|err| QvpnError::Protocol(format!("malformed identity provider response: {}", err)))
+}
+
+/// Sends a `GET`, or a `POST` of `form` if given, to `url`. The requests
+/// are few and short, so they block a thread of tokio's pool rather than
+/// pull in an async HTTP client.
+async fn request(url: &Url, form: Option<String>) -> Result<(u16, Vec<u8>)> {
+  let url = url.clone();
+  tokio::task::spawn_blocking(move || request_blocking(&url, form.as_deref()))
+    .await
+    .map_err(|err| QvpnError::Io(io::Error::other(err)))?
+}
+
+fn request_blocking(url: &Url, form: Option<&str>) -> Result<(u16, Vec<u8>)> {
+  let host = url
+    .host_str()
+    .ok_or_else(|| QvpnError::InvalidInput(format!("{} names no host", url)))?;
+  let port = url.port_or_known_default().unwrap_or(443);
+  let addrs: Vec<_> = (host, port).to_socket_addrs()?.collect();
+  let tls = match url.scheme() {
+    "https" => true,
+    "http" if addrs.iter().all(|addr| addr.ip().is_loopback()) => false,
+    _ => return Err(QvpnError::InvalidInput(format!("{} must use https", url))),
+  };
+  let addr = addrs
+    .first()
+    .ok_or_else(|| QvpnError::Dial(host.into(), io::ErrorKind::NotFound.into()))?;
+  let tcp = TcpStream::connect_timeout(addr, HTTP_TIMEOUT)
+    .map_err(|err| QvpnError::Dial(host.into(), err))?;
+  tcp.set_read_timeout(Some(HTTP_TIMEOUT))?;
+  tcp.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+  let mut target = url.path().to_string();
+  if let Some(query) = url.query() {
+    target.push('?');
+    target.push_str(query);
+  }
+  let mut req = format!(
+    "{} {} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n",
+    if form.is_some() { "POST" } else { "GET" },
+    target,
+    host
+  );
+  if let Some(form) = form {
+    req.push_str("Content-Type: application/x-www-form-urlencoded\r\n");
+    req.push_str(&format!("Content-Length: {}\r\n\r\n{}", form.len(), form));
+  } else {
+    req.push_str("\r\n");
+  }
+
+  let response = if tls {
+    let config = rustls::ClientConfig::builder_with_provider(tls::provider())
+      .with_safe_default_protocol_versions()?
+      .with_root_certificates(tls::native_roots()?)
+      .with_no_client_auth();
+    let name = rustls::pki_types::ServerName::try_from(host.to_string())
+      .map_err(|_| QvpnError::InvalidInput(format!("invalid host name {}", host)))?;
+    let conn = rustls::ClientConnection::new(Arc::new(config), name)?;
+    exchange(rustls::StreamOwned::new(conn, tcp), &req)?
+  } else {
+    exchange(tcp, &req)?
+  };
+  parse_response(&response)
+}
+
+/// Writes `req` and reads the response until the server closes the
+/// connection.
+fn exchange(mut stream: impl Read + Write, req: &str) -> Result<Vec<u8>> {
+  stream.write_all(req.as_bytes())?;
+  stream.flush()?;
+  let mut response = vec![];
+  let mut chunk = [0; 8192];
+  loop {
+    match stream.read(&mut chunk) {
+      Ok(0) => break,
+      Ok(len) => response.extend_from_slice(&chunk[..len]),
+      // Servers often close without a TLS close_notify after a complete
+      // response.
+      Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && !response.is_empty() => break,
+      Err(err) => return Err(err.into()),
+    }
+    if response.len() > MAX_RESPONSE {
+      return Err(QvpnError::Protocol(
+        "identity provider response too large".into(),
+      ));
+    }
+  }
+  Ok(response)
+}
+
+fn parse_response(response: &[u8]) -> Result<(u16, Vec<u8>)> {
+  let end = response
+    .windows(4)
+    .position(|x| x == b"\r\n\r\n")
+    .ok_or_else(|| QvpnError::Protocol("identity provider response cut short".into()))?;
+  let head = ResponseHead::decode(&response[..end + 4])?;
+  let body = &response[end + 4..];
+  let chunked = head
+    .get("Transfer-Encoding")
+    .is_some_and(|x| x.eq_ignore_ascii_case("chunked"));
+  let body = if chunked {
+    dechunk(body)?
+  } else {
+    match head.content_length() {
+      Some(len) => body.get(..len as usize).unwrap_or(body).to_vec(),
+      None => body.to_vec(),
+    }
+  };
+  Ok((head.status, body))
+}
+
+/// Joins a chunked transfer encoded body.
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>> {
+  let malformed = || QvpnError::Protocol("malformed chunked identity provider response".into());
+  let mut out = vec![];
+  loop {
+    let line_end = body
+      .windows(2)
+      .position(|x| x == b"\r\n")
+      .ok_or_else(malformed)?;
+    let size = std::str::from_utf8(&body[..line_end]).map_err(|_| malformed())?;
+    let size = size.split(';').next().unwrap_or_default().trim();
+    let size = usize::from_str_radix(size, 16).map_err(|_| malformed())?;
+    body = &body[line_end + 2..];
+    if size == 0 {
+      return Ok(out);
+    }
+    out.extend_from_slice(body.get(..size).ok_or_else(malformed)?);
+    body = body.get(size + 2..).ok_or_else(malformed)?;
+  }
+}
@@ -0,0 +1,221 @@
+//! Messages kept for peers that are offline.
+//!
+//! With an outbox configured, a message for a known peer that isn't
+//! connected is written to a JSON file instead of being sent, and delivered
+//! in a [`Message::Stored`] once the peer is back. The receiver answers with
+//! a [`Message::Ack`], upon which the message is forgotten; until then it is
+//! delivered again on every reconnect, and receivers drop the ids they have
+//! seen. Messages are kept for a limited time and a limited number of bytes.
+//!
+//! [`Message::Stored`]: crate::protocol::Message::Stored
+//! [`Message::Ack`]: crate::protocol::Message::Ack
+
+use std::{
+  collections::{HashSet, VecDeque},
+  fs,
+  io::{self, Write},
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{topics, QvpnError, Result};
+
+/// Bytes of stored messages kept by default.
+pub const DEFAULT_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+/// How long stored messages are kept by default.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// Ids of delivered stored messages remembered to drop repeats.
+pub const RECEIVED_CAPACITY: usize = 4096;
+
+/// How much an outbox keeps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboxLimits {
+  /// Bytes of messages, as written to the file.
+  pub max_bytes: u64,
+  /// Time after which a message is dropped undelivered.
+  pub ttl: Duration,
+}
+
+impl Default for OutboxLimits {
+  fn default() -> Self {
+    OutboxLimits {
+      max_bytes: DEFAULT_MAX_BYTES,
+      ttl: DEFAULT_TTL,
+    }
+  }
+}
+
+/// A message waiting for its peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+  id: u64,
+  peer: SocketAddr,
+  /// Seconds since the Unix epoch when the message was stored.
+  stored_at: u64,
+  /// The encoded message, base64 encoded.
+  body: String,
+}
+
+/// Messages for offline peers, backed by a file.
+#[derive(Debug)]
+pub struct Outbox {
+  path: PathBuf,
+  limits: OutboxLimits,
+  entries: Mutex<Vec<Entry>>,
+}
+
+impl Outbox {
+  /// Opens the outbox at `path`. A missing or unreadable file starts an
+  /// empty outbox.
+  pub fn open(path: impl Into<PathBuf>, limits: OutboxLimits) -> Arc<Self> {
+    let path = path.into();
+    let entries = match load(&path) {
+      Ok(entries) => entries,
+      Err(err) => {
+        if err.kind() != io::ErrorKind::NotFound {
+          warn!(path = %path.display(), "ignoring outbox: {}", err);
+        }
+        vec![]
+      }
+    };
+    debug!(path = %path.display(), messages = entries.len(), "outbox opened");
+    Arc::new(Outbox {
+      path,
+      limits,
+      entries: Mutex::new(entries),
+    })
+  }
+
+  /// Keeps `msg` for `peer`, returning its id. Fails if it doesn't fit.
+  pub fn store(&self, peer: SocketAddr, msg: &Bytes) -> Result<u64> {
+    let body = STANDARD.encode(msg);
+    let mut entries = self.entries.lock().expect("outbox lock poisoned");
+    self.expire(&mut entries);
+    let size: u64 = entries.iter().map(|x| x.body.len() as u64).sum();
+    if size + body.len() as u64 > self.limits.max_bytes {
+      return Err(QvpnError::QueueFull(peer));
+    }
+    let id = topics::new_id();
+    entries.push(Entry {
+      id,
+      peer,
+      stored_at: now(),
+      body,
+    });
+    self.save(&entries);
+    Ok(id)
+  }
+
+  /// Messages waiting for `peer`, oldest first, with their ids.
+  pub fn pending(&self, peer: &SocketAddr) -> Vec<(u64, Bytes)> {
+    let mut entries = self.entries.lock().expect("outbox lock poisoned");
+    if self.expire(&mut entries) {
+      self.save(&entries);
+    }
+    entries
+      .iter()
+      .filter(|x| x.peer == *peer)
+      .filter_map(|x| match STANDARD.decode(&x.body) {
+        Ok(body) => Some((x.id, Bytes::from(body))),
+        Err(err) => {
+          warn!(%peer, id = x.id, "skipping corrupt stored message: {}", err);
+          None
+        }
+      })
+      .collect()
+  }
+
+  /// Number of messages waiting for `peer`.
+  pub fn count(&self, peer: &SocketAddr) -> usize {
+    let entries = self.entries.lock().expect("outbox lock poisoned");
+    entries.iter().filter(|x| x.peer == *peer).count()
+  }
+
+  /// Forgets the messages `peer` acknowledged.
+  pub fn ack(&self, peer: &SocketAddr, ids: &[u64]) {
+    let mut entries = self.entries.lock().expect("outbox lock poisoned");
+    let len = entries.len();
+    entries.retain(|x| !(x.peer == *peer && ids.contains(&x.id)));
+    if entries.len() != len {
+      debug!(%peer, acked = len - entries.len(), "stored messages delivered");
+      self.save(&entries);
+    }
+  }
+
+  /// Drops messages older than the TTL, returning whether any were.
+  fn expire(&self, entries: &mut Vec<Entry>) -> bool {
+    let oldest = now().saturating_sub(self.limits.ttl.as_secs());
+    let len = entries.len();
+    entries.retain(|x| x.stored_at >= oldest);
+    if entries.len() != len {
+      debug!(
+        expired = len - entries.len(),
+        "dropping undelivered messages"
+      );
+    }
+    entries.len() != len
+  }
+
+  fn save(&self, entries: &[Entry]) {
+    if let Err(err) = save(&self.path, entries) {
+      warn!(path = %self.path.display(), "couldn't save outbox: {}", err);
+    }
+  }
+}
+
+/// Ids of the stored messages delivered lately.
+#[derive(Debug, Default)]
+pub struct Received {
+  inner: Mutex<(HashSet<u64>, VecDeque<u64>)>,
+}
+
+impl Received {
+  /// Records the stored message `id`, returning false if it was delivered
+  /// before.
+  pub fn first_seen(&self, id: u64) -> bool {
+    let mut inner = self.inner.lock().expect("outbox lock poisoned");
+    let (seen, order) = &mut *inner;
+    if !seen.insert(id) {
+      return false;
+    }
+    order.push_back(id);
+    if order.len() > RECEIVED_CAPACITY {
+      if let Some(oldest) = order.pop_front() {
+        seen.remove(&oldest);
+      }
+    }
+    true
+  }
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|x| x.as_secs())
+    .unwrap_or_default()
+}
+
+fn load(path: &Path) -> io::Result<Vec<Entry>> {
+  Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Writes the entries to a temporary file, then moves it over `path` so a
+/// crash never leaves a truncated outbox.
+fn save(path: &Path, entries: &[Entry]) -> io::Result<()> {
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  let partial = path.with_extension("json.part");
+  let mut file = fs::File::create(&partial)?;
+  file.write_all(&serde_json::to_vec_pretty(entries)?)?;
+  fs::rename(&partial, path)
+}
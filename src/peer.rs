@@ -0,0 +1,1609 @@
+//! Peer-to-peer messaging node on a QUIC [`peer_endpoint`](crate::peer_endpoint).
+
+use bytes::Bytes;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{
+  broadcast,
+  mpsc::{self, UnboundedReceiver},
+  Mutex,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+  chunking::{self, Assembler, ChunkLimits},
+  gossip::Gossip,
+  identity::{self, Identity},
+  known_hosts::KnownHosts,
+  mdns,
+  outbox::{Outbox, OutboxLimits, Received},
+  peer_endpoint::{self, Endpoint, Incoming},
+  peer_manager::{self, PeerEvent, PeerManager, PeerState},
+  peer_store::PeerStore,
+  protocol,
+  reconnect::ReconnectPolicy,
+  relay::{Relay, RelayLimits, RelayStats},
+  rendezvous::Message,
+  send_queue::{QueueLimits, SendQueue},
+  topics::{self, Topics},
+  tuning::Tuning,
+  QvpnError, Result,
+};
+
+/// Builder for a [`Peer`].
+#[derive(Debug, Clone)]
+pub struct PeerBuilder {
+  local_ip: IpAddr,
+  local_port: Option<u16>,
+  idle_timeout: Duration,
+  tuning: Tuning,
+  keep_alive_interval: Duration,
+  bootstrap: Vec<SocketAddr>,
+  reconnect: ReconnectPolicy,
+  rendezvous: Option<(SocketAddr, String)>,
+  rendezvous_server: bool,
+  relay: Option<RelayLimits>,
+  relay_via: Vec<SocketAddr>,
+  mdns: bool,
+  store: Option<PathBuf>,
+  gossip: Option<Gossip>,
+  identity: Option<Arc<Identity>>,
+  known_hosts: Option<KnownHosts>,
+  reap_after: Duration,
+  send_queue: QueueLimits,
+  topics: Vec<String>,
+  outbox: Option<(PathBuf, OutboxLimits)>,
+  chunking: ChunkLimits,
+}
+
+impl Default for PeerBuilder {
+  fn default() -> Self {
+    Self {
+      local_ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+      local_port: None,
+      idle_timeout: Duration::from_secs(3600),
+      tuning: Tuning::default(),
+      keep_alive_interval: Duration::from_secs(20),
+      bootstrap: vec![],
+      reconnect: ReconnectPolicy::default(),
+      rendezvous: None,
+      rendezvous_server: false,
+      relay: None,
+      relay_via: vec![],
+      mdns: false,
+      store: None,
+      gossip: None,
+      identity: None,
+      known_hosts: None,
+      reap_after: peer_manager::DEFAULT_REAP_AFTER,
+      send_queue: QueueLimits::default(),
+      topics: vec![],
+      outbox: None,
+      chunking: ChunkLimits::default(),
+    }
+  }
+}
+
+impl PeerBuilder {
+  /// Local IP address to listen on.
+  pub fn local_ip(mut self, ip: IpAddr) -> Self {
+    self.local_ip = ip;
+    self
+  }
+
+  /// Local address to listen on, instead of a random port on the local IP.
+  pub fn bind(mut self, addr: SocketAddr) -> Self {
+    self.local_ip = addr.ip();
+    self.local_port = Some(addr.port());
+    self
+  }
+
+  /// Idle timeout for connections [default: 1 hour].
+  pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+    self.idle_timeout = timeout;
+    self
+  }
+
+  /// Flow-control windows and initial RTT of every connection.
+  pub fn tuning(mut self, tuning: Tuning) -> Self {
+    self.tuning = tuning;
+    self
+  }
+
+  /// Send keep-alives and pings to every peer after this long, so quiet
+  /// peers aren't dropped by NATs or the idle timeout.
+  pub fn keep_alive_interval(mut self, interval: Duration) -> Self {
+    self.keep_alive_interval = interval;
+    self
+  }
+
+  /// Peer to connect to on startup. May be called multiple times.
+  pub fn connect_to(mut self, peer: SocketAddr) -> Self {
+    self.bootstrap.push(peer);
+    self
+  }
+
+  /// Retry connecting to bootstrap peers, and reconnect to them after they
+  /// disconnect.
+  pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+    self.reconnect = policy;
+    self
+  }
+
+  /// Register with the rendezvous server at `server` under `name`, so peers
+  /// behind other NATs can reach this one with [`PeerHandle::punch`].
+  pub fn rendezvous(mut self, server: SocketAddr, name: impl Into<String>) -> Self {
+    self.rendezvous = Some((server, name.into()));
+    self
+  }
+
+  /// Act as a rendezvous server: record the addresses peers register from,
+  /// introduce them to each other and relay their messages when hole
+  /// punching fails.
+  pub fn rendezvous_server(mut self, enabled: bool) -> Self {
+    self.rendezvous_server = enabled;
+    self
+  }
+
+  /// Forward messages between connected peers that can't reach each other,
+  /// up to the given bandwidth. Rendezvous servers relay without a limit
+  /// unless this is set.
+  pub fn relay(mut self, limits: RelayLimits) -> Self {
+    self.relay = Some(limits);
+    self
+  }
+
+  /// Relaying peer to fall back to when hole punching fails, instead of the
+  /// rendezvous server. May be called multiple times; the first is used.
+  pub fn relay_via(mut self, relay: SocketAddr) -> Self {
+    self.relay_via.push(relay);
+    self
+  }
+
+  /// Advertise this peer on the local network with mDNS and connect to the
+  /// peers found there.
+  pub fn mdns(mut self, enabled: bool) -> Self {
+    self.mdns = enabled;
+    self
+  }
+
+  /// Remember peers in the file at `path` and reconnect to them on the next
+  /// start.
+  pub fn peer_store(mut self, path: impl Into<PathBuf>) -> Self {
+    self.store = Some(path.into());
+    self
+  }
+
+  /// Send the peer list to every peer that connects, and connect to peers
+  /// learned from others as `gossip` allows.
+  pub fn gossip(mut self, gossip: Gossip) -> Self {
+    self.gossip = Some(gossip);
+    self
+  }
+
+  /// Prove `identity` to every peer that connects, check theirs, and sign
+  /// the messages this node sends.
+  pub fn identity(mut self, identity: Arc<Identity>) -> Self {
+    self.identity = Some(identity);
+    self
+  }
+
+  /// Record the identity each peer address proves the first time in
+  /// `known_hosts`, and disconnect peers at that address proving another.
+  pub fn known_hosts(mut self, known_hosts: KnownHosts) -> Self {
+    self.known_hosts = Some(known_hosts);
+    self
+  }
+
+  /// Forget peers that stay disconnected or banned for `interval`.
+  pub fn reap_after(mut self, interval: Duration) -> Self {
+    self.reap_after = interval;
+    self
+  }
+
+  /// Queue up to `limits.capacity` messages per peer, handling more as
+  /// `limits.overflow` says.
+  pub fn send_queue(mut self, limits: QueueLimits) -> Self {
+    self.send_queue = limits;
+    self
+  }
+
+  /// Keep messages for known peers that are offline in the file at `path`,
+  /// and deliver them when the peers are back.
+  pub fn outbox(mut self, path: impl Into<PathBuf>, limits: OutboxLimits) -> Self {
+    self.outbox = Some((path.into(), limits));
+    self
+  }
+
+  /// Send messages bigger than `limits.chunk_size` in parts, and accept
+  /// messages of up to `limits.max_assembled` bytes in parts.
+  pub fn chunking(mut self, limits: ChunkLimits) -> Self {
+    self.chunking = limits;
+    self
+  }
+
+  /// Subscribe to `topic` from the start.
+  pub fn subscribe(mut self, topic: impl Into<String>) -> Self {
+    self.topics.push(topic.into());
+    self
+  }
+
+  /// Creates the endpoint and connects to the bootstrap peers.
+  pub async fn build(self) -> Result<Peer> {
+    let mut transport = quinn::TransportConfig::default();
+    transport
+      .max_idle_timeout(Some(quinn::IdleTimeout::try_from(self.idle_timeout)?))
+      .keep_alive_interval(Some(self.keep_alive_interval));
+    self.tuning.configure(&mut transport)?;
+    let (node, incoming) = Endpoint::bind(
+      SocketAddr::new(self.local_ip, self.local_port.unwrap_or(0)),
+      transport,
+    )?;
+    let Incoming {
+      connections: mut incoming_conns,
+      messages: incoming_messages,
+      mut disconnections,
+    } = incoming;
+    let local_addr = node.local_addr()?;
+    let socket_addr = local_addr;
+    let rendezvous = self.rendezvous.as_ref().map(|(server, _)| *server);
+    let topics = Topics::default();
+    for topic in &self.topics {
+      topics::check_name(topic)?;
+      topics.subscribe(topic);
+    }
+    let handle = PeerHandle {
+      node: node.clone(),
+      peers: Arc::new(PeerManager::new(self.reap_after)),
+      relayed: Arc::default(),
+      fallback_relay: self.relay_via.first().copied().or(rendezvous),
+      rendezvous,
+      store: self.store.as_ref().map(PeerStore::open),
+      gossip: self.gossip,
+      tried: Arc::default(),
+      socket_addr,
+      identity: self.identity,
+      known_hosts: self.known_hosts,
+      identities: Arc::default(),
+      challenges: Arc::default(),
+      queues: Arc::default(),
+      queue_limits: self.send_queue,
+      topics: Arc::new(topics),
+      outbox: self
+        .outbox
+        .as_ref()
+        .map(|(path, limits)| Outbox::open(path, *limits)),
+      received: Arc::default(),
+      chunk_size: self.chunking.chunk_size,
+      assembler: Arc::new(Assembler::new(self.chunking.max_assembled)),
+      relay: match self.relay {
+        Some(limits) => Some(Arc::new(Relay::new(limits))),
+        None if self.rendezvous_server => Some(Arc::new(Relay::new(RelayLimits::default()))),
+        None => None,
+      },
+    };
+
+    for peer in self.bootstrap.iter().chain(&self.relay_via) {
+      info!(%peer, "connecting");
+      handle.peers.start_connecting(*peer);
+      connect(&node, peer, &self.reconnect).await?;
+      handle.add_peer(*peer).await;
+    }
+    if let Some((server, name)) = &self.rendezvous {
+      info!(%server, %name, "registering with rendezvous server");
+      connect(&node, server, &self.reconnect).await?;
+      let register = Message::Register { name: name.clone() };
+      node.send_message(register.encode(&[]), server).await?;
+    }
+    let peers = handle.clone();
+    tokio::spawn(async move {
+      loop {
+        match incoming_conns.recv().await {
+          None => {
+            debug!("incoming connections closed");
+            break;
+          }
+          Some(peer) => {
+            info!(%peer, "incoming");
+            peers.add_peer(peer).await;
+          }
+        }
+      }
+    });
+
+    let peers = handle.clone();
+    let (bootstrap, policy, endpoint) = (self.bootstrap, self.reconnect, node);
+    tokio::spawn(async move {
+      loop {
+        match disconnections.recv().await {
+          None => {
+            debug!("disconnection events closed");
+            break;
+          }
+          Some(peer) => {
+            info!(%peer, "disconnected");
+            peers.remove_peer(peer).await;
+            if policy.max_attempts > 0 && bootstrap.contains(&peer) {
+              let (endpoint, peers) = (endpoint.clone(), peers.clone());
+              tokio::spawn(async move {
+                // The peer went away, so the first attempt waits too.
+                tokio::time::sleep(policy.backoff(1)).await;
+                // Skipped if it is back already, or was banned.
+                if !peers.peers.start_connecting(peer) {
+                  return;
+                }
+                match connect(&endpoint, &peer, &policy).await {
+                  Ok(()) => {
+                    info!(%peer, "reconnected");
+                    peers.add_peer(peer).await;
+                  }
+                  Err(err) => {
+                    warn!(%peer, "giving up reconnecting: {}", err);
+                    peers.peers.set_disconnected(peer);
+                  }
+                }
+              });
+            }
+          }
+        }
+      }
+    });
+
+    if let Some(store) = &handle.store {
+      for record in store.peers() {
+        let handle = handle.clone();
+        tokio::spawn(async move { handle.connect_known(record.address).await });
+      }
+    }
+    if self.mdns {
+      let (found_tx, mut found) = mpsc::unbounded_channel();
+      tokio::spawn(async move {
+        if let Err(err) = mdns::run(socket_addr, found_tx).await {
+          warn!("mDNS discovery failed: {}", err);
+        }
+      });
+      let handle = handle.clone();
+      tokio::spawn(async move {
+        while let Some(peer) = found.recv().await {
+          handle.connect_known(peer).await;
+        }
+      });
+    }
+    let reaper = handle.clone();
+    tokio::spawn(async move {
+      let mut ticks = tokio::time::interval(REAP_INTERVAL);
+      loop {
+        ticks.tick().await;
+        let reaped = reaper.peers.reap();
+        if !reaped.is_empty() {
+          // Gossip may offer them again.
+          let mut tried = reaper.tried.lock().await;
+          for peer in &reaped {
+            tried.remove(peer);
+          }
+        }
+      }
+    });
+    let pinger = handle.clone();
+    let interval = self.keep_alive_interval;
+    tokio::spawn(async move {
+      let mut ticks = tokio::time::interval(interval);
+      ticks.tick().await;
+      loop {
+        ticks.tick().await;
+        let sent = pinger.send_to_all(Bytes::from_static(PING)).await;
+        if !sent.failed.is_empty() {
+          debug!("ping: {}", sent);
+        }
+      }
+    });
+    Ok(Peer {
+      handle,
+      incoming_messages,
+      socket_addr,
+      local_addr,
+      registry: self.rendezvous_server.then(HashMap::new),
+    })
+  }
+}
+
+/// Application-level ping sent to every peer each keep-alive interval. It is
+/// never returned by [`Peer::next_message`].
+const PING: &[u8] = b"\0qvpn-ping";
+
+/// Whether `message` belongs to the identity handshake, which
+/// [`Peer::next_message`] handles itself.
+fn is_handshake(message: &protocol::Message) -> bool {
+  matches!(
+    message,
+    protocol::Message::Hello { .. } | protocol::Message::Proof { .. }
+  )
+}
+
+/// How often peers are checked for reaping.
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a hole punching connection attempt may take.
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection attempts made after a punch request before falling back to
+/// relaying.
+const PUNCH_ATTEMPTS: u32 = 3;
+
+/// Connects to `peer`, retrying as `policy` allows.
+async fn connect(endpoint: &Endpoint, peer: &SocketAddr, policy: &ReconnectPolicy) -> Result<()> {
+  policy
+    .retry("connecting to peer", || async {
+      endpoint.connect_to(peer).await?;
+      Ok(())
+    })
+    .await
+}
+
+/// Cloneable handle used to send messages from a [`Peer`].
+#[derive(Clone)]
+pub struct PeerHandle {
+  /// Shares its connections with every clone, so sends need no lock.
+  node: Endpoint,
+  peers: Arc<PeerManager>,
+  /// Peers that couldn't be reached directly, and the peer relaying their
+  /// messages.
+  relayed: Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>,
+  /// Relay to use when hole punching fails.
+  fallback_relay: Option<SocketAddr>,
+  rendezvous: Option<SocketAddr>,
+  store: Option<Arc<PeerStore>>,
+  gossip: Option<Gossip>,
+  /// Gossiped peers connected to or attempted already.
+  tried: Arc<Mutex<HashSet<SocketAddr>>>,
+  socket_addr: SocketAddr,
+  /// Set when forwarding messages for other peers.
+  relay: Option<Arc<Relay>>,
+  identity: Option<Arc<Identity>>,
+  known_hosts: Option<KnownHosts>,
+  /// Public keys peers have proven they hold. Locked only briefly, so it
+  /// can be read from synchronous code.
+  identities: Arc<std::sync::Mutex<HashMap<SocketAddr, Vec<u8>>>>,
+  /// Challenges sent to peers and not answered yet.
+  challenges: Arc<std::sync::Mutex<HashMap<SocketAddr, Vec<u8>>>>,
+  /// Messages waiting to be sent, per peer.
+  queues: Arc<std::sync::Mutex<HashMap<SocketAddr, Arc<SendQueue>>>>,
+  queue_limits: QueueLimits,
+  topics: Arc<Topics>,
+  outbox: Option<Arc<Outbox>>,
+  /// Stored messages delivered to us lately.
+  received: Arc<Received>,
+  /// Messages bigger than this are sent in parts.
+  chunk_size: usize,
+  assembler: Arc<Assembler>,
+}
+
+impl PeerHandle {
+  /// Number of known peers, including relayed ones.
+  pub async fn peer_count(&self) -> usize {
+    self.peers.connected().len() + self.relayed.lock().await.len()
+  }
+
+  /// Peers reached through a relay, and the relay each goes through.
+  pub async fn relayed_peers(&self) -> Vec<(SocketAddr, SocketAddr)> {
+    let relayed = self.relayed.lock().await;
+    relayed
+      .iter()
+      .map(|(peer, relay)| (*peer, *relay))
+      .collect()
+  }
+
+  /// Traffic relayed for other peers, if relaying is enabled.
+  pub fn relay_stats(&self) -> Option<Vec<((SocketAddr, SocketAddr), RelayStats)>> {
+    self.relay.as_deref().map(Relay::stats)
+  }
+
+  /// Peers connected directly, in the order they connected.
+  pub async fn peers(&self) -> Vec<SocketAddr> {
+    self.peers.connected()
+  }
+
+  /// State of the direct connection to `peer`, unless it is unknown.
+  pub fn peer_state(&self, peer: &SocketAddr) -> Option<PeerState> {
+    self.peers.state(peer)
+  }
+
+  /// Subscribes to the [`PeerEvent`]s from now on.
+  pub fn events(&self) -> broadcast::Receiver<PeerEvent> {
+    self.peers.subscribe()
+  }
+
+  /// Peers remembered across restarts, if a peer store is configured.
+  pub fn peer_store(&self) -> Option<&Arc<PeerStore>> {
+    self.store.as_ref()
+  }
+
+  /// Queues `msg` for a single peer, to be sent through its relay if it
+  /// couldn't be reached directly. Fails if the peer's queue is full and
+  /// refuses new messages, or the peer is dropped while waiting for room.
+  /// Peers a message can't be sent to are dropped.
+  pub async fn send_message(&self, msg: Bytes, peer: &SocketAddr) -> Result<()> {
+    if let Some(outbox) = &self.outbox {
+      if self.is_offline(peer).await {
+        let id = outbox.store(*peer, &msg)?;
+        debug!(%peer, id, "peer offline, message stored");
+        return Ok(());
+      }
+    }
+    let queue = self.queue(*peer);
+    if msg.len() <= self.chunk_size {
+      return queue.push(msg).await;
+    }
+    let parts = chunking::split(&msg, self.chunk_size);
+    debug!(%peer, len = msg.len(), parts = parts.len(), "sending message in parts");
+    for part in parts {
+      queue.push(part).await?;
+    }
+    Ok(())
+  }
+
+  /// Adds a part of a message from `peer`. Returns the message for the
+  /// application: the whole message once its last part arrived, or any
+  /// other message as it is.
+  fn receive_chunk(&self, peer: SocketAddr, msg: Bytes) -> Option<Bytes> {
+    let message = match protocol::Message::decode(&msg) {
+      Ok(mut messages) if messages.len() == 1 => messages.remove(0),
+      _ => return Some(msg),
+    };
+    let (id, index, count, data) = match message {
+      protocol::Message::Chunk {
+        id,
+        index,
+        count,
+        data,
+      } => (id, index, count, data),
+      _ => return Some(msg),
+    };
+    match self.assembler.add(peer, id, index, count, data) {
+      Ok(assembled) => assembled,
+      Err(err) => {
+        warn!(%peer, id, "dropping message in parts: {}", err);
+        None
+      }
+    }
+  }
+
+  /// Messages stored for `peer` until it is back.
+  pub fn stored(&self, peer: &SocketAddr) -> usize {
+    self.outbox.as_ref().map_or(0, |outbox| outbox.count(peer))
+  }
+
+  /// Whether `peer` was connected before but isn't now.
+  async fn is_offline(&self, peer: &SocketAddr) -> bool {
+    if self.relayed.lock().await.contains_key(peer) {
+      return false;
+    }
+    match self.peers.state(peer) {
+      Some(PeerState::Disconnected) => true,
+      None => self.store.as_ref().is_some_and(|x| x.get(peer).is_some()),
+      _ => false,
+    }
+  }
+
+  /// Sends `peer` the messages stored for it.
+  async fn replay(&self, peer: SocketAddr) {
+    let pending = match &self.outbox {
+      Some(outbox) => outbox.pending(&peer),
+      None => return,
+    };
+    if pending.is_empty() {
+      return;
+    }
+    info!(%peer, messages = pending.len(), "delivering stored messages");
+    for (id, body) in pending {
+      let stored = protocol::Message::Stored {
+        id,
+        body: body.to_vec(),
+      };
+      if let Err(err) = self.send_message(stored.encode(), &peer).await {
+        debug!(%peer, "delivering stored messages failed: {}", err);
+        break;
+      }
+    }
+  }
+
+  /// Acts on a stored message or acknowledgement `msg` from `peer`.
+  /// Returns the message for the application: the body of a stored message
+  /// delivered for the first time, or any other message as it is.
+  async fn receive_stored(&self, peer: SocketAddr, msg: Bytes) -> Option<Bytes> {
+    let messages = match protocol::Message::decode(&msg) {
+      Ok(messages) => messages,
+      Err(_) => return Some(msg),
+    };
+    match messages.as_slice() {
+      [protocol::Message::Stored { id, body }] => {
+        let ack = protocol::Message::Ack { ids: vec![*id] };
+        if let Err(err) = self.send(&ack, &peer).await {
+          debug!(%peer, "acknowledging stored message failed: {}", err);
+        }
+        if self.received.first_seen(*id) {
+          Some(Bytes::copy_from_slice(body))
+        } else {
+          debug!(%peer, id, "dropping repeated stored message");
+          None
+        }
+      }
+      [protocol::Message::Ack { ids }] => {
+        if let Some(outbox) = &self.outbox {
+          outbox.ack(&peer, ids);
+        }
+        None
+      }
+      _ => Some(msg),
+    }
+  }
+
+  /// Messages waiting to be sent to `peer`.
+  pub fn queued(&self, peer: &SocketAddr) -> usize {
+    let queues = self.queues.lock().expect("queue lock poisoned");
+    queues.get(peer).map_or(0, |queue| queue.len())
+  }
+
+  /// The send queue of `peer`, started along with its sending task if it
+  /// has none.
+  fn queue(&self, peer: SocketAddr) -> Arc<SendQueue> {
+    let mut queues = self.queues.lock().expect("queue lock poisoned");
+    if let Some(queue) = queues.get(&peer) {
+      return queue.clone();
+    }
+    let queue = Arc::new(SendQueue::new(peer, self.queue_limits));
+    queues.insert(peer, queue.clone());
+    let (handle, sending) = (self.clone(), queue.clone());
+    tokio::spawn(async move {
+      while let Some(msg) = sending.pop().await {
+        if let Err(err) = handle.deliver(msg, &peer).await {
+          info!(%peer, "dropping unreachable peer: {}", err);
+          handle.drop_peer(peer).await;
+          break;
+        }
+      }
+    });
+    queue
+  }
+
+  /// Closes the send queue of `peer`, discarding its messages.
+  fn close_queue(&self, peer: &SocketAddr) {
+    let queue = self
+      .queues
+      .lock()
+      .expect("queue lock poisoned")
+      .remove(peer);
+    if let Some(queue) = queue {
+      if queue.dropped() > 0 {
+        debug!(%peer, dropped = queue.dropped(), "send queue closed");
+      }
+      queue.close();
+    }
+  }
+
+  /// Sends `msg` to `peer` right away, through its relay if it couldn't be
+  /// reached directly.
+  async fn deliver(&self, msg: Bytes, peer: &SocketAddr) -> Result<()> {
+    let relay = self.relayed.lock().await.get(peer).copied();
+    match relay {
+      Some(relay) => {
+        self
+          .node
+          .send_message(Message::Relay { to: *peer }.encode(&msg), &relay)
+          .await?
+      }
+      None => self.node.send_message(msg, peer).await?,
+    }
+    Ok(())
+  }
+
+  /// Queues `msg` for every known peer at once. Peers whose queues refuse
+  /// it are listed in the returned [`Broadcast`].
+  pub async fn send_to_all(&self, msg: Bytes) -> Broadcast {
+    let mut peers = self.peers.connected();
+    peers.extend(self.relayed.lock().await.keys().copied());
+    debug!(?msg, "sending to all peers");
+    self.send_to(msg, peers).await
+  }
+
+  /// Queues `msg` for each of `peers` at once.
+  async fn send_to(&self, msg: Bytes, peers: Vec<SocketAddr>) -> Broadcast {
+    let mut sends: FuturesUnordered<_> = peers
+      .into_iter()
+      .map(|peer| {
+        let msg = msg.clone();
+        async move { (peer, self.send_message(msg, &peer).await) }
+      })
+      .collect();
+    let mut sent = Broadcast::default();
+    while let Some((peer, result)) = sends.next().await {
+      match result {
+        Ok(()) => sent.delivered.push(peer),
+        Err(err) => sent.failed.push((peer, err)),
+      }
+    }
+    sent
+  }
+
+  /// Opens a stream of its own to `peer`, which must be reachable
+  /// directly. Messages sent on it arrive in order.
+  pub async fn open_stream(
+    &self,
+    peer: &SocketAddr,
+  ) -> Result<(peer_endpoint::SendStream, peer_endpoint::RecvStream)> {
+    if self.relayed.lock().await.contains_key(peer) {
+      return Err(QvpnError::Unsupported(format!(
+        "streams to relayed peer {}",
+        peer
+      )));
+    }
+    self.node.open_stream(peer).await
+  }
+
+  /// Sends a typed message to a single peer, signed if this node has an
+  /// identity.
+  pub async fn send(&self, message: &protocol::Message, peer: &SocketAddr) -> Result<()> {
+    self.send_message(self.seal(message).encode(), peer).await
+  }
+
+  /// Sends a typed message to every known peer, signed if this node has an
+  /// identity. See [`PeerHandle::send_to_all`].
+  pub async fn broadcast(&self, message: &protocol::Message) -> Broadcast {
+    self.send_to_all(self.seal(message).encode()).await
+  }
+
+  /// Subscribes to `topic`, telling every peer.
+  pub async fn subscribe(&self, topic: &str) -> Result<()> {
+    topics::check_name(topic)?;
+    if self.topics.subscribe(topic) {
+      self.announce_topics().await;
+    }
+    Ok(())
+  }
+
+  /// Unsubscribes from `topic`, telling every peer. Returns false if not
+  /// subscribed.
+  pub async fn unsubscribe(&self, topic: &str) -> bool {
+    let unsubscribed = self.topics.unsubscribe(topic);
+    if unsubscribed {
+      self.announce_topics().await;
+    }
+    unsubscribed
+  }
+
+  /// Topics subscribed to, in order.
+  pub fn subscriptions(&self) -> Vec<String> {
+    self.topics.subscriptions()
+  }
+
+  /// Peers subscribed to `topic` that publications are sent to directly.
+  pub fn subscribers(&self, topic: &str) -> Vec<SocketAddr> {
+    self.topics.subscribers(topic)
+  }
+
+  /// Publishes `payload` to the subscribers of `topic`, signed if this
+  /// node has an identity. The returned [`Broadcast`] lists the peers it
+  /// was queued for directly; they forward it to the other subscribers.
+  pub async fn publish(&self, topic: &str, payload: Vec<u8>) -> Broadcast {
+    let id = topics::new_id();
+    self.topics.first_seen(id);
+    let message = protocol::Message::Publish {
+      id,
+      topic: topic.to_string(),
+      payload,
+    };
+    let subscribers = self.topics.subscribers(topic);
+    self
+      .send_to(self.seal(&message).encode(), subscribers)
+      .await
+  }
+
+  /// Sends the topics subscribed to to every peer.
+  async fn announce_topics(&self) {
+    let topics = self.topics.subscriptions();
+    let sent = self
+      .broadcast(&protocol::Message::Subscribe { topics })
+      .await;
+    if !sent.failed.is_empty() {
+      debug!("announcing topics: {}", sent);
+    }
+  }
+
+  /// Acts on a subscription or publication `msg` from `peer`, forwarding
+  /// publications seen for the first time to the other subscribers of their
+  /// topic. Returns whether `msg` is for the application: a first seen
+  /// publication on a topic subscribed to, or any other message.
+  async fn receive_topic(&self, peer: SocketAddr, msg: &Bytes) -> bool {
+    if let Ok(messages) = protocol::Message::decode(msg) {
+      if let [protocol::Message::Subscribe { topics }] = messages.as_slice() {
+        debug!(%peer, ?topics, "subscriptions");
+        self.topics.set_remote(peer, topics.clone());
+        return false;
+      }
+    }
+    let (id, topic) = match topics::publication(msg) {
+      Some(publication) => publication,
+      None => return true,
+    };
+    if !self.topics.first_seen(id) {
+      debug!(%peer, %topic, id, "dropping repeated publication");
+      return false;
+    }
+    let subscribed = self.topics.is_subscribed(&topic);
+    let subscribers: Vec<_> = self
+      .topics
+      .subscribers(&topic)
+      .into_iter()
+      .filter(|x| *x != peer)
+      .collect();
+    if !subscribers.is_empty() {
+      // Forwarded in the background, so a full queue doesn't hold up
+      // receiving.
+      let (handle, msg) = (self.clone(), msg.clone());
+      tokio::spawn(async move {
+        let sent = handle.send_to(msg, subscribers).await;
+        if !sent.failed.is_empty() {
+          debug!(%topic, id, "forwarding: {}", sent);
+        }
+      });
+    }
+    subscribed
+  }
+
+  /// Signs `message` unless it is part of the handshake, only concerns the
+  /// receiving peer, or is signed already.
+  fn seal(&self, message: &protocol::Message) -> protocol::Message {
+    use protocol::Message::*;
+    match (&self.identity, message) {
+      (_, Hello { .. })
+      | (_, Proof { .. })
+      | (_, Signed { .. })
+      | (_, Subscribe { .. })
+      | (_, Stored { .. })
+      | (_, Ack { .. })
+      | (_, Chunk { .. })
+      | (None, _) => message.clone(),
+      (Some(identity), message) => message.sign(identity),
+    }
+  }
+
+  /// This node's identity, if it has one.
+  pub fn identity(&self) -> Option<&Arc<Identity>> {
+    self.identity.as_ref()
+  }
+
+  /// Public key `peer` has proven it holds.
+  pub fn identity_of(&self, peer: &SocketAddr) -> Option<Vec<u8>> {
+    let identities = self.identities.lock().expect("identity lock poisoned");
+    identities.get(peer).cloned()
+  }
+
+  /// Sends `peer` our public key and a challenge to prove its own.
+  async fn hello(&self, peer: SocketAddr) {
+    let identity = match &self.identity {
+      Some(identity) => identity,
+      None => return,
+    };
+    let challenge = identity::challenge();
+    self
+      .challenges
+      .lock()
+      .expect("identity lock poisoned")
+      .insert(peer, challenge.clone());
+    let hello = protocol::Message::Hello {
+      public_key: identity.public_key().to_vec(),
+      challenge,
+    };
+    if let Err(err) = self.send(&hello, &peer).await {
+      debug!(%peer, "sending hello failed: {}", err);
+    }
+  }
+
+  /// Acts on a handshake message from `peer`.
+  async fn handshake(&self, peer: SocketAddr, message: protocol::Message) -> Result<()> {
+    let identity = match &self.identity {
+      Some(identity) => identity,
+      None => return Ok(()),
+    };
+    match message {
+      protocol::Message::Hello { challenge, .. } => {
+        let challenged = self
+          .challenges
+          .lock()
+          .expect("identity lock poisoned")
+          .contains_key(&peer);
+        if !challenged && self.identity_of(&peer).is_none() {
+          self.hello(peer).await;
+        }
+        let proof = protocol::Message::Proof {
+          public_key: identity.public_key().to_vec(),
+          signature: identity.prove(&challenge),
+        };
+        self.send(&proof, &peer).await?;
+      }
+      protocol::Message::Proof {
+        public_key,
+        signature,
+      } => {
+        let challenge = self
+          .challenges
+          .lock()
+          .expect("identity lock poisoned")
+          .remove(&peer)
+          .ok_or_else(|| QvpnError::Protocol("unexpected identity proof".into()))?;
+        if !identity::verify_proof(&public_key, &challenge, &signature) {
+          return Err(QvpnError::Protocol("identity proof doesn't verify".into()));
+        }
+        let fingerprint = identity::fingerprint(&public_key);
+        if let Some(known_hosts) = &self.known_hosts {
+          if let Err(err) = known_hosts.check(&peer.to_string(), &fingerprint) {
+            self.node.disconnect_from(&peer);
+            self.peers.ban(peer);
+            self.remove_peer(peer).await;
+            return Err(err);
+          }
+        }
+        info!(%peer, %fingerprint, "peer identified");
+        if let Some(store) = &self.store {
+          let key = identity::encode_key(&public_key);
+          match store.get(&peer).and_then(|record| record.public_key) {
+            Some(known) if known != key => {
+              warn!(%peer, %fingerprint, "peer identity changed since last seen")
+            }
+            _ => {}
+          }
+          store.set_public_key(peer, key);
+        }
+        self
+          .identities
+          .lock()
+          .expect("identity lock poisoned")
+          .insert(peer, public_key);
+        self.replay(peer).await;
+      }
+      _ => {}
+    }
+    Ok(())
+  }
+
+  /// Asks the rendezvous server to introduce this peer to the one
+  /// registered as `name`. Both sides then try to connect to each other,
+  /// and the peer is added directly or as relayed once that settles.
+  pub async fn punch(&self, name: &str) -> Result<()> {
+    let server = self
+      .rendezvous
+      .ok_or_else(|| QvpnError::InvalidInput("no rendezvous server configured".into()))?;
+    let connect = Message::Connect {
+      name: name.to_string(),
+    };
+    self.node.send_message(connect.encode(&[]), &server).await?;
+    Ok(())
+  }
+
+  /// Marks a newly connected peer connected, unless it was already, and
+  /// sends it the peer list when gossiping. Banned peers are disconnected.
+  async fn add_peer(&self, peer: SocketAddr) {
+    if self.peers.state(&peer) == Some(PeerState::Banned) {
+      debug!(%peer, "disconnecting banned peer");
+      self.node.disconnect_from(&peer);
+      return;
+    }
+    if !self.peers.set_connected(peer) {
+      return;
+    }
+    if let Some(store) = &self.store {
+      store.connected(peer);
+    }
+    self.hello(peer).await;
+    let topics = self.topics.subscriptions();
+    if !topics.is_empty() {
+      if let Err(err) = self
+        .send(&protocol::Message::Subscribe { topics }, &peer)
+        .await
+      {
+        debug!(%peer, "sending subscriptions failed: {}", err);
+      }
+    }
+    // With an identity, stored messages wait until the peer proves its own.
+    if self.identity.is_none() {
+      self.replay(peer).await;
+    }
+    if self.gossip.is_some() {
+      let peers = self
+        .peers
+        .connected()
+        .into_iter()
+        .filter(|x| *x != peer)
+        .collect();
+      if let Err(err) = self
+        .send(&protocol::Message::PeerList { peers }, &peer)
+        .await
+      {
+        debug!(%peer, "sending peer list failed: {}", err);
+      }
+    }
+  }
+
+  /// Connects to peers from a list `from` sent, as the gossip settings
+  /// allow. Peers already connected or tried are skipped.
+  pub async fn learn_peers(&self, from: SocketAddr, peers: &[SocketAddr]) {
+    let gossip = match self.gossip {
+      Some(gossip) => gossip,
+      None => return,
+    };
+    let known = self.peers().await;
+    let selected = gossip.select(
+      peers,
+      self.socket_addr,
+      &known,
+      &mut *self.tried.lock().await,
+    );
+    for peer in selected {
+      debug!(%from, %peer, "connecting to gossiped peer");
+      let handle = self.clone();
+      tokio::spawn(async move { handle.connect_known(peer).await });
+    }
+  }
+
+  /// Marks a peer disconnected, and forgets the identity it proved.
+  async fn remove_peer(&self, peer: SocketAddr) {
+    self.peers.set_disconnected(peer);
+    self.close_queue(&peer);
+    self.topics.forget(&peer);
+    self.assembler.forget(&peer);
+    self
+      .identities
+      .lock()
+      .expect("identity lock poisoned")
+      .remove(&peer);
+    if let Some(store) = &self.store {
+      store.disconnected(peer);
+    }
+  }
+
+  /// Forgets a peer that couldn't be sent to, disconnecting it if it is
+  /// reached directly.
+  async fn drop_peer(&self, peer: SocketAddr) {
+    self.close_queue(&peer);
+    self.topics.forget(&peer);
+    self.assembler.forget(&peer);
+    if self.relayed.lock().await.remove(&peer).is_some() {
+      return;
+    }
+    self.node.disconnect_from(&peer);
+    self.remove_peer(peer).await;
+  }
+
+  /// Connects to `peer` and adds it to the peers, unless it is connected
+  /// or being connected to already. Banned peers are refused.
+  pub async fn connect(&self, peer: SocketAddr) -> Result<()> {
+    if !self.peers.start_connecting(peer) {
+      return match self.peers.state(&peer) {
+        Some(PeerState::Banned) => Err(QvpnError::Unauthenticated(format!(
+          "peer {} is banned",
+          peer
+        ))),
+        _ => Ok(()),
+      };
+    }
+    if let Err(err) = self.node.connect_to(&peer).await {
+      self.peers.set_disconnected(peer);
+      return Err(err);
+    }
+    self.add_peer(peer).await;
+    Ok(())
+  }
+
+  /// Connects to a peer found on the local network or remembered from an
+  /// earlier run, unless it is connected, being connected to or banned.
+  async fn connect_known(&self, peer: SocketAddr) {
+    if matches!(self.peers.state(&peer), Some(state) if state != PeerState::Disconnected) {
+      return;
+    }
+    match self.connect(peer).await {
+      Ok(()) => info!(%peer, "connected to known peer"),
+      Err(err) => debug!(%peer, "couldn't connect to known peer: {}", err),
+    }
+  }
+
+  /// Sends messages for `peer` through the connected peer `relay`, which
+  /// must have relaying enabled.
+  pub async fn relay_through(&self, peer: SocketAddr, relay: SocketAddr) {
+    info!(%peer, %relay, "relaying");
+    self.relayed.lock().await.insert(peer, relay);
+  }
+
+  /// Connects to `peer` while it connects to us, falling back to relaying
+  /// when that fails.
+  async fn connect_punched(&self, peer: SocketAddr) {
+    if self.peers.state(&peer) == Some(PeerState::Banned) {
+      debug!(%peer, "not punching to banned peer");
+      return;
+    }
+    for attempt in 1..=PUNCH_ATTEMPTS {
+      match tokio::time::timeout(PUNCH_TIMEOUT, self.node.connect_to(&peer)).await {
+        Ok(Ok(())) => {
+          info!(%peer, attempt, "hole punched");
+          self.relayed.lock().await.remove(&peer);
+          self.add_peer(peer).await;
+          return;
+        }
+        Ok(Err(err)) => debug!(%peer, attempt, "hole punching failed: {}", err),
+        Err(_) => debug!(%peer, attempt, "hole punching timed out"),
+      }
+    }
+    match self.fallback_relay {
+      Some(relay) => {
+        warn!(%peer, %relay, "couldn't punch a hole, relaying");
+        self.relayed.lock().await.insert(peer, relay);
+      }
+      None => warn!(%peer, "couldn't punch a hole"),
+    }
+  }
+}
+
+/// Outcome of queueing a message for every peer.
+#[derive(Debug, Default)]
+pub struct Broadcast {
+  /// Peers the message was queued for.
+  pub delivered: Vec<SocketAddr>,
+  /// Peers whose queues refused it, and why.
+  pub failed: Vec<(SocketAddr, QvpnError)>,
+}
+
+impl fmt::Display for Broadcast {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let total = self.delivered.len() + self.failed.len();
+    write!(f, "queued for {} of {} peers", self.delivered.len(), total)?;
+    for (peer, err) in &self.failed {
+      write!(f, "; {}: {}", peer, err)?;
+    }
+    Ok(())
+  }
+}
+
+/// A running peer-to-peer node.
+pub struct Peer {
+  handle: PeerHandle,
+  incoming_messages: UnboundedReceiver<(SocketAddr, Bytes)>,
+  socket_addr: SocketAddr,
+  local_addr: SocketAddr,
+  /// Registered peers by name, when acting as a rendezvous server.
+  registry: Option<HashMap<String, SocketAddr>>,
+}
+
+impl Peer {
+  /// Returns a builder with default settings.
+  pub fn builder() -> PeerBuilder {
+    PeerBuilder::default()
+  }
+
+  /// Address this node tells other peers to reach it at: its local
+  /// address. Peers behind a NAT learn their public address from a
+  /// rendezvous server instead.
+  pub fn socket_addr(&self) -> SocketAddr {
+    self.socket_addr
+  }
+
+  /// Local address this node is bound to.
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local_addr
+  }
+
+  /// Returns a handle that can send messages concurrently with
+  /// [`Peer::next_message`].
+  pub fn handle(&self) -> PeerHandle {
+    self.handle.clone()
+  }
+
+  /// Traffic relayed for other peers, if relaying is enabled.
+  pub fn relay_stats(&self) -> Option<Vec<((SocketAddr, SocketAddr), RelayStats)>> {
+    self.handle.relay_stats()
+  }
+
+  /// Waits for the next message from any peer. Rendezvous messages are
+  /// handled here, so this must be polled for hole punching, relaying and
+  /// the rendezvous server to make progress.
+  pub async fn next_message(&mut self) -> Option<(SocketAddr, Bytes)> {
+    loop {
+      let (peer, msg) = self.incoming_messages.recv().await?;
+      if msg == PING {
+        debug!(%peer, "ping");
+        continue;
+      }
+      let result = match Message::decode(&msg) {
+        None if self.handle.identity.is_some() => match protocol::Message::decode(&msg) {
+          Ok(mut messages) if messages.len() == 1 && is_handshake(&messages[0]) => {
+            let message = messages.remove(0);
+            if let Err(err) = self.handle.handshake(peer, message).await {
+              warn!(%peer, "identity handshake failed: {}", err);
+            }
+            continue;
+          }
+          _ => Ok(Some((peer, msg))),
+        },
+        None => Ok(Some((peer, msg))),
+        Some(Ok((message, payload))) => self.handle_rendezvous(peer, message, payload).await,
+        Some(Err(err)) => Err(err),
+      };
+      match result {
+        Ok(Some((from, msg))) => {
+          let msg = match self.handle.receive_chunk(from, msg) {
+            Some(msg) => msg,
+            None => continue,
+          };
+          let msg = match self.handle.receive_stored(from, msg).await {
+            Some(msg) => msg,
+            None => continue,
+          };
+          if self.handle.receive_topic(from, &msg).await {
+            return Some((from, msg));
+          }
+        }
+        Ok(None) => {}
+        Err(err) => warn!(%peer, "rendezvous message failed: {}", err),
+      }
+    }
+  }
+
+  /// Acts on a rendezvous message from `peer`, returning the payload of a
+  /// relayed message.
+  async fn handle_rendezvous(
+    &mut self,
+    peer: SocketAddr,
+    message: Message,
+    payload: Bytes,
+  ) -> Result<Option<(SocketAddr, Bytes)>> {
+    debug!(%peer, ?message, "rendezvous message");
+    let handle = self.handle.clone();
+    match (message, &mut self.registry) {
+      (Message::Register { name }, Some(registry)) => {
+        info!(%peer, %name, "peer registered");
+        registry.insert(name, peer);
+        let reply = Message::Registered { address: peer };
+        handle.send_message(reply.encode(&[]), &peer).await?;
+      }
+      (Message::Connect { name }, Some(registry)) => match registry.get(&name) {
+        Some(&target) => {
+          info!(%peer, %target, %name, "introducing peers");
+          let node = &handle.node;
+          futures::try_join!(
+            node.send_message(Message::Punch { peer: target }.encode(&[]), &peer),
+            node.send_message(Message::Punch { peer }.encode(&[]), &target),
+          )?;
+        }
+        None => {
+          let reply = Message::Error {
+            reason: format!("no peer registered as `{}`", name),
+          };
+          handle.send_message(reply.encode(&[]), &peer).await?;
+        }
+      },
+      (Message::Relay { to }, registry) => {
+        // Only registered or connected peers may relay, and only to each
+        // other.
+        let allowed = match registry {
+          Some(registry) => {
+            let registered = |addr| registry.values().any(|x| *x == addr);
+            registered(peer) && registered(to)
+          }
+          None => handle.peers.is_connected(&peer) && handle.peers.is_connected(&to),
+        };
+        match &handle.relay {
+          Some(relay) if allowed => {
+            if relay.admit(peer, to, payload.len()) {
+              handle
+                .node
+                .send_message(Message::Relayed { from: peer }.encode(&payload), &to)
+                .await?;
+            } else {
+              debug!(%peer, %to, len = payload.len(), "over relay rate, dropping");
+            }
+          }
+          _ => debug!(%peer, %to, "refusing to relay"),
+        }
+      }
+      (Message::Registered { address }, _) => {
+        info!(%address, "registered with rendezvous server")
+      }
+      (Message::Punch { peer: target }, _) if handle.rendezvous == Some(peer) => {
+        tokio::spawn(async move { handle.connect_punched(target).await });
+      }
+      (Message::Relayed { from }, _) => {
+        // Answer through the same relay unless the peer is reachable.
+        if !handle.peers.is_connected(&from) {
+          handle.relayed.lock().await.entry(from).or_insert(peer);
+        }
+        return Ok(Some((from, payload)));
+      }
+      (Message::Error { reason }, _) => warn!(%peer, "rendezvous server: {}", reason),
+      (message, _) => debug!(%peer, ?message, "ignoring unexpected rendezvous message"),
+    }
+    Ok(None)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::time::{sleep, timeout, Instant};
+
+  use super::*;
+
+  /// How long a test waits for anything to happen over loopback.
+  const WAIT: Duration = Duration::from_secs(10);
+
+  type Messages = UnboundedReceiver<(SocketAddr, Bytes)>;
+
+  /// A peer on a loopback port, configured by `configure`, and the
+  /// messages it passes on to the application.
+  async fn peer(configure: impl FnOnce(PeerBuilder) -> PeerBuilder) -> (PeerHandle, Messages) {
+    let mut peer = configure(Peer::builder()).build().await.unwrap();
+    let handle = peer.handle();
+    let (messages, received) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+      while let Some(message) = peer.next_message().await {
+        if messages.send(message).is_err() {
+          break;
+        }
+      }
+    });
+    (handle, received)
+  }
+
+  /// A file path of its own for `name` in this test run.
+  fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("qvpn-peer-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+  }
+
+  async fn next(messages: &mut Messages) -> (SocketAddr, Bytes) {
+    timeout(WAIT, messages.recv())
+      .await
+      .expect("no message in time")
+      .expect("peer stopped")
+  }
+
+  /// The typed message `msg` carries.
+  fn decode(msg: &Bytes) -> protocol::Message {
+    let mut messages = protocol::Message::decode(msg).unwrap();
+    assert_eq!(messages.len(), 1);
+    messages.remove(0)
+  }
+
+  async fn until(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + WAIT;
+    while !condition() {
+      assert!(
+        Instant::now() < deadline,
+        "timed out waiting until {}",
+        what
+      );
+      sleep(Duration::from_millis(10)).await;
+    }
+  }
+
+  /// Connects `from` to `to`, and waits until `to` has it connected too.
+  async fn connect(from: &PeerHandle, to: &PeerHandle) {
+    from.connect(to.socket_addr).await.unwrap();
+    until("the connection is accepted", || {
+      to.peers.is_connected(&from.socket_addr)
+    })
+    .await;
+  }
+
+  #[tokio::test]
+  async fn messages_arrive_in_order() {
+    let (a, mut a_messages) = peer(|x| x).await;
+    let (b, mut b_messages) = peer(|x| x).await;
+    connect(&b, &a).await;
+    for i in 0..50u32 {
+      b.send_message(Bytes::from(i.to_be_bytes().to_vec()), &a.socket_addr)
+        .await
+        .unwrap();
+    }
+    for i in 0..50u32 {
+      assert_eq!(
+        next(&mut a_messages).await,
+        (b.socket_addr, Bytes::from(i.to_be_bytes().to_vec()))
+      );
+    }
+    a.send_message(Bytes::from_static(b"back"), &b.socket_addr)
+      .await
+      .unwrap();
+    assert_eq!(
+      next(&mut b_messages).await,
+      (a.socket_addr, Bytes::from_static(b"back"))
+    );
+  }
+
+  #[tokio::test]
+  async fn streams_carry_messages_in_order() {
+    let (a, mut a_messages) = peer(|x| x).await;
+    let (b, _b_messages) = peer(|x| x).await;
+    let (mut send, _recv) = b.open_stream(&a.socket_addr).await.unwrap();
+    for msg in [&b"one"[..], b"two", b"three"] {
+      send.send_message(Bytes::from(msg)).await.unwrap();
+    }
+    send.finish().unwrap();
+    for msg in [&b"one"[..], b"two", b"three"] {
+      assert_eq!(
+        next(&mut a_messages).await,
+        (b.socket_addr, Bytes::from(msg))
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn large_messages_arrive_whole() {
+    let chunking = ChunkLimits {
+      chunk_size: 1024,
+      ..ChunkLimits::default()
+    };
+    let (a, _a_messages) = peer(|x| x.chunking(chunking)).await;
+    let (b, mut b_messages) = peer(|x| x).await;
+    connect(&a, &b).await;
+    let msg: Bytes = (0..100_000).map(|i| i as u8).collect::<Vec<_>>().into();
+    a.send_message(msg.clone(), &b.socket_addr).await.unwrap();
+    assert_eq!(next(&mut b_messages).await, (a.socket_addr, msg));
+  }
+
+  #[tokio::test]
+  async fn relays_forward_between_their_peers() {
+    let (relay, _relay_messages) = peer(|x| x.relay(RelayLimits::default())).await;
+    let (a, mut a_messages) = peer(|x| x).await;
+    let (c, mut c_messages) = peer(|x| x).await;
+    connect(&a, &relay).await;
+    connect(&c, &relay).await;
+    a.relay_through(c.socket_addr, relay.socket_addr).await;
+    a.send_message(Bytes::from_static(b"hi"), &c.socket_addr)
+      .await
+      .unwrap();
+    assert_eq!(
+      next(&mut c_messages).await,
+      (a.socket_addr, Bytes::from_static(b"hi"))
+    );
+    // The answer goes back the way the message came.
+    c.send_message(Bytes::from_static(b"hello"), &a.socket_addr)
+      .await
+      .unwrap();
+    assert_eq!(
+      next(&mut a_messages).await,
+      (c.socket_addr, Bytes::from_static(b"hello"))
+    );
+    let stats = relay.relay_stats().unwrap();
+    let relayed: u64 = stats.iter().map(|(_, x)| x.messages).sum();
+    assert_eq!(relayed, 2);
+    assert!(!a.peers.is_connected(&c.socket_addr));
+  }
+
+  #[tokio::test]
+  async fn peers_refuse_to_relay_without_relaying_enabled() {
+    let (middle, _middle_messages) = peer(|x| x).await;
+    let (a, _a_messages) = peer(|x| x).await;
+    let (c, mut c_messages) = peer(|x| x).await;
+    connect(&a, &middle).await;
+    connect(&c, &middle).await;
+    a.relay_through(c.socket_addr, middle.socket_addr).await;
+    a.send_message(Bytes::from_static(b"hi"), &c.socket_addr)
+      .await
+      .unwrap();
+    assert!(timeout(Duration::from_millis(500), c_messages.recv())
+      .await
+      .is_err());
+  }
+
+  #[tokio::test]
+  async fn gossip_connects_to_learned_peers() {
+    let gossip = Gossip {
+      auto_connect: true,
+      ..Gossip::default()
+    };
+    let (a, _a_messages) = peer(|x| x.gossip(gossip)).await;
+    let (b, _b_messages) = peer(|x| x.gossip(gossip)).await;
+    let (c, mut c_messages) = peer(|x| x.gossip(gossip)).await;
+    connect(&b, &a).await;
+    connect(&c, &a).await;
+    let (from, msg) = next(&mut c_messages).await;
+    assert_eq!(from, a.socket_addr);
+    let peers = match decode(&msg) {
+      protocol::Message::PeerList { peers } => peers,
+      message => panic!("expected a peer list, got {:?}", message),
+    };
+    assert_eq!(peers, vec![b.socket_addr]);
+    c.learn_peers(from, &peers).await;
+    until("the learned peer is connected", || {
+      c.peers.is_connected(&b.socket_addr) && b.peers.is_connected(&c.socket_addr)
+    })
+    .await;
+  }
+
+  #[tokio::test]
+  async fn publications_reach_every_subscriber_once() {
+    let (a, mut a_messages) = peer(|x| x.subscribe("news")).await;
+    let (b, mut b_messages) = peer(|x| x.subscribe("news")).await;
+    let (c, mut c_messages) = peer(|x| x.subscribe("news")).await;
+    // A line a - b - c, so b forwards what a publishes to c.
+    connect(&a, &b).await;
+    connect(&c, &b).await;
+    until("subscriptions are known", || {
+      a.subscribers("news") == vec![b.socket_addr] && b.subscribers("news").len() == 2
+    })
+    .await;
+    let sent = a.publish("news", b"extra".to_vec()).await;
+    assert_eq!(sent.delivered, vec![b.socket_addr]);
+    for (messages, from) in [
+      (&mut b_messages, a.socket_addr),
+      (&mut c_messages, b.socket_addr),
+    ] {
+      let (peer, msg) = next(messages).await;
+      assert_eq!(peer, from);
+      match decode(&msg) {
+        protocol::Message::Publish { topic, payload, .. } => {
+          assert_eq!(
+            (topic.as_str(), payload.as_slice()),
+            ("news", &b"extra"[..])
+          )
+        }
+        message => panic!("expected a publication, got {:?}", message),
+      }
+    }
+    // Nothing comes back to the publisher, or twice to anyone.
+    sleep(Duration::from_millis(300)).await;
+    assert!(a_messages.try_recv().is_err());
+    assert!(b_messages.try_recv().is_err());
+    assert!(c_messages.try_recv().is_err());
+  }
+
+  #[tokio::test]
+  async fn stored_messages_reach_peers_once_back() {
+    let path = temp_path("outbox");
+    let (a, _a_messages) = peer(|x| x.outbox(&path, OutboxLimits::default())).await;
+    let (b, mut b_messages) = peer(|x| x).await;
+    connect(&b, &a).await;
+    a.node.disconnect_from(&b.socket_addr);
+    until("both sides see the disconnection", || {
+      a.peer_state(&b.socket_addr) == Some(PeerState::Disconnected)
+        && b.peer_state(&a.socket_addr) == Some(PeerState::Disconnected)
+    })
+    .await;
+    a.send_message(Bytes::from_static(b"later"), &b.socket_addr)
+      .await
+      .unwrap();
+    assert_eq!(a.stored(&b.socket_addr), 1);
+
+    connect(&b, &a).await;
+    assert_eq!(
+      next(&mut b_messages).await,
+      (a.socket_addr, Bytes::from_static(b"later"))
+    );
+    until("the message is acknowledged", || {
+      a.stored(&b.socket_addr) == 0
+    })
+    .await;
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[tokio::test]
+  async fn peers_prove_their_identities() {
+    let paths = (temp_path("a.pk8"), temp_path("b.pk8"));
+    let a_identity = Identity::load_or_generate(&paths.0).unwrap();
+    let b_identity = Identity::load_or_generate(&paths.1).unwrap();
+    let (a, _a_messages) = peer(|x| x.identity(a_identity.clone())).await;
+    let (b, _b_messages) = peer(|x| x.identity(b_identity.clone())).await;
+    connect(&b, &a).await;
+    until("both identities are proven", || {
+      a.identity_of(&b.socket_addr).as_deref() == Some(b_identity.public_key())
+        && b.identity_of(&a.socket_addr).as_deref() == Some(a_identity.public_key())
+    })
+    .await;
+    let _ = std::fs::remove_file(&paths.0);
+    let _ = std::fs::remove_file(&paths.1);
+  }
+}
@@ -0,0 +1,343 @@
+//! QUIC endpoint of a [`Peer`](crate::peer::Peer).
+//!
+//! A peer accepts and opens connections on one UDP socket and keeps the
+//! connections to each other peer in a pool. A message is a 4-byte
+//! big-endian length and that many bytes. [`Endpoint::send_message`] sends
+//! each message on a unidirectional stream of its own, read in the order
+//! the streams were opened, and [`Endpoint::open_stream`] opens a stream
+//! carrying messages one after another. Messages from every connection
+//! come out of one [`Incoming`] channel, along with the peers that connect
+//! and disconnect.
+//!
+//! Certificates are self-signed and not checked: peers prove who they are
+//! with their [`Identity`](crate::identity::Identity) instead.
+
+use std::{
+  collections::HashMap,
+  convert::TryFrom,
+  net::SocketAddr,
+  sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::{debug, trace};
+
+use crate::{protocol, tls, QvpnError, Result};
+
+/// ALPN protocol peers speak.
+pub const ALPN: &[u8] = b"qvpn-peer";
+
+/// Largest message accepted: the largest frame, with room for a relay or
+/// rendezvous header around it.
+pub const MAX_MESSAGE: usize = protocol::MAX_FRAME + 64 * 1024;
+
+/// Name peers connect to, which their self-signed certificates are for.
+const SERVER_NAME: &str = "qvpn-peer";
+
+/// What the peers of an [`Endpoint`] send and how they come and go.
+pub struct Incoming {
+  /// Peers that connected to us.
+  pub connections: UnboundedReceiver<SocketAddr>,
+  /// Messages from any peer.
+  pub messages: UnboundedReceiver<(SocketAddr, Bytes)>,
+  /// Peers whose last connection closed.
+  pub disconnections: UnboundedReceiver<SocketAddr>,
+}
+
+/// Connections to other peers, shared by every clone.
+#[derive(Clone)]
+pub struct Endpoint {
+  endpoint: quinn::Endpoint,
+  pool: Arc<Mutex<HashMap<SocketAddr, Vec<quinn::Connection>>>>,
+  /// Held while connecting to an address, so concurrent attempts share one
+  /// connection.
+  connecting: Arc<Mutex<HashMap<SocketAddr, Arc<tokio::sync::Mutex<()>>>>>,
+  messages: UnboundedSender<(SocketAddr, Bytes)>,
+  disconnections: UnboundedSender<SocketAddr>,
+}
+
+impl std::fmt::Debug for Endpoint {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Endpoint")
+      .field("local_addr", &self.endpoint.local_addr().ok())
+      .finish_non_exhaustive()
+  }
+}
+
+impl Endpoint {
+  /// Listens on `addr`, with `transport` for every connection.
+  pub fn bind(addr: SocketAddr, transport: quinn::TransportConfig) -> Result<(Self, Incoming)> {
+    let transport = Arc::new(transport);
+
+    let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.into()])?;
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+      certified.signing_key.serialize_der(),
+    ));
+    let mut crypto = rustls::ServerConfig::builder_with_provider(tls::provider())
+      .with_protocol_versions(&[&rustls::version::TLS13])?
+      .with_no_client_auth()
+      .with_single_cert(vec![certified.cert.der().clone()], key)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut server_config =
+      quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+    server_config.transport_config(transport.clone());
+
+    let mut crypto = tls::peer_client_config()?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let mut client_config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(crypto)?));
+    client_config.transport_config(transport);
+
+    let mut endpoint = quinn::Endpoint::server(server_config, addr)?;
+    endpoint.set_default_client_config(client_config);
+
+    let (messages_tx, messages) = mpsc::unbounded_channel();
+    let (connections_tx, connections) = mpsc::unbounded_channel();
+    let (disconnections_tx, disconnections) = mpsc::unbounded_channel();
+    let node = Endpoint {
+      endpoint,
+      pool: Arc::default(),
+      connecting: Arc::default(),
+      messages: messages_tx,
+      disconnections: disconnections_tx,
+    };
+    let accepting = node.clone();
+    tokio::spawn(async move {
+      while let Some(incoming) = accepting.endpoint.accept().await {
+        let (node, connections) = (accepting.clone(), connections_tx.clone());
+        tokio::spawn(async move {
+          match incoming.await {
+            Ok(connection) => {
+              let peer = connection.remote_address();
+              node.serve(connection);
+              let _ = connections.send(peer);
+            }
+            Err(err) => debug!("incoming peer connection failed: {}", err),
+          }
+        });
+      }
+      debug!("peer endpoint closed");
+    });
+    Ok((
+      node,
+      Incoming {
+        connections,
+        messages,
+        disconnections,
+      },
+    ))
+  }
+
+  /// Local address the endpoint is bound to.
+  pub fn local_addr(&self) -> Result<SocketAddr> {
+    Ok(self.endpoint.local_addr()?)
+  }
+
+  /// Connects to `peer`, unless connected already.
+  pub async fn connect_to(&self, peer: &SocketAddr) -> Result<()> {
+    let lock = self
+      .connecting
+      .lock()
+      .expect("peer pool lock poisoned")
+      .entry(*peer)
+      .or_default()
+      .clone();
+    let result = async {
+      let _connecting = lock.lock().await;
+      if self.connection(peer).is_some() {
+        return Ok(());
+      }
+      let connection = self.endpoint.connect(*peer, SERVER_NAME)?.await?;
+      trace!(%peer, "connected");
+      self.serve(connection);
+      Ok(())
+    }
+    .await;
+    let mut connecting = self.connecting.lock().expect("peer pool lock poisoned");
+    // Only the last attempt waiting for the lock forgets it.
+    if Arc::strong_count(&lock) == 2 {
+      connecting.remove(peer);
+    }
+    result
+  }
+
+  /// Closes every connection to `peer`.
+  pub fn disconnect_from(&self, peer: &SocketAddr) {
+    let connections = self
+      .pool
+      .lock()
+      .expect("peer pool lock poisoned")
+      .remove(peer);
+    for connection in connections.into_iter().flatten() {
+      connection.close(0u32.into(), b"");
+    }
+  }
+
+  /// Sends `msg` to `peer` on a stream of its own. Fails unless connected.
+  pub async fn send_message(&self, msg: Bytes, peer: &SocketAddr) -> Result<()> {
+    let connection = self
+      .connection(peer)
+      .ok_or(QvpnError::Disconnected(*peer))?;
+    let mut send = connection.open_uni().await?;
+    write_message(&mut send, &msg).await?;
+    send.finish()?;
+    Ok(())
+  }
+
+  /// Connects to `peer` unless connected already, and opens a stream of
+  /// its own to it.
+  pub async fn open_stream(&self, peer: &SocketAddr) -> Result<(SendStream, RecvStream)> {
+    self.connect_to(peer).await?;
+    let connection = self
+      .connection(peer)
+      .ok_or(QvpnError::Disconnected(*peer))?;
+    let (send, recv) = connection.open_bi().await?;
+    Ok((SendStream(send), RecvStream(recv)))
+  }
+
+  /// The newest open connection to `peer`.
+  fn connection(&self, peer: &SocketAddr) -> Option<quinn::Connection> {
+    let pool = self.pool.lock().expect("peer pool lock poisoned");
+    pool.get(peer)?.last().cloned()
+  }
+
+  /// Pools `connection` and passes on the messages it carries until it
+  /// closes.
+  fn serve(&self, connection: quinn::Connection) {
+    let peer = connection.remote_address();
+    self
+      .pool
+      .lock()
+      .expect("peer pool lock poisoned")
+      .entry(peer)
+      .or_default()
+      .push(connection.clone());
+    let node = self.clone();
+    tokio::spawn(async move {
+      futures::join!(
+        node.read_uni_streams(peer, &connection),
+        node.read_bi_streams(peer, &connection)
+      );
+      debug!(%peer, "connection closed: {}", connection.closed().await);
+      let mut pool = node.pool.lock().expect("peer pool lock poisoned");
+      if let Some(connections) = pool.get_mut(&peer) {
+        connections.retain(|x| x.stable_id() != connection.stable_id());
+        if !connections.is_empty() {
+          return;
+        }
+        pool.remove(&peer);
+      }
+      let _ = node.disconnections.send(peer);
+    });
+  }
+
+  /// Reads the message on each unidirectional stream, one stream after
+  /// another so messages arrive in the order they were sent.
+  async fn read_uni_streams(&self, peer: SocketAddr, connection: &quinn::Connection) {
+    while let Ok(mut recv) = connection.accept_uni().await {
+      loop {
+        match read_message(&mut recv).await {
+          Ok(Some(msg)) => {
+            let _ = self.messages.send((peer, msg));
+          }
+          Ok(None) => break,
+          Err(err) => {
+            debug!(%peer, "reading message failed: {}", err);
+            break;
+          }
+        }
+      }
+    }
+  }
+
+  /// Reads the messages on each bidirectional stream as they come.
+  async fn read_bi_streams(&self, peer: SocketAddr, connection: &quinn::Connection) {
+    while let Ok((send, mut recv)) = connection.accept_bi().await {
+      let messages = self.messages.clone();
+      tokio::spawn(async move {
+        // Kept open until the other side is done.
+        let _send = send;
+        loop {
+          match read_message(&mut recv).await {
+            Ok(Some(msg)) => {
+              let _ = messages.send((peer, msg));
+            }
+            Ok(None) => break,
+            Err(err) => {
+              debug!(%peer, "reading stream failed: {}", err);
+              break;
+            }
+          }
+        }
+      });
+    }
+  }
+}
+
+/// Sending half of a stream from [`Endpoint::open_stream`].
+#[derive(Debug)]
+pub struct SendStream(quinn::SendStream);
+
+impl SendStream {
+  /// Sends `msg` after any sent before.
+  pub async fn send_message(&mut self, msg: Bytes) -> Result<()> {
+    write_message(&mut self.0, &msg).await
+  }
+
+  /// Ends the stream once everything sent is delivered.
+  pub fn finish(mut self) -> Result<()> {
+    self.0.finish()?;
+    Ok(())
+  }
+}
+
+/// Receiving half of a stream from [`Endpoint::open_stream`].
+#[derive(Debug)]
+pub struct RecvStream(quinn::RecvStream);
+
+impl RecvStream {
+  /// The next message, or `None` once the stream ended.
+  pub async fn next(&mut self) -> Result<Option<Bytes>> {
+    read_message(&mut self.0).await
+  }
+}
+
+async fn write_message(send: &mut quinn::SendStream, msg: &[u8]) -> Result<()> {
+  let len = u32::try_from(msg.len())
+    .ok()
+    .filter(|len| *len as usize <= MAX_MESSAGE)
+    .ok_or_else(|| QvpnError::InvalidInput(format!("{} byte message too long", msg.len())))?;
+  send.write_all(&len.to_be_bytes()).await?;
+  send.write_all(msg).await?;
+  Ok(())
+}
+
+/// Reads the next message on `recv`, or `None` if the stream ended before
+/// another one.
+async fn read_message(recv: &mut quinn::RecvStream) -> Result<Option<Bytes>> {
+  let mut len = [0; 4];
+  match recv.read_exact(&mut len).await {
+    Ok(()) => {}
+    Err(quinn::ReadExactError::FinishedEarly(0)) => return Ok(None),
+    Err(err) => return Err(read_error(err)),
+  }
+  let len = u32::from_be_bytes(len) as usize;
+  if len > MAX_MESSAGE {
+    return Err(QvpnError::Protocol(format!(
+      "{} byte message exceeds the {} byte limit",
+      len, MAX_MESSAGE
+    )));
+  }
+  let mut msg = vec![0; len];
+  recv.read_exact(&mut msg).await.map_err(read_error)?;
+  Ok(Some(msg.into()))
+}
+
+fn read_error(err: quinn::ReadExactError) -> QvpnError {
+  match err {
+    quinn::ReadExactError::FinishedEarly(_) => QvpnError::Protocol("message cut short".into()),
+    quinn::ReadExactError::ReadError(err) => QvpnError::Io(err.into()),
+  }
+}
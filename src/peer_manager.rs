@@ -0,0 +1,212 @@
+//! Connection states of the peers a node knows.
+//!
+//! A [`PeerManager`] follows each peer address through the [`PeerState`]s:
+//! `Connecting` while a connection is attempted, `Connected` once it is up,
+//! `Disconnected` when it closes or a message to it can't be sent, and
+//! `Banned` when the peer proves an identity other than the one recorded
+//! for its address. Only connected peers are sent messages, and banned ones
+//! are refused. Peers that stay disconnected or banned for the reap
+//! interval are forgotten. Every change is published as a [`PeerEvent`].
+
+use std::{
+  collections::HashMap,
+  net::SocketAddr,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use tokio::sync::broadcast;
+use tracing::debug;
+
+/// How long disconnected and banned peers are remembered by default.
+pub const DEFAULT_REAP_AFTER: Duration = Duration::from_secs(600);
+
+/// Events buffered for each subscriber before the oldest are dropped.
+const EVENT_CAPACITY: usize = 64;
+
+/// Where the connection to a peer stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+  /// A connection is being attempted.
+  Connecting,
+  /// Connected; messages are sent to the peer.
+  Connected,
+  /// The connection closed, failed, or couldn't carry a message.
+  Disconnected,
+  /// The peer proved the wrong identity and is refused.
+  Banned,
+}
+
+/// Something that happened to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEvent {
+  /// The peer entered a new state.
+  Changed { peer: SocketAddr, state: PeerState },
+  /// The peer was disconnected or banned for the reap interval and is
+  /// forgotten.
+  Reaped(SocketAddr),
+}
+
+/// The states of a node's peers.
+#[derive(Debug)]
+pub struct PeerManager {
+  reap_after: Duration,
+  inner: Mutex<Inner>,
+  events: broadcast::Sender<PeerEvent>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+  peers: HashMap<SocketAddr, Entry>,
+  /// Counts connections, to list peers in the order they connected.
+  next: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+  state: PeerState,
+  /// When the peer entered its state.
+  since: Instant,
+  /// Order the peer last connected in.
+  order: u64,
+}
+
+impl PeerManager {
+  /// Forgets disconnected and banned peers after `reap_after`.
+  pub fn new(reap_after: Duration) -> Self {
+    PeerManager {
+      reap_after,
+      inner: Mutex::default(),
+      events: broadcast::channel(EVENT_CAPACITY).0,
+    }
+  }
+
+  /// Subscribes to the [`PeerEvent`]s from now on.
+  pub fn subscribe(&self) -> broadcast::Receiver<PeerEvent> {
+    self.events.subscribe()
+  }
+
+  /// State of `peer`, unless it is unknown or was forgotten.
+  pub fn state(&self, peer: &SocketAddr) -> Option<PeerState> {
+    let inner = self.inner.lock().unwrap();
+    inner.peers.get(peer).map(|entry| entry.state)
+  }
+
+  /// Whether `peer` is connected.
+  pub fn is_connected(&self, peer: &SocketAddr) -> bool {
+    self.state(peer) == Some(PeerState::Connected)
+  }
+
+  /// Connected peers, in the order they connected.
+  pub fn connected(&self) -> Vec<SocketAddr> {
+    let inner = self.inner.lock().unwrap();
+    let mut peers: Vec<_> = inner
+      .peers
+      .iter()
+      .filter(|(_, entry)| entry.state == PeerState::Connected)
+      .map(|(peer, entry)| (entry.order, *peer))
+      .collect();
+    peers.sort();
+    peers.into_iter().map(|(_, peer)| peer).collect()
+  }
+
+  /// Marks a connection attempt to `peer`, returning false if it is
+  /// connected, being connected to or banned already.
+  pub fn start_connecting(&self, peer: SocketAddr) -> bool {
+    self.transition(peer, PeerState::Connecting, |state| {
+      matches!(state, None | Some(PeerState::Disconnected))
+    })
+  }
+
+  /// Marks `peer` connected, returning false if it was already, or is
+  /// banned.
+  pub fn set_connected(&self, peer: SocketAddr) -> bool {
+    self.transition(peer, PeerState::Connected, |state| {
+      !matches!(state, Some(PeerState::Connected) | Some(PeerState::Banned))
+    })
+  }
+
+  /// Marks a connected peer, or one being connected to, disconnected,
+  /// returning false if it was neither.
+  pub fn set_disconnected(&self, peer: SocketAddr) -> bool {
+    self.transition(peer, PeerState::Disconnected, |state| {
+      matches!(
+        state,
+        Some(PeerState::Connecting) | Some(PeerState::Connected)
+      )
+    })
+  }
+
+  /// Refuses `peer` until it is reaped.
+  pub fn ban(&self, peer: SocketAddr) {
+    self.transition(peer, PeerState::Banned, |state| {
+      state != Some(PeerState::Banned)
+    });
+  }
+
+  /// Forgets the peers disconnected or banned for longer than the reap
+  /// interval, returning them.
+  pub fn reap(&self) -> Vec<SocketAddr> {
+    let reaped: Vec<_> = {
+      let mut inner = self.inner.lock().unwrap();
+      let expired: Vec<_> = inner
+        .peers
+        .iter()
+        .filter(|(_, entry)| {
+          matches!(entry.state, PeerState::Disconnected | PeerState::Banned)
+            && entry.since.elapsed() >= self.reap_after
+        })
+        .map(|(peer, _)| *peer)
+        .collect();
+      for peer in &expired {
+        inner.peers.remove(peer);
+      }
+      expired
+    };
+    for peer in &reaped {
+      debug!(%peer, "forgetting peer");
+      let _ = self.events.send(PeerEvent::Reaped(*peer));
+    }
+    reaped
+  }
+
+  /// Moves `peer` to `state` if `allowed` from the state it is in,
+  /// returning whether it moved.
+  fn transition(
+    &self,
+    peer: SocketAddr,
+    state: PeerState,
+    allowed: impl FnOnce(Option<PeerState>) -> bool,
+  ) -> bool {
+    {
+      let mut inner = self.inner.lock().unwrap();
+      let previous = inner.peers.get(&peer);
+      if !allowed(previous.map(|entry| entry.state)) {
+        return false;
+      }
+      let mut order = previous.map_or(inner.next, |entry| entry.order);
+      if state == PeerState::Connected {
+        order = inner.next;
+        inner.next += 1;
+      }
+      let since = Instant::now();
+      inner.peers.insert(
+        peer,
+        Entry {
+          state,
+          since,
+          order,
+        },
+      );
+    }
+    debug!(%peer, ?state, "peer state");
+    let _ = self.events.send(PeerEvent::Changed { peer, state });
+    true
+  }
+}
+
+impl Default for PeerManager {
+  fn default() -> Self {
+    PeerManager::new(DEFAULT_REAP_AFTER)
+  }
+}
@@ -0,0 +1,163 @@
+//! Peers remembered across restarts of a peer node.
+//!
+//! [`PeerStore`] records every peer the node has been connected to, with
+//! the time it was last seen and the public key and nickname it is known
+//! by, and writes them to a JSON file on every connect and disconnect. A
+//! restarted node reconnects to the peers it finds there.
+
+use std::{
+  fs,
+  io::{self, Write},
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+  time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// What is known about one peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerRecord {
+  /// Address the peer was last connected at.
+  pub address: SocketAddr,
+  /// Public key the peer identified itself with, if any.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub public_key: Option<String>,
+  /// Seconds since the Unix epoch when the peer was last seen.
+  pub last_seen: u64,
+  /// Name given to the peer locally.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub nickname: Option<String>,
+}
+
+/// Known peers, backed by a file.
+#[derive(Debug)]
+pub struct PeerStore {
+  path: PathBuf,
+  peers: Mutex<Vec<PeerRecord>>,
+}
+
+impl PeerStore {
+  /// Opens the store at `path`. A missing or unreadable file starts an
+  /// empty store.
+  pub fn open(path: impl Into<PathBuf>) -> Arc<Self> {
+    let path = path.into();
+    let peers = match load(&path) {
+      Ok(peers) => peers,
+      Err(err) => {
+        if err.kind() != io::ErrorKind::NotFound {
+          warn!(path = %path.display(), "ignoring peer store: {}", err);
+        }
+        vec![]
+      }
+    };
+    debug!(path = %path.display(), peers = peers.len(), "peer store opened");
+    Arc::new(PeerStore {
+      path,
+      peers: Mutex::new(peers),
+    })
+  }
+
+  /// File the peers are stored in.
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// All known peers, most recently seen first.
+  pub fn peers(&self) -> Vec<PeerRecord> {
+    let mut peers = self.peers.lock().expect("peer store lock poisoned").clone();
+    peers.sort_by_key(|x| std::cmp::Reverse(x.last_seen));
+    peers
+  }
+
+  /// The record for the peer at `address`.
+  pub fn get(&self, address: &SocketAddr) -> Option<PeerRecord> {
+    let peers = self.peers.lock().expect("peer store lock poisoned");
+    peers.iter().find(|x| x.address == *address).cloned()
+  }
+
+  /// Records that `address` connected, adding it if it is new.
+  pub fn connected(&self, address: SocketAddr) {
+    self.update(address, |_| {});
+  }
+
+  /// Records that `address` disconnected. The peer stays known so it can be
+  /// reconnected to later.
+  pub fn disconnected(&self, address: SocketAddr) {
+    self.update(address, |_| {});
+  }
+
+  /// Sets the local name of the peer at `address`, or clears it.
+  pub fn set_nickname(&self, address: SocketAddr, nickname: Option<String>) {
+    self.update(address, |record| record.nickname = nickname);
+  }
+
+  /// Sets the public key the peer at `address` identified itself with.
+  pub fn set_public_key(&self, address: SocketAddr, public_key: String) {
+    self.update(address, |record| record.public_key = Some(public_key));
+  }
+
+  /// Forgets the peer at `address`, returning whether it was known.
+  pub fn remove(&self, address: &SocketAddr) -> bool {
+    let mut peers = self.peers.lock().expect("peer store lock poisoned");
+    let len = peers.len();
+    peers.retain(|x| x.address != *address);
+    let removed = peers.len() != len;
+    if removed {
+      self.save(&peers);
+    }
+    removed
+  }
+
+  /// Marks `address` as seen now, applies `f` to its record and saves.
+  fn update(&self, address: SocketAddr, f: impl FnOnce(&mut PeerRecord)) {
+    let mut peers = self.peers.lock().expect("peer store lock poisoned");
+    let index = match peers.iter().position(|x| x.address == address) {
+      Some(index) => index,
+      None => {
+        peers.push(PeerRecord {
+          address,
+          public_key: None,
+          last_seen: 0,
+          nickname: None,
+        });
+        peers.len() - 1
+      }
+    };
+    let record = &mut peers[index];
+    record.last_seen = now();
+    f(record);
+    self.save(&peers);
+  }
+
+  fn save(&self, peers: &[PeerRecord]) {
+    if let Err(err) = save(&self.path, peers) {
+      warn!(path = %self.path.display(), "couldn't save peer store: {}", err);
+    }
+  }
+}
+
+fn now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|x| x.as_secs())
+    .unwrap_or_default()
+}
+
+fn load(path: &Path) -> io::Result<Vec<PeerRecord>> {
+  Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+/// Writes the peers to a temporary file, then moves it over `path` so a
+/// crash never leaves a truncated store.
+fn save(path: &Path, peers: &[PeerRecord]) -> io::Result<()> {
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir)?;
+  }
+  let partial = path.with_extension("json.part");
+  let mut file = fs::File::create(&partial)?;
+  file.write_all(&serde_json::to_vec_pretty(peers)?)?;
+  fs::rename(&partial, path)
+}
@@ -0,0 +1,206 @@
+//! Dropping root once privileged setup is done.
+//!
+//! A server started as root opens its TUN interface and binds its sockets,
+//! then switches to an unprivileged user before it accepts a connection, so
+//! QUIC, TLS and request parsing never run as root.
+//!
+//! A tunnel keeps changing the network after that: it routes the networks
+//! of site gateways, and removes its firewall rules and restores IPv4
+//! forwarding on exit. On Linux the server keeps `CAP_NET_ADMIN` for that,
+//! and passes it on to the `ip`, `nft` and `iptables` commands and hooks it
+//! runs, which run as the unprivileged user too.
+
+#[cfg(unix)]
+use std::{ffi::CString, io, mem, ptr};
+
+#[cfg(unix)]
+use tracing::info;
+
+use crate::{QvpnError, Result};
+
+/// Switches the whole process to `user`, and to `group` or else the user's
+/// primary group, giving up every supplementary group. Either may be a name
+/// or a numeric id.
+///
+/// With `net_admin`, keeps `CAP_NET_ADMIN` for the process and the
+/// programs it runs, which only Linux supports.
+///
+/// Fails unless the process runs as root, and if root could be regained
+/// afterwards.
+#[cfg(unix)]
+pub fn drop_privileges(user: &str, group: Option<&str>, net_admin: bool) -> Result<()> {
+  if net_admin && !cfg!(target_os = "linux") {
+    return Err(QvpnError::Unsupported(
+      "a tunnel can't keep changing routes and firewall rules after dropping root on this platform"
+        .into(),
+    ));
+  }
+  let (uid, primary) = lookup_user(user)?;
+  let gid = match group {
+    Some(group) => lookup_group(group)?,
+    None => primary.ok_or_else(|| {
+      QvpnError::InvalidInput(format!("user {} has no primary group; give a group", user))
+    })?,
+  };
+  // Groups first: changing them takes the privileges the user gives up.
+  cvt(unsafe { libc::setgroups(1, &gid) })?;
+  cvt(unsafe { libc::setgid(gid) })?;
+  #[cfg(target_os = "linux")]
+  if net_admin {
+    // Keeps the permitted capabilities across the switch, to pick from.
+    cvt(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1) })?;
+  }
+  cvt(unsafe { libc::setuid(uid) })?;
+  #[cfg(target_os = "linux")]
+  if net_admin {
+    keep_net_admin()?;
+  }
+  if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+    return Err(QvpnError::Unsupported(
+      "root privileges could be regained after dropping them".into(),
+    ));
+  }
+  info!(user, uid, gid, net_admin, "dropped root privileges");
+  Ok(())
+}
+
+/// Switches the whole process to `user` and `group`.
+#[cfg(not(unix))]
+pub fn drop_privileges(_user: &str, _group: Option<&str>, _net_admin: bool) -> Result<()> {
+  Err(QvpnError::Unsupported(
+    "dropping privileges is not supported on this platform".into(),
+  ))
+}
+
+/// Bit of `CAP_NET_ADMIN` in a capability set.
+#[cfg(target_os = "linux")]
+const CAP_NET_ADMIN: u32 = 12;
+
+/// `_LINUX_CAPABILITY_VERSION_3`, which takes two [`CapData`].
+#[cfg(target_os = "linux")]
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapHeader {
+  version: u32,
+  pid: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+  effective: u32,
+  permitted: u32,
+  inheritable: u32,
+}
+
+/// Narrows the capabilities the process kept across `setuid` down to
+/// `CAP_NET_ADMIN`, and makes it ambient so the programs it runs get it too.
+#[cfg(target_os = "linux")]
+fn keep_net_admin() -> io::Result<()> {
+  let mut header = CapHeader {
+    version: CAPABILITY_VERSION_3,
+    pid: 0,
+  };
+  let bit = 1 << CAP_NET_ADMIN;
+  let mut data = [CapData::default(); 2];
+  data[0] = CapData {
+    effective: bit,
+    permitted: bit,
+    inheritable: bit,
+  };
+  let ret = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_mut_ptr()) };
+  cvt(ret as libc::c_int)?;
+  cvt(unsafe {
+    libc::prctl(
+      libc::PR_CAP_AMBIENT,
+      libc::PR_CAP_AMBIENT_RAISE,
+      CAP_NET_ADMIN as libc::c_ulong,
+      0 as libc::c_ulong,
+      0 as libc::c_ulong,
+    )
+  })?;
+  cvt(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0) })
+}
+
+/// Id and, if it has a password entry, primary group of `user`.
+#[cfg(unix)]
+fn lookup_user(user: &str) -> Result<(libc::uid_t, Option<libc::gid_t>)> {
+  let mut entry: libc::passwd = unsafe { mem::zeroed() };
+  let mut found = ptr::null_mut();
+  let mut buf = vec![0; LOOKUP_BUF_LEN];
+  let id = user.parse::<libc::uid_t>().ok();
+  let ret = match id {
+    Some(uid) => unsafe {
+      libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut found)
+    },
+    None => {
+      let name = c_name(user)?;
+      unsafe {
+        libc::getpwnam_r(
+          name.as_ptr(),
+          &mut entry,
+          buf.as_mut_ptr(),
+          buf.len(),
+          &mut found,
+        )
+      }
+    }
+  };
+  if ret != 0 {
+    return Err(io::Error::from_raw_os_error(ret).into());
+  }
+  match (found.is_null(), id) {
+    (false, _) => Ok((entry.pw_uid, Some(entry.pw_gid))),
+    (true, Some(uid)) => Ok((uid, None)),
+    (true, None) => Err(QvpnError::InvalidInput(format!("no user named {}", user))),
+  }
+}
+
+/// Id of `group`.
+#[cfg(unix)]
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+  if let Ok(gid) = group.parse() {
+    return Ok(gid);
+  }
+  let name = c_name(group)?;
+  let mut entry: libc::group = unsafe { mem::zeroed() };
+  let mut found = ptr::null_mut();
+  let mut buf = vec![0; LOOKUP_BUF_LEN];
+  let ret = unsafe {
+    libc::getgrnam_r(
+      name.as_ptr(),
+      &mut entry,
+      buf.as_mut_ptr(),
+      buf.len(),
+      &mut found,
+    )
+  };
+  if ret != 0 {
+    return Err(io::Error::from_raw_os_error(ret).into());
+  }
+  if found.is_null() {
+    return Err(QvpnError::InvalidInput(format!("no group named {}", group)));
+  }
+  Ok(entry.gr_gid)
+}
+
+/// Room for the strings of a password or group entry.
+#[cfg(unix)]
+const LOOKUP_BUF_LEN: usize = 16 * 1024;
+
+#[cfg(unix)]
+fn c_name(name: &str) -> Result<CString> {
+  CString::new(name).map_err(|_| QvpnError::InvalidInput(format!("invalid name {:?}", name)))
+}
+
+/// Turns the `-1` a libc call fails with into the error in `errno`.
+#[cfg(unix)]
+fn cvt(ret: libc::c_int) -> io::Result<()> {
+  if ret < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
@@ -0,0 +1,165 @@
+//! Typed messages exchanged by peers.
+//!
+//! Each [`Message`] is bincode encoded and framed with its length as a
+//! 32-bit big-endian integer, so several messages can share one peer message
+//! or stream and a message is never split at an arbitrary byte.
+
+use std::net::SocketAddr;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  identity::{self, Identity},
+  QvpnError, Result,
+};
+
+/// Largest encoded message accepted.
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+
+/// A message between peers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message {
+  /// A line of chat, sent to one peer if `direct` and to all otherwise.
+  Chat { text: String, direct: bool },
+  /// Asks for a [`Message::Pong`].
+  Ping,
+  /// Answers a [`Message::Ping`].
+  Pong,
+  /// Asks for the peers the receiver is connected to.
+  PeerListRequest,
+  /// Peers the sender is connected to.
+  PeerList { peers: Vec<SocketAddr> },
+  /// Offers a file to the receiver.
+  FileOffer {
+    name: String,
+    size: u64,
+    /// Hex encoded SHA-256 of the contents.
+    hash: String,
+  },
+  /// Accepts a [`Message::FileOffer`]; the receiver already has the bytes
+  /// before `offset`.
+  FileAccept { hash: String, offset: u64 },
+  /// Declines a [`Message::FileOffer`].
+  FileReject { hash: String },
+  /// Bytes of an accepted file starting at `offset`.
+  FileChunk {
+    hash: String,
+    offset: u64,
+    data: Vec<u8>,
+  },
+  /// Starts the identity handshake: the sender's public key and a challenge
+  /// for the receiver to sign.
+  Hello {
+    public_key: Vec<u8>,
+    challenge: Vec<u8>,
+  },
+  /// Answers a [`Message::Hello`] challenge, proving the sender holds the
+  /// key behind `public_key`.
+  Proof {
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+  },
+  /// Encoded messages signed by the peer that wrote them, which need not be
+  /// the peer that delivered them.
+  Signed {
+    origin: Vec<u8>,
+    body: Vec<u8>,
+    signature: Vec<u8>,
+  },
+  /// Topics the sender subscribes to, replacing any it sent before.
+  Subscribe { topics: Vec<String> },
+  /// A message for the subscribers of `topic`, forwarded from subscriber to
+  /// subscriber and told apart by `id`.
+  Publish {
+    id: u64,
+    topic: String,
+    payload: Vec<u8>,
+  },
+  /// A message kept while the receiver was offline, delivered once it came
+  /// back. Answered with a [`Message::Ack`].
+  Stored { id: u64, body: Vec<u8> },
+  /// Acknowledges [`Message::Stored`] messages, which the sender then
+  /// forgets.
+  Ack { ids: Vec<u64> },
+  /// Part `index` of `count` of a message too big to send whole.
+  Chunk {
+    id: u64,
+    index: u32,
+    count: u32,
+    data: Vec<u8>,
+  },
+}
+
+impl Message {
+  /// Encodes the message as a single frame.
+  pub fn encode(&self) -> Bytes {
+    let body = bincode::serialize(self).expect("message serializes");
+    let mut buf = BytesMut::with_capacity(4 + body.len());
+    buf.put_u32(body.len() as u32);
+    buf.put_slice(&body);
+    buf.freeze()
+  }
+
+  /// Wraps the message in a [`Message::Signed`] by `identity`.
+  pub fn sign(&self, identity: &Identity) -> Message {
+    let body = self.encode().to_vec();
+    Message::Signed {
+      origin: identity.public_key().to_vec(),
+      signature: identity.sign(&body),
+      body,
+    }
+  }
+
+  /// Unwraps a [`Message::Signed`], checking its signature, and returns the
+  /// public key of its author with the messages it carries. Other messages
+  /// are returned as they are, without an author.
+  pub fn open(self) -> Result<(Option<Vec<u8>>, Vec<Message>)> {
+    match self {
+      Message::Signed {
+        origin,
+        body,
+        signature,
+      } => {
+        if !identity::verify(&origin, &body, &signature) {
+          return Err(QvpnError::Protocol(format!(
+            "bad signature from {}",
+            identity::fingerprint(&origin)
+          )));
+        }
+        Ok((Some(origin), Message::decode(&Bytes::from(body))?))
+      }
+      message => Ok((None, vec![message])),
+    }
+  }
+
+  /// Decodes every frame in `msg`.
+  pub fn decode(msg: &Bytes) -> Result<Vec<Message>> {
+    let mut buf = msg.clone();
+    let mut messages = vec![];
+    while buf.has_remaining() {
+      if buf.remaining() < 4 {
+        return Err(QvpnError::Protocol("truncated frame header".into()));
+      }
+      let len = buf.get_u32() as usize;
+      if len > MAX_FRAME {
+        return Err(QvpnError::Protocol(format!(
+          "{} byte frame exceeds the {} byte limit",
+          len, MAX_FRAME
+        )));
+      }
+      if buf.remaining() < len {
+        return Err(QvpnError::Protocol(format!(
+          "frame of {} bytes truncated to {}",
+          len,
+          buf.remaining()
+        )));
+      }
+      let body = buf.split_to(len);
+      let message = bincode::deserialize(&body)
+        .map_err(|err| QvpnError::Protocol(format!("bad message: {}", err)))?;
+      messages.push(message);
+    }
+    Ok(messages)
+  }
+}
@@ -0,0 +1,160 @@
+//! TCP connections carried over QUIC streams.
+//!
+//! The client opens a bidirectional stream and sends `CONNECT host:port\r\n`.
+//! The server dials the target, answers with [`CONNECT_OK`] and then copies
+//! bytes between the stream and the TCP connection until both sides finish.
+
+use std::{future::Future, str};
+
+use tokio::{
+  io::{self, AsyncWriteExt},
+  net::{TcpListener, TcpStream},
+};
+use tracing::{info, warn};
+
+use crate::{http, QvpnError, Result};
+
+/// Response line sent by the server once the target is connected.
+pub const CONNECT_OK: &[u8] = b"HTTP/3 200 OK\r\n";
+
+/// Formats a request asking the server to connect to `target`.
+pub fn connect_request(target: &str) -> String {
+  format!("CONNECT {}\r\n", target)
+}
+
+/// Extracts the `host:port` target from a `CONNECT` request line, ignoring
+/// an HTTP version after it.
+pub fn connect_target(line: &[u8]) -> Option<Result<&str>> {
+  let target = line.strip_prefix(b"CONNECT ")?;
+  let target = target.strip_suffix(b"\r\n").unwrap_or(target);
+  let end = target
+    .iter()
+    .position(|&c| c == b' ')
+    .unwrap_or(target.len());
+  let target = &target[..end];
+  Some(
+    str::from_utf8(target).map_err(|_| QvpnError::BadRequest("target is not valid utf-8".into())),
+  )
+}
+
+/// Accepts local TCP clients on `listener` and runs `handle` for each of
+/// them with a clone of `connection`.
+///
+/// The server never opens streams of its own, so accepting one only
+/// completes once the connection is closed, which ends the loop.
+pub async fn serve<F, Fut>(
+  listener: &TcpListener,
+  connection: quinn::Connection,
+  handle: F,
+) -> Result<()>
+where
+  F: Fn(TcpStream, quinn::Connection) -> Fut,
+  Fut: Future<Output = Result<()>> + Send + 'static,
+{
+  loop {
+    tokio::select! {
+      accepted = listener.accept() => {
+        let (tcp, peer) = accepted?;
+        let handler = handle(tcp, connection.clone());
+        tokio::spawn(async move {
+          if let Err(err) = handler.await {
+            warn!(%peer, "proxy client failed: {}", err);
+          }
+        });
+      }
+      stream = connection.accept_bi() => {
+        return match stream {
+          Err(err) => Err(err.into()),
+          Ok(_) => Err(QvpnError::Protocol("unexpected stream from server".into())),
+        };
+      }
+    }
+  }
+}
+
+/// Handles one HTTP `CONNECT` request from a local client such as a web
+/// browser, tunnelling it to the server.
+pub async fn handle_http(mut tcp: TcpStream, connection: quinn::Connection) -> Result<()> {
+  let (head, extra) = match http::read_until(&mut tcp, b"\r\n\r\n").await {
+    Ok((head, extra)) if head.ends_with(b"\r\n\r\n") => (head, extra),
+    Ok(_) => return Err(QvpnError::BadRequest("incomplete request head".into())),
+    Err(err) => {
+      let _ = tcp.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+      return Err(err);
+    }
+  };
+  let target = match connect_target(&head) {
+    Some(Ok(target)) => target.to_string(),
+    Some(Err(err)) => {
+      tcp.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+      return Err(err);
+    }
+    None => {
+      tcp
+        .write_all(b"HTTP/1.1 501 Not Implemented\r\n\r\n")
+        .await?;
+      return Err(QvpnError::Unsupported(
+        "only CONNECT requests can be proxied".into(),
+      ));
+    }
+  };
+  info!(%target, "http connect");
+  let (mut send, recv, rest) = match open(&connection, &target).await {
+    Ok(stream) => stream,
+    Err(err) => {
+      tcp.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await?;
+      return Err(err);
+    }
+  };
+  tcp
+    .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+    .await?;
+  send.write_all(&extra).await?;
+  splice(tcp, &rest, send, recv).await?;
+  Ok(())
+}
+
+/// Opens a stream to the server and asks it to connect to `target`.
+///
+/// Returns the stream and any bytes the target sent along with the response.
+pub async fn open(
+  connection: &quinn::Connection,
+  target: &str,
+) -> Result<(quinn::SendStream, quinn::RecvStream, Vec<u8>)> {
+  let (mut send, mut recv) = connection.open_bi().await?;
+  send.write_all(connect_request(target).as_bytes()).await?;
+  let (status, rest) = http::read_until(&mut recv, b"\r\n").await?;
+  if status != CONNECT_OK {
+    return Err(QvpnError::Remote(
+      String::from_utf8_lossy(&status).trim_end().to_string(),
+    ));
+  }
+  Ok((send, recv, rest))
+}
+
+/// Copies bytes both ways between `tcp` and a QUIC stream, after first
+/// writing `prefix` to `tcp`.
+///
+/// Each direction is closed as soon as its source reaches end of file.
+/// Returns the bytes copied from `tcp` to the stream and from the stream to
+/// `tcp`, not counting `prefix`.
+pub async fn splice(
+  mut tcp: TcpStream,
+  prefix: &[u8],
+  mut send: quinn::SendStream,
+  mut recv: quinn::RecvStream,
+) -> Result<(u64, u64)> {
+  let (mut tcp_read, mut tcp_write) = tcp.split();
+  let upstream = async {
+    let copied = io::copy(&mut tcp_read, &mut send).await?;
+    send.finish()?;
+    Ok::<_, QvpnError>(copied)
+  };
+  let downstream = async {
+    tcp_write.write_all(prefix).await?;
+    let copied = io::copy(&mut recv, &mut tcp_write).await?;
+    tcp_write.shutdown().await?;
+    Ok::<_, QvpnError>(copied)
+  };
+  tokio::try_join!(upstream, downstream)
+}
@@ -0,0 +1,161 @@
+//! Pre-shared key authentication.
+//!
+//! Right after the QUIC handshake the client opens a control stream and the
+//! two sides prove to each other that they know the key:
+//!
+//! 1. the client sends a random nonce,
+//! 2. the server answers with a random challenge and an HMAC-SHA256 over
+//!    both, proving it knows the key,
+//! 3. the client checks it and sends its own HMAC over both,
+//! 4. the server checks that and finishes the stream.
+//!
+//! Both HMACs also cover keying material exported from the connection's TLS
+//! session, so a proof made on one connection is worthless on any other: a
+//! machine in the middle, terminating TLS towards each side, can't relay the
+//! exchange between the two.
+//!
+//! The server closes connections that don't complete this in time.
+
+use std::{fs, path::Path, time::Duration};
+
+use ring::{
+  hmac,
+  rand::{SecureRandom, SystemRandom},
+};
+
+use crate::{QvpnError, Result};
+
+/// Bytes of each nonce.
+pub const NONCE_LEN: usize = 32;
+
+/// Shortest key accepted.
+pub const MIN_KEY_LEN: usize = 16;
+
+/// How long the server waits for a client to prove the key by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A pre-shared key.
+#[derive(Clone)]
+pub struct Psk {
+  key: hmac::Key,
+}
+
+impl Psk {
+  /// Creates a key from `secret`, which must be at least [`MIN_KEY_LEN`]
+  /// bytes.
+  pub fn new(secret: &[u8]) -> Result<Self> {
+    if secret.len() < MIN_KEY_LEN {
+      return Err(QvpnError::InvalidInput(format!(
+        "pre-shared key is {} bytes, need at least {}",
+        secret.len(),
+        MIN_KEY_LEN
+      )));
+    }
+    Ok(Psk {
+      key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+    })
+  }
+
+  /// Reads the key from a file, ignoring a trailing newline.
+  pub fn load(path: &Path) -> Result<Self> {
+    let secret = fs::read(path)?;
+    let end = secret
+      .iter()
+      .rposition(|c| !matches!(c, b'\r' | b'\n'))
+      .map_or(0, |x| x + 1);
+    Psk::new(&secret[..end])
+  }
+
+  /// Proves knowledge of the key to the server on a new stream of
+  /// `connection`, and checks the server knows it too.
+  pub async fn authenticate(&self, connection: &quinn::Connection) -> Result<()> {
+    let session = session(connection)?;
+    let (mut send, mut recv) = connection.open_bi().await?;
+    let nonce = nonce();
+    send.write_all(&nonce).await?;
+    let mut reply = [0; NONCE_LEN + 32];
+    recv.read_exact(&mut reply).await.map_err(read_error)?;
+    let (challenge, tag) = reply.split_at(NONCE_LEN);
+    hmac::verify(
+      &self.key,
+      &message(b"server", &session, &nonce, challenge),
+      tag,
+    )
+    .map_err(|_| QvpnError::Unauthenticated("server doesn't know the pre-shared key".into()))?;
+    let tag = hmac::sign(&self.key, &message(b"client", &session, challenge, &nonce));
+    send.write_all(tag.as_ref()).await?;
+    send.finish()?;
+    // The server finishes its side only once it accepted the proof.
+    recv.read_to_end(0).await?;
+    Ok(())
+  }
+
+  /// Waits for the client to prove knowledge of the key on the first stream
+  /// it opens on `connection`.
+  pub async fn accept(&self, connection: &quinn::Connection) -> Result<()> {
+    let (mut send, mut recv) = connection.accept_bi().await.map_err(|err| {
+      QvpnError::Unauthenticated(format!("connection closed before the key: {}", err))
+    })?;
+    let session = session(connection)?;
+    let mut nonce = [0; NONCE_LEN];
+    recv.read_exact(&mut nonce).await.map_err(read_error)?;
+    let challenge = self::nonce();
+    let tag = hmac::sign(&self.key, &message(b"server", &session, &nonce, &challenge));
+    send.write_all(&challenge).await?;
+    send.write_all(tag.as_ref()).await?;
+    let mut tag = [0; 32];
+    recv.read_exact(&mut tag).await.map_err(read_error)?;
+    hmac::verify(
+      &self.key,
+      &message(b"client", &session, &challenge, &nonce),
+      &tag,
+    )
+    .map_err(|_| QvpnError::Unauthenticated("wrong pre-shared key".into()))?;
+    send.finish()?;
+    Ok(())
+  }
+}
+
+impl std::fmt::Debug for Psk {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str("Psk(..)")
+  }
+}
+
+fn nonce() -> [u8; NONCE_LEN] {
+  let mut nonce = [0; NONCE_LEN];
+  SystemRandom::new()
+    .fill(&mut nonce)
+    .expect("system randomness available");
+  nonce
+}
+
+/// Keying material both ends of `connection`'s TLS session, and only they,
+/// derive alike.
+fn session(connection: &quinn::Connection) -> Result<[u8; 32]> {
+  let mut session = [0; 32];
+  connection
+    .export_keying_material(&mut session, EXPORTER_LABEL, &[])
+    .map_err(|_| QvpnError::Unauthenticated("couldn't export TLS keying material".into()))?;
+  Ok(session)
+}
+
+/// TLS exporter label binding the proofs to a connection.
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-qvpn-psk";
+
+/// What each side signs: its role, the connection's exported keying
+/// material, then the other side's nonce and its own.
+fn message(role: &[u8], session: &[u8], theirs: &[u8], ours: &[u8]) -> Vec<u8> {
+  [b"qvpn-psk\0".as_ref(), role, session, theirs, ours].concat()
+}
+
+fn read_error(err: quinn::ReadExactError) -> QvpnError {
+  match err {
+    quinn::ReadExactError::FinishedEarly(_) => {
+      QvpnError::Unauthenticated("pre-shared key exchange cut short".into())
+    }
+    quinn::ReadExactError::ReadError(err) => {
+      QvpnError::Unauthenticated(format!("pre-shared key exchange failed: {}", err))
+    }
+  }
+}
@@ -0,0 +1,209 @@
+//! qlog traces of connection statistics.
+//!
+//! quinn does not expose per-packet events, so a [`Trace`] samples
+//! [`quinn::Connection::stats`] and writes the changes as qlog 0.3 events in
+//! the JSON-SEQ format. The files load into qvis, which plots the RTT and
+//! congestion window from the `recovery:metrics_updated` events.
+
+use std::{
+  path::{Path, PathBuf},
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tokio::{fs::File, io::AsyncWriteExt, sync::oneshot};
+use tracing::{debug, warn};
+
+use crate::{net, Result};
+
+/// How often statistics are sampled.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// JSON-SEQ record separator.
+const RECORD_SEPARATOR: u8 = 0x1e;
+
+/// Which end of the connection a trace is recorded at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vantage {
+  /// The end that opened the connection.
+  Client,
+  /// The end that accepted it.
+  Server,
+}
+
+impl Vantage {
+  fn name(self) -> &'static str {
+    match self {
+      Vantage::Client => "client",
+      Vantage::Server => "server",
+    }
+  }
+}
+
+/// A running trace. Recording stops when the connection is closed or the
+/// trace is dropped, whichever comes first.
+#[derive(Debug)]
+pub struct Trace {
+  path: PathBuf,
+  stop: oneshot::Sender<()>,
+}
+
+impl Trace {
+  /// Starts tracing `connection` into a new file in `dir`.
+  pub async fn start(dir: &Path, connection: &quinn::Connection, vantage: Vantage) -> Result<Self> {
+    tokio::fs::create_dir_all(dir).await?;
+    let reference_time = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default();
+    let path = dir.join(format!(
+      "{}-{}-{}.sqlog",
+      reference_time.as_millis(),
+      connection.stable_id(),
+      vantage.name()
+    ));
+    let mut file = File::create(&path).await?;
+    let header = json!({
+      "qlog_version": "0.3",
+      "qlog_format": "JSON-SEQ",
+      "title": "qvpn",
+      "trace": {
+        "vantage_point": { "type": vantage.name() },
+        "common_fields": {
+          "group_id": connection.stable_id().to_string(),
+          "time_format": "relative",
+          "reference_time": reference_time.as_secs_f64() * 1000.0,
+        },
+      },
+    });
+    write_record(&mut file, &header).await?;
+    let (stop, stopped) = oneshot::channel();
+    let connection = connection.clone();
+    let trace_path = path.clone();
+    tokio::spawn(async move {
+      if let Err(err) = record(file, connection, vantage, stopped).await {
+        warn!(path = %trace_path.display(), "qlog trace failed: {}", err);
+      }
+    });
+    debug!(path = %path.display(), "qlog trace started");
+    Ok(Trace { path, stop })
+  }
+
+  /// File the trace is written to.
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Whether recording has stopped because the connection closed.
+  pub fn is_finished(&self) -> bool {
+    self.stop.is_closed()
+  }
+}
+
+async fn record(
+  mut file: File,
+  connection: quinn::Connection,
+  vantage: Vantage,
+  mut stopped: oneshot::Receiver<()>,
+) -> Result<()> {
+  let start = Instant::now();
+  let (local, remote) = match vantage {
+    Vantage::Client => ("src", "dst"),
+    Vantage::Server => ("dst", "src"),
+  };
+  let remote_address = net::canonical(connection.remote_address());
+  let mut started = json!({});
+  started[format!("{}_ip", remote)] = json!(remote_address.ip().to_string());
+  started[format!("{}_port", remote)] = json!(remote_address.port());
+  if let Some(ip) = connection.local_ip() {
+    started[format!("{}_ip", local)] = json!(ip.to_string());
+  }
+  write_event(&mut file, start, "connectivity:connection_started", started).await?;
+
+  let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+  let (mut last_metrics, mut last_stats) = (None, None);
+  loop {
+    let done = tokio::select! {
+      _ = interval.tick() => false,
+      _ = &mut stopped => true,
+    };
+    let current = connection.stats();
+    let metrics = json!({
+      "smoothed_rtt": current.path.rtt.as_secs_f64() * 1000.0,
+      "congestion_window": current.path.cwnd,
+    });
+    if last_metrics.as_ref() != Some(&metrics) {
+      write_event(
+        &mut file,
+        start,
+        "recovery:metrics_updated",
+        metrics.clone(),
+      )
+      .await?;
+      last_metrics = Some(metrics);
+    }
+    let snapshot = stats(&current);
+    if last_stats.as_ref() != Some(&snapshot) {
+      write_event(&mut file, start, "qvpn:stats", snapshot.clone()).await?;
+      last_stats = Some(snapshot);
+    }
+    let closed = current.frame_tx.connection_close > 0 || current.frame_rx.connection_close > 0;
+    if done || closed {
+      let trigger = if closed { "clean" } else { "unspecified" };
+      write_event(
+        &mut file,
+        start,
+        "connectivity:connection_closed",
+        json!({ "trigger": trigger }),
+      )
+      .await?;
+      file.flush().await?;
+      return Ok(());
+    }
+  }
+}
+
+/// Frame counters as a JSON object.
+fn frames(frames: &quinn::FrameStats) -> Value {
+  json!({
+    "acks": frames.acks,
+    "crypto": frames.crypto,
+    "datagram": frames.datagram,
+    "max_data": frames.max_data,
+    "max_stream_data": frames.max_stream_data,
+    "ping": frames.ping,
+    "reset_stream": frames.reset_stream,
+    "stream": frames.stream,
+    "data_blocked": frames.data_blocked,
+    "stream_data_blocked": frames.stream_data_blocked,
+  })
+}
+
+/// quinn's statistics for a connection as a JSON object.
+pub fn stats(stats: &quinn::ConnectionStats) -> Value {
+  json!({
+    "rtt_ms": stats.path.rtt.as_secs_f64() * 1000.0,
+    "congestion_window": stats.path.cwnd,
+    "congestion_events": stats.path.congestion_events,
+    "udp_tx": { "datagrams": stats.udp_tx.datagrams, "bytes": stats.udp_tx.bytes, "transmits": stats.udp_tx.ios },
+    "udp_rx": { "datagrams": stats.udp_rx.datagrams, "bytes": stats.udp_rx.bytes, "transmits": stats.udp_rx.ios },
+    "frame_tx": frames(&stats.frame_tx),
+    "frame_rx": frames(&stats.frame_rx),
+  })
+}
+
+async fn write_event(file: &mut File, start: Instant, name: &str, data: Value) -> Result<()> {
+  let event = json!({
+    "time": start.elapsed().as_secs_f64() * 1000.0,
+    "name": name,
+    "data": data,
+  });
+  write_record(file, &event).await
+}
+
+async fn write_record(file: &mut File, record: &Value) -> Result<()> {
+  let mut line = vec![RECORD_SEPARATOR];
+  line.extend(serde_json::to_vec(record).expect("json serializes"));
+  line.push(b'\n');
+  file.write_all(&line).await?;
+  Ok(())
+}
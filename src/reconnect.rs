@@ -0,0 +1,123 @@
+//! Reconnection with jittered exponential backoff.
+
+use std::{future::Future, time::Duration};
+
+use ring::rand::{self, SecureRandom};
+use tracing::warn;
+
+use crate::Result;
+
+/// When and how often to retry after a connection is lost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+  /// Attempts after the first failure; zero disables reconnecting.
+  pub max_attempts: u32,
+  /// Delay before the first attempt. Each further attempt doubles it.
+  pub initial_backoff: Duration,
+  /// Upper bound on the delay between attempts.
+  pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+  fn default() -> Self {
+    Self {
+      max_attempts: 0,
+      initial_backoff: Duration::from_millis(200),
+      max_backoff: Duration::from_secs(10),
+    }
+  }
+}
+
+impl ReconnectPolicy {
+  /// Delay before attempt number `attempt`, counting from one.
+  ///
+  /// The delay doubles with every attempt up to `max_backoff`, and a random
+  /// half of it is dropped so clients that lost the same server don't all
+  /// come back at once.
+  pub fn backoff(&self, attempt: u32) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(31);
+    let delay = self
+      .initial_backoff
+      .saturating_mul(1 << exponent)
+      .min(self.max_backoff);
+    let mut random = [0; 4];
+    let fraction = match rand::SystemRandom::new().fill(&mut random) {
+      Ok(()) => u32::from_le_bytes(random) as f64 / u32::MAX as f64,
+      Err(_) => 1.0,
+    };
+    delay / 2 + (delay / 2).mul_f64(fraction)
+  }
+
+  /// Runs `op` until it succeeds, fails with an error that reconnecting
+  /// can't fix, or the attempts are used up.
+  pub async fn retry<T, F, Fut>(&self, what: &str, mut op: F) -> Result<T>
+  where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+  {
+    let mut attempt = 0;
+    loop {
+      match op().await {
+        Err(err) if err.is_transient() && attempt < self.max_attempts => {
+          attempt += 1;
+          let delay = self.backoff(attempt);
+          warn!(
+            attempt,
+            max_attempts = self.max_attempts,
+            ?delay,
+            "{} failed, reconnecting: {}",
+            what,
+            err
+          );
+          tokio::time::sleep(delay).await;
+        }
+        result => return result,
+      }
+    }
+  }
+
+  /// Keeps a long-running session alive: calls `connect`, then runs
+  /// `session` on the connection until it ends, and starts over when either
+  /// fails with a transient error. The attempt count resets after every
+  /// successful connect.
+  pub async fn sustain<C, F, FFut, S, SFut>(
+    &self,
+    what: &str,
+    mut connect: F,
+    mut session: S,
+  ) -> Result<()>
+  where
+    F: FnMut() -> FFut,
+    FFut: Future<Output = Result<C>>,
+    S: FnMut(C) -> SFut,
+    SFut: Future<Output = Result<()>>,
+  {
+    let mut attempt = 0;
+    loop {
+      let err = match connect().await {
+        Ok(connection) => {
+          attempt = 0;
+          match session(connection).await {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+          }
+        }
+        Err(err) => err,
+      };
+      if !err.is_transient() || attempt >= self.max_attempts {
+        return Err(err);
+      }
+      attempt += 1;
+      let delay = self.backoff(attempt);
+      warn!(
+        attempt,
+        max_attempts = self.max_attempts,
+        ?delay,
+        "{} lost, reconnecting: {}",
+        what,
+        err
+      );
+      tokio::time::sleep(delay).await;
+    }
+  }
+}
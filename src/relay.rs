@@ -0,0 +1,99 @@
+//! Forwarding for peers that can't reach each other directly.
+//!
+//! A peer with relaying enabled forwards [`Message::Relay`] messages from one
+//! connected peer to another, each on its own QUIC stream like any other
+//! peer message. A token bucket caps the bandwidth a relay gives away, and
+//! the bytes forwarded and dropped are counted per pair of peers.
+//!
+//! [`Message::Relay`]: crate::rendezvous::Message::Relay
+
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Instant};
+
+/// Bandwidth a relay gives away.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayLimits {
+  /// Bytes per second forwarded across all peers; zero means unlimited.
+  pub rate: u64,
+}
+
+/// Traffic forwarded between one pair of peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayStats {
+  /// Messages forwarded.
+  pub messages: u64,
+  /// Payload bytes forwarded.
+  pub bytes: u64,
+  /// Messages dropped because the relay was over its rate.
+  pub dropped: u64,
+}
+
+/// Rate limiting and accounting state of a relaying peer.
+#[derive(Debug)]
+pub struct Relay {
+  limits: RelayLimits,
+  bucket: Mutex<Bucket>,
+  stats: Mutex<HashMap<(SocketAddr, SocketAddr), RelayStats>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+  tokens: f64,
+  refilled: Instant,
+}
+
+impl Relay {
+  /// Creates a relay with the given limits.
+  pub fn new(limits: RelayLimits) -> Self {
+    Relay {
+      limits,
+      bucket: Mutex::new(Bucket {
+        tokens: limits.rate as f64,
+        refilled: Instant::now(),
+      }),
+      stats: Mutex::default(),
+    }
+  }
+
+  /// Limits this relay enforces.
+  pub fn limits(&self) -> RelayLimits {
+    self.limits
+  }
+
+  /// Decides whether `len` bytes from `from` to `to` may be forwarded now,
+  /// and counts them either way.
+  pub fn admit(&self, from: SocketAddr, to: SocketAddr, len: usize) -> bool {
+    let admitted = self.limits.rate == 0 || {
+      let mut bucket = self.bucket.lock().expect("relay lock poisoned");
+      let now = Instant::now();
+      let burst = self.limits.rate as f64;
+      let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+      bucket.tokens = (bucket.tokens + elapsed * burst).min(burst);
+      bucket.refilled = now;
+      // A full bucket lets one message through even if it is larger than the
+      // burst, so big messages are slowed down rather than never relayed.
+      if bucket.tokens >= len as f64 || bucket.tokens >= burst {
+        bucket.tokens -= len as f64;
+        true
+      } else {
+        false
+      }
+    };
+    let mut stats = self.stats.lock().expect("relay lock poisoned");
+    let pair = stats.entry((from, to)).or_default();
+    if admitted {
+      pair.messages += 1;
+      pair.bytes += len as u64;
+    } else {
+      pair.dropped += 1;
+    }
+    admitted
+  }
+
+  /// Traffic forwarded so far, by source and destination.
+  pub fn stats(&self) -> Vec<((SocketAddr, SocketAddr), RelayStats)> {
+    let stats = self.stats.lock().expect("relay lock poisoned");
+    let mut stats: Vec<_> = stats.iter().map(|(pair, x)| (*pair, *x)).collect();
+    stats.sort_by_key(|(pair, _)| *pair);
+    stats
+  }
+}
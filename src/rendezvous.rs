@@ -0,0 +1,69 @@
+//! Rendezvous protocol for peers behind NATs.
+//!
+//! Peers register a name with a rendezvous server, which records the address
+//! it sees their messages come from. To reach another peer, a peer asks the
+//! server to introduce them: the server sends each side a [`Message::Punch`]
+//! with the other's observed address and both connect at once, which opens
+//! a mapping in each NAT for the other's packets. If that fails, messages
+//! are relayed through the server instead.
+//!
+//! Rendezvous messages travel as ordinary peer messages: [`PREFIX`], a JSON
+//! encoded [`Message`], a newline, and for relayed messages the payload.
+
+use std::net::SocketAddr;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::{QvpnError, Result};
+
+/// Marks a peer message as a rendezvous message.
+pub const PREFIX: &[u8] = b"\0qvpn-rendezvous\n";
+
+/// A rendezvous message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+  /// Peer to server: remember my address under `name`.
+  Register { name: String },
+  /// Server to peer: registered, and this is the address you were seen at.
+  Registered { address: SocketAddr },
+  /// Peer to server: introduce me to the peer registered as `name`.
+  Connect { name: String },
+  /// Server to peer: connect to `peer` now; it is connecting to you.
+  Punch { peer: SocketAddr },
+  /// Peer to server: forward the payload to the registered peer at `to`.
+  Relay { to: SocketAddr },
+  /// Server to peer: the payload was relayed from `from`.
+  Relayed { from: SocketAddr },
+  /// Server to peer: a request failed.
+  Error { reason: String },
+}
+
+impl Message {
+  /// Encodes the message followed by `payload`.
+  pub fn encode(&self, payload: &[u8]) -> Bytes {
+    let json = serde_json::to_vec(self).expect("json serializes");
+    let mut buf = BytesMut::with_capacity(PREFIX.len() + json.len() + 1 + payload.len());
+    buf.put_slice(PREFIX);
+    buf.put_slice(&json);
+    buf.put_u8(b'\n');
+    buf.put_slice(payload);
+    buf.freeze()
+  }
+
+  /// Decodes a rendezvous message and its payload, or returns `None` if
+  /// `msg` is an application message.
+  pub fn decode(msg: &Bytes) -> Option<Result<(Message, Bytes)>> {
+    let rest = msg.strip_prefix(PREFIX)?;
+    let start = PREFIX.len();
+    Some(match rest.iter().position(|&c| c == b'\n') {
+      Some(end) => serde_json::from_slice(&rest[..end])
+        .map(|message| (message, msg.slice(start + end + 1..)))
+        .map_err(|err| QvpnError::Protocol(format!("bad rendezvous message: {}", err))),
+      None => Err(QvpnError::Protocol(
+        "unterminated rendezvous message".into(),
+      )),
+    })
+  }
+}
@@ -0,0 +1,155 @@
+//! Line editor and commands of the interactive peer node.
+//!
+//! rustyline blocks, so lines are read on a thread of their own and handed
+//! to the async side over a channel. Lines starting with `/` are parsed
+//! into [`Command`]s; anything else is chat for every peer.
+
+use std::{net::SocketAddr, str::FromStr, thread};
+
+use rustyline::{error::ReadlineError, Editor};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Help text listing the commands.
+pub const HELP: &str = "\
+/connect <addr>        connect to a peer
+/peers                 list peers
+/nick <peer> [name]    name a peer, or clear its name
+/msg <peer> <text>     send a message to one peer
+/send <peer> <path>    offer a file to a peer
+/sub <topic>           subscribe to a topic
+/unsub <topic>         unsubscribe from a topic
+/pub <topic> <text>    publish to a topic's subscribers
+/topics                list subscribed topics
+/accept [id]           accept a file offer
+/reject [id]           reject a file offer
+/stats                 show traffic counters
+/quit                  exit
+Any other line is sent to every peer.";
+
+/// A line typed at the prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+  /// Chat for every peer.
+  Say(String),
+  /// Chat for one peer, by address or nickname.
+  Msg(String, String),
+  /// Connect to a peer.
+  Connect(SocketAddr),
+  /// List the peers.
+  Peers,
+  /// Name a peer, or clear its name.
+  Nick(String, Option<String>),
+  /// Offer a file to a peer.
+  Send(String, String),
+  /// Accept the file offer whose id starts with the argument.
+  Accept(String),
+  /// Reject the file offer whose id starts with the argument.
+  Reject(String),
+  /// Subscribe to a topic.
+  Sub(String),
+  /// Unsubscribe from a topic.
+  Unsub(String),
+  /// Publish text to a topic.
+  Pub(String, String),
+  /// List the subscribed topics.
+  Topics,
+  /// Show traffic counters.
+  Stats,
+  /// Show the commands.
+  Help,
+  /// Exit.
+  Quit,
+}
+
+impl FromStr for Command {
+  type Err = String;
+
+  fn from_str(line: &str) -> std::result::Result<Self, Self::Err> {
+    if !line.starts_with('/') {
+      return Ok(Command::Say(line.to_string()));
+    }
+    let (name, rest) = match line.find(char::is_whitespace) {
+      Some(end) => (&line[..end], line[end..].trim()),
+      None => (line, ""),
+    };
+    let mut args = rest.split_whitespace();
+    let usage = |usage: &str| Err(format!("usage: {}", usage));
+    match name {
+      "/connect" => match rest.parse() {
+        Ok(addr) => Ok(Command::Connect(addr)),
+        Err(_) => usage("/connect <addr>"),
+      },
+      "/peers" => Ok(Command::Peers),
+      "/nick" => match (args.next(), args.next(), args.next()) {
+        (Some(peer), name, None) => Ok(Command::Nick(peer.into(), name.map(Into::into))),
+        _ => usage("/nick <peer> [name]"),
+      },
+      "/msg" => match rest.split_once(char::is_whitespace) {
+        Some((peer, text)) if !text.trim().is_empty() => {
+          Ok(Command::Msg(peer.into(), text.trim().into()))
+        }
+        _ => usage("/msg <peer> <text>"),
+      },
+      "/send" => match (args.next(), args.next(), args.next()) {
+        (Some(peer), Some(path), None) => Ok(Command::Send(peer.into(), path.into())),
+        _ => usage("/send <peer> <path>"),
+      },
+      "/sub" => match (args.next(), args.next()) {
+        (Some(topic), None) => Ok(Command::Sub(topic.into())),
+        _ => usage("/sub <topic>"),
+      },
+      "/unsub" => match (args.next(), args.next()) {
+        (Some(topic), None) => Ok(Command::Unsub(topic.into())),
+        _ => usage("/unsub <topic>"),
+      },
+      "/pub" => match rest.split_once(char::is_whitespace) {
+        Some((topic, text)) if !text.trim().is_empty() => {
+          Ok(Command::Pub(topic.into(), text.trim().into()))
+        }
+        _ => usage("/pub <topic> <text>"),
+      },
+      "/topics" => Ok(Command::Topics),
+      "/accept" => Ok(Command::Accept(rest.into())),
+      "/reject" => Ok(Command::Reject(rest.into())),
+      "/stats" => Ok(Command::Stats),
+      "/help" => Ok(Command::Help),
+      "/quit" | "/exit" => Ok(Command::Quit),
+      _ => Err(format!("unknown command `{}`, try /help", name)),
+    }
+  }
+}
+
+/// Reads lines with `prompt` until end of input. Ctrl-C reads as `/quit`, and
+/// empty lines are skipped.
+pub fn lines(prompt: &str) -> mpsc::UnboundedReceiver<String> {
+  let (tx, rx) = mpsc::unbounded_channel();
+  let prompt = prompt.to_string();
+  thread::spawn(move || {
+    let mut editor = Editor::<()>::new();
+    loop {
+      match editor.readline(&prompt) {
+        Ok(line) => {
+          let line = line.trim();
+          if line.is_empty() {
+            continue;
+          }
+          editor.add_history_entry(line);
+          if tx.send(line.to_string()).is_err() {
+            break;
+          }
+        }
+        Err(ReadlineError::Interrupted) => {
+          let _ = tx.send("/quit".into());
+          break;
+        }
+        Err(ReadlineError::Eof) => break,
+        Err(err) => {
+          warn!("reading input failed: {}", err);
+          break;
+        }
+      }
+    }
+  });
+  rx
+}
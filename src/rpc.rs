@@ -0,0 +1,426 @@
+//! Request/response calls over QUIC streams.
+//!
+//! One side of a bidirectional stream makes calls with a [`Caller`], the
+//! other answers them with a [`Router`]. A call is a [`Frame::Request`]
+//! naming a method, with its argument bincode encoded and an id the
+//! [`Frame::Response`] carries back, so calls made at once on one stream
+//! are answered in whatever order they complete. Frames are framed with
+//! their length like [`control`] messages. A call not answered in time
+//! fails with [`QvpnError::Timeout`], and a call given up, by timing out
+//! or being dropped, sends a [`Frame::Cancel`] that aborts its handler.
+//!
+//! Servers answer calls on streams opened with [`REQUEST_LINE`], with the
+//! methods routed by [`ServerBuilder::rpc`] and [`PING`].
+//!
+//! [`control`]: crate::control
+//! [`ServerBuilder::rpc`]: crate::ServerBuilder::rpc
+
+use std::{
+  collections::HashMap,
+  fmt,
+  future::Future,
+  io,
+  pin::Pin,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::Duration,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{
+  io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+  sync::{mpsc, oneshot},
+  task::AbortHandle,
+};
+use tracing::debug;
+
+use crate::{control::Control, QvpnError, Result};
+
+/// First line of a stream carrying calls to a server.
+pub const REQUEST_LINE: &[u8] = b"RPC\r\n";
+
+/// Method every server answers with `()`, taking `()`.
+pub const PING: &str = "qvpn.ping";
+
+/// Largest encoded frame accepted.
+pub const MAX_FRAME: usize = 1024 * 1024;
+
+/// How long a call waits for its response by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Calls answered at once on one stream; more are refused.
+pub const MAX_IN_FLIGHT: usize = 64;
+
+/// A frame on a call stream.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Frame {
+  /// Calls `method` with the encoded argument `body`.
+  Request {
+    id: u64,
+    method: String,
+    body: Vec<u8>,
+  },
+  /// Answers call `id` with the encoded result, or why it failed.
+  Response {
+    id: u64,
+    result: std::result::Result<Vec<u8>, String>,
+  },
+  /// Gives up call `id`, which is answered no more.
+  Cancel { id: u64 },
+}
+
+impl Frame {
+  fn encode(&self) -> BytesMut {
+    let body = bincode::serialize(self).expect("frame serializes");
+    let mut buf = BytesMut::with_capacity(4 + body.len());
+    buf.put_u32(body.len() as u32);
+    buf.put_slice(&body);
+    buf
+  }
+}
+
+/// Reads the next frame into `buf`, or `None` once the stream finished.
+async fn read_frame<R: AsyncRead + Unpin>(
+  recv: &mut R,
+  buf: &mut BytesMut,
+) -> Result<Option<Frame>> {
+  loop {
+    if buf.len() >= 4 {
+      let len = (&buf[..4]).get_u32() as usize;
+      if len > MAX_FRAME {
+        return Err(QvpnError::Protocol(format!(
+          "{} byte rpc frame exceeds the {} byte limit",
+          len, MAX_FRAME
+        )));
+      }
+      if buf.len() >= 4 + len {
+        buf.advance(4);
+        let body = buf.split_to(len);
+        return bincode::deserialize(&body)
+          .map(Some)
+          .map_err(|err| QvpnError::Protocol(format!("bad rpc frame: {}", err)));
+      }
+    }
+    if recv.read_buf(buf).await? == 0 {
+      if buf.is_empty() {
+        return Ok(None);
+      }
+      return Err(QvpnError::Protocol("rpc stream cut short".into()));
+    }
+  }
+}
+
+/// Writes the frames sent on `frames` until every sender is gone, then
+/// finishes the stream.
+async fn write_frames<W: AsyncWrite + Unpin>(
+  mut send: W,
+  mut frames: mpsc::UnboundedReceiver<Frame>,
+) -> io::Result<()> {
+  while let Some(frame) = frames.recv().await {
+    send.write_all(&frame.encode()).await?;
+  }
+  send.shutdown().await
+}
+
+fn closed() -> QvpnError {
+  io::Error::new(io::ErrorKind::ConnectionAborted, "rpc stream closed").into()
+}
+
+type Reply = oneshot::Sender<std::result::Result<Vec<u8>, String>>;
+
+/// Makes calls on one stream. Clones share the stream, which is finished
+/// once the last of them is dropped.
+#[derive(Debug, Clone)]
+pub struct Caller {
+  inner: Arc<Inner>,
+  timeout: Duration,
+}
+
+#[derive(Debug)]
+struct Inner {
+  next_id: AtomicU64,
+  /// Calls waiting for their response, by id. `None` once the stream
+  /// closed.
+  pending: Arc<Mutex<Option<HashMap<u64, Reply>>>>,
+  frames: mpsc::UnboundedSender<Frame>,
+  /// Control stream of the connection, kept open as long as the calls.
+  _control: Option<Control>,
+}
+
+impl Caller {
+  /// Makes calls on the stream `send` and `recv`, whose other side answers
+  /// them with a [`Router`].
+  pub fn new<S, R>(send: S, recv: R) -> Self
+  where
+    S: AsyncWrite + Unpin + Send + 'static,
+    R: AsyncRead + Unpin + Send + 'static,
+  {
+    Caller::start(send, recv, None)
+  }
+
+  pub(crate) fn start<S, R>(send: S, mut recv: R, control: Option<Control>) -> Self
+  where
+    S: AsyncWrite + Unpin + Send + 'static,
+    R: AsyncRead + Unpin + Send + 'static,
+  {
+    let (frames, outgoing) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+      if let Err(err) = write_frames(send, outgoing).await {
+        debug!("rpc stream failed: {}", err);
+      }
+    });
+    let pending = Arc::new(Mutex::new(Some(HashMap::<u64, Reply>::new())));
+    let responses = pending.clone();
+    tokio::spawn(async move {
+      let mut buf = BytesMut::new();
+      loop {
+        match read_frame(&mut recv, &mut buf).await {
+          Ok(Some(Frame::Response { id, result })) => {
+            let reply = responses
+              .lock()
+              .unwrap()
+              .as_mut()
+              .and_then(|x| x.remove(&id));
+            if let Some(reply) = reply {
+              let _ = reply.send(result);
+            }
+          }
+          Ok(Some(frame)) => {
+            debug!(?frame, "unexpected rpc frame");
+            break;
+          }
+          Ok(None) => break,
+          Err(err) => {
+            debug!("rpc stream failed: {}", err);
+            break;
+          }
+        }
+      }
+      // Fails the calls still waiting, and those made from now on.
+      responses.lock().unwrap().take();
+    });
+    Caller {
+      inner: Arc::new(Inner {
+        next_id: AtomicU64::new(0),
+        pending,
+        frames,
+        _control: control,
+      }),
+      timeout: DEFAULT_TIMEOUT,
+    }
+  }
+
+  /// Waits `timeout` for each call instead of [`DEFAULT_TIMEOUT`].
+  pub fn timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = timeout;
+    self
+  }
+
+  /// Calls `method` with `arg`, waiting for the result as long as the
+  /// caller's timeout. A handler's error is returned as
+  /// [`QvpnError::Remote`]. Dropping the call cancels it.
+  pub async fn call<Q, R>(&self, method: &str, arg: &Q) -> Result<R>
+  where
+    Q: Serialize,
+    R: DeserializeOwned,
+  {
+    self.call_timeout(method, arg, self.timeout).await
+  }
+
+  /// Calls `method` with `arg`, waiting `timeout` for the result.
+  pub async fn call_timeout<Q, R>(&self, method: &str, arg: &Q, timeout: Duration) -> Result<R>
+  where
+    Q: Serialize,
+    R: DeserializeOwned,
+  {
+    let body = bincode::serialize(arg)
+      .map_err(|err| QvpnError::InvalidInput(format!("bad rpc argument: {}", err)))?;
+    if body.len() + method.len() + 32 > MAX_FRAME {
+      return Err(QvpnError::InvalidInput(format!(
+        "rpc argument exceeds the {} byte limit",
+        MAX_FRAME
+      )));
+    }
+    let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+    let (reply, response) = oneshot::channel();
+    self
+      .inner
+      .pending
+      .lock()
+      .unwrap()
+      .as_mut()
+      .ok_or_else(closed)?
+      .insert(id, reply);
+    let mut call = Call {
+      inner: &self.inner,
+      id,
+      answered: false,
+    };
+    self
+      .inner
+      .frames
+      .send(Frame::Request {
+        id,
+        method: method.to_string(),
+        body,
+      })
+      .map_err(|_| closed())?;
+    let result = tokio::time::timeout(timeout, response)
+      .await
+      .map_err(|_| QvpnError::Timeout("rpc call"))?
+      .map_err(|_| closed())?;
+    call.answered = true;
+    let body = result.map_err(QvpnError::Remote)?;
+    bincode::deserialize(&body)
+      .map_err(|err| QvpnError::Protocol(format!("bad rpc response to {}: {}", method, err)))
+  }
+}
+
+/// A call in flight, cancelled if dropped unanswered.
+struct Call<'a> {
+  inner: &'a Inner,
+  id: u64,
+  answered: bool,
+}
+
+impl Drop for Call<'_> {
+  fn drop(&mut self) {
+    if self.answered {
+      return;
+    }
+    if let Some(pending) = self.inner.pending.lock().unwrap().as_mut() {
+      pending.remove(&self.id);
+    }
+    let _ = self.inner.frames.send(Frame::Cancel { id: self.id });
+  }
+}
+
+type Answer = Pin<Box<dyn Future<Output = std::result::Result<Vec<u8>, String>> + Send>>;
+type Handler = Arc<dyn Fn(Vec<u8>) -> Answer + Send + Sync>;
+
+/// Answers calls with the handlers of their methods.
+#[derive(Default, Clone)]
+pub struct Router {
+  methods: HashMap<String, Handler>,
+}
+
+impl fmt::Debug for Router {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut methods: Vec<_> = self.methods.keys().collect();
+    methods.sort();
+    f.debug_struct("Router").field("methods", &methods).finish()
+  }
+}
+
+impl Router {
+  /// A router answering no methods.
+  pub fn new() -> Self {
+    Router::default()
+  }
+
+  /// Answers calls to `method` with `handler`, replacing any handler
+  /// routed before. An error it returns is sent to the caller as text.
+  pub fn route<Q, R, F, Fut>(mut self, method: impl Into<String>, handler: F) -> Self
+  where
+    Q: DeserializeOwned,
+    R: Serialize,
+    F: Fn(Q) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<R>> + Send + 'static,
+  {
+    let method = method.into();
+    let name = method.clone();
+    let handler: Handler = Arc::new(move |body| match bincode::deserialize(&body) {
+      Ok(arg) => {
+        let answer = handler(arg);
+        Box::pin(async move {
+          let result = answer.await.map_err(|err| err.to_string())?;
+          bincode::serialize(&result).map_err(|err| err.to_string())
+        })
+      }
+      Err(err) => {
+        let err = format!("bad argument to {}: {}", name, err);
+        Box::pin(async move { Err(err) })
+      }
+    });
+    self.methods.insert(method, handler);
+    self
+  }
+
+  /// Whether `method` is routed.
+  pub fn has(&self, method: &str) -> bool {
+    self.methods.contains_key(method)
+  }
+
+  /// Answers the calls made on the stream `send` and `recv`, of which
+  /// `received` was already read, until the caller finishes it. Each call
+  /// runs in a task of its own.
+  pub async fn serve<S, R>(self: Arc<Self>, send: S, mut recv: R, received: Vec<u8>) -> Result<()>
+  where
+    S: AsyncWrite + Unpin + Send + 'static,
+    R: AsyncRead + Unpin + Send + 'static,
+  {
+    let (frames, outgoing) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(write_frames(send, outgoing));
+    let running = Arc::new(Mutex::new(HashMap::<u64, AbortHandle>::new()));
+    let mut buf = BytesMut::from(&received[..]);
+    let result = loop {
+      let (id, method, body) = match read_frame(&mut recv, &mut buf).await {
+        Ok(Some(Frame::Request { id, method, body })) => (id, method, body),
+        Ok(Some(Frame::Cancel { id })) => {
+          if let Some(call) = running.lock().unwrap().remove(&id) {
+            debug!(id, "rpc call cancelled");
+            call.abort();
+          }
+          continue;
+        }
+        Ok(Some(Frame::Response { .. })) => {
+          break Err(QvpnError::Protocol("unexpected rpc response".into()))
+        }
+        Ok(None) => break Ok(()),
+        Err(err) => break Err(err),
+      };
+      let answer = match self.methods.get(&method) {
+        _ if running.lock().unwrap().len() >= MAX_IN_FLIGHT => {
+          let _ = frames.send(Frame::Response {
+            id,
+            result: Err(format!("more than {} calls in flight", MAX_IN_FLIGHT)),
+          });
+          continue;
+        }
+        Some(handler) => handler(body),
+        None => {
+          let _ = frames.send(Frame::Response {
+            id,
+            result: Err(format!("unknown method {}", method)),
+          });
+          continue;
+        }
+      };
+      let (frames, done) = (frames.clone(), running.clone());
+      // Registered before it can finish and remove itself.
+      let mut calls = running.lock().unwrap();
+      let task = tokio::spawn(async move {
+        let result = answer.await;
+        if done.lock().unwrap().remove(&id).is_some() {
+          let _ = frames.send(Frame::Response { id, result });
+        }
+      });
+      calls.insert(id, task.abort_handle());
+    };
+    drop(frames);
+    match result {
+      // Answers the calls still running before finishing the stream.
+      Ok(()) => writer.await.map_err(io::Error::from)??,
+      Err(_) => {
+        for (_, call) in running.lock().unwrap().drain() {
+          call.abort();
+        }
+        writer.abort();
+      }
+    }
+    result
+  }
+}
@@ -0,0 +1,196 @@
+//! Bounded queues of messages waiting to be sent to a peer.
+//!
+//! A node gives every peer it sends to a [`SendQueue`] of its own, drained
+//! by a task sending one message at a time. A slow or stalled peer then
+//! holds up neither the other peers nor the sender, and no more than the
+//! queue's capacity of messages is kept for it. What happens to a message
+//! arriving at a full queue is up to the [`Overflow`] policy.
+
+use std::{
+  collections::VecDeque,
+  fmt,
+  net::SocketAddr,
+  str::FromStr,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+  },
+};
+
+use bytes::Bytes;
+use serde::{de, Deserialize, Deserializer};
+use tokio::sync::{Notify, Semaphore, TryAcquireError};
+use tracing::debug;
+
+use crate::{QvpnError, Result};
+
+/// Messages queued per peer by default.
+pub const DEFAULT_CAPACITY: usize = 256;
+
+/// What to do with a message for a peer whose queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+  /// Wait for the peer to take a message.
+  #[default]
+  Block,
+  /// Drop the message queued longest to make room.
+  DropOldest,
+  /// Refuse the new message.
+  DropNewest,
+}
+
+impl FromStr for Overflow {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "block" => Ok(Overflow::Block),
+      "drop-oldest" => Ok(Overflow::DropOldest),
+      "drop-newest" => Ok(Overflow::DropNewest),
+      _ => Err(format!(
+        "unknown overflow policy `{}`, expected block, drop-oldest or drop-newest",
+        s
+      )),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Overflow {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Overflow {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Overflow::Block => f.write_str("block"),
+      Overflow::DropOldest => f.write_str("drop-oldest"),
+      Overflow::DropNewest => f.write_str("drop-newest"),
+    }
+  }
+}
+
+/// Size and overflow policy of the queues.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLimits {
+  /// Messages queued per peer, at least one.
+  pub capacity: usize,
+  pub overflow: Overflow,
+}
+
+impl Default for QueueLimits {
+  fn default() -> Self {
+    QueueLimits {
+      capacity: DEFAULT_CAPACITY,
+      overflow: Overflow::default(),
+    }
+  }
+}
+
+/// Messages waiting to be sent to one peer.
+#[derive(Debug)]
+pub struct SendQueue {
+  peer: SocketAddr,
+  overflow: Overflow,
+  messages: Mutex<VecDeque<Bytes>>,
+  /// One permit per free slot. Closed with the queue.
+  space: Semaphore,
+  /// Woken when a message is queued or the queue closes.
+  ready: Notify,
+  dropped: AtomicU64,
+}
+
+impl SendQueue {
+  /// An empty queue for `peer`.
+  pub fn new(peer: SocketAddr, limits: QueueLimits) -> Self {
+    let capacity = limits.capacity.max(1);
+    SendQueue {
+      peer,
+      overflow: limits.overflow,
+      messages: Mutex::new(VecDeque::with_capacity(capacity.min(DEFAULT_CAPACITY))),
+      space: Semaphore::new(capacity),
+      ready: Notify::new(),
+      dropped: AtomicU64::new(0),
+    }
+  }
+
+  /// Queues `message`, as the overflow policy says if the queue is full.
+  /// Fails if the message is refused, or the queue is closed.
+  pub async fn push(&self, message: Bytes) -> Result<()> {
+    let closed = || QvpnError::Disconnected(self.peer);
+    let permit = match self.overflow {
+      Overflow::Block => self.space.acquire().await.map_err(|_| closed())?,
+      Overflow::DropNewest => match self.space.try_acquire() {
+        Ok(permit) => permit,
+        Err(TryAcquireError::Closed) => return Err(closed()),
+        Err(TryAcquireError::NoPermits) => {
+          self.dropped.fetch_add(1, Ordering::Relaxed);
+          return Err(QvpnError::QueueFull(self.peer));
+        }
+      },
+      Overflow::DropOldest => loop {
+        match self.space.try_acquire() {
+          Ok(permit) => break permit,
+          Err(TryAcquireError::Closed) => return Err(closed()),
+          Err(TryAcquireError::NoPermits) => {
+            let mut messages = self.messages.lock().unwrap();
+            // Taken by the sending task since, in which case a slot is
+            // free now.
+            if messages.pop_front().is_some() {
+              messages.push_back(message);
+              drop(messages);
+              self.dropped.fetch_add(1, Ordering::Relaxed);
+              debug!(peer = %self.peer, "send queue full, dropped oldest message");
+              return Ok(());
+            }
+          }
+        }
+      },
+    };
+    permit.forget();
+    self.messages.lock().unwrap().push_back(message);
+    self.ready.notify_one();
+    Ok(())
+  }
+
+  /// Takes the next message to send, waiting for one. `None` once the
+  /// queue is closed.
+  pub async fn pop(&self) -> Option<Bytes> {
+    loop {
+      if self.space.is_closed() {
+        return None;
+      }
+      if let Some(message) = self.messages.lock().unwrap().pop_front() {
+        self.space.add_permits(1);
+        return Some(message);
+      }
+      self.ready.notified().await;
+    }
+  }
+
+  /// Messages waiting.
+  pub fn len(&self) -> usize {
+    self.messages.lock().unwrap().len()
+  }
+
+  /// Whether no messages are waiting.
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Messages dropped or refused because the queue was full.
+  pub fn dropped(&self) -> u64 {
+    self.dropped.load(Ordering::Relaxed)
+  }
+
+  /// Discards the waiting messages and refuses new ones, waking senders
+  /// blocked on a full queue.
+  pub fn close(&self) {
+    self.space.close();
+    self.messages.lock().unwrap().clear();
+    self.ready.notify_one();
+  }
+}
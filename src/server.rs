@@ -0,0 +1,3226 @@
+//! File server over QUIC.
+//!
+//! Clients negotiating the qvpn protocol send `METHOD /path\r\n` style
+//! requests, one per stream. Clients negotiating HTTP/3 get files through
+//! [`h3`], unless the server is built with
+//! [`ServerBuilder::legacy_proto`].
+
+use std::{
+  ascii,
+  convert::{TryFrom, TryInto},
+  fmt, fs,
+  io::{self, SeekFrom},
+  iter,
+  net::{IpAddr, Ipv4Addr, SocketAddr},
+  path::{Path, PathBuf},
+  str::{self, FromStr},
+  sync::{Arc, RwLock},
+  time::Duration,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{future, Future, FutureExt};
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use serde::{de, Deserialize, Deserializer};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+  io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader},
+  net::{TcpListener, TcpStream},
+  sync::Mutex,
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::{
+  access::SigningKey,
+  accounting::Accounting,
+  acme::{self, AcmeConfig},
+  auth::{self, Authenticator, Logins},
+  bench,
+  compress::{self, Encoder, Encoding},
+  congestion::{self, Congestion},
+  control::{self, Channels, Control},
+  dashboard,
+  datagram::{self, Frame, Kind},
+  dns::DnsConfig,
+  doq,
+  egress::{self, Policy},
+  expose,
+  file_cache::FileCache,
+  forward,
+  fragment::Reassembler,
+  h3,
+  hook::{self, Hooks},
+  http::{self, ResponseHead},
+  integrity::{self, DigestCache},
+  lease::{Ipv4Net, Lease, LeasePool},
+  limit::{RateLimiter, ValidatedAddrs},
+  listing, log,
+  metrics::{self, Metrics},
+  mount::{Mount, Mounts, Resolved},
+  net,
+  oidc::Verifier,
+  privilege, proxy,
+  psk::{self, Psk},
+  qlog, rpc,
+  site::{self, Firewall, Masquerade, Sites},
+  stats::{self, Stats, Tracker},
+  sync,
+  tls::{self, CertResolver},
+  tun::{self, Router, Tun, TunConfig},
+  tuning::Tuning,
+  udp, version, QvpnError, Result, ALPN_LEGACY, ALPN_QVPN,
+};
+#[cfg(unix)]
+use crate::{config::Config, manage};
+
+/// What a server does with the requests it receives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+  /// Serve files and, if enabled, tunnel leases.
+  #[default]
+  File,
+  /// Additionally relay `CONNECT host:port` requests to TCP targets, for
+  /// clients running a SOCKS5 or HTTP proxy.
+  ConnectProxy,
+  /// Answer DNS over QUIC queries by forwarding them to an upstream
+  /// resolver, instead of serving files.
+  Doq,
+}
+
+impl FromStr for Mode {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "file" => Ok(Mode::File),
+      "connect-proxy" => Ok(Mode::ConnectProxy),
+      "doq" => Ok(Mode::Doq),
+      _ => Err(format!(
+        "unknown mode `{}`, expected file, connect-proxy or doq",
+        s
+      )),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Mode {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Mode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Mode::File => f.write_str("file"),
+      Mode::ConnectProxy => f.write_str("connect-proxy"),
+      Mode::Doq => f.write_str("doq"),
+    }
+  }
+}
+
+/// Default number of bytes read from a file at a time when streaming it.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunks the send buffer of a file transfer holds before it grows.
+const SEND_BUFFER_CHUNKS: usize = 8;
+
+/// Builder for a [`Server`].
+#[derive(Debug, Clone)]
+pub struct ServerBuilder {
+  root: Option<PathBuf>,
+  mounts: Vec<Mount>,
+  index: Option<String>,
+  follow_symlinks: bool,
+  compression: bool,
+  cache_size: u64,
+  listen: SocketAddr,
+  key: Option<PathBuf>,
+  cert: Option<PathBuf>,
+  acme: Option<AcmeConfig>,
+  client_ca: Option<PathBuf>,
+  psk: Option<Psk>,
+  psk_timeout: Duration,
+  keylog: bool,
+  stateless_retry: bool,
+  zero_rtt: bool,
+  mode: Mode,
+  uploads: bool,
+  bench: bool,
+  rpc: rpc::Router,
+  forwards: forward::Acl,
+  expose: Option<SocketAddr>,
+  tunnel: Option<TunConfig>,
+  subnet: Ipv4Net,
+  routes: Vec<Ipv4Net>,
+  sites: Vec<site::Rule>,
+  egress: Vec<egress::Rule>,
+  firewall: bool,
+  clamp_mss: bool,
+  dns: DnsConfig,
+  hooks: Hooks,
+  idle_timeout: Option<Duration>,
+  keep_alive_interval: Option<Duration>,
+  congestion: Congestion,
+  tuning: Tuning,
+  chunk_size: usize,
+  metrics: Option<SocketAddr>,
+  dashboard: Option<(SocketAddr, dashboard::Password)>,
+  qlog: Option<PathBuf>,
+  max_connections: Option<u32>,
+  max_streams: Option<u64>,
+  connection_rate: Option<u32>,
+  retry_token_lifetime: Option<Duration>,
+  migration: bool,
+  log_amplification: bool,
+  validation_cache: Option<Duration>,
+  legacy_proto: bool,
+  upstream: Option<SocketAddr>,
+  user: Option<String>,
+  group: Option<String>,
+  stats_interval: Option<Duration>,
+  admin_socket: Option<PathBuf>,
+  config_file: Option<PathBuf>,
+  accounting: Option<PathBuf>,
+  authenticator: Option<Arc<dyn Authenticator>>,
+  verifier: Option<Arc<Verifier>>,
+  access: Option<SigningKey>,
+}
+
+impl ServerBuilder {
+  /// Creates a builder serving files from `root`.
+  pub fn new(root: impl Into<PathBuf>) -> Self {
+    Self {
+      root: Some(root.into()),
+      ..Self::without_root()
+    }
+  }
+
+  /// Creates a builder serving only the directories added with
+  /// [`ServerBuilder::mount`].
+  pub fn without_root() -> Self {
+    Self {
+      root: None,
+      mounts: vec![],
+      index: None,
+      follow_symlinks: false,
+      compression: false,
+      cache_size: 0,
+      listen: net::dual_stack(4433),
+      key: None,
+      cert: None,
+      acme: None,
+      client_ca: None,
+      psk: None,
+      psk_timeout: psk::DEFAULT_TIMEOUT,
+      keylog: false,
+      stateless_retry: false,
+      zero_rtt: false,
+      mode: Mode::default(),
+      uploads: false,
+      bench: false,
+      rpc: rpc::Router::new(),
+      forwards: forward::Acl::default(),
+      expose: None,
+      tunnel: None,
+      subnet: Ipv4Net::new([10, 8, 0, 0].into(), 24).unwrap(),
+      routes: vec![],
+      sites: vec![],
+      egress: vec![],
+      firewall: true,
+      clamp_mss: false,
+      dns: DnsConfig::default(),
+      hooks: Hooks::default(),
+      idle_timeout: None,
+      keep_alive_interval: None,
+      congestion: Congestion::default(),
+      tuning: Tuning::default(),
+      chunk_size: DEFAULT_CHUNK_SIZE,
+      metrics: None,
+      dashboard: None,
+      qlog: None,
+      max_connections: None,
+      max_streams: None,
+      connection_rate: None,
+      retry_token_lifetime: None,
+      migration: true,
+      log_amplification: false,
+      validation_cache: None,
+      legacy_proto: false,
+      upstream: None,
+      user: None,
+      group: None,
+      stats_interval: None,
+      admin_socket: None,
+      accounting: None,
+      authenticator: None,
+      verifier: None,
+      access: None,
+      config_file: None,
+    }
+  }
+
+  /// Address to listen on. The default `[::]:4433` accepts both IPv6 and
+  /// IPv4 clients.
+  pub fn listen(mut self, addr: SocketAddr) -> Self {
+    self.listen = addr;
+    self
+  }
+
+  /// TLS private key and certificate chain, in PEM or DER format.
+  ///
+  /// A self-signed certificate is generated when this is not set. The files
+  /// are checked for changes every [`CERT_POLL_INTERVAL`] and reloaded for
+  /// new connections, so renewing them needs no restart.
+  pub fn certificate(mut self, key: impl Into<PathBuf>, cert: impl Into<PathBuf>) -> Self {
+    self.key = Some(key.into());
+    self.cert = Some(cert.into());
+    self
+  }
+
+  /// Obtain and renew the certificate from an ACME CA instead of loading it
+  /// from files. A self-signed certificate is served until the first one is
+  /// issued.
+  pub fn acme(mut self, config: AcmeConfig) -> Self {
+    self.acme = Some(config);
+    self
+  }
+
+  /// Require clients to present a certificate signed by one of the CAs in
+  /// this PEM or DER file.
+  pub fn client_ca(mut self, path: impl Into<PathBuf>) -> Self {
+    self.client_ca = Some(path.into());
+    self
+  }
+
+  /// Require clients to prove they know this pre-shared key right after the
+  /// handshake. 0-RTT is disabled, since nothing may be answered before.
+  pub fn psk(mut self, psk: Psk) -> Self {
+    self.psk = Some(psk);
+    self
+  }
+
+  /// How long clients get to prove the pre-shared key.
+  pub fn psk_timeout(mut self, timeout: Duration) -> Self {
+    self.psk_timeout = timeout;
+    self
+  }
+
+  /// Log TLS keys to the file named by `SSLKEYLOGFILE`.
+  pub fn keylog(mut self, enabled: bool) -> Self {
+    self.keylog = enabled;
+    self
+  }
+
+  /// Enable stateless retries.
+  pub fn stateless_retry(mut self, enabled: bool) -> Self {
+    self.stateless_retry = enabled;
+    self
+  }
+
+  /// How long a stateless retry token stays valid [default: 15 seconds].
+  pub fn retry_token_lifetime(mut self, lifetime: Duration) -> Self {
+    self.retry_token_lifetime = Some(lifetime);
+    self
+  }
+
+  /// Let clients keep their connections when their address changes
+  /// [default: true].
+  pub fn migration(mut self, enabled: bool) -> Self {
+    self.migration = enabled;
+    self
+  }
+
+  /// Log how many bytes each handshake sent per byte received, warning when
+  /// that exceeds the threefold anti-amplification limit.
+  pub fn log_amplification(mut self, enabled: bool) -> Self {
+    self.log_amplification = enabled;
+    self
+  }
+
+  /// Remember addresses that completed a handshake for this long and exempt
+  /// them from [`ServerBuilder::connection_rate`].
+  pub fn validation_cache(mut self, ttl: Duration) -> Self {
+    self.validation_cache = Some(ttl);
+    self
+  }
+
+  /// Accept 0-RTT early data from clients resuming a session. Only `GET`
+  /// requests are answered before the handshake completes, since early data
+  /// can be replayed.
+  pub fn zero_rtt(mut self, enabled: bool) -> Self {
+    self.zero_rtt = enabled;
+    self
+  }
+
+  /// Which requests the server answers besides file requests.
+  pub fn mode(mut self, mode: Mode) -> Self {
+    self.mode = mode;
+    self
+  }
+
+  /// Speak only the qvpn protocol, under the `h3-29` ALPN protocol it used
+  /// before HTTP/3 was supported, for clients that predate it. HTTP/3
+  /// clients can't connect.
+  pub fn legacy_proto(mut self, enabled: bool) -> Self {
+    self.legacy_proto = enabled;
+    self
+  }
+
+  /// Resolver that [`Mode::Doq`] forwards queries to.
+  pub fn upstream(mut self, addr: SocketAddr) -> Self {
+    self.upstream = Some(addr);
+    self
+  }
+
+  /// Accept `PUT` requests that write files below the root.
+  pub fn uploads(mut self, enabled: bool) -> Self {
+    self.uploads = enabled;
+    self
+  }
+
+  /// Also serve the directory of `mount` under its prefix. A mount at `/`
+  /// replaces the root. See [`mount`](crate::mount).
+  pub fn mount(mut self, mount: Mount) -> Self {
+    self.mounts.push(mount);
+    self
+  }
+
+  /// Answer requests for a directory containing a file with this name,
+  /// e.g. `index.html`, with the file instead of a listing.
+  pub fn index(mut self, name: impl Into<String>) -> Self {
+    self.index = Some(name.into());
+    self
+  }
+
+  /// Follow symlinks in served directories wherever they lead. By default
+  /// only links to targets inside the same mount are followed.
+  pub fn follow_symlinks(mut self, enabled: bool) -> Self {
+    self.follow_symlinks = enabled;
+    self
+  }
+
+  /// Compress file responses for clients that accept it, unless their type
+  /// is already compressed. See [`compress`](crate::compress).
+  pub fn compression(mut self, enabled: bool) -> Self {
+    self.compression = enabled;
+    self
+  }
+
+  /// Answer `qvpn bench` requests with synthetic data. See
+  /// [`bench`](crate::bench).
+  pub fn bench(mut self, enabled: bool) -> Self {
+    self.bench = enabled;
+    self
+  }
+
+  /// Answer [`rpc`](crate::rpc) calls with the methods of `router`, besides
+  /// [`rpc::PING`]. Callers must log in first if logins are required.
+  pub fn rpc(mut self, router: rpc::Router) -> Self {
+    self.rpc = router;
+    self
+  }
+
+  /// Let clients forward connections to the target of `rule`, as with
+  /// `ssh -L`, even outside [`Mode::ConnectProxy`]. See
+  /// [`forward`](crate::forward).
+  pub fn forward(mut self, rule: forward::Target) -> Self {
+    self.forwards.targets.push(rule);
+    self
+  }
+
+  /// Let clients have the server listen on the address of `rule` and
+  /// forward the connections to them, as with `ssh -R`. See
+  /// [`forward`](crate::forward).
+  pub fn reverse_forward(mut self, rule: forward::Listen) -> Self {
+    self.forwards.listens.push(rule);
+    self
+  }
+
+  /// Accept HTTP connections on this address for the services clients
+  /// [`expose`](crate::expose), routed by the name in their `Host` header.
+  pub fn expose(mut self, addr: SocketAddr) -> Self {
+    self.expose = Some(addr);
+    self
+  }
+
+  /// Keep up to `bytes` of popular files in memory instead of reading them
+  /// from disk for every request. See [`file_cache`](crate::file_cache).
+  pub fn cache_size(mut self, bytes: u64) -> Self {
+    self.cache_size = bytes;
+    self
+  }
+
+  /// Serve Prometheus metrics over HTTP/1.1 on this address.
+  pub fn metrics(mut self, addr: SocketAddr) -> Self {
+    self.metrics = Some(addr);
+    self
+  }
+
+  /// Serve the web [`dashboard`](crate::dashboard) over HTTP/1.1 on this
+  /// address, behind basic authentication with `password`. The address
+  /// must be a loopback one, since the password isn't encrypted.
+  pub fn dashboard(mut self, addr: SocketAddr, password: dashboard::Password) -> Self {
+    self.dashboard = Some((addr, password));
+    self
+  }
+
+  /// Log the [`Stats`] of every connection this often.
+  pub fn stats_interval(mut self, interval: Duration) -> Self {
+    self.stats_interval = Some(interval);
+    self
+  }
+
+  /// Answer the management API on a Unix socket at this path. See
+  /// [`manage`](crate::manage).
+  pub fn admin_socket(mut self, path: impl Into<PathBuf>) -> Self {
+    self.admin_socket = Some(path.into());
+    self
+  }
+
+  /// Append a JSON line for every client session to the file at this path,
+  /// besides logging it. See [`accounting`](crate::accounting).
+  pub fn accounting(mut self, path: impl Into<PathBuf>) -> Self {
+    self.accounting = Some(path.into());
+    self
+  }
+
+  /// Require tunnel and proxy clients to log in with a user name and
+  /// password that `authenticator` accepts. See [`auth`](crate::auth).
+  pub fn authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+    self.authenticator = Some(authenticator);
+    self
+  }
+
+  /// Require tunnel and proxy clients to log in, accepting the ID tokens
+  /// `verifier` accepts besides any password logins. See
+  /// [`oidc`](crate::oidc).
+  pub fn oidc(mut self, verifier: Verifier) -> Self {
+    self.verifier = Some(Arc::new(verifier));
+    self
+  }
+
+  /// Serve files only to requests presenting an access token signed with
+  /// `key` that covers the path. See [`access`](crate::access).
+  pub fn access_tokens(mut self, key: SigningKey) -> Self {
+    self.access = Some(key);
+    self
+  }
+
+  /// Config file the management API's `reload` re-reads tunnel routes and
+  /// resolvers from.
+  pub fn config_file(mut self, path: impl Into<PathBuf>) -> Self {
+    self.config_file = Some(path.into());
+    self
+  }
+
+  /// Write a qlog trace of every connection into this directory.
+  pub fn qlog(mut self, dir: impl Into<PathBuf>) -> Self {
+    self.qlog = Some(dir.into());
+    self
+  }
+
+  /// Close connections after this long without activity.
+  pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+    self.idle_timeout = Some(timeout);
+    self
+  }
+
+  /// Send keep-alives after this long without traffic.
+  pub fn keep_alive_interval(mut self, interval: Duration) -> Self {
+    self.keep_alive_interval = Some(interval);
+    self
+  }
+
+  /// Congestion controller for client connections.
+  pub fn congestion(mut self, congestion: Congestion) -> Self {
+    self.congestion = congestion;
+    self
+  }
+
+  /// Replace quinn's flow-control windows and initial RTT. A
+  /// [`ServerBuilder::max_streams_per_connection`] limit wins over the
+  /// stream limit here.
+  pub fn tuning(mut self, tuning: Tuning) -> Self {
+    self.tuning = tuning;
+    self
+  }
+
+  /// How many bytes of a file are read at a time when streaming it.
+  pub fn chunk_size(mut self, bytes: usize) -> Self {
+    self.chunk_size = bytes.max(1);
+    self
+  }
+
+  /// Refuse new connections with a `CONNECTION_REFUSED` transport error
+  /// while this many are open.
+  pub fn max_connections(mut self, max: u32) -> Self {
+    self.max_connections = Some(max);
+    self
+  }
+
+  /// Streams a client may have open at once on one connection.
+  pub fn max_streams_per_connection(mut self, max: u64) -> Self {
+    self.max_streams = Some(max);
+    self
+  }
+
+  /// New connections each source IP may open a minute, in bursts of up to
+  /// as many. Connections beyond that are refused with a
+  /// `CONNECTION_REFUSED` transport error before their handshake.
+  pub fn connection_rate(mut self, per_minute: u32) -> Self {
+    self.connection_rate = Some(per_minute);
+    self
+  }
+
+  /// Forward IP packets between clients and a local TUN interface, or
+  /// Ethernet frames and a TAP interface if `config` asks for one.
+  pub fn tunnel(mut self, config: TunConfig) -> Self {
+    self.tunnel = Some(config);
+    self
+  }
+
+  /// Masquerade the tunnel subnet out of the server's other interfaces and
+  /// let its traffic through the forward chain with nftables, turning on
+  /// IPv4 forwarding if needed, so full-tunnel clients reach past the
+  /// server. The rules are removed on shutdown. Ignored if the tunnel
+  /// config picks a [`masquerade`](TunConfig::masquerade) firewall or
+  /// bridges the interface. Linux only [default: true].
+  pub fn firewall(mut self, enabled: bool) -> Self {
+    self.firewall = enabled;
+    self
+  }
+
+  /// Clamp the MSS that TCP connections through the tunnel negotiate to
+  /// the MTU of each client's path, so segments too large for it aren't
+  /// silently dropped [default: false].
+  pub fn clamp_mss(mut self, enabled: bool) -> Self {
+    self.clamp_mss = enabled;
+    self
+  }
+
+  /// Subnet that tunnel clients are leased addresses from.
+  ///
+  /// The server's interface takes the first host address, overriding the
+  /// address and netmask given to [`ServerBuilder::tunnel`].
+  pub fn subnet(mut self, subnet: Ipv4Net) -> Self {
+    self.subnet = subnet;
+    self
+  }
+
+  /// Networks besides the subnet that clients route through the tunnel,
+  /// pushed to them over the control channel. Clients route only these
+  /// unless they opt into a full tunnel.
+  pub fn routes(mut self, routes: Vec<Ipv4Net>) -> Self {
+    self.routes = routes;
+    self
+  }
+
+  /// Let tunnel clients be the gateways of networks inside `rule`'s, routed
+  /// through the tunnel to them and pushed to every client. See
+  /// [`site`](crate::site).
+  pub fn site(mut self, rule: site::Rule) -> Self {
+    self.sites.push(rule);
+    self
+  }
+
+  /// Let tunnel clients send packets out of the tunnel only as `rule`, or
+  /// the rules before it, allows. See [`egress`](crate::egress).
+  pub fn egress(mut self, rule: egress::Rule) -> Self {
+    self.egress.push(rule);
+    self
+  }
+
+  /// Name servers and search domains pushed to tunnel clients, which resolve
+  /// every name through them while tunneled.
+  pub fn dns(mut self, dns: DnsConfig) -> Self {
+    self.dns = dns;
+    self
+  }
+
+  /// Run the commands of `hooks` as the tunnel comes up and goes down, and
+  /// as clients connect to and disconnect from it. See
+  /// [`hook`](crate::hook).
+  pub fn hooks(mut self, hooks: Hooks) -> Self {
+    self.hooks = hooks;
+    self
+  }
+
+  /// Switches to this unprivileged user once the endpoint is bound and the
+  /// TUN interface is open, before any connection is accepted. Requires
+  /// running as root. Files written later, such as ACME certificates, must
+  /// be writable by the user. A tunnel with site gateways, firewall rules
+  /// or hooks keeps `CAP_NET_ADMIN`, which only Linux supports; see
+  /// [`privilege`](crate::privilege).
+  pub fn user(mut self, user: impl Into<String>) -> Self {
+    self.user = Some(user.into());
+    self
+  }
+
+  /// Group to switch to with [`ServerBuilder::user`] instead of the user's
+  /// primary group.
+  pub fn group(mut self, group: impl Into<String>) -> Self {
+    self.group = Some(group.into());
+    self
+  }
+
+  /// Binds the endpoint and returns a server ready to [`Server::run`].
+  pub fn build(self) -> Result<Server> {
+    if self.group.is_some() && self.user.is_none() {
+      return Err(QvpnError::InvalidInput(
+        "a group to switch to needs a user".into(),
+      ));
+    }
+    let upstream = match (self.mode, self.upstream) {
+      (Mode::Doq, None) => {
+        return Err(QvpnError::InvalidInput(
+          "doq mode needs an upstream resolver".into(),
+        ))
+      }
+      (Mode::Doq, Some(_)) if self.psk.is_some() => {
+        return Err(QvpnError::InvalidInput(
+          "DoQ clients can't prove a pre-shared key".into(),
+        ))
+      }
+      (Mode::Doq, upstream) => upstream,
+      _ => None,
+    };
+    let mut transport_config = quinn::TransportConfig::default();
+    // HTTP/3 clients open a control stream and two QPACK streams.
+    let uni_streams = if self.legacy_proto || self.mode == Mode::Doq {
+      0
+    } else {
+      3
+    };
+    transport_config.max_concurrent_uni_streams(quinn::VarInt::from_u32(uni_streams));
+    self.tuning.configure(&mut transport_config)?;
+    if let Some(max) = self.max_streams {
+      transport_config.max_concurrent_bidi_streams(max.try_into()?);
+    }
+    if let Some(idle_timeout) = self.idle_timeout {
+      transport_config.max_idle_timeout(Some(idle_timeout.try_into()?));
+    }
+    transport_config.keep_alive_interval(self.keep_alive_interval);
+    self.congestion.configure(&mut transport_config);
+    let crypto = rustls::ServerConfig::builder_with_provider(tls::provider())
+      .with_protocol_versions(&[&rustls::version::TLS13])?;
+    let crypto = match &self.client_ca {
+      Some(path) => {
+        let roots = Arc::new(tls::load_roots(path)?);
+        let verifier =
+          rustls::server::WebPkiClientVerifier::builder_with_provider(roots, tls::provider())
+            .build()
+            .map_err(|err| QvpnError::InvalidInput(format!("{}: {}", path.display(), err)))?;
+        crypto.with_client_cert_verifier(verifier)
+      }
+      None => crypto.with_no_client_auth(),
+    };
+    let (cert_chain, key) = match (&self.key, &self.cert) {
+      (Some(key_path), Some(cert_path)) => load_certificate(key_path, cert_path)?,
+      _ => self_signed_certificate()?,
+    };
+    if let Some(pin) = cert_chain.first().and_then(tls::spki_hash) {
+      info!(pin = %tls::encode_pin(&pin), "certificate loaded");
+    }
+    let certificate = CertResolver::new(cert_chain, &key)?;
+    if let Some(config) = &self.acme {
+      if config.domains.is_empty() {
+        return Err(QvpnError::InvalidInput(
+          "ACME needs at least one domain".into(),
+        ));
+      }
+      if !acme::install_cached(config, &certificate)? {
+        warn!("serving a self-signed certificate until ACME issues one");
+      }
+    }
+    let mut crypto = crypto.with_cert_resolver(certificate.clone());
+    crypto.max_early_data_size = if self.zero_rtt && self.psk.is_none() {
+      u32::MAX
+    } else {
+      0
+    };
+    crypto.alpn_protocols = if self.mode == Mode::Doq {
+      vec![doq::ALPN.to_vec()]
+    } else if self.legacy_proto {
+      vec![ALPN_LEGACY.to_vec()]
+    } else {
+      std::iter::once(ALPN_QVPN)
+        .chain(h3::ALPN.iter().copied())
+        .map(<[u8]>::to_vec)
+        .collect()
+    };
+    if self.keylog {
+      crypto.key_log = Arc::new(rustls::KeyLogFile::new());
+    }
+    let mut server_config =
+      quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+    server_config.transport_config(Arc::new(transport_config));
+    if let Some(lifetime) = self.retry_token_lifetime {
+      server_config.retry_token_lifetime(lifetime);
+    }
+    server_config.migration(self.migration);
+
+    let mut mounts = self.mounts;
+    if let Some(root) = self.root {
+      if !mounts.iter().any(|x| x.prefix() == "/") {
+        mounts.push(Mount::new("/", root));
+      }
+    }
+    let mounts = Arc::new(Mounts::new(mounts)?);
+
+    let logins = Logins::default();
+    let routes_sites = !self.sites.is_empty();
+    let tunnel = match &self.tunnel {
+      Some(config) => {
+        let pool = LeasePool::new(self.subnet)?;
+        let config = TunConfig {
+          address: pool.gateway(),
+          netmask: self.subnet.netmask(),
+          ..config.clone()
+        };
+        let tun = Tun::open(&config)?;
+        let masquerade = match config.masquerade {
+          Some(firewall) => Some(Masquerade::new(firewall, tun.name())?),
+          None if self.firewall && cfg!(target_os = "linux") && config.bridge.is_none() => {
+            match Masquerade::new(Firewall::Nftables, tun.name()) {
+              Ok(masquerade) => Some(masquerade),
+              Err(err) => {
+                warn!(
+                  "couldn't install firewall rules, full-tunnel clients won't reach past the server: {}",
+                  err
+                );
+                None
+              }
+            }
+          }
+          None => None,
+        };
+        #[cfg(target_os = "linux")]
+        if !self.sites.is_empty() && !site::forwarding_enabled() {
+          warn!("IPv4 forwarding is off; set net.ipv4.ip_forward = 1 to route between sites");
+        }
+        let egress = Arc::new(Policy::new(self.egress));
+        let routes: Vec<_> = iter::once(self.subnet).chain(self.routes.clone()).collect();
+        let down = self.hooks.up(vec![
+          ("QVPN_INTERFACE", tun.name().to_string()),
+          ("QVPN_ADDRESS", pool.gateway().to_string()),
+          ("QVPN_SUBNET", self.subnet.to_string()),
+          ("QVPN_ROUTES", hook::list(&routes)),
+        ]);
+        Some(Tunnel {
+          tun: Arc::new(tun),
+          router: Router::default()
+            .clamp_mss(self.clamp_mss)
+            .egress(egress.clone(), logins.clone()),
+          pool: Arc::new(Mutex::new(pool)),
+          subnet: self.subnet,
+          routes: Arc::new(RwLock::new(self.routes)),
+          sites: Arc::new(Sites::new(self.sites, self.subnet)),
+          egress,
+          dns: Arc::new(RwLock::new(self.dns)),
+          hooks: Arc::new(self.hooks),
+          _down: Arc::new(down),
+          masquerade: masquerade.map(Arc::new),
+        })
+      }
+      None => None,
+    };
+
+    #[cfg(unix)]
+    let admin_listener = self.admin_socket.as_deref().map(manage::bind).transpose()?;
+    #[cfg(not(unix))]
+    if self.admin_socket.is_some() {
+      return Err(QvpnError::Unsupported(
+        "the management socket is only supported on Unix".into(),
+      ));
+    }
+
+    let metrics_listener = match self.metrics {
+      Some(addr) => {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Some(listener)
+      }
+      None => None,
+    };
+
+    let expose_listener = match self.expose {
+      Some(addr) => {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Some(listener)
+      }
+      None => None,
+    };
+
+    let dashboard = match self.dashboard {
+      Some((addr, _)) if !net::canonical(addr).ip().is_loopback() => {
+        return Err(QvpnError::InvalidInput(format!(
+          "the dashboard sends its password in the clear, so it only listens on \
+           loopback addresses, not {}",
+          addr
+        )))
+      }
+      Some((addr, password)) => {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Some((listener, password))
+      }
+      None => None,
+    };
+
+    let accounting = match &self.accounting {
+      Some(path) => Accounting::with_file(path)?,
+      None => Accounting::default(),
+    };
+
+    let endpoint = quinn::Endpoint::new(
+      quinn::EndpointConfig::default(),
+      Some(server_config),
+      net::bind_udp(self.listen)?,
+      Arc::new(quinn::TokioRuntime),
+    )?;
+    if let Some(user) = &self.user {
+      // What the tunnel still has to change once running as `user`.
+      let net_admin = tunnel.as_ref().is_some_and(|tunnel| {
+        routes_sites || tunnel.masquerade.is_some() || !tunnel.hooks.is_empty()
+      });
+      privilege::drop_privileges(user, self.group.as_deref(), net_admin)?;
+    }
+    let metrics = Arc::new(Metrics::default());
+    if let Some(tunnel) = &tunnel {
+      metrics.watch_sites(tunnel.sites.clone());
+      metrics.watch_egress(tunnel.egress.clone());
+    }
+    Ok(Server {
+      endpoint,
+      stateless_retry: self.stateless_retry,
+      max_connections: self.max_connections,
+      metrics_listener,
+      expose_listener,
+      dashboard,
+      #[cfg(unix)]
+      admin_listener,
+      config_file: self.config_file,
+      stats_interval: self.stats_interval,
+      acme: self.acme,
+      cert_files: self.key.zip(self.cert),
+      certificate,
+      shared: Shared {
+        mounts,
+        index: self.index.map(Arc::from),
+        follow_symlinks: self.follow_symlinks,
+        compression: self.compression,
+        digests: Arc::default(),
+        files: Some(self.cache_size)
+          .filter(|x| *x > 0)
+          .map(|x| Arc::new(FileCache::new(x))),
+        tunnel,
+        client_auth: self.client_ca.is_some(),
+        psk: self.psk.map(Arc::new),
+        psk_timeout: self.psk_timeout,
+        zero_rtt: self.zero_rtt,
+        congestion: self.congestion,
+        mode: self.mode,
+        uploads: self.uploads,
+        bench: self.bench,
+        rpc: Arc::new(self.rpc.route(rpc::PING, |()| async { Ok(()) })),
+        forwards: Arc::new(self.forwards),
+        exposed: self.expose.map(|_| expose::Registry::default()),
+        chunk_size: self.chunk_size,
+        metrics,
+        stats: Tracker::default(),
+        accounting,
+        authenticator: self.authenticator,
+        verifier: self.verifier,
+        logins,
+        access: self.access.map(Arc::new),
+        qlog: self.qlog.map(Arc::from),
+        rate_limiter: self.connection_rate.map(|x| Arc::new(RateLimiter::new(x))),
+        validated: self
+          .validation_cache
+          .map(|x| Arc::new(ValidatedAddrs::new(x))),
+        log_amplification: self.log_amplification,
+        legacy_proto: self.legacy_proto,
+        upstream,
+        controls: Channels::default(),
+        udp: Arc::default(),
+      },
+    })
+  }
+}
+
+fn load_certificate(
+  key_path: &Path,
+  cert_path: &Path,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+  let is_der = |path: &Path| path.extension().is_some_and(|x| x == "der");
+  let in_file = |path: &Path, err| match err {
+    QvpnError::InvalidInput(err) => QvpnError::InvalidInput(format!("{}: {}", path.display(), err)),
+    err => err,
+  };
+  let key =
+    tls::parse_key(&fs::read(key_path)?, is_der(key_path)).map_err(|err| in_file(key_path, err))?;
+  let cert_chain = tls::parse_chain(&fs::read(cert_path)?, is_der(cert_path))
+    .map_err(|err| in_file(cert_path, err))?;
+  Ok((cert_chain, key))
+}
+
+/// What the dashboard shows: the open connections, the tunnel leases, the
+/// site gateways and recent log events.
+async fn status(shared: Shared) -> serde_json::Value {
+  let leases = match &shared.tunnel {
+    Some(tunnel) => tunnel.router.leases().await,
+    None => vec![],
+  };
+  let leases: Vec<_> = leases
+    .iter()
+    .map(|(address, connection)| {
+      serde_json::json!({
+        "address": address.to_string(),
+        "id": connection.stable_id(),
+        "remote": net::canonical(connection.remote_address()).to_string(),
+      })
+    })
+    .collect();
+  let sites = match &shared.tunnel {
+    Some(tunnel) => tunnel.sites.health(),
+    None => vec![],
+  };
+  let sites: Vec<_> = sites
+    .iter()
+    .map(|site| {
+      serde_json::json!({
+        "address": site.address.to_string(),
+        "networks": site.networks.iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "remote": site.remote.to_string(),
+        "rtt_ms": site.rtt.as_millis() as u64,
+        "up_secs": site.up.as_secs(),
+      })
+    })
+    .collect();
+  let connections: Vec<_> = shared.stats.snapshot().iter().map(Stats::to_json).collect();
+  serde_json::json!({
+    "connections": connections,
+    "leases": leases,
+    "sites": sites,
+    "events": log::recent(),
+  })
+}
+
+/// How often the certificate files are checked for changes.
+pub const CERT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Reloads the certificate into `resolver` whenever the modification time of
+/// either file changes. Connections already established keep the
+/// certificate they were accepted with.
+async fn watch_certificate(key_path: PathBuf, cert_path: PathBuf, resolver: Arc<CertResolver>) {
+  let modified = |path: &Path| fs::metadata(path).and_then(|x| x.modified()).ok();
+  let mut last = (modified(&key_path), modified(&cert_path));
+  loop {
+    tokio::time::sleep(CERT_POLL_INTERVAL).await;
+    let current = (modified(&key_path), modified(&cert_path));
+    if current == last {
+      continue;
+    }
+    // A file caught half written fails to parse and is retried on the next
+    // poll; one replaced after the other is picked up by a second reload.
+    let result = load_certificate(&key_path, &cert_path)
+      .and_then(|(cert_chain, key)| resolver.set(cert_chain, &key));
+    match result {
+      Ok(()) => {
+        info!(cert = %cert_path.display(), "certificate reloaded");
+        last = current;
+      }
+      Err(err) => warn!(cert = %cert_path.display(), "couldn't reload certificate: {}", err),
+    }
+  }
+}
+
+/// The server as its management API sees it.
+#[cfg(unix)]
+struct Admin {
+  shared: Shared,
+  certificate: Arc<CertResolver>,
+  cert_files: Option<(PathBuf, PathBuf)>,
+  config_file: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl manage::Handler for Admin {
+  fn clients(&self) -> Vec<manage::ClientInfo> {
+    self
+      .shared
+      .stats
+      .snapshot()
+      .into_iter()
+      .map(|stats| manage::ClientInfo {
+        id: stats.id,
+        remote: stats.remote,
+      })
+      .collect()
+  }
+
+  fn kick(&self, id: usize, reason: &str) -> bool {
+    let connection = match self.shared.stats.connection(id) {
+      Some(connection) => connection,
+      None => return false,
+    };
+    let reason = reason.to_string();
+    // A client with a control stream is told why before it is closed.
+    if !self.shared.controls.send(
+      id,
+      control::Message::Disconnect {
+        reason: reason.clone(),
+      },
+    ) {
+      connection.close(CLOSE_DISCONNECTED.into(), reason.as_bytes());
+    }
+    true
+  }
+
+  fn stats(&self) -> Vec<Stats> {
+    self.shared.stats.snapshot()
+  }
+
+  fn reload(&self) -> Result<serde_json::Value> {
+    let path = self.config_file.as_deref().ok_or_else(|| {
+      QvpnError::Unsupported("the server was started without a config file".into())
+    })?;
+    let tunnel = self
+      .shared
+      .tunnel
+      .as_ref()
+      .ok_or_else(|| QvpnError::Unsupported("tunnel disabled".into()))?;
+    let config = Config::load(path)?.tunnel;
+    let dns = DnsConfig {
+      servers: config.dns,
+      search: config.search_domains,
+    };
+    *tunnel.routes.write().expect("routes lock poisoned") = config.routes.clone();
+    *tunnel.dns.write().expect("dns lock poisoned") = dns.clone();
+    let routes = tunnel.pushed_routes();
+    self.shared.controls.broadcast(&control::Message::Routes {
+      routes: routes.clone(),
+    });
+    let clients = self.shared.controls.broadcast(&control::Message::Dns {
+      servers: dns.servers.clone(),
+      search: dns.search.clone(),
+    });
+    Ok(serde_json::json!({
+      "routes": routes.iter().map(ToString::to_string).collect::<Vec<_>>(),
+      "dns": dns.servers,
+      "search_domains": dns.search,
+      "clients": clients,
+    }))
+  }
+
+  fn rotate_certs(&self) -> Result<()> {
+    let (key, cert) = self
+      .cert_files
+      .as_ref()
+      .ok_or_else(|| QvpnError::Unsupported("the certificate isn't loaded from files".into()))?;
+    let (cert_chain, key) = load_certificate(key, cert)?;
+    self.certificate.set(cert_chain, &key)
+  }
+}
+
+fn self_signed_certificate() -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+  let dirs = directories_next::ProjectDirs::from("org", "quinn", "quinn-examples")
+    .ok_or_else(|| QvpnError::InvalidInput("no valid home directory found".into()))?;
+  let path = dirs.data_local_dir();
+  let cert_path = path.join("cert.der");
+  let key_path = path.join("key.der");
+  let (cert, key) = match fs::read(&cert_path).and_then(|x| Ok((x, fs::read(&key_path)?))) {
+    Ok(x) => x,
+    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+      info!("generating self-signed certificate");
+      let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+      let key = certified.signing_key.serialize_der();
+      let cert = certified.cert.der().to_vec();
+      fs::create_dir_all(path)?;
+      fs::write(&cert_path, &cert)?;
+      fs::write(&key_path, &key)?;
+      (cert, key)
+    }
+    Err(e) => {
+      return Err(e.into());
+    }
+  };
+  Ok((
+    vec![CertificateDer::from(cert)],
+    PrivateKeyDer::Pkcs8(key.into()),
+  ))
+}
+
+/// Request sent by tunnel clients predating the [`control`] channel to
+/// obtain a [`Lease`].
+pub const LEASE_REQUEST: &[u8] = b"LEASE\r\n";
+
+/// Application close code for connections refused for lack of resources or
+/// for exceeding [`ServerBuilder::connection_rate`].
+pub const CLOSE_REFUSED: u32 = 1;
+
+/// Application close code for connections without a client certificate when
+/// [`ServerBuilder::client_ca`] is set, or that fail to prove the
+/// [`ServerBuilder::psk`].
+pub const CLOSE_UNAUTHENTICATED: u32 = 2;
+
+/// Application close code for connections the server ended after sending a
+/// [`control::Message::Disconnect`] with the reason.
+pub const CLOSE_DISCONNECTED: u32 = 3;
+
+#[derive(Clone)]
+struct Tunnel {
+  tun: Arc<Tun>,
+  router: Router,
+  pool: Arc<Mutex<LeasePool>>,
+  subnet: Ipv4Net,
+  /// Pushed to clients along with the subnet; changed by a reload.
+  routes: Arc<RwLock<Vec<Ipv4Net>>>,
+  /// Gateway clients, whose networks are pushed too.
+  sites: Arc<Sites>,
+  /// Where clients may send packets.
+  egress: Arc<Policy>,
+  dns: Arc<RwLock<DnsConfig>>,
+  hooks: Arc<Hooks>,
+  /// Runs the `down` hook once the last clone is dropped.
+  _down: Arc<hook::Down>,
+  /// Removes its rules once the last clone is dropped.
+  masquerade: Option<Arc<Masquerade>>,
+}
+
+impl Tunnel {
+  /// Routes pushed to clients: the subnet, then the configured ones, then
+  /// those of the sites.
+  fn pushed_routes(&self) -> Vec<Ipv4Net> {
+    let routes = self.routes.read().expect("routes lock poisoned");
+    iter::once(self.subnet)
+      .chain(routes.iter().copied())
+      .chain(self.sites.networks())
+      .collect()
+  }
+
+  /// Runs the `client-connect` hook for the client of `connection`, leased
+  /// `address` and logged in as `user` if it did, and the
+  /// `client-disconnect` one once the connection closes.
+  fn hook_client(&self, connection: &quinn::Connection, address: Ipv4Addr, user: Option<String>) {
+    let hooks = &self.hooks;
+    if hooks.client_connect.is_none() && hooks.client_disconnect.is_none() {
+      return;
+    }
+    let mut env = vec![
+      ("QVPN_INTERFACE", self.tun.name().to_string()),
+      ("QVPN_ADDRESS", address.to_string()),
+      ("QVPN_ROUTES", hook::list(&self.pushed_routes())),
+      (
+        "QVPN_REMOTE",
+        net::canonical(connection.remote_address()).to_string(),
+      ),
+    ];
+    if let Some(user) = user {
+      env.push(("QVPN_USER", user));
+    }
+    if let Some(identity) = ClientIdentity::from_connection(connection) {
+      env.push(("QVPN_IDENTITY", identity.to_string()));
+    }
+    hooks.run(hook::Event::ClientConnect, &env);
+    if hooks.client_disconnect.is_none() {
+      return;
+    }
+    let (hooks, connection) = (hooks.clone(), connection.clone());
+    tokio::spawn(async move {
+      connection.closed().await;
+      let stats = Stats::of(&connection, 0);
+      env.push(("QVPN_BYTES_SENT", stats.bytes_sent.to_string()));
+      env.push(("QVPN_BYTES_RECEIVED", stats.bytes_received.to_string()));
+      hooks.run(hook::Event::ClientDisconnect, &env);
+    });
+  }
+
+  /// Routes `networks` to the client of `connection`, which must have
+  /// leased an address, in place of any it advertised before, and pushes
+  /// the new routes to every client.
+  async fn advertise(
+    &self,
+    controls: &Channels,
+    connection: &quinn::Connection,
+    networks: Vec<Ipv4Net>,
+  ) -> Result<()> {
+    let id = connection.stable_id();
+    let address = self
+      .router
+      .leases()
+      .await
+      .into_iter()
+      .find(|(_, x)| x.stable_id() == id)
+      .map(|(address, _)| address)
+      .ok_or_else(|| QvpnError::BadRequest("advertised networks before a lease".into()))?;
+    let address = match address {
+      IpAddr::V4(address) => address,
+      IpAddr::V6(_) => return Err(QvpnError::BadRequest("lease is not IPv4".into())),
+    };
+    let old = self.sites.insert(connection, address, networks.clone())?;
+    self.router.route_networks(address.into(), &networks).await;
+    self.route_sites(&old, &networks);
+    info!(%address, ?networks, "site gateway up");
+    controls.broadcast(&control::Message::Routes {
+      routes: self.pushed_routes(),
+    });
+    Ok(())
+  }
+
+  /// Forgets the networks the client of `connection` advertised, if any,
+  /// and pushes the new routes to every client.
+  fn withdraw(&self, controls: &Channels, connection: &quinn::Connection) {
+    let old = self.sites.remove(connection);
+    if old.is_empty() {
+      return;
+    }
+    self.route_sites(&old, &[]);
+    info!(networks = ?old, "site gateway down");
+    controls.broadcast(&control::Message::Routes {
+      routes: self.pushed_routes(),
+    });
+  }
+
+  /// Moves the kernel routes of a site from networks `old` to `new`.
+  fn route_sites(&self, old: &[Ipv4Net], new: &[Ipv4Net]) {
+    if self.tun.is_provided() {
+      return;
+    }
+    for network in old.iter().filter(|x| !new.contains(x)) {
+      if let Err(err) = tun::delete_route(self.tun.name(), *network) {
+        warn!(%network, "couldn't remove site route: {}", err);
+      }
+    }
+    for network in new.iter().filter(|x| !old.contains(x)) {
+      if let Err(err) = tun::add_route(self.tun.name(), *network) {
+        warn!(%network, "couldn't route site: {}", err);
+      }
+    }
+  }
+}
+
+/// State shared by every connection.
+#[derive(Clone)]
+struct Shared {
+  mounts: Arc<Mounts>,
+  /// File served for directories containing it, instead of a listing.
+  index: Option<Arc<str>>,
+  /// Follow symlinks out of their mount.
+  follow_symlinks: bool,
+  /// Compress file responses for clients that accept it.
+  compression: bool,
+  /// Digests of files served to clients asking for one.
+  digests: Arc<DigestCache>,
+  /// Contents of popular files, if kept in memory.
+  files: Option<Arc<FileCache>>,
+  tunnel: Option<Tunnel>,
+  client_auth: bool,
+  psk: Option<Arc<Psk>>,
+  psk_timeout: Duration,
+  zero_rtt: bool,
+  congestion: Congestion,
+  mode: Mode,
+  uploads: bool,
+  /// Answer benchmark requests.
+  bench: bool,
+  /// Methods answered on rpc streams.
+  rpc: Arc<rpc::Router>,
+  /// Rules for clients forwarding TCP ports.
+  forwards: Arc<forward::Acl>,
+  /// Names of the services clients expose, if the server accepts them.
+  exposed: Option<expose::Registry>,
+  /// UDP flows relayed for proxy clients.
+  udp: Arc<udp::Flows>,
+  chunk_size: usize,
+  metrics: Arc<Metrics>,
+  stats: Tracker,
+  accounting: Accounting,
+  /// Checks the password logins of tunnel and proxy clients, if they must
+  /// log in.
+  authenticator: Option<Arc<dyn Authenticator>>,
+  /// Checks the token logins of tunnel and proxy clients, if they must log
+  /// in.
+  verifier: Option<Arc<Verifier>>,
+  logins: Logins,
+  /// Checks the access tokens of file requests, if they need one.
+  access: Option<Arc<SigningKey>>,
+  qlog: Option<Arc<Path>>,
+  rate_limiter: Option<Arc<RateLimiter>>,
+  validated: Option<Arc<ValidatedAddrs>>,
+  log_amplification: bool,
+  legacy_proto: bool,
+  /// Resolver DoQ queries are forwarded to, in [`Mode::Doq`].
+  upstream: Option<SocketAddr>,
+  /// Control streams of connected clients.
+  controls: Channels,
+}
+
+impl Shared {
+  /// Whether `connection` may use the tunnel and proxy: its client logged
+  /// in, or no login is required.
+  fn logged_in(&self, connection: &quinn::Connection) -> bool {
+    (self.authenticator.is_none() && self.verifier.is_none())
+      || self.logins.user(connection).is_some()
+  }
+}
+
+/// Identity of a client that authenticated with a certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+  /// SHA-256 fingerprint of the client's leaf certificate.
+  pub fingerprint: String,
+}
+
+impl ClientIdentity {
+  fn from_connection(connection: &quinn::Connection) -> Option<Self> {
+    let chain = connection
+      .peer_identity()?
+      .downcast::<Vec<CertificateDer<'static>>>()
+      .ok()?;
+    let leaf = chain.first()?;
+    Some(ClientIdentity {
+      fingerprint: tls::fingerprint(leaf),
+    })
+  }
+}
+
+impl fmt::Display for ClientIdentity {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "sha256:{}", self.fingerprint)
+  }
+}
+
+/// A bound file server.
+pub struct Server {
+  endpoint: quinn::Endpoint,
+  stateless_retry: bool,
+  max_connections: Option<u32>,
+  metrics_listener: Option<std::net::TcpListener>,
+  expose_listener: Option<std::net::TcpListener>,
+  dashboard: Option<(std::net::TcpListener, dashboard::Password)>,
+  #[cfg(unix)]
+  admin_listener: Option<std::os::unix::net::UnixListener>,
+  // Only the management socket reloads settings.
+  #[cfg_attr(not(unix), allow(dead_code))]
+  config_file: Option<PathBuf>,
+  stats_interval: Option<Duration>,
+  acme: Option<AcmeConfig>,
+  cert_files: Option<(PathBuf, PathBuf)>,
+  certificate: Arc<CertResolver>,
+  shared: Shared,
+}
+
+impl Server {
+  /// Returns a builder serving files from `root`.
+  pub fn builder(root: impl Into<PathBuf>) -> ServerBuilder {
+    ServerBuilder::new(root)
+  }
+
+  /// The address the server is listening on.
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.endpoint.local_addr()
+  }
+
+  /// The address metrics are served on, if enabled.
+  pub fn metrics_addr(&self) -> Option<SocketAddr> {
+    self.metrics_listener.as_ref()?.local_addr().ok()
+  }
+
+  /// The address exposed services are reached on, if enabled.
+  pub fn expose_addr(&self) -> Option<SocketAddr> {
+    self.expose_listener.as_ref()?.local_addr().ok()
+  }
+
+  /// The address the dashboard is served on, if enabled.
+  pub fn dashboard_addr(&self) -> Option<SocketAddr> {
+    self.dashboard.as_ref()?.0.local_addr().ok()
+  }
+
+  /// Counters updated while the server runs.
+  pub fn metrics(&self) -> Arc<Metrics> {
+    self.shared.metrics.clone()
+  }
+
+  /// Transfer statistics of every open connection.
+  pub fn stats(&self) -> Vec<Stats> {
+    self.shared.stats.snapshot()
+  }
+
+  /// Accepts connections until the endpoint is closed.
+  pub async fn run(self) {
+    self.run_until(future::pending()).await
+  }
+
+  /// Accepts connections until `shutdown` completes, then tells clients
+  /// with a control stream why they are disconnected and closes the
+  /// endpoint.
+  pub async fn run_until(mut self, shutdown: impl Future<Output = ()>) {
+    if let Some(listener) = self.metrics_listener.take() {
+      let metrics = self.shared.metrics.clone();
+      tokio::spawn(async move {
+        let result = match TcpListener::from_std(listener) {
+          Ok(listener) => metrics::serve(listener, metrics).await,
+          Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+          error!("metrics endpoint failed: {}", err);
+        }
+      });
+    }
+    if let Some((listener, registry)) = self.expose_listener.take().zip(self.shared.exposed.clone())
+    {
+      tokio::spawn(async move {
+        let result = match TcpListener::from_std(listener) {
+          Ok(listener) => expose::serve(listener, registry).await,
+          Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+          error!("expose endpoint failed: {}", err);
+        }
+      });
+    }
+    if let Some((listener, password)) = self.dashboard.take() {
+      let shared = self.shared.clone();
+      tokio::spawn(async move {
+        let status = move || status(shared.clone());
+        let result = match TcpListener::from_std(listener) {
+          Ok(listener) => dashboard::serve(listener, password, status).await,
+          Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+          error!("dashboard failed: {}", err);
+        }
+      });
+    }
+    if let Some(interval) = self.stats_interval {
+      tokio::spawn(stats::report(&self.shared.stats, interval));
+    }
+    if let Some(config) = self.acme.take() {
+      tokio::spawn(acme::run(config, self.certificate.clone()));
+    }
+    #[cfg(unix)]
+    if let Some(listener) = self.admin_listener.take() {
+      let admin = Admin {
+        shared: self.shared.clone(),
+        certificate: self.certificate.clone(),
+        cert_files: self.cert_files.clone(),
+        config_file: self.config_file.clone(),
+      };
+      tokio::spawn(async move {
+        let result = match UnixListener::from_std(listener) {
+          Ok(listener) => manage::serve(listener, Arc::new(admin)).await,
+          Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+          error!("management socket failed: {}", err);
+        }
+      });
+    }
+    if let Some((key, cert)) = self.cert_files.take() {
+      tokio::spawn(watch_certificate(key, cert, self.certificate.clone()));
+    }
+    let (certificate, controls) = (self.certificate.clone(), self.shared.controls.clone());
+    tokio::spawn(async move {
+      loop {
+        certificate.changed().await;
+        let clients = controls.broadcast(&control::Message::Rekey);
+        debug!(clients, "announced the new certificate");
+      }
+    });
+    if let Some(tunnel) = &self.shared.tunnel {
+      info!(interface = tunnel.tun.name(), "tunnel up");
+      let router = tunnel.router.clone();
+      let tun = tunnel.tun.clone();
+      tokio::spawn(async move {
+        if let Err(err) = router.run(tun).await {
+          error!("tunnel failed: {}", err);
+        }
+      });
+    }
+    tokio::pin!(shutdown);
+    loop {
+      let incoming = tokio::select! {
+        incoming = self.endpoint.accept() => match incoming {
+          Some(incoming) => incoming,
+          None => return,
+        },
+        () = &mut shutdown => break,
+      };
+      let remote = net::canonical(incoming.remote_address());
+      if self.stateless_retry && !incoming.remote_address_validated() {
+        debug!(%remote, "sending retry");
+        let _ = incoming.retry();
+        continue;
+      }
+      if let Some(max) = self.max_connections {
+        if self.endpoint.open_connections() >= max as usize {
+          debug!(%remote, "too many connections");
+          self.shared.metrics.connection_refused();
+          incoming.refuse();
+          continue;
+        }
+      }
+      if let Some(limiter) = &self.shared.rate_limiter {
+        let validated = self
+          .shared
+          .validated
+          .as_ref()
+          .is_some_and(|x| x.contains(remote.ip()));
+        if !validated && !limiter.admit(remote.ip()) {
+          debug!(%remote, "connection rate exceeded");
+          self.shared.metrics.connection_refused();
+          incoming.refuse();
+          continue;
+        }
+      }
+      let conn = match incoming.accept() {
+        Ok(conn) => conn,
+        Err(err) => {
+          debug!(%remote, "couldn't accept connection: {}", err);
+          self.shared.metrics.handshake_failed();
+          continue;
+        }
+      };
+      let span = info_span!("connection", %remote);
+      let shared = self.shared.clone();
+      tokio::spawn(
+        async move {
+          debug!("connection incoming");
+          if let Err(err) = handle_connection(shared, conn).await {
+            warn!("connection failed: {}", err);
+          }
+        }
+        .instrument(span),
+      );
+    }
+    let clients = self
+      .shared
+      .controls
+      .broadcast(&control::Message::Disconnect {
+        reason: "server shutting down".into(),
+      });
+    info!(clients, "shutting down");
+    if clients > 0 {
+      tokio::time::sleep(DISCONNECT_GRACE).await;
+    }
+    self.endpoint.close(0u32.into(), b"server shutting down");
+    self.endpoint.wait_idle().await;
+  }
+}
+
+/// Records the client's address as validated and logs the handshake's
+/// amplification once the handshake of `connection` is complete.
+async fn handshake_complete(
+  shared: Shared,
+  connection: quinn::Connection,
+  established: Option<Established>,
+) {
+  // A 0-RTT connection resolves to false both when its early data was
+  // rejected and when the handshake failed, so only an accepted one counts.
+  if let Some(established) = established {
+    if !established.await {
+      return;
+    }
+  }
+  let remote = net::canonical(connection.remote_address());
+  if let Some(validated) = &shared.validated {
+    validated.insert(remote.ip());
+  }
+  if shared.log_amplification {
+    let stats = connection.stats();
+    let (sent, received) = (stats.udp_tx.bytes, stats.udp_rx.bytes);
+    if sent > received.saturating_mul(AMPLIFICATION_FACTOR) {
+      warn!(
+        sent,
+        received, "handshake exceeded the anti-amplification limit"
+      );
+    } else {
+      debug!(sent, received, "handshake amplification");
+    }
+  }
+}
+
+/// Bytes a server may send per byte received from an unvalidated address.
+const AMPLIFICATION_FACTOR: u64 = 3;
+
+/// Resolves once the handshake of a connection accepted with 0-RTT completes.
+type Established = future::Shared<quinn::ZeroRttAccepted>;
+
+async fn handle_connection(shared: Shared, conn: quinn::Connecting) -> Result<()> {
+  // Client certificates are only known once the handshake completes, and
+  // the pre-shared key is proven after it, so 0-RTT is off when either is
+  // required.
+  let early = if shared.zero_rtt && !shared.client_auth && shared.psk.is_none() {
+    conn
+      .into_0rtt()
+      .map(|(connection, established)| (connection, Some(established.shared())))
+  } else {
+    Err(conn)
+  };
+  let (connection, established) = match early {
+    Ok(accepted) => accepted,
+    Err(conn) => match conn.await {
+      Ok(connection) => (connection, None),
+      Err(err) => {
+        shared.metrics.handshake_failed();
+        return Err(err.into());
+      }
+    },
+  };
+  shared.metrics.connection_accepted();
+  shared.stats.track(&connection);
+  if shared.validated.is_some() || shared.log_amplification {
+    tokio::spawn(
+      handshake_complete(shared.clone(), connection.clone(), established.clone()).in_current_span(),
+    );
+  }
+  let _report = congestion::Report::new(connection.clone(), shared.congestion);
+  let identity = ClientIdentity::from_connection(&connection);
+  match &identity {
+    Some(identity) => info!(client = %identity, "established"),
+    None if shared.client_auth => {
+      connection.close(CLOSE_UNAUTHENTICATED.into(), b"client certificate required");
+      return Err(QvpnError::Unauthenticated(format!(
+        "{} presented no client certificate",
+        net::canonical(connection.remote_address())
+      )));
+    }
+    None => info!("established"),
+  }
+  let h3 = !shared.legacy_proto
+    && tls::alpn_protocol(&connection).is_some_and(|protocol| protocol != ALPN_QVPN);
+  if let Some(psk) = &shared.psk {
+    if h3 {
+      connection.close(CLOSE_UNAUTHENTICATED.into(), b"pre-shared key required");
+      return Err(QvpnError::Unauthenticated(format!(
+        "{} connected over HTTP/3, which can't prove the pre-shared key",
+        net::canonical(connection.remote_address())
+      )));
+    }
+    let proof = tokio::time::timeout(shared.psk_timeout, psk.accept(&connection)).await;
+    let err = match proof {
+      Ok(Ok(())) => None,
+      Ok(Err(err)) => Some(err.to_string()),
+      Err(_) => Some("timed out".into()),
+    };
+    if let Some(err) = err {
+      connection.close(CLOSE_UNAUTHENTICATED.into(), b"pre-shared key required");
+      return Err(QvpnError::Unauthenticated(format!(
+        "{} didn't prove the pre-shared key: {}",
+        net::canonical(connection.remote_address()),
+        err
+      )));
+    }
+    debug!("pre-shared key proven");
+  }
+  shared
+    .accounting
+    .open(&connection, identity.map(|x| x.to_string()));
+  let _trace = match &shared.qlog {
+    Some(dir) => match qlog::Trace::start(dir, &connection, qlog::Vantage::Server).await {
+      Ok(trace) => Some(trace),
+      Err(err) => {
+        warn!("couldn't start qlog trace: {}", err);
+        None
+      }
+    },
+    None => None,
+  };
+  if let Some(upstream) = shared.upstream {
+    return doq::serve(connection, upstream).await;
+  }
+  if h3 {
+    return serve_h3(shared, connection).await;
+  }
+  tokio::spawn(handle_datagrams(shared.clone(), connection.clone()).in_current_span());
+
+  // Each stream initiated by the client constitutes a new request.
+  loop {
+    let (send, recv) = match connection.accept_bi().await {
+      Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
+        info!("connection closed");
+        return Ok(());
+      }
+      Err(e) => return Err(e.into()),
+      Ok(s) => s,
+    };
+    let span = info_span!("stream", id = %send.id());
+    let open = shared.metrics.open_stream();
+    shared.stats.stream_opened(&connection);
+    let (shared, connection, established) =
+      (shared.clone(), connection.clone(), established.clone());
+    tokio::spawn(
+      async move {
+        let _open = open;
+        if let Err(err) = handle_request(shared, connection, established, send, recv).await {
+          warn!("request failed: {}", err);
+        }
+      }
+      .instrument(span),
+    );
+  }
+}
+
+async fn handle_datagrams(shared: Shared, connection: quinn::Connection) {
+  let tunnel = shared.tunnel.clone();
+  let mut reassembler = Reassembler::default();
+  loop {
+    let datagram = match connection.read_datagram().await {
+      Ok(datagram) => datagram,
+      Err(e) => {
+        debug!("datagrams closed: {}", e);
+        break;
+      }
+    };
+    match Frame::decode(datagram) {
+      Some(Frame {
+        kind: Kind::Packet,
+        id,
+        payload,
+      }) => match &tunnel {
+        Some(Tunnel { tun, router, .. }) => {
+          if let Err(err) = router.inbound(tun, &connection, id, payload).await {
+            debug!("{}", err);
+          }
+        }
+        None => debug!("tunnel disabled, dropping packet"),
+      },
+      Some(Frame {
+        kind: Kind::Fragment,
+        id,
+        payload,
+      }) => match &tunnel {
+        Some(Tunnel { tun, router, .. }) => {
+          if let Some(packet) = reassembler.push(payload) {
+            if let Err(err) = router.inbound(tun, &connection, id, packet).await {
+              debug!("{}", err);
+            }
+          }
+        }
+        None => debug!("tunnel disabled, dropping packet"),
+      },
+      Some(Frame {
+        kind: Kind::Request,
+        id,
+        payload,
+      }) => {
+        tokio::spawn(
+          handle_datagram_request(shared.clone(), connection.clone(), id, payload)
+            .in_current_span(),
+        );
+      }
+      Some(Frame {
+        kind: Kind::Udp,
+        id,
+        payload,
+      }) => shared.udp.deliver(&connection, id, &payload),
+      _ => debug!("ignoring unexpected datagram"),
+    }
+  }
+  if let Some(tunnel) = tunnel {
+    tunnel.withdraw(&shared.controls, &connection);
+    tunnel.router.remove_connection(&connection).await;
+    tunnel.pool.lock().await.release(connection.stable_id());
+  }
+}
+
+/// Leases a tunnel address to `connection` and writes the lease to `send`.
+async fn handle_lease(
+  tunnel: Option<Tunnel>,
+  accounting: &Accounting,
+  connection: &quinn::Connection,
+  send: &mut quinn::SendStream,
+) -> Result<()> {
+  let tunnel = tunnel.ok_or_else(|| QvpnError::Unsupported("tunnel disabled".into()))?;
+  if tunnel.tun.is_tap() {
+    return Err(QvpnError::Incompatible(
+      "the tunnel carries Ethernet frames; upgrade the client".into(),
+    ));
+  }
+  let lease = lease_address(&tunnel, accounting, connection, None)
+    .await
+    .inspect_err(|_| connection.close(CLOSE_REFUSED.into(), b"address pool exhausted"))?;
+  send.write_all(lease.encode().as_bytes()).await?;
+  Ok(())
+}
+
+/// Leases an address from the pool to `connection`, whose client logged in
+/// as `user` if it did, and routes packets for it there. A connection
+/// asking again gets the lease it holds.
+async fn lease_address(
+  tunnel: &Tunnel,
+  accounting: &Accounting,
+  connection: &quinn::Connection,
+  user: Option<String>,
+) -> Result<Lease> {
+  let (lease, held) = {
+    let mut pool = tunnel.pool.lock().await;
+    let dns = tunnel.dns.read().expect("dns lock poisoned").clone();
+    let held = pool.leased(connection.stable_id()).is_some();
+    let address = pool
+      .allocate(connection.stable_id())
+      .ok_or_else(|| QvpnError::Unsupported("address pool exhausted".into()))?;
+    let subnet = pool.subnet();
+    let lease = Lease {
+      address,
+      netmask: subnet.netmask(),
+      gateway: pool.gateway(),
+      mtu: tunnel.tun.mtu(),
+      routes: tunnel.pushed_routes(),
+      dns: dns.servers,
+      search: dns.search,
+      tap: tunnel.tun.is_tap(),
+    };
+    (lease, held)
+  };
+  let address = lease.address;
+  if held {
+    debug!(%address, "connection asked again for its tunnel address");
+    return Ok(lease);
+  }
+  info!(%address, "leased tunnel address");
+  accounting.leased(connection, address.into());
+  tunnel
+    .router
+    .insert(address.into(), connection.clone())
+    .await;
+  tunnel.hook_client(connection, address, user);
+  Ok(lease)
+}
+
+/// Answers a request with a single datagram, or an error frame if the file
+/// does not fit in one.
+async fn handle_datagram_request(
+  shared: Shared,
+  connection: quinn::Connection,
+  id: u32,
+  req: Bytes,
+) {
+  let max = datagram::max_payload(&connection).unwrap_or(0);
+  let (kind, payload) = match read_small_file(&shared, &connection, &req, max).await {
+    Ok(body) => (Kind::Response, Bytes::from(body)),
+    Err(err) => {
+      warn!("datagram request failed: {}", err);
+      (Kind::Error, Bytes::from(err.to_string()))
+    }
+  };
+  let frame = Frame { kind, id, payload };
+  if let Err(err) = connection.send_datagram(frame.encode()) {
+    warn!("failed to send response: {}", err);
+  }
+}
+
+async fn read_small_file(
+  shared: &Shared,
+  connection: &quinn::Connection,
+  req: &[u8],
+  max: usize,
+) -> Result<Vec<u8>> {
+  let path = request_path(req, "GET")?;
+  if let Some(key) = &shared.access {
+    key.authorize(&path, request_query(req), &request_headers(req)?)?;
+  }
+  let real_path = match resolve(shared, connection, &path).await? {
+    Resolved::Path { real_path, .. } => real_path,
+    Resolved::Mounts(_) => return Err(QvpnError::NotFound(PathBuf::from(path))),
+  };
+  let meta = tokio::fs::metadata(&real_path)
+    .await
+    .map_err(|_| QvpnError::NotFound(real_path.clone()))?;
+  if meta.len() > max as u64 {
+    return Err(QvpnError::Unsupported(
+      "response too large for a datagram, use the stream transport".into(),
+    ));
+  }
+  Ok(tokio::fs::read(&real_path).await?)
+}
+
+/// Application error code used to reset a stream whose response could not be
+/// completed.
+const INTERNAL_ERROR: u32 = 1;
+
+async fn handle_request(
+  shared: Shared,
+  connection: quinn::Connection,
+  established: Option<Established>,
+  mut send: quinn::SendStream,
+  mut recv: quinn::RecvStream,
+) -> Result<()> {
+  let line = http::read_until(&mut recv, b"\r\n").await;
+  if let Ok((req, _)) = &line {
+    let mut parts = req.trim_ascii_end().splitn(3, |&c| c == b' ');
+    let method = String::from_utf8_lossy(parts.next().unwrap_or_default());
+    let path = String::from_utf8_lossy(parts.next().unwrap_or_default());
+    shared
+      .metrics
+      .request(&method, path.split('?').next().unwrap_or_default());
+    // Early data can be replayed by an attacker, so anything but a GET
+    // waits for the handshake to complete.
+    if let Some(established) = established.filter(|_| method != "GET") {
+      established.await;
+    }
+  }
+  let result = match line {
+    Ok((req, _)) if req == LEASE_REQUEST && !shared.logged_in(&connection) => Err(
+      QvpnError::Unauthenticated("the legacy lease request can't log in".into()),
+    ),
+    Ok((req, _)) if req == LEASE_REQUEST => {
+      handle_lease(shared.tunnel, &shared.accounting, &connection, &mut send).await
+    }
+    Ok((req, rest)) if req.starts_with(version::REQUEST_PREFIX) => match version::respond(&req) {
+      Ok(head) if head.is_success() => {
+        let version = version::check(&head)?;
+        send.write_all(head.encode().as_bytes()).await?;
+        let control = Control::new(send, recv, rest);
+        return serve_control(shared, connection, version, control).await;
+      }
+      Ok(head) => {
+        warn!(
+          offered = %String::from_utf8_lossy(&req).trim_end(),
+          "client speaks no protocol version of ours"
+        );
+        send
+          .write_all(head.encode().as_bytes())
+          .await
+          .map_err(Into::into)
+      }
+      Err(err) => Err(err),
+    },
+    Ok((req, _)) if req == rpc::REQUEST_LINE && !shared.logged_in(&connection) => {
+      Err(QvpnError::Unauthenticated("log in first".into()))
+    }
+    Ok((req, rest)) if req == rpc::REQUEST_LINE => {
+      return shared.rpc.clone().serve(send, recv, rest).await;
+    }
+    Ok((req, _)) if req.starts_with(bench::REQUEST_PREFIX) && !shared.logged_in(&connection) => {
+      Err(QvpnError::Unauthenticated("log in first".into()))
+    }
+    Ok((req, _)) if req.starts_with(bench::REQUEST_PREFIX) => {
+      serve_bench(&shared, &connection, &req, &mut send).await
+    }
+    Ok((req, _)) if req.starts_with(udp::REQUEST_PREFIX) && !shared.logged_in(&connection) => {
+      Err(QvpnError::Unauthenticated("log in first".into()))
+    }
+    Ok((req, _)) if req.starts_with(udp::REQUEST_PREFIX) => match udp::connect_target(&req) {
+      _ if shared.mode != Mode::ConnectProxy => {
+        Err(QvpnError::Unsupported("proxy disabled".into()))
+      }
+      Ok(target) => shared.udp.relay(&connection, target, &mut send, recv).await,
+      Err(err) => Err(err),
+    },
+    Ok((req, _)) if req.starts_with(forward::LISTEN_PREFIX) && !shared.logged_in(&connection) => {
+      Err(QvpnError::Unauthenticated("log in first".into()))
+    }
+    Ok((req, _)) if req.starts_with(forward::LISTEN_PREFIX) => {
+      let user = shared.logins.user(&connection);
+      let forwards = &shared.forwards;
+      forward::serve_listen(
+        forwards,
+        user.as_deref(),
+        &req,
+        &connection,
+        &mut send,
+        recv,
+      )
+      .await
+    }
+    Ok((req, _)) if req.starts_with(expose::REQUEST_PREFIX) && !shared.logged_in(&connection) => {
+      Err(QvpnError::Unauthenticated("log in first".into()))
+    }
+    Ok((req, _)) if req.starts_with(expose::REQUEST_PREFIX) => match &shared.exposed {
+      Some(registry) => expose::serve_register(registry, &req, &connection, &mut send, recv).await,
+      None => Err(QvpnError::Unsupported("exposing services disabled".into())),
+    },
+    Ok((req, rest)) => match proxy::connect_target(&req) {
+      Some(_) if !shared.logged_in(&connection) => {
+        Err(QvpnError::Unauthenticated("log in first".into()))
+      }
+      Some(target) => match dial(&shared, &connection, target).await {
+        Ok(tcp) => {
+          send.write_all(proxy::CONNECT_OK).await?;
+          let (sent, received) = proxy::splice(tcp, &rest, send, recv).await?;
+          shared.metrics.sent(sent);
+          shared.metrics.received(received);
+          return Ok(());
+        }
+        Err(err) => Err(err),
+      },
+      None if req.starts_with(b"PUT ") => {
+        receive_file(&shared, &connection, &req, rest, recv, &mut send).await
+      }
+      None if req.starts_with(b"SYNC ") => {
+        compare_manifest(&shared, &connection, &req, rest, recv, &mut send).await
+      }
+      None => {
+        // The client finishes its side after the request headers.
+        let mut headers = rest;
+        headers.extend(recv.read_to_end(http::MAX_HEAD).await?);
+        serve_file(&shared, &connection, &req, &headers, &mut send).await
+      }
+    },
+    Err(err) => Err(err),
+  };
+  match result {
+    Ok(()) => {
+      // Gracefully terminate the stream
+      send.finish()?;
+      Ok(())
+    }
+    Err(err) => {
+      match error_response(&err) {
+        Some(head) => {
+          send.write_all(head.encode().as_bytes()).await?;
+          send.finish()?;
+        }
+        None => {
+          let _ = send.reset(INTERNAL_ERROR.into());
+        }
+      }
+      Err(err)
+    }
+  }
+}
+
+/// Answers control messages from a client speaking protocol `version` and
+/// sends it those queued in [`Shared::controls`], until either side ends
+/// the stream.
+async fn serve_control(
+  shared: Shared,
+  connection: quinn::Connection,
+  version: u32,
+  mut control: Control,
+) -> Result<()> {
+  let mut queued = shared.controls.register(&connection);
+  // The tunnel MTU last advertised, once leased an address.
+  let mut mtu = None;
+  let mut mtu_check = tokio::time::interval(MTU_CHECK_INTERVAL);
+  mtu_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+  let result = async {
+    loop {
+      tokio::select! {
+        message = control.recv() => match message? {
+          Some(control::Message::LeaseRequest) => {
+            let lease = match &shared.tunnel {
+              _ if !shared.logged_in(&connection) => {
+                Err(QvpnError::Unauthenticated("log in first".into()))
+              }
+              Some(tunnel) if tunnel.tun.is_tap() && version < version::TAP => {
+                Err(QvpnError::Incompatible(
+                  "the tunnel carries Ethernet frames; upgrade the client".into(),
+                ))
+              }
+              Some(tunnel) => {
+                let user = shared.logins.user(&connection);
+                lease_address(tunnel, &shared.accounting, &connection, user).await
+              }
+              None => Err(QvpnError::Unsupported("tunnel disabled".into())),
+            };
+            match lease {
+              Ok(lease) => {
+                control.send(&control::Message::Mtu { mtu: lease.mtu }).await?;
+                control
+                  .send(&control::Message::Routes {
+                    routes: lease.routes,
+                  })
+                  .await?;
+                control
+                  .send(&control::Message::Dns {
+                    servers: lease.dns,
+                    search: lease.search,
+                  })
+                  .await?;
+                if lease.tap {
+                  control.send(&control::Message::Ethernet).await?;
+                }
+                control
+                  .send(&control::Message::Assign {
+                    address: lease.address,
+                    netmask: lease.netmask,
+                    gateway: lease.gateway,
+                  })
+                  .await?;
+                if version >= version::PATH_MTU {
+                  mtu = Some(lease.mtu);
+                }
+              }
+              Err(err) => {
+                disconnect(&mut control, &connection, &err.to_string()).await?;
+                return Err(err);
+              }
+            }
+          }
+          Some(control::Message::BondRequest) => {
+            let token = match &shared.tunnel {
+              Some(tunnel) => tunnel.router.bond_token(&connection).await?,
+              None => None,
+            };
+            match token {
+              Some(token) => control.send(&control::Message::BondToken { token }).await?,
+              None => {
+                let reason = "no tunnel address to bond";
+                disconnect(&mut control, &connection, reason).await?;
+                return Err(QvpnError::BadRequest(reason.into()));
+              }
+            }
+          }
+          Some(control::Message::Join { token }) => {
+            let joined = match &shared.tunnel {
+              Some(tunnel) => match tunnel.router.bond_owner(token).await {
+                // The uplink carries the owner's packets, so rules scoped to
+                // the owner's user must apply to it too.
+                Some(owner) if shared.logged_in(&owner) => {
+                  if let Some(user) = shared.logins.user(&owner) {
+                    shared.logins.insert(&connection, &user);
+                  }
+                  tunnel.router.join(token, connection.clone()).await
+                }
+                _ => None,
+              },
+              None => None,
+            };
+            match joined {
+              Some(IpAddr::V4(address)) => {
+                info!(%address, "joined bonded uplink");
+                control.send(&control::Message::Joined { address }).await?;
+              }
+              _ => {
+                let reason = "unknown bond token";
+                disconnect(&mut control, &connection, reason).await?;
+                return Err(QvpnError::Unauthenticated(reason.into()));
+              }
+            }
+          }
+          Some(control::Message::Authenticate { user, password }) => {
+            let login = match check_password(&shared, &user, &password).await {
+              true => Ok(user),
+              false => Err(format!("wrong password for {}", user)),
+            };
+            finish_login(&shared, &connection, &mut control, login).await?;
+          }
+          Some(control::Message::Token { token }) => {
+            let login = match &shared.verifier {
+              Some(verifier) => verifier.verify(&token).await.map_err(|err| match err {
+                QvpnError::Unauthenticated(reason) => reason,
+                err => err.to_string(),
+              }),
+              None => Err("token logins aren't configured".into()),
+            };
+            finish_login(&shared, &connection, &mut control, login).await?;
+          }
+          Some(control::Message::Advertise { networks }) => {
+            let advertised = match &shared.tunnel {
+              _ if !shared.logged_in(&connection) => {
+                Err(QvpnError::Unauthenticated("log in first".into()))
+              }
+              Some(_) if version < version::SITES => Err(QvpnError::Incompatible(
+                "advertising networks needs a newer client".into(),
+              )),
+              Some(tunnel) => match tunnel
+                .sites
+                .check(&networks, shared.logins.user(&connection).as_deref())
+              {
+                Ok(()) => tunnel.advertise(&shared.controls, &connection, networks).await,
+                Err(err) => Err(err),
+              },
+              None => Err(QvpnError::Unsupported("tunnel disabled".into())),
+            };
+            if let Err(err) = advertised {
+              disconnect(&mut control, &connection, &err.to_string()).await?;
+              return Err(err);
+            }
+          }
+          Some(control::Message::Keepalive) => control.send(&control::Message::Keepalive).await?,
+          Some(message) => debug!(?message, "ignoring control message"),
+          None => return control.finish().await,
+        },
+        Some(message) = queued.recv() => match message {
+          control::Message::Disconnect { reason } => {
+            return disconnect(&mut control, &connection, &reason).await;
+          }
+          message => control.send(&message).await?,
+        },
+        _ = mtu_check.tick(), if mtu.is_some() => {
+          if let Some(tunnel) = &shared.tunnel {
+            let path_mtu = tunnel.tun.mtu_for(&connection);
+            if mtu != Some(path_mtu) {
+              debug!(mtu = path_mtu, "tunnel MTU changed with the path");
+              control.send(&control::Message::Mtu { mtu: path_mtu }).await?;
+              mtu = Some(path_mtu);
+            }
+          }
+        }
+      }
+    }
+  }
+  .await;
+  shared.controls.unregister(&connection);
+  shared.logins.remove(&connection);
+  result
+}
+
+/// Whether `authenticator` accepts `password` for `user`. Every password
+/// is accepted when logins aren't required.
+async fn check_password(shared: &Shared, user: &str, password: &str) -> bool {
+  if !auth::valid_user(user) {
+    warn!(user = %user.escape_debug(), "refusing login with an invalid user name");
+    return false;
+  }
+  match &shared.authenticator {
+    Some(authenticator) => match authenticator.authenticate(user, password).await {
+      Ok(accepted) => accepted,
+      Err(err) => {
+        error!(%user, "couldn't check login: {}", err);
+        false
+      }
+    },
+    None => shared.verifier.is_none(),
+  }
+}
+
+/// Answers a login on `control`: records `login`, the user logged in, or
+/// tells the client it failed and closes the connection, returning why as
+/// [`QvpnError::Unauthenticated`].
+async fn finish_login(
+  shared: &Shared,
+  connection: &quinn::Connection,
+  control: &mut Control,
+  login: std::result::Result<String, String>,
+) -> Result<()> {
+  let reason = match login {
+    Ok(user) => {
+      info!(%user, "logged in");
+      shared.logins.insert(connection, &user);
+      shared.accounting.logged_in(connection, &user);
+      return control.send(&control::Message::Authenticated).await;
+    }
+    Err(reason) => reason,
+  };
+  let told = "login rejected";
+  control
+    .send(&control::Message::AuthFailed {
+      reason: told.into(),
+    })
+    .await?;
+  let _ = tokio::time::timeout(DISCONNECT_GRACE, control.finish()).await;
+  connection.close(CLOSE_UNAUTHENTICATED.into(), told.as_bytes());
+  Err(QvpnError::Unauthenticated(reason))
+}
+
+/// How often the path of each client leased a tunnel address is checked
+/// for a new tunnel MTU to advertise.
+const MTU_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a client gets to receive a [`control::Message::Disconnect`]
+/// before its connection is closed.
+const DISCONNECT_GRACE: Duration = Duration::from_secs(2);
+
+/// Tells the client why it is being disconnected, then closes its
+/// connection with [`CLOSE_DISCONNECTED`].
+async fn disconnect(
+  control: &mut Control,
+  connection: &quinn::Connection,
+  reason: &str,
+) -> Result<()> {
+  info!(%reason, "disconnecting client");
+  control
+    .send(&control::Message::Disconnect {
+      reason: reason.into(),
+    })
+    .await?;
+  // Finishing resolves once the client acknowledged everything sent.
+  let _ = tokio::time::timeout(DISCONNECT_GRACE, control.finish()).await;
+  connection.close(CLOSE_DISCONNECTED.into(), reason.as_bytes());
+  Ok(())
+}
+
+/// Answers the HTTP/3 requests made on `connection`, each on a task of
+/// its own, until the client closes it.
+async fn serve_h3(shared: Shared, connection: quinn::Connection) -> Result<()> {
+  let mut requests = h3::Connection::accept(connection.clone()).await?;
+  while let Some(incoming) = requests.next().await? {
+    let open = shared.metrics.open_stream();
+    shared.stats.stream_opened(&connection);
+    let (shared, connection) = (shared.clone(), connection.clone());
+    tokio::spawn(
+      async move {
+        let _open = open;
+        let (req, stream) = match incoming.request().await {
+          Ok(request) => request,
+          Err(err) => {
+            warn!("reading request failed: {}", err);
+            return;
+          }
+        };
+        let span = info_span!("stream", id = %stream.id());
+        if let Err(err) = handle_h3_request(shared, connection, req, stream)
+          .instrument(span)
+          .await
+        {
+          warn!("request failed: {}", err);
+        }
+      }
+      .in_current_span(),
+    );
+  }
+  info!("connection closed");
+  Ok(())
+}
+
+/// Answers a `GET` or `HEAD` request on an HTTP/3 request stream. Both are
+/// safe to answer from early data.
+async fn handle_h3_request(
+  shared: Shared,
+  connection: quinn::Connection,
+  req: h3::Request,
+  mut stream: h3::Stream,
+) -> Result<()> {
+  shared
+    .metrics
+    .request(&req.method, req.path.split('?').next().unwrap_or_default());
+  let result = match req.method.as_str() {
+    "GET" | "HEAD" => match req.decoded_path() {
+      Ok(path) => {
+        let mut response = Responder::H3 {
+          stream: &mut stream,
+          body: req.method == "GET",
+        };
+        let (query, headers) = (req.query(), &req.headers);
+        send_file(&shared, &connection, &path, query, headers, &mut response).await
+      }
+      Err(err) => Err(err),
+    },
+    method => Err(QvpnError::Unsupported(format!(
+      "{} requests over HTTP/3",
+      method
+    ))),
+  };
+  match result {
+    Ok(()) => {
+      stream.finish().await?;
+      Ok(())
+    }
+    Err(err) => {
+      match error_response(&err) {
+        Some(head) => {
+          stream.send_head(&head).await?;
+          stream.finish().await?;
+        }
+        None => stream.reset(),
+      }
+      Err(err)
+    }
+  }
+}
+
+/// Writes the body of a `PUT /path` request to the path below the root.
+///
+/// The body follows the headers and must be exactly `Content-Length` bytes
+/// long. It is written to a temporary file first, which replaces the target
+/// once complete, so an interrupted upload never leaves a truncated file.
+async fn receive_file(
+  shared: &Shared,
+  connection: &quinn::Connection,
+  req: &[u8],
+  rest: Vec<u8>,
+  recv: quinn::RecvStream,
+  send: &mut quinn::SendStream,
+) -> Result<()> {
+  let (mount, real_path) = resolve_upload(shared, connection, req, "PUT").await?;
+  let name = match real_path.file_name() {
+    Some(name) if real_path != mount.dir() => name.to_string_lossy().into_owned(),
+    _ => return Err(QvpnError::BadRequest("missing file name".into())),
+  };
+  let (len, mut body) = request_body(rest, recv).await?;
+  if tokio::fs::metadata(&real_path)
+    .await
+    .is_ok_and(|meta| meta.is_dir())
+  {
+    return Err(QvpnError::BadRequest("cannot replace a directory".into()));
+  }
+  if let Some(parent) = real_path.parent() {
+    tokio::fs::create_dir_all(parent).await?;
+  }
+  let part_path = real_path.with_file_name(format!(".{}.part", name));
+  let mut file = tokio::fs::File::create(&part_path).await?;
+  let received = match tokio::io::copy(&mut body, &mut file).await {
+    Ok(received) => received,
+    Err(err) => {
+      let _ = tokio::fs::remove_file(&part_path).await;
+      return Err(err.into());
+    }
+  };
+  shared.metrics.received(received);
+  file.sync_all().await?;
+  if received != len {
+    let _ = tokio::fs::remove_file(&part_path).await;
+    return Err(QvpnError::BadRequest(format!(
+      "expected {} bytes but received {}",
+      len, received
+    )));
+  }
+  tokio::fs::rename(&part_path, &real_path).await?;
+  info!(path = %real_path.display(), bytes = received, "stored upload");
+  let head = ResponseHead::new(201, "Created").header("Content-Length", 0);
+  send.write_all(head.encode().as_bytes()).await?;
+  Ok(())
+}
+
+/// Answers a `SYNC /dir` request with the paths of its manifest that are
+/// missing or changed below `dir`, as a JSON array.
+async fn compare_manifest(
+  shared: &Shared,
+  connection: &quinn::Connection,
+  req: &[u8],
+  rest: Vec<u8>,
+  recv: quinn::RecvStream,
+  send: &mut quinn::SendStream,
+) -> Result<()> {
+  let (mount, dir) = resolve_upload(shared, connection, req, "SYNC").await?;
+  let (len, mut body) = request_body(rest, recv).await?;
+  if len > sync::MAX_MANIFEST {
+    return Err(QvpnError::BadRequest("manifest too long".into()));
+  }
+  let mut manifest = Vec::new();
+  body.read_to_end(&mut manifest).await?;
+  let manifest: Vec<sync::FileEntry> = serde_json::from_slice(&manifest)
+    .map_err(|err| QvpnError::BadRequest(format!("malformed manifest: {}", err)))?;
+  let confine = Some(mount).filter(|_| !shared.follow_symlinks);
+  let missing = sync::missing(&dir, &manifest, confine).await?;
+  info!(
+    dir = %dir.display(),
+    needed = missing.len(),
+    files = manifest.len(),
+    "compared sync manifest"
+  );
+  let body = serde_json::to_string(&missing).expect("paths serialize");
+  let head = ResponseHead::new(200, "OK")
+    .header("Content-Type", listing::Format::Json.mime())
+    .header("Content-Length", body.len());
+  send.write_all(head.encode().as_bytes()).await?;
+  send.write_all(body.as_bytes()).await?;
+  Ok(())
+}
+
+/// Answers a benchmark request with synthetic data or counters.
+async fn serve_bench(
+  shared: &Shared,
+  connection: &quinn::Connection,
+  req: &[u8],
+  send: &mut quinn::SendStream,
+) -> Result<()> {
+  if !shared.bench {
+    return Err(QvpnError::Unsupported("benchmarks disabled".into()));
+  }
+  let request = bench::Request::parse(req)?;
+  info!(?request, "benchmark");
+  let sent = bench::serve(request, connection, send, shared.chunk_size).await?;
+  shared.metrics.sent(sent);
+  Ok(())
+}
+
+/// Reads the headers that follow a request line and returns the
+/// `Content-Length` and a reader for exactly that many body bytes.
+async fn request_body(
+  rest: Vec<u8>,
+  recv: quinn::RecvStream,
+) -> Result<(u64, impl tokio::io::AsyncRead + Unpin)> {
+  let mut body = std::io::Cursor::new(rest).chain(recv);
+  let (headers, leftover) = http::read_until(&mut body, b"\r\n\r\n").await?;
+  let headers = parse_headers(&headers)?;
+  let len: u64 = http::find_header(&headers, "Content-Length")
+    .and_then(|x| x.parse().ok())
+    .ok_or_else(|| QvpnError::BadRequest("missing Content-Length".into()))?;
+  Ok((len, std::io::Cursor::new(leftover).chain(body).take(len)))
+}
+
+/// Connects to the target of a `CONNECT` request, which outside
+/// [`Mode::ConnectProxy`] a forwarding rule must allow.
+async fn dial(
+  shared: &Shared,
+  connection: &quinn::Connection,
+  target: Result<&str>,
+) -> Result<TcpStream> {
+  if shared.mode != Mode::ConnectProxy && shared.forwards.is_empty() {
+    return Err(QvpnError::Unsupported("proxy disabled".into()));
+  }
+  let target = target?;
+  let user = shared.logins.user(connection);
+  if shared.mode != Mode::ConnectProxy && !shared.forwards.allows(target, user.as_deref()) {
+    return Err(QvpnError::Unauthenticated(format!(
+      "forwarding to {} not allowed",
+      target
+    )));
+  }
+  info!(%target, "connecting");
+  TcpStream::connect(target)
+    .await
+    .map_err(|err| QvpnError::Dial(target.to_string(), err))
+}
+
+/// Response sent for errors that occur before any of the body has been
+/// written. Other errors reset the stream instead.
+fn error_response(err: &QvpnError) -> Option<ResponseHead> {
+  let (status, reason) = match err {
+    QvpnError::BadRequest(_) => (400, "Bad Request"),
+    QvpnError::NotFound(_) => (404, "Not Found"),
+    QvpnError::RangeNotSatisfiable(len) => {
+      return Some(
+        ResponseHead::new(416, "Range Not Satisfiable")
+          .header("Content-Range", format!("bytes */{}", len))
+          .header("Content-Length", 0),
+      )
+    }
+    QvpnError::Unsupported(_) => (501, "Not Implemented"),
+    QvpnError::Unauthenticated(_) => (403, "Forbidden"),
+    QvpnError::Conflict(_) => (409, "Conflict"),
+    QvpnError::Dial(..) => (502, "Bad Gateway"),
+    _ => return None,
+  };
+  Some(ResponseHead::new(status, reason).header("Content-Length", 0))
+}
+
+/// Where a response goes, and how it is framed there.
+enum Responder<'a> {
+  /// An HTTP/1.1 style head, then the body as is.
+  Qvpn(&'a mut quinn::SendStream),
+  /// HTTP/3 HEADERS and DATA frames, without the DATA for `HEAD` requests.
+  H3 {
+    stream: &'a mut h3::Stream,
+    body: bool,
+  },
+}
+
+impl Responder<'_> {
+  /// Writes `head`, returning whether the body should follow.
+  async fn write_head(&mut self, head: &ResponseHead) -> Result<bool> {
+    match self {
+      Responder::Qvpn(send) => {
+        send.write_all(head.encode().as_bytes()).await?;
+        Ok(true)
+      }
+      Responder::H3 { stream, body } => {
+        stream.send_head(head).await?;
+        Ok(*body)
+      }
+    }
+  }
+
+  /// Writes the next part of the body. The data is queued by reference,
+  /// without copying it into the stream's own buffers.
+  async fn write_data(&mut self, data: Bytes) -> Result<()> {
+    if data.is_empty() {
+      return Ok(());
+    }
+    match self {
+      Responder::Qvpn(send) => send.write_chunk(data).await?,
+      Responder::H3 { stream, .. } => stream.send_data(data).await?,
+    }
+    Ok(())
+  }
+}
+
+/// Answers a `GET /path` request with the file, as [`send_file`] does.
+async fn serve_file(
+  shared: &Shared,
+  connection: &quinn::Connection,
+  req: &[u8],
+  headers: &[u8],
+  response_stream: &mut quinn::SendStream,
+) -> Result<()> {
+  let path = request_path(req, "GET")?;
+  let headers = parse_headers(headers)?;
+  let query = request_query(req);
+  let mut response = Responder::Qvpn(response_stream);
+  send_file(shared, connection, &path, query, &headers, &mut response).await
+}
+
+/// Streams the file at `path` below the root, or the part selected by a
+/// `Range` header, to `response`, reading `chunk_size` bytes at a
+/// time. Each chunk waits for stream flow control before the next one is
+/// read. Files small enough for the in-memory cache are read whole and sent
+/// from there. Directories are answered with their index file if they have one,
+/// or else a listing, which includes the mounts below them. With access
+/// tokens, the `query` or `headers` must present one covering `path`.
+/// Clients asking for a digest of the file get it in a header. Requests
+/// whose `If-None-Match` or `If-Modified-Since` header shows they have the
+/// current version are answered with `304 Not Modified`.
+async fn send_file(
+  shared: &Shared,
+  connection: &quinn::Connection,
+  path: &str,
+  query: Option<&str>,
+  headers: &[(String, String)],
+  response: &mut Responder<'_>,
+) -> Result<()> {
+  if let Some(key) = &shared.access {
+    key.authorize(path, query, headers)?;
+  }
+  let mut real_path = match resolve(shared, connection, path).await? {
+    Resolved::Path { real_path, .. } => real_path,
+    Resolved::Mounts(_) => return send_listing(shared, path, None, headers, response).await,
+  };
+  if tokio::fs::metadata(&real_path)
+    .await
+    .is_ok_and(|meta| meta.is_dir())
+  {
+    let index = shared.index.as_deref().map(|x| real_path.join(x));
+    match index {
+      Some(index) if tokio::fs::metadata(&index).await.is_ok_and(|x| x.is_file()) => {
+        real_path = index;
+      }
+      _ => {
+        let dir = Some(real_path.as_path());
+        return send_listing(shared, path, dir, headers, response).await;
+      }
+    }
+  }
+  let mut file = match tokio::fs::File::open(&real_path).await {
+    Ok(file) => file,
+    Err(err) => {
+      debug!("{}: {}", real_path.display(), err);
+      return Err(QvpnError::NotFound(real_path));
+    }
+  };
+  let meta = file.metadata().await?;
+  let len = meta.len();
+  let modified = meta.modified().ok();
+  let etag = http::etag(len, modified);
+  if http::not_modified(headers, &etag, modified) {
+    let mut head = ResponseHead::new(304, "Not Modified").header("ETag", &etag);
+    if let Some(modified) = modified {
+      head = head.header("Last-Modified", httpdate::fmt_http_date(modified));
+    }
+    response.write_head(&head).await?;
+    info!("not modified");
+    return Ok(());
+  }
+  let range = match http::find_header(headers, "Range").and_then(|x| http::parse_range(x, len)) {
+    Some(Some(range)) => Some(range),
+    Some(None) => return Err(QvpnError::RangeNotSatisfiable(len)),
+    None => None,
+  };
+  let (mut head, start, count) = match range {
+    Some((start, end)) => (
+      ResponseHead::new(206, "Partial Content")
+        .header("Content-Range", format!("bytes {}-{}/{}", start, end, len)),
+      start,
+      end - start + 1,
+    ),
+    None => (ResponseHead::new(200, "OK"), 0, len),
+  };
+  let mime = mime_guess::from_path(&real_path).first_or_octet_stream();
+  let encoding = http::find_header(headers, "Accept-Encoding")
+    .and_then(Encoding::negotiate)
+    .filter(|_| shared.compression && range.is_none() && len >= compress::MIN_SIZE)
+    .filter(|_| compress::compressible(mime.essence_str()));
+  head = head
+    .header("Content-Type", mime)
+    .header("Accept-Ranges", "bytes");
+  head = match encoding {
+    Some(encoding) => head
+      .header("Content-Encoding", encoding)
+      .header("Vary", "Accept-Encoding"),
+    None => head.header("Content-Length", count),
+  };
+  head = head.header("ETag", etag);
+  if let Some(modified) = modified {
+    head = head.header("Last-Modified", httpdate::fmt_http_date(modified));
+  }
+  if http::find_header(headers, integrity::WANT_HEADER).is_some() {
+    let digest = shared.digests.get(&real_path).await?;
+    head = head.header(integrity::HEADER, integrity::header_value(&digest));
+  }
+  if !response.write_head(&head).await? {
+    return Ok(());
+  }
+  let cached = match &shared.files {
+    Some(files) if files.admits(len) => match files.get(&real_path, len, modified) {
+      Some(data) => {
+        shared.metrics.file_cache_hit();
+        Some(data)
+      }
+      None => {
+        let mut data = Vec::with_capacity(len as usize);
+        (&mut file).take(len).read_to_end(&mut data).await?;
+        if data.len() as u64 != len {
+          return Err(QvpnError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file shrank while being read",
+          )));
+        }
+        let data = Bytes::from(data);
+        files.insert(&real_path, modified, data.clone());
+        shared.metrics.file_cache_miss(files.size());
+        Some(data)
+      }
+    },
+    _ => None,
+  };
+  let sent = match (cached, encoding) {
+    (Some(data), None) => {
+      // Queued by reference, so the cached copy is never copied again.
+      response
+        .write_data(data.slice(start as usize..(start + count) as usize))
+        .await?;
+      count
+    }
+    (Some(data), Some(encoding)) => {
+      let mut reader = &data[start as usize..(start + count) as usize];
+      send_compressed(encoding, &mut reader, response).await?
+    }
+    (None, None) => {
+      file.seek(SeekFrom::Start(start)).await?;
+      send_chunks(file.take(count), shared.chunk_size, response).await?
+    }
+    (None, Some(encoding)) => {
+      file.seek(SeekFrom::Start(start)).await?;
+      let mut reader = BufReader::with_capacity(shared.chunk_size, file).take(count);
+      send_compressed(encoding, &mut reader, response).await?
+    }
+  };
+  shared.metrics.sent(sent);
+  info!(
+    bytes = sent,
+    encoding = encoding.map_or("identity", Encoding::name),
+    "complete"
+  );
+  Ok(())
+}
+
+/// Streams `reader` to `response` in chunks of up to `chunk_size`
+/// bytes, returning the bytes sent.
+///
+/// Chunks are read straight into a shared buffer and handed to the stream
+/// by reference, without copying them into the stream's own buffers. Once
+/// the peer acknowledged a chunk and the stream released it, its space is
+/// reclaimed for the next reads, so a transfer reuses a few buffers instead
+/// of allocating or copying per write.
+async fn send_chunks(
+  mut reader: impl AsyncRead + Unpin,
+  chunk_size: usize,
+  response: &mut Responder<'_>,
+) -> Result<u64> {
+  let mut buf = BytesMut::with_capacity(chunk_size * SEND_BUFFER_CHUNKS);
+  let mut sent = 0;
+  loop {
+    buf.reserve(chunk_size);
+    let len = reader.read_buf(&mut (&mut buf).limit(chunk_size)).await?;
+    if len == 0 {
+      return Ok(sent);
+    }
+    response.write_data(buf.split().freeze()).await?;
+    sent += len as u64;
+  }
+}
+
+/// Streams `reader` to `response` compressed with `encoding`,
+/// returning the bytes sent.
+async fn send_compressed(
+  encoding: Encoding,
+  reader: &mut (impl AsyncBufReadExt + Unpin),
+  response: &mut Responder<'_>,
+) -> Result<u64> {
+  let mut encoder = Encoder::new(encoding);
+  let mut sent = 0;
+  loop {
+    let chunk = reader.fill_buf().await?;
+    if chunk.is_empty() {
+      break;
+    }
+    let len = chunk.len();
+    let data = encoder.update(chunk);
+    reader.consume(len);
+    sent += data.len() as u64;
+    response.write_data(data.into()).await?;
+  }
+  let data = encoder.finish();
+  sent += data.len() as u64;
+  response.write_data(data.into()).await?;
+  Ok(sent)
+}
+
+/// Answers a request for the directory at request path `path` with a
+/// listing of `dir`, if it's in a mount, and the mounts below it.
+async fn send_listing(
+  shared: &Shared,
+  path: &str,
+  dir: Option<&Path>,
+  headers: &[(String, String)],
+  response: &mut Responder<'_>,
+) -> Result<()> {
+  let format = listing::Format::from_accept(http::find_header(headers, "Accept"));
+  let mut entries = match dir {
+    Some(dir) => listing::read_dir(dir).await?,
+    None => vec![],
+  };
+  for name in shared.mounts.children(path) {
+    if !entries.iter().any(|x| x.name == name) {
+      entries.push(listing::Entry {
+        name,
+        dir: true,
+        size: 0,
+        modified: None,
+      });
+    }
+  }
+  entries.sort_by(|a, b| b.dir.cmp(&a.dir).then_with(|| a.name.cmp(&b.name)));
+  let body = listing::render(format, path, &entries);
+  let head = ResponseHead::new(200, "OK")
+    .header("Content-Type", format.mime())
+    .header("Content-Length", body.len());
+  let len = body.len() as u64;
+  if response.write_head(&head).await? {
+    response.write_data(body.into()).await?;
+    shared.metrics.sent(len);
+  }
+  info!(entries = entries.len(), "listed directory");
+  Ok(())
+}
+
+/// Maps the request `path` through the mounts, refusing the client on
+/// `connection` if its mount doesn't allow it, and paths that symlinks
+/// lead out of the mount unless the server follows them.
+async fn resolve<'a>(
+  shared: &'a Shared,
+  connection: &quinn::Connection,
+  path: &str,
+) -> Result<Resolved<'a>> {
+  let resolved = shared.mounts.resolve(path)?;
+  if let Resolved::Path { mount, real_path } = &resolved {
+    let identity = ClientIdentity::from_connection(connection);
+    if !mount.allows(identity.as_ref().map(|x| x.fingerprint.as_str())) {
+      return Err(QvpnError::Unauthenticated(format!(
+        "{} is not open to this client",
+        mount.prefix()
+      )));
+    }
+    if !shared.follow_symlinks && !mount.contains(real_path).await {
+      warn!(%path, "refusing a path that a symlink leads out of its mount");
+      return Err(QvpnError::NotFound(real_path.clone()));
+    }
+  }
+  Ok(resolved)
+}
+
+/// Maps the path of a `METHOD /path\r\n` upload request into a mount that
+/// takes uploads, returning the mount and the path on disk.
+async fn resolve_upload<'a>(
+  shared: &'a Shared,
+  connection: &quinn::Connection,
+  req: &[u8],
+  method: &str,
+) -> Result<(&'a Mount, PathBuf)> {
+  if !shared.uploads {
+    return Err(QvpnError::Unsupported("uploads disabled".into()));
+  }
+  match resolve(shared, connection, &request_path(req, method)?).await? {
+    Resolved::Path { mount, .. } if mount.is_readonly() => Err(QvpnError::Unsupported(format!(
+      "uploads to {} disabled",
+      mount.prefix()
+    ))),
+    Resolved::Path { mount, real_path } => Ok((mount, real_path)),
+    Resolved::Mounts(_) => Err(QvpnError::BadRequest("path is not in a mount".into())),
+  }
+}
+
+/// The query of a `METHOD /path?query\r\n` request, if it has one.
+fn request_query(req: &[u8]) -> Option<&str> {
+  let line = req.split(|&c| c == b'\r').next()?;
+  let target = line.split(|&c| c == b' ').nth(1)?;
+  let at = target.iter().position(|&c| c == b'?')?;
+  str::from_utf8(&target[at + 1..]).ok()
+}
+
+/// The headers following the request line of a datagram request.
+fn request_headers(req: &[u8]) -> Result<Vec<(String, String)>> {
+  let at = req
+    .windows(2)
+    .position(|x| x == b"\r\n")
+    .unwrap_or(req.len());
+  parse_headers(req.get(at + 2..).unwrap_or_default())
+}
+
+/// Parses `Name: value\r\n` lines.
+fn parse_headers(headers: &[u8]) -> Result<Vec<(String, String)>> {
+  str::from_utf8(headers)
+    .map_err(|_| QvpnError::BadRequest("headers are not valid utf-8".into()))
+    .and_then(|headers| http::parse_headers(headers.split("\r\n")).map_err(QvpnError::BadRequest))
+}
+
+/// Extracts the percent-decoded path, without any query, from the
+/// `METHOD /path\r\n` or `METHOD /path HTTP/3\r\n` line that starts a
+/// request, which headers may follow. Spaces in the path must be
+/// percent-encoded.
+fn request_path(req: &[u8], method: &str) -> Result<String> {
+  let mut escaped = String::new();
+  for &x in req {
+    escaped.extend(ascii::escape_default(x).map(char::from));
+  }
+  debug!(request = %escaped, "content");
+  // Execute the request
+  let x = req
+    .strip_prefix(method.as_bytes())
+    .and_then(|x| x.strip_prefix(b" "))
+    .ok_or_else(|| QvpnError::BadRequest(format!("missing {}", method)))?;
+  let x = x
+    .windows(2)
+    .position(|x| x == b"\r\n")
+    .map(|at| &x[..at])
+    .ok_or_else(|| QvpnError::BadRequest("missing \\r\\n".into()))?;
+  let (target, version) = match x.iter().position(|&c| c == b' ') {
+    Some(at) => (&x[..at], Some(&x[at + 1..])),
+    None => (x, None),
+  };
+  if version.is_some_and(|x| !x.starts_with(b"HTTP/") || x.contains(&b' ')) {
+    return Err(QvpnError::BadRequest(
+      "path contains an unencoded space".into(),
+    ));
+  }
+  let end = target
+    .iter()
+    .position(|&c| c == b'?')
+    .unwrap_or(target.len());
+  http::decode_path(&target[..end])
+}
+
+#[cfg(test)]
+mod tests {
+  use bytes::Buf;
+  use url::Url;
+
+  use super::*;
+  use crate::Client;
+
+  /// The request line the client sends for `url`, with headers after it.
+  fn request(url: &str, headers: &str) -> Vec<u8> {
+    let url = Url::parse(url).unwrap();
+    format!("GET {} HTTP/3\r\n{}\r\n", url.path(), headers).into_bytes()
+  }
+
+  #[test]
+  fn request_path_round_trips_urls() {
+    for (url, path) in [
+      ("https://example.com/a%20b/c.txt", "/a b/c.txt"),
+      ("https://example.com/a b/c.txt", "/a b/c.txt"),
+      (
+        "https://example.com/%C3%BCber/na%C3%AFve.txt",
+        "/über/naïve.txt",
+      ),
+      ("https://example.com/über/日本.txt", "/über/日本.txt"),
+      ("https://example.com/100%25.txt", "/100%.txt"),
+      ("https://example.com/q.txt?token=x", "/q.txt"),
+    ] {
+      assert_eq!(request_path(&request(url, ""), "GET").unwrap(), path);
+    }
+  }
+
+  #[test]
+  fn request_path_ignores_headers() {
+    let req = request(
+      "https://example.com/a%20b.txt",
+      "Accept: application/json\r\nAuthorization: Bearer x y\r\nRange: bytes=0-9\r\n",
+    );
+    assert_eq!(request_path(&req, "GET").unwrap(), "/a b.txt");
+  }
+
+  #[test]
+  fn request_path_without_version() {
+    assert_eq!(request_path(b"GET /a%20b\r\n", "GET").unwrap(), "/a b");
+  }
+
+  #[test]
+  fn request_path_rejects_bad_paths() {
+    for req in [
+      &b"GET /a b HTTP/3\r\n\r\n"[..],
+      b"GET /a%00b HTTP/3\r\n\r\n",
+      b"GET /a%ffb HTTP/3\r\n\r\n",
+      b"GET /a HTTP/3",
+      b"PUT /a HTTP/3\r\n\r\n",
+    ] {
+      assert!(request_path(req, "GET").is_err(), "{:?}", req);
+    }
+  }
+
+  /// A server on a loopback port, configured by `configure`.
+  fn server(configure: impl FnOnce(ServerBuilder) -> ServerBuilder) -> Server {
+    let builder = Server::builder(std::env::temp_dir()).listen(([127, 0, 0, 1], 0).into());
+    configure(builder).build().unwrap()
+  }
+
+  /// The first Initial datagram of a client's handshake, captured before it
+  /// reaches any server.
+  async fn initial() -> Vec<u8> {
+    let tap = tokio::net::UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+    let mut crypto = tls::Trust::Insecure.client_config().unwrap();
+    crypto.alpn_protocols = vec![ALPN_QVPN.to_vec()];
+    let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap();
+    let client = quinn::Endpoint::client(([127, 0, 0, 1], 0).into()).unwrap();
+    let _connecting = client
+      .connect_with(
+        quinn::ClientConfig::new(Arc::new(crypto)),
+        tap.local_addr().unwrap(),
+        "localhost",
+      )
+      .unwrap();
+    let mut buf = vec![0; 65536];
+    let len = tap.recv(&mut buf).await.unwrap();
+    buf.truncate(len);
+    buf
+  }
+
+  /// Sends `initial` to `server` from an address that never answers, as an
+  /// attacker spoofing its victim's address would, and returns what the
+  /// victim gets in the following `wait`.
+  async fn spoof(server: SocketAddr, initial: &[u8], wait: Duration) -> Vec<Vec<u8>> {
+    let victim = tokio::net::UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+    victim.send_to(initial, server).await.unwrap();
+    let mut received = vec![];
+    let mut buf = vec![0; 65536];
+    let deadline = tokio::time::Instant::now() + wait;
+    while let Ok(Ok(len)) = tokio::time::timeout_at(deadline, victim.recv(&mut buf)).await {
+      received.push(buf[..len].to_vec());
+    }
+    received
+  }
+
+  #[tokio::test]
+  async fn always_retry_answers_spoofed_initials_with_a_retry() {
+    let server = server(|x| {
+      x.stateless_retry(true)
+        .validation_cache(Duration::from_secs(60))
+    });
+    let addr = server.local_addr().unwrap();
+    let (endpoint, validated) = (server.endpoint.clone(), server.shared.validated.clone());
+    tokio::spawn(server.run());
+    let initial = initial().await;
+    let received = spoof(addr, &initial, Duration::from_millis(500)).await;
+    assert_eq!(received.len(), 1);
+    // Long header of type Retry, no handshake data.
+    assert_eq!(received[0][0] & 0xf0, 0xf0);
+    assert!(received[0].len() < initial.len());
+    assert_eq!(endpoint.open_connections(), 0);
+    assert!(!validated.unwrap().contains([127, 0, 0, 1].into()));
+  }
+
+  #[tokio::test]
+  async fn spoofed_initials_stay_within_the_amplification_limit() {
+    let server = server(|x| x.validation_cache(Duration::from_secs(60)));
+    let addr = server.local_addr().unwrap();
+    let validated = server.shared.validated.clone().unwrap();
+    tokio::spawn(server.run());
+    let initial = initial().await;
+    // Long enough for the server to retransmit its first flight.
+    let received = spoof(addr, &initial, Duration::from_millis(1500)).await;
+    assert!(!received.is_empty());
+    // Once any budget is left quinn fills a whole datagram, so only what
+    // came before the last one is held to the limit.
+    let before_last: usize = received[..received.len() - 1].iter().map(Vec::len).sum();
+    assert!(
+      (before_last as u64) < AMPLIFICATION_FACTOR * initial.len() as u64,
+      "{:?} bytes sent for {} received",
+      received.iter().map(Vec::len).collect::<Vec<_>>(),
+      initial.len()
+    );
+    // The handshake never completes, so the address isn't validated.
+    assert!(!validated.contains([127, 0, 0, 1].into()));
+  }
+
+  /// Makes a `method` request for `path` over HTTP/3, returning the
+  /// response and its body.
+  async fn h3_request(
+    requests: &mut ::h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>,
+    method: &str,
+    path: &str,
+  ) -> (::http::Response<()>, Vec<u8>) {
+    let req = ::http::Request::builder()
+      .method(method)
+      .uri(format!("https://localhost{}", path))
+      .body(())
+      .unwrap();
+    let mut stream = requests.send_request(req).await.unwrap();
+    stream.finish().await.unwrap();
+    let response = stream.recv_response().await.unwrap();
+    let mut body = vec![];
+    while let Some(mut data) = stream.recv_data().await.unwrap() {
+      body.extend(data.copy_to_bytes(data.remaining()));
+    }
+    (response, body)
+  }
+
+  #[tokio::test]
+  async fn files_are_served_over_http3() {
+    let root = std::env::temp_dir().join(format!("qvpn-h3-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("hello.txt"), b"hello over http/3").unwrap();
+    let server = Server::builder(root.clone())
+      .listen(([127, 0, 0, 1], 0).into())
+      .build()
+      .unwrap();
+    let addr = server.local_addr().unwrap();
+    tokio::spawn(server.run());
+
+    let mut crypto = tls::Trust::Insecure.client_config().unwrap();
+    crypto.alpn_protocols = vec![b"h3".to_vec()];
+    let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap();
+    let mut endpoint = quinn::Endpoint::client(([127, 0, 0, 1], 0).into()).unwrap();
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+    let connection = endpoint.connect(addr, "localhost").unwrap().await.unwrap();
+    let (mut driver, mut requests) = ::h3::client::new(h3_quinn::Connection::new(connection))
+      .await
+      .unwrap();
+    tokio::spawn(async move { driver.wait_idle().await });
+
+    let (response, body) = h3_request(&mut requests, "GET", "/hello.txt").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(body, b"hello over http/3");
+    let (response, body) = h3_request(&mut requests, "HEAD", "/hello.txt").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-length"], "17");
+    assert!(body.is_empty());
+    let (response, _) = h3_request(&mut requests, "GET", "/missing.txt").await;
+    assert_eq!(response.status(), 404);
+    let (response, _) = h3_request(&mut requests, "DELETE", "/hello.txt").await;
+    assert_eq!(response.status(), 501);
+    std::fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn rpc_calls_are_answered() {
+    let router = rpc::Router::new().route("add", |(a, b): (u32, u32)| async move { Ok(a + b) });
+    let server = server(|x| x.rpc(router));
+    let url = Url::parse(&format!("https://{}/", server.local_addr().unwrap())).unwrap();
+    tokio::spawn(server.run());
+    let client = Client::builder()
+      .trust(tls::Trust::Insecure)
+      .build()
+      .unwrap();
+    let caller = client.rpc(&url, None).await.unwrap();
+    assert_eq!(
+      caller.call::<_, u32>("add", &(2u32, 3u32)).await.unwrap(),
+      5
+    );
+    caller.call::<_, ()>(rpc::PING, &()).await.unwrap();
+    assert!(caller.call::<_, ()>("missing", &()).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn dashboard_only_listens_on_loopback() {
+    let password = dashboard::Password::new(b"secret").unwrap();
+    let dashboard = |addr: SocketAddr| {
+      Server::builder(std::env::temp_dir())
+        .listen(([127, 0, 0, 1], 0).into())
+        .dashboard(addr, password.clone())
+        .build()
+    };
+    assert!(dashboard(([0, 0, 0, 0], 0).into()).is_err());
+    assert!(dashboard("[::]:0".parse().unwrap()).is_err());
+    assert!(dashboard(([127, 0, 0, 1], 0).into()).is_ok());
+  }
+}
@@ -0,0 +1,272 @@
+//! Running the server or a client as a long-lived service.
+//!
+//! With `--daemon` a binary detaches from its terminal before starting the
+//! runtime, and with `--pid-file` records its process id. Under systemd
+//! (`Type=notify`) it reports readiness through `NOTIFY_SOCKET` once it
+//! serves or its tunnel or proxy is up, and pings the watchdog at half of
+//! `WatchdogSec`. [`systemd_unit`] writes a unit for either. On Windows,
+//! [`windows`] runs them as a service instead.
+
+use std::{
+  env, fs,
+  path::{Path, PathBuf},
+  process,
+  sync::Once,
+  time::Duration,
+};
+
+use tracing::{debug, warn};
+
+use crate::{QvpnError, Result};
+
+#[cfg(windows)]
+pub mod windows;
+
+/// Default `WatchdogSec` of generated units.
+pub const DEFAULT_WATCHDOG: Duration = Duration::from_secs(30);
+
+/// Detaches from the terminal: forks twice with a new session in between,
+/// so the process is re-parented and can't acquire a controlling terminal,
+/// and points stdin and stdout at `/dev/null`. Stderr, where logs go, is
+/// kept, as is the working directory, so relative paths keep resolving.
+///
+/// Must be called before any other thread starts, so before the runtime.
+#[cfg(unix)]
+pub fn daemonize() -> Result<()> {
+  use std::{io, os::unix::io::AsRawFd};
+
+  for session in [true, false] {
+    match unsafe { libc::fork() } {
+      -1 => return Err(io::Error::last_os_error().into()),
+      0 => {}
+      _ => unsafe { libc::_exit(0) },
+    }
+    if session && unsafe { libc::setsid() } == -1 {
+      return Err(io::Error::last_os_error().into());
+    }
+  }
+  let null = fs::OpenOptions::new()
+    .read(true)
+    .write(true)
+    .open("/dev/null")?;
+  for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO] {
+    if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+      return Err(io::Error::last_os_error().into());
+    }
+  }
+  Ok(())
+}
+
+/// Detaches from the terminal.
+#[cfg(not(unix))]
+pub fn daemonize() -> Result<()> {
+  Err(QvpnError::Unsupported(
+    "daemon mode is not supported on this platform".into(),
+  ))
+}
+
+/// A file holding the process id, removed when dropped.
+#[derive(Debug)]
+pub struct PidFile {
+  path: PathBuf,
+}
+
+impl PidFile {
+  /// Writes the process id to `path`, unless it names a process that is
+  /// still running.
+  pub fn create(path: impl Into<PathBuf>) -> Result<Self> {
+    let path = path.into();
+    if let Some(pid) = fs::read_to_string(&path)
+      .ok()
+      .and_then(|x| x.trim().parse().ok())
+    {
+      if running(pid) {
+        return Err(QvpnError::InvalidInput(format!(
+          "{} names process {}, which is still running",
+          path.display(),
+          pid
+        )));
+      }
+    }
+    fs::write(&path, format!("{}\n", process::id()))?;
+    Ok(PidFile { path })
+  }
+
+  /// Path of the file.
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+}
+
+impl Drop for PidFile {
+  fn drop(&mut self) {
+    // Fails if privileges were dropped since it was written.
+    if let Err(err) = fs::remove_file(&self.path) {
+      debug!(path = %self.path.display(), "couldn't remove the pid file: {}", err);
+    }
+  }
+}
+
+#[cfg(unix)]
+fn running(pid: u32) -> bool {
+  use std::io;
+
+  let err = match unsafe { libc::kill(pid as libc::pid_t, 0) } {
+    0 => return true,
+    _ => io::Error::last_os_error(),
+  };
+  // It exists but belongs to someone else.
+  err.raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn running(_pid: u32) -> bool {
+  false
+}
+
+/// Sends `state`, such as `READY=1`, to the service manager. Returns
+/// whether there is one listening.
+#[cfg(unix)]
+pub fn notify(state: &str) -> Result<bool> {
+  use std::os::unix::net::UnixDatagram;
+
+  let socket_path = match env::var_os("NOTIFY_SOCKET") {
+    Some(path) => path,
+    None => return Ok(false),
+  };
+  let socket = UnixDatagram::unbound()?;
+  match socket_path.to_str().and_then(|x| x.strip_prefix('@')) {
+    #[cfg(target_os = "linux")]
+    Some(name) => {
+      use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+      let addr = SocketAddr::from_abstract_name(name)?;
+      socket.send_to_addr(state.as_bytes(), &addr)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    Some(_) => {
+      return Err(QvpnError::Unsupported(
+        "abstract notify sockets are only supported on Linux".into(),
+      ))
+    }
+    None => {
+      socket.send_to(state.as_bytes(), &socket_path)?;
+    }
+  }
+  Ok(true)
+}
+
+/// Sends `state` to the service manager.
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> Result<bool> {
+  Ok(false)
+}
+
+/// Tells the service manager the service is up and starts pinging its
+/// watchdog, if it has one. Later calls, as after reconnecting, only
+/// repeat the notification.
+///
+/// Must be called within the runtime.
+pub fn ready() {
+  static WATCHDOG: Once = Once::new();
+  match notify("READY=1") {
+    Ok(false) => return,
+    Ok(true) => {}
+    Err(err) => {
+      warn!("couldn't notify the service manager: {}", err);
+      return;
+    }
+  }
+  if let Some(interval) = watchdog_interval() {
+    WATCHDOG.call_once(|| {
+      tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval / 2);
+        loop {
+          ticks.tick().await;
+          if let Err(err) = notify("WATCHDOG=1") {
+            warn!("couldn't ping the watchdog: {}", err);
+          }
+        }
+      });
+    });
+  }
+}
+
+/// Tells the service manager the service is shutting down.
+pub fn stopping() {
+  if let Err(err) = notify("STOPPING=1") {
+    debug!("couldn't notify the service manager: {}", err);
+  }
+}
+
+/// Interval the service manager expects watchdog pings within, if it
+/// expects them from this process.
+fn watchdog_interval() -> Option<Duration> {
+  if let Ok(pid) = env::var("WATCHDOG_PID") {
+    if pid.parse() != Ok(process::id()) {
+      return None;
+    }
+  }
+  let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+  Some(Duration::from_micros(usec)).filter(|x| !x.is_zero())
+}
+
+/// Completes on `SIGINT`, or on Unix also `SIGTERM`, which is how service
+/// managers stop a service.
+pub async fn shutdown_signal() {
+  #[cfg(unix)]
+  {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+      tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+      }
+      return;
+    }
+  }
+  let _ = tokio::signal::ctrl_c().await;
+}
+
+/// A systemd unit running `exec` with `args` as a `Type=notify` service
+/// that is restarted if it fails or stops pinging its watchdog.
+pub fn systemd_unit(description: &str, exec: &Path, args: &[&str], watchdog: Duration) -> String {
+  let mut command = quote(&exec.display().to_string());
+  for arg in args {
+    command.push(' ');
+    command.push_str(&quote(arg));
+  }
+  format!(
+    "[Unit]\n\
+     Description={}\n\
+     Wants=network-online.target\n\
+     After=network-online.target\n\
+     \n\
+     [Service]\n\
+     Type=notify\n\
+     ExecStart={}\n\
+     Restart=on-failure\n\
+     WatchdogSec={}\n\
+     \n\
+     [Install]\n\
+     WantedBy=multi-user.target\n",
+    description,
+    command,
+    watchdog.as_secs().max(1)
+  )
+}
+
+/// Quotes `arg` for an `ExecStart=` line if it needs it.
+fn quote(arg: &str) -> String {
+  if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || "\"'\\$%;".contains(c)) {
+    let escaped = arg
+      .replace('\\', "\\\\")
+      .replace('"', "\\\"")
+      .replace('$', "$$")
+      .replace('%', "%%");
+    format!("\"{}\"", escaped)
+  } else {
+    arg.to_string()
+  }
+}
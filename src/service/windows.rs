@@ -0,0 +1,348 @@
+//! Running the server or a client tunnel as a Windows service.
+//!
+//! [`install`] registers a service that starts at boot and is restarted
+//! when it fails, and an event source of the same name, so events logged
+//! by [`init_log`] show up in the Application event log. The service
+//! manager starts the executable with the arguments it was installed with,
+//! which must lead it to [`run`].
+
+use std::{ffi::OsString, io, path::PathBuf, process::Command, ptr, sync::Mutex, time::Duration};
+
+use tokio::sync::oneshot;
+use tracing::{error, Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use windows_service::{
+  define_windows_service,
+  service::{
+    ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept,
+    ServiceErrorControl, ServiceExitCode, ServiceFailureActions, ServiceFailureResetPeriod,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+  },
+  service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+  service_dispatcher,
+  service_manager::{ServiceManager, ServiceManagerAccess},
+};
+use windows_sys::Win32::{
+  Foundation::HANDLE,
+  System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+    EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+  },
+};
+
+use crate::{log::LogFormat, QvpnError, Result};
+
+/// Name of the service and of its event source.
+pub const SERVICE_NAME: &str = "qvpn";
+
+/// Registry key of the event source.
+const EVENT_SOURCE_KEY: &str = r"HKLM\SYSTEM\CurrentControlSet\Services\EventLog\Application\qvpn";
+
+/// Message file whose events 1 to 1000 display their one string as is.
+const EVENT_MESSAGE_FILE: &str = r"%SystemRoot%\System32\EventCreate.exe";
+
+/// Event id of every logged event.
+const EVENT_ID: u32 = 1;
+
+/// How long after a failure the service is restarted.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Registers the service running `exe` with `args` as `display_name`.
+///
+/// Requires Administrator rights.
+pub fn install(exe: PathBuf, args: Vec<OsString>, display_name: &str) -> Result<()> {
+  let manager = ServiceManager::local_computer(
+    None::<&str>,
+    ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+  )
+  .map_err(service_error)?;
+  let info = ServiceInfo {
+    name: SERVICE_NAME.into(),
+    display_name: display_name.into(),
+    service_type: ServiceType::OWN_PROCESS,
+    start_type: ServiceStartType::AutoStart,
+    error_control: ServiceErrorControl::Normal,
+    executable_path: exe,
+    launch_arguments: args,
+    dependencies: vec![],
+    // LocalSystem, which may create adapters.
+    account_name: None,
+    account_password: None,
+  };
+  let service = manager
+    .create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+    .map_err(service_error)?;
+  service
+    .set_description("Tunnels IP packets and serves files over QUIC")
+    .map_err(service_error)?;
+  let restart = ServiceAction {
+    action_type: ServiceActionType::Restart,
+    delay: RESTART_DELAY,
+  };
+  service
+    .update_failure_actions(ServiceFailureActions {
+      reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(24 * 60 * 60)),
+      reboot_msg: None,
+      command: None,
+      actions: Some(vec![restart.clone(), restart.clone(), restart]),
+    })
+    .map_err(service_error)?;
+  // Also restart after errors the service reports itself.
+  service
+    .set_failure_actions_on_non_crash_failures(true)
+    .map_err(service_error)?;
+  reg(&[
+    "add",
+    EVENT_SOURCE_KEY,
+    "/v",
+    "EventMessageFile",
+    "/t",
+    "REG_EXPAND_SZ",
+    "/d",
+    EVENT_MESSAGE_FILE,
+    "/f",
+  ])?;
+  reg(&[
+    "add",
+    EVENT_SOURCE_KEY,
+    "/v",
+    "TypesSupported",
+    "/t",
+    "REG_DWORD",
+    "/d",
+    "7",
+    "/f",
+  ])
+}
+
+/// Stops and removes the service and its event source.
+///
+/// Requires Administrator rights.
+pub fn uninstall() -> Result<()> {
+  let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+    .map_err(service_error)?;
+  let service = manager
+    .open_service(
+      SERVICE_NAME,
+      ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )
+    .map_err(service_error)?;
+  // Deleted once it has stopped and every handle to it is closed.
+  service.delete().map_err(service_error)?;
+  if service.query_status().map_err(service_error)?.current_state != ServiceState::Stopped {
+    service.stop().map_err(service_error)?;
+  }
+  reg(&["delete", EVENT_SOURCE_KEY, "/f"])
+}
+
+/// Completes when the service manager asks the service to stop.
+pub struct Shutdown(oneshot::Receiver<()>);
+
+impl Shutdown {
+  /// Waits for the stop request.
+  pub async fn wait(self) {
+    let _ = self.0.await;
+  }
+}
+
+type Main = Box<dyn FnOnce(Shutdown) -> Result<()> + Send>;
+
+static MAIN: Mutex<Option<Main>> = Mutex::new(None);
+
+/// Runs `main` as the service, reporting it running until it returns. An
+/// error stops the service as failed, so it is restarted.
+///
+/// Only works in a process the service manager started.
+pub fn run(main: impl FnOnce(Shutdown) -> Result<()> + Send + 'static) -> Result<()> {
+  *MAIN.lock().expect("service main poisoned") = Some(Box::new(main));
+  service_dispatcher::start(SERVICE_NAME, ffi_service_main).map_err(|err| {
+    QvpnError::Unsupported(format!(
+      "not started by the service manager; install the service instead ({})",
+      err
+    ))
+  })
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_arguments: Vec<OsString>) {
+  if let Err(err) = run_service() {
+    error!("service failed: {}", err);
+  }
+}
+
+fn run_service() -> Result<()> {
+  let (stop, stopped) = oneshot::channel();
+  let stop = Mutex::new(Some(stop));
+  let status = service_control_handler::register(SERVICE_NAME, move |control| match control {
+    ServiceControl::Stop | ServiceControl::Shutdown => {
+      if let Some(stop) = stop.lock().expect("stop poisoned").take() {
+        let _ = stop.send(());
+      }
+      ServiceControlHandlerResult::NoError
+    }
+    ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+    _ => ServiceControlHandlerResult::NotImplemented,
+  })
+  .map_err(service_error)?;
+  set_state(
+    &status,
+    ServiceState::Running,
+    ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+    ServiceExitCode::Win32(0),
+  )?;
+  let main = MAIN
+    .lock()
+    .expect("service main poisoned")
+    .take()
+    .ok_or_else(|| QvpnError::Unsupported("service started twice".into()))?;
+  let result = main(Shutdown(stopped));
+  let code = match result {
+    Ok(()) => ServiceExitCode::Win32(0),
+    Err(_) => ServiceExitCode::ServiceSpecific(1),
+  };
+  set_state(
+    &status,
+    ServiceState::Stopped,
+    ServiceControlAccept::empty(),
+    code,
+  )?;
+  result
+}
+
+fn set_state(
+  status: &ServiceStatusHandle,
+  state: ServiceState,
+  accepted: ServiceControlAccept,
+  code: ServiceExitCode,
+) -> Result<()> {
+  status
+    .set_service_status(ServiceStatus {
+      service_type: ServiceType::OWN_PROCESS,
+      current_state: state,
+      controls_accepted: accepted,
+      exit_code: code,
+      checkpoint: 0,
+      wait_hint: Duration::default(),
+      process_id: None,
+    })
+    .map_err(service_error)
+}
+
+/// Installs the global subscriber, writing events to the Application event
+/// log. `level` is a filter as for [`crate::log::init`].
+pub fn init_log(level: Option<&str>) -> Result<()> {
+  crate::log::init_with(level, LogFormat::Text, EventLog::register()?, false)
+}
+
+/// The service's event source.
+struct EventLog {
+  handle: HANDLE,
+}
+
+impl EventLog {
+  fn register() -> Result<Self> {
+    let name = wide(SERVICE_NAME);
+    let handle = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+    if handle == 0 {
+      return Err(io::Error::last_os_error().into());
+    }
+    Ok(EventLog { handle })
+  }
+}
+
+impl Drop for EventLog {
+  fn drop(&mut self) {
+    unsafe { DeregisterEventSource(self.handle) };
+  }
+}
+
+impl<'a> MakeWriter<'a> for EventLog {
+  type Writer = EventWriter<'a>;
+
+  fn make_writer(&'a self) -> Self::Writer {
+    EventWriter::new(self, EVENTLOG_INFORMATION_TYPE)
+  }
+
+  fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+    let kind = match *meta.level() {
+      Level::ERROR => EVENTLOG_ERROR_TYPE,
+      Level::WARN => EVENTLOG_WARNING_TYPE,
+      _ => EVENTLOG_INFORMATION_TYPE,
+    };
+    EventWriter::new(self, kind)
+  }
+}
+
+/// Collects one formatted event and reports it when dropped.
+struct EventWriter<'a> {
+  log: &'a EventLog,
+  kind: u16,
+  buf: Vec<u8>,
+}
+
+impl<'a> EventWriter<'a> {
+  fn new(log: &'a EventLog, kind: u16) -> Self {
+    EventWriter {
+      log,
+      kind,
+      buf: vec![],
+    }
+  }
+}
+
+impl io::Write for EventWriter<'_> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.buf.extend_from_slice(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+impl Drop for EventWriter<'_> {
+  fn drop(&mut self) {
+    let message = wide(String::from_utf8_lossy(&self.buf).trim_end());
+    let strings = [message.as_ptr()];
+    unsafe {
+      ReportEventW(
+        self.log.handle,
+        self.kind,
+        0,
+        EVENT_ID,
+        ptr::null_mut(),
+        strings.len() as u16,
+        0,
+        strings.as_ptr(),
+        ptr::null(),
+      )
+    };
+  }
+}
+
+/// `s` as a nul-terminated UTF-16 string.
+fn wide(s: &str) -> Vec<u16> {
+  s.encode_utf16().chain(Some(0)).collect()
+}
+
+fn service_error(err: windows_service::Error) -> QvpnError {
+  match err {
+    windows_service::Error::Winapi(err) => err.into(),
+    err => QvpnError::Io(io::Error::other(err.to_string())),
+  }
+}
+
+/// Runs `reg` with `args`.
+fn reg(args: &[&str]) -> Result<()> {
+  let status = Command::new("reg").args(args).status()?;
+  if !status.success() {
+    return Err(QvpnError::Io(io::Error::other(format!(
+      "reg {} failed: {}",
+      args.join(" "),
+      status
+    ))));
+  }
+  Ok(())
+}
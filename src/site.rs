@@ -0,0 +1,450 @@
+//! Site-to-site gateways.
+//!
+//! A tunnel client can be the router of a LAN. Once leased an address, it
+//! sends [`Message::Advertise`] with the networks behind it. The server
+//! checks each against its [`Rule`]s, routes them through its interface to
+//! that client, and pushes them to every client along with its own routes,
+//! so each site reaches the others through the server. Clients leave the
+//! networks they advertise themselves out of the routes they install.
+//!
+//! Either side may [`Masquerade`] the traffic coming out of the tunnel, so
+//! that hosts on its LAN need no route back to the other sites.
+//!
+//! [`Message::Advertise`]: crate::control::Message::Advertise
+
+use std::{
+  collections::HashMap,
+  fmt,
+  net::{Ipv4Addr, SocketAddr},
+  str::FromStr,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use serde::{de, Deserialize, Deserializer};
+#[cfg(target_os = "linux")]
+use tracing::warn;
+
+use crate::{lease::Ipv4Net, net, QvpnError, Result};
+
+/// Networks a client may advertise.
+#[derive(Debug, Clone)]
+pub struct Rule {
+  pub(crate) network: Ipv4Net,
+  pub(crate) users: Vec<String>,
+}
+
+impl Rule {
+  /// Lets clients advertise `network` or any network inside it.
+  pub fn new(network: Ipv4Net) -> Self {
+    Rule {
+      network,
+      users: vec![],
+    }
+  }
+
+  /// Lets only these users advertise the networks; any client that may
+  /// use the tunnel if empty.
+  pub fn users(mut self, users: Vec<String>) -> Self {
+    self.users = users;
+    self
+  }
+
+  fn allows(&self, network: Ipv4Net, user: Option<&str>) -> bool {
+    let users = &self.users;
+    network.prefix() >= self.network.prefix()
+      && self.network.contains(network.addr())
+      && (users.is_empty() || user.is_some_and(|user| users.iter().any(|x| x == user)))
+  }
+}
+
+/// A gateway client and the networks behind it.
+#[derive(Debug)]
+struct Site {
+  address: Ipv4Addr,
+  networks: Vec<Ipv4Net>,
+  connection: quinn::Connection,
+  since: Instant,
+}
+
+/// How one site's connection is doing.
+#[derive(Debug, Clone)]
+pub struct Health {
+  /// Tunnel address of the site's gateway.
+  pub address: Ipv4Addr,
+  /// Networks behind it.
+  pub networks: Vec<Ipv4Net>,
+  /// Where the gateway connects from.
+  pub remote: SocketAddr,
+  /// Current round-trip time estimate.
+  pub rtt: Duration,
+  /// Packets sent to the gateway, and of those, found lost.
+  pub sent_packets: u64,
+  pub lost_packets: u64,
+  /// How long the gateway has been advertising.
+  pub up: Duration,
+}
+
+/// The sites of a server and the rules for them.
+#[derive(Debug, Default)]
+pub struct Sites {
+  rules: Vec<Rule>,
+  /// The tunnel subnet, which no site may overlap.
+  subnet: Option<Ipv4Net>,
+  /// By connection.
+  sites: Mutex<HashMap<usize, Site>>,
+}
+
+impl Sites {
+  /// No sites yet, to be allowed by `rules` outside the tunnel `subnet`.
+  pub fn new(rules: Vec<Rule>, subnet: Ipv4Net) -> Self {
+    Sites {
+      rules,
+      subnet: Some(subnet),
+      sites: Mutex::default(),
+    }
+  }
+
+  /// Checks that `user`, if logged in, may advertise every one of
+  /// `networks`, none of which may overlap the tunnel subnet.
+  pub fn check(&self, networks: &[Ipv4Net], user: Option<&str>) -> Result<()> {
+    if let Some(network) = networks
+      .iter()
+      .find(|network| self.subnet.is_some_and(|x| x.overlaps(**network)))
+    {
+      return Err(QvpnError::BadRequest(format!(
+        "advertised network {} overlaps the tunnel subnet",
+        network
+      )));
+    }
+    match networks
+      .iter()
+      .find(|network| !self.rules.iter().any(|rule| rule.allows(**network, user)))
+    {
+      Some(network) => Err(QvpnError::Unauthenticated(format!(
+        "advertising {} is not allowed",
+        network
+      ))),
+      None => Ok(()),
+    }
+  }
+
+  /// Records `networks` as behind the client of `connection`, leased
+  /// `address`, and returns those it advertised before. Fails if one
+  /// overlaps the networks of another site, or another of `networks`.
+  pub fn insert(
+    &self,
+    connection: &quinn::Connection,
+    address: Ipv4Addr,
+    networks: Vec<Ipv4Net>,
+  ) -> Result<Vec<Ipv4Net>> {
+    let mut sites = self.sites.lock().unwrap();
+    let id = connection.stable_id();
+    let taken = sites
+      .iter()
+      .filter(|(x, _)| **x != id)
+      .flat_map(|(_, site)| &site.networks);
+    for (i, network) in networks.iter().enumerate() {
+      if let Some(other) = taken
+        .clone()
+        .chain(&networks[..i])
+        .find(|x| x.overlaps(*network))
+      {
+        return Err(QvpnError::BadRequest(format!(
+          "advertised network {} overlaps {}",
+          network, other
+        )));
+      }
+    }
+    let site = Site {
+      address,
+      networks,
+      connection: connection.clone(),
+      since: Instant::now(),
+    };
+    match sites.insert(id, site) {
+      Some(old) => Ok(old.networks),
+      None => Ok(vec![]),
+    }
+  }
+
+  /// Forgets the site of `connection`, returning the networks behind it.
+  pub fn remove(&self, connection: &quinn::Connection) -> Vec<Ipv4Net> {
+    let mut sites = self.sites.lock().unwrap();
+    match sites.remove(&connection.stable_id()) {
+      Some(site) => site.networks,
+      None => vec![],
+    }
+  }
+
+  /// The networks behind every site.
+  pub fn networks(&self) -> Vec<Ipv4Net> {
+    let sites = self.sites.lock().unwrap();
+    sites.values().flat_map(|x| x.networks.clone()).collect()
+  }
+
+  /// How each site's connection is doing.
+  pub fn health(&self) -> Vec<Health> {
+    let sites = self.sites.lock().unwrap();
+    let mut health: Vec<_> = sites
+      .values()
+      .map(|site| {
+        let stats = site.connection.stats();
+        Health {
+          address: site.address,
+          networks: site.networks.clone(),
+          remote: net::canonical(site.connection.remote_address()),
+          rtt: site.connection.rtt(),
+          sent_packets: stats.path.sent_packets,
+          lost_packets: stats.path.lost_packets,
+          up: site.since.elapsed(),
+        }
+      })
+      .collect();
+    health.sort_by_key(|x| x.address);
+    health
+  }
+}
+
+/// Tool masquerading rules are installed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Firewall {
+  Iptables,
+  Nftables,
+}
+
+impl FromStr for Firewall {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    match s {
+      "iptables" => Ok(Firewall::Iptables),
+      "nftables" => Ok(Firewall::Nftables),
+      _ => Err(format!(
+        "unknown firewall `{}`, expected iptables or nftables",
+        s
+      )),
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Firewall {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Firewall {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(match self {
+      Firewall::Iptables => "iptables",
+      Firewall::Nftables => "nftables",
+    })
+  }
+}
+
+/// Mark iptables puts on packets that came out of a tunnel, to masquerade
+/// them on the way out of another interface.
+#[cfg(target_os = "linux")]
+const MARK: &str = "0x7176/0xffff";
+
+/// Rules rewriting the source of packets that come out of a tunnel
+/// interface and leave through another one, and letting them and their
+/// replies through the forward chain, removed when dropped. IPv4
+/// forwarding is turned on meanwhile if it was off.
+#[derive(Debug)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct Masquerade {
+  firewall: Firewall,
+  dev: String,
+  /// Whether forwarding was turned on, to be turned off again.
+  forwarding: bool,
+}
+
+impl Masquerade {
+  /// Masquerades what comes out of the interface named `dev`.
+  #[cfg(target_os = "linux")]
+  pub fn new(firewall: Firewall, dev: &str) -> Result<Self> {
+    let mut masquerade = Masquerade {
+      firewall,
+      dev: dev.to_string(),
+      forwarding: false,
+    };
+    // Rules left behind by a run that didn't get to clean up are replaced.
+    masquerade.remove();
+    for args in masquerade.rules(true) {
+      run(&args)?;
+    }
+    if !forwarding_enabled() {
+      match std::fs::write(FORWARDING, "1") {
+        Ok(()) => masquerade.forwarding = true,
+        Err(err) => warn!("IPv4 forwarding is off and couldn't be turned on: {}", err),
+      }
+    }
+    Ok(masquerade)
+  }
+
+  /// Masquerades what comes out of the interface named `dev`.
+  #[cfg(not(target_os = "linux"))]
+  pub fn new(firewall: Firewall, dev: &str) -> Result<Self> {
+    Err(QvpnError::Unsupported(format!(
+      "cannot masquerade {} with {} on this platform",
+      dev, firewall
+    )))
+  }
+
+  /// Commands adding, or else deleting, the rules.
+  #[cfg(target_os = "linux")]
+  fn rules(&self, add: bool) -> Vec<Vec<String>> {
+    let dev = self.dev.as_str();
+    let table = format!(
+      "qvpn_{}",
+      dev.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+    );
+    let line = |x: &str| x.split_whitespace().map(str::to_string).collect::<Vec<_>>();
+    match (self.firewall, add) {
+      (Firewall::Iptables, _) => {
+        let (op, insert) = if add { ("-A", "-I") } else { ("-D", "-D") };
+        vec![
+          line(&format!(
+            "iptables -t mangle {} PREROUTING -i {} -j MARK --set-xmark {}",
+            op, dev, MARK
+          )),
+          line(&format!(
+            "iptables -t nat {} POSTROUTING -m mark --mark {} ! -o {} -j MASQUERADE",
+            op, MARK, dev
+          )),
+          // Ahead of any rules dropping forwarded packets.
+          line(&format!("iptables {} FORWARD -i {} -j ACCEPT", insert, dev)),
+          line(&format!(
+            "iptables {} FORWARD -o {} -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT",
+            insert, dev
+          )),
+        ]
+      }
+      (Firewall::Nftables, true) => vec![
+        line(&format!("nft add table ip {}", table)),
+        line(&format!(
+          "nft add chain ip {} postrouting {{ type nat hook postrouting priority 100 ; }}",
+          table
+        )),
+        line(&format!(
+          "nft add rule ip {} postrouting iifname {} oifname != {} masquerade",
+          table, dev, dev
+        )),
+        line(&format!(
+          "nft add chain ip {} forward {{ type filter hook forward priority 0 ; }}",
+          table
+        )),
+        line(&format!(
+          "nft add rule ip {} forward iifname {} accept",
+          table, dev
+        )),
+        line(&format!(
+          "nft add rule ip {} forward oifname {} ct state established,related accept",
+          table, dev
+        )),
+      ],
+      (Firewall::Nftables, false) => vec![line(&format!("nft delete table ip {}", table))],
+    }
+  }
+
+  /// Deletes the rules, returning whether every command succeeded.
+  #[cfg(target_os = "linux")]
+  fn remove(&self) -> bool {
+    let mut removed = true;
+    for args in self.rules(false) {
+      let status = std::process::Command::new(&args[0])
+        .args(&args[1..])
+        .stderr(std::process::Stdio::null())
+        .status();
+      removed &= status.is_ok_and(|x| x.success());
+    }
+    removed
+  }
+}
+
+impl Drop for Masquerade {
+  fn drop(&mut self) {
+    #[cfg(target_os = "linux")]
+    {
+      if !self.remove() {
+        warn!(
+          dev = %self.dev,
+          "couldn't remove all masquerading rules; delete them with {}",
+          self.firewall
+        );
+      }
+      if self.forwarding {
+        if let Err(err) = std::fs::write(FORWARDING, "0") {
+          warn!("couldn't turn IPv4 forwarding back off: {}", err);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn run(args: &[String]) -> Result<()> {
+  let status = std::process::Command::new(&args[0])
+    .args(&args[1..])
+    .status()
+    .map_err(|err| std::io::Error::new(err.kind(), format!("running {}: {}", args[0], err)))?;
+  if !status.success() {
+    return Err(QvpnError::Io(std::io::Error::other(format!(
+      "{} failed: {}",
+      args.join(" "),
+      status
+    ))));
+  }
+  Ok(())
+}
+
+#[cfg(target_os = "linux")]
+const FORWARDING: &str = "/proc/sys/net/ipv4/ip_forward";
+
+/// Whether the kernel forwards IPv4 packets between interfaces, which a
+/// gateway needs.
+#[cfg(target_os = "linux")]
+pub fn forwarding_enabled() -> bool {
+  std::fs::read_to_string(FORWARDING).is_ok_and(|x| x.trim() == "1")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn net(s: &str) -> Ipv4Net {
+    s.parse().unwrap()
+  }
+
+  #[test]
+  fn check_rejects_the_tunnel_subnet() {
+    let sites = Sites::new(vec![Rule::new(net("0.0.0.0/0"))], net("10.8.0.0/24"));
+    assert!(sites.check(&[net("192.168.1.0/24")], None).is_ok());
+    for network in ["10.8.0.0/24", "10.8.0.128/25", "10.0.0.0/8"] {
+      assert!(matches!(
+        sites.check(&[net(network)], None),
+        Err(QvpnError::BadRequest(_))
+      ));
+    }
+  }
+
+  #[test]
+  fn check_follows_rules() {
+    let rule = Rule::new(net("192.168.0.0/16")).users(vec!["branch".into()]);
+    let sites = Sites::new(vec![rule], net("10.8.0.0/24"));
+    assert!(sites
+      .check(&[net("192.168.1.0/24")], Some("branch"))
+      .is_ok());
+    assert!(sites
+      .check(&[net("192.168.1.0/24")], Some("other"))
+      .is_err());
+    assert!(sites.check(&[net("192.168.1.0/24")], None).is_err());
+    assert!(sites
+      .check(&[net("172.16.0.0/12")], Some("branch"))
+      .is_err());
+  }
+}
@@ -0,0 +1,117 @@
+//! SOCKS5 front-end that tunnels TCP connections over QUIC streams.
+//!
+//! Only the `CONNECT` command without authentication is supported, which is
+//! all most browsers and command line tools need.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use tokio::{
+  io::{AsyncReadExt, AsyncWriteExt},
+  net::TcpStream,
+};
+use tracing::info;
+
+use crate::{proxy, QvpnError, Result};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+const REP_SUCCEEDED: u8 = 0;
+const REP_GENERAL_FAILURE: u8 = 1;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// Handles one SOCKS5 client, tunnelling its connection to the server.
+pub async fn handle(mut tcp: TcpStream, connection: quinn::Connection) -> Result<()> {
+  let target = match handshake(&mut tcp).await {
+    Ok(target) => target,
+    Err((reply, err)) => {
+      if let Some(reply) = reply {
+        let _ = tcp.write_all(&reply_message(reply)).await;
+      }
+      return Err(err);
+    }
+  };
+  info!(%target, "socks connect");
+  let (send, recv, rest) = match proxy::open(&connection, &target).await {
+    Ok(stream) => stream,
+    Err(err) => {
+      tcp.write_all(&reply_message(REP_GENERAL_FAILURE)).await?;
+      return Err(err);
+    }
+  };
+  tcp.write_all(&reply_message(REP_SUCCEEDED)).await?;
+  proxy::splice(tcp, &rest, send, recv).await?;
+  Ok(())
+}
+
+/// Negotiates the method and reads the `CONNECT` request, returning the
+/// target as `host:port`. On failure, returns the reply code to send if the
+/// request got far enough to need one.
+async fn handshake(tcp: &mut TcpStream) -> std::result::Result<String, (Option<u8>, QvpnError)> {
+  let io = |err: std::io::Error| (None, err.into());
+  let bad = |msg: &str| (None, QvpnError::BadRequest(msg.into()));
+
+  let mut header = [0; 2];
+  tcp.read_exact(&mut header).await.map_err(io)?;
+  if header[0] != VERSION {
+    return Err(bad("not a SOCKS5 client"));
+  }
+  let mut methods = vec![0; header[1] as usize];
+  tcp.read_exact(&mut methods).await.map_err(io)?;
+  if !methods.contains(&NO_AUTH) {
+    let _ = tcp.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await;
+    return Err(bad("client requires authentication"));
+  }
+  tcp.write_all(&[VERSION, NO_AUTH]).await.map_err(io)?;
+
+  let mut request = [0; 4];
+  tcp.read_exact(&mut request).await.map_err(io)?;
+  let [version, command, _, address_type] = request;
+  if version != VERSION {
+    return Err(bad("not a SOCKS5 request"));
+  }
+  if command != CMD_CONNECT {
+    return Err((
+      Some(REP_COMMAND_NOT_SUPPORTED),
+      QvpnError::Unsupported(format!("SOCKS command {}", command)),
+    ));
+  }
+  let host = match address_type {
+    ATYP_IPV4 => {
+      let mut addr = [0; 4];
+      tcp.read_exact(&mut addr).await.map_err(io)?;
+      Ipv4Addr::from(addr).to_string()
+    }
+    ATYP_IPV6 => {
+      let mut addr = [0; 16];
+      tcp.read_exact(&mut addr).await.map_err(io)?;
+      format!("[{}]", Ipv6Addr::from(addr))
+    }
+    ATYP_DOMAIN => {
+      let len = tcp.read_u8().await.map_err(io)?;
+      let mut name = vec![0; len as usize];
+      tcp.read_exact(&mut name).await.map_err(io)?;
+      String::from_utf8(name).map_err(|_| bad("domain name is not valid utf-8"))?
+    }
+    _ => {
+      return Err((
+        Some(REP_ADDRESS_TYPE_NOT_SUPPORTED),
+        QvpnError::Unsupported(format!("SOCKS address type {}", address_type)),
+      ))
+    }
+  };
+  let port = tcp.read_u16().await.map_err(io)?;
+  Ok(format!("{}:{}", host, port))
+}
+
+/// Reply with an unspecified bound address, which clients ignore for
+/// `CONNECT`.
+fn reply_message(reply: u8) -> [u8; 10] {
+  [VERSION, reply, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]
+}
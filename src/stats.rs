@@ -0,0 +1,174 @@
+//! Transfer statistics of connections.
+//!
+//! A [`Tracker`] keeps the connections of a server or client while they are
+//! open and reads their [`Stats`] from quinn on demand; [`report`] logs them
+//! at a fixed interval.
+
+use std::{
+  collections::HashMap,
+  future::Future,
+  net::SocketAddr,
+  sync::{Arc, Mutex},
+  time::Duration,
+};
+
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::net;
+
+/// Transfer statistics of one connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+  /// Id of the connection, unique while it is open.
+  pub id: usize,
+  /// Address of the other end.
+  pub remote: SocketAddr,
+  /// UDP payload bytes sent.
+  pub bytes_sent: u64,
+  /// UDP payload bytes received.
+  pub bytes_received: u64,
+  /// UDP datagrams sent.
+  pub packets_sent: u64,
+  /// UDP datagrams received.
+  pub packets_received: u64,
+  /// QUIC packets declared lost.
+  pub lost_packets: u64,
+  /// Current smoothed round-trip time.
+  pub rtt: Duration,
+  /// Current congestion window, in bytes.
+  pub cwnd: u64,
+  /// Streams opened on the connection for requests and proxied
+  /// connections.
+  pub streams_opened: u64,
+}
+
+impl Stats {
+  /// Reads the statistics of `connection`, which had `streams_opened`
+  /// streams opened on it.
+  pub fn of(connection: &quinn::Connection, streams_opened: u64) -> Self {
+    let stats = connection.stats();
+    Stats {
+      id: connection.stable_id(),
+      remote: net::canonical(connection.remote_address()),
+      bytes_sent: stats.udp_tx.bytes,
+      bytes_received: stats.udp_rx.bytes,
+      packets_sent: stats.udp_tx.datagrams,
+      packets_received: stats.udp_rx.datagrams,
+      lost_packets: stats.path.lost_packets,
+      rtt: stats.path.rtt,
+      cwnd: stats.path.cwnd,
+      streams_opened,
+    }
+  }
+
+  /// Fraction of the packets sent that were lost.
+  pub fn loss(&self) -> f64 {
+    if self.packets_sent == 0 {
+      return 0.0;
+    }
+    (self.lost_packets as f64 / self.packets_sent as f64).min(1.0)
+  }
+
+  /// The statistics as a JSON object, with the RTT in milliseconds.
+  pub fn to_json(&self) -> Value {
+    json!({
+      "id": self.id,
+      "remote": self.remote.to_string(),
+      "bytes_sent": self.bytes_sent,
+      "bytes_received": self.bytes_received,
+      "packets_sent": self.packets_sent,
+      "packets_received": self.packets_received,
+      "lost_packets": self.lost_packets,
+      "loss": self.loss(),
+      "rtt_ms": self.rtt.as_secs_f64() * 1000.0,
+      "cwnd": self.cwnd,
+      "streams_opened": self.streams_opened,
+    })
+  }
+}
+
+/// The open connections of a server or client. Cloning it shares the same
+/// set.
+#[derive(Debug, Clone, Default)]
+pub struct Tracker {
+  connections: Arc<Mutex<HashMap<usize, Tracked>>>,
+}
+
+#[derive(Debug)]
+struct Tracked {
+  connection: quinn::Connection,
+  streams_opened: u64,
+}
+
+impl Tracker {
+  /// Keeps the statistics of `connection` available until it closes.
+  pub fn track(&self, connection: &quinn::Connection) {
+    self
+      .connections
+      .lock()
+      .expect("stats lock poisoned")
+      .entry(connection.stable_id())
+      .or_insert_with(|| Tracked {
+        connection: connection.clone(),
+        streams_opened: 0,
+      });
+  }
+
+  /// Counts a stream opened on `connection`, if it is tracked.
+  pub fn stream_opened(&self, connection: &quinn::Connection) {
+    let mut connections = self.connections.lock().expect("stats lock poisoned");
+    if let Some(tracked) = connections.get_mut(&connection.stable_id()) {
+      tracked.streams_opened += 1;
+    }
+  }
+
+  /// The open connection with id `id`, if any.
+  pub fn connection(&self, id: usize) -> Option<quinn::Connection> {
+    let connections = self.connections.lock().expect("stats lock poisoned");
+    let tracked = connections.get(&id)?;
+    Some(tracked.connection.clone()).filter(|connection| connection.close_reason().is_none())
+  }
+
+  /// Statistics of every open connection. Closed ones are forgotten.
+  pub fn snapshot(&self) -> Vec<Stats> {
+    let mut connections = self.connections.lock().expect("stats lock poisoned");
+    connections.retain(|_, tracked| tracked.connection.close_reason().is_none());
+    connections
+      .values()
+      .map(|tracked| Stats::of(&tracked.connection, tracked.streams_opened))
+      .collect()
+  }
+}
+
+/// Logs the statistics of every connection `tracker` holds each
+/// `interval`, until the tracker and all its clones are dropped.
+pub fn report(tracker: &Tracker, interval: Duration) -> impl Future<Output = ()> {
+  let connections = Arc::downgrade(&tracker.connections);
+  async move {
+    let mut ticks = tokio::time::interval(interval);
+    // The first tick completes at once, when there is nothing to report.
+    ticks.tick().await;
+    loop {
+      ticks.tick().await;
+      let tracker = match connections.upgrade() {
+        Some(connections) => Tracker { connections },
+        None => return,
+      };
+      for stats in tracker.snapshot() {
+        info!(
+          remote = %stats.remote,
+          bytes_sent = stats.bytes_sent,
+          bytes_received = stats.bytes_received,
+          packets_sent = stats.packets_sent,
+          packets_received = stats.packets_received,
+          rtt = ?stats.rtt,
+          loss = stats.loss(),
+          cwnd = stats.cwnd,
+          streams = stats.streams_opened,
+          "connection stats"
+        );
+      }
+    }
+  }
+}
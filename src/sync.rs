@@ -0,0 +1,142 @@
+//! Recursive directory sync.
+//!
+//! The client scans a local directory and sends its manifest with
+//! `SYNC /dir`. The server compares it with the files below `/dir` and
+//! answers with the paths that are missing or differ, which the client then
+//! uploads with concurrent `PUT` requests over the same connection.
+
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{integrity, mount::Mount, QvpnError, Result};
+
+/// Longest manifest the server accepts.
+pub const MAX_MANIFEST: u64 = 16 * 1024 * 1024;
+
+/// One file of a manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+  /// Path relative to the synced directory, with `/` separators.
+  pub path: String,
+  /// Size in bytes.
+  pub size: u64,
+  /// Modification time in seconds since the Unix epoch, if known.
+  pub modified: Option<u64>,
+  /// SHA-256 of the contents, as lowercase hex.
+  pub hash: String,
+}
+
+/// Files transferred by a sync.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+  /// Files in the local directory.
+  pub scanned: usize,
+  /// Files uploaded because they were missing or changed.
+  pub uploaded: usize,
+  /// Bytes uploaded.
+  pub bytes: u64,
+}
+
+/// Formats a request announcing a manifest of `len` bytes for `path`.
+pub fn sync_request(path: &str, len: usize) -> String {
+  format!(
+    "SYNC {} HTTP/3\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+    path, len
+  )
+}
+
+/// Lists every regular file below `dir`, sorted by path. Names that are not
+/// valid UTF-8 are skipped.
+pub async fn scan(dir: &Path) -> Result<Vec<FileEntry>> {
+  let mut entries = vec![];
+  let mut pending = vec![PathBuf::new()];
+  while let Some(relative) = pending.pop() {
+    let mut reader = tokio::fs::read_dir(dir.join(&relative)).await?;
+    while let Some(entry) = reader.next_entry().await? {
+      let name = match entry.file_name().into_string() {
+        Ok(name) => name,
+        Err(_) => continue,
+      };
+      let path = relative.join(&name);
+      let meta = entry.metadata().await?;
+      if meta.is_dir() {
+        pending.push(path);
+      } else if meta.is_file() {
+        entries.push(FileEntry {
+          path: path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/"),
+          size: meta.len(),
+          modified: meta
+            .modified()
+            .ok()
+            .and_then(|x| x.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|x| x.as_secs()),
+          hash: hash_file(&entry.path()).await?,
+        });
+      }
+    }
+  }
+  entries.sort_by(|a, b| a.path.cmp(&b.path));
+  Ok(entries)
+}
+
+/// Paths of `manifest` whose file below `dir` is missing or has a different
+/// size or hash. Files a symlink leads out of `confine` count as missing.
+pub async fn missing(
+  dir: &Path,
+  manifest: &[FileEntry],
+  confine: Option<&Mount>,
+) -> Result<Vec<String>> {
+  let mut missing = vec![];
+  for entry in manifest {
+    let path = resolve(dir, &entry.path)?;
+    if let Some(mount) = confine {
+      if !mount.contains(&path).await {
+        missing.push(entry.path.clone());
+        continue;
+      }
+    }
+    let same = match tokio::fs::metadata(&path).await {
+      Ok(meta) if meta.is_file() && meta.len() == entry.size => {
+        hash_file(&path).await? == entry.hash
+      }
+      _ => false,
+    };
+    if !same {
+      missing.push(entry.path.clone());
+    }
+  }
+  Ok(missing)
+}
+
+/// Joins a manifest path onto `dir`, rejecting anything but plain names.
+fn resolve(dir: &Path, path: &str) -> Result<PathBuf> {
+  let mut real_path = dir.to_path_buf();
+  for c in Path::new(path).components() {
+    match c {
+      Component::Normal(x) => real_path.push(x),
+      x => {
+        return Err(QvpnError::BadRequest(format!(
+          "illegal component in manifest path: {:?}",
+          x
+        )))
+      }
+    }
+  }
+  Ok(real_path)
+}
+
+/// SHA-256 of a file, as lowercase hex.
+pub(crate) async fn hash_file(path: &Path) -> Result<String> {
+  Ok(
+    integrity::hash_file(path)
+      .await?
+      .iter()
+      .map(|b| format!("{:02x}", b))
+      .collect(),
+  )
+}
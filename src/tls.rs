@@ -0,0 +1,366 @@
+//! Certificate helpers shared by the server and client.
+
+use std::{
+  convert::TryFrom,
+  fs, io,
+  path::{Path, PathBuf},
+  sync::{Arc, RwLock},
+};
+
+use ring::digest;
+use rustls::{
+  client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+  crypto::{self, CryptoProvider, WebPkiSupportedAlgorithms},
+  pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+  server::{ClientHello, ResolvesServerCert},
+  sign::CertifiedKey,
+  DigitallySignedStruct, SignatureScheme,
+};
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::{known_hosts::KnownHosts, QvpnError, Result};
+
+/// How a client decides whether to trust the server's certificate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Trust {
+  /// Certificate authorities from the operating system's trust store.
+  #[default]
+  Native,
+  /// Only the certificate authorities in this PEM or DER file.
+  Ca(PathBuf),
+  /// Accept any certificate. Only useful for testing.
+  Insecure,
+  /// Only a leaf certificate whose public key has one of these SHA-256
+  /// hashes, whoever signed it.
+  Pinned(Vec<[u8; 32]>),
+  /// Whatever public key a server presents the first time, recorded in
+  /// this `known_hosts` file, and only that key afterwards.
+  Tofu(PathBuf),
+}
+
+impl Trust {
+  /// A TLS 1.3 client configuration that trusts servers this way.
+  pub fn client_config(&self) -> Result<rustls::ClientConfig> {
+    let builder = rustls::ClientConfig::builder_with_provider(provider())
+      .with_protocol_versions(&[&rustls::version::TLS13])?;
+    let builder = match self {
+      Trust::Native => builder.with_root_certificates(native_roots()?),
+      Trust::Ca(path) => builder.with_root_certificates(load_roots(path)?),
+      Trust::Insecure => {
+        warn!("server certificates are not verified");
+        builder
+          .dangerous()
+          .with_custom_certificate_verifier(KeyVerifier::new(KeyPolicy::Any))
+      }
+      Trust::Pinned(pins) => builder
+        .dangerous()
+        .with_custom_certificate_verifier(KeyVerifier::new(KeyPolicy::Pinned(pins.clone()))),
+      Trust::Tofu(path) => builder
+        .dangerous()
+        .with_custom_certificate_verifier(KeyVerifier::new(KeyPolicy::Tofu(KnownHosts::new(path)))),
+    };
+    Ok(builder.with_no_client_auth())
+  }
+}
+
+/// A TLS 1.3 client configuration for connecting to other peers, which
+/// accepts any certificate: peers prove who they are with their
+/// [`Identity`](crate::identity::Identity) instead.
+pub fn peer_client_config() -> Result<rustls::ClientConfig> {
+  Ok(
+    rustls::ClientConfig::builder_with_provider(provider())
+      .with_protocol_versions(&[&rustls::version::TLS13])?
+      .dangerous()
+      .with_custom_certificate_verifier(KeyVerifier::new(KeyPolicy::Any))
+      .with_no_client_auth(),
+  )
+}
+
+/// The cryptography both sides of a connection use.
+pub fn provider() -> Arc<CryptoProvider> {
+  Arc::new(crypto::ring::default_provider())
+}
+
+/// ALPN protocol `connection` negotiated, once its handshake got that far.
+pub fn alpn_protocol(connection: &quinn::Connection) -> Option<Vec<u8>> {
+  connection
+    .handshake_data()?
+    .downcast::<quinn::crypto::rustls::HandshakeData>()
+    .ok()?
+    .protocol
+}
+
+/// Loads the operating system's trusted certificate authorities.
+pub fn native_roots() -> Result<rustls::RootCertStore> {
+  let native = rustls_native_certs::load_native_certs();
+  for err in &native.errors {
+    warn!("couldn't load some native trust roots: {}", err);
+  }
+  if native.certs.is_empty() {
+    if let Some(err) = native.errors.into_iter().next() {
+      return Err(io::Error::other(err).into());
+    }
+  }
+  let mut roots = rustls::RootCertStore::empty();
+  roots.add_parsable_certificates(native.certs);
+  Ok(roots)
+}
+
+/// Which server keys a [`KeyVerifier`] accepts, instead of checking who
+/// signed the certificate.
+#[derive(Debug)]
+enum KeyPolicy {
+  /// Any key at all.
+  Any,
+  /// Keys with one of these SPKI hashes.
+  Pinned(Vec<[u8; 32]>),
+  /// The key recorded for the server name, or any key for a new name.
+  Tofu(KnownHosts),
+}
+
+/// Accepts the server's key by its [`KeyPolicy`], and still checks that
+/// the server holds the private key.
+#[derive(Debug)]
+struct KeyVerifier {
+  policy: KeyPolicy,
+  algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl KeyVerifier {
+  fn new(policy: KeyPolicy) -> Arc<Self> {
+    Arc::new(KeyVerifier {
+      policy,
+      algorithms: provider().signature_verification_algorithms,
+    })
+  }
+}
+
+impl ServerCertVerifier for KeyVerifier {
+  fn verify_server_cert(
+    &self,
+    end_entity: &CertificateDer<'_>,
+    _intermediates: &[CertificateDer<'_>],
+    server_name: &ServerName<'_>,
+    _ocsp_response: &[u8],
+    _now: UnixTime,
+  ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+    let pin = || {
+      spki_hash(end_entity).ok_or(rustls::Error::InvalidCertificate(
+        rustls::CertificateError::BadEncoding,
+      ))
+    };
+    match &self.policy {
+      KeyPolicy::Any => {}
+      KeyPolicy::Pinned(pins) => {
+        let pin = pin()?;
+        if !pins.contains(&pin) {
+          return Err(rustls::Error::General(format!(
+            "server public key {} matches no pin",
+            encode_pin(&pin)
+          )));
+        }
+      }
+      KeyPolicy::Tofu(known_hosts) => {
+        known_hosts
+          .check(&server_name.to_str(), &encode_pin(&pin()?))
+          .map_err(|err| rustls::Error::General(err.to_string()))?;
+      }
+    }
+    Ok(ServerCertVerified::assertion())
+  }
+
+  fn verify_tls12_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+    crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+  }
+
+  fn verify_tls13_signature(
+    &self,
+    message: &[u8],
+    cert: &CertificateDer<'_>,
+    dss: &DigitallySignedStruct,
+  ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+    crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+  }
+
+  fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+    self.algorithms.supported_schemes()
+  }
+}
+
+/// SHA-256 of the DER encoded SubjectPublicKeyInfo of `cert`, the value a
+/// [`Trust::Pinned`] pin is compared against.
+pub fn spki_hash(cert: &CertificateDer<'_>) -> Option<[u8; 32]> {
+  let spki = subject_public_key_info(cert)?;
+  let mut hash = [0; 32];
+  hash.copy_from_slice(digest::digest(&digest::SHA256, spki).as_ref());
+  Some(hash)
+}
+
+/// Parses a pin given as 64 hex digits, optionally separated by colons.
+pub fn parse_pin(pin: &str) -> Result<[u8; 32]> {
+  let digits: String = pin.chars().filter(|&c| c != ':').collect();
+  let invalid =
+    || QvpnError::InvalidInput(format!("invalid pin `{}`, expected a SHA-256 in hex", pin));
+  if digits.len() != 64 || !digits.is_ascii() {
+    return Err(invalid());
+  }
+  let mut hash = [0; 32];
+  for (i, byte) in hash.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+  }
+  Ok(hash)
+}
+
+/// Formats a pin as lowercase hex, as accepted by [`parse_pin`].
+pub fn encode_pin(pin: &[u8; 32]) -> String {
+  pin.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Finds the SubjectPublicKeyInfo in a DER encoded certificate.
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+  let (_, certificate, _) = der_element(cert)?;
+  let (_, tbs, _) = der_element(certificate)?;
+  let mut rest = tbs;
+  // Skip the explicitly tagged version, if present.
+  if rest.first() == Some(&0xa0) {
+    rest = der_element(rest)?.2;
+  }
+  // Skip serial number, signature algorithm, issuer, validity and subject.
+  for _ in 0..5 {
+    rest = der_element(rest)?.2;
+  }
+  let (whole, _, _) = der_element(rest)?;
+  Some(whole)
+}
+
+/// Splits the first DER element off `input`, returning the whole element,
+/// its contents and what follows it.
+fn der_element(input: &[u8]) -> Option<(&[u8], &[u8], &[u8])> {
+  let first = *input.get(1)?;
+  let (len, header) = if first < 0x80 {
+    (first as usize, 2)
+  } else {
+    let octets = (first & 0x7f) as usize;
+    if octets == 0 || octets > 4 {
+      return None;
+    }
+    let len = input
+      .get(2..2 + octets)?
+      .iter()
+      .fold(0, |len, &b| (len << 8) | b as usize);
+    (len, 2 + octets)
+  };
+  let end = header.checked_add(len)?;
+  let whole = input.get(..end)?;
+  Some((whole, &whole[header..], &input[end..]))
+}
+
+/// Loads trusted certificates from a PEM or DER file.
+pub fn load_roots(path: &Path) -> Result<rustls::RootCertStore> {
+  let contents = fs::read(path)?;
+  let mut roots = rustls::RootCertStore::empty();
+  if path.extension().is_some_and(|x| x == "der") {
+    roots
+      .add(CertificateDer::from(contents))
+      .map_err(|err| QvpnError::InvalidInput(format!("{}: {}", path.display(), err)))?;
+  } else {
+    let certs = rustls_pemfile::certs(&mut &contents[..])
+      .collect::<io::Result<Vec<_>>>()
+      .map_err(|_| QvpnError::InvalidInput(format!("{}: malformed PEM", path.display())))?;
+    let (valid, _) = roots.add_parsable_certificates(certs);
+    if valid == 0 {
+      return Err(QvpnError::InvalidInput(format!(
+        "{}: no usable certificates",
+        path.display()
+      )));
+    }
+  }
+  Ok(roots)
+}
+
+/// SHA-256 fingerprint of a DER encoded certificate, as lowercase hex.
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+  digest::digest(&digest::SHA256, cert)
+    .as_ref()
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect()
+}
+
+/// Server certificate that can be replaced while the server runs, so new
+/// connections pick up a renewed certificate without a restart.
+#[derive(Debug)]
+pub struct CertResolver {
+  key: RwLock<Arc<CertifiedKey>>,
+  changed: Notify,
+}
+
+impl CertResolver {
+  /// Serves `chain`, signed with `key`.
+  pub fn new(chain: Vec<CertificateDer<'static>>, key: &PrivateKeyDer<'_>) -> Result<Arc<Self>> {
+    Ok(Arc::new(CertResolver {
+      key: RwLock::new(certified_key(chain, key)?),
+      changed: Notify::new(),
+    }))
+  }
+
+  /// Serves `chain` to connections accepted from now on.
+  pub fn set(&self, chain: Vec<CertificateDer<'static>>, key: &PrivateKeyDer<'_>) -> Result<()> {
+    let certified = certified_key(chain, key)?;
+    *self.key.write().expect("certificate lock poisoned") = certified;
+    self.changed.notify_waiters();
+    Ok(())
+  }
+
+  /// Completes the next time the certificate is [`CertResolver::set`].
+  pub async fn changed(&self) {
+    self.changed.notified().await;
+  }
+}
+
+impl ResolvesServerCert for CertResolver {
+  fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+    Some(self.key.read().expect("certificate lock poisoned").clone())
+  }
+}
+
+fn certified_key(
+  chain: Vec<CertificateDer<'static>>,
+  key: &PrivateKeyDer<'_>,
+) -> Result<Arc<CertifiedKey>> {
+  let key = crypto::ring::sign::any_supported_type(key)
+    .map_err(|_| QvpnError::InvalidInput("unsupported private key type".into()))?;
+  Ok(Arc::new(CertifiedKey::new(chain, key)))
+}
+
+/// Parses a certificate chain, in DER if `der` is set and PEM otherwise.
+pub fn parse_chain(contents: &[u8], der: bool) -> Result<Vec<CertificateDer<'static>>> {
+  if der {
+    return Ok(vec![CertificateDer::from(contents.to_vec())]);
+  }
+  let chain = rustls_pemfile::certs(&mut &contents[..])
+    .collect::<io::Result<Vec<_>>>()
+    .map_err(|_| QvpnError::InvalidInput("malformed certificate PEM".into()))?;
+  if chain.is_empty() {
+    return Err(QvpnError::InvalidInput("no certificates found".into()));
+  }
+  Ok(chain)
+}
+
+/// Parses a PKCS #8, PKCS #1 or SEC1 private key, in DER if `der` is set
+/// and PEM otherwise.
+pub fn parse_key(contents: &[u8], der: bool) -> Result<PrivateKeyDer<'static>> {
+  if der {
+    return PrivateKeyDer::try_from(contents.to_vec())
+      .map_err(|err| QvpnError::InvalidInput(err.into()));
+  }
+  rustls_pemfile::private_key(&mut &contents[..])
+    .map_err(|_| QvpnError::InvalidInput("malformed private key PEM".into()))?
+    .ok_or_else(|| QvpnError::InvalidInput("no private key found".into()))
+}
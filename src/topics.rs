@@ -0,0 +1,167 @@
+//! Publish/subscribe topics over the peer mesh.
+//!
+//! Peers tell their neighbours which topics they subscribe to with a
+//! [`Message::Subscribe`] when they connect and whenever that changes. A
+//! [`Message::Publish`] is sent to the neighbours subscribed to its topic,
+//! and each of them forwards it to its own subscribed neighbours, so a
+//! publication floods the subscribers of a topic connected through one
+//! another. Publications carry a random id; a node remembers the ids it has
+//! seen and drops repeats, so each is delivered and forwarded once.
+//!
+//! Signed publications are forwarded as they are, so subscribers can tell
+//! who published them however many hops away.
+
+use std::{
+  collections::{BTreeSet, HashMap, HashSet, VecDeque},
+  net::SocketAddr,
+  sync::Mutex,
+};
+
+use bytes::Bytes;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::{protocol::Message, QvpnError, Result};
+
+/// Publication ids remembered to suppress duplicates.
+pub const SEEN_CAPACITY: usize = 4096;
+
+/// Most topics recorded for one peer.
+pub const MAX_TOPICS: usize = 256;
+
+/// Longest topic name accepted, in bytes.
+pub const MAX_TOPIC_LEN: usize = 128;
+
+/// Subscriptions of a node and its neighbours.
+#[derive(Debug, Default)]
+pub struct Topics {
+  inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+  local: BTreeSet<String>,
+  remote: HashMap<SocketAddr, HashSet<String>>,
+  seen: HashSet<u64>,
+  /// Seen ids, oldest first.
+  order: VecDeque<u64>,
+}
+
+impl Topics {
+  /// Subscribes to `topic`, returning false if subscribed already.
+  pub fn subscribe(&self, topic: &str) -> bool {
+    self.inner.lock().unwrap().local.insert(topic.to_string())
+  }
+
+  /// Unsubscribes from `topic`, returning false if not subscribed.
+  pub fn unsubscribe(&self, topic: &str) -> bool {
+    self.inner.lock().unwrap().local.remove(topic)
+  }
+
+  /// Topics subscribed to, in order.
+  pub fn subscriptions(&self) -> Vec<String> {
+    let inner = self.inner.lock().unwrap();
+    inner.local.iter().cloned().collect()
+  }
+
+  /// Whether this node subscribes to `topic`.
+  pub fn is_subscribed(&self, topic: &str) -> bool {
+    self.inner.lock().unwrap().local.contains(topic)
+  }
+
+  /// Records the topics `peer` subscribes to, replacing earlier ones. Names
+  /// that are too long and topics past [`MAX_TOPICS`] are ignored.
+  pub fn set_remote(&self, peer: SocketAddr, topics: Vec<String>) {
+    let topics: HashSet<_> = topics
+      .into_iter()
+      .filter(|topic| topic.len() <= MAX_TOPIC_LEN)
+      .take(MAX_TOPICS)
+      .collect();
+    let mut inner = self.inner.lock().unwrap();
+    if topics.is_empty() {
+      inner.remote.remove(&peer);
+    } else {
+      inner.remote.insert(peer, topics);
+    }
+  }
+
+  /// Forgets the subscriptions of a disconnected peer.
+  pub fn forget(&self, peer: &SocketAddr) {
+    self.inner.lock().unwrap().remote.remove(peer);
+  }
+
+  /// Neighbours subscribed to `topic`.
+  pub fn subscribers(&self, topic: &str) -> Vec<SocketAddr> {
+    let inner = self.inner.lock().unwrap();
+    inner
+      .remote
+      .iter()
+      .filter(|(_, topics)| topics.contains(topic))
+      .map(|(peer, _)| *peer)
+      .collect()
+  }
+
+  /// Records the publication `id`, returning false if it was seen before.
+  pub fn first_seen(&self, id: u64) -> bool {
+    let mut inner = self.inner.lock().unwrap();
+    if !inner.seen.insert(id) {
+      return false;
+    }
+    inner.order.push_back(id);
+    if inner.order.len() > SEEN_CAPACITY {
+      if let Some(oldest) = inner.order.pop_front() {
+        inner.seen.remove(&oldest);
+      }
+    }
+    true
+  }
+}
+
+/// Checks that `topic` can be subscribed to.
+pub fn check_name(topic: &str) -> Result<()> {
+  if topic.is_empty() || topic.len() > MAX_TOPIC_LEN {
+    return Err(QvpnError::InvalidInput(format!(
+      "topic names are 1 to {} bytes",
+      MAX_TOPIC_LEN
+    )));
+  }
+  Ok(())
+}
+
+/// A random publication id.
+pub fn new_id() -> u64 {
+  let mut id = [0; 8];
+  SystemRandom::new()
+    .fill(&mut id)
+    .expect("system randomness available");
+  u64::from_be_bytes(id)
+}
+
+/// Id and topic of the publication in `msg`, which may be signed. A
+/// signed publication must verify.
+pub fn publication(msg: &Bytes) -> Option<(u64, String)> {
+  let mut messages = Message::decode(msg).ok()?;
+  if messages.len() != 1 {
+    return None;
+  }
+  let message = messages.remove(0);
+  let message = match message {
+    Message::Signed { ref body, .. } => {
+      // Look inside before checking the signature, which only
+      // publications need.
+      match Message::decode(&Bytes::copy_from_slice(body))
+        .ok()?
+        .as_slice()
+      {
+        [Message::Publish { .. }] => {}
+        _ => return None,
+      }
+      let (_, mut messages) = message.open().ok()?;
+      messages.remove(0)
+    }
+    message => message,
+  };
+  match message {
+    Message::Publish { id, topic, .. } => Some((id, topic)),
+    _ => None,
+  }
+}
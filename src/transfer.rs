@@ -0,0 +1,279 @@
+//! File transfer between peers.
+//!
+//! The sender offers a file with its name, size and SHA-256. Once the
+//! receiver accepts, naming the offset it already has, the sender streams
+//! the rest in [`Message::FileChunk`]s over a stream of its own. Received
+//! bytes go to a partial file named after the hash, so an interrupted
+//! transfer resumes where it stopped when the same file is offered again.
+
+use std::{
+  collections::HashMap,
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use tokio::{
+  fs,
+  io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+  sync::Mutex,
+};
+use tracing::{debug, info};
+
+use crate::{peer::PeerHandle, protocol::Message, sync::hash_file, QvpnError, Result};
+
+/// Bytes of file data per chunk.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A file offered by a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Offer {
+  /// File name, without directories.
+  pub name: String,
+  /// Size in bytes.
+  pub size: u64,
+  /// SHA-256 of the contents, as lowercase hex.
+  pub hash: String,
+}
+
+/// Offers made to and received from peers.
+#[derive(Debug)]
+pub struct Transfers {
+  dir: PathBuf,
+  /// Files offered to peers, by peer and hash.
+  outgoing: Mutex<HashMap<(SocketAddr, String), PathBuf>>,
+  /// Offers received, by peer and hash, and whether they were accepted.
+  incoming: Mutex<HashMap<(SocketAddr, String), (Offer, bool)>>,
+}
+
+impl Transfers {
+  /// Creates the transfer state, saving received files to `dir`.
+  pub fn new(dir: impl Into<PathBuf>) -> Arc<Self> {
+    Arc::new(Transfers {
+      dir: dir.into(),
+      outgoing: Mutex::default(),
+      incoming: Mutex::default(),
+    })
+  }
+
+  /// Offers the file at `path` to `peer`.
+  pub async fn offer(&self, node: &PeerHandle, peer: SocketAddr, path: &Path) -> Result<Offer> {
+    let name = path
+      .file_name()
+      .map(|x| x.to_string_lossy().into_owned())
+      .ok_or_else(|| QvpnError::InvalidInput(format!("{} is not a file", path.display())))?;
+    let size = fs::metadata(path).await?.len();
+    let hash = hash_file(path).await?;
+    let offer = Offer { name, size, hash };
+    self
+      .outgoing
+      .lock()
+      .await
+      .insert((peer, offer.hash.clone()), path.to_owned());
+    let message = Message::FileOffer {
+      name: offer.name.clone(),
+      size: offer.size,
+      hash: offer.hash.clone(),
+    };
+    node.send(&message, &peer).await?;
+    Ok(offer)
+  }
+
+  /// Records an offer from `peer` until it is accepted or rejected.
+  pub async fn offered(&self, peer: SocketAddr, offer: Offer) -> Result<()> {
+    if !is_plain_name(&offer.name) {
+      return Err(QvpnError::Protocol(format!(
+        "illegal file name {:?}",
+        offer.name
+      )));
+    }
+    let is_hex = |c| matches!(c, b'0'..=b'9' | b'a'..=b'f');
+    if offer.hash.len() != 64 || !offer.hash.bytes().all(is_hex) {
+      return Err(QvpnError::Protocol(format!(
+        "illegal file hash {:?}",
+        offer.hash
+      )));
+    }
+    let mut incoming = self.incoming.lock().await;
+    incoming.insert((peer, offer.hash.clone()), (offer, false));
+    Ok(())
+  }
+
+  /// Offers received and not yet answered.
+  pub async fn pending(&self) -> Vec<(SocketAddr, Offer)> {
+    let incoming = self.incoming.lock().await;
+    incoming
+      .iter()
+      .filter(|(_, (_, accepted))| !accepted)
+      .map(|((peer, _), (offer, _))| (*peer, offer.clone()))
+      .collect()
+  }
+
+  /// Accepts the pending offer whose hash starts with `id`, resuming from
+  /// any partial file left by an earlier attempt.
+  pub async fn accept(&self, node: &PeerHandle, id: &str) -> Result<(SocketAddr, Offer)> {
+    let (peer, offer) = self.take_pending(id, true).await?;
+    let offset = match fs::metadata(self.partial_path(&offer)).await {
+      Ok(metadata) => metadata.len().min(offer.size),
+      Err(_) => 0,
+    };
+    info!(%peer, name = %offer.name, offset, "accepting file");
+    let message = Message::FileAccept {
+      hash: offer.hash.clone(),
+      offset,
+    };
+    node.send(&message, &peer).await?;
+    if offset == offer.size {
+      self.complete(peer, &offer).await?;
+    }
+    Ok((peer, offer))
+  }
+
+  /// Rejects the pending offer whose hash starts with `id`.
+  pub async fn reject(&self, node: &PeerHandle, id: &str) -> Result<(SocketAddr, Offer)> {
+    let (peer, offer) = self.take_pending(id, false).await?;
+    let message = Message::FileReject {
+      hash: offer.hash.clone(),
+    };
+    node.send(&message, &peer).await?;
+    Ok((peer, offer))
+  }
+
+  /// Streams a file `peer` accepted, starting at `offset`.
+  pub async fn send(
+    &self,
+    node: &PeerHandle,
+    peer: SocketAddr,
+    hash: &str,
+    offset: u64,
+  ) -> Result<()> {
+    let path = self
+      .outgoing
+      .lock()
+      .await
+      .remove(&(peer, hash.to_string()))
+      .ok_or_else(|| QvpnError::Protocol(format!("no file offered with hash {}", hash)))?;
+    let mut file = fs::File::open(&path).await?;
+    file.seek(std::io::SeekFrom::Start(offset)).await?;
+    let (mut send, _recv) = node.open_stream(&peer).await?;
+    let mut offset = offset;
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+      let len = file.read(&mut buf).await?;
+      if len == 0 {
+        break;
+      }
+      let chunk = Message::FileChunk {
+        hash: hash.to_string(),
+        offset,
+        data: buf[..len].to_vec(),
+      };
+      send.send_message(chunk.encode()).await?;
+      offset += len as u64;
+    }
+    send.finish()?;
+    info!(%peer, path = %path.display(), bytes = offset, "file sent");
+    Ok(())
+  }
+
+  /// Writes a chunk of an accepted file, returning the path of the file once
+  /// it is complete and its hash checks out.
+  pub async fn receive(
+    &self,
+    peer: SocketAddr,
+    hash: &str,
+    offset: u64,
+    data: &[u8],
+  ) -> Result<Option<PathBuf>> {
+    let offer = match self.incoming.lock().await.get(&(peer, hash.to_string())) {
+      Some((offer, true)) => offer.clone(),
+      _ => {
+        return Err(QvpnError::Protocol(format!(
+          "chunk of a file not accepted: {}",
+          hash
+        )))
+      }
+    };
+    let partial = self.partial_path(&offer);
+    let mut file = fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&partial)
+      .await?;
+    let len = file.metadata().await?.len();
+    if offset != len || len + data.len() as u64 > offer.size {
+      return Err(QvpnError::Protocol(format!(
+        "chunk at {} doesn't continue {} of {} bytes",
+        offset, len, offer.size
+      )));
+    }
+    file.write_all(data).await?;
+    file.flush().await?;
+    debug!(%peer, name = %offer.name, offset, len = data.len(), "chunk received");
+    if len + data.len() as u64 == offer.size {
+      return self.complete(peer, &offer).await.map(Some);
+    }
+    Ok(None)
+  }
+
+  /// Checks a fully received file and moves it into place.
+  async fn complete(&self, peer: SocketAddr, offer: &Offer) -> Result<PathBuf> {
+    self
+      .incoming
+      .lock()
+      .await
+      .remove(&(peer, offer.hash.clone()));
+    let partial = self.partial_path(offer);
+    if hash_file(&partial).await? != offer.hash {
+      fs::remove_file(&partial).await?;
+      return Err(QvpnError::Protocol(format!(
+        "{} doesn't match its hash",
+        offer.name
+      )));
+    }
+    let path = self.dir.join(&offer.name);
+    fs::rename(&partial, &path).await?;
+    info!(%peer, path = %path.display(), "file received");
+    Ok(path)
+  }
+
+  /// Removes the pending offer whose hash starts with `id`, or marks it
+  /// accepted.
+  async fn take_pending(&self, id: &str, accept: bool) -> Result<(SocketAddr, Offer)> {
+    let mut incoming = self.incoming.lock().await;
+    let mut matches = incoming
+      .iter()
+      .filter(|((_, hash), (_, accepted))| !accepted && hash.starts_with(id))
+      .map(|(key, _)| key.clone());
+    let key = match (matches.next(), matches.next()) {
+      (Some(key), None) => key,
+      (None, _) => return Err(QvpnError::InvalidInput(format!("no offer `{}`", id))),
+      (Some(_), Some(_)) => {
+        return Err(QvpnError::InvalidInput(format!(
+          "`{}` matches several offers",
+          id
+        )))
+      }
+    };
+    let offer = if accept {
+      let entry = incoming.get_mut(&key).expect("offer exists");
+      entry.1 = true;
+      entry.0.clone()
+    } else {
+      incoming.remove(&key).expect("offer exists").0
+    };
+    Ok((key.0, offer))
+  }
+
+  /// Where the bytes of `offer` are collected until it is complete.
+  fn partial_path(&self, offer: &Offer) -> PathBuf {
+    let prefix = &offer.hash[..offer.hash.len().min(16)];
+    self.dir.join(format!(".{}.{}.part", offer.name, prefix))
+  }
+}
+
+/// Whether `name` is a plain file name that can't escape the download
+/// directory.
+fn is_plain_name(name: &str) -> bool {
+  !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0'])
+}
@@ -0,0 +1,1010 @@
+//! Layer 3 tunnelling of IP packets over QUIC datagrams.
+//!
+//! Packets read from a local TUN interface are sent as unreliable QUIC
+//! datagrams framed as [`Kind::Packet`]; packets received from the remote side
+//! are written back to the interface unchanged. Packets too large for a
+//! datagram on the path are sent in [`fragment`]s.
+//!
+//! A server may instead carry Ethernet frames through a TAP interface, which
+//! it can add to a bridge to join clients to an existing LAN segment. The
+//! [`Router`] then switches frames by MAC address, learning the addresses
+//! behind each client from the frames it sends and flooding broadcasts to
+//! everyone.
+
+use std::{
+  collections::HashMap,
+  convert::TryInto,
+  io,
+  net::{IpAddr, Ipv4Addr, Ipv6Addr},
+  path::PathBuf,
+  sync::Arc,
+  time::Instant,
+};
+
+use bytes::Bytes;
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::{
+  auth::Logins,
+  bond::Reorder,
+  datagram::{self, Frame, Kind},
+  egress::Policy,
+  fragment::{self, Reassembler},
+  lease::Ipv4Net,
+  net,
+  site::Firewall,
+  QvpnError, Result,
+};
+
+/// Default interface MTU.
+///
+/// QUIC datagrams carry at most ~1200 bytes on an unprobed path, so the
+/// interface MTU is kept below that once packet and frame overhead is taken
+/// into account.
+pub const DEFAULT_MTU: u16 = 1150;
+
+/// Settings for a TUN interface.
+#[derive(Debug, Clone)]
+pub struct TunConfig {
+  /// Interface name, e.g. `qvpn0`.
+  pub name: String,
+  /// Address assigned to the interface.
+  pub address: Ipv4Addr,
+  /// Netmask of the tunnel subnet.
+  pub netmask: Ipv4Addr,
+  /// Interface MTU.
+  pub mtu: u16,
+  /// Descriptor of an interface another program opened, used instead of
+  /// creating one. Only supported on Unix.
+  pub fd: Option<i32>,
+  /// Unix socket of a program that opens the interface for each lease and
+  /// sends its descriptor back, like Android's `VpnService`.
+  pub fd_socket: Option<PathBuf>,
+  /// Carry Ethernet frames through a TAP interface instead of IP packets.
+  /// Only supported on Linux, unless the interface is provided.
+  pub tap: bool,
+  /// Bridge to add a TAP interface to, joining the tunnel to that LAN
+  /// segment. The interface then takes no address of its own.
+  pub bridge: Option<String>,
+  /// Masquerade packets coming out of the interface with this firewall.
+  /// See [`site`](crate::site).
+  pub masquerade: Option<Firewall>,
+  /// Block what the host sends other than through the interface and to
+  /// the tunnel's servers with this firewall, until the tunnel is torn
+  /// down. See [`kill_switch`](crate::kill_switch).
+  pub kill_switch: Option<Firewall>,
+}
+
+impl Default for TunConfig {
+  fn default() -> Self {
+    Self {
+      name: "qvpn0".into(),
+      address: Ipv4Addr::new(10, 8, 0, 1),
+      netmask: Ipv4Addr::new(255, 255, 255, 0),
+      mtu: DEFAULT_MTU,
+      fd: None,
+      fd_socket: None,
+      tap: false,
+      bridge: None,
+      masquerade: None,
+      kill_switch: None,
+    }
+  }
+}
+
+#[cfg(unix)]
+pub mod fd;
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(windows)]
+pub(crate) mod windows;
+
+/// Length of an Ethernet header with a VLAN tag, which a TAP interface
+/// reads on top of the MTU.
+const ETHERNET_HEADER_LEN: usize = 18;
+
+/// An Ethernet MAC address.
+pub type Mac = [u8; 6];
+
+/// An open TUN or TAP interface.
+pub struct Tun {
+  inner: Device,
+  mtu: u16,
+  tap: bool,
+}
+
+enum Device {
+  #[cfg(target_os = "linux")]
+  Created(tokio_tun::Tun),
+  #[cfg(target_os = "macos")]
+  Created(macos::Device),
+  #[cfg(windows)]
+  Created(windows::Device),
+  #[cfg(unix)]
+  Provided(fd::Device),
+}
+
+impl Tun {
+  /// Brings up the interface described by `config`, creating it unless
+  /// `config` gives the descriptor of one already open.
+  pub fn open(config: &TunConfig) -> Result<Tun> {
+    if let Some(fd) = config.fd {
+      #[cfg(unix)]
+      return Tun::from_fd(fd::duplicate(fd)?, config);
+      #[cfg(not(unix))]
+      return Err(QvpnError::Unsupported(format!(
+        "cannot use TUN descriptor {} on this platform",
+        fd
+      )));
+    }
+    if config.tap && !cfg!(target_os = "linux") {
+      return Err(QvpnError::Unsupported(
+        "TAP interfaces are only supported on Linux".into(),
+      ));
+    }
+    Ok(Tun {
+      inner: create(config)?,
+      mtu: config.mtu,
+      tap: config.tap,
+    })
+  }
+
+  /// Takes over the interface another program opened as `fd`, already
+  /// configured with the address, routes and resolvers of the lease.
+  #[cfg(unix)]
+  pub fn from_fd(fd: std::os::unix::io::OwnedFd, config: &TunConfig) -> Result<Tun> {
+    Ok(Tun {
+      inner: Device::Provided(fd::Device::new(fd, config.name.clone())?),
+      mtu: config.mtu,
+      tap: config.tap,
+    })
+  }
+
+  /// Whether another program opened the interface, which then also takes
+  /// care of its routes and resolvers.
+  pub fn is_provided(&self) -> bool {
+    match self.inner {
+      #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+      Device::Created(_) => false,
+      #[cfg(unix)]
+      Device::Provided(_) => true,
+    }
+  }
+
+  /// Name of the interface.
+  pub fn name(&self) -> &str {
+    match self.inner {
+      #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+      Device::Created(ref device) => device.name(),
+      #[cfg(unix)]
+      Device::Provided(ref device) => device.name(),
+    }
+  }
+
+  /// Interface MTU.
+  pub fn mtu(&self) -> u16 {
+    self.mtu
+  }
+
+  /// MTU of the packets, or frames' payloads, `connection` carries: the
+  /// interface MTU, or less if its path fits no more in a datagram.
+  pub fn mtu_for(&self, connection: &quinn::Connection) -> u16 {
+    path_mtu(connection, self.tap).map_or(self.mtu, |mtu| mtu.min(self.mtu))
+  }
+
+  /// Sets the interface MTU to `mtu`, at most the MTU it was opened with,
+  /// which packet buffers are sized for. Returns the MTU set.
+  pub fn set_mtu(&self, mtu: u16) -> Result<u16> {
+    let mtu = mtu.min(self.mtu);
+    match self.inner {
+      #[cfg(target_os = "linux")]
+      Device::Created(ref device) => {
+        ip(&["link", "set", "dev", device.name(), "mtu", &mtu.to_string()])?
+      }
+      #[cfg(any(target_os = "macos", windows))]
+      Device::Created(ref device) => device.set_mtu(mtu)?,
+      #[cfg(unix)]
+      Device::Provided(_) => {
+        return Err(QvpnError::Unsupported(
+          "the MTU of a provided interface is up to the program that opened it".into(),
+        ))
+      }
+    }
+    Ok(mtu)
+  }
+
+  /// Whether the interface carries Ethernet frames rather than IP packets.
+  pub fn is_tap(&self) -> bool {
+    self.tap
+  }
+
+  /// Longest packet, or frame of a TAP interface, read from the interface.
+  pub fn max_len(&self) -> usize {
+    match self.tap {
+      true => self.mtu as usize + ETHERNET_HEADER_LEN,
+      false => self.mtu as usize,
+    }
+  }
+
+  /// Reads one IP packet, or Ethernet frame, from the interface.
+  pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+    match self.inner {
+      #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+      Device::Created(ref device) => device.recv(buf).await,
+      #[cfg(unix)]
+      Device::Provided(ref device) => device.recv(buf).await,
+    }
+  }
+
+  /// Writes one IP packet, or Ethernet frame, to the interface.
+  pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+    match self.inner {
+      #[cfg(target_os = "linux")]
+      Device::Created(ref device) => device.send_all(packet).await,
+      #[cfg(any(target_os = "macos", windows))]
+      Device::Created(ref device) => device.send(packet).await,
+      #[cfg(unix)]
+      Device::Provided(ref device) => device.send(packet).await,
+    }
+  }
+}
+
+/// Creates and brings up the interface described by `config`, and adds it
+/// to its bridge if it has one.
+///
+/// Requires `CAP_NET_ADMIN`.
+#[cfg(target_os = "linux")]
+fn create(config: &TunConfig) -> Result<Device> {
+  let mut builder = tokio_tun::Tun::builder()
+    .name(&config.name)
+    .mtu(config.mtu as i32)
+    .up();
+  if config.tap {
+    builder = builder.tap();
+  }
+  if config.bridge.is_none() {
+    builder = builder.address(config.address).netmask(config.netmask);
+  }
+  let inner = builder
+    .build()
+    .map_err(tun_error)?
+    .pop()
+    .ok_or_else(|| QvpnError::Unsupported("no TUN queue created".into()))?;
+  if let Some(bridge) = &config.bridge {
+    ip(&["link", "set", "dev", inner.name(), "master", bridge])?;
+  }
+  Ok(Device::Created(inner))
+}
+
+/// Creates and brings up the interface described by `config`. The
+/// interface is named `utun<N>` whatever `config` asks for, unless it asks
+/// for such a name.
+///
+/// Requires root.
+#[cfg(target_os = "macos")]
+fn create(config: &TunConfig) -> Result<Device> {
+  Ok(Device::Created(macos::Device::open(config)?))
+}
+
+/// Creates and brings up the interface described by `config`.
+///
+/// Requires Administrator rights and `wintun.dll`, which is loaded from
+/// the path in `QVPN_WINTUN`, or else found next to the executable or on
+/// the system search path.
+#[cfg(windows)]
+fn create(config: &TunConfig) -> Result<Device> {
+  Ok(Device::Created(windows::Device::open(config)?))
+}
+
+/// Creates and brings up the interface described by `config`.
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn create(_config: &TunConfig) -> Result<Device> {
+  Err(QvpnError::Unsupported(
+    "TUN devices are not supported on this platform".into(),
+  ))
+}
+
+#[cfg(target_os = "linux")]
+fn tun_error(err: tokio_tun::Error) -> QvpnError {
+  match err {
+    tokio_tun::Error::IoError(err) => err.into(),
+    tokio_tun::Error::NixError(err) => io::Error::from_raw_os_error(err as i32).into(),
+  }
+}
+
+/// Networks that together cover every IPv4 address. Routing them through a
+/// tunnel overrides the default route without replacing it.
+pub const FULL_TUNNEL: [Ipv4Net; 2] = [Ipv4Net::half(0), Ipv4Net::half(1)];
+
+/// Routes `net` through the interface named `dev`.
+#[cfg(target_os = "linux")]
+pub fn add_route(dev: &str, net: Ipv4Net) -> Result<()> {
+  ip(&["route", "replace", &net.to_string(), "dev", dev])
+}
+
+/// Routes `net` through the interface named `dev`.
+#[cfg(target_os = "macos")]
+pub fn add_route(dev: &str, net: Ipv4Net) -> Result<()> {
+  macos::add_route(dev, net)
+}
+
+/// Routes `net` through the interface named `dev`.
+#[cfg(windows)]
+pub fn add_route(dev: &str, net: Ipv4Net) -> Result<()> {
+  windows::add_route(dev, net)
+}
+
+/// Routes `net` through the interface named `dev`.
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn add_route(_dev: &str, net: Ipv4Net) -> Result<()> {
+  Err(QvpnError::Unsupported(format!(
+    "cannot install route {} on this platform",
+    net
+  )))
+}
+
+/// Removes the route for `net` through the interface named `dev`.
+#[cfg(target_os = "linux")]
+pub fn delete_route(dev: &str, net: Ipv4Net) -> Result<()> {
+  ip(&["route", "del", &net.to_string(), "dev", dev])
+}
+
+/// Removes the route for `net` through the interface named `dev`.
+#[cfg(target_os = "macos")]
+pub fn delete_route(dev: &str, net: Ipv4Net) -> Result<()> {
+  macos::delete_route(dev, net)
+}
+
+/// Removes the route for `net` through the interface named `dev`.
+#[cfg(windows)]
+pub fn delete_route(dev: &str, net: Ipv4Net) -> Result<()> {
+  windows::delete_route(dev, net)
+}
+
+/// Removes the route for `net` through the interface named `dev`.
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn delete_route(_dev: &str, net: Ipv4Net) -> Result<()> {
+  Err(QvpnError::Unsupported(format!(
+    "cannot remove route {} on this platform",
+    net
+  )))
+}
+
+/// A host route keeping traffic to one address off the tunnel, removed when
+/// dropped.
+#[derive(Debug)]
+pub struct BypassRoute {
+  net: Ipv4Net,
+  /// Index of the interface the route goes through.
+  #[cfg(windows)]
+  interface: String,
+}
+
+impl BypassRoute {
+  /// Pins `addr` to the route it takes now, so the tunnel's own packets to
+  /// the server don't loop back into a tunnel routing everything.
+  #[cfg(target_os = "linux")]
+  pub fn new(addr: Ipv4Addr) -> Result<Self> {
+    let output = std::process::Command::new("ip")
+      .args(["-4", "route", "get", &addr.to_string()])
+      .output()?;
+    if !output.status.success() {
+      return Err(QvpnError::Io(io::Error::other(format!(
+        "ip route get {} failed: {}",
+        addr, output.status
+      ))));
+    }
+    let route = String::from_utf8_lossy(&output.stdout);
+    let mut words = route.split_whitespace();
+    let (mut via, mut dev) = (None, None);
+    while let Some(word) = words.next() {
+      match word {
+        "via" => via = words.next(),
+        "dev" => dev = words.next(),
+        _ => {}
+      }
+    }
+    let dev =
+      dev.ok_or_else(|| QvpnError::Io(io::Error::other(format!("no route to {}", addr))))?;
+    let net = Ipv4Net::new(addr, 32).expect("/32 is a valid prefix");
+    let net_str = net.to_string();
+    let mut args = vec!["route", "replace", &net_str];
+    if let Some(via) = via {
+      args.extend(["via", via]);
+    }
+    args.extend(["dev", dev]);
+    ip(&args)?;
+    Ok(BypassRoute { net })
+  }
+
+  /// Pins `addr` to the route it takes now.
+  #[cfg(target_os = "macos")]
+  pub fn new(addr: Ipv4Addr) -> Result<Self> {
+    macos::pin_route(addr)?;
+    Ok(BypassRoute {
+      net: Ipv4Net::new(addr, 32).expect("/32 is a valid prefix"),
+    })
+  }
+
+  /// Pins `addr` to the route it takes now.
+  #[cfg(windows)]
+  pub fn new(addr: Ipv4Addr) -> Result<Self> {
+    let interface = windows::pin_route(addr)?;
+    Ok(BypassRoute {
+      net: Ipv4Net::new(addr, 32).expect("/32 is a valid prefix"),
+      interface,
+    })
+  }
+
+  /// Pins `addr` to the route it takes now.
+  #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+  pub fn new(addr: Ipv4Addr) -> Result<Self> {
+    Err(QvpnError::Unsupported(format!(
+      "cannot pin the route to {} on this platform",
+      addr
+    )))
+  }
+}
+
+impl Drop for BypassRoute {
+  fn drop(&mut self) {
+    #[cfg(target_os = "linux")]
+    if let Err(err) = ip(&["route", "del", &self.net.to_string()]) {
+      warn!(net = %self.net, "couldn't remove bypass route: {}", err);
+    }
+    #[cfg(target_os = "macos")]
+    if let Err(err) = macos::unpin_route(self.net.addr()) {
+      warn!(net = %self.net, "couldn't remove bypass route: {}", err);
+    }
+    #[cfg(windows)]
+    if let Err(err) = windows::delete_route(&self.interface, self.net) {
+      warn!(net = %self.net, "couldn't remove bypass route: {}", err);
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn ip(args: &[&str]) -> Result<()> {
+  let status = std::process::Command::new("ip").args(args).status()?;
+  if !status.success() {
+    return Err(QvpnError::Io(io::Error::other(format!(
+      "ip {} failed: {}",
+      args.join(" "),
+      status
+    ))));
+  }
+  Ok(())
+}
+
+/// Source address of an IPv4 or IPv6 packet.
+pub fn source(packet: &[u8]) -> Option<IpAddr> {
+  match packet.first()? >> 4 {
+    4 if packet.len() >= 20 => Some(IpAddr::V4(ipv4_at(packet, 12))),
+    6 if packet.len() >= 40 => Some(IpAddr::V6(ipv6_at(packet, 8))),
+    _ => None,
+  }
+}
+
+/// Destination address of an IPv4 or IPv6 packet.
+pub fn destination(packet: &[u8]) -> Option<IpAddr> {
+  match packet.first()? >> 4 {
+    4 if packet.len() >= 20 => Some(IpAddr::V4(ipv4_at(packet, 16))),
+    6 if packet.len() >= 40 => Some(IpAddr::V6(ipv6_at(packet, 24))),
+    _ => None,
+  }
+}
+
+fn ipv4_at(packet: &[u8], at: usize) -> Ipv4Addr {
+  Ipv4Addr::new(packet[at], packet[at + 1], packet[at + 2], packet[at + 3])
+}
+
+fn ipv6_at(packet: &[u8], at: usize) -> Ipv6Addr {
+  let mut octets = [0; 16];
+  octets.copy_from_slice(&packet[at..at + 16]);
+  Ipv6Addr::from(octets)
+}
+
+/// Destination MAC address of an Ethernet frame.
+pub fn mac_destination(frame: &[u8]) -> Option<Mac> {
+  frame.get(..6)?.try_into().ok()
+}
+
+/// Source MAC address of an Ethernet frame.
+pub fn mac_source(frame: &[u8]) -> Option<Mac> {
+  frame.get(6..12)?.try_into().ok()
+}
+
+/// Whether `mac` addresses a group of stations, like the broadcast address.
+fn is_group(mac: &Mac) -> bool {
+  mac[0] & 1 == 1
+}
+
+/// Largest packet, or payload of an Ethernet frame if `tap`, that fits in
+/// one datagram on the path of `connection` as far as its path MTU
+/// discovery has found.
+pub fn path_mtu(connection: &quinn::Connection, tap: bool) -> Option<u16> {
+  let max = datagram::max_payload(connection)?;
+  let max = match tap {
+    true => max.saturating_sub(ETHERNET_HEADER_LEN),
+    false => max,
+  };
+  Some(max.min(u16::MAX as usize) as u16)
+}
+
+/// Lowers the maximum segment size a TCP SYN in `packet` offers to what
+/// fits in an `mtu` sized packet, fixing up the checksum. Returns whether
+/// it did.
+pub fn clamp_mss(packet: &mut [u8], mtu: u16) -> bool {
+  let (at, tcp) = match mss_option(packet) {
+    Some(found) => found,
+    None => return false,
+  };
+  let max = mtu.saturating_sub(tcp as u16 + 20);
+  let mss = u16::from_be_bytes([packet[at], packet[at + 1]]);
+  if mss <= max {
+    return false;
+  }
+  packet[at..at + 2].copy_from_slice(&max.to_be_bytes());
+  // Updated incrementally, as in RFC 1624: HC' = ~(~HC + ~m + m').
+  let check = tcp + 16;
+  let old = u16::from_be_bytes([packet[check], packet[check + 1]]);
+  let mut sum = u32::from(!old) + u32::from(!mss) + u32::from(max);
+  while sum > 0xffff {
+    sum = (sum & 0xffff) + (sum >> 16);
+  }
+  packet[check..check + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+  true
+}
+
+/// Offset of the value of the MSS option of a TCP SYN in `packet`, an
+/// IPv4 or IPv6 packet, along with the offset of the TCP header.
+fn mss_option(packet: &[u8]) -> Option<(usize, usize)> {
+  const TCP: u8 = 6;
+  const SYN: u8 = 0x02;
+  let tcp = match packet.first()? >> 4 {
+    // Only the first fragment carries the TCP header.
+    4 if packet.len() >= 20
+      && packet[9] == TCP
+      && u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff == 0 =>
+    {
+      usize::from(packet[0] & 0x0f) * 4
+    }
+    6 if packet.len() >= 40 && packet[6] == TCP => 40,
+    _ => return None,
+  };
+  let header = packet.get(tcp..tcp + 20)?;
+  if header[13] & SYN == 0 {
+    return None;
+  }
+  let options = packet.get(tcp + 20..tcp + usize::from(header[12] >> 4) * 4)?;
+  let mut i = 0;
+  while i < options.len() {
+    match options[i] {
+      // End of the options.
+      0 => return None,
+      // No-op padding.
+      1 => i += 1,
+      2 if options.get(i + 1) == Some(&4) && i + 4 <= options.len() => {
+        return Some((tcp + 20 + i + 2, tcp))
+      }
+      _ => match options.get(i + 1) {
+        Some(&len) if len >= 2 => i += usize::from(len),
+        _ => return None,
+      },
+    }
+  }
+  None
+}
+
+/// Sends `packet` to the remote side as a single datagram.
+///
+/// Packets that exceed the current maximum datagram size are sent in
+/// [`fragment`]s instead.
+pub fn send_packet(connection: &quinn::Connection, packet: &[u8]) -> Result<()> {
+  send_frame(connection, Frame::packet(packet.to_vec()))
+}
+
+/// Sends a tunnel packet framed as `frame`, in fragments if it is too large
+/// for one datagram, or drops it if it takes too many.
+pub fn send_frame(connection: &quinn::Connection, frame: Frame) -> Result<()> {
+  let packet = &frame.payload;
+  let max = datagram::max_payload(connection)
+    .ok_or_else(|| QvpnError::Unsupported("peer does not support datagrams".into()))?;
+  if packet.len() > max {
+    match fragment::split(&frame, max) {
+      Some(fragments) => {
+        for fragment in fragments {
+          connection.send_datagram(fragment.encode())?;
+        }
+      }
+      None => debug!(len = packet.len(), max, "dropping oversized packet"),
+    }
+    return Ok(());
+  }
+  connection.send_datagram(frame.encode())?;
+  Ok(())
+}
+
+/// Forwards packets between `tun` and a single connection until either side
+/// fails.
+pub async fn pump(tun: Arc<Tun>, connection: quinn::Connection) -> Result<()> {
+  let outbound = async {
+    let mut buf = vec![0; tun.max_len()];
+    loop {
+      let len = tun.recv(&mut buf).await?;
+      send_packet(&connection, &buf[..len])?;
+    }
+  };
+  let inbound = async {
+    let mut reassembler = Reassembler::default();
+    loop {
+      match Frame::decode(connection.read_datagram().await?) {
+        Some(Frame {
+          kind: Kind::Packet,
+          payload,
+          ..
+        }) => tun.send(&payload).await?,
+        Some(Frame {
+          kind: Kind::Fragment,
+          payload,
+          ..
+        }) => {
+          if let Some(packet) = reassembler.push(payload) {
+            tun.send(&packet).await?;
+          }
+        }
+        _ => debug!("ignoring non-packet datagram"),
+      }
+    }
+  };
+  tokio::select! {
+    res = outbound => res,
+    res = inbound => res,
+  }
+}
+
+/// Maps tunnel addresses to the connections that own them, so a server can
+/// fan packets read from its interface out to many clients.
+#[derive(Clone, Default)]
+pub struct Router {
+  routes: Arc<Mutex<HashMap<IpAddr, Route>>>,
+  /// Tunnel addresses of the clients behind which MAC addresses were seen,
+  /// when the interface carries Ethernet frames.
+  macs: Arc<Mutex<HashMap<Mac, IpAddr>>>,
+  /// Networks behind site gateways, with the gateway's tunnel address.
+  networks: Arc<Mutex<Vec<(Ipv4Net, IpAddr)>>>,
+  /// Whether to clamp the MSS of TCP SYNs.
+  clamp: bool,
+  /// Where clients may send packets, by the users logged in on their
+  /// connections.
+  egress: Arc<Policy>,
+  logins: Logins,
+}
+
+/// The connections carrying the packets of one tunnel address.
+struct Route {
+  /// The connection that leased the address, then any that joined it as
+  /// further paths of a bonded uplink.
+  paths: Vec<quinn::Connection>,
+  /// Puts packets arriving over several paths back in order.
+  reorder: Reorder,
+  /// Secret the other paths join with, once asked for.
+  token: Option<u64>,
+}
+
+impl Route {
+  fn has_path(&self, connection: &quinn::Connection) -> bool {
+    let id = connection.stable_id();
+    self.paths.iter().any(|path| path.stable_id() == id)
+  }
+
+  /// The path with the lowest round-trip time.
+  fn fastest(&self) -> Option<&quinn::Connection> {
+    self.paths.iter().min_by_key(|path| path.rtt())
+  }
+}
+
+impl Router {
+  /// Clamps the MSS TCP SYNs offer, both to and from clients, to what fits
+  /// in the MTU of the client's path, so connections don't stall on
+  /// segments too large for it.
+  pub fn clamp_mss(mut self, enabled: bool) -> Self {
+    self.clamp = enabled;
+    self
+  }
+
+  /// Drops the packets of clients that `policy` doesn't allow out of the
+  /// tunnel, checked against the users `logins` records.
+  pub fn egress(mut self, policy: Arc<Policy>, logins: Logins) -> Self {
+    self.egress = policy;
+    self.logins = logins;
+    self
+  }
+
+  /// Routes packets for `addr` to `connection`.
+  pub async fn insert(&self, addr: IpAddr, connection: quinn::Connection) {
+    let route = Route {
+      paths: vec![connection],
+      reorder: Reorder::default(),
+      token: None,
+    };
+    self.routes.lock().await.insert(addr, route);
+  }
+
+  /// Drops every route leased by `connection`, and `connection` from the
+  /// routes it joined.
+  pub async fn remove_connection(&self, connection: &quinn::Connection) {
+    let id = connection.stable_id();
+    let mut routes = self.routes.lock().await;
+    routes.retain(|_, route| {
+      if route.paths[0].stable_id() == id {
+        return false;
+      }
+      route.paths.retain(|path| path.stable_id() != id);
+      true
+    });
+    let leased: Vec<_> = routes.keys().copied().collect();
+    drop(routes);
+    let mut macs = self.macs.lock().await;
+    macs.retain(|_, addr| leased.contains(addr));
+    drop(macs);
+    let mut networks = self.networks.lock().await;
+    networks.retain(|(_, addr)| leased.contains(addr));
+  }
+
+  /// Routes packets for `networks` to the client leased `addr`, and lets it
+  /// send from them, replacing any networks routed to it before.
+  pub async fn route_networks(&self, addr: IpAddr, networks: &[Ipv4Net]) {
+    let mut routed = self.networks.lock().await;
+    routed.retain(|(_, x)| *x != addr);
+    routed.extend(networks.iter().map(|network| (*network, addr)));
+    // Longest prefix first, so the first match is the most specific.
+    routed.sort_by_key(|(network, _)| std::cmp::Reverse(network.prefix()));
+  }
+
+  /// The leased address routing `addr`: itself if it is leased, or else
+  /// the gateway of a site network containing it.
+  async fn resolve(&self, addr: IpAddr) -> IpAddr {
+    if self.routes.lock().await.contains_key(&addr) {
+      return addr;
+    }
+    let ip = match addr {
+      IpAddr::V4(ip) => ip,
+      IpAddr::V6(_) => return addr,
+    };
+    let networks = self.networks.lock().await;
+    networks
+      .iter()
+      .find(|(network, _)| network.contains(ip))
+      .map_or(addr, |(_, gateway)| *gateway)
+  }
+
+  /// Connection to send packets for `addr` on, if any: the fastest path of
+  /// a bonded uplink, of the client leased `addr` or of the site gateway
+  /// routing it.
+  pub async fn get(&self, addr: &IpAddr) -> Option<quinn::Connection> {
+    let addr = self.resolve(*addr).await;
+    self.routes.lock().await.get(&addr)?.fastest().cloned()
+  }
+
+  /// Every leased address with the connection that leased it.
+  pub async fn leases(&self) -> Vec<(IpAddr, quinn::Connection)> {
+    let routes = self.routes.lock().await;
+    routes
+      .iter()
+      .map(|(addr, route)| (*addr, route.paths[0].clone()))
+      .collect()
+  }
+
+  /// Token that lets other connections join the address `connection`
+  /// leased, created on first use. `None` if it leased none.
+  pub async fn bond_token(&self, connection: &quinn::Connection) -> Result<Option<u64>> {
+    let id = connection.stable_id();
+    let mut routes = self.routes.lock().await;
+    let route = match routes
+      .values_mut()
+      .find(|route| route.paths[0].stable_id() == id)
+    {
+      Some(route) => route,
+      None => return Ok(None),
+    };
+    if route.token.is_none() {
+      let mut token = [0; 8];
+      SystemRandom::new()
+        .fill(&mut token)
+        .map_err(|_| QvpnError::Io(io::Error::other("no randomness for a bond token")))?;
+      route.token = Some(u64::from_be_bytes(token));
+    }
+    Ok(route.token)
+  }
+
+  /// The connection that leased the address `token` was issued for.
+  pub async fn bond_owner(&self, token: u64) -> Option<quinn::Connection> {
+    let routes = self.routes.lock().await;
+    routes
+      .values()
+      .find(|route| route.token == Some(token))
+      .map(|route| route.paths[0].clone())
+  }
+
+  /// Adds `connection` as another path of the address `token` was issued
+  /// for, returning that address.
+  pub async fn join(&self, token: u64, connection: quinn::Connection) -> Option<IpAddr> {
+    let mut routes = self.routes.lock().await;
+    let (addr, route) = routes
+      .iter_mut()
+      .find(|(_, route)| route.token == Some(token))?;
+    if !route.has_path(&connection) {
+      route.paths.push(connection);
+    }
+    Some(*addr)
+  }
+
+  /// Reads packets from `tun` and sends each to the connection owning its
+  /// destination address. Packets with no route are dropped; Ethernet
+  /// frames are [switched](Router::switch) instead.
+  pub async fn run(self, tun: Arc<Tun>) -> Result<()> {
+    let mut buf = vec![0; tun.max_len()];
+    loop {
+      let len = tun.recv(&mut buf).await?;
+      let packet = &mut buf[..len];
+      if tun.is_tap() {
+        self.switch(None, packet).await;
+        continue;
+      }
+      let connection = match destination(packet) {
+        Some(dst) => self.get(&dst).await,
+        None => None,
+      };
+      if let Some(connection) = connection {
+        if self.clamp {
+          clamp_mss(packet, tun.mtu_for(&connection));
+        }
+        if let Err(err) = send_packet(&connection, packet) {
+          debug!("{}", err);
+        }
+      }
+    }
+  }
+
+  /// Writes packet number `seq` received from `connection` to `tun`, after
+  /// any it has to wait for when it came over a bonded uplink. Packets whose
+  /// source is not an address routed to `connection` are dropped, so a client
+  /// can only send from the address it was leased, or the networks behind
+  /// it if it is a site gateway.
+  ///
+  /// Ethernet frames are [switched](Router::switch) instead, and dropped if
+  /// they come from a MAC address seen behind another client.
+  pub async fn inbound(
+    &self,
+    tun: &Tun,
+    connection: &quinn::Connection,
+    seq: u32,
+    mut packet: Bytes,
+  ) -> Result<()> {
+    if self.clamp && !tun.is_tap() && mss_option(&packet).is_some() {
+      let mut copy = packet.to_vec();
+      clamp_mss(&mut copy, tun.mtu_for(connection));
+      packet = copy.into();
+    }
+    let source = match tun.is_tap() {
+      true => None,
+      false => match source(&packet) {
+        Some(src) => Some(self.resolve(src).await),
+        None => None,
+      },
+    };
+    let (from, ready) = {
+      let mut routes = self.routes.lock().await;
+      let from = match tun.is_tap() {
+        true => routes
+          .iter()
+          .find(|(_, route)| route.has_path(connection))
+          .map(|(addr, _)| *addr),
+        false => source,
+      };
+      match from.and_then(|src| Some(src).zip(routes.get_mut(&src))) {
+        Some((from, route)) if route.has_path(connection) => {
+          if seq == 0 || route.paths.len() == 1 {
+            (from, vec![packet])
+          } else {
+            (from, route.reorder.push(seq, packet, Instant::now()))
+          }
+        }
+        _ => {
+          warn!(
+            remote = %net::canonical(connection.remote_address()),
+            "dropping spoofed packet"
+          );
+          return Ok(());
+        }
+      }
+    };
+    let user = match self.egress.is_empty() {
+      true => None,
+      false => self.logins.user(connection),
+    };
+    for packet in ready {
+      if !self.egress.is_empty() && !self.permits(tun, &packet, user.as_deref()) {
+        debug!(
+          remote = %net::canonical(connection.remote_address()),
+          "egress policy dropped packet"
+        );
+      } else if !tun.is_tap() {
+        tun.send(&packet).await?;
+      } else if !self.learn(from, &packet).await {
+        warn!(
+          remote = %net::canonical(connection.remote_address()),
+          "dropping spoofed frame"
+        );
+      } else if self.switch(Some(from), &packet).await {
+        tun.send(&packet).await?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Whether the egress policy lets `user` send `packet`, an Ethernet frame
+  /// if `tun` is a TAP interface.
+  fn permits(&self, tun: &Tun, packet: &[u8], user: Option<&str>) -> bool {
+    if !tun.is_tap() {
+      return self.egress.allows(packet, user);
+    }
+    match packet.get(12..14) {
+      Some([0x08, 0x06]) => true,
+      Some([0x08, 0x00]) => self.egress.allows(&packet[14..], user),
+      _ => self.egress.allows(&[], user),
+    }
+  }
+
+  /// Records that the source MAC address of `frame` is behind the client
+  /// leased `addr`. Returns false if it is another client's.
+  async fn learn(&self, addr: IpAddr, frame: &[u8]) -> bool {
+    let src = match mac_source(frame) {
+      Some(src) if !is_group(&src) => src,
+      _ => return false,
+    };
+    *self.macs.lock().await.entry(src).or_insert(addr) == addr
+  }
+
+  /// Sends an Ethernet frame read from the interface, or received from the
+  /// client leased `from`, to the client behind its destination MAC
+  /// address. Frames to a group address, or one not seen yet, go to every
+  /// other client instead. Returns whether the interface should have the
+  /// frame too.
+  async fn switch(&self, from: Option<IpAddr>, frame: &[u8]) -> bool {
+    let dst = match mac_destination(frame) {
+      Some(dst) => dst,
+      None => return false,
+    };
+    let owner = match is_group(&dst) {
+      true => None,
+      false => self.macs.lock().await.get(&dst).copied(),
+    };
+    let connections: Vec<_> = {
+      let routes = self.routes.lock().await;
+      match owner {
+        Some(addr) if Some(addr) == from => vec![],
+        Some(addr) => routes
+          .get(&addr)
+          .and_then(Route::fastest)
+          .cloned()
+          .into_iter()
+          .collect(),
+        None => routes
+          .iter()
+          .filter(|(addr, _)| Some(**addr) != from)
+          .filter_map(|(_, route)| route.fastest().cloned())
+          .collect(),
+      }
+    };
+    for connection in connections {
+      if let Err(err) = send_packet(&connection, frame) {
+        debug!("{}", err);
+      }
+    }
+    owner.is_none()
+  }
+}
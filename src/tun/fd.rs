@@ -0,0 +1,182 @@
+//! TUN devices opened by another program and handed over as descriptors.
+//!
+//! This is how Android's `VpnService` and some sandboxes provide the
+//! interface: they create and configure it, including its routes and
+//! resolvers, and give the client a descriptor to read and write raw IP
+//! packets on. The descriptor is passed by number, or received over a Unix
+//! socket after telling the program the lease it is to configure.
+
+use std::{
+  io::{self, Write},
+  mem,
+  net::{IpAddr, Ipv4Addr},
+  os::unix::{
+    io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    net::UnixStream,
+  },
+  path::Path,
+  ptr,
+  time::Duration,
+};
+
+use serde::Serialize;
+use tokio::io::{unix::AsyncFd, Interest};
+
+use crate::{QvpnError, Result};
+
+/// How long the program on the socket gets to hand the descriptor over.
+pub const HANDOFF_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The lease sent to the program on the socket, as a line of JSON, for it
+/// to configure the interface with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Handoff {
+  pub address: Ipv4Addr,
+  pub netmask: Ipv4Addr,
+  pub mtu: u16,
+  /// Networks to route through the interface, like `10.1.0.0/16`.
+  pub routes: Vec<String>,
+  pub dns: Vec<IpAddr>,
+  pub search: Vec<String>,
+  /// Whether all IPv4 traffic is to go through the interface.
+  pub full_tunnel: bool,
+  /// Whether the interface is to be a TAP interface, carrying Ethernet
+  /// frames.
+  pub tap: bool,
+}
+
+/// A TUN device opened by another program.
+pub struct Device {
+  fd: AsyncFd<OwnedFd>,
+  name: String,
+}
+
+impl Device {
+  /// Takes over `fd`, calling the interface `name` in logs.
+  pub fn new(fd: OwnedFd, name: String) -> Result<Self> {
+    let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+    cvt(flags.into())?;
+    cvt(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) }.into())?;
+    Ok(Device {
+      fd: AsyncFd::new(fd)?,
+      name,
+    })
+  }
+
+  /// Name of the interface.
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Reads one IP packet from the interface.
+  pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+    self
+      .fd
+      .async_io(Interest::READABLE, |fd| {
+        cvt(unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) } as i64)
+      })
+      .await
+  }
+
+  /// Writes one IP packet to the interface.
+  pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+    self
+      .fd
+      .async_io(Interest::WRITABLE, |fd| {
+        cvt(unsafe { libc::write(fd.as_raw_fd(), packet.as_ptr().cast(), packet.len()) } as i64)
+      })
+      .await?;
+    Ok(())
+  }
+}
+
+/// A copy of the descriptor `fd`, which stays open, so the tunnel can be
+/// brought up on it again after reconnecting.
+pub fn duplicate(fd: RawFd) -> Result<OwnedFd> {
+  let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+  cvt(copy.into())
+    .map_err(|err| QvpnError::InvalidInput(format!("can't use TUN descriptor {}: {}", fd, err)))?;
+  // Safety: the descriptor was just created and nothing else owns it.
+  Ok(unsafe { OwnedFd::from_raw_fd(copy) })
+}
+
+/// Sends `handoff` to the program listening on the Unix socket at `path`
+/// and receives the descriptor of the interface it configured.
+pub async fn request(path: &Path, handoff: &Handoff) -> Result<OwnedFd> {
+  let mut line = serde_json::to_vec(handoff).expect("handoffs serialize");
+  line.push(b'\n');
+  let path = path.to_path_buf();
+  tokio::task::spawn_blocking(move || {
+    let fail = |err: io::Error| {
+      QvpnError::Io(io::Error::new(
+        err.kind(),
+        format!("TUN descriptor from {}: {}", path.display(), err),
+      ))
+    };
+    let mut socket = UnixStream::connect(&path).map_err(fail)?;
+    socket
+      .set_read_timeout(Some(HANDOFF_TIMEOUT))
+      .map_err(fail)?;
+    socket.write_all(&line).map_err(fail)?;
+    receive(&socket).map_err(fail)
+  })
+  .await
+  .map_err(io::Error::from)?
+}
+
+/// Receives a descriptor sent on `socket` with `SCM_RIGHTS`.
+fn receive(socket: &UnixStream) -> io::Result<OwnedFd> {
+  let mut byte = [0u8; 1];
+  let mut iov = libc::iovec {
+    iov_base: byte.as_mut_ptr().cast(),
+    iov_len: byte.len(),
+  };
+  // Aligned for the control message header.
+  let mut control = [0u64; 8];
+  let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+  msg.msg_iov = &mut iov;
+  msg.msg_iovlen = 1;
+  msg.msg_control = control.as_mut_ptr().cast();
+  msg.msg_controllen = mem::size_of_val(&control) as _;
+  let len = cvt(unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) } as i64)?;
+  let mut received = None;
+  let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+  while !cmsg.is_null() {
+    let header = unsafe { &*cmsg };
+    if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_RIGHTS {
+      let data = unsafe { libc::CMSG_DATA(cmsg) };
+      let count = (header.cmsg_len as usize - (data as usize - cmsg as usize))
+        / mem::size_of::<libc::c_int>();
+      for i in 0..count {
+        let fd = unsafe { ptr::read_unaligned((data as *const libc::c_int).add(i)) };
+        // Safety: the kernel just installed the descriptor for us.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // Only the first is wanted; the others are closed.
+        received.get_or_insert(fd);
+      }
+    }
+    cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+  }
+  match received {
+    Some(fd) => {
+      cvt(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) }.into())?;
+      Ok(fd)
+    }
+    None if len == 0 => Err(io::Error::new(
+      io::ErrorKind::UnexpectedEof,
+      "closed without sending a descriptor",
+    )),
+    None => Err(io::Error::new(
+      io::ErrorKind::InvalidData,
+      "no descriptor sent",
+    )),
+  }
+}
+
+/// Turns the `-1` a libc call fails with into the error in `errno`.
+fn cvt(ret: i64) -> io::Result<usize> {
+  if ret < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(ret as usize)
+}
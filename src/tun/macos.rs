@@ -0,0 +1,281 @@
+//! TUN interfaces on macOS, through utun control sockets.
+//!
+//! utun interfaces are named `utun<N>`: a configured name of that form asks
+//! for that unit, any other name for the next free one. Every packet on the
+//! socket is preceded by its address family as a 32-bit big-endian integer.
+//! Addresses are set with `ifconfig` and routes with `route`.
+
+use std::{
+  io, mem,
+  net::Ipv4Addr,
+  os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+  process::Command,
+};
+
+use tokio::io::{unix::AsyncFd, Interest};
+use tracing::debug;
+
+use super::TunConfig;
+use crate::{lease::Ipv4Net, QvpnError, Result};
+
+/// Kernel control the utun interfaces belong to.
+const UTUN_CONTROL: &[u8] = b"com.apple.net.utun_control";
+
+/// Length of the address family header on each packet.
+const HEADER_LEN: usize = 4;
+
+/// An open utun interface.
+pub struct Device {
+  fd: AsyncFd<OwnedFd>,
+  name: String,
+}
+
+impl Device {
+  /// Creates the interface and configures it as described by `config`.
+  ///
+  /// Requires root.
+  pub fn open(config: &TunConfig) -> Result<Self> {
+    // Unit 0 asks for the next free one; unit N + 1 for utunN.
+    let unit = config
+      .name
+      .strip_prefix("utun")
+      .and_then(|n| n.parse::<u32>().ok())
+      .map_or(0, |n| n + 1);
+    let fd = utun_socket(unit)?;
+    let name = interface_name(&fd)?;
+    if unit == 0 && name != config.name {
+      debug!(requested = %config.name, interface = %name, "utun interfaces can't be named");
+    }
+    let subnet = Ipv4Net::new(config.address, u32::from(config.netmask).count_ones() as u8)
+      .expect("netmask has at most 32 bits");
+    // utun interfaces are point to point: the destination is our own
+    // address and the subnet is routed explicitly.
+    let address = config.address.to_string();
+    let mtu = config.mtu.to_string();
+    run(
+      "ifconfig",
+      &[
+        &name,
+        "inet",
+        &address,
+        &address,
+        "netmask",
+        &config.netmask.to_string(),
+        "mtu",
+        &mtu,
+        "up",
+      ],
+    )?;
+    add_route(&name, subnet)?;
+    Ok(Device {
+      fd: AsyncFd::new(fd)?,
+      name,
+    })
+  }
+
+  /// Name of the interface.
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Sets the interface MTU.
+  pub fn set_mtu(&self, mtu: u16) -> Result<()> {
+    run("ifconfig", &[&self.name, "mtu", &mtu.to_string()])
+  }
+
+  /// Reads one IP packet from the interface.
+  pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+    let mut header = [0u8; HEADER_LEN];
+    let len = self
+      .fd
+      .async_io(Interest::READABLE, |fd| {
+        let iov = [
+          libc::iovec {
+            iov_base: header.as_mut_ptr().cast(),
+            iov_len: header.len(),
+          },
+          libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+          },
+        ];
+        cvt(unsafe { libc::readv(fd.as_raw_fd(), iov.as_ptr(), iov.len() as i32) } as i64)
+      })
+      .await?;
+    Ok(len.saturating_sub(HEADER_LEN))
+  }
+
+  /// Writes one IP packet to the interface.
+  pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+    let family = match packet.first().map(|x| x >> 4) {
+      Some(4) => libc::AF_INET,
+      Some(6) => libc::AF_INET6,
+      _ => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          "not an IP packet",
+        ))
+      }
+    };
+    let header = (family as u32).to_be_bytes();
+    self
+      .fd
+      .async_io(Interest::WRITABLE, |fd| {
+        let iov = [
+          libc::iovec {
+            iov_base: header.as_ptr() as *mut _,
+            iov_len: header.len(),
+          },
+          libc::iovec {
+            iov_base: packet.as_ptr() as *mut _,
+            iov_len: packet.len(),
+          },
+        ];
+        cvt(unsafe { libc::writev(fd.as_raw_fd(), iov.as_ptr(), iov.len() as i32) } as i64)
+      })
+      .await?;
+    Ok(())
+  }
+}
+
+/// Connects a non-blocking utun control socket to `unit`.
+fn utun_socket(unit: u32) -> Result<OwnedFd> {
+  let fd = unsafe { libc::socket(libc::PF_SYSTEM, libc::SOCK_DGRAM, libc::SYSPROTO_CONTROL) };
+  cvt(fd.into())?;
+  // Safety: the descriptor was just created and nothing else owns it.
+  let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+  let mut info: libc::ctl_info = unsafe { mem::zeroed() };
+  for (dst, src) in info.ctl_name.iter_mut().zip(UTUN_CONTROL) {
+    *dst = *src as libc::c_char;
+  }
+  cvt(unsafe { libc::ioctl(fd.as_raw_fd(), libc::CTLIOCGINFO, &mut info) }.into())?;
+  let addr = libc::sockaddr_ctl {
+    sc_len: mem::size_of::<libc::sockaddr_ctl>() as u8,
+    sc_family: libc::AF_SYSTEM as u8,
+    ss_sysaddr: libc::AF_SYS_CONTROL as u16,
+    sc_id: info.ctl_id,
+    sc_unit: unit,
+    sc_reserved: [0; 5],
+  };
+  cvt(
+    unsafe {
+      libc::connect(
+        fd.as_raw_fd(),
+        (&addr as *const libc::sockaddr_ctl).cast(),
+        mem::size_of_val(&addr) as libc::socklen_t,
+      )
+    }
+    .into(),
+  )?;
+  let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+  cvt(flags.into())?;
+  cvt(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) }.into())?;
+  Ok(fd)
+}
+
+/// Name the kernel gave the interface of `fd`.
+fn interface_name(fd: &OwnedFd) -> Result<String> {
+  let mut name = [0u8; libc::IFNAMSIZ];
+  let mut len = name.len() as libc::socklen_t;
+  cvt(
+    unsafe {
+      libc::getsockopt(
+        fd.as_raw_fd(),
+        libc::SYSPROTO_CONTROL,
+        libc::UTUN_OPT_IFNAME,
+        name.as_mut_ptr().cast(),
+        &mut len,
+      )
+    }
+    .into(),
+  )?;
+  let name = name.split(|&c| c == 0).next().unwrap_or_default();
+  Ok(String::from_utf8_lossy(name).into_owned())
+}
+
+/// Turns the `-1` a libc call fails with into the error in `errno`.
+fn cvt(ret: i64) -> io::Result<usize> {
+  if ret < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(ret as usize)
+}
+
+/// Routes `net` through the interface named `dev`.
+pub fn add_route(dev: &str, net: Ipv4Net) -> Result<()> {
+  run(
+    "route",
+    &[
+      "-q",
+      "-n",
+      "add",
+      "-net",
+      &net.to_string(),
+      "-interface",
+      dev,
+    ],
+  )
+}
+
+/// Removes the route for `net` through the interface named `dev`.
+pub fn delete_route(dev: &str, net: Ipv4Net) -> Result<()> {
+  run(
+    "route",
+    &[
+      "-q",
+      "-n",
+      "delete",
+      "-net",
+      &net.to_string(),
+      "-interface",
+      dev,
+    ],
+  )
+}
+
+/// Pins `addr` to the gateway, or failing that the interface, it is routed
+/// through now.
+pub fn pin_route(addr: Ipv4Addr) -> Result<()> {
+  let output = Command::new("route")
+    .args(["-n", "get", &addr.to_string()])
+    .output()?;
+  let route = String::from_utf8_lossy(&output.stdout);
+  let field = |name: &str| {
+    route
+      .lines()
+      .filter_map(|line| line.trim().strip_prefix(name))
+      .map(|value| value.trim_start_matches(':').trim().to_string())
+      .next()
+  };
+  let host = addr.to_string();
+  match (field("gateway"), field("interface")) {
+    (Some(gateway), _) => run("route", &["-q", "-n", "add", "-host", &host, &gateway]),
+    (None, Some(dev)) => run(
+      "route",
+      &["-q", "-n", "add", "-host", &host, "-interface", &dev],
+    ),
+    (None, None) => Err(QvpnError::Io(io::Error::other(format!(
+      "no route to {}",
+      addr
+    )))),
+  }
+}
+
+/// Removes a route made by [`pin_route`].
+pub fn unpin_route(addr: Ipv4Addr) -> Result<()> {
+  run("route", &["-q", "-n", "delete", "-host", &addr.to_string()])
+}
+
+/// Runs `program` with `args`.
+pub fn run(program: &str, args: &[&str]) -> Result<()> {
+  let status = Command::new(program).args(args).status()?;
+  if !status.success() {
+    return Err(QvpnError::Io(io::Error::other(format!(
+      "{} {} failed: {}",
+      program,
+      args.join(" "),
+      status
+    ))));
+  }
+  Ok(())
+}
@@ -0,0 +1,226 @@
+//! TUN interfaces on Windows, through the Wintun driver.
+//!
+//! `wintun.dll` isn't shipped with the binary. It is looked up at the path
+//! in `QVPN_WINTUN`, then next to the executable, either on its own or as
+//! unpacked from the release zip (`wintun\bin\<arch>\wintun.dll`), then on
+//! the system search path. Addresses, routes and resolvers are configured
+//! with `netsh`.
+
+use std::{
+  convert::TryFrom,
+  env, io,
+  net::Ipv4Addr,
+  path::{Path, PathBuf},
+  process::Command,
+  sync::Arc,
+};
+
+use tracing::debug;
+use wintun::{Adapter, Session, Wintun};
+
+use super::TunConfig;
+use crate::{lease::Ipv4Net, QvpnError, Result};
+
+/// Environment variable naming the `wintun.dll` to load.
+pub const WINTUN_ENV: &str = "QVPN_WINTUN";
+
+/// Tunnel type adapters are created with.
+const TUNNEL_TYPE: &str = "qvpn";
+
+/// Directory of the driver for this architecture in the release zip.
+const ARCH: &str = if cfg!(target_arch = "x86_64") {
+  "amd64"
+} else if cfg!(target_arch = "aarch64") {
+  "arm64"
+} else if cfg!(target_arch = "arm") {
+  "arm"
+} else {
+  "x86"
+};
+
+/// An open Wintun adapter.
+pub struct Device {
+  // Dropping the adapter deletes the interface, after the session ends.
+  session: Arc<Session>,
+  adapter: Arc<Adapter>,
+  name: String,
+}
+
+impl Device {
+  /// Creates the adapter described by `config` and starts a session on it.
+  ///
+  /// Requires Administrator rights.
+  pub fn open(config: &TunConfig) -> Result<Self> {
+    let wintun = load()?;
+    let adapter = match Adapter::open(&wintun, &config.name) {
+      Ok(adapter) => adapter,
+      Err(_) => Adapter::create(&wintun, &config.name, TUNNEL_TYPE, None).map_err(wintun_error)?,
+    };
+    adapter
+      .set_network_addresses_tuple(config.address.into(), config.netmask.into(), None)
+      .map_err(wintun_error)?;
+    adapter.set_mtu(config.mtu as usize).map_err(wintun_error)?;
+    let session = adapter
+      .start_session(wintun::MAX_RING_CAPACITY)
+      .map_err(wintun_error)?;
+    Ok(Device {
+      session: Arc::new(session),
+      adapter,
+      name: config.name.clone(),
+    })
+  }
+
+  /// Name of the interface.
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Sets the interface MTU.
+  pub fn set_mtu(&self, mtu: u16) -> Result<()> {
+    self.adapter.set_mtu(mtu as usize).map_err(wintun_error)
+  }
+
+  /// Reads one IP packet from the interface.
+  pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+    let session = self.session.clone();
+    let packet = tokio::task::spawn_blocking(move || {
+      session
+        .receive_blocking()
+        .map(|packet| packet.bytes().to_vec())
+    })
+    .await?
+    .map_err(|err| io::Error::other(err.to_string()))?;
+    let len = packet.len().min(buf.len());
+    buf[..len].copy_from_slice(&packet[..len]);
+    Ok(len)
+  }
+
+  /// Writes one IP packet to the interface.
+  pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(packet.len())
+      .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet too large"))?;
+    let mut buf = self
+      .session
+      .allocate_send_packet(len)
+      .map_err(|err| io::Error::other(err.to_string()))?;
+    buf.bytes_mut().copy_from_slice(packet);
+    self.session.send_packet(buf);
+    Ok(())
+  }
+}
+
+impl Drop for Device {
+  fn drop(&mut self) {
+    // Wakes a reader blocked in `recv`.
+    if let Err(err) = self.session.shutdown() {
+      debug!("couldn't shut down the wintun session: {}", err);
+    }
+  }
+}
+
+/// Loads `wintun.dll` from the first place it is found.
+fn load() -> Result<Wintun> {
+  let mut candidates: Vec<PathBuf> = env::var_os(WINTUN_ENV)
+    .map(PathBuf::from)
+    .into_iter()
+    .collect();
+  if let Some(dir) = env::current_exe().ok().as_deref().and_then(Path::parent) {
+    candidates.push(dir.join("wintun.dll"));
+    candidates.push(dir.join("wintun").join("bin").join(ARCH).join("wintun.dll"));
+  }
+  if let Some(path) = candidates.iter().find(|path| path.is_file()) {
+    debug!(path = %path.display(), "loading wintun");
+    // Safety: the DLL's initialization runs with our privileges, which is
+    // why only the configured path and the executable's own directory are
+    // tried before the system search path.
+    return unsafe { wintun::load_from_path(path) }.map_err(wintun_error);
+  }
+  unsafe { wintun::load() }.map_err(|err| {
+    QvpnError::Unsupported(format!(
+      "couldn't load wintun.dll ({}); download it from https://www.wintun.net and put it \
+       next to the executable or set {} to its path",
+      err, WINTUN_ENV
+    ))
+  })
+}
+
+fn wintun_error(err: wintun::Error) -> QvpnError {
+  match err {
+    wintun::Error::Io(err) => err.into(),
+    err => QvpnError::Io(io::Error::other(err.to_string())),
+  }
+}
+
+/// Routes `net` through the interface named `dev`.
+pub fn add_route(dev: &str, net: Ipv4Net) -> Result<()> {
+  netsh(&[
+    "interface",
+    "ipv4",
+    "add",
+    "route",
+    &format!("prefix={}", net),
+    &format!("interface={}", dev),
+    "store=active",
+  ])
+}
+
+/// Removes the route for `net` through the interface named `dev`.
+pub fn delete_route(dev: &str, net: Ipv4Net) -> Result<()> {
+  netsh(&[
+    "interface",
+    "ipv4",
+    "delete",
+    "route",
+    &format!("prefix={}", net),
+    &format!("interface={}", dev),
+    "store=active",
+  ])
+}
+
+/// Pins `addr` to the interface and next hop it is routed through now,
+/// returning the interface index.
+pub fn pin_route(addr: Ipv4Addr) -> Result<String> {
+  let script = format!(
+    "$r = Find-NetRoute -RemoteIPAddress {} | Where-Object NextHop | Select-Object -First 1; \
+     '{{0}} {{1}}' -f $r.InterfaceIndex, $r.NextHop",
+    addr
+  );
+  let output = Command::new("powershell")
+    .args(["-NoProfile", "-NonInteractive", "-Command", &script])
+    .output()?;
+  let route = String::from_utf8_lossy(&output.stdout);
+  let mut words = route.split_whitespace();
+  let (interface, next_hop) = match (words.next(), words.next()) {
+    (Some(interface), Some(next_hop)) if output.status.success() => (interface, next_hop),
+    _ => {
+      return Err(QvpnError::Io(io::Error::other(format!(
+        "no route to {}",
+        addr
+      ))))
+    }
+  };
+  netsh(&[
+    "interface",
+    "ipv4",
+    "add",
+    "route",
+    &format!("prefix={}/32", addr),
+    &format!("interface={}", interface),
+    &format!("nexthop={}", next_hop),
+    "store=active",
+  ])?;
+  Ok(interface.to_string())
+}
+
+/// Runs `netsh` with `args`.
+pub fn netsh(args: &[&str]) -> Result<()> {
+  let status = Command::new("netsh").args(args).status()?;
+  if !status.success() {
+    return Err(QvpnError::Io(io::Error::other(format!(
+      "netsh {} failed: {}",
+      args.join(" "),
+      status
+    ))));
+  }
+  Ok(())
+}
@@ -0,0 +1,45 @@
+//! Flow-control windows and other transport tuning.
+//!
+//! quinn's defaults let a peer have 1.25 MB unread per stream and send at
+//! most 10 MB unacknowledged per connection, which caps throughput at
+//! roughly the window divided by the round-trip time. Paths with a large
+//! bandwidth-delay product need bigger windows to be filled.
+
+use std::{convert::TryInto, time::Duration};
+
+use crate::Result;
+
+/// Settings replacing quinn's defaults, each left alone when unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tuning {
+  /// Bytes the peer may send on a stream before it is read.
+  pub stream_receive_window: Option<u64>,
+  /// Bytes the peer may send on all streams of a connection before they
+  /// are read, unlimited by default. Also sets how much the local side
+  /// keeps unacknowledged in flight.
+  pub receive_window: Option<u64>,
+  /// Bidirectional streams the peer may have open at once.
+  pub max_bidi_streams: Option<u64>,
+  /// Round-trip time assumed before the first one is measured.
+  pub initial_rtt: Option<Duration>,
+}
+
+impl Tuning {
+  /// Applies the settings to a transport configuration.
+  pub fn configure(&self, transport: &mut quinn::TransportConfig) -> Result<()> {
+    if let Some(window) = self.stream_receive_window {
+      transport.stream_receive_window(window.try_into()?);
+    }
+    if let Some(window) = self.receive_window {
+      transport.receive_window(window.try_into()?);
+      transport.send_window(window);
+    }
+    if let Some(max) = self.max_bidi_streams {
+      transport.max_concurrent_bidi_streams(max.try_into()?);
+    }
+    if let Some(rtt) = self.initial_rtt {
+      transport.initial_rtt(rtt);
+    }
+    Ok(())
+  }
+}
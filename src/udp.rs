@@ -0,0 +1,349 @@
+//! UDP flows carried in QUIC datagrams.
+//!
+//! For each local source address the client opens a bidirectional stream and
+//! sends `CONNECT-UDP host:port\r\n`. The server binds a socket connected to
+//! the target and answers `200 OK` with the id of the flow in a
+//! [`FLOW_HEADER`] header. Payloads then travel both ways as [`Kind::Udp`]
+//! datagrams numbered by that id, and the flow lasts until the client
+//! finishes the stream or the connection closes.
+//!
+//! Datagrams are as unreliable as UDP itself: those too large for the
+//! connection, or arriving faster than they can be sent, are dropped.
+
+use std::{
+  collections::HashMap,
+  fmt, io,
+  net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+  str::{self, FromStr},
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use serde::{de, Deserialize, Deserializer};
+use tokio::net::UdpSocket;
+use tracing::{debug, info};
+
+use crate::{
+  datagram::{self, Frame, Kind},
+  http::{self, ResponseHead},
+  QvpnError, Result,
+};
+
+/// Header of the `200 OK` response that carries the flow id.
+pub const FLOW_HEADER: &str = "Qvpn-Flow";
+
+/// How long a flow may go without a datagram from its local source before
+/// the client closes it.
+pub const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Largest UDP payload read; bigger ones never fit in a datagram anyway.
+const MAX_PAYLOAD: usize = 65_535;
+
+/// Start of a request line asking for a flow.
+pub const REQUEST_PREFIX: &[u8] = b"CONNECT-UDP ";
+
+/// Formats a request asking the server to relay a UDP flow to `target`.
+pub fn connect_request(target: &str) -> String {
+  format!("CONNECT-UDP {}\r\n", target)
+}
+
+/// Extracts the `host:port` target from a `CONNECT-UDP` request line.
+pub fn connect_target(line: &[u8]) -> Result<&str> {
+  let target = line
+    .strip_prefix(REQUEST_PREFIX)
+    .ok_or_else(|| QvpnError::BadRequest("not a CONNECT-UDP request".into()))?;
+  let target = target.strip_suffix(b"\r\n").unwrap_or(target);
+  str::from_utf8(target).map_err(|_| QvpnError::BadRequest("target is not valid utf-8".into()))
+}
+
+/// A local UDP port forwarded to a target reachable from the server, parsed
+/// from `local:port=remote:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Forward {
+  /// Address datagrams are accepted on.
+  pub local: SocketAddr,
+  /// `host:port` the server relays them to.
+  pub remote: String,
+}
+
+impl FromStr for Forward {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+    let (local, remote) = s
+      .split_once('=')
+      .ok_or_else(|| format!("expected local:port=remote:port, got `{}`", s))?;
+    let local = local
+      .parse()
+      .map_err(|err| format!("bad local address `{}`: {}", local, err))?;
+    match remote.rsplit_once(':') {
+      Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+      _ => return Err(format!("expected host:port, got `{}`", remote)),
+    }
+    Ok(Forward {
+      local,
+      remote: remote.to_string(),
+    })
+  }
+}
+
+impl<'de> Deserialize<'de> for Forward {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+    String::deserialize(deserializer)?
+      .parse()
+      .map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Forward {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}={}", self.local, self.remote)
+  }
+}
+
+/// The flows a server relays, shared by its connections.
+#[derive(Debug, Default)]
+pub struct Flows {
+  next: AtomicU32,
+  /// Sockets of the flows, by connection and flow id.
+  sockets: Mutex<HashMap<(usize, u32), Arc<UdpSocket>>>,
+}
+
+impl Flows {
+  /// Sends `payload` to the target of flow `id` of `connection`, if it is
+  /// still open.
+  pub fn deliver(&self, connection: &quinn::Connection, id: u32, payload: &[u8]) {
+    let socket = self
+      .sockets
+      .lock()
+      .unwrap()
+      .get(&(connection.stable_id(), id))
+      .cloned();
+    match socket {
+      Some(socket) => {
+        if let Err(err) = socket.try_send(payload) {
+          debug!(id, "dropping udp datagram: {}", err);
+        }
+      }
+      None => debug!(id, "dropping datagram of unknown udp flow"),
+    }
+  }
+
+  /// Relays a flow to `target` for the client of `connection`, from
+  /// answering its request on `send` until it finishes the stream or the
+  /// connection closes.
+  pub async fn relay(
+    &self,
+    connection: &quinn::Connection,
+    target: &str,
+    send: &mut quinn::SendStream,
+    mut recv: quinn::RecvStream,
+  ) -> Result<()> {
+    let dial = |err| QvpnError::Dial(target.to_string(), err);
+    let addr = tokio::net::lookup_host(target)
+      .await
+      .map_err(dial)?
+      .next()
+      .ok_or_else(|| dial(io::ErrorKind::NotFound.into()))?;
+    let local: SocketAddr = match addr {
+      SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+      SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await.map_err(dial)?;
+    let socket = Arc::new(socket);
+    let id = self.next.fetch_add(1, Ordering::Relaxed);
+    let key = (connection.stable_id(), id);
+    self.sockets.lock().unwrap().insert(key, socket.clone());
+    info!(%target, id, "relaying udp");
+    let result = async {
+      let head = ResponseHead::new(200, "OK").header(FLOW_HEADER, id);
+      send.write_all(head.encode().as_bytes()).await?;
+      let mut buf = vec![0; MAX_PAYLOAD];
+      loop {
+        tokio::select! {
+          len = socket.recv(&mut buf) => {
+            send_datagram(connection, id, Bytes::copy_from_slice(&buf[..len?]));
+          }
+          // The client sends nothing more on the stream; it ending, or the
+          // connection closing, ends the flow.
+          res = recv.read_to_end(0) => {
+            if let Err(err) = res {
+              debug!(id, "udp flow stream failed: {}", err);
+            }
+            break;
+          }
+        }
+      }
+      Ok::<_, QvpnError>(())
+    }
+    .await;
+    self.sockets.lock().unwrap().remove(&key);
+    debug!(id, "udp flow closed");
+    result
+  }
+}
+
+/// Sends `payload` as a datagram of flow `id`, dropping it if it doesn't
+/// fit.
+fn send_datagram(connection: &quinn::Connection, id: u32, payload: Bytes) {
+  let frame = Frame {
+    kind: Kind::Udp,
+    id,
+    payload,
+  };
+  if let Some(max) = datagram::max_payload(connection) {
+    if frame.payload.len() > max {
+      debug!(id, len = frame.payload.len(), max, "udp datagram too large");
+      return;
+    }
+  }
+  if let Err(err) = connection.send_datagram(frame.encode()) {
+    debug!(id, "dropping udp datagram: {}", err);
+  }
+}
+
+/// A flow of the client: one local source talking to one target.
+struct Flow {
+  id: u32,
+  /// Finishing it closes the flow at the server.
+  send: quinn::SendStream,
+  last_used: Instant,
+}
+
+/// The flows of one forwarded port, by local source address.
+#[derive(Default)]
+struct Port {
+  flows: HashMap<SocketAddr, Flow>,
+  /// Sources by flow id, to address replies.
+  sources: HashMap<u32, SocketAddr>,
+}
+
+/// Relays the datagrams received on the `ports` sockets to their targets
+/// through `connection`, and the replies back, until the connection fails.
+pub async fn forward(
+  ports: &[(Arc<UdpSocket>, Forward)],
+  connection: quinn::Connection,
+) -> Result<()> {
+  let tables: Vec<_> = ports.iter().map(|_| Mutex::new(Port::default())).collect();
+  let replies = async {
+    loop {
+      let frame = match Frame::decode(connection.read_datagram().await?) {
+        Some(frame) if frame.kind == Kind::Udp => frame,
+        _ => {
+          debug!("ignoring unexpected datagram");
+          continue;
+        }
+      };
+      let found = tables.iter().enumerate().find_map(|(index, table)| {
+        let source = table.lock().unwrap().sources.get(&frame.id).copied();
+        source.map(|source| (index, source))
+      });
+      match found {
+        Some((index, source)) => {
+          if let Err(err) = ports[index].0.try_send_to(&frame.payload, source) {
+            debug!(%source, "dropping udp reply: {}", err);
+          }
+        }
+        None => debug!(id = frame.id, "dropping datagram of unknown udp flow"),
+      }
+    }
+  };
+  let requests = ports
+    .iter()
+    .zip(&tables)
+    .map(|((socket, forward), table)| accept(socket, forward, table, &connection));
+  let expire = async {
+    let mut interval = tokio::time::interval(FLOW_IDLE_TIMEOUT / 4);
+    loop {
+      interval.tick().await;
+      for table in &tables {
+        let mut table = table.lock().unwrap();
+        let Port { flows, sources } = &mut *table;
+        flows.retain(|source, flow| {
+          if flow.last_used.elapsed() < FLOW_IDLE_TIMEOUT {
+            return true;
+          }
+          debug!(%source, id = flow.id, "udp flow idle, closing");
+          let _ = flow.send.finish();
+          sources.remove(&flow.id);
+          false
+        });
+      }
+    }
+  };
+  tokio::select! {
+    res = replies => res,
+    res = futures::future::try_join_all(requests) => res.map(|_| ()),
+    () = expire => Ok(()),
+  }
+}
+
+/// Relays the datagrams local clients send to `socket`, opening a flow for
+/// each new source.
+async fn accept(
+  socket: &UdpSocket,
+  forward: &Forward,
+  table: &Mutex<Port>,
+  connection: &quinn::Connection,
+) -> Result<()> {
+  let mut buf = vec![0; MAX_PAYLOAD];
+  loop {
+    let (len, source) = socket.recv_from(&mut buf).await?;
+    let id = {
+      let mut table = table.lock().unwrap();
+      table.flows.get_mut(&source).map(|flow| {
+        flow.last_used = Instant::now();
+        flow.id
+      })
+    };
+    let id = match id {
+      Some(id) => id,
+      None => match open(connection, &forward.remote).await {
+        Ok(flow) => {
+          info!(%source, remote = %forward.remote, id = flow.id, "udp flow opened");
+          let id = flow.id;
+          let mut table = table.lock().unwrap();
+          table.sources.insert(id, source);
+          table.flows.insert(source, flow);
+          id
+        }
+        // The server refusing a target is the client's problem, not the
+        // connection's.
+        Err(err @ QvpnError::Remote(_)) => {
+          info!(%source, remote = %forward.remote, "udp flow refused: {}", err);
+          continue;
+        }
+        Err(err) => return Err(err),
+      },
+    };
+    send_datagram(connection, id, Bytes::copy_from_slice(&buf[..len]));
+  }
+}
+
+/// Asks the server for a flow to `target`.
+async fn open(connection: &quinn::Connection, target: &str) -> Result<Flow> {
+  let (mut send, mut recv) = connection.open_bi().await?;
+  send.write_all(connect_request(target).as_bytes()).await?;
+  let (head, _) = http::read_until(&mut recv, b"\r\n\r\n").await?;
+  let head = ResponseHead::decode(&head)?;
+  if !head.is_success() {
+    return Err(QvpnError::Remote(head.to_string()));
+  }
+  let id = head
+    .get(FLOW_HEADER)
+    .and_then(|x| x.parse().ok())
+    .ok_or_else(|| QvpnError::Protocol(format!("no {} header", FLOW_HEADER)))?;
+  // The server never sends on the stream; dropping its receiving side
+  // keeps the flow open.
+  drop(recv);
+  Ok(Flow {
+    id,
+    send,
+    last_used: Instant::now(),
+  })
+}
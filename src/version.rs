@@ -0,0 +1,162 @@
+//! Negotiation of the qvpn protocol version.
+//!
+//! The [`ALPN_QVPN`] ALPN protocol names the protocol family, and versions
+//! are negotiated on top of it. Tunnel and proxy clients open a control
+//! stream with a `QVPN <version>...\r\n` line listing the versions they
+//! speak. The server answers `200 OK` with the highest version both speak
+//! in a `Qvpn-Version` header, after which the stream carries [`control`]
+//! messages, or `505 Version Not Supported` with the versions it speaks in
+//! `Qvpn-Versions`.
+//!
+//! [`control`]: crate::control
+
+use std::fmt::Write;
+
+use crate::{
+  control::Control,
+  http::{self, ResponseHead},
+  tls, QvpnError, Result, ALPN_LEGACY, ALPN_QVPN,
+};
+
+/// Protocol versions this build speaks, oldest first. Version 2 adds
+/// bonded uplinks, version 3 password and token logins, version 4 [`rpc`]
+/// calls, version 5 [`udp`] flows, version 6 reverse [`forward`]s,
+/// version 7 [`expose`]d services, version 8 tunnels of Ethernet frames,
+/// version 9 [`site`] gateways, version 10 tunnel MTUs following the path
+/// MTU.
+///
+/// [`rpc`]: crate::rpc
+/// [`udp`]: crate::udp
+/// [`forward`]: crate::forward
+/// [`expose`]: crate::expose
+/// [`site`]: crate::site
+pub const VERSIONS: &[u32] = &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
+/// First version with bonded uplinks.
+pub const BONDING: u32 = 2;
+
+/// First version with user logins.
+pub const AUTH: u32 = 3;
+
+/// First version with [`rpc`](crate::rpc) calls.
+pub const RPC: u32 = 4;
+
+/// First version relaying [`udp`](crate::udp) flows.
+pub const UDP: u32 = 5;
+
+/// First version with reverse [`forward`](crate::forward)s.
+pub const REVERSE_FORWARD: u32 = 6;
+
+/// First version with [`expose`](crate::expose)d services.
+pub const EXPOSE: u32 = 7;
+
+/// First version with tunnels carrying Ethernet frames through TAP
+/// interfaces.
+pub const TAP: u32 = 8;
+
+/// First version with [`site`](crate::site) gateways.
+pub const SITES: u32 = 9;
+
+/// First version whose servers advertise a new tunnel MTU whenever path
+/// MTU discovery changes what fits in a datagram.
+pub const PATH_MTU: u32 = 10;
+
+/// Start of a version request line.
+pub const REQUEST_PREFIX: &[u8] = b"QVPN ";
+
+/// TLS alert sent when client and server share no ALPN protocol.
+const NO_APPLICATION_PROTOCOL: u64 = 0x100 | 120;
+
+/// The request line offering [`VERSIONS`].
+pub fn request() -> String {
+  let mut line = String::from("QVPN");
+  for version in VERSIONS {
+    write!(line, " {}", version).expect("writing to a string");
+  }
+  line.push_str("\r\n");
+  line
+}
+
+/// Answers a version request line with the version chosen, or the versions
+/// this build speaks if none of the offered ones is among them.
+pub fn respond(req: &[u8]) -> Result<ResponseHead> {
+  let offered = std::str::from_utf8(req)
+    .ok()
+    .and_then(|req| req.strip_prefix("QVPN "))
+    .map(|versions| {
+      versions
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<std::result::Result<Vec<u32>, _>>()
+    })
+    .and_then(|versions| versions.ok())
+    .ok_or_else(|| QvpnError::BadRequest("malformed version request".into()))?;
+  Ok(match VERSIONS.iter().rev().find(|x| offered.contains(x)) {
+    Some(version) => ResponseHead::new(200, "OK").header("Qvpn-Version", version),
+    None => ResponseHead::new(505, "Version Not Supported").header("Qvpn-Versions", list(VERSIONS)),
+  })
+}
+
+/// Checks the server's answer to [`request`], returning the version chosen.
+pub fn check(head: &ResponseHead) -> Result<u32> {
+  match head.status {
+    200 => head
+      .get("Qvpn-Version")
+      .and_then(|x| x.parse().ok())
+      .filter(|x| VERSIONS.contains(x))
+      .ok_or_else(|| QvpnError::Protocol(format!("server chose no valid version: {:?}", head))),
+    505 => Err(QvpnError::Incompatible(format!(
+      "server speaks qvpn protocol versions {}, this client speaks {}; upgrade the older side",
+      head.get("Qvpn-Versions").unwrap_or("unknown"),
+      list(VERSIONS)
+    ))),
+    _ => Err(QvpnError::Remote(head.to_string())),
+  }
+}
+
+/// Negotiates a version on a new stream of `connection`, which must have
+/// negotiated [`ALPN_QVPN`], and returns it with the stream as the control
+/// stream.
+pub async fn negotiate(connection: &quinn::Connection) -> Result<(u32, Control)> {
+  let protocol = tls::alpn_protocol(connection);
+  if protocol.as_deref() != Some(ALPN_QVPN) {
+    let hint = if protocol.as_deref() == Some(ALPN_LEGACY) {
+      "; it is older or runs with --legacy-proto"
+    } else {
+      ""
+    };
+    return Err(QvpnError::Incompatible(format!(
+      "server doesn't speak {}{}",
+      String::from_utf8_lossy(ALPN_QVPN),
+      hint
+    )));
+  }
+  let (mut send, mut recv) = connection.open_bi().await?;
+  send.write_all(request().as_bytes()).await?;
+  let (head, rest) = http::read_until(&mut recv, b"\r\n\r\n").await?;
+  let version = check(&ResponseHead::decode(&head)?)?;
+  Ok((version, Control::new(send, recv, rest)))
+}
+
+/// Replaces the error for a connection refused for lack of a common ALPN
+/// protocol with one saying so.
+pub fn connection_error(err: quinn::ConnectionError, offered: &[&[u8]]) -> QvpnError {
+  let code = match &err {
+    quinn::ConnectionError::ConnectionClosed(close) => u64::from(close.error_code),
+    quinn::ConnectionError::TransportError(err) => u64::from(err.code),
+    _ => return err.into(),
+  };
+  if code != NO_APPLICATION_PROTOCOL {
+    return err.into();
+  }
+  let offered: Vec<_> = offered.iter().map(|x| String::from_utf8_lossy(x)).collect();
+  QvpnError::Incompatible(format!(
+    "server speaks none of the protocols {}",
+    offered.join(", ")
+  ))
+}
+
+fn list(versions: &[u32]) -> String {
+  let versions: Vec<_> = versions.iter().map(u32::to_string).collect();
+  versions.join(", ")
+}